@@ -6,7 +6,301 @@
 #include <stdlib.h>
 
 /**
- * PointerOrError returns either a `void *` pointer or an error.
+ * Version of the EncodeParams struct implemented by this library.
+ */
+#define ENCODE_PARAMS_VERSION 1
+
+/**
+ * Flags for `EncodeParams.flags`.
+ */
+#define ENCODE_ADD_SPECIAL_TOKENS (1 << 0)
+
+#define ENCODE_RETURN_TOKENS (1 << 1)
+
+#define ENCODE_RETURN_TYPE_IDS (1 << 2)
+
+#define ENCODE_RETURN_SPECIAL_TOKENS_MASK (1 << 3)
+
+#define ENCODE_RETURN_ATTENTION_MASK (1 << 4)
+
+#define ENCODE_RETURN_OFFSETS (1 << 5)
+
+#define ENCODE_WITH_OFFSETS_CHAR_MODE (1 << 6)
+
+#define ENCODE_RETURN_TOKEN_CHAR_LENGTHS (1 << 7)
+
+/**
+ * Offsets are returned in UTF-16 code units (as used by JavaScript and JVM strings), instead of bytes.
+ * It can't be combined with ENCODE_WITH_OFFSETS_CHAR_MODE.
+ */
+#define ENCODE_WITH_OFFSETS_UTF16_MODE (1 << 8)
+
+/**
+ * Return the overflowing windows of truncated sentences (see `Buffer.overflowing`), and the span of
+ * the original text covered by each window (see `Buffer.window`).
+ */
+#define ENCODE_RETURN_OVERFLOWING (1 << 9)
+
+/**
+ * Special tokens (those with `special_tokens_mask` set) report an empty offset at the boundary of the
+ * text next to them: the end of the previous non-special token, or for leading special tokens, the start
+ * of the first non-special token. By default special tokens always report the offset (0, 0).
+ */
+#define ENCODE_SPECIAL_TOKENS_BOUNDARY_OFFSETS (1 << 10)
+
+/**
+ * Return per-token debug information about the segmentation: the pre-token each token came from
+ * (`Buffer.word_ids`) and its model score (`Buffer.token_scores`).
+ */
+#define ENCODE_RETURN_DEBUG_INFO (1 << 11)
+
+/**
+ * Special token strings in the text (e.g. "<|im_end|>") are encoded as plain text, instead of as the
+ * special tokens, overriding the tokenizer setting (see `set_encode_special_tokens`). It can't be combined
+ * with ENCODE_MATCH_SPECIAL_TOKENS.
+ */
+#define ENCODE_SPLIT_SPECIAL_TOKENS (1 << 12)
+
+/**
+ * Special token strings in the text are encoded as the special tokens, overriding the tokenizer setting
+ * (see `set_encode_special_tokens`).
+ */
+#define ENCODE_MATCH_SPECIAL_TOKENS (1 << 13)
+
+/**
+ * Return the length of the sequence before padding (see `Buffer.length`), as `return_length` in transformers.
+ */
+#define ENCODE_RETURN_LENGTH (1 << 14)
+
+/**
+ * The matrices of `encode_batch_padded` hold i32 values (see `PaddedBatch.dtype`), instead of u32. It fails
+ * if an id doesn't fit. It can't be combined with ENCODE_OUTPUT_INT64, and it is ignored by the other functions.
+ */
+#define ENCODE_OUTPUT_INT32 (1 << 15)
+
+/**
+ * The matrices of `encode_batch_padded` hold i64 values (see `PaddedBatch.dtype`), instead of u32. It is
+ * ignored by the other functions.
+ */
+#define ENCODE_OUTPUT_INT64 (1 << 16)
+
+/**
+ * Return the words (pre-tokens) of the text (see `Buffer.words`), and the word each token came from (see
+ * `Buffer.word_ids`), e.g. for whole-word masking.
+ */
+#define ENCODE_RETURN_WORDS (1 << 17)
+
+/**
+ * Skip the normalizer of the tokenizer, for texts already normalized upstream: the offsets are then into the
+ * exact text given. The rest of the pipeline (added tokens, pre-tokenizer, model and post-processor) is unchanged.
+ */
+#define ENCODE_SKIP_NORMALIZATION (1 << 18)
+
+/**
+ * Return whether each token starts a new word (see `Buffer.word_starts`), e.g. for whole-word masking.
+ */
+#define ENCODE_RETURN_WORD_STARTS (1 << 19)
+
+/**
+ * The attention and special tokens masks (if requested) are returned bit-packed, 1 bit per token, in
+ * `Buffer.attention_mask_bits` and `Buffer.special_tokens_mask_bits`, instead of `Buffer.attention_mask` and
+ * `Buffer.special_tokens_mask`, for very long sequences. It is ignored by `encode_batch_padded`.
+ */
+#define ENCODE_PACKED_MASKS (1 << 20)
+
+/**
+ * Special tokens (those with `special_tokens_mask` set) report the offset (NO_OFFSET, NO_OFFSET), instead of
+ * (0, 0), so only the content tokens have offsets into the text. It can't be combined with
+ * ENCODE_SPECIAL_TOKENS_BOUNDARY_OFFSETS.
+ */
+#define ENCODE_SPECIAL_TOKENS_NO_OFFSETS (1 << 21)
+
+/**
+ * Return the word (pre-token) each token came from (see `Buffer.word_ids`), without the debug information of
+ * ENCODE_RETURN_DEBUG_INFO or the word spans of ENCODE_RETURN_WORDS, as `word_ids()` in transformers.
+ */
+#define ENCODE_RETURN_WORD_IDS (1 << 22)
+
+/**
+ * Return the sequence (0 for the first text, 1 for the second text of a pair) each token came from (see
+ * `Buffer.sequence_ids`), as `sequence_ids()` in transformers.
+ */
+#define ENCODE_RETURN_SEQUENCE_IDS (1 << 23)
+
+/**
+ * NO_OFFSET is the start and end of the offsets of the special tokens with ENCODE_SPECIAL_TOKENS_NO_OFFSETS.
+ */
+#define NO_OFFSET UINT32_MAX
+
+/**
+ * poll_result status: the job is still running.
+ */
+#define JOB_PENDING 0
+
+/**
+ * poll_result status: the job is completed, and its results were returned.
+ */
+#define JOB_COMPLETED 1
+
+/**
+ * poll_result status: there is no such job (or its results were already taken).
+ */
+#define JOB_UNKNOWN 2
+
+/**
+ * Error code returned by `encode_error_code` when there is no error.
+ */
+#define ENCODE_OK 0
+
+/**
+ * Error code returned by `encode_error_code` for the errors without a specific code.
+ */
+#define ENCODE_ERROR_OTHER 1
+
+/**
+ * Error code returned by `encode_error_code`: an input has more bytes than `EncodeLimits.max_input_bytes`.
+ */
+#define ENCODE_ERROR_INPUT_TOO_LARGE 2
+
+/**
+ * Error code returned by `encode_error_code`: the batch has more inputs than `EncodeLimits.max_batch_size`.
+ */
+#define ENCODE_ERROR_BATCH_TOO_LARGE 3
+
+/**
+ * Error code returned by `encode_error_code`: an input produces more tokens than `EncodeLimits.max_tokens`.
+ * The number of tokens produced until the encoding was aborted is returned by `encode_error_token_count`.
+ */
+#define ENCODE_ERROR_TOKEN_BUDGET_EXCEEDED 4
+
+/**
+ * Error code set by the verified loaders (e.g. `from_bytes_verified`): the tokenizer was loaded.
+ */
+#define LOAD_OK 0
+
+/**
+ * Error code set by the verified loaders: the tokenizer definition is invalid.
+ */
+#define LOAD_ERROR_INVALID 1
+
+/**
+ * Error code set by the verified loaders: the SHA-256 digest of the contents doesn't match the expected one.
+ */
+#define LOAD_ERROR_CHECKSUM_MISMATCH 2
+
+/**
+ * Error code set by the verified loaders: the contents couldn't be read (or downloaded).
+ */
+#define LOAD_ERROR_IO 3
+
+/**
+ * Type of the values of the PaddedBatch matrices (u32, unless selected by the EncodeParams flags), or of the
+ * tensors of `encode_batch_into_tensors`.
+ */
+#define PADDED_DTYPE_UINT32 0
+
+/**
+ * Type of the values of the PaddedBatch matrices with ENCODE_OUTPUT_INT32, or of the tensors of
+ * `encode_batch_into_tensors`.
+ */
+#define PADDED_DTYPE_INT32 1
+
+/**
+ * Type of the values of the PaddedBatch matrices with ENCODE_OUTPUT_INT64, or of the tensors of
+ * `encode_batch_into_tensors`.
+ */
+#define PADDED_DTYPE_INT64 2
+
+/**
+ * Number of buckets of the PhaseProfile histograms: bucket `i` counts the calls that took from `2^i`
+ * (inclusive) to `2^(i+1)` (exclusive) nanoseconds -- bucket 0 also counts the calls under 1 nanosecond, and
+ * the last bucket all the longer calls.
+ */
+#define PROFILE_NUM_BUCKETS 40
+
+/**
+ * build_fim_prompt mode: prefix-suffix-middle, the prompt is `<prefix> prefix <suffix> suffix <middle>`.
+ */
+#define FIM_PSM 0
+
+/**
+ * build_fim_prompt mode: suffix-prefix-middle, the prompt is `<prefix> <suffix> suffix <middle> prefix`, so
+ * the generated middle continues the prefix.
+ */
+#define FIM_SPM 1
+
+/**
+ * Number of buckets of the SequenceStats histogram: bucket 0 counts the empty sequences, and bucket `i > 0`
+ * the sequences with `2^(i-1)` (inclusive) to `2^i` (exclusive) tokens -- the last bucket also counts all
+ * the longer sequences.
+ */
+#define SEQUENCE_STATS_NUM_BUCKETS 33
+
+/**
+ * Model type of TrainParams: Byte-Pair Encoding.
+ */
+#define TRAIN_MODEL_BPE 0
+
+/**
+ * Model type of TrainParams: WordPiece.
+ */
+#define TRAIN_MODEL_WORDPIECE 1
+
+/**
+ * Model type of TrainParams: Unigram.
+ */
+#define TRAIN_MODEL_UNIGRAM 2
+
+/**
+ * Model type of TrainParams: WordLevel.
+ */
+#define TRAIN_MODEL_WORDLEVEL 3
+
+/**
+ * TOKEN_NOT_FOUND is the id returned by `token_to_id_batch` for the tokens not in the vocabulary.
+ */
+#define TOKEN_NOT_FOUND UINT32_MAX
+
+/**
+ * ConfigSnapshot is a copy of the configuration of a TokenizerState (see `TokenizerState::config_snapshot`):
+ * padding, truncation, added tokens and the per-tokenizer options.
+ */
+typedef struct ConfigSnapshot ConfigSnapshot;
+
+/**
+ * CorpusReader streams the encodings of the records of a corpus (see `corpus_open`). It is opaque to the host.
+ */
+typedef struct CorpusReader CorpusReader;
+
+/**
+ * DecodeStream decodes the ids generated one at a time, see `decode_stream_new`. It is opaque to the host.
+ */
+typedef struct DecodeStream DecodeStream;
+
+/**
+ * EncodingHandle is an opaque handle to an encoded text, including its overflowing windows. It is
+ * returned by `encode_to_handle` and `encoding_deserialize`, and must be freed with `free_encoding`.
+ */
+typedef struct EncodingHandle EncodingHandle;
+
+/**
+ * ResultSet holds the results of `encode_batch_into`, and is reused by successive calls: its memory is kept
+ * and overwritten, so steady-state encoding doesn't allocate (or free) memory for the results. It is an
+ * opaque `ResultSet *` in C, created with `result_set_new` and freed with `result_set_free`.
+ */
+typedef struct ResultSet ResultSet;
+
+/**
+ * TokenizerHandle is the object returned to Golang by `from_bytes`: an opaque `TokenizerHandle *` in C.
+ *
+ * It is reference counted (an `Arc`): see `tokenizer_retain` and `tokenizer_release`. Since it can
+ * be shared, the Tokenizer and the per-tokenizer options are kept behind a `RwLock` -- except for
+ * frozen handles (see `freeze`), which are immutable and don't need locking.
+ */
+typedef struct TokenizerHandle TokenizerHandle;
+
+/**
+ * PointerOrError returns either a `void *` pointer or an error. 
  * It can be used by functions interfacing with Rust from other languages (using the C binding).
  *
  * Either `value` or `error` will be defined. The `value` underlying type is defined by the function
@@ -21,28 +315,16 @@ typedef struct PointerOrError {
 } PointerOrError;
 
 /**
- * TruncationParameters represents the truncation parameters
- * that can be set with "with_truncation".
- */
-typedef struct TruncationParams {
-  uint8_t direction;
-  uint8_t strategy;
-  uint32_t max_length;
-  uint32_t stride;
-} TruncationParams;
-
-/**
- * PaddingParams represents the padding parameters: it maps to the values in
- * tokenizers::tokenizer::PaddingParams.
+ * BytesOrError returns either a byte buffer or an error.
+ *
+ * Either `data` (with `len` bytes) or `error` will be defined. Ownership of both is transferred
+ * to the caller, who must free it with `free_bytes`.
  */
-typedef struct PaddingParams {
-  uint32_t strategy;
-  uint8_t direction;
-  uint32_t pad_to_multiple_of;
-  uint32_t pad_id;
-  uint32_t pad_type_id;
-  const char *pad_token;
-} PaddingParams;
+typedef struct BytesOrError {
+  uint8_t *data;
+  uint64_t len;
+  char *error;
+} BytesOrError;
 
 /**
  * Offset of the toke in the sentence.
@@ -66,127 +348,2986 @@ typedef struct Buffer {
   char **tokens;
   struct Offset *offsets;
   uint32_t len;
+  int64_t *word_ids;
+  double *token_scores;
+  struct Offset window;
+  struct Buffer *overflowing;
+  uint64_t num_overflowing;
+  char *tokens_arena;
+  uint64_t tokens_arena_len;
+  uint64_t length;
+  struct Offset *words;
+  uint64_t num_words;
+  uint32_t *word_starts;
+  uint8_t *attention_mask_bits;
+  uint8_t *special_tokens_mask_bits;
+  int64_t *sequence_ids;
+  uint32_t *token_char_lengths;
 } Buffer;
 
 /**
- * EncodeResult represents the result of encoding one (`encode` function)
- * or more (`encode_batch` function) sentences.
+ * EncodeResultsV2 is the 64-bit version of EncodeResults, returned by `encode_v2` and
+ * `encode_batch_v2`: the number of results and the `total_tokens` over all of them are u64, so
+ * batches with more than 4G tokens can be represented.
  *
- * It will contain either an error as a C string, or a number of Buffer
- * results, one per sentence encoded -- only one if using `encode` function.
- *
- * Once it is no longer used, free the data with `free_encode_results`.
+ * Once it is no longer used, free the data with `free_encode_results_v2`.
  */
-typedef struct EncodeResults {
-  uint32_t len;
+typedef struct EncodeResultsV2 {
+  uint64_t len;
+  uint64_t total_tokens;
   struct Buffer *encoded;
   char *error;
-} EncodeResults;
+} EncodeResultsV2;
 
 /**
  * EncodeParams specifies what information to return from the
  * encoded sentences.
  * It controls which fields in Buffer are set.
+ *
+ * `flags` is a bitmask of the `ENCODE_*` flags, and `version` should be set to
+ * the `ENCODE_PARAMS_VERSION` the caller was compiled with: new options are added as
+ * new flags, so older callers keep working with newer versions of the library.
  */
 typedef struct EncodeParams {
-  bool add_special_tokens;
-  bool return_tokens;
-  bool return_type_ids;
-  bool return_special_tokens_mask;
-  bool return_attention_mask;
-  bool return_offsets;
-  bool with_offsets_char_mode;
+  uint32_t version;
+  uint64_t flags;
 } EncodeParams;
 
 /**
- * This function returns a Tokenizer reference to Golang (casted as a C `void*` in the `value` field) or
- * an error.
- *
- * The parameter `bytes` should be the json contents for a `tokenizer.json` file, with its definitions (symbols,
- * truncation parameters, etc.)
- *
- * # Safety
- *
- * The caller has ownership of `bytes` and of the returned `Tokenizer`.
+ * ArrowArray is the `struct ArrowArray` of the Arrow C Data Interface: the values of an array.
  */
-struct PointerOrError from_bytes(const uint8_t *bytes,
-                                 uint32_t len);
+typedef struct ArrowArray {
+  int64_t length;
+  int64_t null_count;
+  int64_t offset;
+  int64_t n_buffers;
+  int64_t n_children;
+  const void **buffers;
+  struct ArrowArray **children;
+  struct ArrowArray *dictionary;
+  void (*release)(struct ArrowArray*);
+  void *private_data;
+} ArrowArray;
 
 /**
- * Frees a Tokenizer allocated by Rust and returned to Golang.
+ * ArrowSchema is the `struct ArrowSchema` of the Arrow C Data Interface: the type of an ArrowArray.
  */
-void free_tokenizer(void *ptr);
+typedef struct ArrowSchema {
+  const char *format;
+  const char *name;
+  const char *metadata;
+  int64_t flags;
+  int64_t n_children;
+  struct ArrowSchema **children;
+  struct ArrowSchema *dictionary;
+  void (*release)(struct ArrowSchema*);
+  void *private_data;
+} ArrowSchema;
 
 /**
- * Frees a `*C.char` allocated by Rust and return to Golang.
+ * ChatTemplateResult is the result of `apply_chat_template`: the rendered `text`, and its `len` token `ids`.
+ * `generation_prompt_ids` holds the last `generation_prompt_len` of them, the tokens of the generation prompt
+ * (the start of the assistant message) added with `add_generation_prompt`.
+ *
+ * Either the results or `error` will be defined. Once it is no longer used, free it with
+ * `free_chat_template_result`.
  */
-void free_string(char *ptr);
+typedef struct ChatTemplateResult {
+  char *text;
+  uint32_t *ids;
+  uint64_t len;
+  uint32_t *generation_prompt_ids;
+  uint64_t generation_prompt_len;
+  char *error;
+} ChatTemplateResult;
 
 /**
- * Returns the vocab size.
+ * ChatTemplateParams are the options of `apply_chat_template`.
  */
-uint32_t vocab_size(void *ptr);
+typedef struct ChatTemplateParams {
+  const char *template_name;
+  bool add_generation_prompt;
+  bool continue_final_message;
+  const uint8_t *variables;
+  uint32_t variables_len;
+} ChatTemplateParams;
 
 /**
- * set_truncation modifies the tokenizer with the given truncation parameters.
- * It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
- * The returned string needs to be freed with `free_string`.
+ * SentencePieceParams are the options to match the encodings of the reference sentencepiece library, for
+ * SentencePiece-derived tokenizers.
  */
-char *set_truncation(void *tokenizer_ptr,
-                     const struct TruncationParams *params);
+typedef struct SentencePieceParams {
+  uint8_t add_dummy_prefix;
+  bool remove_extra_whitespaces;
+} SentencePieceParams;
 
 /**
- * get_truncation gets the current Tokenizer's truncation parameters.
- *
- * If there are truncation parameters configured in the Tokenizer, the values are read into the `params` passed,
- * and it returns true.
- *
- * If there are no truncation values configured, it returns false.
+ * TruncationParameters represents the truncation parameters
+ * that can be set with "with_truncation".
  */
-bool get_truncation(void *tokenizer_ptr,
-                    struct TruncationParams *params);
+typedef struct TruncationParams {
+  uint8_t direction;
+  uint8_t strategy;
+  uint32_t max_length;
+  uint32_t stride;
+} TruncationParams;
 
 /**
- * set_padding modifies the tokenizer with the given padding parameters.
- * It doesn't return anything.
+ * PaddingParams represents the padding parameters: it maps to the values in
+ * tokenizers::tokenizer::PaddingParams.
+ */
+typedef struct PaddingParams {
+  uint32_t strategy;
+  uint8_t direction;
+  uint32_t pad_to_multiple_of;
+  uint32_t pad_id;
+  uint32_t pad_type_id;
+  const char *pad_token;
+} PaddingParams;
+
+/**
+ * CorpusOptions configures how `corpus_open` splits and chunks the corpus.
  */
-void set_padding(void *tokenizer_ptr, const struct PaddingParams *params);
+typedef struct CorpusOptions {
+  /**
+   * Byte separating the records of the corpus, usually `'\n'`. If it is `'\n'`, a trailing `'\r'` is also
+   * removed from the records. The delimiter itself is never part of the records.
+   */
+  uint8_t delimiter;
+  /**
+   * If set, empty records are skipped.
+   */
+  bool skip_empty;
+  /**
+   * Maximum number of records per chunk, must be positive.
+   */
+  uint32_t max_chunk_records;
+  /**
+   * If positive, a chunk is closed once it holds at least this many bytes of text, even if it has fewer
+   * than `max_chunk_records` records.
+   */
+  uint64_t max_chunk_bytes;
+  /**
+   * Number of chunks encoded ahead of `corpus_next`: the reader blocks once they are all waiting to be taken,
+   * so memory use is bounded. Zero means the reader only reads the next chunk when it is asked for.
+   */
+  uint32_t prefetch;
+} CorpusOptions;
 
 /**
- * get_padding gets the current Tokenizer's padding parameters.
+ * EncodeResult represents the result of encoding one (`encode` function)
+ * or more (`encode_batch` function) sentences.
  *
- * If there are padding parameters configured in the Tokenizer, the values are read into the `params` passed,
- * and it returns true. The `params.pad_token` ownership is transferred to the caller, who must free it
- * after use (see `free_string()`).
+ * It will contain either an error as a C string, or a number of Buffer
+ * results, one per sentence encoded -- only one if using `encode` function.
  *
- * If there are no truncation values configured, it returns false.
+ * Once it is no longer used, free the data with `free_encode_results`.
  */
-bool get_padding(void *tokenizer_ptr,
-                 struct PaddingParams *params);
+typedef struct EncodeResults {
+  uint32_t len;
+  struct Buffer *encoded;
+  char *error;
+} EncodeResults;
 
 /**
- * Encodes string using given tokenizer and EncodeParams.
+ * FertilityDistribution summarizes the distribution of a ratio of tokens, see `FertilityStats`.
  */
-struct EncodeResults encode(void *tokenizer_ptr, const char *message, struct EncodeParams options);
+typedef struct FertilityDistribution {
+  double mean;
+  double p50;
+  double p90;
+  double p99;
+  double max;
+} FertilityDistribution;
 
 /**
- * Encode a batch of strings using given tokenizer and EncodeParams.
- * The
+ * FertilityStats holds the fertility statistics of a tokenizer over a sample corpus, see `fertility`.
  */
-struct EncodeResults encode_batch(void *tokenizer_ptr,
-                                  uint32_t num_messages,
-                                  const char *const *messages,
-                                  struct EncodeParams options);
+typedef struct FertilityStats {
+  /**
+   * Number of texts of the corpus.
+   */
+  uint64_t num_texts;
+  /**
+   * Number of words of the corpus: the sequences of characters separated by (Unicode) whitespace.
+   */
+  uint64_t num_words;
+  /**
+   * Number of bytes of the corpus.
+   */
+  uint64_t num_bytes;
+  /**
+   * Number of tokens of the corpus, not counting the special tokens added by the post-processor.
+   */
+  uint64_t num_tokens;
+  /**
+   * Number of tokens per word, over the words of the corpus.
+   */
+  struct FertilityDistribution tokens_per_word;
+  /**
+   * Number of tokens per byte, over the (non-empty) texts of the corpus. The ratio of the whole corpus is
+   * `num_tokens / num_bytes`.
+   */
+  struct FertilityDistribution tokens_per_byte;
+} FertilityStats;
 
 /**
- * This function is release Vec<Buffer> from Rust returned to Golang by `encode_batch`.
+ * FlatBatch holds the encodings of a batch concatenated, see `encode_batch_flat`.
+ *
+ * Once it is no longer used, free it with `free_flat_batch`.
  */
-void free_encode_results(struct EncodeResults results);
-
-/**
- * tokenizer.Decode method.
- * The returned string needs to be deallocated with `free_string`.
+typedef struct FlatBatch {
+  uint64_t batch_size;
+  uint64_t num_tokens;
+  uint64_t *starts;
+  uint32_t *ids;
+  uint32_t *type_ids;
+  uint32_t *special_tokens_mask;
+  uint32_t *attention_mask;
+  struct Offset *offsets;
+  uint8_t *tokens;
+  uint64_t tokens_len;
+  uint64_t *token_starts;
+  char *error;
+} FlatBatch;
+
+/**
+ * DecodeResults is the result of `decode`, `decode_batch` and `decode_with_params`: the `len` decoded texts
+ * (null-terminated), in `decoded`.
+ *
+ * Either `decoded` or `error` will be defined. Once it is no longer used, free it with `free_decode_results`.
+ */
+typedef struct DecodeResults {
+  uint64_t len;
+  char **decoded;
+  char *error;
+} DecodeResults;
+
+/**
+ * HostDecodeFn is a host callback that joins the `num_tokens` token strings (given as arrays of pointers
+ * to `lens` bytes of UTF-8, not null-terminated) into the decoded text, replacing the decoder of the
+ * tokenizer (see `set_host_decoder`). `user_data` is the one given to `set_host_decoder`.
+ *
+ * It returns the decoded text (setting its length in `out_len`), or null if it failed. The returned bytes
+ * are owned by the host: they are copied before the next call, so they only need to remain valid until then.
+ */
+typedef const uint8_t *(*HostDecodeFn)(void *user_data,
+                                       const uint8_t *const *tokens,
+                                       const uint64_t *lens,
+                                       uint64_t num_tokens,
+                                       uint64_t *out_len);
+
+/**
+ * TokenTransform is a host callback called by `decode_with_params` with each token string (`len` bytes of
+ * UTF-8, not null-terminated), e.g. for redaction or casing fixes, before the tokens are joined by the
+ * decoder. `user_data` is the `token_transform_data` given in DecodeParams.
+ *
+ * It returns the transformed token (setting its length in `out_len`), or null to drop the token. The
+ * returned bytes are owned by the host: they are copied before the next call, so they only need to
+ * remain valid until then.
+ */
+typedef const uint8_t *(*TokenTransform)(void *user_data,
+                                         const uint8_t *token,
+                                         uint64_t len,
+                                         uint64_t *out_len);
+
+/**
+ * DecodeParams holds the options for `decode_with_params`.
+ */
+typedef struct DecodeParams {
+  bool skip_special_tokens;
+  uint8_t spaces_between_special_tokens;
+  const char *token_filter;
+  const char *token_filter_replacement;
+  TokenTransform token_transform;
+  void *token_transform_data;
+  uint8_t invalid_utf8;
+} DecodeParams;
+
+/**
+ * DecodedWithSpans is the result of `decode_with_spans`: the decoded `text` (null-terminated), and for each
+ * of the `len` ids decoded, the byte span of `text` it contributed to, in `spans`.
+ *
+ * Either `text` and `spans` or `error` will be defined. Once it is no longer used, free it with
+ * `free_decoded_with_spans`.
+ */
+typedef struct DecodedWithSpans {
+  char *text;
+  struct Offset *spans;
+  uint64_t len;
+  char *error;
+} DecodedWithSpans;
+
+/**
+ * DocumentField is one field of a document encoded with `encode_document`.
+ */
+typedef struct DocumentField {
+  const uint8_t *text;
+  uint32_t len;
+  uint32_t type_id;
+  uint32_t max_tokens;
+} DocumentField;
+
+/**
+ * TokenHealing is the result of `token_healing`: the `len` token `ids` of the prompt without its trailing
+ * partial token, and the text `prefix` the first generated token must start with -- the text of the
+ * removed token, or an empty string if no token was removed.
+ *
+ * Either the results or `error` will be defined. Once it is no longer used, free it with `free_token_healing`.
+ */
+typedef struct TokenHealing {
+  uint32_t *ids;
+  uint64_t len;
+  char *prefix;
+  char *error;
+} TokenHealing;
+
+/**
+ * JobCallback is an optional host callback called (from a library thread) once the job `job_id` is
+ * completed, so its results can be taken with `poll_result`. `user_data` is the one given to `submit_batch`.
+ */
+typedef void (*JobCallback)(void *user_data, uint64_t job_id);
+
+/**
+ * EncodeLimits are the limits on the inputs of the encode calls of a tokenizer, see `set_encode_limits`.
+ */
+typedef struct EncodeLimits {
+  uint64_t max_input_bytes;
+  uint64_t max_batch_size;
+  uint64_t max_tokens;
+} EncodeLimits;
+
+/**
+ * ReadCallback is a host function that reads up to `len` bytes into `buf`, like Go's `io.Reader`: it returns
+ * the number of bytes read, 0 at the end of the input, or a negative value on error.
+ */
+typedef int64_t (*ReadCallback)(void *user_data, uint8_t *buf, uint64_t len);
+
+/**
+ * DecryptCallback is a host function that decrypts the `len` bytes of `data`: it returns the decrypted bytes
+ * (setting their length in `out_len`), or null on error. The returned bytes are owned by the host, and only
+ * need to remain valid until the function loading the tokenizer returns -- they are parsed in place, not
+ * copied.
+ */
+typedef const uint8_t *(*DecryptCallback)(void *user_data,
+                                          const uint8_t *data,
+                                          uint64_t len,
+                                          uint64_t *out_len);
+
+/**
+ * TiktokenDefinition is the in-memory representation of a tiktoken encoding, see `from_tiktoken_ranks`.
+ */
+typedef struct TiktokenDefinition {
+  uint64_t num_ranks;
+  const uint8_t *const *tokens;
+  const uint32_t *token_lengths;
+  const uint32_t *ranks;
+  uint64_t num_special_tokens;
+  const uint8_t *const *special_tokens;
+  const uint32_t *special_token_lengths;
+  const uint32_t *special_token_ids;
+  const char *pattern;
+} TiktokenDefinition;
+
+/**
+ * PaddedBatch holds the encodings of a batch as `[batch_size, max_len]` row-major matrices, see
+ * `encode_batch_padded`.
+ *
+ * Once it is no longer used, free it with `free_padded_batch`.
+ */
+typedef struct PaddedBatch {
+  uint64_t batch_size;
+  uint64_t max_len;
+  uint32_t dtype;
+  void *ids;
+  void *attention_mask;
+  void *type_ids;
+  uint64_t *lengths;
+  char *error;
+} PaddedBatch;
+
+/**
+ * PipelineSlot is one slot of the PipelineRing: the input text set by the host, and its encoding set by
+ * the workers.
+ */
+typedef struct PipelineSlot {
+  uint64_t seq;
+  const uint8_t *text;
+  uint64_t len;
+  uint64_t completed;
+  struct EncodeResultsV2 results;
+} PipelineSlot;
+
+/**
+ * PipelineRing is the ring of `capacity` slots shared by the host and the pipeline workers (see
+ * `pipeline_new`). `head` and `tail` are absolute indices (slot `i` is `slots[i % capacity]`), only written by
+ * the host, and read by the workers: they must be accessed atomically (with release stores), e.g. with Go's
+ * `sync/atomic`.
+ *
+ * The host (a single producer and consumer) streams texts with:
+ *
+ * 1. Produce: while `tail - head < capacity`, fill the `seq`, `text` and `len` of slot `tail`, and then
+ *    increment `tail`.
+ * 2. Consume: once the `completed` field of slot `head` equals `head + 1`, read its `results`, and then
+ *    increment `head` to release the slot (its results are no longer valid).
+ *
+ * Results are consumed in the order the texts were produced, although they are encoded in parallel.
+ */
+typedef struct PipelineRing {
+  uint64_t capacity;
+  struct PipelineSlot *slots;
+  uint64_t head;
+  uint64_t tail;
+  void *internal;
+} PipelineRing;
+
+/**
+ * LineColumnSpan is the span of a token in the text as (line, column) positions, all 0-based: the end is
+ * exclusive, as the offsets. See `token_line_columns`.
+ */
+typedef struct LineColumnSpan {
+  uint32_t start_line;
+  uint32_t start_column;
+  uint32_t end_line;
+  uint32_t end_column;
+} LineColumnSpan;
+
+/**
+ * TokenLineColumns is the result of `token_line_columns`: one LineColumnSpan per token of the Buffer.
+ *
+ * Either the `len` `spans` or `error` will be defined. Once it is no longer used, free it with
+ * `free_token_line_columns`.
+ */
+typedef struct TokenLineColumns {
+  struct LineColumnSpan *spans;
+  uint64_t len;
+  char *error;
+} TokenLineColumns;
+
+/**
+ * PhaseProfile aggregates the durations of one phase of the encoding, one per text encoded.
+ */
+typedef struct PhaseProfile {
+  uint64_t count;
+  uint64_t total_ns;
+  uint64_t max_ns;
+  uint64_t buckets[PROFILE_NUM_BUCKETS];
+} PhaseProfile;
+
+/**
+ * Profile holds the histograms of the durations of each phase of the encoding, see `get_profile`.
+ */
+typedef struct Profile {
+  /**
+   * Normalization, including the extraction of the added tokens.
+   */
+  struct PhaseProfile normalize;
+  struct PhaseProfile pre_tokenize;
+  /**
+   * Tokenization of the pre-tokenized words by the model.
+   */
+  struct PhaseProfile model;
+  /**
+   * Truncation and post-processing (e.g. adding special tokens): the padding to the longest text of the
+   * batch is not included.
+   */
+  struct PhaseProfile post_process;
+} Profile;
+
+/**
+ * SequenceStats holds the statistics of the sequences encoded by a tokenizer, see `get_sequence_stats`.
+ */
+typedef struct SequenceStats {
+  /**
+   * Number of sequences encoded.
+   */
+  uint64_t count;
+  /**
+   * Number of sequences truncated (to the `max_length` of the truncation parameters).
+   */
+  uint64_t truncated;
+  /**
+   * Total number of tokens, not counting the padding.
+   */
+  uint64_t total_tokens;
+  /**
+   * Total number of padding tokens added.
+   */
+  uint64_t total_padding;
+  /**
+   * Longest sequence, not counting the padding.
+   */
+  uint64_t max_len;
+  /**
+   * Histogram of the lengths of the sequences, not counting the padding.
+   */
+  uint64_t buckets[SEQUENCE_STATS_NUM_BUCKETS];
+} SequenceStats;
+
+/**
+ * DecodeStreamStatus tells whether a DecodeStream is withholding text, see `decode_stream_status`.
+ */
+typedef struct DecodeStreamStatus {
+  uint32_t pending_ids;
+  uint32_t pending_bytes;
+} DecodeStreamStatus;
+
+/**
+ * ThreadPolicy configures the threads used by the batch calls, see `set_thread_policy`.
+ */
+typedef struct ThreadPolicy {
+  /**
+   * Maximum number of threads used to encode a batch. Zero means one per CPU (or one per CPU in `cpus`,
+   * if given).
+   */
+  uint32_t max_threads;
+  /**
+   * If not null, the `num_cpus` CPUs (as numbered by the OS) the threads are pinned to. Only supported on Linux.
+   */
+  const uint32_t *cpus;
+  uint32_t num_cpus;
+} ThreadPolicy;
+
+/**
+ * SlowInput describes a text reported to the slow-input hook.
+ */
+typedef struct SlowInput {
+  /**
+   * Length of the text in bytes.
+   */
+  uint64_t len;
+  /**
+   * Number of tokens the text was encoded to (before padding).
+   */
+  uint64_t num_tokens;
+  /**
+   * Time it took to encode the text.
+   */
+  uint64_t duration_ns;
+  /**
+   * The first `sample_len` bytes of the text (UTF-8, truncated at a character boundary): only valid during
+   * the callback.
+   */
+  const uint8_t *sample;
+  uint64_t sample_len;
+} SlowInput;
+
+/**
+ * SlowInputCallback is called (from the thread encoding the text) with each slow input, and the `user_data`
+ * given to `set_slow_input_hook`.
+ */
+typedef void (*SlowInputCallback)(void *user_data, const struct SlowInput *input);
+
+/**
+ * VocabExtensionParams are the options of `extend_vocabulary`.
+ */
+typedef struct VocabExtensionParams {
+  uint32_t num_merges;
+  uint32_t min_frequency;
+} VocabExtensionParams;
+
+/**
+ * TrainParams are the options of `train_from_files`.
+ */
+typedef struct TrainParams {
+  uint32_t model;
+  uint32_t vocab_size;
+  uint32_t min_frequency;
+  const char *const *special_tokens;
+  uint32_t num_special_tokens;
+  const char *initial_alphabet;
+  uint32_t limit_alphabet;
+  const char *unk_token;
+} TrainParams;
+
+/**
+ * Vocab is a list of `len` (token, id) pairs returned by the vocabulary queries (e.g. `get_added_vocab`),
+ * as parallel arrays `tokens` and `ids`, sorted by id.
+ *
+ * Either the arrays or `error` will be defined. Once it is no longer used, free it with `free_vocab`.
+ */
+typedef struct Vocab {
+  uint64_t len;
+  char **tokens;
+  uint32_t *ids;
+  char *error;
+} Vocab;
+
+/**
+ * PackedTokens holds `len` token strings packed in one buffer: the token `i` is made of the bytes
+ * `bytes[offsets[i]..offsets[i+1]]`, so `offsets` has `len + 1` entries, and `bytes` has `offsets[len]` bytes.
+ *
+ * Either the arrays or `error` will be defined. Once it is no longer used, free it with `free_packed_tokens`.
+ */
+typedef struct PackedTokens {
+  uint64_t len;
+  uint8_t *bytes;
+  uint64_t *offsets;
+  char *error;
+} PackedTokens;
+
+/**
+ * AddedTokenAttributes are the attributes of an added token to update with `set_added_token_attributes`.
+ * Each is either 0 -> unchanged, 1 -> true or 2 -> false.
+ */
+typedef struct AddedTokenAttributes {
+  uint8_t single_word;
+  uint8_t lstrip;
+  uint8_t rstrip;
+  uint8_t normalized;
+  uint8_t special;
+} AddedTokenAttributes;
+
+/**
+ * VocabEntryCallback is called by `for_each_vocab_entry` with each (token, id) of the vocabulary, and the
+ * `user_data` given to it: the `len` bytes of `token` are only valid during the call. It returns true to
+ * continue, or false to stop the iteration.
+ */
+typedef bool (*VocabEntryCallback)(void *user_data, const uint8_t *token, uint64_t len, uint32_t id);
+
+/**
+ * This function returns a Tokenizer reference to Golang (casted as a C `void*` in the `value` field) or
+ * an error.
+ *
+ * The parameter `bytes` should be the json contents for a `tokenizer.json` file, with its definitions (symbols, 
+ * truncation parameters, etc.)
+ *
+ * Tokenizers loaded from identical contents share the parsed Tokenizer, so loading the same tokenizer many
+ * times (e.g. once per worker) only holds it in memory once. Each handle can still be configured independently:
+ * it gets its own copy of the Tokenizer the first time it is modified.
+ *
+ * # Safety
+ *
+ * The caller has ownership of `bytes` and of the returned `Tokenizer`.
+ */
+struct PointerOrError from_bytes(const uint8_t *bytes,
+                                 uint32_t len);
+
+/**
+ * Frees a Tokenizer allocated by Rust and returned to Golang.
+ *
+ * It is the same as `tokenizer_release`: the Tokenizer is only freed once all references
+ * acquired with `tokenizer_retain` are also released.
+ *
+ * # Safety
+ *
+ * `ptr` must have been returned by `from_bytes`, and it must not be used after this call.
+ */
+void free_tokenizer(struct TokenizerHandle *ptr);
+
+/**
+ * Frees a `*C.char` allocated by Rust and return to Golang.
+ *
+ * # Safety
+ *
+ * `ptr` must have been allocated by Rust, and it must not be used after this call.
+ */
+void free_string(char *ptr);
+
+/**
+ * Frees a `BytesOrError` returned by Rust to Golang.
+ *
+ * # Safety
+ *
+ * `bytes` must have been returned by Rust, and it must not be used after this call.
+ */
+void free_bytes(struct BytesOrError bytes);
+
+/**
+ * encode_batch_arena encodes a batch of UTF-8 strings (given as in `encode_batch_bytes`), like
+ * `encode_batch_bytes_v2`, but allocates all the results (the Buffers, all their arrays and the token
+ * strings) in one single block of memory: so building the results takes one allocation, and freeing them
+ * takes one call, independent of the batch size.
+ *
+ * The results must be freed with `free_encode_results_arena` -- not with `free_encode_results_v2`. The
+ * `tokens_arena` field of the Buffers is not used.
+ */
+struct EncodeResultsV2 encode_batch_arena(struct TokenizerHandle *tokenizer_ptr,
+                                          uint64_t num_messages,
+                                          const uint8_t *const *messages,
+                                          const uint32_t *lengths,
+                                          struct EncodeParams options);
+
+/**
+ * Frees the results returned by `encode_batch_arena`, in one deallocation.
+ *
+ * # Safety
+ *
+ * `results` must have been returned by `encode_batch_arena`, and it must not be used after this call.
+ */
+void free_encode_results_arena(struct EncodeResultsV2 results);
+
+/**
+ * result_set_new creates an empty ResultSet, owned by the caller: free it with `result_set_free`.
+ */
+struct ResultSet *result_set_new(void);
+
+/**
+ * result_set_free frees the ResultSet, and with it the last results written into it.
+ *
+ * # Safety
+ *
+ * `result_set` must have been returned by `result_set_new`, and it must not be used after this call.
+ */
+void result_set_free(struct ResultSet *result_set);
+
+/**
+ * encode_batch_into encodes a batch of UTF-8 strings (given as in `encode_batch_bytes`), like
+ * `encode_batch_arena`, but writes the results into the `result_set`, reusing its memory (it only grows
+ * if the results don't fit).
+ *
+ * The results returned are owned by the `result_set`: they are valid until the next call with the same
+ * `result_set`, or until it is freed, and must not be freed otherwise -- except for the `error`, if set,
+ * which must be freed with `free_string`.
+ *
+ * The `result_set` must not be used concurrently by different threads.
+ *
+ * # Safety
+ *
+ * `result_set` must have been returned by `result_set_new`.
+ */
+struct EncodeResultsV2 encode_batch_into(struct TokenizerHandle *tokenizer_ptr,
+                                         struct ResultSet *result_set,
+                                         uint64_t num_messages,
+                                         const uint8_t *const *messages,
+                                         const uint32_t *lengths,
+                                         struct EncodeParams options);
+
+/**
+ * encode_batch_arrow encodes a batch of UTF-8 strings (given as in `encode_batch_bytes`), and exports the
+ * encodings as an Arrow struct array, through the Arrow C Data Interface: one row per sequence, with the
+ * columns `ids` and, if requested with their `ENCODE_RETURN_*` flag, `type_ids`, `special_tokens_mask` and
+ * `attention_mask` -- each a `large_list<uint32>` -- and `offsets`, a `large_list<fixed_size_list<uint32>[2]>`.
+ *
+ * The array and its type are written to `array` and `schema`, to be imported by an Arrow library, which
+ * takes ownership of them: they are freed by their `release` callback, as with any Arrow C Data Interface
+ * export. Only available with the `arrow` feature (enabled by default).
+ *
+ * It returns null if ok, or a string with an error message (owned by caller), to be freed with `free_string`.
+ *
+ * # Safety
+ *
+ * `messages` and `lengths` must point to `num_messages` strings and lengths, and `array` and `schema` to
+ * writable (uninitialized) structs.
+ */
+char *encode_batch_arrow(struct TokenizerHandle *tokenizer_ptr,
+                         uint64_t num_messages,
+                         const uint8_t *const *messages,
+                         const uint32_t *lengths,
+                         struct EncodeParams options,
+                         struct ArrowArray *array,
+                         struct ArrowSchema *schema);
+
+/**
+ * apply_chat_template renders the chat template of the tokenizer config (see `set_tokenizer_config`) with the
+ * `len` bytes of `messages`, a JSON array of messages (objects with the `role` and `content`), and encodes
+ * the rendered text (without adding special tokens: the template adds them). The special tokens of the
+ * config (`bos_token`, `eos_token`, ...) are available to the template.
+ *
+ * The `params` (if not null) select the template, and, as in transformers:
+ *
+ * - `add_generation_prompt`: the template adds the start of an assistant message, for the model to
+ *   generate the response. Its token ids are the `generation_prompt_ids` of the result.
+ * - `continue_final_message`: the chat is truncated right after the content of the final message, so the
+ *   model continues it (prefill). It can't be combined with `add_generation_prompt`.
+ * - `variables`: a JSON object with extra variables for the template (e.g. `date_string`, `tools`, or any
+ *   context referenced by community templates), as the keyword arguments of transformers. They can replace
+ *   the special tokens, but not `messages` or `add_generation_prompt`.
+ *
+ * The result must be freed with `free_chat_template_result`.
+ *
+ * # Safety
+ *
+ * `messages` must point to `len` bytes, and `params` must be null or point to a ChatTemplateParams.
+ */
+struct ChatTemplateResult apply_chat_template(struct TokenizerHandle *tokenizer_ptr,
+                                              const uint8_t *messages,
+                                              uint32_t len,
+                                              const struct ChatTemplateParams *params);
+
+/**
+ * Frees the ChatTemplateResult returned by `apply_chat_template`.
+ *
+ * # Safety
+ *
+ * `result` must have been returned by `apply_chat_template`, and it must not be used after this call.
+ */
+void free_chat_template_result(struct ChatTemplateResult result);
+
+/**
+ * set_sentencepiece_options sets the options to match the reference sentencepiece library, for
+ * SentencePiece-derived tokenizers:
+ *
+ * - `add_dummy_prefix`: whether a space is prepended to the text (and removed when decoding), either by the
+ *   Metaspace pre-tokenizer or, for Llama-style pipelines, by the normalizer.
+ * - `remove_extra_whitespaces`: whether leading, trailing and repeated spaces are removed before encoding.
+ *
+ * The original pipeline is kept, so resetting the options to their defaults restores it.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+ * The returned string needs to be freed with `free_string`.
+ */
+char *set_sentencepiece_options(struct TokenizerHandle *tokenizer_ptr,
+                                const struct SentencePieceParams *params);
+
+/**
+ * set_precompiled_charsmap installs the `len` bytes of a SentencePiece precompiled charsmap (the
+ * `normalizer_spec.precompiled_charsmap` of a SentencePiece model, e.g. mT5 or XLM-R) as the Precompiled
+ * normalizer of the tokenizer, for pipelines assembled from raw SentencePiece assets: it replaces the current
+ * Precompiled normalizer, if any, or otherwise it is prepended to the current normalizers. An empty charsmap
+ * (`len` 0) removes the Precompiled normalizers, as SentencePiece does for the identity normalization.
+ *
+ * The charsmap is validated, so a corrupted one is reported as an error. The compatibility options (see
+ * `set_sentencepiece_options`) are kept.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+ * The returned string needs to be freed with `free_string`.
+ *
+ * # Safety
+ *
+ * `precompiled_charsmap` must point to `len` bytes.
+ */
+char *set_precompiled_charsmap(struct TokenizerHandle *tokenizer_ptr,
+                               const uint8_t *precompiled_charsmap,
+                               uint32_t len);
+
+/**
+ * set_legacy_llama_mode sets the compatibility mode with the original (slow) Llama/Mistral tokenizers, for
+ * exact parity with transformers:
+ *
+ * - 0: as configured (default).
+ * - 1: `legacy=True`: the dummy prefix (a space) is added to the text after each special token, so e.g.
+ *   "<s>Hello" is encoded as "<s>", "▁Hello".
+ * - 2: `legacy=False`: the dummy prefix is only added at the start of the text.
+ *
+ * It has no effect if the dummy prefix is disabled (see `set_sentencepiece_options`).
+ *
+ * It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+ * The returned string needs to be freed with `free_string`.
+ */
+char *set_legacy_llama_mode(struct TokenizerHandle *tokenizer_ptr,
+                            uint8_t mode);
+
+/**
+ * set_add_bos_token enables or disables the insertion of the BOS (beginning of sequence) token, by editing the
+ * post-processor template (Bert and Roberta post-processors are converted to the equivalent template):
+ *
+ * - `mode`: 0 -> as configured (default), 1 -> enabled, 2 -> disabled.
+ * - `token_id`: the BOS token, if it's not configured in the post-processor (or to replace it), or -1 to use the
+ *   configured one.
+ *
+ * The BOS tokens are the special tokens inserted before the (first) sequence. The original post-processor is kept,
+ * so resetting `mode` to 0 restores it.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+ * The returned string needs to be freed with `free_string`.
+ */
+char *set_add_bos_token(struct TokenizerHandle *tokenizer_ptr,
+                        uint8_t mode,
+                        int64_t token_id);
+
+/**
+ * set_add_eos_token enables or disables the insertion of the EOS (end of sequence) token, the same way as
+ * `set_add_bos_token`: the EOS tokens are the special tokens inserted after the (last) sequence.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+ * The returned string needs to be freed with `free_string`.
+ */
+char *set_add_eos_token(struct TokenizerHandle *tokenizer_ptr,
+                        uint8_t mode,
+                        int64_t token_id);
+
+/**
+ * compile returns the tokenizer compiled to a compact binary format, to be loaded with `from_compiled`: it
+ * holds the same definitions as its `tokenizer.json` (including the padding, truncation and added tokens),
+ * but it is smaller, and faster to load, since no JSON needs to be parsed.
+ *
+ * The per-tokenizer options of the binding (e.g. deterministic mode) are not included, as in the
+ * `tokenizer.json` file.
+ *
+ * The format is versioned, but it depends on the version of the library: compiled tokenizers should be
+ * rebuilt (from their `tokenizer.json`) when the library is upgraded.
+ *
+ * The result must be freed with `free_bytes`.
+ */
+struct BytesOrError compile(struct TokenizerHandle *tokenizer_ptr);
+
+/**
+ * from_compiled loads a tokenizer compiled with `compile` from the `len` bytes in `bytes`, and returns it in
+ * the `value` field, or an error -- as `from_bytes`, it is shared with the other handles loaded from the same
+ * contents.
+ *
+ * # Safety
+ *
+ * `bytes` must point to `len` bytes.
+ */
+struct PointerOrError from_compiled(const uint8_t *bytes,
+                                    uint64_t len);
+
+/**
+ * set_tokenizer_config sets the settings of the tokenizer read from the `len` bytes of `config`, the contents
+ * of a `tokenizer_config.json` file, replacing any previous ones. If `config` is null, they are cleared.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller), to be freed with `free_string`.
+ *
+ * # Safety
+ *
+ * `config` must be null or point to `len` bytes.
+ */
+char *set_tokenizer_config(struct TokenizerHandle *tokenizer_ptr,
+                           const uint8_t *config,
+                           uint32_t len);
+
+/**
+ * get_chat_template returns the (Jinja) chat template with the given null-terminated `name`, or the default
+ * one if `name` is null, from the `chat_template` setting of the tokenizer config (see `set_tokenizer_config`).
+ * The templates are not rendered: that is left to the host.
+ *
+ * The template is returned in a BytesOrError, that must be freed with `free_bytes`. It is an error if the
+ * tokenizer has no such template.
+ *
+ * # Safety
+ *
+ * `name` must be null or a null-terminated string.
+ */
+struct BytesOrError get_chat_template(struct TokenizerHandle *tokenizer_ptr,
+                                      const char *name);
+
+/**
+ * get_chat_template_names returns the names of the chat templates of the tokenizer config (see
+ * `set_tokenizer_config`) as a JSON array of strings, in order: a single template is named "default". It is
+ * empty if there is no tokenizer config, or it has no chat template.
+ *
+ * The result is returned in a BytesOrError, that must be freed with `free_bytes`.
+ */
+struct BytesOrError get_chat_template_names(struct TokenizerHandle *tokenizer_ptr);
+
+/**
+ * apply_tokenizer_config applies the relevant settings of the `config_len` bytes of `config`, the contents of
+ * the `tokenizer_config.json` file of the model, and of the `special_tokens_map_len` bytes of
+ * `special_tokens_map` (if not null), the contents of its `special_tokens_map.json` file, to the tokenizer --
+ * as transformers does when loading a model directory. The settings are also kept, as with
+ * `set_tokenizer_config`.
+ *
+ * The settings applied are:
+ *
+ * - The special tokens (`bos_token`, `eos_token`, ..., and `additional_special_tokens`): those missing from
+ *   the tokenizer, or not special in it, are added as special tokens. Those of `special_tokens_map.json`
+ *   replace those of `tokenizer_config.json`.
+ * - `padding_side`: the direction of the tokenizer padding, if set, and the default padding direction
+ *   otherwise (see `encode_batch_padded`). And `pad_token` is the token of the tokenizer padding, if set.
+ * - `model_max_length` (with `truncation_side`): the truncation `max_length`, if the tokenizer has no
+ *   truncation.
+ *
+ * It returns a JSON object reporting the settings applied, with the keys `padding_side`,
+ * `truncation_max_length` and `pad_token` (omitted if not applied), and `special_tokens_added` (an array),
+ * in a BytesOrError that must be freed with `free_bytes`.
+ *
+ * # Safety
+ *
+ * `config` must point to `config_len` bytes, and `special_tokens_map` must be null or point to
+ * `special_tokens_map_len` bytes.
+ */
+struct BytesOrError apply_tokenizer_config(struct TokenizerHandle *tokenizer_ptr,
+                                           const uint8_t *config,
+                                           uint32_t config_len,
+                                           const uint8_t *special_tokens_map,
+                                           uint32_t special_tokens_map_len);
+
+/**
+ * vocab_size reads the vocab size into `size`.
+ * It returns null if ok, or a string with an error message (owned by caller) if something went wrong, e.g. an
+ * invalid tokenizer. The returned string needs to be freed with `free_string`.
+ *
+ * # Safety
+ *
+ * `size` must point to a u32.
+ */
+char *vocab_size(struct TokenizerHandle *ptr,
+                 uint32_t *size);
+
+/**
+ * set_truncation modifies the tokenizer with the given truncation parameters.
+ * It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+ * The returned string needs to be freed with `free_string`.
+ */
+char *set_truncation(struct TokenizerHandle *tokenizer_ptr,
+                     const struct TruncationParams *params);
+
+/**
+ * get_truncation gets the current Tokenizer's truncation parameters.
+ *
+ * If there are truncation parameters configured in the Tokenizer, the values are read into the `params` passed,
+ * and `is_set` is set to true. If there are no truncation values configured, `is_set` is set to false.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller) if something went wrong, e.g. an
+ * invalid tokenizer. The returned string needs to be freed with `free_string`.
+ *
+ * # Safety
+ *
+ * `params` must point to a TruncationParams, and `is_set` to a bool.
+ */
+char *get_truncation(struct TokenizerHandle *tokenizer_ptr,
+                     struct TruncationParams *params,
+                     bool *is_set);
+
+/**
+ * set_padding modifies the tokenizer with the given padding parameters.
+ * It doesn't return anything: it is a no-op on frozen tokenizers (see `freeze`), or if `pad_token` is not
+ * valid UTF-8.
+ */
+void set_padding(struct TokenizerHandle *tokenizer_ptr,
+                 const struct PaddingParams *params);
+
+/**
+ * get_padding gets the current Tokenizer's padding parameters.
+ *
+ * If there are padding parameters configured in the Tokenizer, the values are read into the `params` passed,
+ * and `is_set` is set to true. The `params.pad_token` ownership is transferred to the caller, who must free it
+ * after use (see `free_string()`). If there are no padding values configured, `is_set` is set to false.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller) if something went wrong, e.g. an
+ * invalid tokenizer. The returned string needs to be freed with `free_string`.
+ *
+ * # Safety
+ *
+ * `params` must point to a PaddingParams, and `is_set` to a bool.
+ */
+char *get_padding(struct TokenizerHandle *tokenizer_ptr,
+                  struct PaddingParams *params,
+                  bool *is_set);
+
+/**
+ * config_snapshot returns a snapshot of the configuration of the tokenizer in the `value` field: padding,
+ * truncation, added tokens and the per-tokenizer options (e.g. deterministic mode, compatibility options,
+ * `encode_special_tokens`). It can be restored with `config_restore`, e.g. to temporarily reconfigure a
+ * tokenizer while handling a request, and reliably roll back, also on error paths.
+ *
+ * The caller owns the returned snapshot, and should free it with `free_config_snapshot`.
+ */
+struct PointerOrError config_snapshot(struct TokenizerHandle *ptr);
+
+/**
+ * config_restore restores the configuration of the `snapshot` (see `config_snapshot`) in the tokenizer.
+ * The snapshot is not consumed: it can be restored again, and must still be freed with
+ * `free_config_snapshot`.
+ *
+ * It is all or nothing: if it fails, the tokenizer is not modified.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+ * The returned string needs to be freed with `free_string`.
+ *
+ * # Safety
+ *
+ * `snapshot` must have been returned by `config_snapshot`, and not freed.
+ */
+char *config_restore(struct TokenizerHandle *ptr,
+                     const struct ConfigSnapshot *snapshot);
+
+/**
+ * free_config_snapshot frees a snapshot returned by `config_snapshot`.
+ *
+ * # Safety
+ *
+ * `snapshot` must have been returned by `config_snapshot`, or be null, and it must not be used after this call.
+ */
+void free_config_snapshot(struct ConfigSnapshot *snapshot);
+
+/**
+ * has_normalizer returns whether the tokenizer has a normalizer. It returns false for an invalid tokenizer.
+ */
+bool has_normalizer(struct TokenizerHandle *ptr);
+
+/**
+ * has_pre_tokenizer returns whether the tokenizer has a pre-tokenizer. It returns false for an invalid tokenizer.
+ */
+bool has_pre_tokenizer(struct TokenizerHandle *ptr);
+
+/**
+ * has_post_processor returns whether the tokenizer has a post-processor. It returns false for an invalid
+ * tokenizer.
+ */
+bool has_post_processor(struct TokenizerHandle *ptr);
+
+/**
+ * has_decoder returns whether the tokenizer has a decoder. It returns false for an invalid tokenizer.
+ */
+bool has_decoder(struct TokenizerHandle *ptr);
+
+/**
+ * has_padding returns whether padding is configured in the tokenizer. It returns false for an invalid tokenizer.
+ */
+bool has_padding(struct TokenizerHandle *ptr);
+
+/**
+ * has_truncation returns whether truncation is configured in the tokenizer. It returns false for an invalid
+ * tokenizer.
+ */
+bool has_truncation(struct TokenizerHandle *ptr);
+
+/**
+ * conformance_vectors returns the conformance vectors of the `num_probes` UTF-8 strings `probes` (given as in
+ * `encode_batch_bytes`) encoded by the tokenizer, to be compared with the ones of other bindings or releases:
+ * the bytes of a canonical (compact, with the keys in a fixed order) JSON object with the `version` of the
+ * format, `add_special_tokens`, and the `vectors`, with the `text`, `ids`, `tokens` and byte `offsets` (pairs
+ * of start and end) of each probe. The settings of the tokenizer (e.g. truncation and padding) are applied.
+ *
+ * The result must be freed with `free_bytes`.
+ *
+ * # Safety
+ *
+ * `probes` and `lengths` must point to `num_probes` strings and lengths.
+ */
+struct BytesOrError conformance_vectors(struct TokenizerHandle *tokenizer_ptr,
+                                        uint32_t num_probes,
+                                        const uint8_t *const *probes,
+                                        const uint32_t *lengths,
+                                        bool add_special_tokens);
+
+/**
+ * corpus_open opens the text file at the null-terminated `path` -- decompressing it if it is gzip-compressed --
+ * and starts encoding its records (split as configured by `corpus_options`) with the tokenizer and the given
+ * EncodeParams, in a background thread. It returns the `CorpusReader *` in the `value` field, or an error.
+ *
+ * Take the encoded chunks with `corpus_next`. The reader holds a reference to the tokenizer (see
+ * `tokenizer_retain`), so it can be freed independently. Free the reader with `corpus_free`.
+ *
+ * # Safety
+ *
+ * `path` must be a null-terminated string, and `corpus_options` must point to a CorpusOptions.
+ */
+struct PointerOrError corpus_open(struct TokenizerHandle *tokenizer_ptr,
+                                  const char *path,
+                                  const struct CorpusOptions *corpus_options,
+                                  struct EncodeParams options);
+
+/**
+ * corpus_next returns the encodings of the next chunk of records of the corpus, one Buffer per record, in the
+ * order of the corpus. It blocks until the chunk is encoded. Ownership of the results is transferred to the
+ * caller, who must free them with `free_encode_results_v2`.
+ *
+ * At the end of the corpus it returns empty results (`len == 0` and no error). After an error (e.g. a
+ * record that is not valid UTF-8), it also returns empty results.
+ *
+ * # Safety
+ *
+ * `reader` must have been returned by `corpus_open`, and not yet freed.
+ */
+struct EncodeResultsV2 corpus_next(struct CorpusReader *reader);
+
+/**
+ * corpus_free stops reading the corpus, and frees the reader, along with the chunks encoded ahead.
+ *
+ * # Safety
+ *
+ * `reader` must have been returned by `corpus_open`, and it must not be used after this call.
+ */
+void corpus_free(struct CorpusReader *reader);
+
+/**
+ * unigram_lattice returns the segmentation lattices of the `text` for a tokenizer with a Unigram model, as
+ * JSON in a BytesOrError, that must be freed with `free_bytes`.
+ *
+ * The text is normalized and pre-tokenized (added tokens are not handled), and one lattice is built for each
+ * pre-token. The JSON is an object with the key `pre_tokens`, an array with one object per pre-token, with:
+ *
+ * - `text`: the normalized pre-token.
+ * - `offsets`: `[start, end]` byte offsets of the pre-token in the original `text`.
+ * - `nodes`: array of the pieces matching a span of the pre-token, as objects with the keys `id`, `piece`,
+ *   `start` and `end` (byte offsets in the pre-token `text`), and `score` (the log-probability of the piece,
+ *   or the penalized score of the unknown token).
+ * - `arcs`: array of `[from, to]` indices of nodes, for each node `from` that ends where node `to` starts.
+ * - `best`: indices of the nodes of the best (Viterbi) segmentation.
+ *
+ * This is meant for research and debugging: the lattices are built without caching, on each call.
+ *
+ * # Safety
+ *
+ * `text` must be a valid C string.
+ */
+struct BytesOrError unigram_lattice(struct TokenizerHandle *tokenizer_ptr,
+                                    const char *text);
+
+/**
+ * Encodes string using given tokenizer and EncodeParams.
+ */
+struct EncodeResults encode(struct TokenizerHandle *tokenizer_ptr,
+                            const char *message,
+                            struct EncodeParams options);
+
+/**
+ * Encodes the UTF-8 string given by `bytes` and `len` using given tokenizer and EncodeParams.
+ *
+ * Different from `encode`, the string doesn't need to be NUL-terminated, and it may contain NUL
+ * characters: so the caller can pass the string contents without copying them to a C string.
+ */
+struct EncodeResults encode_bytes(struct TokenizerHandle *tokenizer_ptr,
+                                  const uint8_t *bytes,
+                                  uint32_t len,
+                                  struct EncodeParams options);
+
+/**
+ * Same as `encode`, but returns an `EncodeResultsV2`, which must be freed with `free_encode_results_v2`.
+ */
+struct EncodeResultsV2 encode_v2(struct TokenizerHandle *tokenizer_ptr,
+                                 const char *message,
+                                 struct EncodeParams options);
+
+/**
+ * Encode a batch of strings using given tokenizer and EncodeParams.
+ * The results are returned in the same order as the `messages`.
+ */
+struct EncodeResults encode_batch(struct TokenizerHandle *tokenizer_ptr,
+                                  uint32_t num_messages,
+                                  const char *const *messages,
+                                  struct EncodeParams options);
+
+/**
+ * Encode a batch of UTF-8 strings, each given by a pointer in `messages` and its length in `lengths`,
+ * using given tokenizer and EncodeParams.
+ *
+ * Different from `encode_batch`, the strings don't need to be NUL-terminated, and they may contain NUL
+ * characters: so the caller can pass the strings contents without copying them to C strings.
+ */
+struct EncodeResults encode_batch_bytes(struct TokenizerHandle *tokenizer_ptr,
+                                        uint32_t num_messages,
+                                        const uint8_t *const *messages,
+                                        const uint32_t *lengths,
+                                        struct EncodeParams options);
+
+/**
+ * Encodes a batch mixing single texts and pairs of texts (e.g. heterogeneous requests, where only some are
+ * sentence pairs) in one parallel call, using given tokenizer and EncodeParams. Input `i` is the UTF-8 text
+ * given by `messages[i]` and `lengths[i]` and, if `pairs[i]` is not null, the second text of a pair, given
+ * by `pairs[i]` and `pair_lengths[i]`. `pairs` (and `pair_lengths`) can be null if there are no pairs.
+ *
+ * The type ids and special tokens of the pairs are those of the pair template of the post-processor, and the
+ * offsets of the tokens of the second text of a pair are relative to it.
+ *
+ * The results are returned in the same order as the inputs, and must be freed with `free_encode_results_v2`.
+ *
+ * # Safety
+ *
+ * `messages` and `lengths` must point to `num_messages` elements, as `pairs` and `pair_lengths` if not null.
+ */
+struct EncodeResultsV2 encode_batch_mixed(struct TokenizerHandle *tokenizer_ptr,
+                                          uint64_t num_messages,
+                                          const uint8_t *const *messages,
+                                          const uint32_t *lengths,
+                                          const uint8_t *const *pairs,
+                                          const uint32_t *pair_lengths,
+                                          struct EncodeParams options);
+
+/**
+ * Encodes the pair of UTF-8 texts given by `text_a` (with `len_a` bytes) and `text_b` (with `len_b` bytes),
+ * e.g. a (question, context) pair for cross-encoders and QA models, using given tokenizer and EncodeParams.
+ *
+ * The type ids and special tokens are those of the pair template of the post-processor, the truncation
+ * strategy decides which of the texts is truncated, and the offsets of the tokens of `text_b` are relative to
+ * it. The result must be freed with `free_encode_results_v2`.
+ *
+ * # Safety
+ *
+ * `text_a` and `text_b` must point to `len_a` and `len_b` bytes.
+ */
+struct EncodeResultsV2 encode_pair(struct TokenizerHandle *tokenizer_ptr,
+                                   const uint8_t *text_a,
+                                   uint32_t len_a,
+                                   const uint8_t *text_b,
+                                   uint32_t len_b,
+                                   struct EncodeParams options);
+
+/**
+ * Encodes a batch of pairs of texts in one parallel call, using given tokenizer and EncodeParams: pair `i` is
+ * made of the UTF-8 texts given by `texts_a[i]` and `lengths_a[i]`, and by `texts_b[i]` and `lengths_b[i]`.
+ * Each pair is encoded as in `encode_pair` -- see `encode_batch_mixed` to mix pairs and single texts.
+ *
+ * The results are returned in the same order as the pairs, and must be freed with `free_encode_results_v2`.
+ *
+ * # Safety
+ *
+ * `texts_a`, `lengths_a`, `texts_b` and `lengths_b` must point to `num_pairs` elements.
+ */
+struct EncodeResultsV2 encode_batch_pairs(struct TokenizerHandle *tokenizer_ptr,
+                                          uint64_t num_pairs,
+                                          const uint8_t *const *texts_a,
+                                          const uint32_t *lengths_a,
+                                          const uint8_t *const *texts_b,
+                                          const uint32_t *lengths_b,
+                                          struct EncodeParams options);
+
+/**
+ * Same as `encode_batch`, but it takes a u64 `num_messages` and returns an `EncodeResultsV2`, with
+ * u64 counts. It must be freed with `free_encode_results_v2`.
+ */
+struct EncodeResultsV2 encode_batch_v2(struct TokenizerHandle *tokenizer_ptr,
+                                       uint64_t num_messages,
+                                       const char *const *messages,
+                                       struct EncodeParams options);
+
+/**
+ * This function is release Vec<Buffer> from Rust returned to Golang by `encode_batch`.
+ */
+void free_encode_results(struct EncodeResults results);
+
+/**
+ * This function is release Vec<Buffer> from Rust returned to Golang by `encode_v2` and `encode_batch_v2`.
+ */
+void free_encode_results_v2(struct EncodeResultsV2 results);
+
+/**
+ * Encodes the UTF-8 string given by `bytes` and `len` using given tokenizer, and returns an EncodingHandle
+ * to the result in the `value` field, instead of converting it to a Buffer. Only the `options` flags that
+ * change how the text is encoded are used (e.g. ENCODE_ADD_SPECIAL_TOKENS or ENCODE_WITH_OFFSETS_CHAR_MODE):
+ * the fields of the Buffer are selected by `encoding_to_buffer`.
+ *
+ * The caller owns the returned handle, and should free it with `free_encoding`.
+ *
+ * # Safety
+ *
+ * `bytes` must point to `len` bytes.
+ */
+struct PointerOrError encode_to_handle(struct TokenizerHandle *tokenizer_ptr,
+                                       const uint8_t *bytes,
+                                       uint32_t len,
+                                       struct EncodeParams options);
+
+/**
+ * Converts the encoding to a Buffer, returned as an EncodeResultsV2 with one result, with the fields requested
+ * in `options`, as `encode_v2` does. The offsets mode flags must be the same used to encode it, and the
+ * debug scores of ENCODE_RETURN_DEBUG_INFO and the word starts of ENCODE_RETURN_WORD_STARTS are not available
+ * (there is no tokenizer).
+ *
+ * `text` (with `len` bytes) is the text encoded: it can be null, to use the text kept in the handle.
+ *
+ * The results must be freed with `free_encode_results_v2`.
+ *
+ * # Safety
+ *
+ * `encoding_ptr` must have been returned by `encode_to_handle` or `encoding_deserialize`, and `text` must be
+ * null or point to `len` bytes.
+ */
+struct EncodeResultsV2 encoding_to_buffer(const struct EncodingHandle *encoding_ptr,
+                                          const uint8_t *text,
+                                          uint32_t len,
+                                          struct EncodeParams options);
+
+/**
+ * Serializes the encoding (ids, tokens, offsets, masks, word ids and overflowing windows), with the text
+ * encoded, to a MessagePack blob, which can be stored or sent to another process, and loaded with
+ * `encoding_deserialize`.
+ *
+ * The returned bytes must be freed with `free_bytes`.
+ *
+ * # Safety
+ *
+ * `encoding_ptr` must have been returned by `encode_to_handle` or `encoding_deserialize`.
+ */
+struct BytesOrError encoding_serialize(const struct EncodingHandle *encoding_ptr);
+
+/**
+ * Loads an encoding serialized with `encoding_serialize` from the `len` bytes, and returns an
+ * EncodingHandle to it in the `value` field.
+ *
+ * The caller owns the returned handle, and should free it with `free_encoding`.
+ *
+ * # Safety
+ *
+ * `bytes` must point to `len` bytes.
+ */
+struct PointerOrError encoding_deserialize(const uint8_t *bytes, uint32_t len);
+
+/**
+ * Appends the `len` bytes of UTF-8 `text` to the text of the encoding, and updates the encoding as if the whole
+ * text had been encoded with the `tokenizer_ptr` and the same EncodeParams: only the last word of the
+ * encoding and the appended text are encoded again, and the post-processing (special tokens, truncation and
+ * padding) is redone. It is meant for interactive editors that tokenize a document as the user types.
+ *
+ * The tokenizer must be the one used to encode it. The result is the same as encoding the whole text for the
+ * usual pipelines, where pre-tokens (words) don't depend on the text after them.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller), to be freed with `free_string`.
+ *
+ * # Safety
+ *
+ * `encoding_ptr` must have been returned by `encode_to_handle` or `encoding_deserialize`, and `text` must
+ * point to `len` bytes.
+ */
+char *encoding_append(struct TokenizerHandle *tokenizer_ptr,
+                      struct EncodingHandle *encoding_ptr,
+                      const uint8_t *text,
+                      uint32_t len);
+
+/**
+ * Frees an EncodingHandle returned by `encode_to_handle` or `encoding_deserialize`.
+ *
+ * # Safety
+ *
+ * `encoding_ptr` must have been returned by Rust, and it must not be used after this call.
+ */
+void free_encoding(struct EncodingHandle *encoding_ptr);
+
+/**
+ * tokenizer_to_bytes returns the `tokenizer.json` contents of the tokenizer, with all its modifications (e.g.
+ * truncation, padding and added tokens), to be loaded back with `from_bytes`. If `pretty` is set, the JSON is
+ * indented.
+ *
+ * The per-tokenizer options of the binding (e.g. deterministic mode) are not included: they are not part of
+ * the `tokenizer.json` format.
+ *
+ * The result is returned in a BytesOrError, that must be freed with `free_bytes`.
+ */
+struct BytesOrError tokenizer_to_bytes(struct TokenizerHandle *tokenizer_ptr,
+                                       bool pretty);
+
+/**
+ * tokenizer_save writes the `tokenizer.json` contents of the tokenizer (see `tokenizer_to_bytes`) to the file
+ * at `path`, replacing it if it exists.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+ * The returned string needs to be freed with `free_string`.
+ *
+ * # Safety
+ *
+ * `path` must be a valid C string.
+ */
+char *tokenizer_save(struct TokenizerHandle *tokenizer_ptr,
+                     const char *path,
+                     bool pretty);
+
+/**
+ * export_legacy_files writes the vocabulary (and merges) of the model in the classic formats to the
+ * existing directory `dir`, for tools that can't read `tokenizer.json`:
+ *
+ * - BPE: `vocab.json` and `merges.txt`.
+ * - WordPiece: `vocab.txt`.
+ * - WordLevel: `vocab.json`.
+ * - Unigram: `unigram.json`.
+ *
+ * If `prefix` is not null, the file names are prefixed with `<prefix>-`. Only the model is exported: added
+ * tokens and the other components of the pipeline (normalizer, pre-tokenizer, etc.) are not.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+ * The returned string needs to be freed with `free_string`.
+ *
+ * # Safety
+ *
+ * `dir` must be a valid C string, and `prefix` either null or a valid C string.
+ */
+char *export_legacy_files(struct TokenizerHandle *tokenizer_ptr,
+                          const char *dir,
+                          const char *prefix);
+
+/**
+ * export_tiktoken_ranks returns the mergeable ranks of a byte-level BPE model (GPT-2 style) in the tiktoken
+ * format: one line per token, with the base64 encoding of its bytes and its rank (its id), separated by a
+ * space. Together with `export_tiktoken_special_tokens` it allows using the tokenizer with runtimes that only
+ * understand tiktoken files.
+ *
+ * Ranks are the token ids, which matches the merge priorities of tokenizers converted from tiktoken. It fails
+ * if the model is not a byte-level BPE.
+ *
+ * The result is returned in a BytesOrError, that must be freed with `free_bytes`.
+ */
+struct BytesOrError export_tiktoken_ranks(struct TokenizerHandle *tokenizer_ptr);
+
+/**
+ * export_tiktoken_special_tokens returns the special tokens map to use along with `export_tiktoken_ranks`:
+ * a JSON object mapping each added token that is not part of the model's vocabulary to its id.
+ *
+ * The result is returned in a BytesOrError, that must be freed with `free_bytes`.
+ */
+struct BytesOrError export_tiktoken_special_tokens(struct TokenizerHandle *tokenizer_ptr);
+
+/**
+ * export_gguf_vocab returns the vocabulary of the tokenizer in the layout llama.cpp expects in GGUF files, so
+ * models converted outside of Python ship a matching tokenizer. It is returned as a JSON object with the GGUF
+ * metadata keys and their values:
+ *
+ * - `tokenizer.ggml.model`: "gpt2" for byte-level BPE, "llama" for SentencePiece models (BPE with byte fallback
+ *   or Unigram), or "bert" for WordPiece.
+ * - `tokenizer.ggml.tokens`: array with the token of each id, including added tokens. Gaps in the ids are
+ *   filled with unused `[PAD<id>]` tokens.
+ * - `tokenizer.ggml.scores`: array with the score of each token: the log-probability for Unigram, minus the
+ *   merge rank for BPE, and -1000 for added tokens.
+ * - `tokenizer.ggml.token_type`: array with the llama.cpp type of each token (1: normal, 2: unknown,
+ *   3: control, 4: user defined, 5: unused, 6: byte).
+ * - `tokenizer.ggml.merges`: array of "left right" merges, for BPE models.
+ * - `tokenizer.ggml.bos_token_id` and `tokenizer.ggml.eos_token_id`, if the post-processor inserts them, and
+ *   `tokenizer.ggml.add_bos_token` and `tokenizer.ggml.add_eos_token` (see `get_template_special_tokens`).
+ * - `tokenizer.ggml.unknown_token_id` and `tokenizer.ggml.padding_token_id`, if defined.
+ *
+ * The result is returned in a BytesOrError, that must be freed with `free_bytes`.
+ */
+struct BytesOrError export_gguf_vocab(struct TokenizerHandle *tokenizer_ptr);
+
+/**
+ * fertility writes to `stats` the fertility statistics of the tokenizer over a sample corpus: the
+ * `num_messages` UTF-8 strings (given as in `encode_batch_bytes`). These are the number of tokens per word
+ * (the words being separated by whitespace, so that tokenizers with different pre-tokenizers can be compared)
+ * and per byte, with their mean and percentiles -- a lower fertility means shorter sequences for the same text.
+ *
+ * The texts are encoded without the special tokens and without truncation, and the encoding is not recorded
+ * in the sequence statistics (see `set_sequence_stats`).
+ *
+ * It returns null if ok, or a string with an error message (owned by caller), to be freed with `free_string`.
+ *
+ * # Safety
+ *
+ * `messages` and `lengths` must point to `num_messages` strings and lengths, and `stats` to a writable
+ * FertilityStats.
+ */
+char *fertility(struct TokenizerHandle *tokenizer_ptr,
+                uint32_t num_messages,
+                const uint8_t *const *messages,
+                const uint32_t *lengths,
+                struct FertilityStats *stats);
+
+/**
+ * encode_batch_flat encodes a batch of UTF-8 strings (given as in `encode_batch_bytes`), and returns the
+ * encodings concatenated into one array per field -- ids, type ids, masks, offsets, and the token strings
+ * concatenated into one block of bytes -- along with the start of each sequence in them (see `FlatBatch`).
+ *
+ * For large batches of short texts it is much cheaper than `encode_batch_bytes_v2`: it takes a handful of
+ * allocations for the whole batch, instead of several per sequence plus one per token, and it is freed with a
+ * single call. Unlike `encode_batch_padded`, the sequences are not padded.
+ *
+ * Only the `ENCODE_ADD_SPECIAL_TOKENS`, the `ENCODE_RETURN_*` flags of the fields of FlatBatch, and the offsets
+ * and special tokens flags of the EncodeParams are used.
+ *
+ * Once it is no longer used, free it with `free_flat_batch`.
+ *
+ * # Safety
+ *
+ * `messages` and `lengths` must point to `num_messages` strings and lengths.
+ */
+struct FlatBatch encode_batch_flat(struct TokenizerHandle *tokenizer_ptr,
+                                   uint64_t num_messages,
+                                   const uint8_t *const *messages,
+                                   const uint32_t *lengths,
+                                   struct EncodeParams options);
+
+/**
+ * Frees the FlatBatch returned by `encode_batch_flat`.
+ *
+ * # Safety
+ *
+ * `batch` must have been returned by `encode_batch_flat`, and it must not be used after this call.
+ */
+void free_flat_batch(struct FlatBatch batch);
+
+/**
+ * tokenizer.Decode method: it decodes the `len` ids, and returns the text as the only element of the results.
+ *
+ * The results must be freed with `free_decode_results`.
+ *
+ * # Safety
+ *
+ * `ids` must point to `len` ids.
+ */
+struct DecodeResults decode(struct TokenizerHandle *tokenizer_ptr,
+                            const uint32_t *ids,
+                            uint32_t len,
+                            bool skip_special_tokens);
+
+/**
+ * Decodes a batch of `num_sequences` sequences of ids in one parallel call: sequence `i` has the
+ * `lengths[i]` ids pointed by `ids[i]`. The texts are returned in the same order as the sequences, or the
+ * error of the first sequence that failed.
+ *
+ * Large batches are decoded in parallel, by the threads of the batch calls (see `set_thread_policy`): small
+ * ones (under 32 sequences), or with a host decoder, are decoded sequentially on the calling thread.
+ *
+ * The results must be freed with `free_decode_results`.
+ *
+ * # Safety
+ *
+ * `ids` and `lengths` must point to `num_sequences` elements, and each `ids[i]` to `lengths[i]` ids.
+ */
+struct DecodeResults decode_batch(struct TokenizerHandle *tokenizer_ptr,
+                                  uint64_t num_sequences,
+                                  const uint32_t *const *ids,
+                                  const uint32_t *lengths,
+                                  bool skip_special_tokens);
+
+/**
+ * Frees the DecodeResults returned by `decode`, `decode_batch` or `decode_with_params`.
+ *
+ * # Safety
+ *
+ * `results` must have been returned by `decode`, `decode_batch` or `decode_with_params`, and it must not be used
+ * after this call.
+ */
+void free_decode_results(struct DecodeResults results);
+
+/**
+ * set_host_decoder sets a host callback that joins the decoded token strings (see `HostDecodeFn`) in
+ * place of the decoder of the tokenizer, for `decode` and `decode_with_params`: e.g. for special
+ * whitespace rules for code generation. Everything else (including the mapping of ids to token strings)
+ * is still done natively. Passing a null `decode_fn` restores the decoder of the tokenizer.
+ *
+ * The callback may be called concurrently from different threads, if the tokenizer is shared.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+ * The returned string needs to be freed with `free_string`.
+ */
+char *set_host_decoder(struct TokenizerHandle *tokenizer_ptr,
+                       HostDecodeFn decode_fn,
+                       void *user_data);
+
+/**
+ * tokenizer.Decode method with DecodeParams.
+ *
+ * With `spaces_between_special_tokens` set to 1 or 2, the ids in between special tokens are decoded
+ * separately, and the resulting texts and special tokens are joined with or without spaces, matching
+ * the behavior of the reference (transformers "slow") implementations.
+ *
+ * With `token_filter` set to a regex (e.g. `<extra_id_\d+>`), the tokens it fully matches are dropped, or
+ * replaced by the `token_filter_replacement` token, before they are joined by the decoder. The remaining
+ * tokens are then passed to the `token_transform` callback, if given (see `TokenTransform`).
+ *
+ * With `invalid_utf8` set to 1 or 2, the byte sequences that can't form valid UTF-8 (e.g. the byte fallback
+ * or byte-level tokens of an incomplete character) are dropped, or escaped as "\xNN" (e.g. "\xE2\x82"),
+ * instead of being replaced by U+FFFD. NUL characters, which can't be part of the returned C string, are
+ * handled the same way (U+FFFD by default, or dropped, or "\x00").
+ *
+ * It returns the text as the only element of the results, or the error, e.g. with an invalid tokenizer or
+ * `token_filter`. The results must be freed with `free_decode_results`.
+ *
+ * # Safety
+ *
+ * `ids` must point to `len` ids.
+ */
+struct DecodeResults decode_with_params(struct TokenizerHandle *tokenizer_ptr,
+                                        const uint32_t *ids,
+                                        uint32_t len,
+                                        struct DecodeParams params);
+
+/**
+ * Decodes the `len` ids as `decode`, and also returns for each id the byte span of the decoded text it
+ * contributed to, e.g. to attribute the generated text to the tokens (for log-probability highlighting)
+ * without encoding the output again.
+ *
+ * The ids are decoded incrementally, as in streaming decoding: the span of an id is the text appended when
+ * it is decoded, so the spans are contiguous and in order. Ids that only decode to part of a character (e.g.
+ * byte-level tokens of an emoji) share the span of the character, and ids that add no text (e.g. skipped
+ * special tokens) have an empty span. The text is the same `decode` returns for the usual decoders, with the
+ * NUL characters (which can't be part of a C string) replaced by U+FFFD.
+ *
+ * The result must be freed with `free_decoded_with_spans`.
+ *
+ * # Safety
+ *
+ * `ids` must point to `len` ids.
+ */
+struct DecodedWithSpans decode_with_spans(struct TokenizerHandle *tokenizer_ptr,
+                                          const uint32_t *ids,
+                                          uint32_t len,
+                                          bool skip_special_tokens);
+
+/**
+ * Frees the DecodedWithSpans returned by `decode_with_spans`.
+ *
+ * # Safety
+ *
+ * `result` must have been returned by `decode_with_spans`, and it must not be used after this call.
+ */
+void free_decoded_with_spans(struct DecodedWithSpans result);
+
+/**
+ * describe returns a concise multi-line description of the pipeline of the tokenizer, for logs and support
+ * tickets: one line for each of the normalizer, pre-tokenizer, model (type, vocabulary size and its main
+ * parameters), added tokens, post-processor (with its template), decoder, padding and truncation. Sequences of
+ * components are listed in order, and large parameters (e.g. the vocabulary) are summarized.
+ *
+ * The format is meant for humans, and it may change between versions.
+ *
+ * It returns null if the tokenizer is invalid. The returned string needs to be freed with `free_string`.
+ */
+char *describe(struct TokenizerHandle *tokenizer_ptr);
+
+/**
+ * encode_document encodes the `num_fields` fields of a structured document (e.g. the title, body and url of a
+ * web page for a ranker) in one sequence, using given tokenizer and EncodeParams, and returns an EncodeResultsV2
+ * with one Buffer:
+ *
+ * - The tokens of each field get the `type_id` of the field, and are limited to its `max_tokens` (if not 0).
+ * - The `num_separator_ids` tokens `separator_ids` (e.g. the id of "[SEP]") are inserted between the fields,
+ *   as special tokens with the type id of the previous field.
+ * - If ENCODE_ADD_SPECIAL_TOKENS is set, the special tokens the post-processor adds before and after a single
+ *   sequence (e.g. "[CLS]" and "[SEP]") are added before the first field and after the last one.
+ * - The padding of the tokenizer is applied, but not its truncation: use the `max_tokens` of the fields instead.
+ *
+ * The offsets of the tokens are into the text of their field, and the word ids are numbered across all the
+ * fields. The span of the tokens of each field, as token indices, is written to the `num_fields` entries of
+ * `field_spans`, if not null.
+ *
+ * The results must be freed with `free_encode_results_v2`.
+ *
+ * # Safety
+ *
+ * `fields` must point to `num_fields` DocumentField, each with `len` bytes of `text`, `separator_ids` to
+ * `num_separator_ids` ids, and `field_spans` must be null or point to space for `num_fields` Offset.
+ */
+struct EncodeResultsV2 encode_document(struct TokenizerHandle *tokenizer_ptr,
+                                       uint32_t num_fields,
+                                       const struct DocumentField *fields,
+                                       uint32_t num_separator_ids,
+                                       const uint32_t *separator_ids,
+                                       struct EncodeParams options,
+                                       struct Offset *field_spans);
+
+/**
+ * token_healing encodes the `prompt` and removes its last token if it may be a partial token, that is, if
+ * there are other tokens in the vocabulary that extend it (e.g.: a prompt ending in "http" is likely to
+ * continue as "https"): generating constrained to tokens that start with the returned `prefix` lets the
+ * model pick the best tokenization across the prompt/generation boundary ("token healing").
+ *
+ * If `add_special_tokens` is true, the special tokens are added to the prompt, and no token is removed if
+ * the prompt ends with a special token.
+ *
+ * The result must be freed with `free_token_healing`.
+ *
+ * # Safety
+ *
+ * `prompt` must be a valid C string.
+ */
+struct TokenHealing token_healing(struct TokenizerHandle *tokenizer_ptr,
+                                  const char *prompt,
+                                  bool add_special_tokens);
+
+/**
+ * Frees a `TokenHealing` returned by Rust to Golang.
+ *
+ * # Safety
+ *
+ * `healing` must have been returned by `token_healing`, and it must not be used after this call.
+ */
+void free_token_healing(struct TokenHealing healing);
+
+/**
+ * allowed_tokens_mask returns the bit-mask of the tokens consistent with continuing the text given by the
+ * `len` bytes of `prefix`, for prefix-constrained decoding: the tokens whose text is a prefix of `prefix` (so
+ * they consume part of it), or that start with `prefix` (so they complete it). Special tokens are never
+ * allowed.
+ *
+ * The mask has one bit per token id up to `max_token_id` (inclusive), where token id `i` is the bit `i % 8`
+ * (least significant first) of byte `i / 8`. It is returned in a BytesOrError, that must be freed with
+ * `free_bytes`.
+ *
+ * The vocabulary trie used is built on the first call, and cached until the tokenizer is modified.
+ *
+ * # Safety
+ *
+ * `prefix` must point to `len` bytes.
+ */
+struct BytesOrError allowed_tokens_mask(struct TokenizerHandle *tokenizer_ptr,
+                                        const uint8_t *prefix,
+                                        uint32_t len);
+
+/**
+ * export_vocab_trie returns the prefix trie of the vocabulary used by `allowed_tokens_mask`, serialized as a
+ * compact binary blob, so client-side autocomplete and constrained decoding engines can do prefix matching
+ * consistent with this tokenizer. The trie is keyed by the surface form of the tokens (the bytes they decode
+ * to), and special tokens are not included.
+ *
+ * The blob is made of, with all integers as little-endian uint32:
+ *
+ * - Header: the magic "GTVT", the format version (1), `num_nodes`, `num_edges` and `num_ids`.
+ * - Nodes: `num_nodes` entries of (first_edge, num_edges, ids_start, ids_end, subtree_end). The node 0 is the
+ *   root. The children of a node are the edges `first_edge..first_edge+num_edges`, sorted by byte. The tokens
+ *   ending at the node are `ids[ids_start..ids_end]`, and the tokens of its whole subtree (the ones starting with
+ *   the prefix of the node) are `ids[ids_start..subtree_end]`.
+ * - Edge targets: `num_edges` child node indices.
+ * - Ids: `num_ids` token ids.
+ * - Edge bytes: `num_edges` bytes, the byte of each edge.
+ *
+ * It is returned in a BytesOrError, that must be freed with `free_bytes`.
+ */
+struct BytesOrError export_vocab_trie(struct TokenizerHandle *tokenizer_ptr);
+
+/**
+ * get_template_special_tokens returns the special tokens inserted by the post-processor (TemplateProcessing,
+ * BertProcessing or RobertaProcessing), so generation code can find the BOS/EOS token ids of the model. It is
+ * returned as a JSON object with the keys:
+ *
+ * - `single` and `pair`: arrays with the special tokens inserted for single and pair inputs, in order.
+ * - `bos`: array with the special tokens inserted before the (first) sequence.
+ * - `eos`: array with the special tokens inserted after the (last) sequence.
+ *
+ * Each special token is an object with the `token` string and its `id`. The arrays are empty if the
+ * tokenizer has no such post-processor.
+ *
+ * The result is returned in a BytesOrError, that must be freed with `free_bytes`.
+ */
+struct BytesOrError get_template_special_tokens(struct TokenizerHandle *tokenizer_ptr);
+
+/**
+ * encode_with_generation_budget encodes the `len` bytes of the UTF-8 `text` (a prompt) using given tokenizer and
+ * EncodeParams, as `encode_v2` does, but truncated to leave room for `reserve_for_generation` generated tokens:
+ * the prompt (with its special tokens) is truncated to the configured truncation `max_length` minus
+ * `reserve_for_generation`, with the configured truncation direction and stride. It is an error if the
+ * truncation is not configured (see `set_truncation`), or if the reserve doesn't fit in `max_length`.
+ *
+ * The number of tokens of the prompt dropped by the truncation is written to `dropped`, if not null.
+ *
+ * The results must be freed with `free_encode_results_v2`.
+ *
+ * # Safety
+ *
+ * `text` must point to `len` bytes, and `dropped` must be null or point to a u64.
+ */
+struct EncodeResultsV2 encode_with_generation_budget(struct TokenizerHandle *tokenizer_ptr,
+                                                     const uint8_t *text,
+                                                     uint32_t len,
+                                                     uint32_t reserve_for_generation,
+                                                     struct EncodeParams options,
+                                                     uint64_t *dropped);
+
+/**
+ * tokenizer_retain increments the reference count of the tokenizer, so it can be independently held
+ * (and released) by different owners.
+ *
+ * Each call must be matched by a call to `tokenizer_release`.
+ *
+ * # Safety
+ *
+ * `tokenizer_ptr` must be a valid tokenizer, that is, one that was not yet fully released.
+ */
+void tokenizer_retain(struct TokenizerHandle *tokenizer_ptr);
+
+/**
+ * tokenizer_release decrements the reference count of the tokenizer, and frees it when the last
+ * reference is released.
+ *
+ * # Safety
+ *
+ * `tokenizer_ptr` must be a valid tokenizer, and the caller must own the reference being released.
+ */
+void tokenizer_release(struct TokenizerHandle *tokenizer_ptr);
+
+/**
+ * tokenizer_reload parses a new Tokenizer from the json contents of a `tokenizer.json` file (see
+ * `from_bytes`) and atomically swaps it in place of the current one, behind the same handle: all existing
+ * references remain valid, and pick up the new Tokenizer. Per-tokenizer options (e.g.: deterministic mode)
+ * are preserved. As with `from_bytes`, the new Tokenizer is shared with the other handles loaded from the same
+ * contents.
+ *
+ * The parsing, and the application of the per-tokenizer options, happen without holding the tokenizer lock,
+ * so concurrent encode/decode calls are only blocked for the swap itself. If parsing fails, the current
+ * Tokenizer is kept.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+ * The returned string needs to be freed with `free_string`.
+ *
+ * # Safety
+ *
+ * `bytes` must point to `len` bytes.
+ */
+char *tokenizer_reload(struct TokenizerHandle *tokenizer_ptr,
+                       const uint8_t *bytes,
+                       uint32_t len);
+
+/**
+ * set_model_from_json replaces the model of the tokenizer (e.g. with a pruned BPE) with the one defined by
+ * `model_json`, the JSON of the `model` field of a `tokenizer.json` file, keeping the rest of the pipeline
+ * (normalizer, pre-tokenizer, post-processor, decoder, padding, truncation and added tokens), e.g. to A/B test
+ * vocabularies behind identical preprocessing. Per-tokenizer options (e.g.: deterministic mode) are preserved.
+ *
+ * The added tokens keep their ids: the new vocabulary is expected to be compatible with them. If the model
+ * can't be parsed, the current one is kept.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+ * The returned string needs to be freed with `free_string`.
+ *
+ * # Safety
+ *
+ * `model_json` must be a valid C string.
+ */
+char *set_model_from_json(struct TokenizerHandle *tokenizer_ptr,
+                          const char *model_json);
+
+/**
+ * freeze returns a new frozen (read-only) handle with a snapshot of the tokenizer and its options, in
+ * the `value` field.
+ *
+ * Configuration setters (`set_truncation`, `set_padding`, etc.) on a frozen handle are rejected, and
+ * in exchange encode/decode calls don't need any locking. The original tokenizer is not affected, and can
+ * still be configured.
+ *
+ * The caller owns the returned handle, and should free it with `free_tokenizer`.
+ */
+struct PointerOrError freeze(struct TokenizerHandle *tokenizer_ptr);
+
+/**
+ * is_frozen returns whether the tokenizer is a frozen (read-only) handle, see `freeze`.
+ */
+bool is_frozen(struct TokenizerHandle *tokenizer_ptr);
+
+/**
+ * set_deterministic enables or disables the deterministic mode of the tokenizer: while enabled, all
+ * stochastic behavior configured in the model (e.g.: BPE dropout) is disabled, so encodings are
+ * reproducible. Disabling it restores the model configuration.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+ * The returned string needs to be freed with `free_string`.
+ */
+char *set_deterministic(struct TokenizerHandle *tokenizer_ptr,
+                        bool deterministic);
+
+/**
+ * get_deterministic returns whether the deterministic mode of the tokenizer is enabled.
+ */
+bool get_deterministic(struct TokenizerHandle *tokenizer_ptr);
+
+/**
+ * set_encode_special_tokens sets whether special token strings in the text (e.g. "<|im_end|>") are encoded
+ * as plain text (if true), instead of as the special tokens (the default). Encoding user-supplied text as
+ * plain text prevents it from injecting control tokens. It can be overridden per call with the EncodeParams
+ * flags ENCODE_SPLIT_SPECIAL_TOKENS and ENCODE_MATCH_SPECIAL_TOKENS.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+ * The returned string needs to be freed with `free_string`.
+ */
+char *set_encode_special_tokens(struct TokenizerHandle *tokenizer_ptr,
+                                bool value);
+
+/**
+ * get_encode_special_tokens returns whether special token strings in the text are encoded as plain text,
+ * see `set_encode_special_tokens`.
+ */
+bool get_encode_special_tokens(struct TokenizerHandle *tokenizer_ptr);
+
+/**
+ * hash_ids returns a stable hash of the `len` token `ids`: the XXH3 64-bit hash (with seed 0) of the ids as
+ * little-endian u32 values, so it is the same across platforms and releases, and it can be reproduced
+ * elsewhere (e.g. `xxhash.xxh3_64(numpy.asarray(ids, dtype="<u4").tobytes())` in Python).
+ *
+ * # Safety
+ *
+ * `ids` must point to `len` ids.
+ */
+uint64_t hash_ids(const uint32_t *ids,
+                  uint64_t len);
+
+/**
+ * from_pretrained loads the tokenizer of the model repository `model_id` (e.g. "bert-base-uncased" or
+ * "google/gemma-2b") of the HuggingFace Hub: its `tokenizer.json` file at `revision` (a branch, tag or commit,
+ * "main" if null), downloaded with the `auth_token` (if not null) for private or gated models.
+ *
+ * The files are cached as the HuggingFace libraries do (and shared with them): in `HF_HUB_CACHE`, by default
+ * `~/.cache/huggingface/hub`, so a tokenizer is only downloaded once. The environment variables `HF_ENDPOINT`,
+ * `HF_HOME`, `HF_TOKEN` (used if `auth_token` is null) and `HF_HUB_OFFLINE` (only the cache is used) are
+ * honored as well.
+ *
+ * It returns the Tokenizer in the `value` field, or an error, as `from_bytes`. Only available with the `http`
+ * feature (enabled by default).
+ *
+ * # Safety
+ *
+ * `model_id` must be a null-terminated string, and `revision` and `auth_token` null or null-terminated strings.
+ */
+struct PointerOrError from_pretrained(const char *model_id,
+                                      const char *revision,
+                                      const char *auth_token);
+
+/**
+ * from_pretrained_verified loads the tokenizer of the model repository `model_id` of the HuggingFace Hub, as
+ * `from_pretrained`, after checking that the SHA-256 digest of its `tokenizer.json` (downloaded or cached) is
+ * the 32 bytes at `expected_sha256` (if not null), as `from_bytes_verified`.
+ *
+ * It returns the Tokenizer in the `value` field, or an error. If `error_code` is not null, it is set to
+ * `LOAD_OK`, `LOAD_ERROR_IO` (the file can't be downloaded), `LOAD_ERROR_CHECKSUM_MISMATCH` or
+ * `LOAD_ERROR_INVALID`.
+ *
+ * # Safety
+ *
+ * As `from_pretrained`; `expected_sha256` must be null or point to 32 bytes, and `error_code` must be null or
+ * point to a writable u32.
+ */
+struct PointerOrError from_pretrained_verified(const char *model_id,
+                                               const char *revision,
+                                               const char *auth_token,
+                                               const uint8_t *expected_sha256,
+                                               uint32_t *error_code);
+
+/**
+ * submit_batch submits a batch of UTF-8 strings (given as in `encode_batch_bytes`) to be encoded in the
+ * background, with the given EncodeParams, and returns immediately the id of the job -- always positive.
+ *
+ * The texts are copied, so they can be freed once it returns. The tokenizer is retained until the job is
+ * completed. Once completed, the optional `callback` is called, and the results can be taken with
+ * `poll_result`. Errors (including invalid inputs) are reported in the results.
+ *
+ * # Safety
+ *
+ * `messages` and `lengths` must point to `num_messages` strings and lengths.
+ */
+uint64_t submit_batch(struct TokenizerHandle *tokenizer_ptr,
+                      uint32_t num_messages,
+                      const uint8_t *const *messages,
+                      const uint32_t *lengths,
+                      struct EncodeParams options,
+                      JobCallback callback,
+                      void *user_data);
+
+/**
+ * poll_result returns the status of the job `job_id` (JOB_PENDING, JOB_COMPLETED or JOB_UNKNOWN). If it is
+ * completed, and `results` is not null, its results are written to `results` and ownership is transferred to
+ * the caller, who must free them with `free_encode_results_v2`: the job is then forgotten. With a null
+ * `results`, only the status is returned.
+ *
+ * # Safety
+ *
+ * `results` must be null or point to a writable EncodeResultsV2.
+ */
+uint8_t poll_result(uint64_t job_id,
+                    struct EncodeResultsV2 *results);
+
+/**
+ * from_bytes_lenient loads a tokenizer, like `from_bytes`, but tolerating `tokenizer.json` files written by
+ * other (e.g. newer) versions of the tokenizers library, by applying the known migrations:
+ *
+ * - A `version` other than "1.0" is read as "1.0".
+ * - Unknown fields are ignored (as `from_bytes` does).
+ * - Null fields of the components that this version can't read are dropped, so their defaults are used.
+ *
+ * Changes that can't be migrated safely (e.g. a component of an unknown type, or a field with a value this
+ * version doesn't support) still fail, but the error names the field, e.g.
+ * `pre_tokenizer.pretokenizers[1].behavior`, instead of only the position in the JSON.
+ *
+ * It returns the Tokenizer in the `value` field, or an error. If `migrations` is not null, it is set to the
+ * descriptions of the migrations applied, one per line (a C string that must be freed with `free_string`),
+ * or to null if none was needed.
+ *
+ * # Safety
+ *
+ * `bytes` must point to `len` bytes, and `migrations` must be null or point to a writable pointer.
+ */
+struct PointerOrError from_bytes_lenient(const uint8_t *bytes,
+                                         uint32_t len,
+                                         char **migrations);
+
+/**
+ * set_encode_limits sets the limits on the inputs of the encode calls of the tokenizer: the calls exceeding
+ * them fail before encoding anything, with an error that `encode_error_code` identifies as
+ * `ENCODE_ERROR_INPUT_TOO_LARGE` or `ENCODE_ERROR_BATCH_TOO_LARGE`. A limit of 0 disables it, so the default
+ * `EncodeLimits` (all zeros) removes all limits.
+ *
+ * The `max_tokens` limit (e.g. a quota) is checked while encoding the texts with `encode*` and `encode_batch*`:
+ * the encoding of an input is aborted as soon as its texts produce more tokens -- not counting the special
+ * tokens added, and before any truncation -- and the call fails with `ENCODE_ERROR_TOKEN_BUDGET_EXCEEDED`.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller), to be freed with `free_string`.
+ */
+char *set_encode_limits(struct TokenizerHandle *tokenizer_ptr,
+                        struct EncodeLimits limits);
+
+/**
+ * get_encode_limits returns the limits on the inputs of the encode calls of the tokenizer (see
+ * `set_encode_limits`), or no limits if the tokenizer is null.
+ */
+struct EncodeLimits get_encode_limits(struct TokenizerHandle *tokenizer_ptr);
+
+/**
+ * encode_error_code returns the code of the `error` of the results of an encode call (e.g.
+ * `EncodeResults.error`): `ENCODE_OK` if it is null, one of the specific `ENCODE_ERROR_*` codes, or
+ * `ENCODE_ERROR_OTHER`.
+ *
+ * # Safety
+ *
+ * `error` must be null or a valid C string.
+ */
+uint32_t encode_error_code(const char *error);
+
+/**
+ * encode_error_token_count returns the number of tokens produced by the input that exceeded the token budget
+ * until its encoding was aborted, if `error` is an `ENCODE_ERROR_TOKEN_BUDGET_EXCEEDED` error (see
+ * `encode_error_code`), or 0 otherwise.
+ *
+ * # Safety
+ *
+ * `error` must be null or a valid C string.
+ */
+uint64_t encode_error_token_count(const char *error);
+
+/**
+ * from_reader loads a tokenizer from the `tokenizer.json` contents read by the host `read` callback (see
+ * `ReadCallback`), called repeatedly (from the calling thread) until the end of the input, with the given
+ * `user_data`. The contents are parsed as they are read, so they are never buffered whole in memory.
+ *
+ * It returns the Tokenizer in the `value` field, or an error, as `from_bytes`.
+ */
+struct PointerOrError from_reader(ReadCallback read,
+                                  void *user_data);
+
+/**
+ * from_bytes_with_overlays loads a tokenizer, like `from_bytes`, from the `tokenizer.json` contents in `bytes`
+ * merged with the `num_overlays` JSON fragments (e.g. sidecar files with extra added tokens, or a
+ * post-processor template) given by pointers in `overlays` and their lengths in `lengths`, in order.
+ *
+ * Each overlay is a JSON object, merged into the definition: objects are merged recursively, and other
+ * values replaced -- except for `added_tokens`, where the tokens of the overlay replace those with the same
+ * `id`, and the others are appended.
+ *
+ * It returns the Tokenizer in the `value` field, or an error.
+ *
+ * # Safety
+ *
+ * `overlays` and `lengths` must point to `num_overlays` buffers and lengths.
+ */
+struct PointerOrError from_bytes_with_overlays(const uint8_t *bytes,
+                                               uint32_t len,
+                                               uint32_t num_overlays,
+                                               const uint8_t *const *overlays,
+                                               const uint32_t *lengths);
+
+/**
+ * from_encrypted_bytes loads a tokenizer, like `from_bytes`, from the encrypted `tokenizer.json` contents in
+ * `bytes`: they are passed to the host `decrypt` callback (with the given `user_data`), and the decrypted
+ * contents it returns are parsed (see `DecryptCallback`). So the decrypted contents are never written to disk.
+ *
+ * It returns the Tokenizer in the `value` field, or an error.
+ *
+ * # Safety
+ *
+ * `bytes` must point to `len` bytes.
+ */
+struct PointerOrError from_encrypted_bytes(const uint8_t *bytes,
+                                           uint64_t len,
+                                           DecryptCallback decrypt,
+                                           void *user_data);
+
+/**
+ * from_bytes_verified loads a tokenizer, like `from_bytes`, after checking that the SHA-256 digest of the
+ * `tokenizer.json` contents in `bytes` is the 32 bytes at `expected_sha256` (if not null). This protects from
+ * loading corrupted or tampered files.
+ *
+ * It returns the Tokenizer in the `value` field, or an error. If `error_code` is not null, it is set to
+ * `LOAD_OK`, `LOAD_ERROR_CHECKSUM_MISMATCH` or `LOAD_ERROR_INVALID`.
+ *
+ * # Safety
+ *
+ * `bytes` must point to `len` bytes, `expected_sha256` must be null or point to 32 bytes, and `error_code`
+ * must be null or point to a writable u32.
+ */
+struct PointerOrError from_bytes_verified(const uint8_t *bytes,
+                                          uint32_t len,
+                                          const uint8_t *expected_sha256,
+                                          uint32_t *error_code);
+
+/**
+ * from_file_verified loads a tokenizer from the `tokenizer.json` file at `path`, after checking that the
+ * SHA-256 digest of its contents is the 32 bytes at `expected_sha256` (if not null), as `from_bytes_verified`.
+ *
+ * It returns the Tokenizer in the `value` field, or an error. If `error_code` is not null, it is set to
+ * `LOAD_OK`, `LOAD_ERROR_IO` (the file can't be read), `LOAD_ERROR_CHECKSUM_MISMATCH` or `LOAD_ERROR_INVALID`.
+ *
+ * # Safety
+ *
+ * `path` must be a null-terminated string, `expected_sha256` must be null or point to 32 bytes, and
+ * `error_code` must be null or point to a writable u32.
+ */
+struct PointerOrError from_file_verified(const char *path,
+                                         const uint8_t *expected_sha256,
+                                         uint32_t *error_code);
+
+/**
+ * from_bytes_with_config loads a tokenizer, like `from_bytes`, along with the settings of the `config_len`
+ * bytes of `config`, the contents of its `tokenizer_config.json` file (e.g. the chat templates, see
+ * `get_chat_template`). If `config` is null, it is the same as `from_bytes`.
+ *
+ * It returns the Tokenizer in the `value` field, or an error.
+ *
+ * # Safety
+ *
+ * `bytes` must point to `len` bytes, and `config` must be null or point to `config_len` bytes.
+ */
+struct PointerOrError from_bytes_with_config(const uint8_t *bytes,
+                                             uint32_t len,
+                                             const uint8_t *config,
+                                             uint32_t config_len);
+
+/**
+ * from_tiktoken_ranks builds a byte-level BPE tokenizer from the in-memory representation of a tiktoken
+ * encoding (see `TiktokenDefinition`): its mergeable ranks (token bytes to rank, which are also the token
+ * ids), its special tokens and its split pattern -- as the tiktoken converter of transformers does, but without
+ * writing any intermediary file. It is the inverse of `export_tiktoken_ranks` and
+ * `export_tiktoken_special_tokens`.
+ *
+ * It returns the Tokenizer in the `value` field, or an error.
+ *
+ * # Safety
+ *
+ * `definition` must point to a TiktokenDefinition, with valid pointers to its arrays, and `pattern` null or a
+ * null-terminated string.
+ */
+struct PointerOrError from_tiktoken_ranks(const struct TiktokenDefinition *definition);
+
+/**
+ * from_files builds a tokenizer from the files of the models that don't ship a `tokenizer.json`, as the
+ * converters of transformers do:
+ *
+ * - With a null `merges`, a WordPiece tokenizer (as BERT) from `vocab`, a `vocab.txt` file with one token per
+ *   line (its id being the line number). `lowercase` is the `do_lower_case` of the model (see its
+ *   `tokenizer_config.json`): if set, the text is lowercased and its accents removed.
+ * - Otherwise, a byte-level BPE tokenizer (as GPT-2, or RoBERTa if "<s>" and "</s>" are in the vocabulary)
+ *   from `vocab`, a `vocab.json` file mapping the tokens to their ids, and `merges`, a `merges.txt` file with
+ *   one merge per line. If `lowercase` is set, the text is lowercased.
+ *
+ * The special tokens of these models found in the vocabulary (e.g. "[CLS]", "[SEP]" or "<|endoftext|>") are
+ * registered as special tokens. The tokenizer can then be saved as a `tokenizer.json` with `tokenizer_save`.
+ *
+ * It returns the Tokenizer in the `value` field, or an error.
+ *
+ * # Safety
+ *
+ * `vocab` must be a null-terminated string, and `merges` null or a null-terminated string.
+ */
+struct PointerOrError from_files(const char *vocab,
+                                 const char *merges,
+                                 bool lowercase);
+
+/**
+ * encode_batch_padded encodes a batch of UTF-8 strings (given as in `encode_batch_bytes`), and returns the
+ * ids and attention masks (and type ids, if ENCODE_RETURN_TYPE_IDS is set) as `[batch_size, max_len]`
+ * row-major matrices, where `max_len` is the length of the longest encoding, along with the length of
+ * each sequence before padding. So they can be wrapped as tensors without copying each sequence.
+ *
+ * The matrices hold u32 values, or i32 or i64 values with the ENCODE_OUTPUT_INT32 or ENCODE_OUTPUT_INT64
+ * flags, as used by most inference runtimes (see `PaddedBatch.dtype`).
+ *
+ * The sequences are padded with the padding parameters of the tokenizer (see `set_padding`), or with id 0
+ * in the padding side of the tokenizer config (see `apply_tokenizer_config`), or to the right, if none is set.
+ * Only the `ENCODE_ADD_SPECIAL_TOKENS`, `ENCODE_RETURN_TYPE_IDS`, the special tokens flags and the output
+ * type flags of the EncodeParams are used.
+ *
+ * Once it is no longer used, free it with `free_padded_batch`.
+ *
+ * # Safety
+ *
+ * `messages` and `lengths` must point to `num_messages` strings and lengths.
+ */
+struct PaddedBatch encode_batch_padded(struct TokenizerHandle *tokenizer_ptr,
+                                       uint32_t num_messages,
+                                       const uint8_t *const *messages,
+                                       const uint32_t *lengths,
+                                       struct EncodeParams options);
+
+/**
+ * Frees the PaddedBatch returned by `encode_batch_padded`.
+ *
+ * # Safety
+ *
+ * `batch` must have been returned by `encode_batch_padded`, and it must not be used after this call.
+ */
+void free_padded_batch(struct PaddedBatch batch);
+
+/**
+ * encode_batch_into_tensors encodes a batch of UTF-8 strings (given as in `encode_batch_bytes`), and writes
+ * the ids and the attention masks directly to the `[rows, cols]` row-major tensors at `ids` and
+ * `attention_mask` (if not null), owned by the host -- e.g. the memory of GoMLX/XLA tensors -- so the
+ * inputs of a model are built without any intermediary copy.
+ *
+ * The values of the tensors are of type `dtype`, one of the PADDED_DTYPE_* constants (the ENCODE_OUTPUT_*
+ * flags are ignored). The sequences are truncated to `cols` tokens (the overflowing tokens are dropped),
+ * and padded with `pad_id` (in the padding direction of the tokenizer, see `set_padding`, or the padding
+ * side of the tokenizer config, or to the right). There must be at most `rows` strings: the remaining rows are filled with padding.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller), to be freed with `free_string`.
+ * On error, the tensors are not written.
+ *
+ * # Safety
+ *
+ * `messages` and `lengths` must point to `num_messages` strings and lengths, and `ids` and `attention_mask`
+ * (if not null) must point to writable, aligned `rows * cols` values of type `dtype`.
+ */
+char *encode_batch_into_tensors(struct TokenizerHandle *tokenizer_ptr,
+                                uint32_t num_messages,
+                                const uint8_t *const *messages,
+                                const uint32_t *lengths,
+                                struct EncodeParams options,
+                                void *ids,
+                                void *attention_mask,
+                                uint64_t rows,
+                                uint64_t cols,
+                                uint32_t dtype,
+                                uint32_t pad_id);
+
+/**
+ * pipeline_new starts a pipeline encoding the texts streamed through a ring of `capacity` slots (see
+ * `PipelineRing`) with `num_workers` threads, using the tokenizer and the given EncodeParams. It returns the
+ * `PipelineRing *` in the `value` field, or an error.
+ *
+ * The pipeline holds a reference to the tokenizer (see `tokenizer_retain`), so it can be freed independently.
+ * Stop the pipeline and free it with `pipeline_free`.
+ */
+struct PointerOrError pipeline_new(struct TokenizerHandle *tokenizer_ptr,
+                                   uint64_t capacity,
+                                   uint32_t num_workers,
+                                   struct EncodeParams options);
+
+/**
+ * pipeline_free stops the workers of the pipeline (waiting for the texts being encoded), and frees it with
+ * all the results of its slots.
+ *
+ * # Safety
+ *
+ * `ring` must have been returned by `pipeline_new`, and it must not be used after this call.
+ */
+void pipeline_free(struct PipelineRing *ring);
+
+/**
+ * token_line_columns returns the (line, column) start and end of each token of the `buffer` (an encoding
+ * returned by any of the encode functions, with ENCODE_RETURN_OFFSETS) in the `len` bytes of the UTF-8 `text`
+ * it was encoded from. The `options` must have the same offsets mode flags used to encode it, and the
+ * columns are counted in the same units as the offsets (bytes, characters or UTF-16 code units, as used by
+ * LSP), except that tabs advance to the next multiple of `tab_width` columns, if it is not 0.
+ *
+ * Lines and columns are 0-based. "\r\n", "\n" and "\r" are line breaks: a token ending right after a line
+ * break ends at column 0 of the next line. The overflowing windows of a Buffer have offsets into the same
+ * text, so they can be mapped with the same `text`.
+ *
+ * The result must be freed with `free_token_line_columns`.
+ *
+ * # Safety
+ *
+ * `text` must point to `len` bytes, and `buffer` to a Buffer returned by Rust that was not freed yet.
+ */
+struct TokenLineColumns token_line_columns(const uint8_t *text,
+                                           uint32_t len,
+                                           const struct Buffer *buffer,
+                                           struct EncodeParams options,
+                                           uint32_t tab_width);
+
+/**
+ * Frees the TokenLineColumns returned by `token_line_columns`.
+ *
+ * # Safety
+ *
+ * `result` must have been returned by `token_line_columns`, and it must not be used after this call.
+ */
+void free_token_line_columns(struct TokenLineColumns result);
+
+/**
+ * set_profiling enables (or disables) the profiling of the encoding by the encode calls, see `get_profile`.
+ *
+ * Profiling adds the overhead of timing each phase of each text: it is meant for diagnostics.
+ */
+void set_profiling(bool enabled);
+
+/**
+ * get_profile writes to `profile` the histograms of the durations of each phase of the encoding, aggregated
+ * over all the texts encoded (by all tokenizers) while profiling was enabled, since the last `reset_profile`.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller), to be freed with `free_string`.
+ *
+ * # Safety
+ *
+ * `profile` must point to a writable Profile.
+ */
+char *get_profile(struct Profile *profile);
+
+/**
+ * reset_profile clears the histograms returned by `get_profile`.
+ */
+void reset_profile(void);
+
+/**
+ * Encodes the `len` bytes of UTF-8 `text` with placeholder markers, using given tokenizer and EncodeParams, and
+ * returns an EncodeResultsV2 with one Buffer.
+ *
+ * The placeholders are the substrings matched by the `pattern` regex, or if null by the default pattern, which
+ * matches the T5 sentinels (`<extra_id_0>`, `<extra_id_1>`, ...) and the fill-in-the-middle markers
+ * (`<fim_prefix>`, `<fim_middle>`, `<fim_suffix>`, `<fim_pad>`, and the same in the `<|fim_prefix|>` form).
+ * Each placeholder is mapped to the id of the token with the same content, as a special token, and the text in
+ * between them is encoded as usual. Unlike `encode`, the placeholders are mapped even if they are not added
+ * tokens of the tokenizer, or if the special tokens are encoded as text (see `set_encode_special_tokens`).
+ *
+ * It is an error if a placeholder is not a token of the tokenizer: e.g. `<fim_prefix>` with a tokenizer
+ * without FIM tokens.
+ *
+ * The results must be freed with `free_encode_results_v2`.
+ *
+ * # Safety
+ *
+ * `text` must point to `len` bytes, and `pattern` must be null or a null-terminated string.
+ */
+struct EncodeResultsV2 encode_with_placeholders(struct TokenizerHandle *tokenizer_ptr,
+                                                const uint8_t *text,
+                                                uint32_t len,
+                                                const char *pattern,
+                                                struct EncodeParams options);
+
+/**
+ * build_fim_prompt encodes the fill-in-the-middle prompt of a code model, to generate the code in between the
+ * `prefix_len` bytes of UTF-8 `prefix` and the `suffix_len` bytes of `suffix`, using given tokenizer and
+ * EncodeParams. It returns an EncodeResultsV2 with one Buffer, with the tokens ordered according to `mode`,
+ * FIM_PSM or FIM_SPM.
+ *
+ * The FIM tokens are the ones the tokenizer defines, following the conventions of the code model families:
+ * `<fim_prefix>`, `<fim_suffix>` and `<fim_middle>` (StarCoder), `<|fim_prefix|>`, ... (Qwen, CodeGemma),
+ * `<fim-prefix>`, ... (SantaCoder), `▁<PRE>`, `▁<SUF>` and `▁<MID>` (CodeLlama), or `<｜fim▁begin｜>`,
+ * `<｜fim▁hole｜>` and `<｜fim▁end｜>` (DeepSeek-Coder). It is an error if the tokenizer has none of them.
+ *
+ * If ENCODE_ADD_SPECIAL_TOKENS is set, the prompt starts with the BOS tokens the post-processor adds (e.g.
+ * "<s>"), but no tokens are added at the end. The offsets of the prefix tokens are into the `prefix` (they are
+ * the sequence 0), and the ones of the suffix into the `suffix` (the sequence 1). The truncation and padding
+ * of the tokenizer are not applied.
+ *
+ * The results must be freed with `free_encode_results_v2`.
+ *
+ * # Safety
+ *
+ * `prefix` and `suffix` must point to `prefix_len` and `suffix_len` bytes.
+ */
+struct EncodeResultsV2 build_fim_prompt(struct TokenizerHandle *tokenizer_ptr,
+                                        const uint8_t *prefix,
+                                        uint32_t prefix_len,
+                                        const uint8_t *suffix,
+                                        uint32_t suffix_len,
+                                        uint32_t mode,
+                                        struct EncodeParams options);
+
+/**
+ * Encodes the `len` raw bytes, not required to be valid UTF-8 (e.g. logs with invalid sequences, or mixed
+ * encodings), using given tokenizer and EncodeParams, and returns an EncodeResultsV2 with one Buffer.
+ *
+ * The valid UTF-8 parts are encoded as text, and each byte of the invalid sequences is encoded as its byte
+ * token: the byte fallback token (e.g. "<0xFF>") if the model has them, or the byte-level (GPT-2 style) token
+ * of the byte for byte-level tokenizers -- other tokenizers fail. Each run of invalid bytes is one word.
+ *
+ * Offsets are in bytes: ENCODE_WITH_OFFSETS_CHAR_MODE, ENCODE_WITH_OFFSETS_UTF16_MODE and
+ * ENCODE_RETURN_TOKEN_CHAR_LENGTHS are not supported.
+ *
+ * The results must be freed with `free_encode_results_v2`.
+ *
+ * # Safety
+ *
+ * `bytes` must point to `len` bytes.
+ */
+struct EncodeResultsV2 encode_raw_bytes(struct TokenizerHandle *tokenizer_ptr,
+                                        const uint8_t *bytes,
+                                        uint64_t len,
+                                        struct EncodeParams options);
+
+/**
+ * start_recording starts logging the encode and decode calls (of all tokenizers) to a new file at the
+ * null-terminated `path`, replacing any recording in progress. The definitions of the tokenizers are always
+ * logged, but the texts and token ids only if `include_inputs` is set: otherwise only their lengths are.
+ *
+ * Recording is meant for debugging: each call is written (and flushed) to the file before it runs.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller), to be freed with `free_string`.
+ *
+ * # Safety
+ *
+ * `path` must be a null-terminated string.
+ */
+char *start_recording(const char *path,
+                      bool include_inputs);
+
+/**
+ * stop_recording stops the recording in progress, if any, and closes its file.
+ */
+void stop_recording(void);
+
+/**
+ * replay_recording repeats the calls logged by `start_recording` to the file at the null-terminated `path`,
+ * in order, using the recorded tokenizer definitions. If the inputs were not recorded, placeholder inputs
+ * of the same lengths are used. Token transform callbacks (see `decode_with_params`) are not replayed.
+ *
+ * The results of the replayed calls are discarded: the point is to reproduce their crashes (panics, which
+ * are reported to stderr by the panic hook, and returned by the calls as errors). It
+ * returns null if ok, or a string with an error message (owned by caller) if the recording is invalid, to
+ * be freed with `free_string`.
+ *
+ * # Safety
+ *
+ * `path` must be a null-terminated string.
+ */
+char *replay_recording(const char *path);
+
+/**
+ * registry_load registers a tokenizer under `name`, parsing it from the json contents of a
+ * `tokenizer.json` file (see `from_bytes`), and returns a reference to it in the `value` field.
+ *
+ * If a tokenizer is already registered under `name`, `bytes` is ignored, and the registered
+ * tokenizer is returned instead. In either case the reference count of `name` is incremented,
+ * and the caller should call `registry_unload` once it no longer uses it.
+ *
+ * The tokenizer returned is owned by the registry: it must not be freed with `free_tokenizer`, unless
+ * an extra reference was taken with `tokenizer_retain`.
+ *
+ * # Safety
+ *
+ * `name` must be a valid C string, and `bytes` must point to `len` bytes.
+ */
+struct PointerOrError registry_load(const char *name,
+                                    const uint8_t *bytes,
+                                    uint32_t len);
+
+/**
+ * registry_get returns the tokenizer registered under `name`, or null if there is none.
+ *
+ * If found, the reference count of `name` is incremented, and the caller should call `registry_unload`
+ * once it no longer uses it.
+ *
+ * The tokenizer returned is owned by the registry: it must not be freed with `free_tokenizer`.
+ *
+ * # Safety
+ *
+ * `name` must be a valid C string.
+ */
+struct TokenizerHandle *registry_get(const char *name);
+
+/**
+ * registry_unload decrements the reference count of the tokenizer registered under `name`, and
+ * once it reaches 0, the tokenizer is unregistered and the registry's reference to it is released.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller) if `name` is not registered.
+ * The returned string needs to be freed with `free_string`.
+ *
+ * # Safety
+ *
+ * `name` must be a valid C string.
+ */
+char *registry_unload(const char *name);
+
+/**
+ * Encode a batch of UTF-8 strings (given as in `encode_batch_bytes`) using given tokenizer and
+ * EncodeParams, and return all results serialized in one MessagePack blob, so the caller does only
+ * one copy and one free (with `free_bytes`) per batch.
+ *
+ * The schema of the blob is a map with the following keys (string keys, in this order):
+ *
+ * - `encodings`: array with one map per message encoded, with the keys:
+ *   - `ids`: array of uint, always present.
+ *   - `type_ids`: array of uint, if ENCODE_RETURN_TYPE_IDS is set.
+ *   - `special_tokens_mask`: array of uint, if ENCODE_RETURN_SPECIAL_TOKENS_MASK is set.
+ *   - `attention_mask`: array of uint, if ENCODE_RETURN_ATTENTION_MASK is set.
+ *   - `tokens`: array of str, if ENCODE_RETURN_TOKENS is set.
+ *   - `offsets`: array of `[start, end]` uint pairs, if ENCODE_RETURN_OFFSETS is set (in UTF-16 code units if
+ *     ENCODE_WITH_OFFSETS_UTF16_MODE is set).
+ *   - `token_char_lengths`: array of uint, if ENCODE_RETURN_TOKEN_CHAR_LENGTHS is set.
+ *   - `word_ids`: array of int, if ENCODE_RETURN_DEBUG_INFO, ENCODE_RETURN_WORDS or ENCODE_RETURN_WORD_IDS is set
+ *     (see `Buffer.word_ids`).
+ *   - `token_scores`: array of float, if ENCODE_RETURN_DEBUG_INFO is set (see `Buffer.token_scores`).
+ *   - `window`: `[start, end]` uint pair, if ENCODE_RETURN_OVERFLOWING is set (see `Buffer.window`).
+ *   - `overflowing`: array of maps with these same keys, one per overflowing window, if
+ *     ENCODE_RETURN_OVERFLOWING is set.
+ *   - `length`: uint, if ENCODE_RETURN_LENGTH is set (see `Buffer.length`).
+ *   - `words`: array of `[start, end]` uint pairs, if ENCODE_RETURN_WORDS is set (see `Buffer.words`).
+ *   - `word_starts`: array of uint, if ENCODE_RETURN_WORD_STARTS is set (see `Buffer.word_starts`).
+ *   - `attention_mask_bits` and `special_tokens_mask_bits`: binary, instead of `attention_mask` and
+ *     `special_tokens_mask` if ENCODE_PACKED_MASKS is set (see `Buffer.attention_mask_bits`).
+ *   - `sequence_ids`: array of int, if ENCODE_RETURN_SEQUENCE_IDS is set (see `Buffer.sequence_ids`).
+ */
+struct BytesOrError encode_batch_serialized(struct TokenizerHandle *tokenizer_ptr,
+                                            uint32_t num_messages,
+                                            const uint8_t *const *messages,
+                                            const uint32_t *lengths,
+                                            struct EncodeParams options);
+
+/**
+ * set_sequence_stats enables (or disables) the collection of the statistics of the sequences encoded by
+ * the tokenizer, retrieved with `get_sequence_stats`. Enabling it when already enabled keeps the statistics,
+ * and disabling it discards them.
+ *
+ * It must be enabled before freezing the tokenizer, and the statistics are then shared with the frozen copy.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller), to be freed with `free_string`.
+ */
+char *set_sequence_stats(struct TokenizerHandle *tokenizer_ptr,
+                         bool enabled);
+
+/**
+ * get_sequence_stats writes to `stats` the statistics of the sequences encoded by the tokenizer since they
+ * were enabled (see `set_sequence_stats`), or since the last `reset_sequence_stats`.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller), to be freed with `free_string`.
+ *
+ * # Safety
+ *
+ * `stats` must point to a writable SequenceStats.
+ */
+char *get_sequence_stats(struct TokenizerHandle *tokenizer_ptr,
+                         struct SequenceStats *stats);
+
+/**
+ * reset_sequence_stats clears the statistics of the sequences encoded by the tokenizer, if enabled.
+ */
+void reset_sequence_stats(struct TokenizerHandle *tokenizer_ptr);
+
+/**
+ * decode_stream_new returns a `DecodeStream *` in the `value` field, to decode the ids generated one at a time
+ * (e.g. by a language model) with `decode_stream_step`, as `decode` would decode all of them, but in time
+ * linear in the number of ids.
+ *
+ * The stream holds a reference to the tokenizer (see `tokenizer_retain`), so it can be freed independently.
+ * Free the stream with `decode_stream_free`.
+ *
+ * # Safety
+ *
+ * `tokenizer_ptr` must be null or a live tokenizer returned by this library (e.g. by `from_bytes`).
+ */
+struct PointerOrError decode_stream_new(struct TokenizerHandle *tokenizer_ptr,
+                                        bool skip_special_tokens);
+
+/**
+ * decode_stream_step decodes the next `id` of the stream, and returns the text it completes in the `value`
+ * field (a C string that must be freed with `free_string`), or null if it doesn't complete any text yet: e.g.
+ * the first bytes of a multi-byte character, with byte-level or byte fallback tokens. Broken UTF-8 is never
+ * emitted: see `decode_stream_status` for the text withheld.
+ *
+ * # Safety
+ *
+ * `stream` must have been returned by `decode_stream_new`, and not freed.
+ */
+struct PointerOrError decode_stream_step(struct DecodeStream *stream,
+                                         uint32_t id);
+
+/**
+ * decode_stream_status returns whether the stream is withholding text pending the completion of a multi-byte
+ * character, and how much: the number of ids, and of their bytes (e.g. 2 for the first 2 bytes of a 4-byte
+ * emoji). Hosts can use it to flush the withheld text (see `decode_stream_flush`) after a timeout, e.g. for
+ * interactive UIs.
+ *
+ * # Safety
+ *
+ * `stream` must have been returned by `decode_stream_new`, and not freed.
+ */
+struct DecodeStreamStatus decode_stream_status(const struct DecodeStream *stream);
+
+/**
+ * decode_stream_flush returns the text withheld by the stream in the `value` field (a C string that must be
+ * freed with `free_string`), with the incomplete characters replaced by U+FFFD -- so it is still valid UTF-8 --
+ * or null if no text is withheld. The ids given after a flush are decoded following the text emitted before the
+ * withheld ids (e.g. with the space before a word), as if these had not been given.
+ *
+ * # Safety
+ *
+ * `stream` must have been returned by `decode_stream_new`, and not freed.
+ */
+struct PointerOrError decode_stream_flush(struct DecodeStream *stream);
+
+/**
+ * decode_stream_free frees the stream, and its reference to the tokenizer.
+ *
+ * # Safety
+ *
+ * `stream` must have been returned by `decode_stream_new`, and it must not be used after this call.
+ */
+void decode_stream_free(struct DecodeStream *stream);
+
+/**
+ * set_thread_policy configures the threads used to encode batches from now on: at most `max_threads` threads,
+ * optionally pinned to the given CPUs. A null `policy` (or one with no limit and no CPUs) restores the
+ * default, one thread per CPU in a global pool.
+ *
+ * The threads are shared by all the batch calls, including concurrent ones, so they also cap the total number
+ * of threads used for tokenization. Calls already running keep the previous threads.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller), to be freed with `free_string`.
+ *
+ * # Safety
+ *
+ * `policy` must be null or point to a ThreadPolicy, whose `cpus` (if not null) has `num_cpus` values.
+ */
+char *set_thread_policy(const struct ThreadPolicy *policy);
+
+/**
+ * set_slow_input_hook sets the `callback` called with each text, encoded by the encode calls, that took at
+ * least `min_duration_us` microseconds to encode, or that encoded to at least `min_tokens` tokens -- a zero
+ * threshold is disabled. At most `max_sample_len` bytes of the text are passed to the callback.
+ *
+ * A null `callback` removes the hook. The hook applies to all tokenizers, and while it is set the texts of
+ * a batch are timed one by one.
+ */
+void set_slow_input_hook(SlowInputCallback callback,
+                         void *user_data,
+                         uint64_t min_duration_us,
+                         uint64_t min_tokens,
+                         uint32_t max_sample_len);
+
+/**
+ * extend_vocabulary returns a new tokenizer, in the `value` field, with the vocabulary of the BPE tokenizer
+ * extended with up to `params.num_merges` merges (and their tokens) learned from a domain corpus: the
+ * `num_messages` UTF-8 strings (given as in `encode_batch_bytes`). The merges are learned as the BPE trainer
+ * does, but starting from the segmentation of the texts by the current model, and they are applied after
+ * the original merges. The original tokens keep their ids, and the new ones get the ids after them.
+ *
+ * Only the pairs occurring at least `params.min_frequency` times are merged, so fewer merges may be learned.
+ * The original tokenizer is not modified. It is an error if the model is not BPE.
+ *
+ * The caller owns the returned tokenizer, and should free it with `free_tokenizer`.
+ *
+ * # Safety
+ *
+ * `messages` and `lengths` must point to `num_messages` strings and lengths, and `params` to a
+ * VocabExtensionParams.
+ */
+struct PointerOrError extend_vocabulary(struct TokenizerHandle *tokenizer_ptr,
+                                        uint32_t num_messages,
+                                        const uint8_t *const *messages,
+                                        const uint32_t *lengths,
+                                        const struct VocabExtensionParams *params);
+
+/**
+ * train_from_files trains a new tokenizer on the corpus in the `num_files` text files at the paths in
+ * `files` (one text per line), and returns it in the `value` field, or an error. The model type and the
+ * options of its trainer (see the trainers of the tokenizers library) are given in `params`.
+ *
+ * The normalizer, pre-tokenizer and decoder of the new tokenizer are copied from the tokenizer at
+ * `template_ptr` (e.g. one loaded from a `tokenizer.json` with the desired pipeline), which is not modified.
+ * Its model, added tokens, post-processor, padding and truncation are not copied, since they refer to the ids of
+ * its vocabulary: the special tokens of `params` are added instead, and the other settings can be set on the new
+ * tokenizer (e.g. with `set_tokenizer_config`). If `template_ptr` is null, the pipeline only splits the words
+ * on whitespace and punctuation (and joins the WordPiece subwords back when decoding).
+ *
+ * The call blocks until the training is completed, using the threads set with `set_thread_policy`.
+ *
+ * The caller owns the returned tokenizer, and should free it with `free_tokenizer`.
+ *
+ * # Safety
+ *
+ * `params` must point to a TrainParams, and `files` to `num_files` C strings.
+ */
+struct PointerOrError train_from_files(struct TokenizerHandle *template_ptr,
+                                       const struct TrainParams *params,
+                                       uint32_t num_files,
+                                       const char *const *files);
+
+/**
+ * get_added_vocab returns the tokens added on top of the base model (with `add_tokens`, `add_special_tokens`
+ * or in the `added_tokens` section of `tokenizer.json`), that is, the ids not covered by the model's own
+ * vocabulary. Added tokens that are also part of the model's vocabulary (e.g.: `[CLS]` in most BERT
+ * tokenizers) are not included, since they don't require new embedding rows.
+ *
+ * The result must be freed with `free_vocab`.
+ */
+struct Vocab get_added_vocab(struct TokenizerHandle *tokenizer_ptr);
+
+/**
+ * get_vocab returns the vocabulary of the tokenizer: the tokens of the model and, if `with_added_tokens` is
+ * set, the added tokens (see `get_added_vocab`). See `for_each_vocab_entry` to iterate over huge vocabularies
+ * without copying them.
+ *
+ * The result must be freed with `free_vocab`.
+ */
+struct Vocab get_vocab(struct TokenizerHandle *tokenizer_ptr,
+                       bool with_added_tokens);
+
+/**
+ * token_to_id returns the id of the `token` (from the model or the added tokens), or -1 if it is not in the
+ * vocabulary, or if the tokenizer is invalid.
+ *
+ * # Safety
+ *
+ * `token` must be a valid C string.
+ */
+int64_t token_to_id(struct TokenizerHandle *tokenizer_ptr,
+                    const char *token);
+
+/**
+ * id_to_token returns the token of the `id` (from the model or the added tokens), or null if it is not in the
+ * vocabulary, or if the tokenizer is invalid. See `id_to_token_batch` to convert many ids at once.
+ *
+ * The returned string needs to be freed with `free_string`.
+ */
+char *id_to_token(struct TokenizerHandle *tokenizer_ptr,
+                  uint32_t id);
+
+/**
+ * Frees a `Vocab` returned by Rust to Golang.
+ *
+ * # Safety
+ *
+ * `vocab` must have been returned by Rust, and it must not be used after this call.
+ */
+void free_vocab(struct Vocab vocab);
+
+/**
+ * max_token_id returns the largest token id known by the tokenizer, including added tokens. Since ids
+ * may have gaps (e.g.: added tokens with ids far from the model's vocabulary), it can be larger than
+ * `vocab_size - 1`: logits and embeddings tables should have at least `max_token_id + 1` rows.
+ *
+ * It returns -1 if the tokenizer is invalid or its vocabulary is empty.
+ */
+int64_t max_token_id(struct TokenizerHandle *tokenizer_ptr);
+
+/**
+ * ids_are_valid returns whether all the `len` token ids in `ids` are known by the tokenizer (either by the
+ * model or as added tokens), and hence can be decoded.
+ *
+ * It returns false if the tokenizer is invalid.
+ *
+ * # Safety
+ *
+ * `ids` must point to `len` token ids.
+ */
+bool ids_are_valid(struct TokenizerHandle *tokenizer_ptr,
+                   const uint32_t *ids,
+                   uint32_t len);
+
+/**
+ * Frees a `PackedTokens` returned by Rust to Golang.
+ *
+ * # Safety
+ *
+ * `tokens` must have been returned by Rust, and it must not be used after this call.
+ */
+void free_packed_tokens(struct PackedTokens tokens);
+
+/**
+ * preload_token_strings builds the table of the strings of the tokens used to decode (and by
+ * `id_to_token_batch`) now, instead of on the first call that needs it. The table is built lazily so
+ * encode-only workloads don't pay for it -- in load time and memory, with huge vocabularies -- but
+ * latency-critical decode paths can call this after loading (or modifying) the tokenizer.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+ * The returned string needs to be freed with `free_string`.
+ */
+char *preload_token_strings(struct TokenizerHandle *tokenizer_ptr);
+
+/**
+ * id_to_token_batch returns the token strings of the `len` token ids in `ids` (from the model or the added
+ * tokens), packed in one buffer, e.g. to render the top-k tables of logits without one call per id.
+ * Unknown ids get an empty token, since tokens are never empty.
+ *
+ * The result must be freed with `free_packed_tokens`.
+ *
+ * # Safety
+ *
+ * `ids` must point to `len` token ids.
+ */
+struct PackedTokens id_to_token_batch(struct TokenizerHandle *tokenizer_ptr,
+                                      const uint32_t *ids,
+                                      uint32_t len);
+
+/**
+ * token_to_id_batch writes to `ids` the id of each of the `num_tokens` tokens (from the model or the added
+ * tokens), given as UTF-8 strings as in `encode_batch_bytes`, or TOKEN_NOT_FOUND if a token is not in the
+ * vocabulary, e.g. to map word lists (stop words, bad words, constraints) to ids in one call.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+ * The returned string needs to be freed with `free_string`.
+ *
+ * # Safety
+ *
+ * `tokens` and `lengths` must point to `num_tokens` strings and lengths, and `ids` to space for `num_tokens`
+ * ids.
+ */
+char *token_to_id_batch(struct TokenizerHandle *tokenizer_ptr,
+                        uint32_t num_tokens,
+                        const uint8_t *const *tokens,
+                        const uint32_t *lengths,
+                        uint32_t *ids);
+
+/**
+ * set_added_token_attributes updates the attributes of the added `token` (its content), e.g. to fix a
+ * misconfigured checkpoint at load time:
+ *
+ * - `single_word`: whether the token only matches whole words.
+ * - `lstrip` and `rstrip`: whether the spaces on its left (right) are included in the token.
+ * - `normalized`: whether the token is matched against the normalized text, instead of the original text.
+ * - `special`: whether it is a special token (e.g. skipped when decoding with `skip_special_tokens`).
+ *
+ * It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+ * The returned string needs to be freed with `free_string`.
+ *
+ * # Safety
+ *
+ * `token` must be a valid C string.
+ */
+char *set_added_token_attributes(struct TokenizerHandle *tokenizer_ptr,
+                                 const char *token,
+                                 const struct AddedTokenAttributes *attributes);
+
+/**
+ * add_tokens adds the `num_tokens` tokens (their contents) to the added vocabulary, e.g. domain markers, so they
+ * are matched in the input text (after normalization) and never split by the model. The tokens not already
+ * in the vocabulary get new ids, after all the current ones (see `get_added_vocab` to resize the embeddings),
+ * and the others keep their id.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+ * The returned string needs to be freed with `free_string`.
+ *
+ * # Safety
+ *
+ * `tokens` must point to `num_tokens` valid C strings.
+ */
+char *add_tokens(struct TokenizerHandle *tokenizer_ptr,
+                 uint32_t num_tokens,
+                 const char *const *tokens);
+
+/**
+ * add_special_tokens adds the `num_tokens` tokens as special tokens, as `add_tokens`, except that they are
+ * matched in the original text (not normalized), and they are skipped when decoding with
+ * `skip_special_tokens`. Tokens already added are made special.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+ * The returned string needs to be freed with `free_string`.
+ *
+ * # Safety
+ *
+ * `tokens` must point to `num_tokens` valid C strings.
+ */
+char *add_special_tokens(struct TokenizerHandle *tokenizer_ptr,
+                         uint32_t num_tokens,
+                         const char *const *tokens);
+
+/**
+ * remove_added_tokens removes the `num_tokens` added tokens (their contents) from the added vocabulary, e.g.
+ * to clean up the tokens added for an experiment without reloading the tokenizer. They are no longer
+ * matched in the input text, and the added vocabulary is rebuilt without them.
+ *
+ * Tokens that are also in the vocabulary of the model (e.g. "[CLS]" in BERT) only lose their matching as
+ * added (special) tokens: they keep their id, and are encoded and decoded as the other tokens of the model.
+ * The ids of the other tokens are freed. It is an error if any of the tokens is not an added token, in which
+ * case the tokenizer is not modified.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+ * The returned string needs to be freed with `free_string`.
+ *
+ * # Safety
+ *
+ * `tokens` must point to `num_tokens` valid C strings.
+ */
+char *remove_added_tokens(struct TokenizerHandle *tokenizer_ptr,
+                          uint32_t num_tokens,
+                          const char *const *tokens);
+
+/**
+ * for_each_vocab_entry calls the host `callback` (see `VocabEntryCallback`) from the calling thread with each
+ * (token, id) of the vocabulary, sorted by id, and the given `user_data`. If `with_added_tokens` is set, the
+ * added tokens that are not in the model's vocabulary are included.
+ *
+ * Different from the bulk queries (e.g. `get_added_vocab`), the tokens are streamed one by one, so very large
+ * vocabularies are never copied whole. The tokenizer is locked for reading during the iteration: the
+ * callback must not modify it.
+ *
+ * It returns null if ok (also if the callback stopped the iteration), or a string with an error message
+ * (owned by caller) if something went wrong. The returned string needs to be freed with `free_string`.
+ */
+char *for_each_vocab_entry(struct TokenizerHandle *tokenizer_ptr,
+                           bool with_added_tokens,
+                           VocabEntryCallback callback,
+                           void *user_data);
+
+/**
+ * warmup prepares the tokenizer to serve its first request as fast as the following ones: it builds the
+ * tables derived from the vocabulary now instead of on first use (see `preload_token_strings`), and encodes
+ * and decodes a sample text, initializing the parts of the pipeline initialized on first use (e.g. the
+ * patterns of the byte-level pre-tokenizer). It should be called after loading (or modifying) the tokenizer.
+ *
+ * If `on_workers` is set, the sample text is also encoded on each of the threads used to encode batches (see
+ * `set_thread_policy`), starting them if needed, so the first batch call doesn't pay for it either.
+ *
+ * It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+ * The returned string needs to be freed with `free_string`.
  */
-char *decode(void *tokenizer_ptr, const uint32_t *ids, uint32_t len, bool skip_special_tokens);
+char *warmup(struct TokenizerHandle *tokenizer_ptr,
+             bool on_workers);
 
 /* File generated with cbindgen from the Rust library -- don't change it directly */
@@ -15,6 +15,7 @@ package rs
 
 /*
 #cgo linux&&amd64 LDFLAGS: ${SRCDIR}/../../lib/linux_amd64/libgomlx_tokenizers.a -ldl -lm -lstdc++
+#cgo linux&&arm64 LDFLAGS: ${SRCDIR}/../../lib/linux_arm64/libgomlx_tokenizers.a -ldl -lm
 #include <stdlib.h>
 #include "gomlx_tokenizers.h"
 */
@@ -49,20 +50,28 @@ type Encoding struct {
 
 // EncodeParams are passed at `Encode` or `EncodeBatch` calls.
 //
-// It's copy of the underlying C.EncodeParams.
+// It's converted to the flags of the underlying C.EncodeParams.
 type EncodeParams struct {
 	AddSpecialTokens, ReturnTokens, ReturnTypeIds, ReturnSpecialTokensMask, ReturnAttentionMask, ReturnOffsets, WithOffsetsCharMode bool
 }
 
 func encodeParamsToC(p EncodeParams) C.EncodeParams {
+	var flags C.uint64_t
+	setFlag := func(isSet bool, flag C.uint64_t) {
+		if isSet {
+			flags |= flag
+		}
+	}
+	setFlag(p.AddSpecialTokens, C.ENCODE_ADD_SPECIAL_TOKENS)
+	setFlag(p.ReturnTokens, C.ENCODE_RETURN_TOKENS)
+	setFlag(p.ReturnTypeIds, C.ENCODE_RETURN_TYPE_IDS)
+	setFlag(p.ReturnSpecialTokensMask, C.ENCODE_RETURN_SPECIAL_TOKENS_MASK)
+	setFlag(p.ReturnAttentionMask, C.ENCODE_RETURN_ATTENTION_MASK)
+	setFlag(p.ReturnOffsets, C.ENCODE_RETURN_OFFSETS)
+	setFlag(p.WithOffsetsCharMode, C.ENCODE_WITH_OFFSETS_CHAR_MODE)
 	return C.EncodeParams{
-		add_special_tokens:         C.bool(p.AddSpecialTokens),
-		return_tokens:              C.bool(p.ReturnTokens),
-		return_type_ids:            C.bool(p.ReturnTypeIds),
-		return_special_tokens_mask: C.bool(p.ReturnSpecialTokensMask),
-		return_attention_mask:      C.bool(p.ReturnAttentionMask),
-		return_offsets:             C.bool(p.ReturnOffsets),
-		with_offsets_char_mode:     C.bool(p.WithOffsetsCharMode),
+		version: C.ENCODE_PARAMS_VERSION,
+		flags:   flags,
 	}
 }
 
@@ -87,7 +96,7 @@ func uint32VecToSlice(arrPtr *C.uint32_t, arrLen int) []uint32 {
 }
 
 type Tokenizer struct {
-	tokenizer unsafe.Pointer
+	tokenizer *C.TokenizerHandle
 }
 
 type TruncationDirection int
@@ -122,7 +131,7 @@ func FromBytes(data []byte) (*Tokenizer, error) {
 	if err != nil {
 		return nil, err
 	}
-	t := &Tokenizer{tokenizer: pointerOrError.value}
+	t := &Tokenizer{tokenizer: (*C.TokenizerHandle)(pointerOrError.value)}
 	CountTokenizerAllocs.Add(1)
 	runtime.SetFinalizer(t, func(t *Tokenizer) { t.Finalize() })
 
@@ -180,13 +189,17 @@ func (t *Tokenizer) SetNoTruncation() error {
 // GetTruncation returns the current truncation parameters of the Tokenizer.
 // If there are no parameters set, `isSet` is false, and the other values should be ignored.
 // Otherwise, `isSet` is true, and the other values are returned appropriately.
-func (t *Tokenizer) GetTruncation() (isSet bool, direction uint8, maxLength uint32, strategy uint8, stride uint32) {
+// It returns an error if the tokenizer is invalid.
+func (t *Tokenizer) GetTruncation() (isSet bool, direction uint8, maxLength uint32, strategy uint8, stride uint32, err error) {
 	if t.tokenizer == nil {
+		err = errors.New("tokenizer has already finalized and is now invalid")
 		return
 	}
 	params := &C.TruncationParams{}
-	isSet = bool(C.get_truncation(t.tokenizer, params))
+	var cIsSet C.bool
+	err = errorFromCStr(C.get_truncation(t.tokenizer, params, &cIsSet))
 	runtime.KeepAlive(t)
+	isSet = err == nil && bool(cIsSet)
 	if isSet {
 		direction = uint8(params.direction)
 		maxLength = uint32(params.max_length)
@@ -235,13 +248,17 @@ func (t *Tokenizer) SetNoPadding() {
 // GetPadding returns the current padding parameters of the Tokenizer.
 // If there are no parameters set, `isSet` is false, and the other values should be ignored.
 // Otherwise, `isSet` is true, and the other values are returned appropriately.
-func (t *Tokenizer) GetPadding() (isSet bool, strategy uint32, direction uint8, padToMultipleOf, padId, padTypeId uint32, padToken string) {
+// It returns an error if the tokenizer is invalid.
+func (t *Tokenizer) GetPadding() (isSet bool, strategy uint32, direction uint8, padToMultipleOf, padId, padTypeId uint32, padToken string, err error) {
 	if t.tokenizer == nil {
+		err = errors.New("tokenizer has already finalized and is now invalid")
 		return
 	}
 	params := &C.PaddingParams{}
-	isSet = bool(C.get_padding(t.tokenizer, params))
+	var cIsSet C.bool
+	err = errorFromCStr(C.get_padding(t.tokenizer, params, &cIsSet))
 	runtime.KeepAlive(t)
+	isSet = err == nil && bool(cIsSet)
 	if isSet {
 		strategy = uint32(params.strategy)
 		direction = uint8(params.direction)
@@ -373,23 +390,37 @@ func (t *Tokenizer) parseResult(params EncodeParams, buffer C.Buffer, output *En
 	}
 }
 
-func (t *Tokenizer) Decode(tokenIDs []uint32, skipSpecialTokens bool) string {
+// Decode converts the tokenIDs back to a string, or returns an error if the tokenizer is invalid or the decoding
+// failed.
+func (t *Tokenizer) Decode(tokenIDs []uint32, skipSpecialTokens bool) (string, error) {
 	if t.tokenizer == nil {
-		return ""
+		return "", errors.New("tokenizer has already finalized and is now invalid")
 	}
 	if len(tokenIDs) == 0 {
-		return ""
+		return "", nil
 	}
+
+	// We expected a DecodeResults with only one result.
 	res := C.decode(t.tokenizer, (*C.uint)(unsafe.Pointer(&tokenIDs[0])), C.uint(len(tokenIDs)), C.bool(skipSpecialTokens))
 	runtime.KeepAlive(tokenIDs)
-	defer C.free_string(res)
-
-	return C.GoString(res)
+	runtime.KeepAlive(t)
+	defer C.free_decode_results(res)
+	if res.len != 1 || res.error != nil {
+		if res.error != nil {
+			return "", errors.New(C.GoString(res.error))
+		}
+		return "", errors.Errorf("Tokenizer.Decode failed, got %d results, wanted 1.", res.len)
+	}
+	return C.GoString(*res.decoded), nil
 }
 
-func (t *Tokenizer) VocabSize() uint32 {
+// VocabSize returns the number of known tokens, or an error if the tokenizer is invalid.
+func (t *Tokenizer) VocabSize() (uint32, error) {
 	if t.tokenizer == nil {
-		return 0
+		return 0, errors.New("tokenizer has already finalized and is now invalid")
 	}
-	return uint32(C.vocab_size(t.tokenizer))
+	var size C.uint32_t
+	err := errorFromCStr(C.vocab_size(t.tokenizer, &size))
+	runtime.KeepAlive(t)
+	return uint32(size), err
 }
@@ -369,13 +369,15 @@ func TestEncodeWithTruncation(t *testing.T) {
 			tk, err := rs.FromBytes(embeddedBytes)
 			require.NoError(t, err)
 
-			isSet, _, _, _, _ := tk.GetTruncation()
+			isSet, _, _, _, _, err := tk.GetTruncation()
+			require.NoError(t, err)
 			assert.False(t, isSet)
 
 			err = tk.SetTruncation(uint8(tt.dir), uint32(tt.maxLen), 0, 0)
 			require.NoError(t, err)
 
-			isSet, direction, maxLength, strategy, stride := tk.GetTruncation()
+			isSet, direction, maxLength, strategy, stride, err := tk.GetTruncation()
+			require.NoError(t, err)
 			assert.True(t, isSet)
 			assert.Equal(t, uint8(tt.dir), direction)
 			assert.Equal(t, uint32(tt.maxLen), maxLength)
@@ -394,7 +396,8 @@ func TestEncodeWithTruncation(t *testing.T) {
 			// Checks reset of truncation.
 			err = tk.SetNoTruncation()
 			require.NoError(t, err)
-			isSet, _, _, _, _ = tk.GetTruncation()
+			isSet, _, _, _, _, err = tk.GetTruncation()
+			require.NoError(t, err)
 			assert.False(t, isSet)
 
 		})
@@ -449,12 +452,14 @@ func TestEncodeWithPadding(t *testing.T) {
 		t.Run(tt.name, func(t *testing.T) {
 			tk, err := rs.FromBytes(embeddedBytes)
 			require.NoError(t, err)
-			isSet, _, _, _, _, _, _ := tk.GetPadding()
+			isSet, _, _, _, _, _, _, err := tk.GetPadding()
+			require.NoError(t, err)
 			assert.False(t, isSet)
 
 			defer tk.Finalize()
 			tk.SetPadding(tt.padLen, tt.dir, tt.padToMultipleOf, tt.padId, 0, tt.padToken)
-			isSet, strategy, direction, padToMultipleOf, padId, padTypeId, padToken := tk.GetPadding()
+			isSet, strategy, direction, padToMultipleOf, padId, padTypeId, padToken, err := tk.GetPadding()
+			require.NoError(t, err)
 			assert.Equal(t, tt.padLen, strategy)
 			assert.Equal(t, tt.dir, direction)
 			assert.Equal(t, tt.padToMultipleOf, padToMultipleOf)
@@ -473,7 +478,8 @@ func TestEncodeWithPadding(t *testing.T) {
 
 			// Checks reset of padding.
 			tk.SetNoPadding()
-			isSet, _, _, _, _, _, _ = tk.GetPadding()
+			isSet, _, _, _, _, _, _, err = tk.GetPadding()
+			require.NoError(t, err)
 			assert.False(t, isSet)
 		})
 	}
@@ -555,7 +561,8 @@ func TestDecode(t *testing.T) {
 	}
 	for _, tt := range tests {
 		t.Run(tt.name, func(t *testing.T) {
-			got := tk.Decode(tt.tokens, tt.skipSpecial)
+			got, err := tk.Decode(tt.tokens, tt.skipSpecial)
+			require.NoError(t, err)
 			assert.Equal(t, tt.want, got)
 		})
 	}
@@ -565,7 +572,9 @@ func TestVocabSize(t *testing.T) {
 	tk, err := rs.FromFile(bertJson)
 	require.NoError(t, err)
 	defer tk.Finalize()
-	assert.Equal(t, uint32(30522), tk.VocabSize())
+	size, err := tk.VocabSize()
+	require.NoError(t, err)
+	assert.Equal(t, uint32(30522), size)
 }
 
 func BenchmarkEncodeNTimes(b *testing.B) {
@@ -607,7 +616,8 @@ func BenchmarkDecodeNTimes(b *testing.B) {
 	defer tk.Finalize()
 	b.ResetTimer()
 	for i := 0; i < b.N; i++ {
-		str := tk.Decode([]uint32{2829, 4419, 14523, 2058, 1996, 13971, 3899}, true)
+		str, err := tk.Decode([]uint32{2829, 4419, 14523, 2058, 1996, 13971, 3899}, true)
+		require.NoError(b, err)
 		assert.Equal(b, "brown fox jumps over the lazy dog", str)
 	}
 }
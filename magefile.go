@@ -41,6 +41,14 @@ var (
 		"linux/amd64":  "x86_64-unknown-linux-gnu",
 		"darwin/arm64": "aarch64-apple-darwin",
 		"darwin/amd64": "x86_64-apple-darwin",
+		"linux/arm64":  "aarch64-unknown-linux-gnu",
+	}
+
+	// Platforms built with the Rust features without C/C++ dependencies (see `pure-rust` in `rs/Cargo.toml`), so
+	// they can be cross-compiled with only the Rust toolchain (`rustup target add <rust platform>`).
+	// Set GOMLX_TOKENIZERS_PURE_RUST=1 to use them for all platforms.
+	pureRustPlatforms = map[string]bool{
+		"linux/arm64": true,
 	}
 )
 
@@ -80,6 +88,12 @@ func Linux_amd64() error {
 	return rustBuild(true, "linux/amd64")
 }
 
+// Builds the Rust library `libgomlx_tokenizers.a` for linux/arm64 platform.
+func Linux_arm64() error {
+	mg.Deps(Header)
+	return rustBuild(true, "linux/arm64")
+}
+
 // Builds the Rust library `libgomlx_tokenizers.a` for darwin/amd64 platform.
 func Darwin_amd64() error {
 	mg.Deps(Header)
@@ -93,6 +107,7 @@ func Darwin_arm64() error {
 }
 
 // Header builds the `internal/rs/gomlx_tokenizers.h` header file from the Rust sources, using `cbindgen`.
+// The header is also regenerated by `rs/build.rs` on every `cargo build`, this target is useful if only the header is needed.
 func Header() error {
 	// Check whether target is up-to-date.
 	pwd := must1(os.Getwd())
@@ -158,10 +173,15 @@ func rustBuild(isRelease bool, goPlatform string) error {
 	// Build from rust directory `rs`.
 	must(os.Chdir("rs"))
 	fmt.Printf("Building for platform %q\n", goPlatform)
-	args := []string{"build"}
+	// Only the static library is used: `cargo rustc` skips linking the `cdylib` (needed for WebAssembly), which
+	// would require a linker for the target platform when cross-compiling.
+	args := []string{"rustc", "--lib", "--crate-type", "staticlib"}
 	if isRelease {
 		args = append(args, "--release", "--target", rustPlatform)
 	}
+	if pureRustPlatforms[goPlatform] || os.Getenv("GOMLX_TOKENIZERS_PURE_RUST") == "1" {
+		args = append(args, "--no-default-features", "--features", "pure-rust")
+	}
 	err = sh.Run("cargo", args...)
 	must(os.Chdir(".."))
 	if err != nil {
@@ -0,0 +1,63 @@
+// Generates the C header file (`internal/rs/gomlx_tokenizers.h`) used by the Go bindings, with cbindgen,
+// so that the header is always in sync with the exported Rust functions and `#[repr(C)]` types.
+//
+// See `cbindgen.toml` for the configuration.
+//
+// Setting GOMLX_TOKENIZERS_SYMBOL_PREFIX (e.g. `GOMLX_TOKENIZERS_SYMBOL_PREFIX=gomlx_tok_ cargo build`) prefixes
+// the names of all the exported functions (e.g. `gomlx_tok_encode`), so that the library can be linked in the
+// same binary as other Rust libraries exporting the same names (e.g. `free_string`). The generated header then
+// `#define`s each unprefixed name to the prefixed symbol, so the C (and Go) code using it doesn't change.
+
+use std::env;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+const SYMBOL_PREFIX_ENV: &str = "GOMLX_TOKENIZERS_SYMBOL_PREFIX";
+
+// symbol_prefix returns the prefix of the exported symbols, and tells rustc about it: the `symbol!` macro in
+// `lib.rs` reads it with `env!`, and the `symbol_prefix` cfg is set when it is not empty.
+fn symbol_prefix() -> String {
+    println!("cargo:rerun-if-env-changed={}", SYMBOL_PREFIX_ENV);
+    println!("cargo:rustc-check-cfg=cfg(symbol_prefix)");
+    let prefix = env::var(SYMBOL_PREFIX_ENV).unwrap_or_default();
+    let is_identifier = prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !prefix.starts_with(|c: char| c.is_ascii_digit());
+    if !is_identifier {
+        panic!("{}={:?} is not a valid C identifier prefix", SYMBOL_PREFIX_ENV, prefix);
+    }
+    println!("cargo:rustc-env={}={}", SYMBOL_PREFIX_ENV, prefix);
+    if !prefix.is_empty() {
+        println!("cargo:rustc-cfg=symbol_prefix");
+    }
+    prefix
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    let prefix = symbol_prefix();
+
+    let crate_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let header_dir = crate_dir.join("..").join("internal").join("rs");
+    if !header_dir.is_dir() {
+        // Not building from within the Go module (e.g.: vendored crate): nothing to generate.
+        return;
+    }
+    let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml"))
+        .expect("failed to read cbindgen.toml");
+    match cbindgen::generate_with_config(&crate_dir, config) {
+        Ok(mut bindings) => {
+            if !prefix.is_empty() {
+                let mut defines = String::new();
+                for function in &bindings.functions {
+                    let name = function.path.name();
+                    writeln!(defines, "#define {} {}{}", name, prefix, name).unwrap();
+                }
+                bindings.config.after_includes = Some(defines);
+            }
+            // Only written if the contents changed.
+            bindings.write_to_file(header_dir.join("gomlx_tokenizers.h"));
+        }
+        Err(err) => println!("cargo:warning=failed to generate C header: {}", err),
+    }
+}
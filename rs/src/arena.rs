@@ -0,0 +1,522 @@
+use std::error::Error;
+use std::ffi::c_char;
+use std::ptr::null_mut;
+use tokenizers::Encoding;
+use crate::debug::TokenScores;
+use crate::encode::{
+    buffer_len, encode_batch_with_state, err, get_length, get_offsets, get_sequence_ids, get_token_char_lengths, get_token_scores, get_window,
+    get_num_words, get_word_ids, get_words, messages_from_bytes, pack_bits, token_scores_for, word_starts_for, Buffer, EncodeParams, EncodeResultsV2, Offset,
+    ENCODE_PACKED_MASKS, ENCODE_RETURN_ATTENTION_MASK, ENCODE_RETURN_DEBUG_INFO, ENCODE_RETURN_LENGTH, ENCODE_RETURN_OFFSETS, ENCODE_RETURN_OVERFLOWING,
+    ENCODE_RETURN_SPECIAL_TOKENS_MASK, ENCODE_RETURN_TOKENS, ENCODE_RETURN_TOKEN_CHAR_LENGTHS,
+    ENCODE_RETURN_TYPE_IDS, ENCODE_RETURN_WORDS, ENCODE_RETURN_WORD_STARTS, ENCODE_RETURN_SEQUENCE_IDS, ENCODE_RETURN_WORD_IDS,
+};
+use crate::free_string;
+use crate::handle::{convert_to_handle_ref, TokenizerHandle};
+use crate::panics::{catch_panic, catch_panic_or, error_c_string};
+use crate::words::WordStarts;
+
+// Alignment of every allocation in the arena: enough for all the types of a Buffer.
+const ARENA_ALIGN: usize = 8;
+
+// ArenaWriter lays out values in an arena: it is used twice, first only measuring (with a null `base`), to
+// find the size of the arena, and then writing the values into it.
+struct ArenaWriter {
+    base: *mut u8,
+    pos: usize,
+}
+
+impl ArenaWriter {
+    // alloc reserves space for `len` values of type T, and returns a pointer to it -- null if measuring.
+    fn alloc<T>(&mut self, len: usize) -> *mut T {
+        let ptr = if self.base.is_null() { null_mut() } else { unsafe { self.base.add(self.pos).cast() } };
+        self.pos += (len * size_of::<T>()).next_multiple_of(ARENA_ALIGN);
+        ptr
+    }
+
+    // push copies the `values` to the arena, and returns a pointer to them -- null if measuring.
+    fn push<T: Copy>(&mut self, values: &[T]) -> *mut T {
+        let ptr = self.alloc::<T>(values.len());
+        if !ptr.is_null() {
+            unsafe { std::ptr::copy_nonoverlapping(values.as_ptr(), ptr, values.len()) };
+        }
+        ptr
+    }
+
+    // push_with reserves space for `len` values of type T, and moves the `values` to it, returning a pointer to
+    // them -- null if measuring. The `values` are only converted when writing: measuring only needs their number.
+    fn push_with<T>(
+        &mut self,
+        len: usize,
+        values: impl FnOnce() -> Result<Vec<T>, Box<dyn Error>>,
+    ) -> Result<*mut T, Box<dyn Error>> {
+        let ptr = self.alloc::<T>(len);
+        if !ptr.is_null() {
+            let values = values()?;
+            assert_eq!(values.len(), len, "arena layout changed between passes");
+            for (i, value) in values.into_iter().enumerate() {
+                unsafe { ptr.add(i).write(value) };
+            }
+        }
+        Ok(ptr)
+    }
+
+    // push_tokens copies the `tokens` as null-terminated strings to the arena, and returns the array of
+    // pointers to them -- null if measuring.
+    fn push_tokens(&mut self, tokens: &[String]) -> Result<*mut *mut c_char, Box<dyn Error>> {
+        let pointers = self.alloc::<*mut c_char>(tokens.len());
+        let strings = self.alloc::<u8>(tokens.iter().map(|token| token.len() + 1).sum());
+        if strings.is_null() {
+            return Ok(pointers);
+        }
+        let mut start = 0;
+        for (i, token) in tokens.iter().enumerate() {
+            if token.as_bytes().contains(&0) {
+                return Err(err(format!("token {:?} contains a null byte", token)));
+            }
+            unsafe {
+                let dst = strings.add(start);
+                std::ptr::copy_nonoverlapping(token.as_ptr(), dst, token.len());
+                *dst.add(token.len()) = 0;
+                *pointers.add(i) = dst.cast();
+            }
+            start += token.len() + 1;
+        }
+        Ok(pointers)
+    }
+
+    // push_buffer lays out the Buffer of the `encoding` of `text` (with the fields requested in `options`) in
+    // the arena, as `encode_process` does: the Buffer is returned, and only its arrays are in the arena. When
+    // measuring, the values are not converted, and the Buffer returned is incomplete.
+    fn push_buffer(
+        &mut self,
+        encoding: &Encoding,
+        text: &str,
+        options: &EncodeParams,
+        scores: Option<&TokenScores>,
+        word_starts: Option<&WordStarts>,
+    ) -> Result<Buffer, Box<dyn Error>> {
+        let num_tokens = encoding.get_ids().len();
+        let len = buffer_len(num_tokens)?;
+        let measuring = self.base.is_null();
+        let spans = (!measuring && options.has(ENCODE_RETURN_OFFSETS | ENCODE_RETURN_WORDS | ENCODE_RETURN_OVERFLOWING))
+            .then(|| get_offsets(encoding, &[text], options));
+        let spans = spans.as_deref().unwrap_or(&[]);
+        let tokens = if options.has(ENCODE_RETURN_TOKENS) {
+            self.push_tokens(encoding.get_tokens())?
+        } else {
+            null_mut()
+        };
+        let offsets = if options.has(ENCODE_RETURN_OFFSETS) {
+            self.push_with(num_tokens, || spans.iter().map(|&span| Offset::new(span)).collect())?
+        } else {
+            null_mut()
+        };
+        let (word_ids, token_scores) = match scores {
+            Some(scores) if options.has(ENCODE_RETURN_DEBUG_INFO) => (
+                self.push_with(num_tokens, || Ok(get_word_ids(encoding)))?,
+                self.push_with(num_tokens, || Ok(get_token_scores(encoding, scores)))?,
+            ),
+            _ if options.has(ENCODE_RETURN_WORDS | ENCODE_RETURN_WORD_IDS) => {
+                (self.push_with(num_tokens, || Ok(get_word_ids(encoding)))?, null_mut())
+            }
+            _ => (null_mut(), null_mut()),
+        };
+        let (words, num_words) = if options.has(ENCODE_RETURN_WORDS) {
+            let num_words = get_num_words(encoding);
+            let words = self.push_with(num_words, || get_words(encoding, spans).into_iter().map(Offset::new).collect())?;
+            (words, num_words as u64)
+        } else {
+            (null_mut(), 0)
+        };
+        let mut window = Offset { start: 0, end: 0 };
+        let mut overflowing = null_mut();
+        let mut num_overflowing = 0;
+        if options.has(ENCODE_RETURN_OVERFLOWING) {
+            if !measuring {
+                window = Offset::new(get_window(encoding, spans))?;
+            }
+            let overflows = encoding.get_overflowing();
+            if !overflows.is_empty() {
+                overflowing = self.alloc::<Buffer>(overflows.len());
+                num_overflowing = overflows.len() as u64;
+                for (i, overflow) in overflows.iter().enumerate() {
+                    let buffer = self.push_buffer(overflow, text, options, scores, word_starts)?;
+                    if !overflowing.is_null() {
+                        unsafe { overflowing.add(i).write(buffer) };
+                    }
+                }
+            }
+        }
+        let optional = |flag: u64, writer: &mut ArenaWriter, values: &[u32]| {
+            if options.has(flag) { writer.push(values) } else { null_mut() }
+        };
+        let packed = options.has(ENCODE_PACKED_MASKS);
+        let optional_mask = |flag: u64, writer: &mut ArenaWriter, values: &[u32]| {
+            if options.has(flag) && !packed { writer.push(values) } else { null_mut() }
+        };
+        let optional_bits = |flag: u64, writer: &mut ArenaWriter, values: &[u32]| {
+            if options.has(flag) && packed {
+                writer.push_with(values.len().div_ceil(8), || Ok(pack_bits(values)))
+            } else {
+                Ok(null_mut())
+            }
+        };
+        Ok(Buffer {
+            ids: self.push(encoding.get_ids()),
+            type_ids: optional(ENCODE_RETURN_TYPE_IDS, self, encoding.get_type_ids()),
+            special_tokens_mask: optional_mask(ENCODE_RETURN_SPECIAL_TOKENS_MASK, self, encoding.get_special_tokens_mask()),
+            attention_mask: optional_mask(ENCODE_RETURN_ATTENTION_MASK, self, encoding.get_attention_mask()),
+            tokens,
+            offsets,
+            token_char_lengths: if options.has(ENCODE_RETURN_TOKEN_CHAR_LENGTHS) {
+                self.push_with(num_tokens, || Ok(get_token_char_lengths(encoding, &[text], options)))?
+            } else {
+                null_mut()
+            },
+            len,
+            word_ids,
+            token_scores,
+            window,
+            overflowing,
+            num_overflowing,
+            tokens_arena: null_mut(),
+            tokens_arena_len: 0,
+            length: if options.has(ENCODE_RETURN_LENGTH) { get_length(encoding) } else { 0 },
+            words,
+            num_words,
+            word_starts: match word_starts {
+                Some(word_starts) if options.has(ENCODE_RETURN_WORD_STARTS) => {
+                    self.push_with(num_tokens, || Ok(word_starts.get(encoding)))?
+                }
+                _ => null_mut(),
+            },
+            attention_mask_bits: optional_bits(ENCODE_RETURN_ATTENTION_MASK, self, encoding.get_attention_mask())?,
+            special_tokens_mask_bits: optional_bits(
+                ENCODE_RETURN_SPECIAL_TOKENS_MASK,
+                self,
+                encoding.get_special_tokens_mask(),
+            )?,
+            sequence_ids: if options.has(ENCODE_RETURN_SEQUENCE_IDS) {
+                self.push_with(num_tokens, || Ok(get_sequence_ids(encoding)))?
+            } else {
+                null_mut()
+            },
+        })
+    }
+
+    // push_buffers lays out the array of Buffers of the `encodings` of `texts` in the arena, and returns it.
+    fn push_buffers(
+        &mut self,
+        encodings: &[Encoding],
+        texts: &[&str],
+        options: &EncodeParams,
+        scores: Option<&TokenScores>,
+        word_starts: Option<&WordStarts>,
+    ) -> Result<*mut Buffer, Box<dyn Error>> {
+        let buffers = self.alloc::<Buffer>(encodings.len());
+        for (i, (encoding, text)) in encodings.iter().zip(texts).enumerate() {
+            let buffer = self.push_buffer(encoding, text, options, scores, word_starts)?;
+            if !buffers.is_null() {
+                unsafe { buffers.add(i).write(buffer) };
+            }
+        }
+        Ok(buffers)
+    }
+}
+
+// write_batch encodes the batch, and lays out all the results in `arena` after its first `start` words (which
+// are kept), reusing its capacity. It returns the results, with `encoded` pointing to the array of Buffers.
+pub(crate) fn write_batch(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_messages: usize,
+    messages: *const *const u8,
+    lengths: *const u32,
+    options: &EncodeParams,
+    arena: &mut Vec<u64>,
+    start: usize,
+) -> Result<EncodeResultsV2, Box<dyn Error>> {
+    let inputs = messages_from_bytes(num_messages, messages, lengths)?;
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let (encodings, scores, word_starts) = {
+        let state = handle.read();
+        let encodings = encode_batch_with_state(handle, &state, &inputs, options)?;
+        (encodings, token_scores_for(&state, options), word_starts_for(&state, options))
+    };
+    let total_tokens = encodings.iter().map(|encoding| encoding.get_ids().len() as u64).sum();
+
+    // Measure, then write: only the second pass converts the values (see `ArenaWriter::push_with`). If it fails,
+    // the contents of the arena after `start` are left undefined.
+    let mut measure = ArenaWriter { base: null_mut(), pos: start * ARENA_ALIGN };
+    measure.push_buffers(&encodings, &inputs, options, scores.as_deref(), word_starts.as_ref())?;
+    arena.truncate(start);
+    arena.resize(measure.pos / ARENA_ALIGN, 0);
+    let mut writer = ArenaWriter { base: arena.as_mut_ptr().cast(), pos: start * ARENA_ALIGN };
+    let encoded = writer.push_buffers(&encodings, &inputs, options, scores.as_deref(), word_starts.as_ref())?;
+    Ok(EncodeResultsV2 {
+        len: encodings.len() as u64,
+        total_tokens,
+        encoded,
+        error: null_mut(),
+    })
+}
+
+// encode_batch_arena_impl encodes the batch into a new arena: its first word holds the capacity of the arena
+// in words, followed by the array of Buffers (pointed by `encoded`) and all their data.
+fn encode_batch_arena_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_messages: usize,
+    messages: *const *const u8,
+    lengths: *const u32,
+    options: EncodeParams,
+) -> Result<EncodeResultsV2, Box<dyn Error>> {
+    let mut arena: Vec<u64> = vec![0];
+    let results = write_batch(tokenizer_ptr, num_messages, messages, lengths, &options, &mut arena, 1)?;
+    arena[0] = arena.capacity() as u64;
+    std::mem::forget(arena);
+    Ok(results)
+}
+
+/// encode_batch_arena encodes a batch of UTF-8 strings (given as in `encode_batch_bytes`), like
+/// `encode_batch_bytes_v2`, but allocates all the results (the Buffers, all their arrays and the token
+/// strings) in one single block of memory: so building the results takes one allocation, and freeing them
+/// takes one call, independent of the batch size.
+///
+/// The results must be freed with `free_encode_results_arena` -- not with `free_encode_results_v2`. The
+/// `tokens_arena` field of the Buffers is not used.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encode_batch_arena"))]
+pub unsafe extern "C" fn encode_batch_arena(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_messages: u64,
+    messages: *const *const u8,
+    lengths: *const u32,
+    options: EncodeParams,
+) -> EncodeResultsV2 {
+    let result = catch_panic(|| {
+        let num_messages = usize::try_from(num_messages)
+            .map_err(|_| err(format!("{} messages overflow usize", num_messages)))?;
+        encode_batch_arena_impl(tokenizer_ptr, num_messages, messages, lengths, options)
+    });
+    results_or_error(result)
+}
+
+// results_or_error converts errors to an EncodeResultsV2 with the error.
+fn results_or_error(result: Result<EncodeResultsV2, Box<dyn Error>>) -> EncodeResultsV2 {
+    match result {
+        Ok(results) => results,
+        Err(e) => EncodeResultsV2 {
+            len: 0,
+            total_tokens: 0,
+            encoded: null_mut(),
+            error: error_c_string(e),
+        },
+    }
+}
+
+/// Frees the results returned by `encode_batch_arena`, in one deallocation.
+///
+/// # Safety
+///
+/// `results` must have been returned by `encode_batch_arena`, and it must not be used after this call.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("free_encode_results_arena"))]
+pub unsafe extern "C" fn free_encode_results_arena(results: EncodeResultsV2) {
+    catch_panic_or((), || {
+        free_string(results.error);
+        if results.encoded.is_null() {
+            return;
+        }
+        unsafe {
+            let arena = results.encoded.cast::<u64>().sub(1);
+            let capacity = *arena as usize;
+            drop(Vec::from_raw_parts(arena, 0, capacity));
+        }
+    })
+}
+
+/// ResultSet holds the results of `encode_batch_into`, and is reused by successive calls: its memory is kept
+/// and overwritten, so steady-state encoding doesn't allocate (or free) memory for the results. It is an
+/// opaque `ResultSet *` in C, created with `result_set_new` and freed with `result_set_free`.
+pub struct ResultSet {
+    arena: Vec<u64>,
+}
+
+/// result_set_new creates an empty ResultSet, owned by the caller: free it with `result_set_free`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("result_set_new"))]
+pub extern "C" fn result_set_new() -> *mut ResultSet {
+    catch_panic_or(null_mut(), || Box::into_raw(Box::new(ResultSet { arena: Vec::new() })))
+}
+
+/// result_set_free frees the ResultSet, and with it the last results written into it.
+///
+/// # Safety
+///
+/// `result_set` must have been returned by `result_set_new`, and it must not be used after this call.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("result_set_free"))]
+pub unsafe extern "C" fn result_set_free(result_set: *mut ResultSet) {
+    catch_panic_or((), || {
+        if !result_set.is_null() {
+            drop(unsafe { Box::from_raw(result_set) });
+        }
+    })
+}
+
+/// encode_batch_into encodes a batch of UTF-8 strings (given as in `encode_batch_bytes`), like
+/// `encode_batch_arena`, but writes the results into the `result_set`, reusing its memory (it only grows
+/// if the results don't fit).
+///
+/// The results returned are owned by the `result_set`: they are valid until the next call with the same
+/// `result_set`, or until it is freed, and must not be freed otherwise -- except for the `error`, if set,
+/// which must be freed with `free_string`.
+///
+/// The `result_set` must not be used concurrently by different threads.
+///
+/// # Safety
+///
+/// `result_set` must have been returned by `result_set_new`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encode_batch_into"))]
+pub unsafe extern "C" fn encode_batch_into(
+    tokenizer_ptr: *mut TokenizerHandle,
+    result_set: *mut ResultSet,
+    num_messages: u64,
+    messages: *const *const u8,
+    lengths: *const u32,
+    options: EncodeParams,
+) -> EncodeResultsV2 {
+    let result = catch_panic(|| {
+        let result_set = unsafe { result_set.as_mut() }.ok_or_else(|| err("ResultSet is null"))?;
+        let num_messages = usize::try_from(num_messages)
+            .map_err(|_| err(format!("{} messages overflow usize", num_messages)))?;
+        write_batch(tokenizer_ptr, num_messages, messages, lengths, &options, &mut result_set.arena, 0)
+    });
+    results_or_error(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+    use crate::encode::ENCODE_PARAMS_VERSION;
+    use crate::testing::{take_error, TestHandle, WORDPIECE};
+
+    // options returns the EncodeParams with the given flags.
+    fn options(flags: u64) -> EncodeParams {
+        EncodeParams { version: ENCODE_PARAMS_VERSION, flags }
+    }
+
+    // contents returns the ids and the tokens of each of the Buffers of the `results`.
+    fn contents(results: &EncodeResultsV2) -> Vec<(Vec<u32>, Vec<String>)> {
+        let buffers = unsafe { std::slice::from_raw_parts(results.encoded, results.len as usize) };
+        buffers
+            .iter()
+            .map(|buf| unsafe {
+                let ids = std::slice::from_raw_parts(buf.ids, buf.len as usize).to_vec();
+                let tokens = std::slice::from_raw_parts(buf.tokens, buf.len as usize)
+                    .iter()
+                    .map(|&t| CStr::from_ptr(t).to_str().unwrap().to_string())
+                    .collect();
+                (ids, tokens)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn batch_arena() {
+        let handle = TestHandle::new(WORDPIECE);
+        let texts = ["hello world", "ab"];
+        let messages: Vec<*const u8> = texts.iter().map(|t| t.as_ptr()).collect();
+        let lengths: Vec<u32> = texts.iter().map(|t| t.len() as u32).collect();
+        let results = unsafe {
+            encode_batch_arena(handle.0, 2, messages.as_ptr(), lengths.as_ptr(), options(ENCODE_RETURN_TOKENS))
+        };
+        assert!(results.error.is_null());
+        assert_eq!(results.total_tokens, 4);
+        assert_eq!(
+            contents(&results),
+            [
+                (vec![4, 6], vec!["hello".to_string(), "world".to_string()]),
+                (vec![1, 3], vec!["a".to_string(), "##b".to_string()]),
+            ]);
+        // All the arrays are in the arena, after the Buffers.
+        let base = unsafe { results.encoded.cast::<u64>().sub(1) };
+        let arena = base as usize..base as usize + unsafe { *base } as usize * size_of::<u64>();
+        let buffers = unsafe { std::slice::from_raw_parts(results.encoded, 2) };
+        for buf in buffers {
+            assert!(arena.contains(&(buf.ids as usize)) && arena.contains(&(buf.tokens as usize)));
+            assert!(buf.tokens_arena.is_null() && buf.type_ids.is_null());
+        }
+        unsafe { free_encode_results_arena(results) };
+
+        let bad = [0xff_u8];
+        let messages = [bad.as_ptr()];
+        let results = unsafe { encode_batch_arena(handle.0, 1, messages.as_ptr(), [1].as_ptr(), options(0)) };
+        assert!(results.encoded.is_null());
+        assert!(take_error(results.error).is_some());
+    }
+
+    #[test]
+    fn batch_arena_fields() {
+        // The arrays converted from the encodings are the same as those of the Buffers of `encode_batch_str_impl`.
+        let handle = TestHandle::new(WORDPIECE);
+        let texts = ["hello world", "ab hello"];
+        let messages: Vec<*const u8> = texts.iter().map(|t| t.as_ptr()).collect();
+        let lengths: Vec<u32> = texts.iter().map(|t| t.len() as u32).collect();
+        let options = options(
+            ENCODE_RETURN_OFFSETS | ENCODE_RETURN_WORDS | ENCODE_RETURN_DEBUG_INFO | ENCODE_RETURN_TOKEN_CHAR_LENGTHS
+                | ENCODE_RETURN_WORD_STARTS | ENCODE_RETURN_SEQUENCE_IDS | ENCODE_RETURN_ATTENTION_MASK
+                | ENCODE_PACKED_MASKS);
+        let results = unsafe { encode_batch_arena(handle.0, 2, messages.as_ptr(), lengths.as_ptr(), options) };
+        assert!(results.error.is_null());
+        let expected = crate::encode::encode_batch_str_impl(handle.0, &texts, options).unwrap();
+        fn slice<'a, T>(ptr: *const T, len: usize) -> &'a [T] {
+            unsafe { std::slice::from_raw_parts(ptr, len) }
+        }
+        let buffers = slice(results.encoded, 2);
+        for (buf, want) in buffers.iter().zip(&expected) {
+            let n = buf.len as usize;
+            let spans = |ptr: *mut Offset, len| -> Vec<(u32, u32)> {
+                slice(ptr, len).iter().map(|offset: &Offset| (offset.start, offset.end)).collect()
+            };
+            assert_eq!(buf.num_words, want.num_words);
+            assert_eq!(spans(buf.offsets, n), spans(want.offsets, n));
+            assert_eq!(spans(buf.words, buf.num_words as usize), spans(want.words, want.num_words as usize));
+            assert_eq!(slice(buf.word_ids, n), slice(want.word_ids, n));
+            assert_eq!(slice(buf.token_char_lengths, n), slice(want.token_char_lengths, n));
+            assert_eq!(slice(buf.word_starts, n), slice(want.word_starts, n));
+            assert_eq!(slice(buf.sequence_ids, n), slice(want.sequence_ids, n));
+            assert_eq!(slice(buf.attention_mask_bits, n.div_ceil(8)), slice(want.attention_mask_bits, n.div_ceil(8)));
+        }
+        expected.into_iter().for_each(crate::encode::free_buffer);
+        unsafe { free_encode_results_arena(results) };
+    }
+
+    #[test]
+    fn result_set_reuse() {
+        let handle = TestHandle::new(WORDPIECE);
+        let result_set = result_set_new();
+        let encode_into = |texts: &[&str]| {
+            let messages: Vec<*const u8> = texts.iter().map(|t| t.as_ptr()).collect();
+            let lengths: Vec<u32> = texts.iter().map(|t| t.len() as u32).collect();
+            let (messages, lengths) = (messages.as_ptr(), lengths.as_ptr());
+            let (num_messages, options) = (texts.len() as u64, options(ENCODE_RETURN_TOKENS));
+            unsafe { encode_batch_into(handle.0, result_set, num_messages, messages, lengths, options) }
+        };
+        let results = encode_into(&["hello world hello", "a"]);
+        assert_eq!(contents(&results)[1], (vec![1], vec!["a".to_string()]));
+        let capacity = unsafe { (*result_set).arena.capacity() };
+
+        // Smaller results are written in the same memory, without growing it.
+        let results = encode_into(&["ab"]);
+        assert_eq!(contents(&results), [(vec![1, 3], vec!["a".to_string(), "##b".to_string()])]);
+        assert_eq!(unsafe { (*result_set).arena.as_ptr() }, results.encoded.cast());
+        assert_eq!(unsafe { (*result_set).arena.capacity() }, capacity);
+        unsafe { result_set_free(result_set) };
+
+        let (messages, lengths) = (std::ptr::null(), std::ptr::null());
+        let results = unsafe { encode_batch_into(handle.0, null_mut(), 0, messages, lengths, options(0)) };
+        assert_eq!(take_error(results.error).as_deref(), Some("ResultSet is null"));
+    }
+}
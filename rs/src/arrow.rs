@@ -0,0 +1,309 @@
+//! Apache Arrow output (`arrow` feature): the encodings of a batch exported through the Arrow C Data Interface
+//! (https://arrow.apache.org/docs/format/CDataInterface.html), so they can be imported without copies by any
+//! Arrow implementation (pyarrow, arrow-go, arrow-rs, DuckDB, Polars, ...).
+//!
+//! The interface is a stable ABI, its two structs are declared here: no Arrow library is needed.
+
+use std::any::Any;
+use std::error::Error;
+use std::ffi::{c_char, c_void, CString};
+use std::ptr::{null, null_mut};
+use tokenizers::Encoding;
+use crate::encode::{
+    encode_batch_encodings, err, get_offsets, messages_from_bytes, EncodeParams, Offset, ENCODE_RETURN_ATTENTION_MASK,
+    ENCODE_RETURN_OFFSETS, ENCODE_RETURN_SPECIAL_TOKENS_MASK, ENCODE_RETURN_TYPE_IDS,
+};
+use crate::handle::TokenizerHandle;
+use crate::panics::{catch_panic, error_c_string};
+
+/// ArrowSchema is the `struct ArrowSchema` of the Arrow C Data Interface: the type of an ArrowArray.
+#[repr(C)]
+pub struct ArrowSchema {
+    format: *const c_char,
+    name: *const c_char,
+    metadata: *const c_char,
+    flags: i64,
+    n_children: i64,
+    children: *mut *mut ArrowSchema,
+    dictionary: *mut ArrowSchema,
+    release: Option<unsafe extern "C" fn(*mut ArrowSchema)>,
+    private_data: *mut c_void,
+}
+
+/// ArrowArray is the `struct ArrowArray` of the Arrow C Data Interface: the values of an array.
+#[repr(C)]
+pub struct ArrowArray {
+    length: i64,
+    null_count: i64,
+    offset: i64,
+    n_buffers: i64,
+    n_children: i64,
+    buffers: *mut *const c_void,
+    children: *mut *mut ArrowArray,
+    dictionary: *mut ArrowArray,
+    release: Option<unsafe extern "C" fn(*mut ArrowArray)>,
+    private_data: *mut c_void,
+}
+
+// SchemaData owns the strings and the children of an ArrowSchema, in its `private_data`.
+struct SchemaData {
+    format: CString,
+    name: CString,
+    children: Vec<*mut ArrowSchema>,
+}
+
+fn new_schema(format: &str, name: &str, children: Vec<ArrowSchema>) -> ArrowSchema {
+    let mut data = Box::new(SchemaData {
+        format: CString::new(format).unwrap(),
+        name: CString::new(name).unwrap(),
+        children: children.into_iter().map(|child| Box::into_raw(Box::new(child))).collect(),
+    });
+    ArrowSchema {
+        format: data.format.as_ptr(),
+        name: data.name.as_ptr(),
+        metadata: null(),
+        flags: 0,
+        n_children: data.children.len() as i64,
+        children: data.children.as_mut_ptr(),
+        dictionary: null_mut(),
+        release: Some(release_schema),
+        private_data: Box::into_raw(data).cast(),
+    }
+}
+
+unsafe extern "C" fn release_schema(schema: *mut ArrowSchema) {
+    let schema = unsafe { &mut *schema };
+    let data = unsafe { Box::from_raw(schema.private_data.cast::<SchemaData>()) };
+    for &child in &data.children {
+        // The consumer may have moved (and released) a child, in which case its `release` is null.
+        if let Some(release) = unsafe { (*child).release } {
+            unsafe { release(child) };
+        }
+        drop(unsafe { Box::from_raw(child) });
+    }
+    schema.release = None;
+}
+
+// ArrayData owns the buffers and the children of an ArrowArray, in its `private_data`.
+struct ArrayData {
+    buffers: Vec<*const c_void>,
+    children: Vec<*mut ArrowArray>,
+    // The values pointed by `buffers`.
+    _values: Vec<Box<dyn Any>>,
+}
+
+// new_array returns an array of `length` values, none of them null (as in all the exported arrays): `values`
+// are its buffers, after the (null) validity bitmap.
+fn new_array<T: 'static>(length: usize, values: Vec<Vec<T>>, children: Vec<ArrowArray>) -> ArrowArray {
+    let mut buffers = vec![null()];
+    buffers.extend(values.iter().map(|values| values.as_ptr().cast::<c_void>()));
+    let mut data = Box::new(ArrayData {
+        buffers,
+        children: children.into_iter().map(|child| Box::into_raw(Box::new(child))).collect(),
+        _values: values.into_iter().map(|values| Box::new(values) as Box<dyn Any>).collect(),
+    });
+    ArrowArray {
+        length: length as i64,
+        null_count: 0,
+        offset: 0,
+        n_buffers: data.buffers.len() as i64,
+        n_children: data.children.len() as i64,
+        buffers: data.buffers.as_mut_ptr(),
+        children: data.children.as_mut_ptr(),
+        dictionary: null_mut(),
+        release: Some(release_array),
+        private_data: Box::into_raw(data).cast(),
+    }
+}
+
+unsafe extern "C" fn release_array(array: *mut ArrowArray) {
+    let array = unsafe { &mut *array };
+    let data = unsafe { Box::from_raw(array.private_data.cast::<ArrayData>()) };
+    for &child in &data.children {
+        if let Some(release) = unsafe { (*child).release } {
+            unsafe { release(child) };
+        }
+        drop(unsafe { Box::from_raw(child) });
+    }
+    array.release = None;
+}
+
+// Column is a field of the exported struct array: a large list per sequence of the `values`.
+struct Column {
+    schema: ArrowSchema,
+    array: ArrowArray,
+}
+
+// u32_column returns the column `name` of the `values` of the `encodings`: a large list of u32 per sequence,
+// split at `starts`.
+fn u32_column(name: &str, encodings: &[Encoding], starts: &[i64], values: fn(&Encoding) -> &[u32]) -> Column {
+    let values: Vec<u32> = encodings.iter().flat_map(|encoding| values(encoding).iter().copied()).collect();
+    let values = new_array(values.len(), vec![values], Vec::new());
+    Column {
+        schema: new_schema("+L", name, vec![new_schema("I", "item", Vec::new())]),
+        array: new_array(encodings.len(), vec![starts.to_vec()], vec![values]),
+    }
+}
+
+fn encode_batch_arrow_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_messages: usize,
+    messages: *const *const u8,
+    lengths: *const u32,
+    options: EncodeParams,
+) -> Result<(ArrowArray, ArrowSchema), Box<dyn Error>> {
+    let inputs = messages_from_bytes(num_messages, messages, lengths)?;
+    let encodings = encode_batch_encodings(tokenizer_ptr, &inputs, &options)?;
+
+    // The offsets go first, since they can fail to convert.
+    let offsets = if options.has(ENCODE_RETURN_OFFSETS) {
+        let mut offsets = Vec::new();
+        for (encoding, text) in encodings.iter().zip(&inputs) {
+            for &span in get_offsets(encoding, &[text], &options).iter() {
+                let offset = Offset::new(span)?;
+                offsets.extend([offset.start, offset.end]);
+            }
+        }
+        Some(offsets)
+    } else {
+        None
+    };
+
+    let mut starts = Vec::with_capacity(encodings.len() + 1);
+    starts.push(0_i64);
+    for encoding in &encodings {
+        starts.push(starts[starts.len() - 1] + encoding.len() as i64);
+    }
+    let mut columns = vec![u32_column("ids", &encodings, &starts, Encoding::get_ids)];
+    if options.has(ENCODE_RETURN_TYPE_IDS) {
+        columns.push(u32_column("type_ids", &encodings, &starts, Encoding::get_type_ids));
+    }
+    if options.has(ENCODE_RETURN_SPECIAL_TOKENS_MASK) {
+        columns.push(u32_column("special_tokens_mask", &encodings, &starts, Encoding::get_special_tokens_mask));
+    }
+    if options.has(ENCODE_RETURN_ATTENTION_MASK) {
+        columns.push(u32_column("attention_mask", &encodings, &starts, Encoding::get_attention_mask));
+    }
+    if let Some(offsets) = offsets {
+        // Each offset is a fixed size list of its start and end.
+        let num_tokens = offsets.len() / 2;
+        let values = new_array(offsets.len(), vec![offsets], Vec::new());
+        let pairs = new_array::<u8>(num_tokens, Vec::new(), vec![values]);
+        let pair_schema = new_schema("+w:2", "item", vec![new_schema("I", "item", Vec::new())]);
+        columns.push(Column {
+            schema: new_schema("+L", "offsets", vec![pair_schema]),
+            array: new_array(encodings.len(), vec![starts.clone()], vec![pairs]),
+        });
+    }
+
+    let (schemas, arrays): (Vec<_>, Vec<_>) = columns.into_iter().map(|column| (column.schema, column.array)).unzip();
+    let array = new_array::<u8>(encodings.len(), Vec::new(), arrays);
+    Ok((array, new_schema("+s", "", schemas)))
+}
+
+/// encode_batch_arrow encodes a batch of UTF-8 strings (given as in `encode_batch_bytes`), and exports the
+/// encodings as an Arrow struct array, through the Arrow C Data Interface: one row per sequence, with the
+/// columns `ids` and, if requested with their `ENCODE_RETURN_*` flag, `type_ids`, `special_tokens_mask` and
+/// `attention_mask` -- each a `large_list<uint32>` -- and `offsets`, a `large_list<fixed_size_list<uint32>[2]>`.
+///
+/// The array and its type are written to `array` and `schema`, to be imported by an Arrow library, which
+/// takes ownership of them: they are freed by their `release` callback, as with any Arrow C Data Interface
+/// export. Only available with the `arrow` feature (enabled by default).
+///
+/// It returns null if ok, or a string with an error message (owned by caller), to be freed with `free_string`.
+///
+/// # Safety
+///
+/// `messages` and `lengths` must point to `num_messages` strings and lengths, and `array` and `schema` to
+/// writable (uninitialized) structs.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encode_batch_arrow"))]
+pub unsafe extern "C" fn encode_batch_arrow(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_messages: u64,
+    messages: *const *const u8,
+    lengths: *const u32,
+    options: EncodeParams,
+    array: *mut ArrowArray,
+    schema: *mut ArrowSchema,
+) -> *mut c_char {
+    let result = catch_panic(|| {
+        if array.is_null() || schema.is_null() {
+            return Err(err("array and schema must not be null"));
+        }
+        let num_messages = usize::try_from(num_messages)
+            .map_err(|_| err(format!("{} messages overflow usize", num_messages)))?;
+        let (exported_array, exported_schema) =
+            encode_batch_arrow_impl(tokenizer_ptr, num_messages, messages, lengths, options)?;
+        unsafe {
+            array.write(exported_array);
+            schema.write(exported_schema);
+        }
+        Ok(())
+    });
+    match result {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+    use std::mem::MaybeUninit;
+    use crate::encode::ENCODE_PARAMS_VERSION;
+    use crate::testing::{take_error, TestHandle, WORDPIECE};
+
+    // values returns the `len` values of type T of the buffer `i` of the `array`.
+    unsafe fn values<T>(array: &ArrowArray, i: usize, len: usize) -> &[T] {
+        unsafe { std::slice::from_raw_parts(*array.buffers.add(i) as *const T, len) }
+    }
+
+    unsafe fn child<'a, S>(children: *mut *mut S, i: usize) -> &'a S {
+        unsafe { &**children.add(i) }
+    }
+
+    #[test]
+    fn encode_batch_arrow_columns() {
+        let handle = TestHandle::new(WORDPIECE);
+        let texts = ["hello world", "a ab"];
+        let messages: Vec<*const u8> = texts.iter().map(|text| text.as_ptr()).collect();
+        let lengths: Vec<u32> = texts.iter().map(|text| text.len() as u32).collect();
+        let options = EncodeParams {
+            version: ENCODE_PARAMS_VERSION,
+            flags: ENCODE_RETURN_ATTENTION_MASK | ENCODE_RETURN_OFFSETS,
+        };
+        let (mut array, mut schema) = (MaybeUninit::uninit(), MaybeUninit::uninit());
+        let error = unsafe {
+            encode_batch_arrow(
+                handle.0, 2, messages.as_ptr(), lengths.as_ptr(), options, array.as_mut_ptr(), schema.as_mut_ptr())
+        };
+        assert_eq!(take_error(error), None);
+        let (mut array, mut schema) = unsafe { (array.assume_init(), schema.assume_init()) };
+
+        unsafe {
+            assert_eq!(CStr::from_ptr(schema.format).to_str().unwrap(), "+s");
+            let names: Vec<&str> = (0..schema.n_children as usize)
+                .map(|i| CStr::from_ptr(child(schema.children, i).name).to_str().unwrap())
+                .collect();
+            assert_eq!(names, ["ids", "attention_mask", "offsets"]);
+            assert_eq!((array.length, array.n_children), (2, 3));
+
+            let ids = child(array.children, 0);
+            assert_eq!(values::<i64>(ids, 1, 3), [0, 2, 5]);
+            assert_eq!(values::<u32>(child(ids.children, 0), 1, 5), [4, 6, 1, 1, 3]);
+            let attention_mask = child(array.children, 1);
+            assert_eq!(values::<u32>(child(attention_mask.children, 0), 1, 5), [1; 5]);
+            let offsets = child(child(child(array.children, 2).children, 0).children, 0);
+            assert_eq!(values::<u32>(offsets, 1, 10), [0, 5, 6, 11, 0, 1, 2, 3, 3, 4]);
+
+            (array.release.unwrap())(&mut array);
+            (schema.release.unwrap())(&mut schema);
+        }
+        assert!(array.release.is_none() && schema.release.is_none());
+
+        let error = unsafe { encode_batch_arrow(handle.0, 0, null(), null(), options, null_mut(), null_mut()) };
+        assert_eq!(take_error(error).as_deref(), Some("array and schema must not be null"));
+    }
+}
@@ -0,0 +1,34 @@
+//! Generates the conformance test vectors of a tokenizer (see `conformance_vectors` in the library):
+//!
+//!     cargo run --bin conformance -- <tokenizer.json> <probes.txt> [--no-special-tokens]
+//!
+//! The probes are the lines of `probes.txt`, and the canonical JSON of the vectors is written to the standard
+//! output.
+
+use std::error::Error;
+use std::process::ExitCode;
+use tokenizers::tokenizer::Tokenizer;
+
+fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let add_special_tokens = !args.iter().any(|arg| arg == "--no-special-tokens");
+    let paths: Vec<&String> = args.iter().filter(|arg| !arg.starts_with("--")).collect();
+    let [tokenizer_path, probes_path] = paths[..] else {
+        return Err("usage: conformance <tokenizer.json> <probes.txt> [--no-special-tokens]".into());
+    };
+    let tokenizer = Tokenizer::from_file(tokenizer_path).map_err(|e| format!("loading {}: {}", tokenizer_path, e))?;
+    let probes = std::fs::read_to_string(probes_path)?;
+    let probes: Vec<&str> = probes.lines().collect();
+    println!("{}", gomlx_tokenizers::conformance::conformance_json(&tokenizer, &probes, add_special_tokens)?);
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("conformance: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
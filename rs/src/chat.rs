@@ -0,0 +1,336 @@
+//! Rendering of the chat templates of the tokenizer config (see `get_chat_template`), as the
+//! `apply_chat_template` method of transformers: the Jinja templates are rendered with minijinja, with the
+//! Python string methods commonly used by the templates.
+
+use std::error::Error;
+use std::ffi::{c_char, CStr, CString};
+use std::ptr::null_mut;
+use minijinja::{Environment, ErrorKind};
+use serde_json::{Map, Value};
+use crate::encode::err;
+use crate::free_string;
+use crate::handle::{convert_to_handle_ref, TokenizerHandle};
+use crate::panics::{catch_panic, catch_panic_or, error_c_string};
+
+/// ChatTemplateParams are the options of `apply_chat_template`.
+#[repr(C)]
+pub struct ChatTemplateParams {
+    template_name: *const c_char,  // Name of the chat template, or null for the default one.
+    add_generation_prompt: bool,  // Default false
+    continue_final_message: bool,  // Default false
+
+    // `variables_len` bytes of a JSON object with extra variables for the template, or null for none.
+    variables: *const u8,
+    variables_len: u32,
+}
+
+/// ChatTemplateResult is the result of `apply_chat_template`: the rendered `text`, and its `len` token `ids`.
+/// `generation_prompt_ids` holds the last `generation_prompt_len` of them, the tokens of the generation prompt
+/// (the start of the assistant message) added with `add_generation_prompt`.
+///
+/// Either the results or `error` will be defined. Once it is no longer used, free it with
+/// `free_chat_template_result`.
+#[repr(C)]
+pub struct ChatTemplateResult {
+    text: *mut c_char,
+    ids: *mut u32,
+    len: u64,
+    generation_prompt_ids: *mut u32,
+    generation_prompt_len: u64,
+    error: *mut c_char,
+}
+
+// render renders the chat `template` with the given `context`, with the Jinja settings of transformers.
+fn render(template: &str, context: &Value) -> Result<String, Box<dyn Error>> {
+    let mut env = Environment::new();
+    env.set_trim_blocks(true);
+    env.set_lstrip_blocks(true);
+    env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+    env.add_function("raise_exception", |message: String| -> Result<String, minijinja::Error> {
+        Err(minijinja::Error::new(ErrorKind::InvalidOperation, message))
+    });
+    let template = env.template_from_str(template).map_err(|e| err(format!("invalid chat template: {}", e)))?;
+    template.render(context).map_err(|e| err(format!("failed to render chat template: {:#}", e)))
+}
+
+// continue_final_message truncates the `rendered` chat right after the content of the final message, so the
+// model continues it, as transformers does: anything the template adds after it (e.g. the end of turn token)
+// is removed.
+fn continue_final_message(rendered: &mut String, messages: &[Value]) -> Result<(), Box<dyn Error>> {
+    let content = match messages.last().map(|message| &message["content"]) {
+        Some(Value::String(content)) => content.trim(),
+        _ => return Err(err("continue_final_message requires a final message with a string `content`")),
+    };
+    match rendered.rfind(content) {
+        Some(start) => {
+            rendered.truncate(start + content.len());
+            Ok(())
+        }
+        None => Err(err("continue_final_message: the content of the final message is not in the rendered chat")),
+    }
+}
+
+// template_variables parses the `len` bytes of the JSON object with the extra variables of the template, if
+// not null.
+fn template_variables(variables: *const u8, len: u32) -> Result<Map<String, Value>, Box<dyn Error>> {
+    if variables.is_null() {
+        return Ok(Map::new());
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(variables, len as usize) };
+    let variables = match serde_json::from_slice(bytes) {
+        Ok(Value::Object(variables)) => variables,
+        Ok(_) => return Err(err("invalid chat template variables: not a JSON object")),
+        Err(e) => return Err(err(format!("invalid chat template variables: {}", e))),
+    };
+    for reserved in ["messages", "add_generation_prompt"] {
+        if variables.contains_key(reserved) {
+            return Err(err(format!("chat template variable {:?} is set by apply_chat_template", reserved)));
+        }
+    }
+    Ok(variables)
+}
+
+// apply_chat_template_impl returns the rendered chat, its token ids, and how many of them are the generation
+// prompt.
+fn apply_chat_template_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    messages: &[u8],
+    params: *const ChatTemplateParams,
+) -> Result<(CString, Vec<u32>, usize), Box<dyn Error>> {
+    let (template_name, add_generation_prompt, continue_final, variables) = match unsafe { params.as_ref() } {
+        Some(params) => (
+            if params.template_name.is_null() {
+                None
+            } else {
+                Some(unsafe { CStr::from_ptr(params.template_name) }.to_str()?)
+            },
+            params.add_generation_prompt,
+            params.continue_final_message,
+            template_variables(params.variables, params.variables_len)?,
+        ),
+        None => (None, false, false, Map::new()),
+    };
+    if add_generation_prompt && continue_final {
+        return Err(err("add_generation_prompt and continue_final_message are exclusive"));
+    }
+    let messages: Vec<Value> = serde_json::from_slice(messages)
+        .map_err(|e| err(format!("invalid chat messages, expected a JSON array of messages: {}", e)))?;
+
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    let config = state.config().ok_or_else(|| err("tokenizer has no tokenizer config"))?;
+    let template = config
+        .chat_template(template_name)
+        .ok_or_else(|| err(format!("tokenizer has no chat template {:?}", template_name.unwrap_or("default"))))?;
+
+    // The special tokens are available to the template, e.g. `bos_token`, and the extra variables, which may
+    // replace them.
+    let mut context = Map::new();
+    for (key, content) in config.special_tokens() {
+        if key != "additional_special_tokens" {
+            context.insert(key.clone(), Value::String(content.clone()));
+        }
+    }
+    context.extend(variables);
+    context.insert("messages".to_string(), Value::Array(messages));
+    context.insert("add_generation_prompt".to_string(), Value::Bool(false));
+    let mut context = Value::Object(context);
+    let mut text = render(template, &context)?;
+
+    // The generation prompt is what rendering with `add_generation_prompt` adds.
+    let mut prompt_start = text.len();
+    if add_generation_prompt {
+        context["add_generation_prompt"] = Value::Bool(true);
+        let with_prompt = render(template, &context)?;
+        if !with_prompt.starts_with(&text) {
+            prompt_start = 0;
+            while with_prompt.as_bytes().get(prompt_start) == text.as_bytes().get(prompt_start) {
+                prompt_start += 1;
+            }
+        }
+        text = with_prompt;
+    } else if continue_final {
+        let messages = context["messages"].as_array().map_or(&[][..], Vec::as_slice);
+        continue_final_message(&mut text, messages)?;
+        prompt_start = text.len();
+    }
+
+    // The special tokens are part of the rendered text, so they are not added when encoding.
+    let encoding = state
+        .tokenizer
+        .encode(text.as_str(), false)
+        .map_err(|e| err(format!("encoding failed: {}", e)))?;
+    let prompt_len = encoding.get_offsets().iter().rev().take_while(|&&(_, end)| end > prompt_start).count();
+    Ok((CString::new(text)?, encoding.get_ids().to_vec(), prompt_len))
+}
+
+/// apply_chat_template renders the chat template of the tokenizer config (see `set_tokenizer_config`) with the
+/// `len` bytes of `messages`, a JSON array of messages (objects with the `role` and `content`), and encodes
+/// the rendered text (without adding special tokens: the template adds them). The special tokens of the
+/// config (`bos_token`, `eos_token`, ...) are available to the template.
+///
+/// The `params` (if not null) select the template, and, as in transformers:
+///
+/// - `add_generation_prompt`: the template adds the start of an assistant message, for the model to
+///   generate the response. Its token ids are the `generation_prompt_ids` of the result.
+/// - `continue_final_message`: the chat is truncated right after the content of the final message, so the
+///   model continues it (prefill). It can't be combined with `add_generation_prompt`.
+/// - `variables`: a JSON object with extra variables for the template (e.g. `date_string`, `tools`, or any
+///   context referenced by community templates), as the keyword arguments of transformers. They can replace
+///   the special tokens, but not `messages` or `add_generation_prompt`.
+///
+/// The result must be freed with `free_chat_template_result`.
+///
+/// # Safety
+///
+/// `messages` must point to `len` bytes, and `params` must be null or point to a ChatTemplateParams.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("apply_chat_template"))]
+pub unsafe extern "C" fn apply_chat_template(
+    tokenizer_ptr: *mut TokenizerHandle,
+    messages: *const u8,
+    len: u32,
+    params: *const ChatTemplateParams,
+) -> ChatTemplateResult {
+    let messages = if len == 0 { &[][..] } else { unsafe { std::slice::from_raw_parts(messages, len as usize) } };
+    match catch_panic(|| apply_chat_template_impl(tokenizer_ptr, messages, params)) {
+        Ok((text, ids, prompt_len)) => {
+            let mut ids = ids.into_boxed_slice();
+            let len = ids.len();
+            let result = ChatTemplateResult {
+                text: text.into_raw(),
+                ids: ids.as_mut_ptr(),
+                len: len as u64,
+                generation_prompt_ids: unsafe { ids.as_mut_ptr().add(len - prompt_len) },
+                generation_prompt_len: prompt_len as u64,
+                error: null_mut(),
+            };
+            std::mem::forget(ids);
+            result
+        }
+        Err(e) => ChatTemplateResult {
+            text: null_mut(),
+            ids: null_mut(),
+            len: 0,
+            generation_prompt_ids: null_mut(),
+            generation_prompt_len: 0,
+            error: error_c_string(e),
+        },
+    }
+}
+
+/// Frees the ChatTemplateResult returned by `apply_chat_template`.
+///
+/// # Safety
+///
+/// `result` must have been returned by `apply_chat_template`, and it must not be used after this call.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("free_chat_template_result"))]
+pub unsafe extern "C" fn free_chat_template_result(result: ChatTemplateResult) {
+    catch_panic_or((), || {
+        free_string(result.error);
+        free_string(result.text);
+        if !result.ids.is_null() {
+            unsafe {
+                drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(result.ids, result.len as usize)));
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{TestHandle, WORDPIECE};
+
+    const CONFIG: &str = concat!(
+        r#"{"chat_template": "{% for m in messages %}{{ m.role }} {{ m.content }}<|im_end|>{% endfor %}"#,
+        r#"{% if add_generation_prompt %}b {% endif %}"}"#,
+    );
+
+    // params returns the ChatTemplateParams with the given options.
+    fn params(add_generation_prompt: bool, continue_final_message: bool) -> ChatTemplateParams {
+        ChatTemplateParams {
+            template_name: std::ptr::null(),
+            add_generation_prompt,
+            continue_final_message,
+            variables: std::ptr::null(),
+            variables_len: 0,
+        }
+    }
+
+    // apply applies the chat template to the `messages`, returning the text, the ids and the generation prompt ids,
+    // or the error.
+    fn apply(
+        handle: &TestHandle,
+        messages: &str,
+        params: &ChatTemplateParams,
+    ) -> Result<(String, Vec<u32>, Vec<u32>), String> {
+        let result = unsafe { apply_chat_template(handle.0, messages.as_ptr(), messages.len() as u32, params) };
+        if let Some(error) = crate::testing::take_error(result.error) {
+            return Err(error);
+        }
+        let applied = unsafe {
+            (
+                CStr::from_ptr(result.text).to_str().unwrap().to_string(),
+                std::slice::from_raw_parts(result.ids, result.len as usize).to_vec(),
+                std::slice::from_raw_parts(result.generation_prompt_ids, result.generation_prompt_len as usize)
+                    .to_vec(),
+            )
+        };
+        unsafe { free_chat_template_result(result) };
+        Ok(applied)
+    }
+
+    #[test]
+    fn generation_prompt() {
+        let handle = TestHandle::new(WORDPIECE);
+        let messages = r#"[{"role": "a", "content": "hello"}, {"role": "b", "content": "world"}]"#;
+        assert_eq!(apply(&handle, messages, &params(false, false)).unwrap_err(), "tokenizer has no tokenizer config");
+        let error = unsafe { crate::config::set_tokenizer_config(handle.0, CONFIG.as_ptr(), CONFIG.len() as u32) };
+        assert!(error.is_null());
+
+        let (text, ids, prompt_ids) = apply(&handle, messages, &params(false, false)).unwrap();
+        assert_eq!(text, "a hello<|im_end|>b world<|im_end|>");
+        assert_eq!((ids, prompt_ids), (vec![1, 4, 5, 2, 6, 5], vec![]));
+
+        let first_message = r#"[{"role": "a", "content": "hello"}]"#;
+        let (text, ids, prompt_ids) = apply(&handle, first_message, &params(true, false)).unwrap();
+        assert_eq!(text, "a hello<|im_end|>b ");
+        assert_eq!((ids, prompt_ids), (vec![1, 4, 5, 2], vec![2]));
+
+        // The chat ends right after the content of the final message.
+        let (text, ids, prompt_ids) = apply(&handle, messages, &params(false, true)).unwrap();
+        assert_eq!(text, "a hello<|im_end|>b world");
+        assert_eq!((ids, prompt_ids), (vec![1, 4, 5, 2, 6], vec![]));
+
+        assert_eq!(
+            apply(&handle, messages, &params(true, true)).unwrap_err(),
+            "add_generation_prompt and continue_final_message are exclusive");
+    }
+
+    #[test]
+    fn extra_variables() {
+        let handle = TestHandle::new(WORDPIECE);
+        let config = r#"{"bos_token": "a", "chat_template": "{{ bos_token }} {{ greeting }}"}"#;
+        let error = unsafe { crate::config::set_tokenizer_config(handle.0, config.as_ptr(), config.len() as u32) };
+        assert!(error.is_null());
+        let apply_with = |variables: &str| {
+            let params = ChatTemplateParams {
+                variables: variables.as_ptr(),
+                variables_len: variables.len() as u32,
+                ..params(false, false)
+            };
+            apply(&handle, "[]", &params).map(|(text, _, _)| text)
+        };
+        assert_eq!(apply_with(r#"{"greeting": "hello"}"#).unwrap(), "a hello");
+        // The variables can replace the special tokens.
+        assert_eq!(apply_with(r#"{"greeting": "world", "bos_token": "b"}"#).unwrap(), "b world");
+        assert_eq!(apply(&handle, "[]", &params(false, false)).unwrap().0, "a ");
+
+        assert_eq!(
+            apply_with(r#"{"messages": []}"#).unwrap_err(),
+            "chat template variable \"messages\" is set by apply_chat_template");
+        assert_eq!(apply_with("[]").unwrap_err(), "invalid chat template variables: not a JSON object");
+    }
+}
@@ -0,0 +1,830 @@
+use std::error::Error;
+use std::ffi::c_char;
+use std::ptr::null_mut;
+use std::str::FromStr;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokenizers::normalizers::Precompiled;
+use tokenizers::tokenizer::Tokenizer;
+use crate::encode::err;
+use crate::handle::{convert_to_handle_ref, TokenizerHandle};
+use crate::panics::{catch_panic, error_c_string};
+
+// SentencePiece meta symbol, used to represent spaces.
+const SPIECE_UNDERLINE: &str = "▁";
+
+/// SentencePieceParams are the options to match the encodings of the reference sentencepiece library, for
+/// SentencePiece-derived tokenizers.
+#[repr(C)]
+pub struct SentencePieceParams {
+    add_dummy_prefix: u8,  // 0 -> as configured (*), 1 -> enabled, 2 -> disabled
+    remove_extra_whitespaces: bool,  // Default false
+}
+
+/// CompatOptions are the compatibility options of a tokenizer: they are applied by editing its pipeline
+/// (normalizer, pre-tokenizer, decoder and post-processor), see `apply_compat_options`.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct CompatOptions {
+    pub add_dummy_prefix: Option<bool>,
+    pub remove_extra_whitespaces: bool,
+    pub legacy_llama: Option<bool>,
+    pub add_bos_token: Option<bool>,
+    pub bos_token_id: Option<u32>,
+    pub add_eos_token: Option<bool>,
+    pub eos_token_id: Option<u32>,
+}
+
+/// StashedComponents holds the original (serialized) components of the pipeline, while compatibility
+/// options are applied, so they can be restored.
+#[derive(Clone)]
+pub struct StashedComponents {
+    normalizer: Value,
+    pre_tokenizer: Value,
+    decoder: Value,
+    post_processor: Value,
+}
+
+// Position of the special tokens toggled by `set_add_special_token`.
+#[derive(Clone, Copy, PartialEq)]
+enum Position {
+    Bos,
+    Eos,
+}
+
+// COMPONENTS are the keys of the components of the pipeline in the serialized tokenizer, with the
+// key of the list of sub-components of their `Sequence` type.
+const COMPONENTS: [(&str, &str); 3] = [
+    ("normalizer", "normalizers"),
+    ("pre_tokenizer", "pretokenizers"),
+    ("decoder", "decoders"),
+];
+
+/// apply_compat_options returns the `tokenizer` rebuilt with the compatibility `options` applied, and the
+/// stashed original components, to be passed on the next call -- or None if the options are the defaults.
+///
+/// `stashed` are the components stashed by a previous call, if any: they are restored before applying the
+/// new options.
+pub fn apply_compat_options(
+    tokenizer: &Tokenizer,
+    stashed: Option<&StashedComponents>,
+    options: &CompatOptions,
+) -> Result<(Tokenizer, Option<StashedComponents>), Box<dyn Error>> {
+    let mut json = serde_json::to_value(tokenizer)?;
+    let stashed = match stashed {
+        Some(stashed) => stashed.clone(),
+        None => StashedComponents {
+            normalizer: json["normalizer"].clone(),
+            pre_tokenizer: json["pre_tokenizer"].clone(),
+            decoder: json["decoder"].clone(),
+            post_processor: json["post_processor"].clone(),
+        },
+    };
+    restore_components(&mut json, &stashed);
+    if let Some(enabled) = options.add_dummy_prefix {
+        set_dummy_prefix(&mut json, enabled);
+    }
+    if let (Some(legacy), false) = (options.legacy_llama, options.add_dummy_prefix == Some(false)) {
+        set_legacy_llama(&mut json, legacy);
+    }
+    if options.remove_extra_whitespaces {
+        prepend_normalizers(&mut json, vec![
+            json!({"type": "Strip", "strip_left": true, "strip_right": true}),
+            json!({"type": "Replace", "pattern": {"Regex": " {2,}"}, "content": " "}),
+        ]);
+    }
+    let special_tokens = [
+        (Position::Bos, options.add_bos_token, options.bos_token_id),
+        (Position::Eos, options.add_eos_token, options.eos_token_id),
+    ];
+    for (position, enabled, token_id) in special_tokens {
+        let Some(enabled) = enabled else { continue };
+        let token = match token_id {
+            Some(id) => match tokenizer.id_to_token(id) {
+                Some(token) => Some((token, id)),
+                None => return Err(err(format!("special token id {} is not in the vocabulary", id))),
+            },
+            None => None,
+        };
+        set_add_special_token(&mut json, position, enabled, token)?;
+    }
+    let mut rebuilt = Tokenizer::from_str(&json.to_string())
+        .map_err(|e| err(format!("failed to apply compatibility options: {}", e)))?;
+    // Not part of the serialized tokenizer.
+    rebuilt.set_encode_special_tokens(tokenizer.get_encode_special_tokens());
+    let stashed = if *options == CompatOptions::default() { None } else { Some(stashed) };
+    Ok((rebuilt, stashed))
+}
+
+/// restore_components restores the `stashed` original components in the serialized tokenizer `json`.
+pub fn restore_components(json: &mut Value, stashed: &StashedComponents) {
+    json["normalizer"] = stashed.normalizer.clone();
+    json["pre_tokenizer"] = stashed.pre_tokenizer.clone();
+    json["decoder"] = stashed.decoder.clone();
+    json["post_processor"] = stashed.post_processor.clone();
+}
+
+// components_mut returns the component `key` of the serialized tokenizer, and its sub-components if it
+// is a `Sequence`.
+fn components_mut<'a>(json: &'a mut Value, key: &str, sequence_key: &str) -> Vec<&'a mut Value> {
+    let component = &mut json[key];
+    if component["type"] == "Sequence" {
+        match component[sequence_key].as_array_mut() {
+            Some(components) => components.iter_mut().collect(),
+            None => Vec::new(),
+        }
+    } else if component.is_null() {
+        Vec::new()
+    } else {
+        vec![component]
+    }
+}
+
+// set_dummy_prefix enables or disables the dummy prefix (the space prepended to the text) in the serialized
+// tokenizer: either in the Metaspace pre-tokenizer and decoder, or for Llama-style pipelines, as a
+// `Prepend` normalizer and a `Strip` decoder.
+fn set_dummy_prefix(json: &mut Value, enabled: bool) {
+    for (key, sequence_key) in &COMPONENTS[1..] {
+        for component in components_mut(json, key, sequence_key) {
+            if component["type"] != "Metaspace" {
+                continue;
+            }
+            if component.get("add_prefix_space").is_some() {
+                component["add_prefix_space"] = json!(enabled);
+            }
+            if component.get("prepend_scheme").is_some() {
+                component["prepend_scheme"] = json!(if enabled { "always" } else { "never" });
+            }
+        }
+    }
+
+    // Llama-style: the spaces are replaced by the normalizer.
+    let is_prepend = |n: &Value| n["type"] == "Prepend" && n["prepend"] == SPIECE_UNDERLINE;
+    let is_space_replace = |n: &Value| n["type"] == "Replace" && n["content"] == SPIECE_UNDERLINE;
+    let normalizers = components_mut(json, COMPONENTS[0].0, COMPONENTS[0].1);
+    let has_prepend = normalizers.iter().any(|n| is_prepend(n));
+    let has_space_replace = normalizers.iter().any(|n| is_space_replace(n));
+    if !has_prepend && !has_space_replace {
+        return;
+    }
+    let is_strip = |d: &Value| d["type"] == "Strip" && d["content"] == " " && d["start"] == 1;
+    if enabled {
+        if !has_prepend {
+            prepend_normalizers(json, vec![json!({"type": "Prepend", "prepend": SPIECE_UNDERLINE})]);
+        }
+        let decoders = components_mut(json, COMPONENTS[2].0, COMPONENTS[2].1);
+        if !decoders.iter().any(|d| is_strip(d)) {
+            append_decoder(json, json!({"type": "Strip", "content": " ", "start": 1, "stop": 0}));
+        }
+    } else {
+        remove_components(json, COMPONENTS[0], is_prepend);
+        remove_components(json, COMPONENTS[2], is_strip);
+    }
+}
+
+// set_legacy_llama sets whether the dummy prefix is added after special tokens (the `legacy=True` behavior of the
+// original Llama/Mistral tokenizers), or only at the start of the text: either in the Metaspace pre-tokenizer, or
+// for Llama-style pipelines, replacing the `Prepend` normalizer (applied after each special token) by a Metaspace
+// pre-tokenizer that only prepends to the start of the text.
+fn set_legacy_llama(json: &mut Value, legacy: bool) {
+    let mut has_metaspace = false;
+    for component in components_mut(json, COMPONENTS[1].0, COMPONENTS[1].1) {
+        if component["type"] == "Metaspace" {
+            has_metaspace = true;
+            if component["prepend_scheme"] != "never" {
+                component["prepend_scheme"] = json!(if legacy { "always" } else { "first" });
+            }
+        }
+    }
+    if has_metaspace || legacy {
+        return;
+    }
+    let is_prepend = |n: &Value| n["type"] == "Prepend" && n["prepend"] == SPIECE_UNDERLINE;
+    if !components_mut(json, COMPONENTS[0].0, COMPONENTS[0].1).iter().any(|n| is_prepend(n)) {
+        return;
+    }
+    remove_components(json, COMPONENTS[0], is_prepend);
+    let metaspace = json!({"type": "Metaspace", "replacement": SPIECE_UNDERLINE, "prepend_scheme": "first", "split": false});
+    let current = json["pre_tokenizer"].take();
+    json["pre_tokenizer"] = if current.is_null() {
+        metaspace
+    } else {
+        json!({"type": "Sequence", "pretokenizers": [metaspace, current]})
+    };
+}
+
+// set_add_special_token enables or disables the insertion of the BOS (or EOS) token by the post-processor, editing
+// its template: the BOS tokens are the special tokens before the first sequence of the single template, and the
+// EOS tokens the ones after the last sequence. Bert and Roberta post-processors are converted to the equivalent
+// template.
+//
+// When enabling it, if `token` (with its id) is given and it's not the configured one (or there is none), it
+// replaces it, inserted before (or after) each sequence.
+fn set_add_special_token(
+    json: &mut Value,
+    position: Position,
+    enabled: bool,
+    token: Option<(String, u32)>,
+) -> Result<(), Box<dyn Error>> {
+    let name = if position == Position::Bos { "BOS" } else { "EOS" };
+    let is_processor = |p: &Value| {
+        matches!(p["type"].as_str(), Some("TemplateProcessing" | "BertProcessing" | "RobertaProcessing"))
+    };
+    let index = components_mut(json, "post_processor", "processors").iter().position(|p| is_processor(p));
+    if index.is_none() {
+        if !enabled {
+            return Ok(());
+        }
+        if token.is_none() {
+            return Err(err(format!("no {} token in the post-processor, its id must be given", name)));
+        }
+        append_post_processor(json, json!({
+            "type": "TemplateProcessing",
+            "single": [sequence_piece("A", 0)],
+            "pair": [sequence_piece("A", 0), sequence_piece("B", 1)],
+            "special_tokens": {},
+        }));
+    }
+    let mut processors = components_mut(json, "post_processor", "processors");
+    let processor = match index {
+        Some(index) => processors.swap_remove(index),
+        None => processors.pop().unwrap(),
+    };
+    if let Some(template) = template_from_processor(processor) {
+        *processor = template;
+    }
+
+    let configured = special_run(&processor["single"], position);
+    if enabled {
+        let is_configured = |(_, id): &(String, u32)| {
+            configured.iter().any(|c| processor["special_tokens"][c]["ids"] == json!([id]))
+        };
+        if !configured.is_empty() && token.as_ref().is_none_or(is_configured) {
+            return Ok(());
+        }
+    }
+    for key in ["single", "pair"] {
+        if let Some(pieces) = processor[key].as_array_mut() {
+            remove_special_run(pieces, position, &configured);
+        }
+    }
+    if !enabled {
+        return Ok(());
+    }
+    let (token, id) = token.ok_or_else(|| err(format!("no {} token in the post-processor, its id must be given", name)))?;
+    for key in ["single", "pair"] {
+        if let Some(pieces) = processor[key].as_array_mut() {
+            let mut with_token = Vec::with_capacity(pieces.len() * 2);
+            for piece in pieces.drain(..) {
+                let type_id = &piece["Sequence"]["type_id"];
+                let special = (!type_id.is_null()).then(|| json!({"SpecialToken": {"id": token, "type_id": type_id}}));
+                match position {
+                    Position::Bos => with_token.extend(special.into_iter().chain([piece])),
+                    Position::Eos => with_token.extend([piece].into_iter().chain(special)),
+                }
+            }
+            *pieces = with_token;
+        }
+    }
+    processor["special_tokens"][&token] = json!({"id": token, "ids": [id], "tokens": [token]});
+    Ok(())
+}
+
+/// TemplateToken is a special token inserted by the post-processor, see `TemplateSpecialTokens`.
+#[derive(Serialize)]
+pub struct TemplateToken {
+    pub token: String,
+    pub id: u32,
+}
+
+/// TemplateSpecialTokens are the special tokens inserted by the post-processor of a tokenizer, in order, for
+/// single and pair inputs, and the ones inserted before the (first) sequence (`bos`) and after the (last)
+/// sequence (`eos`).
+#[derive(Default, Serialize)]
+pub struct TemplateSpecialTokens {
+    pub single: Vec<TemplateToken>,
+    pub pair: Vec<TemplateToken>,
+    pub bos: Vec<TemplateToken>,
+    pub eos: Vec<TemplateToken>,
+}
+
+/// template_special_tokens returns the special tokens inserted by the TemplateProcessing, BertProcessing or
+/// RobertaProcessing post-processor (possibly within a `Sequence`) of the `tokenizer`: they are empty if there
+/// is none.
+pub fn template_special_tokens(tokenizer: &Tokenizer) -> Result<TemplateSpecialTokens, Box<dyn Error>> {
+    let mut json = json!({"post_processor": serde_json::to_value(tokenizer.get_post_processor())?});
+    let processor = components_mut(&mut json, "post_processor", "processors")
+        .into_iter()
+        .find(|p| matches!(p["type"].as_str(), Some("TemplateProcessing" | "BertProcessing" | "RobertaProcessing")));
+    let Some(processor) = processor else { return Ok(TemplateSpecialTokens::default()) };
+    let template = template_from_processor(processor).unwrap_or_else(|| processor.clone());
+
+    // Each special token of the template may be expanded to more than one token.
+    let expand = |ids: Vec<String>| -> Result<Vec<TemplateToken>, Box<dyn Error>> {
+        let mut tokens = Vec::new();
+        for id in ids {
+            let special = &template["special_tokens"][&id];
+            let (Some(ids), Some(strs)) = (special["ids"].as_array(), special["tokens"].as_array()) else {
+                return Err(err(format!("special token {:?} of the post-processor template is not defined", id)));
+            };
+            for (id, token) in ids.iter().zip(strs) {
+                tokens.push(TemplateToken {
+                    token: token.as_str().unwrap_or_default().to_string(),
+                    id: id.as_u64().and_then(|id| u32::try_from(id).ok()).unwrap_or_default(),
+                });
+            }
+        }
+        Ok(tokens)
+    };
+    let special_ids = |key: &str| -> Vec<String> {
+        let pieces = template[key].as_array().map(Vec::as_slice).unwrap_or_default();
+        pieces.iter().filter_map(|piece| piece["SpecialToken"]["id"].as_str().map(str::to_string)).collect()
+    };
+    let mut eos = special_run(&template["single"], Position::Eos);
+    eos.reverse();
+    Ok(TemplateSpecialTokens {
+        single: expand(special_ids("single"))?,
+        pair: expand(special_ids("pair"))?,
+        bos: expand(special_run(&template["single"], Position::Bos))?,
+        eos: expand(eos)?,
+    })
+}
+
+// sequence_piece returns the serialized template piece of the sequence `id` ("A" or "B").
+fn sequence_piece(id: &str, type_id: u32) -> Value {
+    json!({"Sequence": {"id": id, "type_id": type_id}})
+}
+
+// template_from_processor returns the TemplateProcessing equivalent to the serialized Bert or Roberta
+// post-processor, or None for other post-processors.
+fn template_from_processor(processor: &Value) -> Option<Value> {
+    let pair_type_id = match processor["type"].as_str() {
+        Some("BertProcessing") => 1,
+        Some("RobertaProcessing") => 0,
+        _ => return None,
+    };
+    let (cls, sep) = (&processor["cls"][0], &processor["sep"][0]);
+    let special = |token: &Value, type_id: u32| json!({"SpecialToken": {"id": token, "type_id": type_id}});
+    let mut pair = vec![special(cls, 0), sequence_piece("A", 0), special(sep, 0)];
+    if pair_type_id == 0 {
+        pair.push(special(sep, 0));
+    }
+    pair.extend([sequence_piece("B", pair_type_id), special(sep, pair_type_id)]);
+    let mut special_tokens = serde_json::Map::new();
+    for token in [&processor["cls"], &processor["sep"]] {
+        special_tokens.insert(
+            token[0].as_str()?.to_string(),
+            json!({"id": token[0], "ids": [token[1]], "tokens": [token[0]]}),
+        );
+    }
+    Some(json!({
+        "type": "TemplateProcessing",
+        "single": [special(cls, 0), sequence_piece("A", 0), special(sep, 0)],
+        "pair": pair,
+        "special_tokens": special_tokens,
+    }))
+}
+
+// special_run returns the ids of the special tokens of the serialized `template` before its first sequence
+// (Position::Bos), or after its last sequence (Position::Eos).
+fn special_run(template: &Value, position: Position) -> Vec<String> {
+    let Some(pieces) = template.as_array() else { return Vec::new() };
+    let special_id = |piece: &Value| piece["SpecialToken"]["id"].as_str().map(str::to_string);
+    match position {
+        Position::Bos => pieces.iter().map_while(special_id).collect(),
+        Position::Eos => pieces.iter().rev().map_while(special_id).collect(),
+    }
+}
+
+// remove_special_run removes the special tokens with the given `ids` that are adjacent to a sequence of the
+// template: before it for Position::Bos, and after it for Position::Eos.
+fn remove_special_run(pieces: &mut Vec<Value>, position: Position, ids: &[String]) {
+    let is_special = |piece: &Value| piece.get("SpecialToken").is_some();
+    let mut remove = vec![false; pieces.len()];
+    for (i, piece) in pieces.iter().enumerate() {
+        if piece.get("Sequence").is_none() {
+            continue;
+        }
+        let adjacent: Box<dyn Iterator<Item = usize>> = match position {
+            Position::Bos => Box::new((0..i).rev()),
+            Position::Eos => Box::new(i + 1..pieces.len()),
+        };
+        for j in adjacent.take_while(|&j| is_special(&pieces[j])) {
+            if pieces[j]["SpecialToken"]["id"].as_str().is_some_and(|id| ids.iter().any(|c| c == id)) {
+                remove[j] = true;
+            }
+        }
+    }
+    let mut remove = remove.into_iter();
+    pieces.retain(|_| !remove.next().unwrap());
+}
+
+// append_post_processor adds the `processor` after the current post-processor.
+fn append_post_processor(json: &mut Value, processor: Value) {
+    let current = json["post_processor"].take();
+    json["post_processor"] = if current.is_null() {
+        processor
+    } else if current["type"] == "Sequence" {
+        let mut processors = current["processors"].as_array().cloned().unwrap_or_default();
+        processors.push(processor);
+        json!({"type": "Sequence", "processors": processors})
+    } else {
+        json!({"type": "Sequence", "processors": [current, processor]})
+    };
+}
+
+// prepend_normalizers adds the `normalizers` before the current normalizer.
+fn prepend_normalizers(json: &mut Value, mut normalizers: Vec<Value>) {
+    let current = json["normalizer"].take();
+    if current["type"] == "Sequence" {
+        if let Some(current) = current["normalizers"].as_array() {
+            normalizers.extend(current.iter().cloned());
+        }
+    } else if !current.is_null() {
+        normalizers.push(current);
+    }
+    json["normalizer"] = json!({"type": "Sequence", "normalizers": normalizers});
+}
+
+// append_decoder adds the `decoder` after the current decoder.
+fn append_decoder(json: &mut Value, decoder: Value) {
+    let current = json["decoder"].take();
+    let mut decoders = Vec::new();
+    if current["type"] == "Sequence" {
+        if let Some(current) = current["decoders"].as_array() {
+            decoders.extend(current.iter().cloned());
+        }
+    } else if !current.is_null() {
+        decoders.push(current);
+    }
+    decoders.push(decoder);
+    json["decoder"] = json!({"type": "Sequence", "decoders": decoders});
+}
+
+// remove_components removes the components (or sub-components of a `Sequence`) matching `predicate`.
+fn remove_components(json: &mut Value, (key, sequence_key): (&str, &str), predicate: impl Fn(&Value) -> bool) {
+    let component = &mut json[key];
+    if component["type"] == "Sequence" {
+        if let Some(components) = component[sequence_key].as_array_mut() {
+            components.retain(|c| !predicate(c));
+        }
+    } else if predicate(component) {
+        *component = Value::Null;
+    }
+}
+
+/// set_sentencepiece_options sets the options to match the reference sentencepiece library, for
+/// SentencePiece-derived tokenizers:
+///
+/// - `add_dummy_prefix`: whether a space is prepended to the text (and removed when decoding), either by the
+///   Metaspace pre-tokenizer or, for Llama-style pipelines, by the normalizer.
+/// - `remove_extra_whitespaces`: whether leading, trailing and repeated spaces are removed before encoding.
+///
+/// The original pipeline is kept, so resetting the options to their defaults restores it.
+///
+/// It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+/// The returned string needs to be freed with `free_string`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("set_sentencepiece_options"))]
+pub unsafe extern "C" fn set_sentencepiece_options(
+    tokenizer_ptr: *mut TokenizerHandle,
+    params: *const SentencePieceParams,
+) -> *mut c_char {
+    let result = catch_panic(|| {
+        let params = unsafe { params.as_ref() }.ok_or_else(|| err("SentencePieceParams is null"))?;
+        let add_dummy_prefix = match params.add_dummy_prefix {
+            0 => None,
+            1 => Some(true),
+            2 => Some(false),
+            value => return Err(err(format!("invalid add_dummy_prefix {}", value))),
+        };
+        let mut state = convert_to_handle_ref(tokenizer_ptr)?.write()?;
+        let options = CompatOptions {
+            add_dummy_prefix,
+            remove_extra_whitespaces: params.remove_extra_whitespaces,
+            ..state.compat_options()
+        };
+        state.set_compat_options(options)
+    });
+    match result {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+// check_charsmap_trie checks that the double-array trie of the `precompiled_charsmap` blob (a little-endian
+// u32 with its size in bytes, its u32 units, and the blob of normalized strings) can be searched with any
+// input without indexing out of bounds, and that the normalized strings it points to are valid -- so a
+// corrupted blob is rejected, instead of panicking when normalizing.
+fn check_charsmap_trie(precompiled_charsmap: &[u8]) -> Result<(), Box<dyn Error>> {
+    let invalid = || err("invalid precompiled charsmap: corrupted trie");
+    let trie_size = precompiled_charsmap
+        .get(..4)
+        .map(|size| u32::from_le_bytes(size.try_into().unwrap()) as usize)
+        .ok_or_else(invalid)?;
+    let units: Vec<usize> = precompiled_charsmap
+        .get(4..4 + trie_size / 4 * 4)
+        .ok_or_else(invalid)?
+        .chunks_exact(4)
+        .map(|unit| u32::from_le_bytes(unit.try_into().unwrap()) as usize)
+        .collect();
+    let normalized = std::str::from_utf8(&precompiled_charsmap[4 + units.len() * 4..])
+        .map_err(|_| err("invalid precompiled charsmap: normalized strings are not UTF-8"))?;
+
+    // Unit fields, as in the darts-clone format.
+    let has_leaf = |unit: usize| (unit >> 8) & 1 == 1;
+    let value = |unit: usize| unit & ((1 << 31) - 1);
+    let label = |unit: usize| unit & ((1 << 31) | 0xFF);
+    let offset = |unit: usize| (unit >> 10) << ((unit & (1 << 9)) >> 6);
+
+    // Walk all the nodes reachable from the root.
+    let root = *units.first().ok_or_else(invalid)?;
+    let mut visited = vec![false; units.len()];
+    let mut pending = vec![offset(root)];
+    while let Some(node) = pending.pop() {
+        for c in 1..=255usize {
+            let unit = *units.get(node ^ c).ok_or_else(invalid)?;
+            if label(unit) != c {
+                continue;
+            }
+            let child = node ^ c ^ offset(unit);
+            if has_leaf(unit) {
+                let index = value(*units.get(child).ok_or_else(invalid)?);
+                if !normalized.is_char_boundary(index) {
+                    return Err(invalid());
+                }
+            }
+            if child < visited.len() && !visited[child] {
+                visited[child] = true;
+                pending.push(child);
+            }
+        }
+    }
+    Ok(())
+}
+
+// set_precompiled_charsmap_impl replaces the Precompiled normalizers of the pipeline with the one of the
+// `precompiled_charsmap` (or prepends it), or removes them if it is empty.
+fn set_precompiled_charsmap_impl(tokenizer_ptr: *mut TokenizerHandle, precompiled_charsmap: &[u8]) -> Result<(), Box<dyn Error>> {
+    let precompiled = if precompiled_charsmap.is_empty() {
+        None
+    } else {
+        let precompiled = Precompiled::from(precompiled_charsmap)
+            .map_err(|e| err(format!("invalid precompiled charsmap: {}", e)))?;
+        check_charsmap_trie(precompiled_charsmap)?;
+        Some(serde_json::to_value(&precompiled)?)
+    };
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let mut state = handle.write()?;
+    let mut json = state.pipeline_json()?;
+    let is_precompiled = |normalizer: &Value| normalizer["type"] == "Precompiled";
+    match precompiled {
+        Some(precompiled) => {
+            let mut replaced = false;
+            for normalizer in components_mut(&mut json, COMPONENTS[0].0, COMPONENTS[0].1) {
+                if is_precompiled(normalizer) {
+                    *normalizer = precompiled.clone();
+                    replaced = true;
+                }
+            }
+            if !replaced {
+                prepend_normalizers(&mut json, vec![precompiled]);
+            }
+        }
+        None => remove_components(&mut json, COMPONENTS[0], is_precompiled),
+    }
+    let tokenizer = Tokenizer::from_str(&json.to_string())
+        .map_err(|e| err(format!("failed to set precompiled charsmap: {}", e)))?;
+    state.replace_tokenizer(tokenizer)
+}
+
+/// set_precompiled_charsmap installs the `len` bytes of a SentencePiece precompiled charsmap (the
+/// `normalizer_spec.precompiled_charsmap` of a SentencePiece model, e.g. mT5 or XLM-R) as the Precompiled
+/// normalizer of the tokenizer, for pipelines assembled from raw SentencePiece assets: it replaces the current
+/// Precompiled normalizer, if any, or otherwise it is prepended to the current normalizers. An empty charsmap
+/// (`len` 0) removes the Precompiled normalizers, as SentencePiece does for the identity normalization.
+///
+/// The charsmap is validated, so a corrupted one is reported as an error. The compatibility options (see
+/// `set_sentencepiece_options`) are kept.
+///
+/// It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+/// The returned string needs to be freed with `free_string`.
+///
+/// # Safety
+///
+/// `precompiled_charsmap` must point to `len` bytes.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("set_precompiled_charsmap"))]
+pub unsafe extern "C" fn set_precompiled_charsmap(
+    tokenizer_ptr: *mut TokenizerHandle,
+    precompiled_charsmap: *const u8,
+    len: u32,
+) -> *mut c_char {
+    let precompiled_charsmap = if len == 0 {
+        &[][..]
+    } else {
+        unsafe { std::slice::from_raw_parts(precompiled_charsmap, len as usize) }
+    };
+    match catch_panic(|| set_precompiled_charsmap_impl(tokenizer_ptr, precompiled_charsmap)) {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+/// set_legacy_llama_mode sets the compatibility mode with the original (slow) Llama/Mistral tokenizers, for
+/// exact parity with transformers:
+///
+/// - 0: as configured (default).
+/// - 1: `legacy=True`: the dummy prefix (a space) is added to the text after each special token, so e.g.
+///   "<s>Hello" is encoded as "<s>", "▁Hello".
+/// - 2: `legacy=False`: the dummy prefix is only added at the start of the text.
+///
+/// It has no effect if the dummy prefix is disabled (see `set_sentencepiece_options`).
+///
+/// It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+/// The returned string needs to be freed with `free_string`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("set_legacy_llama_mode"))]
+pub unsafe extern "C" fn set_legacy_llama_mode(tokenizer_ptr: *mut TokenizerHandle, mode: u8) -> *mut c_char {
+    let result = catch_panic(|| {
+        let legacy_llama = match mode {
+            0 => None,
+            1 => Some(true),
+            2 => Some(false),
+            value => return Err(err(format!("invalid legacy Llama mode {}", value))),
+        };
+        let mut state = convert_to_handle_ref(tokenizer_ptr)?.write()?;
+        let options = CompatOptions { legacy_llama, ..state.compat_options() };
+        state.set_compat_options(options)
+    });
+    match result {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+// set_add_special_token_option sets the `add_bos_token` (or `add_eos_token`) compatibility option, see
+// `set_add_bos_token` and `set_add_eos_token`.
+fn set_add_special_token_option(
+    tokenizer_ptr: *mut TokenizerHandle,
+    position: Position,
+    mode: u8,
+    token_id: i64,
+) -> Result<(), Box<dyn Error>> {
+    let enabled = match mode {
+        0 => None,
+        1 => Some(true),
+        2 => Some(false),
+        value => return Err(err(format!("invalid add special token mode {}", value))),
+    };
+    let token_id = if token_id < 0 { None } else { Some(u32::try_from(token_id)?) };
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let mut state = handle.write()?;
+    let mut options = state.compat_options();
+    match position {
+        Position::Bos => (options.add_bos_token, options.bos_token_id) = (enabled, token_id),
+        Position::Eos => (options.add_eos_token, options.eos_token_id) = (enabled, token_id),
+    }
+    state.set_compat_options(options)
+}
+
+/// set_add_bos_token enables or disables the insertion of the BOS (beginning of sequence) token, by editing the
+/// post-processor template (Bert and Roberta post-processors are converted to the equivalent template):
+///
+/// - `mode`: 0 -> as configured (default), 1 -> enabled, 2 -> disabled.
+/// - `token_id`: the BOS token, if it's not configured in the post-processor (or to replace it), or -1 to use the
+///   configured one.
+///
+/// The BOS tokens are the special tokens inserted before the (first) sequence. The original post-processor is kept,
+/// so resetting `mode` to 0 restores it.
+///
+/// It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+/// The returned string needs to be freed with `free_string`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("set_add_bos_token"))]
+pub unsafe extern "C" fn set_add_bos_token(tokenizer_ptr: *mut TokenizerHandle, mode: u8, token_id: i64) -> *mut c_char {
+    match catch_panic(|| set_add_special_token_option(tokenizer_ptr, Position::Bos, mode, token_id)) {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+/// set_add_eos_token enables or disables the insertion of the EOS (end of sequence) token, the same way as
+/// `set_add_bos_token`: the EOS tokens are the special tokens inserted after the (last) sequence.
+///
+/// It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+/// The returned string needs to be freed with `free_string`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("set_add_eos_token"))]
+pub unsafe extern "C" fn set_add_eos_token(tokenizer_ptr: *mut TokenizerHandle, mode: u8, token_id: i64) -> *mut c_char {
+    match catch_panic(|| set_add_special_token_option(tokenizer_ptr, Position::Eos, mode, token_id)) {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::ENCODE_ADD_SPECIAL_TOKENS;
+    use crate::testing::{encode_ids, take_error, TestHandle, SENTENCEPIECE};
+
+    // set_options sets the SentencePiece options of the tokenizer, panicking on error.
+    fn set_options(handle: &TestHandle, add_dummy_prefix: u8, remove_extra_whitespaces: bool) {
+        let params = SentencePieceParams { add_dummy_prefix, remove_extra_whitespaces };
+        assert_eq!(take_error(unsafe { set_sentencepiece_options(handle.0, &params) }), None);
+    }
+
+    #[test]
+    fn sentencepiece_options() {
+        let handle = TestHandle::new(SENTENCEPIECE);
+        assert_eq!(encode_ids(&handle, "hello world", 0), [3, 4]);
+        set_options(&handle, 2, false);
+        assert_eq!(encode_ids(&handle, "hello world", 0), [5, 4]);
+
+        // The extra spaces are otherwise encoded as "▁".
+        assert_eq!(encode_ids(&handle, " hello  world", 0), [3, 6, 4]);
+        set_options(&handle, 0, true);
+        assert_eq!(encode_ids(&handle, " hello  world ", 0), [3, 4]);
+
+        // The defaults restore the original pipeline.
+        set_options(&handle, 0, false);
+        assert_eq!(encode_ids(&handle, " hello  world", ENCODE_ADD_SPECIAL_TOKENS), [1, 3, 6, 4]);
+        let params = SentencePieceParams { add_dummy_prefix: 3, remove_extra_whitespaces: false };
+        let error = take_error(unsafe { set_sentencepiece_options(handle.0, &params) });
+        assert_eq!(error.as_deref(), Some("invalid add_dummy_prefix 3"));
+    }
+
+    #[test]
+    fn legacy_llama_mode() {
+        let handle = TestHandle::new(SENTENCEPIECE);
+        let set_mode = |mode| take_error(unsafe { set_legacy_llama_mode(handle.0, mode) });
+        // legacy=True: the dummy prefix is also added after special tokens.
+        assert_eq!(set_mode(1), None);
+        assert_eq!(encode_ids(&handle, "<s>hello", 0), [1, 3]);
+        assert_eq!(set_mode(2), None);
+        assert_eq!(encode_ids(&handle, "<s>hello", 0), [1, 5]);
+        assert_eq!(encode_ids(&handle, "hello", 0), [3]);
+        assert_eq!(set_mode(0), None);
+        assert_eq!(encode_ids(&handle, "<s>hello", 0), [1, 3]);
+        assert_eq!(set_mode(3).as_deref(), Some("invalid legacy Llama mode 3"));
+
+        // No effect without the dummy prefix.
+        set_options(&handle, 2, false);
+        assert_eq!(set_mode(1), None);
+        assert_eq!(encode_ids(&handle, "<s>hello", 0), [1, 5]);
+    }
+
+    #[test]
+    fn add_bos_eos_tokens() {
+        let handle = TestHandle::new(SENTENCEPIECE);
+        let bos = |mode, id| take_error(unsafe { set_add_bos_token(handle.0, mode, id) });
+        let eos = |mode, id| take_error(unsafe { set_add_eos_token(handle.0, mode, id) });
+        let encode = || encode_ids(&handle, "hello", ENCODE_ADD_SPECIAL_TOKENS);
+        assert_eq!(encode(), [1, 3]);
+        assert_eq!(eos(1, 2), None);
+        assert_eq!(encode(), [1, 3, 2]);
+        assert_eq!(bos(2, -1), None);
+        assert_eq!(encode(), [3, 2]);
+        assert_eq!(bos(0, -1), None);
+        assert_eq!(eos(0, -1), None);
+        assert_eq!(encode(), [1, 3]);
+
+        assert_eq!(eos(1, -1).as_deref(), Some("no EOS token in the post-processor, its id must be given"));
+        assert_eq!(eos(1, 99).as_deref(), Some("special token id 99 is not in the vocabulary"));
+        assert_eq!(encode(), [1, 3]);
+    }
+
+    // normalizer_types returns the types of the normalizers of the tokenizer.
+    fn normalizer_types(handle: &TestHandle) -> Vec<String> {
+        let state = unsafe { (*handle.0).read() };
+        let mut json = json!({"normalizer": serde_json::to_value(state.tokenizer.get_normalizer()).unwrap()});
+        let normalizers = components_mut(&mut json, COMPONENTS[0].0, COMPONENTS[0].1);
+        normalizers.iter().map(|n| n["type"].as_str().unwrap_or_default().to_string()).collect()
+    }
+
+    #[test]
+    fn precompiled_charsmap() {
+        let handle = TestHandle::new(SENTENCEPIECE);
+        set_options(&handle, 0, true);
+        let set = |charsmap: &[u8]| {
+            take_error(unsafe { set_precompiled_charsmap(handle.0, charsmap.as_ptr(), charsmap.len() as u32) })
+        };
+        // A trie of 256 empty units doesn't map anything. The normalizers of the compatibility options are kept.
+        let mut charsmap = 1024u32.to_le_bytes().to_vec();
+        charsmap.resize(4 + 1024, 0);
+        assert_eq!(set(&charsmap), None);
+        assert_eq!(normalizer_types(&handle), ["Strip", "Replace", "Precompiled"]);
+        assert_eq!(encode_ids(&handle, " hello  world ", 0), [3, 4]);
+        assert_eq!(set(&charsmap), None);
+        assert_eq!(normalizer_types(&handle), ["Strip", "Replace", "Precompiled"]);
+
+        assert_eq!(set(&[]), None);
+        assert_eq!(normalizer_types(&handle), ["Strip", "Replace"]);
+
+        // The children of the root are out of the trie.
+        let mut corrupted = 8u32.to_le_bytes().to_vec();
+        corrupted.resize(4 + 8, 0);
+        assert!(set(&corrupted).unwrap().starts_with("invalid precompiled charsmap"));
+        assert_eq!(normalizer_types(&handle), ["Strip", "Replace"]);
+    }
+}
@@ -0,0 +1,116 @@
+//! Compiled tokenizers: a compact binary serialization of a tokenizer (see `compile`), faster to load than its
+//! `tokenizer.json`, for deployments sensitive to the start-up time (e.g. serverless).
+
+use std::error::Error;
+use tokenizers::tokenizer::Tokenizer;
+use crate::encode::err;
+use crate::handle::{convert_to_handle_ref, TokenizerHandle};
+use crate::load::{new_handle, shared_tokenizer};
+use crate::panics::catch_panic;
+use crate::{BytesOrError, PointerOrError};
+
+// COMPILED_MAGIC starts every compiled tokenizer, followed by the COMPILED_VERSION (4 bytes, little-endian)
+// and the MessagePack serialization of the tokenizer.
+const COMPILED_MAGIC: &[u8; 4] = b"GTKC";
+
+// COMPILED_VERSION is the version of the format of the compiled tokenizers.
+const COMPILED_VERSION: u32 = 1;
+
+fn compile_impl(tokenizer_ptr: *mut TokenizerHandle) -> Result<Vec<u8>, Box<dyn Error>> {
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let json = handle.read().pipeline_json()?;
+    let mut bytes = COMPILED_MAGIC.to_vec();
+    bytes.extend_from_slice(&COMPILED_VERSION.to_le_bytes());
+    rmp_serde::encode::write_named(&mut bytes, &json)?;
+    Ok(bytes)
+}
+
+/// compile returns the tokenizer compiled to a compact binary format, to be loaded with `from_compiled`: it
+/// holds the same definitions as its `tokenizer.json` (including the padding, truncation and added tokens),
+/// but it is smaller, and faster to load, since no JSON needs to be parsed.
+///
+/// The per-tokenizer options of the binding (e.g. deterministic mode) are not included, as in the
+/// `tokenizer.json` file.
+///
+/// The format is versioned, but it depends on the version of the library: compiled tokenizers should be
+/// rebuilt (from their `tokenizer.json`) when the library is upgraded.
+///
+/// The result must be freed with `free_bytes`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("compile"))]
+pub extern "C" fn compile(tokenizer_ptr: *mut TokenizerHandle) -> BytesOrError {
+    BytesOrError::from_result(catch_panic(|| compile_impl(tokenizer_ptr)))
+}
+
+// parse_compiled parses the tokenizer compiled in `bytes` (see `compile`).
+fn parse_compiled(bytes: &[u8]) -> Result<Tokenizer, Box<dyn Error>> {
+    let payload = bytes
+        .strip_prefix(COMPILED_MAGIC)
+        .ok_or_else(|| err("not a compiled tokenizer"))?;
+    let (version, payload) = match payload.split_first_chunk::<4>() {
+        Some((version, payload)) => (u32::from_le_bytes(*version), payload),
+        None => return Err(err("compiled tokenizer is truncated")),
+    };
+    if version != COMPILED_VERSION {
+        return Err(err(format!(
+            "compiled tokenizer has version {}, but only version {} is supported: compile it again",
+            version, COMPILED_VERSION
+        )));
+    }
+    rmp_serde::from_slice(payload).map_err(|e| err(format!("failed to load compiled tokenizer: {}", e)))
+}
+
+/// from_compiled loads a tokenizer compiled with `compile` from the `len` bytes in `bytes`, and returns it in
+/// the `value` field, or an error -- as `from_bytes`, it is shared with the other handles loaded from the same
+/// contents.
+///
+/// # Safety
+///
+/// `bytes` must point to `len` bytes.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("from_compiled"))]
+pub unsafe extern "C" fn from_compiled(bytes: *const u8, len: u64) -> PointerOrError {
+    let bytes = if len == 0 { &[][..] } else { unsafe { std::slice::from_raw_parts(bytes, len as usize) } };
+    new_handle(catch_panic(|| shared_tokenizer(bytes, parse_compiled)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokenizers::{PaddingParams, PaddingStrategy};
+    use crate::encode::ENCODE_ADD_SPECIAL_TOKENS;
+    use crate::testing::{encode_ids, take_bytes, take_error, TestHandle, WORDPIECE_BERT};
+
+    // load returns the handle of the tokenizer compiled in `bytes`, or the error.
+    fn load(bytes: &[u8]) -> Result<TestHandle, String> {
+        let result = unsafe { from_compiled(bytes.as_ptr(), bytes.len() as u64) };
+        match take_error(result.error) {
+            Some(e) => Err(e),
+            None => Ok(TestHandle(result.value.cast())),
+        }
+    }
+
+    #[test]
+    fn compile_and_load() {
+        let handle = TestHandle::new(WORDPIECE_BERT);
+        let padding = PaddingParams { strategy: PaddingStrategy::Fixed(5), ..Default::default() };
+        convert_to_handle_ref(handle.0).unwrap().write().unwrap().tokenizer_mut().with_padding(Some(padding));
+        let compiled = take_bytes(compile(handle.0)).unwrap();
+        assert!(compiled.starts_with(b"GTKC\x01\x00\x00\x00"));
+
+        // The padding is kept.
+        let loaded = load(&compiled).unwrap();
+        assert_eq!(encode_ids(&loaded, "hello a", ENCODE_ADD_SPECIAL_TOKENS), [10, 4, 1, 11, 0]);
+        assert_eq!(encode_ids(&loaded, "a b", 0), encode_ids(&handle, "a b", 0));
+
+        assert_eq!(load(WORDPIECE_BERT.as_bytes()).err().as_deref(), Some("not a compiled tokenizer"));
+        assert_eq!(load(b"GTKC\x01").err().as_deref(), Some("compiled tokenizer is truncated"));
+        let mut other_version = compiled.clone();
+        other_version[4] = 2;
+        let error = load(&other_version).err().unwrap();
+        assert!(error.starts_with("compiled tokenizer has version 2, but only version 1"), "{}", error);
+        let error = load(&compiled[..compiled.len() / 2]).err().unwrap();
+        assert!(error.starts_with("failed to load compiled tokenizer"), "{}", error);
+        assert!(take_bytes(compile(std::ptr::null_mut())).is_err());
+    }
+}
@@ -0,0 +1,426 @@
+//! Settings of the `tokenizer_config.json` file of the transformers library, that are not part of the
+//! `tokenizer.json` definitions: they can be loaded along with a tokenizer (see `from_bytes_with_config`),
+//! or later (see `set_tokenizer_config`), and applied to it (see `apply_tokenizer_config`).
+
+use std::error::Error;
+use std::ffi::{c_char, CStr};
+use std::ptr::null_mut;
+use std::sync::Arc;
+use serde::Serialize;
+use serde_json::Value;
+use tokenizers::tokenizer::{PaddingDirection, TruncationDirection, TruncationParams};
+use tokenizers::AddedToken;
+use crate::encode::err;
+use crate::handle::{convert_to_handle_ref, TokenizerHandle, TokenizerState};
+use crate::panics::{catch_panic, error_c_string};
+use crate::BytesOrError;
+
+// Name of the chat template used when none is given, as in transformers.
+const DEFAULT_CHAT_TEMPLATE: &str = "default";
+
+// Keys of the special tokens settings, in `tokenizer_config.json` and `special_tokens_map.json`.
+const SPECIAL_TOKEN_KEYS: [&str; 7] =
+    ["bos_token", "eos_token", "unk_token", "sep_token", "pad_token", "cls_token", "mask_token"];
+
+/// TokenizerConfig holds the settings read from a `tokenizer_config.json` file.
+pub struct TokenizerConfig {
+    // chat_templates holds the (name, Jinja template) pairs of the `chat_template` setting, in order. A single
+    // template is named "default".
+    chat_templates: Vec<(String, String)>,
+
+    // Settings applied to the tokenizer by `apply_tokenizer_config`. `model_max_length` is None if it doesn't
+    // fit u32, as the "very large integer" used by transformers when it is not set.
+    padding_side: Option<PaddingDirection>,
+    truncation_side: Option<TruncationDirection>,
+    model_max_length: Option<u32>,
+
+    // special_tokens holds the (key, content) pairs of the special tokens, in order, with the key
+    // "additional_special_tokens" for the additional ones.
+    special_tokens: Vec<(String, String)>,
+}
+
+/// AppliedSettings reports the settings applied by `apply_tokenizer_config`: the fields of the settings that
+/// were not applied are omitted.
+#[derive(Serialize, Default)]
+struct AppliedSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    padding_side: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    truncation_max_length: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pad_token: Option<String>,
+    special_tokens_added: Vec<String>,
+}
+
+// side parses the `padding_side` or `truncation_side` settings.
+fn side(json: &Value, key: &str) -> Result<Option<bool>, Box<dyn Error>> {
+    match &json[key] {
+        Value::Null => Ok(None),
+        Value::String(side) if side == "left" => Ok(Some(true)),
+        Value::String(side) if side == "right" => Ok(Some(false)),
+        value => Err(err(format!("invalid tokenizer config: invalid `{}` {}", key, value))),
+    }
+}
+
+// special_token_content returns the content of a special token setting: either a string, or an object with
+// the `content` string (a serialized AddedToken).
+fn special_token_content(key: &str, value: &Value) -> Result<String, Box<dyn Error>> {
+    match value {
+        Value::String(content) => Ok(content.clone()),
+        Value::Object(token) => match token.get("content") {
+            Some(Value::String(content)) => Ok(content.clone()),
+            _ => Err(err(format!("invalid `{}`: special token without a `content`", key))),
+        },
+        value => Err(err(format!("invalid `{}`: {} is not a special token", key, value))),
+    }
+}
+
+// special_tokens returns the (key, content) pairs of the special token settings of `json`.
+fn special_tokens(json: &Value) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let mut tokens = Vec::new();
+    for key in SPECIAL_TOKEN_KEYS {
+        match &json[key] {
+            Value::Null => {}
+            value => tokens.push((key.to_string(), special_token_content(key, value)?)),
+        }
+    }
+    match &json["additional_special_tokens"] {
+        Value::Null => {}
+        Value::Array(values) => {
+            for value in values {
+                tokens.push(("additional_special_tokens".to_string(), special_token_content("additional_special_tokens", value)?));
+            }
+        }
+        value => return Err(err(format!("invalid `additional_special_tokens`: {} is not a list", value))),
+    }
+    Ok(tokens)
+}
+
+impl TokenizerConfig {
+    /// Parses the contents of a `tokenizer_config.json` file.
+    pub fn from_slice(bytes: &[u8]) -> Result<TokenizerConfig, Box<dyn Error>> {
+        let json: Value = serde_json::from_slice(bytes)
+            .map_err(|e| err(format!("invalid tokenizer config: {}", e)))?;
+        if !json.is_object() {
+            return Err(err("invalid tokenizer config: not a JSON object"));
+        }
+        let chat_templates = match &json["chat_template"] {
+            Value::Null => Vec::new(),
+            Value::String(template) => vec![(DEFAULT_CHAT_TEMPLATE.to_string(), template.clone())],
+            Value::Array(templates) => templates
+                .iter()
+                .map(|template| match (template["name"].as_str(), template["template"].as_str()) {
+                    (Some(name), Some(template)) => Ok((name.to_string(), template.to_string())),
+                    _ => Err(err("invalid tokenizer config: chat templates must have a `name` and a `template`")),
+                })
+                .collect::<Result<_, _>>()?,
+            _ => return Err(err("invalid tokenizer config: `chat_template` must be a string or a list")),
+        };
+        let model_max_length = match &json["model_max_length"] {
+            Value::Null => None,
+            Value::Number(len) => len.as_u64().and_then(|len| u32::try_from(len).ok()),
+            value => return Err(err(format!("invalid tokenizer config: invalid `model_max_length` {}", value))),
+        };
+        Ok(TokenizerConfig {
+            chat_templates,
+            padding_side: side(&json, "padding_side")?
+                .map(|left| if left { PaddingDirection::Left } else { PaddingDirection::Right }),
+            truncation_side: side(&json, "truncation_side")?
+                .map(|left| if left { TruncationDirection::Left } else { TruncationDirection::Right }),
+            model_max_length,
+            special_tokens: special_tokens(&json).map_err(|e| err(format!("invalid tokenizer config: {}", e)))?,
+        })
+    }
+
+    /// Merges the special tokens of the contents of a `special_tokens_map.json` file: they replace those of
+    /// the config with the same key (all the additional ones, if given).
+    pub fn merge_special_tokens_map(&mut self, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+        let json: Value = serde_json::from_slice(bytes)
+            .map_err(|e| err(format!("invalid special tokens map: {}", e)))?;
+        let tokens = special_tokens(&json).map_err(|e| err(format!("invalid special tokens map: {}", e)))?;
+        self.special_tokens.retain(|(key, _)| !tokens.iter().any(|(k, _)| k == key));
+        self.special_tokens.extend(tokens);
+        Ok(())
+    }
+
+    /// Returns the (key, content) pairs of the special tokens of the config, e.g. ("bos_token", "<s>"), in order.
+    pub fn special_tokens(&self) -> &[(String, String)] {
+        &self.special_tokens
+    }
+
+    /// Returns the padding side of the config, if set.
+    pub fn padding_side(&self) -> Option<PaddingDirection> {
+        self.padding_side
+    }
+
+    // apply applies the settings to the tokenizer of the `state`, and returns the settings applied.
+    fn apply(&self, state: &mut TokenizerState) -> Result<AppliedSettings, Box<dyn Error>> {
+        let mut applied = AppliedSettings::default();
+        let tokenizer = state.tokenizer_mut();
+
+        // Special tokens missing from the tokenizer (or not special in it) are added.
+        for (_, content) in &self.special_tokens {
+            if !tokenizer.get_added_vocabulary().is_special_token(content) && !applied.special_tokens_added.contains(content) {
+                tokenizer.add_special_tokens(&[AddedToken::from(content.clone(), true)]);
+                applied.special_tokens_added.push(content.clone());
+            }
+        }
+
+        // The padding side is also the default padding direction if the tokenizer has no padding (see
+        // `encode_batch_padded`).
+        if let Some(side) = self.padding_side {
+            if let Some(padding) = tokenizer.get_padding_mut() {
+                padding.direction = side;
+            }
+            applied.padding_side = Some(match side {
+                PaddingDirection::Left => "left",
+                PaddingDirection::Right => "right",
+            });
+        }
+        if let Some((_, pad_token)) = self.special_tokens.iter().find(|(key, _)| key == "pad_token") {
+            let pad_id = tokenizer.token_to_id(pad_token);
+            if let (Some(padding), Some(pad_id)) = (tokenizer.get_padding_mut(), pad_id) {
+                padding.pad_token = pad_token.clone();
+                padding.pad_id = pad_id;
+                applied.pad_token = Some(pad_token.clone());
+            }
+        }
+
+        // model_max_length is only the default truncation: it doesn't replace the tokenizer truncation.
+        if let (None, Some(max_length)) = (tokenizer.get_truncation(), self.model_max_length) {
+            tokenizer
+                .with_truncation(Some(TruncationParams {
+                    max_length: max_length as usize,
+                    direction: self.truncation_side.unwrap_or(TruncationDirection::Right),
+                    ..TruncationParams::default()
+                }))
+                .map_err(|e| err(format!("failed to set truncation to model_max_length: {}", e)))?;
+            applied.truncation_max_length = Some(max_length);
+        }
+        Ok(applied)
+    }
+
+    /// Returns the chat template with the given name, or the default one.
+    pub fn chat_template(&self, name: Option<&str>) -> Option<&str> {
+        let name = name.unwrap_or(DEFAULT_CHAT_TEMPLATE);
+        self.chat_templates.iter().find(|(n, _)| n == name).map(|(_, template)| template.as_str())
+    }
+}
+
+// parse_config parses the `len` bytes of `config`, if not null.
+pub(crate) fn parse_config(config: *const u8, len: u32) -> Result<Option<TokenizerConfig>, Box<dyn Error>> {
+    if config.is_null() {
+        return Ok(None);
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(config, len as usize) };
+    TokenizerConfig::from_slice(bytes).map(Some)
+}
+
+fn set_tokenizer_config_impl(tokenizer_ptr: *mut TokenizerHandle, config: *const u8, len: u32) -> Result<(), Box<dyn Error>> {
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let config = parse_config(config, len)?;
+    handle.write()?.set_config(config.map(Arc::new));
+    Ok(())
+}
+
+/// set_tokenizer_config sets the settings of the tokenizer read from the `len` bytes of `config`, the contents
+/// of a `tokenizer_config.json` file, replacing any previous ones. If `config` is null, they are cleared.
+///
+/// It returns null if ok, or a string with an error message (owned by caller), to be freed with `free_string`.
+///
+/// # Safety
+///
+/// `config` must be null or point to `len` bytes.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("set_tokenizer_config"))]
+pub unsafe extern "C" fn set_tokenizer_config(tokenizer_ptr: *mut TokenizerHandle, config: *const u8, len: u32) -> *mut c_char {
+    match catch_panic(|| set_tokenizer_config_impl(tokenizer_ptr, config, len)) {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+fn get_chat_template_impl(tokenizer_ptr: *mut TokenizerHandle, name: *const c_char) -> Result<Vec<u8>, Box<dyn Error>> {
+    let name = if name.is_null() { None } else { Some(unsafe { CStr::from_ptr(name) }.to_str()?) };
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    let config = state.config().ok_or_else(|| err("tokenizer has no tokenizer config"))?;
+    match config.chat_template(name) {
+        Some(template) => Ok(template.as_bytes().to_vec()),
+        None => Err(err(format!("tokenizer has no chat template {:?}", name.unwrap_or(DEFAULT_CHAT_TEMPLATE)))),
+    }
+}
+
+/// get_chat_template returns the (Jinja) chat template with the given null-terminated `name`, or the default
+/// one if `name` is null, from the `chat_template` setting of the tokenizer config (see `set_tokenizer_config`).
+/// The templates are not rendered: that is left to the host.
+///
+/// The template is returned in a BytesOrError, that must be freed with `free_bytes`. It is an error if the
+/// tokenizer has no such template.
+///
+/// # Safety
+///
+/// `name` must be null or a null-terminated string.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("get_chat_template"))]
+pub unsafe extern "C" fn get_chat_template(tokenizer_ptr: *mut TokenizerHandle, name: *const c_char) -> BytesOrError {
+    BytesOrError::from_result(catch_panic(|| get_chat_template_impl(tokenizer_ptr, name)))
+}
+
+fn get_chat_template_names_impl(tokenizer_ptr: *mut TokenizerHandle) -> Result<Vec<u8>, Box<dyn Error>> {
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    let names: Vec<&str> = state
+        .config()
+        .map(|config| config.chat_templates.iter().map(|(name, _)| name.as_str()).collect())
+        .unwrap_or_default();
+    Ok(serde_json::to_vec(&names)?)
+}
+
+/// get_chat_template_names returns the names of the chat templates of the tokenizer config (see
+/// `set_tokenizer_config`) as a JSON array of strings, in order: a single template is named "default". It is
+/// empty if there is no tokenizer config, or it has no chat template.
+///
+/// The result is returned in a BytesOrError, that must be freed with `free_bytes`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("get_chat_template_names"))]
+pub unsafe extern "C" fn get_chat_template_names(tokenizer_ptr: *mut TokenizerHandle) -> BytesOrError {
+    BytesOrError::from_result(catch_panic(|| get_chat_template_names_impl(tokenizer_ptr)))
+}
+
+fn apply_tokenizer_config_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    config: *const u8,
+    config_len: u32,
+    special_tokens_map: *const u8,
+    special_tokens_map_len: u32,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let mut config = parse_config(config, config_len)?.ok_or_else(|| err("tokenizer config is null"))?;
+    if !special_tokens_map.is_null() {
+        let bytes = unsafe { std::slice::from_raw_parts(special_tokens_map, special_tokens_map_len as usize) };
+        config.merge_special_tokens_map(bytes)?;
+    }
+    let mut state = handle.write()?;
+    let applied = config.apply(&mut state)?;
+    state.set_config(Some(Arc::new(config)));
+    Ok(serde_json::to_vec(&applied)?)
+}
+
+/// apply_tokenizer_config applies the relevant settings of the `config_len` bytes of `config`, the contents of
+/// the `tokenizer_config.json` file of the model, and of the `special_tokens_map_len` bytes of
+/// `special_tokens_map` (if not null), the contents of its `special_tokens_map.json` file, to the tokenizer --
+/// as transformers does when loading a model directory. The settings are also kept, as with
+/// `set_tokenizer_config`.
+///
+/// The settings applied are:
+///
+/// - The special tokens (`bos_token`, `eos_token`, ..., and `additional_special_tokens`): those missing from
+///   the tokenizer, or not special in it, are added as special tokens. Those of `special_tokens_map.json`
+///   replace those of `tokenizer_config.json`.
+/// - `padding_side`: the direction of the tokenizer padding, if set, and the default padding direction
+///   otherwise (see `encode_batch_padded`). And `pad_token` is the token of the tokenizer padding, if set.
+/// - `model_max_length` (with `truncation_side`): the truncation `max_length`, if the tokenizer has no
+///   truncation.
+///
+/// It returns a JSON object reporting the settings applied, with the keys `padding_side`,
+/// `truncation_max_length` and `pad_token` (omitted if not applied), and `special_tokens_added` (an array),
+/// in a BytesOrError that must be freed with `free_bytes`.
+///
+/// # Safety
+///
+/// `config` must point to `config_len` bytes, and `special_tokens_map` must be null or point to
+/// `special_tokens_map_len` bytes.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("apply_tokenizer_config"))]
+pub unsafe extern "C" fn apply_tokenizer_config(
+    tokenizer_ptr: *mut TokenizerHandle,
+    config: *const u8,
+    config_len: u32,
+    special_tokens_map: *const u8,
+    special_tokens_map_len: u32,
+) -> BytesOrError {
+    BytesOrError::from_result(catch_panic(|| {
+        apply_tokenizer_config_impl(tokenizer_ptr, config, config_len, special_tokens_map, special_tokens_map_len)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{encode_ids, take_bytes, take_error, TestHandle, WORDPIECE};
+
+    // set_config sets the tokenizer config of the handle to `config`.
+    fn set_config(handle: &TestHandle, config: &str) -> Option<String> {
+        take_error(unsafe { set_tokenizer_config(handle.0, config.as_ptr(), config.len() as u32) })
+    }
+
+    #[test]
+    fn chat_templates() {
+        let handle = TestHandle::new(WORDPIECE);
+        let template = |name: Option<&CStr>| {
+            let bytes = take_bytes(unsafe { get_chat_template(handle.0, name.map_or(std::ptr::null(), CStr::as_ptr)) });
+            bytes.map(|bytes| String::from_utf8(bytes).unwrap())
+        };
+        let names = || String::from_utf8(take_bytes(unsafe { get_chat_template_names(handle.0) }).unwrap()).unwrap();
+        assert_eq!(template(None).unwrap_err(), "tokenizer has no tokenizer config");
+        assert_eq!(names(), "[]");
+
+        assert_eq!(set_config(&handle, r#"{"chat_template": "{{ messages }}"}"#), None);
+        assert_eq!(template(None).unwrap(), "{{ messages }}");
+        assert_eq!(names(), r#"["default"]"#);
+
+        let config = r#"{"chat_template": [
+            {"name": "default", "template": "A"}, {"name": "tool_use", "template": "B"}
+        ]}"#;
+        assert_eq!(set_config(&handle, config), None);
+        assert_eq!((template(None).unwrap(), template(Some(c"tool_use")).unwrap()), ("A".into(), "B".into()));
+        assert_eq!(template(Some(c"rag")).unwrap_err(), "tokenizer has no chat template \"rag\"");
+        assert_eq!(names(), r#"["default","tool_use"]"#);
+
+        assert_eq!(
+            set_config(&handle, r#"{"chat_template": 1}"#).as_deref(),
+            Some("invalid tokenizer config: `chat_template` must be a string or a list"));
+        assert_eq!(
+            set_config(&handle, r#"{"chat_template": [{"name": "a"}]}"#).as_deref(),
+            Some("invalid tokenizer config: chat templates must have a `name` and a `template`"));
+        // A null config clears it.
+        assert_eq!(take_error(unsafe { set_tokenizer_config(handle.0, std::ptr::null(), 0) }), None);
+        assert_eq!(names(), "[]");
+    }
+
+    #[test]
+    fn apply_config() {
+        let handle = TestHandle::new(WORDPIECE);
+        let padding = crate::configure::padding_params(0, 1, 0, 0, 0, "[UNK]".to_string());
+        unsafe { (*handle.0).write().unwrap().tokenizer_mut().with_padding(Some(padding)) };
+        let config = r#"{"padding_side": "left", "truncation_side": "left", "model_max_length": 3,
+            "bos_token": "<s>", "eos_token": {"content": "<|im_end|>"}, "pad_token": "im_end"}"#;
+        let special_tokens_map = r#"{"bos_token": "[BOS]"}"#;
+        let apply = |config: &str, special_tokens_map: Option<&str>| unsafe {
+            let (map, map_len) = special_tokens_map.map_or((std::ptr::null(), 0), |map| (map.as_ptr(), map.len()));
+            take_bytes(apply_tokenizer_config(handle.0, config.as_ptr(), config.len() as u32, map, map_len as u32))
+        };
+        let applied: Value = serde_json::from_slice(&apply(config, Some(special_tokens_map)).unwrap()).unwrap();
+        assert_eq!(applied, serde_json::json!({
+            "padding_side": "left", "truncation_max_length": 3, "pad_token": "im_end",
+            // <|im_end|> is already special, and the <s> of the config is replaced by the [BOS] of the map.
+            "special_tokens_added": ["im_end", "[BOS]"],
+        }));
+        let state = unsafe { (*handle.0).read() };
+        let padding = state.tokenizer.get_padding().unwrap();
+        assert!(matches!(padding.direction, PaddingDirection::Left));
+        assert_eq!((padding.pad_id, padding.pad_token.as_str()), (9, "im_end"));
+        assert!(state.tokenizer.get_added_vocabulary().is_special_token("[BOS]"));
+        assert_eq!(state.config().unwrap().special_tokens().len(), 3);
+        drop(state);
+        assert_eq!(encode_ids(&handle, "a b a b hello", 0), [1, 2, 4]);
+
+        // model_max_length doesn't replace the truncation of the tokenizer.
+        let applied = apply(r#"{"model_max_length": 5}"#, None).unwrap();
+        assert_eq!(String::from_utf8(applied).unwrap(), r#"{"special_tokens_added":[]}"#);
+        assert_eq!(
+            apply(r#"{"padding_side": "up"}"#, None).unwrap_err(),
+            "invalid tokenizer config: invalid `padding_side` \"up\"");
+    }
+}
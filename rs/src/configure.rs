@@ -1,19 +1,41 @@
-use std::ffi::CStr;
+use std::error::Error;
+use std::ffi::{c_char, CStr, CString};
+use std::ptr::null_mut;
 use tokenizers::tokenizer::Tokenizer;
-use crate::encode::convert_to_tokenizer_ref;
+use crate::encode::err;
+use crate::handle::{convert_to_handle_ref, ConfigSnapshot, TokenizerHandle};
+use crate::panics::{catch_panic, catch_panic_or, error_c_string};
+use crate::PointerOrError;
 
 
-/// Returns the vocab size.
-#[no_mangle]
-pub unsafe extern "C" fn vocab_size(ptr: *mut libc::c_void) -> u32 {
-    let tokenizer: &Tokenizer;
-    unsafe {
-        tokenizer = ptr
-            .cast::<Tokenizer>()
-            .as_ref()
-            .expect("failed to cast tokenizer");
+// error_to_c converts the result of a getter to the error returned to C: null if ok, or the error message.
+fn error_to_c(r: Result<(), Box<dyn Error>>) -> *mut c_char {
+    match r {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
     }
-    tokenizer.get_vocab_size(true) as u32
+}
+
+fn vocab_size_impl(ptr: *mut TokenizerHandle, size: *mut u32) -> Result<(), Box<dyn Error>> {
+    let size = unsafe { size.as_mut() }.ok_or_else(|| err("vocab_size: size is null"))?;
+    let handle = convert_to_handle_ref(ptr)?;
+    let state = handle.read();
+    let tokenizer: &Tokenizer = &state.tokenizer;
+    *size = tokenizer.get_vocab_size(true) as u32;
+    Ok(())
+}
+
+/// vocab_size reads the vocab size into `size`.
+/// It returns null if ok, or a string with an error message (owned by caller) if something went wrong, e.g. an
+/// invalid tokenizer. The returned string needs to be freed with `free_string`.
+///
+/// # Safety
+///
+/// `size` must point to a u32.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("vocab_size"))]
+pub unsafe extern "C" fn vocab_size(ptr: *mut TokenizerHandle, size: *mut u32) -> *mut c_char {
+    error_to_c(catch_panic(|| vocab_size_impl(ptr, size)))
 }
 
 /// TruncationParameters represents the truncation parameters
@@ -30,77 +52,97 @@ pub struct TruncationParams {
 /// It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
 /// The returned string needs to be freed with `free_string`.
 #[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("set_truncation"))]
 pub unsafe extern "C" fn set_truncation(
-    tokenizer_ptr: *mut libc::c_void,
+    tokenizer_ptr: *mut TokenizerHandle,
     params: *const TruncationParams,
-) -> *mut libc::c_char {
-    let tokenizer: &mut Tokenizer;
-    unsafe {
-        let o = tokenizer_ptr.cast::<Tokenizer>().as_mut();
-        if o.is_none() {
-            return std::ffi::CString::new("failed to cast tokenizer").unwrap().into_raw();
-        } else {
-            tokenizer = o.unwrap();
-        }
-    }
-    unsafe {
-        let result = if params.is_null() {
-                tokenizer.with_truncation(None)
-            } else {
-                tokenizer.with_truncation(
-                    Some(tokenizers::tokenizer::TruncationParams {
-                        max_length: (*params).max_length as usize,
-                        direction: match (*params).direction {
-                            0 => tokenizers::tokenizer::TruncationDirection::Left,
-                            1 => tokenizers::tokenizer::TruncationDirection::Right,
-                            _ => panic!("invalid truncation direction"),
-                        },
-                        stride: (*params).stride as usize,
-                        strategy: match (*params).strategy {
-                            0 => tokenizers::tokenizer::TruncationStrategy::LongestFirst,
-                            1 => tokenizers::tokenizer::TruncationStrategy::OnlyFirst,
-                            2 => tokenizers::tokenizer::TruncationStrategy::OnlySecond,
-                            _ => panic!("invalid truncation strategy"),
-                        },
-                    }))
-            };
-        if result.is_err() {
-            let err = format!("failed tokenizer.with_truncation: {}", result.unwrap_err());
-            return std::ffi::CString::new(err).unwrap().into_raw();
-        }
-    }
+) -> *mut c_char {
+    error_to_c(catch_panic(|| set_truncation_impl(tokenizer_ptr, params)))
+}
 
-    // No errors.
-    std::ptr::null_mut()
+fn set_truncation_impl(tokenizer_ptr: *mut TokenizerHandle, params: *const TruncationParams) -> Result<(), Box<dyn Error>> {
+    let handle = convert_to_handle_ref(tokenizer_ptr).map_err(|_| err("failed to cast tokenizer"))?;
+    let mut state = handle.write()?;
+    let tokenizer = state.tokenizer_mut();
+    let trunc = match unsafe { params.as_ref() } {
+        None => None,
+        Some(params) => Some(
+            truncation_params(params.direction, params.strategy, params.max_length, params.stride)
+                .map_err(|e| err(format!("failed tokenizer.with_truncation: {}", e)))?,
+        ),
+    };
+    tokenizer
+        .with_truncation(trunc)
+        .map_err(|e| err(format!("failed tokenizer.with_truncation: {}", e)))?;
+    Ok(())
 }
 
-/// get_truncation gets the current Tokenizer's truncation parameters.
-///
-/// If there are truncation parameters configured in the Tokenizer, the values are read into the `params` passed,
-/// and it returns true.
-///
-/// If there are no truncation values configured, it returns false.
-#[no_mangle]
-pub unsafe extern "C" fn get_truncation(
-    tokenizer_ptr: *mut libc::c_void, params: *mut TruncationParams) -> bool {
-    let tokenizer: &Tokenizer = convert_to_tokenizer_ref(tokenizer_ptr).expect("Invalid Tokenizer object!?");
-    match tokenizer.get_truncation() {
+// truncation_params converts the values of the binding's TruncationParams to the tokenizers' TruncationParams.
+pub(crate) fn truncation_params(direction: u8, strategy: u8, max_length: u32, stride: u32)
+    -> Result<tokenizers::tokenizer::TruncationParams, Box<dyn Error>> {
+    Ok(tokenizers::tokenizer::TruncationParams {
+        max_length: max_length as usize,
+        direction: match direction {
+            0 => tokenizers::tokenizer::TruncationDirection::Left,
+            1 => tokenizers::tokenizer::TruncationDirection::Right,
+            _ => return Err(err(format!("invalid truncation direction {}", direction))),
+        },
+        stride: stride as usize,
+        strategy: match strategy {
+            0 => tokenizers::tokenizer::TruncationStrategy::LongestFirst,
+            1 => tokenizers::tokenizer::TruncationStrategy::OnlyFirst,
+            2 => tokenizers::tokenizer::TruncationStrategy::OnlySecond,
+            _ => return Err(err(format!("invalid truncation strategy {}", strategy))),
+        },
+    })
+}
+
+fn get_truncation_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    params: *mut TruncationParams,
+    is_set: *mut bool,
+) -> Result<(), Box<dyn Error>> {
+    let params = unsafe { params.as_mut() }.ok_or_else(|| err("get_truncation: params is null"))?;
+    let is_set = unsafe { is_set.as_mut() }.ok_or_else(|| err("get_truncation: is_set is null"))?;
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    let tokenizer: &Tokenizer = &state.tokenizer;
+    *is_set = match tokenizer.get_truncation() {
         Some(p) => {
-            (*params).max_length = p.max_length as u32;
-            (*params).stride = p.stride as u32;
-            (*params).direction = match p.direction {
+            params.max_length = p.max_length as u32;
+            params.stride = p.stride as u32;
+            params.direction = match p.direction {
                 tokenizers::tokenizer::TruncationDirection::Left => 0,
                 tokenizers::tokenizer::TruncationDirection::Right => 1,
             };
-            (*params).strategy = match p.strategy {
+            params.strategy = match p.strategy {
                 tokenizers::tokenizer::TruncationStrategy::LongestFirst => 0,
                 tokenizers::tokenizer::TruncationStrategy::OnlyFirst => 1,
                 tokenizers::tokenizer::TruncationStrategy::OnlySecond => 2,
             };
-            return true;
+            true
         }
-        None => return false,
-    }
+        None => false,
+    };
+    Ok(())
+}
+
+/// get_truncation gets the current Tokenizer's truncation parameters.
+///
+/// If there are truncation parameters configured in the Tokenizer, the values are read into the `params` passed,
+/// and `is_set` is set to true. If there are no truncation values configured, `is_set` is set to false.
+///
+/// It returns null if ok, or a string with an error message (owned by caller) if something went wrong, e.g. an
+/// invalid tokenizer. The returned string needs to be freed with `free_string`.
+///
+/// # Safety
+///
+/// `params` must point to a TruncationParams, and `is_set` to a bool.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("get_truncation"))]
+pub unsafe extern "C" fn get_truncation(
+    tokenizer_ptr: *mut TokenizerHandle, params: *mut TruncationParams, is_set: *mut bool) -> *mut c_char {
+    error_to_c(catch_panic(|| get_truncation_impl(tokenizer_ptr, params, is_set)))
 }
 
 /// PaddingParams represents the padding parameters: it maps to the values in
@@ -112,89 +154,326 @@ pub struct PaddingParams {
     pad_to_multiple_of: u32,  // Disabled if 0.
     pad_id: u32,
     pad_type_id: u32,
-    pad_token: *const libc::c_char,
+    pad_token: *const c_char,
 }
 
 /// set_padding modifies the tokenizer with the given padding parameters.
-/// It doesn't return anything.
+/// It doesn't return anything: it is a no-op on frozen tokenizers (see `freeze`), or if `pad_token` is not
+/// valid UTF-8.
 #[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("set_padding"))]
 pub unsafe extern "C" fn set_padding(
-    tokenizer_ptr: *mut libc::c_void,
+    tokenizer_ptr: *mut TokenizerHandle,
     params: *const PaddingParams) {
-    let tokenizer: &mut Tokenizer;
-    unsafe {
-        let o = tokenizer_ptr.cast::<Tokenizer>().as_mut();
-        if o.is_none() {
-            return
+    catch_panic_or((), || {
+        // Convert char* to string, before locking the tokenizer.
+        let mut pad_token: String = String::new();
+        if !params.is_null() && !(*params).pad_token.is_null() {
+            match unsafe { CStr::from_ptr((*params).pad_token) }.to_str() {
+                Ok(token) => pad_token = token.to_string(),
+                Err(_) => return,
+            }
         }
-        tokenizer = o.unwrap()
-    }
-    if params.is_null() {
-        tokenizer.with_padding(None);
-        return;
-    }
 
-    // Convert char* to string.
-    let mut pad_token: String = String::new();
-    if !(*params).pad_token.is_null() {
-        let pad_token_cstr = unsafe { CStr::from_ptr((*params).pad_token) };
-        pad_token = pad_token_cstr.to_str().unwrap().to_string();
-    }
+        let mut state = match convert_to_handle_ref(tokenizer_ptr).and_then(|handle| handle.write()) {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        let tokenizer = state.tokenizer_mut();
+        if params.is_null() {
+            tokenizer.with_padding(None);
+            return;
+        }
 
-    // Set up padding.
-    _ = tokenizer.with_padding(
-        Some(tokenizers::tokenizer::PaddingParams {
-            strategy: match (*params).strategy {
-                0 => tokenizers::tokenizer::PaddingStrategy::BatchLongest,
-                _ => tokenizers::tokenizer::PaddingStrategy::Fixed((*params).strategy as usize),
-            },
-            direction: match (*params).direction {
-                0 => tokenizers::tokenizer::PaddingDirection::Left,
-                _ => tokenizers::tokenizer::PaddingDirection::Right,
+        // Set up padding.
+        _ = tokenizer.with_padding(Some(padding_params(
+            (*params).strategy, (*params).direction, (*params).pad_to_multiple_of,
+            (*params).pad_id, (*params).pad_type_id, pad_token)));
+    })
+}
+
+// padding_params converts the values of the binding's PaddingParams to the tokenizers' PaddingParams.
+pub(crate) fn padding_params(
+    strategy: u32, direction: u8, pad_to_multiple_of: u32, pad_id: u32, pad_type_id: u32, pad_token: String,
+) -> tokenizers::tokenizer::PaddingParams {
+    tokenizers::tokenizer::PaddingParams {
+        strategy: match strategy {
+            0 => tokenizers::tokenizer::PaddingStrategy::BatchLongest,
+            _ => tokenizers::tokenizer::PaddingStrategy::Fixed(strategy as usize),
+        },
+        direction: match direction {
+            0 => tokenizers::tokenizer::PaddingDirection::Left,
+            _ => tokenizers::tokenizer::PaddingDirection::Right,
+        },
+        pad_to_multiple_of: if pad_to_multiple_of == 0 {
+                None
+            } else {
+                Some(pad_to_multiple_of as usize)
             },
-            pad_to_multiple_of: if (*params).pad_to_multiple_of == 0 {
-                    None
-                } else {
-                    Some((*params).pad_to_multiple_of as usize)
-                },
-            pad_id: (*params).pad_id,
-            pad_type_id: (*params).pad_type_id,
-            pad_token: pad_token,
-        }));
+        pad_id,
+        pad_type_id,
+        pad_token,
+    }
 }
 
 
-/// get_padding gets the current Tokenizer's padding parameters.
-///
-/// If there are padding parameters configured in the Tokenizer, the values are read into the `params` passed,
-/// and it returns true. The `params.pad_token` ownership is transferred to the caller, who must free it
-/// after use (see `free_string()`).
-///
-/// If there are no truncation values configured, it returns false.
-#[no_mangle]
-pub unsafe extern "C" fn get_padding(
-    tokenizer_ptr: *mut libc::c_void, params: *mut PaddingParams) -> bool {
-    let tokenizer: &Tokenizer = convert_to_tokenizer_ref(tokenizer_ptr).expect("Invalid Tokenizer object!?");
-    match tokenizer.get_padding() {
+fn get_padding_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    params: *mut PaddingParams,
+    is_set: *mut bool,
+) -> Result<(), Box<dyn Error>> {
+    let params = unsafe { params.as_mut() }.ok_or_else(|| err("get_padding: params is null"))?;
+    let is_set = unsafe { is_set.as_mut() }.ok_or_else(|| err("get_padding: is_set is null"))?;
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    let tokenizer: &Tokenizer = &state.tokenizer;
+    *is_set = match tokenizer.get_padding() {
         Some(p) => {
-            (*params).pad_id = p.pad_id as u32;
-            (*params).pad_type_id = p.pad_type_id as u32;
-            (*params).pad_to_multiple_of = match p.pad_to_multiple_of {
+            params.pad_id = p.pad_id;
+            params.pad_type_id = p.pad_type_id;
+            params.pad_to_multiple_of = match p.pad_to_multiple_of {
                 Some(v) => v as u32,
                 None => 0,
             };
-            (*params).direction = match p.direction {
+            params.direction = match p.direction {
                 tokenizers::tokenizer::PaddingDirection::Left => 0,
                 tokenizers::tokenizer::PaddingDirection::Right => 1,
             };
-            (*params).strategy = match p.strategy {
+            params.strategy = match p.strategy {
                 tokenizers::tokenizer::PaddingStrategy::BatchLongest => 0,
                 tokenizers::tokenizer::PaddingStrategy::Fixed(value) => value as u32,
             };
-            (*params).pad_token = std::ffi::CString::new(p.pad_token.as_bytes()).unwrap().into_raw();
-            return true;
+            params.pad_token = CString::new(p.pad_token.as_bytes())?.into_raw();
+            true
         }
-        None => return false,
+        None => false,
+    };
+    Ok(())
+}
+
+/// get_padding gets the current Tokenizer's padding parameters.
+///
+/// If there are padding parameters configured in the Tokenizer, the values are read into the `params` passed,
+/// and `is_set` is set to true. The `params.pad_token` ownership is transferred to the caller, who must free it
+/// after use (see `free_string()`). If there are no padding values configured, `is_set` is set to false.
+///
+/// It returns null if ok, or a string with an error message (owned by caller) if something went wrong, e.g. an
+/// invalid tokenizer. The returned string needs to be freed with `free_string`.
+///
+/// # Safety
+///
+/// `params` must point to a PaddingParams, and `is_set` to a bool.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("get_padding"))]
+pub unsafe extern "C" fn get_padding(
+    tokenizer_ptr: *mut TokenizerHandle, params: *mut PaddingParams, is_set: *mut bool) -> *mut c_char {
+    error_to_c(catch_panic(|| get_padding_impl(tokenizer_ptr, params, is_set)))
+}
+
+/// config_snapshot returns a snapshot of the configuration of the tokenizer in the `value` field: padding,
+/// truncation, added tokens and the per-tokenizer options (e.g. deterministic mode, compatibility options,
+/// `encode_special_tokens`). It can be restored with `config_restore`, e.g. to temporarily reconfigure a
+/// tokenizer while handling a request, and reliably roll back, also on error paths.
+///
+/// The caller owns the returned snapshot, and should free it with `free_config_snapshot`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("config_snapshot"))]
+pub unsafe extern "C" fn config_snapshot(ptr: *mut TokenizerHandle) -> PointerOrError {
+    match catch_panic(|| convert_to_handle_ref(ptr)?.read().config_snapshot()) {
+        Ok(snapshot) => PointerOrError { value: Box::into_raw(Box::new(snapshot)).cast(), error: null_mut() },
+        Err(e) => PointerOrError { value: null_mut(), error: error_c_string(e) },
     }
 }
 
+fn config_restore_impl(ptr: *mut TokenizerHandle, snapshot: *const ConfigSnapshot) -> Result<(), Box<dyn Error>> {
+    let snapshot = unsafe { snapshot.as_ref() }.ok_or_else(|| err("config snapshot is null"))?;
+    let handle = convert_to_handle_ref(ptr)?;
+    let mut state = handle.write()?;
+    state.restore_config(snapshot)
+}
+
+/// config_restore restores the configuration of the `snapshot` (see `config_snapshot`) in the tokenizer.
+/// The snapshot is not consumed: it can be restored again, and must still be freed with
+/// `free_config_snapshot`.
+///
+/// It is all or nothing: if it fails, the tokenizer is not modified.
+///
+/// It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+/// The returned string needs to be freed with `free_string`.
+///
+/// # Safety
+///
+/// `snapshot` must have been returned by `config_snapshot`, and not freed.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("config_restore"))]
+pub unsafe extern "C" fn config_restore(ptr: *mut TokenizerHandle, snapshot: *const ConfigSnapshot) -> *mut c_char {
+    error_to_c(catch_panic(|| config_restore_impl(ptr, snapshot)))
+}
+
+/// free_config_snapshot frees a snapshot returned by `config_snapshot`.
+///
+/// # Safety
+///
+/// `snapshot` must have been returned by `config_snapshot`, or be null, and it must not be used after this call.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("free_config_snapshot"))]
+pub unsafe extern "C" fn free_config_snapshot(snapshot: *mut ConfigSnapshot) {
+    catch_panic_or((), || {
+        if !snapshot.is_null() {
+            drop(unsafe { Box::from_raw(snapshot) });
+        }
+    })
+}
+
+// has_component returns `f` applied to the tokenizer, or false if the handle is invalid.
+fn has_component(ptr: *mut TokenizerHandle, f: impl FnOnce(&Tokenizer) -> bool) -> bool {
+    catch_panic_or(false, || match convert_to_handle_ref(ptr) {
+        Ok(handle) => f(&handle.read().tokenizer),
+        Err(_) => false,
+    })
+}
+
+/// has_normalizer returns whether the tokenizer has a normalizer. It returns false for an invalid tokenizer.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("has_normalizer"))]
+pub unsafe extern "C" fn has_normalizer(ptr: *mut TokenizerHandle) -> bool {
+    has_component(ptr, |tokenizer| tokenizer.get_normalizer().is_some())
+}
+
+/// has_pre_tokenizer returns whether the tokenizer has a pre-tokenizer. It returns false for an invalid tokenizer.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("has_pre_tokenizer"))]
+pub unsafe extern "C" fn has_pre_tokenizer(ptr: *mut TokenizerHandle) -> bool {
+    has_component(ptr, |tokenizer| tokenizer.get_pre_tokenizer().is_some())
+}
+
+/// has_post_processor returns whether the tokenizer has a post-processor. It returns false for an invalid
+/// tokenizer.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("has_post_processor"))]
+pub unsafe extern "C" fn has_post_processor(ptr: *mut TokenizerHandle) -> bool {
+    has_component(ptr, |tokenizer| tokenizer.get_post_processor().is_some())
+}
+
+/// has_decoder returns whether the tokenizer has a decoder. It returns false for an invalid tokenizer.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("has_decoder"))]
+pub unsafe extern "C" fn has_decoder(ptr: *mut TokenizerHandle) -> bool {
+    has_component(ptr, |tokenizer| tokenizer.get_decoder().is_some())
+}
+
+/// has_padding returns whether padding is configured in the tokenizer. It returns false for an invalid tokenizer.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("has_padding"))]
+pub unsafe extern "C" fn has_padding(ptr: *mut TokenizerHandle) -> bool {
+    has_component(ptr, |tokenizer| tokenizer.get_padding().is_some())
+}
+
+/// has_truncation returns whether truncation is configured in the tokenizer. It returns false for an invalid
+/// tokenizer.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("has_truncation"))]
+pub unsafe extern "C" fn has_truncation(ptr: *mut TokenizerHandle) -> bool {
+    has_component(ptr, |tokenizer| tokenizer.get_truncation().is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{encode_ids, take_error, TestHandle, BYTE_FALLBACK_BPE, WORDPIECE, WORDPIECE_BERT};
+
+    #[test]
+    fn vocab_size_and_parameters() {
+        let handle = TestHandle::new(WORDPIECE);
+        let mut size = 0;
+        assert_eq!(take_error(unsafe { vocab_size(handle.0, &mut size) }), None);
+        assert_eq!(size, 10);
+        let error = take_error(unsafe { vocab_size(handle.0, null_mut()) });
+        assert_eq!(error.as_deref(), Some("vocab_size: size is null"));
+        let error = take_error(unsafe { vocab_size(null_mut(), &mut size) });
+        assert_eq!(error.as_deref(), Some("tokenizer passed is null"));
+
+        let mut truncation = TruncationParams { direction: 0, strategy: 0, max_length: 0, stride: 0 };
+        let mut is_set = true;
+        assert_eq!(take_error(unsafe { get_truncation(handle.0, &mut truncation, &mut is_set) }), None);
+        assert!(!is_set);
+        let params = TruncationParams { direction: 1, strategy: 2, max_length: 7, stride: 1 };
+        assert_eq!(take_error(unsafe { set_truncation(handle.0, &params) }), None);
+        assert_eq!(take_error(unsafe { get_truncation(handle.0, &mut truncation, &mut is_set) }), None);
+        assert!(is_set);
+        let values = (truncation.direction, truncation.strategy, truncation.max_length, truncation.stride);
+        assert_eq!(values, (1, 2, 7, 1));
+        let error = take_error(unsafe { get_truncation(handle.0, null_mut(), &mut is_set) });
+        assert_eq!(error.as_deref(), Some("get_truncation: params is null"));
+        let error = take_error(unsafe { get_truncation(handle.0, &mut truncation, null_mut()) });
+        assert_eq!(error.as_deref(), Some("get_truncation: is_set is null"));
+
+        let pad_token = c"[UNK]";
+        let params = PaddingParams {
+            strategy: 8, direction: 1, pad_to_multiple_of: 4, pad_id: 0, pad_type_id: 3, pad_token: pad_token.as_ptr(),
+        };
+        let mut padding = PaddingParams {
+            strategy: 0, direction: 0, pad_to_multiple_of: 0, pad_id: 1, pad_type_id: 0, pad_token: std::ptr::null(),
+        };
+        assert_eq!(take_error(unsafe { get_padding(handle.0, &mut padding, &mut is_set) }), None);
+        assert!(!is_set);
+        // A pad token that is not valid UTF-8 leaves the padding unset.
+        let invalid = PaddingParams { pad_token: c"\xff".as_ptr(), ..params };
+        unsafe { set_padding(handle.0, &invalid) };
+        assert!(!unsafe { has_padding(handle.0) });
+        unsafe { set_padding(handle.0, &params) };
+        assert_eq!(take_error(unsafe { get_padding(handle.0, &mut padding, &mut is_set) }), None);
+        assert!(is_set);
+        let values = (padding.strategy, padding.direction, padding.pad_to_multiple_of, padding.pad_id);
+        assert_eq!(values, (8, 1, 4, 0));
+        assert_eq!(padding.pad_type_id, 3);
+        assert_eq!(take_error(padding.pad_token.cast_mut()).as_deref(), Some("[UNK]"));
+        let error = take_error(unsafe { get_padding(null_mut(), &mut padding, &mut is_set) });
+        assert_eq!(error.as_deref(), Some("tokenizer passed is null"));
+        let error = take_error(unsafe { get_padding(handle.0, null_mut(), &mut is_set) });
+        assert_eq!(error.as_deref(), Some("get_padding: params is null"));
+    }
+
+    #[test]
+    fn has_components() {
+        let queries = [has_normalizer, has_pre_tokenizer, has_post_processor, has_decoder, has_padding, has_truncation];
+        let components = |ptr| queries.map(|has| unsafe { has(ptr) });
+        let handle = TestHandle::new(WORDPIECE_BERT);
+        assert_eq!(components(handle.0), [false, true, true, true, false, false]);
+        let params = TruncationParams { direction: 1, strategy: 0, max_length: 8, stride: 0 };
+        assert_eq!(take_error(unsafe { set_truncation(handle.0, &params) }), None);
+        assert!(unsafe { has_truncation(handle.0) });
+        assert_eq!(components(TestHandle::new(BYTE_FALLBACK_BPE).0), [true, false, false, true, false, false]);
+        assert_eq!(components(null_mut()), [false; 6]);
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        let handle = TestHandle::new(WORDPIECE);
+        let result = unsafe { config_snapshot(handle.0) };
+        assert_eq!(take_error(result.error), None);
+        let snapshot: *mut ConfigSnapshot = result.value.cast();
+
+        let params = TruncationParams { direction: 1, strategy: 0, max_length: 1, stride: 0 };
+        assert_eq!(take_error(unsafe { set_truncation(handle.0, &params) }), None);
+        assert_eq!(take_error(unsafe { crate::handle::set_encode_special_tokens(handle.0, true) }), None);
+        let added = tokenizers::AddedToken::from("ab", false);
+        unsafe { (*handle.0).write().unwrap().tokenizer_mut().add_tokens(&[added]) };
+        assert_eq!(encode_ids(&handle, "ab <|im_end|>", 0), [10]);
+
+        // It can be restored more than once.
+        for _ in 0..2 {
+            assert_eq!(take_error(unsafe { config_restore(handle.0, snapshot) }), None);
+            assert!(!unsafe { has_truncation(handle.0) });
+            assert_eq!(encode_ids(&handle, "ab <|im_end|>", 0), [1, 3, 5]);
+            assert_eq!(take_error(unsafe { crate::handle::set_encode_special_tokens(handle.0, true) }), None);
+        }
+        unsafe { free_config_snapshot(snapshot) };
+
+        let error = take_error(unsafe { config_restore(handle.0, std::ptr::null()) });
+        assert_eq!(error.as_deref(), Some("config snapshot is null"));
+        let result = unsafe { config_snapshot(null_mut()) };
+        assert!(result.value.is_null());
+        assert_eq!(take_error(result.error).as_deref(), Some("tokenizer passed is null"));
+    }
+}
@@ -0,0 +1,109 @@
+//! Conformance test vectors: the encodings of a list of probe strings, in a canonical JSON, to verify that
+//! different bindings (Go, Python) and releases of the library agree bit-for-bit. They are generated with
+//! `conformance_vectors`, or from the command line with the `conformance` binary (see `src/bin/conformance.rs`).
+
+use std::error::Error;
+use serde::Serialize;
+use tokenizers::tokenizer::Tokenizer;
+use crate::encode::{err, messages_from_bytes};
+use crate::handle::{convert_to_handle_ref, TokenizerHandle};
+use crate::panics::catch_panic;
+use crate::BytesOrError;
+
+// CONFORMANCE_VERSION is the version of the format of the conformance vectors.
+const CONFORMANCE_VERSION: u32 = 1;
+
+// The fields are serialized in the order they are declared, so the JSON is canonical.
+#[derive(Serialize)]
+struct Vectors<'a> {
+    version: u32,
+    add_special_tokens: bool,
+    vectors: Vec<Vector<'a>>,
+}
+
+#[derive(Serialize)]
+struct Vector<'a> {
+    text: &'a str,
+    ids: &'a [u32],
+    tokens: &'a [String],
+    offsets: &'a [(usize, usize)],
+}
+
+/// Returns the conformance vectors of the `probes` encoded by the `tokenizer`, in the canonical JSON described
+/// in `conformance_vectors`.
+pub fn conformance_json(tokenizer: &Tokenizer, probes: &[&str], add_special_tokens: bool) -> Result<String, Box<dyn Error>> {
+    let encodings = probes
+        .iter()
+        .map(|&probe| tokenizer.encode(probe, add_special_tokens))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| err(format!("encoding failed: {}", e)))?;
+    let vectors = Vectors {
+        version: CONFORMANCE_VERSION,
+        add_special_tokens,
+        vectors: probes
+            .iter()
+            .zip(&encodings)
+            .map(|(text, encoding)| Vector {
+                text,
+                ids: encoding.get_ids(),
+                tokens: encoding.get_tokens(),
+                offsets: encoding.get_offsets(),
+            })
+            .collect(),
+    };
+    Ok(serde_json::to_string(&vectors)?)
+}
+
+/// conformance_vectors returns the conformance vectors of the `num_probes` UTF-8 strings `probes` (given as in
+/// `encode_batch_bytes`) encoded by the tokenizer, to be compared with the ones of other bindings or releases:
+/// the bytes of a canonical (compact, with the keys in a fixed order) JSON object with the `version` of the
+/// format, `add_special_tokens`, and the `vectors`, with the `text`, `ids`, `tokens` and byte `offsets` (pairs
+/// of start and end) of each probe. The settings of the tokenizer (e.g. truncation and padding) are applied.
+///
+/// The result must be freed with `free_bytes`.
+///
+/// # Safety
+///
+/// `probes` and `lengths` must point to `num_probes` strings and lengths.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("conformance_vectors"))]
+pub unsafe extern "C" fn conformance_vectors(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_probes: u32,
+    probes: *const *const u8,
+    lengths: *const u32,
+    add_special_tokens: bool,
+) -> BytesOrError {
+    BytesOrError::from_result(catch_panic(|| {
+        let probes = messages_from_bytes(num_probes as usize, probes, lengths)?;
+        let handle = convert_to_handle_ref(tokenizer_ptr)?;
+        Ok(conformance_json(&handle.read().tokenizer, &probes, add_special_tokens)?.into_bytes())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{take_bytes, TestHandle, WORDPIECE_BERT};
+
+    #[test]
+    fn vectors() {
+        let handle = TestHandle::new(WORDPIECE_BERT);
+        let probes = ["hello world", "ab"];
+        let lengths = probes.map(|probe| probe.len() as u32);
+        let pointers = probes.map(str::as_ptr);
+        let vectors = unsafe { conformance_vectors(handle.0, 2, pointers.as_ptr(), lengths.as_ptr(), true) };
+        let json = String::from_utf8(take_bytes(vectors).unwrap()).unwrap();
+        assert_eq!(json, concat!(
+            r#"{"version":1,"add_special_tokens":true,"vectors":["#,
+            r#"{"text":"hello world","ids":[10,4,6,11],"tokens":["[CLS]","hello","world","[SEP]"],"#,
+            r#""offsets":[[0,0],[0,5],[6,11],[0,0]]},"#,
+            r###"{"text":"ab","ids":[10,1,3,11],"tokens":["[CLS]","a","##b","[SEP]"],"###,
+            r#""offsets":[[0,0],[0,1],[1,2],[0,0]]}"#,
+            r#"]}"#,
+        ));
+
+        let json = conformance_json(&unsafe { (*handle.0).read() }.tokenizer, &[], false).unwrap();
+        assert_eq!(json, r#"{"version":1,"add_special_tokens":false,"vectors":[]}"#);
+    }
+}
@@ -0,0 +1,366 @@
+//! Corpus streaming: a text file (optionally gzip-compressed) is read, split into records and encoded inside
+//! Rust, and the encodings are handed to the host in bounded chunks, so arbitrarily large corpora can be
+//! tokenized without building the input arrays on the host side.
+
+use std::error::Error;
+use std::ffi::{c_char, CStr};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::ptr::null_mut;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use flate2::read::MultiGzDecoder;
+use crate::encode::{
+    encode_batch_str_impl, err, free_encode_results_v2, result_to_encode_results_v2, EncodeParams,
+    EncodeResultsV2,
+};
+use crate::handle::TokenizerHandle;
+use crate::panics::{catch_panic, catch_panic_or, error_c_string};
+use crate::PointerOrError;
+
+// Magic bytes at the start of gzip files.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// CorpusOptions configures how `corpus_open` splits and chunks the corpus.
+#[repr(C)]
+pub struct CorpusOptions {
+    /// Byte separating the records of the corpus, usually `'\n'`. If it is `'\n'`, a trailing `'\r'` is also
+    /// removed from the records. The delimiter itself is never part of the records.
+    pub delimiter: u8,
+
+    /// If set, empty records are skipped.
+    pub skip_empty: bool,
+
+    /// Maximum number of records per chunk, must be positive.
+    pub max_chunk_records: u32,
+
+    /// If positive, a chunk is closed once it holds at least this many bytes of text, even if it has fewer
+    /// than `max_chunk_records` records.
+    pub max_chunk_bytes: u64,
+
+    /// Number of chunks encoded ahead of `corpus_next`: the reader blocks once they are all waiting to be taken,
+    /// so memory use is bounded. Zero means the reader only reads the next chunk when it is asked for.
+    pub prefetch: u32,
+}
+
+// empty_results returns the results of an empty chunk.
+fn empty_results() -> EncodeResultsV2 {
+    EncodeResultsV2 { len: 0, total_tokens: 0, encoded: null_mut(), error: null_mut() }
+}
+
+// Chunk is a chunk of encoded records, sent from the reader thread. The results are freed if the chunk is
+// dropped before being taken by `corpus_next`.
+struct Chunk(EncodeResultsV2);
+
+unsafe impl Send for Chunk {}
+
+impl Chunk {
+    // take transfers the ownership of the results to the caller.
+    fn take(mut self) -> EncodeResultsV2 {
+        std::mem::replace(&mut self.0, empty_results())
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        unsafe { free_encode_results_v2(std::mem::replace(&mut self.0, empty_results())) };
+    }
+}
+
+/// CorpusReader streams the encodings of the records of a corpus (see `corpus_open`). It is opaque to the host.
+pub struct CorpusReader {
+    // Chunks are received from the reader thread, until it reaches the end of the corpus (or an error).
+    chunks: Option<Receiver<Chunk>>,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl Drop for CorpusReader {
+    fn drop(&mut self) {
+        // Dropping the receiver (and the chunks waiting in it) stops the reader thread at its next chunk.
+        self.chunks = None;
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+}
+
+// open_corpus opens the file at `path`, decompressing it if it starts with the gzip magic bytes.
+fn open_corpus(path: &str) -> Result<Box<dyn BufRead + Send>, Box<dyn Error>> {
+    let file = File::open(path).map_err(|e| err(format!("failed to open corpus {:?}: {}", path, e)))?;
+    let mut file = BufReader::new(file);
+    let is_gzip = file.fill_buf()?.starts_with(&GZIP_MAGIC);
+    if is_gzip {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(file))))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+// RecordSplitter reads the records of a corpus, and groups them in chunks.
+struct RecordSplitter {
+    input: Box<dyn BufRead + Send>,
+    delimiter: u8,
+    skip_empty: bool,
+    max_chunk_records: usize,
+    max_chunk_bytes: usize,
+
+    // Index of the next record (counting the skipped ones), used in error messages.
+    record: u64,
+}
+
+impl RecordSplitter {
+    // next_record returns the next record, or None at the end of the corpus.
+    fn next_record(&mut self) -> Result<Option<String>, Box<dyn Error>> {
+        let mut bytes = Vec::new();
+        loop {
+            bytes.clear();
+            if self.input.read_until(self.delimiter, &mut bytes)? == 0 {
+                return Ok(None);
+            }
+            let index = self.record;
+            self.record += 1;
+            if bytes.last() == Some(&self.delimiter) {
+                bytes.pop();
+            }
+            if self.delimiter == b'\n' && bytes.last() == Some(&b'\r') {
+                bytes.pop();
+            }
+            if self.skip_empty && bytes.is_empty() {
+                continue;
+            }
+            let record = String::from_utf8(bytes)
+                .map_err(|e| err(format!("record #{} of the corpus is not valid UTF-8: {}", index, e)))?;
+            return Ok(Some(record));
+        }
+    }
+
+    // next_chunk returns the records of the next chunk, empty at the end of the corpus.
+    fn next_chunk(&mut self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut records = Vec::new();
+        let mut num_bytes = 0;
+        while records.len() < self.max_chunk_records && (self.max_chunk_bytes == 0 || num_bytes < self.max_chunk_bytes) {
+            match self.next_record()? {
+                Some(record) => {
+                    num_bytes += record.len();
+                    records.push(record);
+                }
+                None => break,
+            }
+        }
+        Ok(records)
+    }
+}
+
+// read_corpus is the loop of the reader thread: it encodes the chunks of records and sends them, until the
+// end of the corpus, an error or a panic (sent as the last chunk), or the CorpusReader is dropped.
+fn read_corpus(
+    mut splitter: RecordSplitter,
+    tokenizer: Arc<TokenizerHandle>,
+    options: EncodeParams,
+    chunks: SyncSender<Chunk>,
+) {
+    let tokenizer_ptr = Arc::as_ptr(&tokenizer).cast_mut();
+    loop {
+        // A panic is sent as the error of the chunk, which ends the reading, as any other error.
+        let result = catch_panic(|| {
+            let records = splitter.next_chunk()?;
+            let inputs: Vec<&str> = records.iter().map(String::as_str).collect();
+            encode_batch_str_impl(tokenizer_ptr, &inputs, options)
+        });
+        let done = !matches!(&result, Ok(buffers) if !buffers.is_empty());
+        let results = match result {
+            Ok(buffers) if buffers.is_empty() => empty_results(),
+            result => result_to_encode_results_v2(result),
+        };
+        if chunks.send(Chunk(results)).is_err() || done {
+            return;
+        }
+    }
+}
+
+fn corpus_open_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    path: *const c_char,
+    corpus_options: &CorpusOptions,
+    options: EncodeParams,
+) -> Result<*mut CorpusReader, Box<dyn Error>> {
+    if tokenizer_ptr.is_null() {
+        return Err(err("tokenizer passed is null"));
+    }
+    if path.is_null() {
+        return Err(err("corpus path is null"));
+    }
+    if corpus_options.max_chunk_records == 0 {
+        return Err(err("max_chunk_records must be positive"));
+    }
+    options.validate()?;
+    let path = unsafe { CStr::from_ptr(path) }.to_str()?;
+    let splitter = RecordSplitter {
+        input: open_corpus(path)?,
+        delimiter: corpus_options.delimiter,
+        skip_empty: corpus_options.skip_empty,
+        max_chunk_records: corpus_options.max_chunk_records as usize,
+        max_chunk_bytes: usize::try_from(corpus_options.max_chunk_bytes).unwrap_or(usize::MAX),
+        record: 0,
+    };
+    let tokenizer = unsafe {
+        Arc::increment_strong_count(tokenizer_ptr.cast_const());
+        Arc::from_raw(tokenizer_ptr.cast_const())
+    };
+    let (sender, receiver) = sync_channel(corpus_options.prefetch as usize);
+    let reader = std::thread::Builder::new()
+        .name("tokenizers-corpus".to_string())
+        .spawn(move || read_corpus(splitter, tokenizer, options, sender))
+        .map_err(|e| err(format!("failed to start corpus reader: {}", e)))?;
+    Ok(Box::into_raw(Box::new(CorpusReader { chunks: Some(receiver), reader: Some(reader) })))
+}
+
+/// corpus_open opens the text file at the null-terminated `path` -- decompressing it if it is gzip-compressed --
+/// and starts encoding its records (split as configured by `corpus_options`) with the tokenizer and the given
+/// EncodeParams, in a background thread. It returns the `CorpusReader *` in the `value` field, or an error.
+///
+/// Take the encoded chunks with `corpus_next`. The reader holds a reference to the tokenizer (see
+/// `tokenizer_retain`), so it can be freed independently. Free the reader with `corpus_free`.
+///
+/// # Safety
+///
+/// `path` must be a null-terminated string, and `corpus_options` must point to a CorpusOptions.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("corpus_open"))]
+pub unsafe extern "C" fn corpus_open(
+    tokenizer_ptr: *mut TokenizerHandle,
+    path: *const c_char,
+    corpus_options: *const CorpusOptions,
+    options: EncodeParams,
+) -> PointerOrError {
+    let result = catch_panic(|| {
+        let corpus_options = unsafe { corpus_options.as_ref() }.ok_or_else(|| err("corpus options are null"))?;
+        corpus_open_impl(tokenizer_ptr, path, corpus_options, options)
+    });
+    match result {
+        Ok(reader) => PointerOrError { value: reader.cast(), error: null_mut() },
+        Err(e) => PointerOrError { value: null_mut(), error: error_c_string(e) },
+    }
+}
+
+/// corpus_next returns the encodings of the next chunk of records of the corpus, one Buffer per record, in the
+/// order of the corpus. It blocks until the chunk is encoded. Ownership of the results is transferred to the
+/// caller, who must free them with `free_encode_results_v2`.
+///
+/// At the end of the corpus it returns empty results (`len == 0` and no error). After an error (e.g. a
+/// record that is not valid UTF-8), it also returns empty results.
+///
+/// # Safety
+///
+/// `reader` must have been returned by `corpus_open`, and not yet freed.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("corpus_next"))]
+pub unsafe extern "C" fn corpus_next(reader: *mut CorpusReader) -> EncodeResultsV2 {
+    let reader = match unsafe { reader.as_mut() } {
+        Some(reader) => reader,
+        None => return result_to_encode_results_v2(Err(err("corpus reader is null"))),
+    };
+    let chunk = catch_panic(|| Ok(reader.chunks.as_ref().and_then(|chunks| chunks.recv().ok())));
+    match chunk {
+        Ok(Some(chunk)) => chunk.take(),
+        Ok(None) => {
+            // The reader thread is done: no more chunks.
+            reader.chunks = None;
+            empty_results()
+        }
+        Err(e) => result_to_encode_results_v2(Err(e)),
+    }
+}
+
+/// corpus_free stops reading the corpus, and frees the reader, along with the chunks encoded ahead.
+///
+/// # Safety
+///
+/// `reader` must have been returned by `corpus_open`, and it must not be used after this call.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("corpus_free"))]
+pub unsafe extern "C" fn corpus_free(reader: *mut CorpusReader) {
+    catch_panic_or((), || {
+        if !reader.is_null() {
+            drop(unsafe { Box::from_raw(reader) });
+        }
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::io::Write;
+    use flate2::write::GzEncoder;
+    use crate::encode::{Buffer, ENCODE_PARAMS_VERSION};
+    use crate::testing::{take_error, temp_dir, TestHandle, WORDPIECE};
+
+    // read_chunks reads the corpus at `path`, returning the ids of the records of each chunk, and the error if
+    // the reading stopped with one.
+    fn read_chunks(
+        handle: &TestHandle,
+        path: &std::path::Path,
+        corpus_options: &CorpusOptions,
+    ) -> (Vec<Vec<Vec<u32>>>, Option<String>) {
+        let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags: 0 };
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+        let opened = unsafe { corpus_open(handle.0, path.as_ptr(), corpus_options, options) };
+        assert!(opened.error.is_null());
+        let reader = opened.value.cast::<CorpusReader>();
+        let mut chunks = Vec::new();
+        let error = loop {
+            let results = unsafe { corpus_next(reader) };
+            if !results.error.is_null() {
+                break take_error(results.error);
+            }
+            if results.len == 0 {
+                break None;
+            }
+            let buffers = unsafe { std::slice::from_raw_parts(results.encoded, results.len as usize) };
+            let ids = |b: &Buffer| unsafe { std::slice::from_raw_parts(b.ids, b.len as usize) }.to_vec();
+            chunks.push(buffers.iter().map(ids).collect());
+            unsafe { free_encode_results_v2(results) };
+        };
+        unsafe { corpus_free(reader) };
+        (chunks, error)
+    }
+
+    #[test]
+    fn corpus_streaming() {
+        let handle = TestHandle::new(WORDPIECE);
+        let dir = temp_dir();
+        let text = b"hello\r\n\nworld a\nab\nhello hello\n";
+        std::fs::write(dir.join("corpus.txt"), text).unwrap();
+        let mut gzip = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(text).unwrap();
+        std::fs::write(dir.join("corpus.txt.gz"), gzip.finish().unwrap()).unwrap();
+
+        let mut corpus_options =
+            CorpusOptions { delimiter: b'\n', skip_empty: true, max_chunk_records: 2, max_chunk_bytes: 0, prefetch: 1 };
+        for name in ["corpus.txt", "corpus.txt.gz"] {
+            let (chunks, error) = read_chunks(&handle, &dir.join(name), &corpus_options);
+            assert_eq!(error, None);
+            assert_eq!(chunks, [vec![vec![4], vec![6, 1]], vec![vec![1, 3], vec![4, 4]]]);
+        }
+
+        // Chunks are closed once they hold max_chunk_bytes, and empty records are kept without skip_empty.
+        corpus_options.skip_empty = false;
+        corpus_options.max_chunk_records = 10;
+        corpus_options.max_chunk_bytes = 5;
+        let (chunks, _) = read_chunks(&handle, &dir.join("corpus.txt"), &corpus_options);
+        assert_eq!(chunks, [vec![vec![4]], vec![vec![], vec![6, 1]], vec![vec![1, 3], vec![4, 4]]]);
+
+        std::fs::write(dir.join("invalid.txt"), b"hello\n\xff\n").unwrap();
+        let (chunks, error) = read_chunks(&handle, &dir.join("invalid.txt"), &corpus_options);
+        assert_eq!(chunks, [vec![vec![4]]]);
+        assert!(error.unwrap().starts_with("record #1 of the corpus is not valid UTF-8"));
+
+        let path = CString::new(dir.join("missing.txt").to_str().unwrap()).unwrap();
+        let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags: 0 };
+        let opened = unsafe { corpus_open(handle.0, path.as_ptr(), &corpus_options, options) };
+        assert!(take_error(opened.error).unwrap().starts_with("failed to open corpus"));
+    }
+}
@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::{c_char, CStr};
+use serde::{Deserialize, Serialize};
+use tokenizers::models::ModelWrapper;
+use tokenizers::tokenizer::{
+    NormalizedString, Normalizer, OffsetReferential, OffsetType, PreTokenizedString, PreTokenizer, Tokenizer,
+};
+use crate::encode::err;
+use crate::handle::{convert_to_handle_ref, TokenizerHandle};
+use crate::panics::catch_panic;
+use crate::BytesOrError;
+
+/// TokenScores holds the model score of each token id, used for the per-token debug information (see
+/// `ENCODE_RETURN_DEBUG_INFO`):
+///
+/// - BPE: the rank of the merge that produces the token (lower merges first); tokens not produced by a
+///   merge (the initial alphabet) have no score.
+/// - Unigram: the log-probability of the piece.
+/// - Other models: no scores.
+///
+/// It is built on first use, and cached in the TokenizerState, see `TokenizerState::token_scores`.
+pub struct TokenScores {
+    scores: HashMap<u32, f64>,
+}
+
+// ModelScores is the part of the serialized model with the information needed for the scores: the models
+// don't give access to their merges or scores otherwise.
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+enum ModelScores {
+    #[serde(rename = "BPE")]
+    Bpe {
+        vocab: HashMap<String, u32>,
+        merges: Vec<Merge>,
+        continuing_subword_prefix: Option<String>,
+    },
+    Unigram {
+        vocab: Vec<(String, f64)>,
+    },
+    #[serde(other)]
+    Other,
+}
+
+// Merge is serialized either as a "left right" string or as a pair, depending on the version.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Merge {
+    Joined(String),
+    Pair(String, String),
+}
+
+impl TokenScores {
+    pub fn new(tokenizer: &Tokenizer) -> Self {
+        let model_scores = serde_json::to_value(tokenizer.get_model())
+            .and_then(serde_json::from_value::<ModelScores>)
+            .unwrap_or(ModelScores::Other);
+        let mut scores = HashMap::new();
+        match model_scores {
+            ModelScores::Bpe { vocab, merges, continuing_subword_prefix } => {
+                for (rank, merge) in merges.into_iter().enumerate() {
+                    let (left, right) = match merge {
+                        Merge::Joined(joined) => match joined.split_once(' ') {
+                            Some((left, right)) => (left.to_string(), right.to_string()),
+                            None => continue,
+                        },
+                        Merge::Pair(left, right) => (left, right),
+                    };
+                    let right = match &continuing_subword_prefix {
+                        Some(prefix) => right.strip_prefix(prefix.as_str()).unwrap_or(&right),
+                        None => &right,
+                    };
+                    if let Some(&id) = vocab.get(&format!("{}{}", left, right)) {
+                        scores.entry(id).or_insert(rank as f64);
+                    }
+                }
+            }
+            ModelScores::Unigram { vocab } => {
+                scores.extend(vocab.into_iter().enumerate().map(|(id, (_, score))| (id as u32, score)));
+            }
+            ModelScores::Other => {}
+        }
+        TokenScores { scores }
+    }
+
+    /// Returns the score of the token `id`, or NaN if it has none.
+    pub fn get(&self, id: u32) -> f64 {
+        self.scores.get(&id).copied().unwrap_or(f64::NAN)
+    }
+}
+
+// UnigramPieces is the part of the serialized Unigram model needed to build its lattices.
+#[derive(Deserialize)]
+struct UnigramPieces {
+    unk_id: Option<u32>,
+    vocab: Vec<(String, f64)>,
+}
+
+// Penalty of the unknown token over the minimum score of the pieces: the same used by the Unigram model.
+const UNK_PENALTY: f64 = 10.0;
+
+// LatticeNode is one piece matching a span of a pre-token, serialized by `unigram_lattice`.
+#[derive(Serialize)]
+struct LatticeNode<'a> {
+    id: u32,
+    piece: &'a str,
+    start: usize,
+    end: usize,
+    score: f64,
+}
+
+// PreTokenLattice is the lattice of one pre-token, serialized by `unigram_lattice`.
+#[derive(Serialize)]
+struct PreTokenLattice<'a> {
+    text: String,
+    offsets: (usize, usize),
+    nodes: Vec<LatticeNode<'a>>,
+    arcs: Vec<(usize, usize)>,
+    best: Vec<usize>,
+}
+
+// build_lattice returns the lattice of the `text` of one pre-token with the Unigram pieces.
+fn build_lattice<'a>(
+    text: &str,
+    offsets: (usize, usize),
+    pieces: &'a UnigramPieces,
+    piece_ids: &HashMap<&str, u32>,
+    max_piece_len: usize,
+) -> PreTokenLattice<'a> {
+    let min_score = pieces.vocab.iter().map(|(_, score)| *score).fold(f64::INFINITY, f64::min);
+    let mut nodes = Vec::new();
+    for (start, c) in text.char_indices() {
+        let mut has_single_char = false;
+        let ends = text[start..]
+            .char_indices()
+            .skip(1)
+            .map(|(i, _)| start + i)
+            .chain(std::iter::once(text.len()))
+            .take_while(|&end| end - start <= max_piece_len);
+        for end in ends {
+            if let Some(&id) = piece_ids.get(&text[start..end]) {
+                let (piece, score) = &pieces.vocab[id as usize];
+                nodes.push(LatticeNode { id, piece, start, end, score: *score });
+                has_single_char |= end - start == c.len_utf8();
+            }
+        }
+        if let (false, Some(unk_id)) = (has_single_char, pieces.unk_id) {
+            if let Some((piece, _)) = pieces.vocab.get(unk_id as usize) {
+                nodes.push(LatticeNode {
+                    id: unk_id,
+                    piece,
+                    start,
+                    end: start + c.len_utf8(),
+                    score: min_score - UNK_PENALTY,
+                });
+            }
+        }
+    }
+
+    // Arcs connect nodes ending where the next one starts.
+    let mut arcs = Vec::new();
+    for (from, from_node) in nodes.iter().enumerate() {
+        for (to, to_node) in nodes.iter().enumerate() {
+            if from_node.end == to_node.start {
+                arcs.push((from, to));
+            }
+        }
+    }
+
+    // Viterbi: best[pos] is the best (score, last node) of a segmentation of text[..pos].
+    let mut best: Vec<Option<(f64, usize)>> = vec![None; text.len() + 1];
+    let mut by_start: Vec<usize> = (0..nodes.len()).collect();
+    by_start.sort_by_key(|&i| nodes[i].start);
+    for &i in &by_start {
+        let node = &nodes[i];
+        let prev_score = if node.start == 0 {
+            0.0
+        } else {
+            match best[node.start] {
+                Some((score, _)) => score,
+                None => continue,
+            }
+        };
+        let score = prev_score + node.score;
+        if best[node.end].is_none_or(|(best_score, _)| score > best_score) {
+            best[node.end] = Some((score, i));
+        }
+    }
+    let mut path = Vec::new();
+    let mut pos = text.len();
+    while pos > 0 {
+        match best[pos] {
+            Some((_, i)) => {
+                path.push(i);
+                pos = nodes[i].start;
+            }
+            None => {
+                path.clear();
+                break;
+            }
+        }
+    }
+    path.reverse();
+    PreTokenLattice { text: text.to_string(), offsets, nodes, arcs, best: path }
+}
+
+fn unigram_lattice_impl(tokenizer_ptr: *mut TokenizerHandle, text: *const c_char) -> Result<Vec<u8>, Box<dyn Error>> {
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let text = unsafe { CStr::from_ptr(text) }.to_str()?;
+    let state = handle.read();
+    let tokenizer = &state.tokenizer;
+    if !matches!(tokenizer.get_model(), ModelWrapper::Unigram(_)) {
+        return Err(err("unigram_lattice requires a Unigram model"));
+    }
+    let pieces: UnigramPieces = serde_json::from_value(serde_json::to_value(tokenizer.get_model())?)?;
+    let piece_ids: HashMap<&str, u32> = pieces
+        .vocab
+        .iter()
+        .enumerate()
+        .map(|(id, (piece, _))| (piece.as_str(), id as u32))
+        .collect();
+    let max_piece_len = pieces.vocab.iter().map(|(piece, _)| piece.len()).max().unwrap_or(0);
+
+    // Normalize and pre-tokenize: the lattices are built for each pre-token.
+    let mut normalized = NormalizedString::from(text);
+    if let Some(normalizer) = tokenizer.get_normalizer() {
+        normalizer
+            .normalize(&mut normalized)
+            .map_err(|e| err(format!("normalization failed: {}", e)))?;
+    }
+    let mut pre_tokenized = PreTokenizedString::from(normalized);
+    if let Some(pre_tokenizer) = tokenizer.get_pre_tokenizer() {
+        pre_tokenizer
+            .pre_tokenize(&mut pre_tokenized)
+            .map_err(|e| err(format!("pre-tokenization failed: {}", e)))?;
+    }
+    let lattices: Vec<PreTokenLattice> = pre_tokenized
+        .get_splits(OffsetReferential::Original, OffsetType::Byte)
+        .into_iter()
+        .map(|(split, offsets, _)| build_lattice(split, offsets, &pieces, &piece_ids, max_piece_len))
+        .collect();
+    Ok(serde_json::to_vec(&serde_json::json!({ "pre_tokens": lattices }))?)
+}
+
+/// unigram_lattice returns the segmentation lattices of the `text` for a tokenizer with a Unigram model, as
+/// JSON in a BytesOrError, that must be freed with `free_bytes`.
+///
+/// The text is normalized and pre-tokenized (added tokens are not handled), and one lattice is built for each
+/// pre-token. The JSON is an object with the key `pre_tokens`, an array with one object per pre-token, with:
+///
+/// - `text`: the normalized pre-token.
+/// - `offsets`: `[start, end]` byte offsets of the pre-token in the original `text`.
+/// - `nodes`: array of the pieces matching a span of the pre-token, as objects with the keys `id`, `piece`,
+///   `start` and `end` (byte offsets in the pre-token `text`), and `score` (the log-probability of the piece,
+///   or the penalized score of the unknown token).
+/// - `arcs`: array of `[from, to]` indices of nodes, for each node `from` that ends where node `to` starts.
+/// - `best`: indices of the nodes of the best (Viterbi) segmentation.
+///
+/// This is meant for research and debugging: the lattices are built without caching, on each call.
+///
+/// # Safety
+///
+/// `text` must be a valid C string.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("unigram_lattice"))]
+pub unsafe extern "C" fn unigram_lattice(tokenizer_ptr: *mut TokenizerHandle, text: *const c_char) -> BytesOrError {
+    BytesOrError::from_result(catch_panic(|| unigram_lattice_impl(tokenizer_ptr, text)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::convert_to_handle_ref;
+    use serde_json::{json, Value};
+    use crate::testing::{take_bytes, TestHandle, BYTE_LEVEL_BPE, UNIGRAM};
+
+    #[test]
+    fn token_scores() {
+        let handle = TestHandle::new(BYTE_LEVEL_BPE);
+        let scores = TokenScores::new(&convert_to_handle_ref(handle.0).unwrap().read().tokenizer);
+        // "hello" is the merge 3 ("hell o"), and "h" is part of the initial alphabet.
+        assert_eq!(scores.get(11), 3.0);
+        assert!(scores.get(0).is_nan());
+    }
+
+    #[test]
+    fn lattice() {
+        let handle = TestHandle::new(UNIGRAM);
+        let lattice = take_bytes(unsafe { unigram_lattice(handle.0, c"ab x".as_ptr()) }).unwrap();
+        let lattice: Value = serde_json::from_slice(&lattice).unwrap();
+        // The unknown "x" has the minimum score (-3.0) minus the penalty.
+        assert_eq!(lattice, json!({"pre_tokens": [
+            {"text": "ab", "offsets": [0, 2], "arcs": [[0, 2]], "best": [1], "nodes": [
+                {"id": 1, "piece": "a", "start": 0, "end": 1, "score": -1.0},
+                {"id": 3, "piece": "ab", "start": 0, "end": 2, "score": -1.5},
+                {"id": 2, "piece": "b", "start": 1, "end": 2, "score": -2.0},
+            ]},
+            {"text": "x", "offsets": [3, 4], "arcs": [], "best": [0], "nodes": [
+                {"id": 0, "piece": "<unk>", "start": 0, "end": 1, "score": -13.0},
+            ]},
+        ]}));
+
+        let handle = TestHandle::new(BYTE_LEVEL_BPE);
+        let error = take_bytes(unsafe { unigram_lattice(handle.0, c"ab".as_ptr()) }).unwrap_err();
+        assert_eq!(error, "unigram_lattice requires a Unigram model");
+    }
+}
@@ -1,25 +1,932 @@
-use tokenizers::tokenizer::Tokenizer;
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::ptr::null_mut;
+use regex::Regex;
+use serde_json::Value;
+use tokenizers::parallelism::MaybeParallelIterator;
+use tokenizers::tokenizer::Decoder;
+use crate::encode::{err, Offset};
+use crate::handle::{convert_to_handle_ref, TokenizerHandle, TokenizerState};
+use crate::panics::{catch_panic, catch_panic_or, error_c_string};
 
-/// tokenizer.Decode method.
-/// The returned string needs to be deallocated with `free_string`.
+// decode_ids decodes the `ids` with the tokenizer of `state`, recording the call (see `start_recording`).
+fn decode_ids(handle: &TokenizerHandle, state: &TokenizerState, ids: &[u32], skip_special_tokens: bool) -> Result<CString, Box<dyn Error>> {
+    crate::record::record_decode(handle, state, ids, &DecodeParams {
+        skip_special_tokens,
+        spaces_between_special_tokens: 0,
+        token_filter: std::ptr::null(),
+        token_filter_replacement: std::ptr::null(),
+        token_transform: None,
+        token_transform_data: null_mut(),
+        invalid_utf8: 0,
+    });
+    let string = match state.host_decoder() {
+        Some(_) => decode_filtered(state, ids, skip_special_tokens, 0, &Some),
+        None => state.tokenizer.decode(ids, skip_special_tokens),
+    }
+        .map_err(|e| err(format!("decoding failed: {}", e)))?;
+    Ok(c_string_of(string, 0))
+}
+
+/// DecodeResults is the result of `decode`, `decode_batch` and `decode_with_params`: the `len` decoded texts
+/// (null-terminated), in `decoded`.
+///
+/// Either `decoded` or `error` will be defined. Once it is no longer used, free it with `free_decode_results`.
+#[repr(C)]
+pub struct DecodeResults {
+    len: u64,
+    decoded: *mut *mut c_char,
+    error: *mut c_char,
+}
+
+impl DecodeResults {
+    fn from_result(r: Result<Vec<CString>, Box<dyn Error>>) -> DecodeResults {
+        match r {
+            Ok(texts) => {
+                let mut decoded: Box<[*mut c_char]> = texts.into_iter().map(CString::into_raw).collect();
+                let results = DecodeResults {
+                    len: decoded.len() as u64,
+                    decoded: decoded.as_mut_ptr(),
+                    error: null_mut(),
+                };
+                std::mem::forget(decoded);
+                results
+            }
+            Err(e) => DecodeResults {
+                len: 0,
+                decoded: null_mut(),
+                error: error_c_string(e),
+            },
+        }
+    }
+}
+
+fn decode_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    ids: *const u32,
+    len: u32,
+    skip_special_tokens: bool,
+) -> Result<Vec<CString>, Box<dyn Error>> {
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let ids = if len == 0 { &[][..] } else { unsafe { std::slice::from_raw_parts(ids, len as usize) } };
+    Ok(vec![decode_ids(handle, &handle.read(), ids, skip_special_tokens)?])
+}
+
+/// tokenizer.Decode method: it decodes the `len` ids, and returns the text as the only element of the results.
+///
+/// The results must be freed with `free_decode_results`.
+///
+/// # Safety
+///
+/// `ids` must point to `len` ids.
 #[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("decode"))]
 pub unsafe extern "C" fn decode(
-    tokenizer_ptr: *mut libc::c_void,
+    tokenizer_ptr: *mut TokenizerHandle,
+    ids: *const u32,
+    len: u32,
+    skip_special_tokens: bool,
+) -> DecodeResults {
+    DecodeResults::from_result(catch_panic(|| decode_impl(tokenizer_ptr, ids, len, skip_special_tokens)))
+}
+
+// MIN_PARALLEL_DECODE_BATCH is the number of sequences from which `decode_batch` decodes them in parallel: smaller
+// batches are decoded on the calling thread, since they take less time than dispatching them to the thread pool.
+const MIN_PARALLEL_DECODE_BATCH: usize = 32;
+
+// decode_in_parallel returns whether `decode_batch` decodes the `num_sequences` in parallel, on the thread pool
+// (see `set_thread_policy`). The host decoder (see `set_host_decoder`) is only called from the calling thread.
+fn decode_in_parallel(num_sequences: usize, has_host_decoder: bool) -> bool {
+    num_sequences >= MIN_PARALLEL_DECODE_BATCH && !has_host_decoder
+}
+
+fn decode_batch_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_sequences: usize,
+    ids: *const *const u32,
+    lengths: *const u32,
+    skip_special_tokens: bool,
+) -> Result<Vec<CString>, Box<dyn Error>> {
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    let mut sequences: Vec<&[u32]> = Vec::with_capacity(num_sequences);
+    for index in 0..num_sequences {
+        let len = unsafe { *lengths.add(index) } as usize;
+        sequences.push(if len == 0 { &[] } else { unsafe { std::slice::from_raw_parts(*ids.add(index), len) } });
+    }
+    let decode = |index: usize| {
+        decode_ids(handle, &state, sequences[index], skip_special_tokens)
+            .map_err(|e| format!("sequence #{}: {}", index, e))
+    };
+    let texts = if decode_in_parallel(num_sequences, state.host_decoder().is_some()) {
+        crate::threads::install(|| (0..num_sequences).into_maybe_par_iter().map(decode).collect())
+    } else {
+        (0..num_sequences).map(decode).collect::<Result<Vec<_>, String>>()
+    };
+    texts.map_err(err)
+}
+
+/// Decodes a batch of `num_sequences` sequences of ids in one parallel call: sequence `i` has the
+/// `lengths[i]` ids pointed by `ids[i]`. The texts are returned in the same order as the sequences, or the
+/// error of the first sequence that failed.
+///
+/// Large batches are decoded in parallel, by the threads of the batch calls (see `set_thread_policy`): small
+/// ones (under 32 sequences), or with a host decoder, are decoded sequentially on the calling thread.
+///
+/// The results must be freed with `free_decode_results`.
+///
+/// # Safety
+///
+/// `ids` and `lengths` must point to `num_sequences` elements, and each `ids[i]` to `lengths[i]` ids.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("decode_batch"))]
+pub unsafe extern "C" fn decode_batch(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_sequences: u64,
+    ids: *const *const u32,
+    lengths: *const u32,
+    skip_special_tokens: bool,
+) -> DecodeResults {
+    let num_sequences = match usize::try_from(num_sequences) {
+        Ok(n) => n,
+        Err(_) => return DecodeResults::from_result(
+            Err(err(format!("num_sequences={} overflows the platform's usize", num_sequences)))),
+    };
+    DecodeResults::from_result(catch_panic(|| {
+        decode_batch_impl(tokenizer_ptr, num_sequences, ids, lengths, skip_special_tokens)
+    }))
+}
+
+/// Frees the DecodeResults returned by `decode`, `decode_batch` or `decode_with_params`.
+///
+/// # Safety
+///
+/// `results` must have been returned by `decode`, `decode_batch` or `decode_with_params`, and it must not be used
+/// after this call.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("free_decode_results"))]
+pub unsafe extern "C" fn free_decode_results(results: DecodeResults) {
+    catch_panic_or((), || {
+        crate::free_string(results.error);
+        if !results.decoded.is_null() {
+            let decoded = std::ptr::slice_from_raw_parts_mut(results.decoded, results.len as usize);
+            let decoded = unsafe { Box::from_raw(decoded) };
+            for &text in decoded.iter() {
+                crate::free_string(text);
+            }
+        }
+    })
+}
+
+/// DecodeParams holds the options for `decode_with_params`.
+#[repr(C)]
+pub struct DecodeParams {
+    pub(crate) skip_special_tokens: bool,
+    pub(crate) spaces_between_special_tokens: u8, // 0 -> Decoder default (*); 1 -> Join with spaces; 2 -> Join without spaces
+    pub(crate) token_filter: *const c_char,  // Regex of the tokens to drop or replace, or null for none (*)
+    pub(crate) token_filter_replacement: *const c_char,  // Token replacing those matching token_filter, or null to drop them (*)
+    pub(crate) token_transform: TokenTransform,  // Callback transforming each token, or null for none (*)
+    pub(crate) token_transform_data: *mut c_void,  // Passed to token_transform
+    pub(crate) invalid_utf8: u8,  // 0 -> Replaced by U+FFFD (*); 1 -> Dropped; 2 -> Escaped as "\xNN"
+}
+
+/// TokenTransform is a host callback called by `decode_with_params` with each token string (`len` bytes of
+/// UTF-8, not null-terminated), e.g. for redaction or casing fixes, before the tokens are joined by the
+/// decoder. `user_data` is the `token_transform_data` given in DecodeParams.
+///
+/// It returns the transformed token (setting its length in `out_len`), or null to drop the token. The
+/// returned bytes are owned by the host: they are copied before the next call, so they only need to
+/// remain valid until then.
+pub type TokenTransform = Option<
+    unsafe extern "C" fn(user_data: *mut c_void, token: *const u8, len: u64, out_len: *mut u64) -> *const u8,
+>;
+
+// transform_token calls the host `transform` with the `token`: invalid UTF-8 results are replaced.
+unsafe fn transform_token(
+    transform: unsafe extern "C" fn(*mut c_void, *const u8, u64, *mut u64) -> *const u8,
+    user_data: *mut c_void,
+    token: String,
+) -> Option<String> {
+    let mut out_len: u64 = 0;
+    let out = unsafe { transform(user_data, token.as_ptr(), token.len() as u64, &mut out_len) };
+    if out.is_null() {
+        return None;
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(out, out_len as usize) };
+    Some(String::from_utf8_lossy(bytes).into_owned())
+}
+
+// TokenFilter drops (or replaces) the tokens matching a regex when decoding, see `DecodeParams`.
+struct TokenFilter {
+    regex: Regex,
+    replacement: Option<String>,
+}
+
+impl TokenFilter {
+    // from_params returns the TokenFilter configured in `params`, or None if there is none.
+    unsafe fn from_params(params: &DecodeParams) -> Result<Option<TokenFilter>, String> {
+        if params.token_filter.is_null() {
+            return Ok(None);
+        }
+        let c_str = |ptr: *const c_char| unsafe { CStr::from_ptr(ptr) }.to_str().map_err(|e| e.to_string());
+        // The pattern must match the whole token.
+        let regex = Regex::new(&format!("^(?:{})$", c_str(params.token_filter)?)).map_err(|e| e.to_string())?;
+        let replacement = if params.token_filter_replacement.is_null() {
+            None
+        } else {
+            Some(c_str(params.token_filter_replacement)?.to_string())
+        };
+        Ok(Some(TokenFilter { regex, replacement }))
+    }
+
+    // apply returns the token, its replacement, or None if it is dropped.
+    fn apply(&self, token: String) -> Option<String> {
+        if self.regex.is_match(&token) {
+            self.replacement.clone()
+        } else {
+            Some(token)
+        }
+    }
+}
+
+// ByteTokens tells which tokens the decoder of a tokenizer converts to bytes, to be decoded as UTF-8: the byte
+// fallback tokens (e.g. "<0xE2>") with a ByteFallback decoder, or all the tokens with a ByteLevel decoder.
+pub(crate) enum ByteTokens {
+    Fallback,
+    // The byte represented by each character of the byte-level tokens.
+    ByteLevel(HashMap<char, u8>),
+}
+
+impl ByteTokens {
+    // of_decoder returns the ByteTokens of the `decoder` (which may be a Sequence), or None if it doesn't convert
+    // tokens to bytes.
+    pub(crate) fn of_decoder(decoder: &impl serde::Serialize) -> Option<ByteTokens> {
+        fn find(decoder: &Value) -> Option<ByteTokens> {
+            match decoder["type"].as_str() {
+                Some("ByteFallback") => Some(ByteTokens::Fallback),
+                Some("ByteLevel") => Some(ByteTokens::ByteLevel(crate::generation::byte_level_char_bytes())),
+                _ => decoder["decoders"].as_array()?.iter().find_map(find),
+            }
+        }
+        find(&serde_json::to_value(decoder).ok()?)
+    }
+
+    // bytes returns the bytes of the `token`, or None if it is not converted to bytes.
+    pub(crate) fn bytes(&self, token: &str) -> Option<Vec<u8>> {
+        match self {
+            ByteTokens::Fallback => {
+                let hex = token.strip_prefix("<0x")?.strip_suffix('>')?;
+                (hex.len() == 2).then(|| u8::from_str_radix(hex, 16).ok()).flatten().map(|b| vec![b])
+            }
+            ByteTokens::ByteLevel(char_bytes) => token.chars().map(|c| char_bytes.get(&c).copied()).collect(),
+        }
+    }
+
+    // token returns the token the decoder converts to the `text`.
+    fn token(&self, text: &str) -> String {
+        match self {
+            ByteTokens::Fallback => text.to_string(),
+            ByteTokens::ByteLevel(char_bytes) => {
+                let byte_chars: HashMap<u8, char> = char_bytes.iter().map(|(&c, &b)| (b, c)).collect();
+                text.bytes().map(|b| byte_chars[&b]).collect()
+            }
+        }
+    }
+
+    // replace_invalid replaces each run of byte tokens that is not valid UTF-8 by one token with its text, with
+    // the invalid bytes dropped (`invalid_utf8` 1) or escaped (2). The other tokens are kept.
+    fn replace_invalid(&self, tokens: Vec<String>, invalid_utf8: u8) -> Vec<String> {
+        let mut replaced = Vec::with_capacity(tokens.len());
+        let mut run: Vec<String> = Vec::new();
+        let mut bytes: Vec<u8> = Vec::new();
+        let flush = |replaced: &mut Vec<String>, run: &mut Vec<String>, bytes: &mut Vec<u8>| {
+            if std::str::from_utf8(bytes).is_ok() {
+                replaced.append(run);
+            } else {
+                let mut text = String::new();
+                for chunk in bytes.utf8_chunks() {
+                    text.push_str(chunk.valid());
+                    if invalid_utf8 == 2 {
+                        chunk.invalid().iter().for_each(|b| text.push_str(&format!("\\x{:02X}", b)));
+                    }
+                }
+                if !text.is_empty() {
+                    replaced.push(self.token(&text));
+                }
+                run.clear();
+            }
+            bytes.clear();
+        };
+        for token in tokens {
+            match self.bytes(&token) {
+                Some(token_bytes) => {
+                    bytes.extend(token_bytes);
+                    run.push(token);
+                }
+                None => {
+                    flush(&mut replaced, &mut run, &mut bytes);
+                    replaced.push(token);
+                }
+            }
+        }
+        flush(&mut replaced, &mut run, &mut bytes);
+        replaced
+    }
+}
+
+// c_string_of converts the decoded `string` to a C string, with the NUL characters handled as selected by
+// `invalid_utf8` (see `DecodeParams`): replaced by U+FFFD, dropped, or escaped as "\x00".
+fn c_string_of(string: String, invalid_utf8: u8) -> CString {
+    CString::new(string).unwrap_or_else(|e| {
+        let string = String::from_utf8(e.into_vec()).unwrap();
+        let replacement = match invalid_utf8 {
+            0 => "\u{FFFD}",
+            1 => "",
+            _ => "\\x00",
+        };
+        CString::new(string.replace('\0', replacement)).unwrap()
+    })
+}
+
+// decode_filtered decodes the ids as `Tokenizer::decode`, except that the tokens are transformed with
+// `filter` (dropped if it returns None) before being joined by the decoder -- or by the host decoder,
+// if one is set (see `set_host_decoder`). The bytes that are not valid UTF-8 are handled as selected by
+// `invalid_utf8` (see `DecodeParams`).
+fn decode_filtered(
+    state: &TokenizerState,
+    ids: &[u32],
+    skip_special_tokens: bool,
+    invalid_utf8: u8,
+    filter: &dyn Fn(String) -> Option<String>,
+) -> tokenizers::Result<String> {
+    let tokenizer = &state.tokenizer;
+    let strings = state.token_strings();
+    let tokens: Vec<String> = ids
+        .iter()
+        .filter_map(|&id| strings.decoded(id, skip_special_tokens))
+        .filter_map(|token| filter(token.to_string()))
+        .collect();
+    if let Some(host_decoder) = state.host_decoder() {
+        return host_decoder.decode(&tokens);
+    }
+    let byte_tokens = tokenizer.get_decoder().filter(|_| invalid_utf8 != 0).and_then(ByteTokens::of_decoder);
+    let tokens = match byte_tokens {
+        Some(byte_tokens) => byte_tokens.replace_invalid(tokens, invalid_utf8),
+        None => tokens,
+    };
+    match tokenizer.get_decoder() {
+        Some(decoder) => decoder.decode(tokens),
+        None => Ok(tokens.join(" ")),
+    }
+}
+
+/// HostDecodeFn is a host callback that joins the `num_tokens` token strings (given as arrays of pointers
+/// to `lens` bytes of UTF-8, not null-terminated) into the decoded text, replacing the decoder of the
+/// tokenizer (see `set_host_decoder`). `user_data` is the one given to `set_host_decoder`.
+///
+/// It returns the decoded text (setting its length in `out_len`), or null if it failed. The returned bytes
+/// are owned by the host: they are copied before the next call, so they only need to remain valid until then.
+pub type HostDecodeFn = Option<
+    unsafe extern "C" fn(
+        user_data: *mut c_void,
+        tokens: *const *const u8,
+        lens: *const u64,
+        num_tokens: u64,
+        out_len: *mut u64,
+    ) -> *const u8,
+>;
+
+/// HostDecoder is the decoder set with `set_host_decoder`: a host callback and its user data.
+#[derive(Clone, Copy)]
+pub struct HostDecoder {
+    decode: unsafe extern "C" fn(*mut c_void, *const *const u8, *const u64, u64, *mut u64) -> *const u8,
+    user_data: *mut c_void,
+}
+
+// The host is responsible for the callback (and its user data) being usable from any thread.
+unsafe impl Send for HostDecoder {}
+unsafe impl Sync for HostDecoder {}
+
+impl HostDecoder {
+    /// Joins the `tokens` with the host callback: invalid UTF-8 results are replaced.
+    pub fn decode(&self, tokens: &[String]) -> tokenizers::Result<String> {
+        let pointers: Vec<*const u8> = tokens.iter().map(|token| token.as_ptr()).collect();
+        let lens: Vec<u64> = tokens.iter().map(|token| token.len() as u64).collect();
+        let mut out_len: u64 = 0;
+        let out = unsafe {
+            (self.decode)(self.user_data, pointers.as_ptr(), lens.as_ptr(), tokens.len() as u64, &mut out_len)
+        };
+        if out.is_null() {
+            return Err("host decoder failed".into());
+        }
+        let bytes = unsafe { std::slice::from_raw_parts(out, out_len as usize) };
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+/// set_host_decoder sets a host callback that joins the decoded token strings (see `HostDecodeFn`) in
+/// place of the decoder of the tokenizer, for `decode` and `decode_with_params`: e.g. for special
+/// whitespace rules for code generation. Everything else (including the mapping of ids to token strings)
+/// is still done natively. Passing a null `decode_fn` restores the decoder of the tokenizer.
+///
+/// The callback may be called concurrently from different threads, if the tokenizer is shared.
+///
+/// It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+/// The returned string needs to be freed with `free_string`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("set_host_decoder"))]
+pub unsafe extern "C" fn set_host_decoder(
+    tokenizer_ptr: *mut TokenizerHandle,
+    decode_fn: HostDecodeFn,
+    user_data: *mut c_void,
+) -> *mut c_char {
+    let result = catch_panic(|| {
+        let mut state = convert_to_handle_ref(tokenizer_ptr)?.write()?;
+        state.set_host_decoder(decode_fn.map(|decode| HostDecoder { decode, user_data }));
+        Ok(())
+    });
+    match result {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+/// tokenizer.Decode method with DecodeParams.
+///
+/// With `spaces_between_special_tokens` set to 1 or 2, the ids in between special tokens are decoded
+/// separately, and the resulting texts and special tokens are joined with or without spaces, matching
+/// the behavior of the reference (transformers "slow") implementations.
+///
+/// With `token_filter` set to a regex (e.g. `<extra_id_\d+>`), the tokens it fully matches are dropped, or
+/// replaced by the `token_filter_replacement` token, before they are joined by the decoder. The remaining
+/// tokens are then passed to the `token_transform` callback, if given (see `TokenTransform`).
+///
+/// With `invalid_utf8` set to 1 or 2, the byte sequences that can't form valid UTF-8 (e.g. the byte fallback
+/// or byte-level tokens of an incomplete character) are dropped, or escaped as "\xNN" (e.g. "\xE2\x82"),
+/// instead of being replaced by U+FFFD. NUL characters, which can't be part of the returned C string, are
+/// handled the same way (U+FFFD by default, or dropped, or "\x00").
+///
+/// It returns the text as the only element of the results, or the error, e.g. with an invalid tokenizer or
+/// `token_filter`. The results must be freed with `free_decode_results`.
+///
+/// # Safety
+///
+/// `ids` must point to `len` ids.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("decode_with_params"))]
+pub unsafe extern "C" fn decode_with_params(
+    tokenizer_ptr: *mut TokenizerHandle,
+    ids: *const u32,
+    len: u32,
+    params: DecodeParams,
+) -> DecodeResults {
+    DecodeResults::from_result(catch_panic(|| Ok(vec![decode_with_params_impl(tokenizer_ptr, ids, len, params)?])))
+}
+
+fn decode_with_params_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    ids: *const u32,
+    len: u32,
+    params: DecodeParams,
+) -> Result<CString, Box<dyn Error>> {
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    let ids_slice = if len == 0 { &[][..] } else { unsafe { std::slice::from_raw_parts(ids, len as usize) } };
+    crate::record::record_decode(handle, &state, ids_slice, &params);
+    let token_filter = unsafe { TokenFilter::from_params(&params) }
+        .map_err(|e| err(format!("invalid token_filter: {}", e)))?;
+    let filter = |token: String| {
+        let token = match &token_filter {
+            Some(token_filter) => token_filter.apply(token)?,
+            None => token,
+        };
+        match params.token_transform {
+            Some(transform) => unsafe { transform_token(transform, params.token_transform_data, token) },
+            None => Some(token),
+        }
+    };
+    let string = match params.spaces_between_special_tokens {
+        0 if params.invalid_utf8 == 0 && token_filter.is_none() && params.token_transform.is_none()
+            && state.host_decoder().is_none() =>
+            state.tokenizer.decode(ids_slice, params.skip_special_tokens),
+        0 => decode_filtered(&state, ids_slice, params.skip_special_tokens, params.invalid_utf8, &filter),
+        1 => decode_around_special_tokens(&state, ids_slice, params.skip_special_tokens, " ", params.invalid_utf8, &filter),
+        2 => decode_around_special_tokens(&state, ids_slice, params.skip_special_tokens, "", params.invalid_utf8, &filter),
+        other => return Err(err(format!("invalid spaces_between_special_tokens {}, it must be 0, 1 or 2", other))),
+    }.map_err(|e| err(format!("failed to decode input: {}", e)))?;
+    Ok(c_string_of(string, params.invalid_utf8))
+}
+
+// decode_around_special_tokens decodes separately the runs of ids in between special tokens, and joins
+// them and the special tokens (unless skipped) with the given separator. All tokens are transformed with
+// `filter` (see `decode_filtered`).
+fn decode_around_special_tokens(
+    state: &TokenizerState,
+    ids: &[u32],
+    skip_special_tokens: bool,
+    separator: &str,
+    invalid_utf8: u8,
+    filter: &dyn Fn(String) -> Option<String>,
+) -> tokenizers::Result<String> {
+    let strings = state.token_strings();
+    let mut sub_texts: Vec<String> = Vec::new();
+    let mut current_ids: Vec<u32> = Vec::new();
+    for id in ids {
+        match strings.special(*id) {
+            Some(token) => {
+                if !current_ids.is_empty() {
+                    sub_texts.push(decode_filtered(state, &current_ids, skip_special_tokens, invalid_utf8, filter)?);
+                    current_ids.clear();
+                }
+                if !skip_special_tokens {
+                    sub_texts.extend(filter(token.to_string()));
+                }
+            }
+            None => current_ids.push(*id),
+        }
+    }
+    if !current_ids.is_empty() {
+        sub_texts.push(decode_filtered(state, &current_ids, skip_special_tokens, invalid_utf8, filter)?);
+    }
+    Ok(sub_texts.join(separator))
+}
+
+/// DecodedWithSpans is the result of `decode_with_spans`: the decoded `text` (null-terminated), and for each
+/// of the `len` ids decoded, the byte span of `text` it contributed to, in `spans`.
+///
+/// Either `text` and `spans` or `error` will be defined. Once it is no longer used, free it with
+/// `free_decoded_with_spans`.
+#[repr(C)]
+pub struct DecodedWithSpans {
+    text: *mut c_char,
+    spans: *mut Offset,
+    len: u64,
+    error: *mut c_char,
+}
+
+// decode_spans_impl decodes the `ids` incrementally (as the streaming decoders do, see `DecodeStream` in the
+// tokenizers crate), and returns the text and the span of each id: the text appended to the output when it is
+// decoded. Ids that only decode to part of a character (e.g. byte-level tokens) share the span of the text
+// emitted once the character is complete, and ids that add no text (e.g. skipped special tokens) have an empty
+// span.
+fn decode_spans_impl(
+    state: &TokenizerState,
+    ids: &[u32],
+    skip_special_tokens: bool,
+) -> tokenizers::Result<(String, Vec<Offset>)> {
+    let decode = |ids: &[u32]| match state.host_decoder() {
+        Some(_) => decode_filtered(state, ids, skip_special_tokens, 0, &Some),
+        None => state.tokenizer.decode(ids, skip_special_tokens),
+    };
+    let offset = |len: usize| u32::try_from(len).map_err(|_| "decoded text too long for u32 offsets");
+    let mut text = String::new();
+    let mut spans: Vec<Offset> = Vec::with_capacity(ids.len());
+    // The ids decoded since `prefix_index` are decoded again with each new id, for the decoders that depend on
+    // the context (e.g. to strip the leading space of the first word): `prefix` is the text of those up to the
+    // last id emitted.
+    let mut window: Vec<u32> = Vec::new();
+    let mut prefix = String::new();
+    let mut prefix_index = 0;
+    let mut num_pending = 0;
+    for (i, &id) in ids.iter().enumerate() {
+        window.push(id);
+        let string = decode(&window)?;
+        let is_last = i == ids.len() - 1;
+        if string.ends_with('\u{FFFD}') && !is_last {
+            // Incomplete character: emitted with the next ids.
+            num_pending += 1;
+            continue;
+        }
+        let new_text = string
+            .strip_prefix(prefix.as_str())
+            .ok_or("decoded text is not an extension of the previously decoded text")?;
+        // NUL characters are replaced by U+FFFD as they are appended, as `c_string_of` does, so the spans are
+        // those of the C string returned.
+        let start = offset(text.len())?;
+        text.push_str(&new_text.replace('\0', "\u{FFFD}"));
+        let end = offset(text.len())?;
+        spans.extend((0..=num_pending).map(|_| Offset { start, end }));
+        num_pending = 0;
+        if !new_text.is_empty() {
+            let new_prefix_index = window.len() - prefix_index;
+            window.drain(..prefix_index);
+            prefix = decode(&window)?;
+            prefix_index = new_prefix_index;
+        }
+    }
+    Ok((text, spans))
+}
+
+/// Decodes the `len` ids as `decode`, and also returns for each id the byte span of the decoded text it
+/// contributed to, e.g. to attribute the generated text to the tokens (for log-probability highlighting)
+/// without encoding the output again.
+///
+/// The ids are decoded incrementally, as in streaming decoding: the span of an id is the text appended when
+/// it is decoded, so the spans are contiguous and in order. Ids that only decode to part of a character (e.g.
+/// byte-level tokens of an emoji) share the span of the character, and ids that add no text (e.g. skipped
+/// special tokens) have an empty span. The text is the same `decode` returns for the usual decoders, with the
+/// NUL characters (which can't be part of a C string) replaced by U+FFFD.
+///
+/// The result must be freed with `free_decoded_with_spans`.
+///
+/// # Safety
+///
+/// `ids` must point to `len` ids.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("decode_with_spans"))]
+pub unsafe extern "C" fn decode_with_spans(
+    tokenizer_ptr: *mut TokenizerHandle,
     ids: *const u32,
     len: u32,
     skip_special_tokens: bool,
-) -> *mut libc::c_char {
-    let tokenizer: &Tokenizer;
-    unsafe {
-        tokenizer = tokenizer_ptr
-            .cast::<Tokenizer>()
-            .as_ref()
-            .expect("failed to cast tokenizer");
-    }
-    let ids_slice = unsafe { std::slice::from_raw_parts(ids, len as usize) };
-    let string = tokenizer
-        .decode(ids_slice, skip_special_tokens)
-        .expect("failed to decode input");
-    let c_string = std::ffi::CString::new(string).unwrap();
-    c_string.into_raw()
+) -> DecodedWithSpans {
+    let ids = if len == 0 { &[][..] } else { unsafe { std::slice::from_raw_parts(ids, len as usize) } };
+    let result = catch_panic(|| {
+        let handle = convert_to_handle_ref(tokenizer_ptr)?;
+        let (text, spans) = decode_spans_impl(&handle.read(), ids, skip_special_tokens)
+            .map_err(|e| err(format!("decoding failed: {}", e)))?;
+        Ok((c_string_of(text, 0), spans))
+    });
+    match result {
+        Ok((text, spans)) => {
+            let mut spans = spans.into_boxed_slice();
+            let result = DecodedWithSpans {
+                text: text.into_raw(),
+                spans: spans.as_mut_ptr(),
+                len: spans.len() as u64,
+                error: null_mut(),
+            };
+            std::mem::forget(spans);
+            result
+        }
+        Err(e) => DecodedWithSpans {
+            text: null_mut(),
+            spans: null_mut(),
+            len: 0,
+            error: error_c_string(e),
+        },
+    }
+}
+
+/// Frees the DecodedWithSpans returned by `decode_with_spans`.
+///
+/// # Safety
+///
+/// `result` must have been returned by `decode_with_spans`, and it must not be used after this call.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("free_decoded_with_spans"))]
+pub unsafe extern "C" fn free_decoded_with_spans(result: DecodedWithSpans) {
+    catch_panic_or((), || {
+        crate::free_string(result.text);
+        crate::free_string(result.error);
+        if !result.spans.is_null() {
+            unsafe {
+                drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(result.spans, result.len as usize)));
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{take_error, TestHandle, BYTE_LEVEL_BPE, WORDPIECE};
+
+    // params returns the DecodeParams with the given `spaces_between_special_tokens`, and defaults otherwise.
+    fn params(spaces_between_special_tokens: u8) -> DecodeParams {
+        DecodeParams {
+            skip_special_tokens: false,
+            spaces_between_special_tokens,
+            token_filter: std::ptr::null(),
+            token_filter_replacement: std::ptr::null(),
+            token_transform: None,
+            token_transform_data: null_mut(),
+            invalid_utf8: 0,
+        }
+    }
+
+    #[test]
+    fn spaces_between_special_tokens() {
+        let handle = TestHandle::new(WORDPIECE);
+        let ids = [4, 5, 6];
+        let decode = |spaces| decode_with_params_impl(handle.0, ids.as_ptr(), ids.len() as u32, params(spaces));
+        assert_eq!(decode(1).unwrap().to_str().unwrap(), "hello <|im_end|> world");
+        assert_eq!(decode(2).unwrap().to_str().unwrap(), "hello<|im_end|>world");
+        let e = decode(3).unwrap_err();
+        assert_eq!(e.to_string(), "invalid spaces_between_special_tokens 3, it must be 0, 1 or 2");
+    }
+
+    #[test]
+    fn decode_with_params_errors() {
+        let handle = TestHandle::new(WORDPIECE);
+        let ids = [4_u32, 6];
+        let results = unsafe { decode_with_params(handle.0, ids.as_ptr(), ids.len() as u32, params(0)) };
+        assert!(results.error.is_null());
+        assert_eq!(results.len, 1);
+        assert_eq!(unsafe { CStr::from_ptr(*results.decoded) }.to_str().unwrap(), "hello world");
+        unsafe { free_decode_results(results) };
+
+        let results = unsafe { decode_with_params(handle.0, ids.as_ptr(), ids.len() as u32, params(3)) };
+        assert!(results.decoded.is_null());
+        assert_eq!(
+            take_error(results.error).as_deref(),
+            Some("invalid spaces_between_special_tokens 3, it must be 0, 1 or 2"));
+
+        let results = unsafe { decode_with_params(null_mut(), ids.as_ptr(), ids.len() as u32, params(0)) };
+        assert_eq!(take_error(results.error).as_deref(), Some("tokenizer passed is null"));
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let handle = TestHandle::new(BYTE_LEVEL_BPE);
+        let ids = [11, 17, 18];
+        let decode = |invalid_utf8| {
+            let params = DecodeParams { invalid_utf8, ..params(0) };
+            decode_with_params_impl(handle.0, ids.as_ptr(), ids.len() as u32, params).unwrap().into_string().unwrap()
+        };
+        assert_eq!(decode(0), "hello world\u{FFFD}");
+        assert_eq!(decode(1), "hello world");
+        assert_eq!(decode(2), "hello world\\xE2");
+    }
+
+    #[test]
+    fn c_string_with_nul() {
+        let c_string = |invalid_utf8| c_string_of("a\0b".to_string(), invalid_utf8).into_string().unwrap();
+        assert_eq!(c_string(0), "a\u{FFFD}b");
+        assert_eq!(c_string(1), "ab");
+        assert_eq!(c_string(2), "a\\x00b");
+        assert_eq!(c_string_of("ab".to_string(), 2).into_string().unwrap(), "ab");
+    }
+
+    #[test]
+    fn token_filter() {
+        let handle = TestHandle::new(WORDPIECE);
+        let ids = [4, 5, 6, 7, 8];
+        let decode = |filter: &CStr, replacement: Option<&CStr>| {
+            let params = DecodeParams {
+                token_filter: filter.as_ptr(),
+                token_filter_replacement: replacement.map_or(std::ptr::null(), CStr::as_ptr),
+                ..params(0)
+            };
+            decode_with_params_impl(handle.0, ids.as_ptr(), ids.len() as u32, params)
+        };
+        assert_eq!(decode(c"<\\|im_end\\|>", None).unwrap().to_str().unwrap(), "hello world <| |>");
+        assert_eq!(decode(c"<\\|.*", Some(c"[UNK]")).unwrap().to_str().unwrap(), "hello [UNK] world [UNK] |>");
+        // The pattern must match the whole token.
+        assert_eq!(decode(c"im_end", None).unwrap().to_str().unwrap(), "hello <|im_end|> world <| |>");
+        let e = decode(c"(", None).unwrap_err();
+        assert!(e.to_string().starts_with("invalid token_filter: "), "{}", e);
+    }
+
+    // upper_case is a TokenTransform returning the token in upper case (in the Vec<u8> given as `user_data`),
+    // or dropping it if it is "world".
+    unsafe extern "C" fn upper_case(
+        user_data: *mut c_void,
+        token: *const u8,
+        len: u64,
+        out_len: *mut u64,
+    ) -> *const u8 {
+        let token = unsafe { std::slice::from_raw_parts(token, len as usize) };
+        if token == b"world" {
+            return std::ptr::null();
+        }
+        let buffer = unsafe { &mut *(user_data as *mut Vec<u8>) };
+        *buffer = token.to_ascii_uppercase();
+        unsafe { *out_len = buffer.len() as u64 };
+        buffer.as_ptr()
+    }
+
+    #[test]
+    fn token_transform() {
+        let handle = TestHandle::new(WORDPIECE);
+        let ids = [4, 6, 1, 3];
+        let mut buffer: Vec<u8> = Vec::new();
+        let transform_params = DecodeParams {
+            token_transform: Some(upper_case),
+            token_transform_data: &mut buffer as *mut Vec<u8> as *mut c_void,
+            ..params(0)
+        };
+        let decoded = decode_with_params_impl(handle.0, ids.as_ptr(), ids.len() as u32, transform_params).unwrap();
+        assert_eq!(decoded.to_str().unwrap(), "HELLO AB");
+
+        // The replacements of the filtered tokens are passed to the transform.
+        let filter_params = DecodeParams {
+            token_filter: c"hello".as_ptr(),
+            token_filter_replacement: c"world".as_ptr(),
+            token_transform: Some(upper_case),
+            token_transform_data: &mut buffer as *mut Vec<u8> as *mut c_void,
+            ..params(0)
+        };
+        let decoded = decode_with_params_impl(handle.0, ids.as_ptr(), ids.len() as u32, filter_params).unwrap();
+        assert_eq!(decoded.to_str().unwrap(), "AB");
+    }
+
+    // join_with_dashes is a HostDecodeFn joining the tokens with "-" (in the Vec<u8> given as `user_data`), or
+    // failing if there are none.
+    unsafe extern "C" fn join_with_dashes(
+        user_data: *mut c_void,
+        tokens: *const *const u8,
+        lens: *const u64,
+        num_tokens: u64,
+        out_len: *mut u64,
+    ) -> *const u8 {
+        if num_tokens == 0 {
+            return std::ptr::null();
+        }
+        let tokens = unsafe { std::slice::from_raw_parts(tokens, num_tokens as usize) };
+        let lens = unsafe { std::slice::from_raw_parts(lens, num_tokens as usize) };
+        let tokens: Vec<&[u8]> =
+            tokens.iter().zip(lens).map(|(&t, &len)| unsafe { std::slice::from_raw_parts(t, len as usize) }).collect();
+        let buffer = unsafe { &mut *(user_data as *mut Vec<u8>) };
+        *buffer = tokens.join(&b'-');
+        unsafe { *out_len = buffer.len() as u64 };
+        buffer.as_ptr()
+    }
+
+    #[test]
+    fn host_decoder() {
+        let handle = TestHandle::new(WORDPIECE);
+        let mut buffer: Vec<u8> = Vec::new();
+        let user_data = &mut buffer as *mut Vec<u8> as *mut c_void;
+        assert_eq!(take_error(unsafe { set_host_decoder(handle.0, Some(join_with_dashes), user_data) }), None);
+        let ids = [4_u32, 1, 3, 6];
+        let decode = |ids: &[u32]| {
+            let results = unsafe { decode(handle.0, ids.as_ptr(), ids.len() as u32, false) };
+            if let Some(e) = take_error(results.error) {
+                return Err(e);
+            }
+            let decoded = unsafe { CStr::from_ptr(*results.decoded) }.to_str().unwrap().to_string();
+            unsafe { free_decode_results(results) };
+            Ok(decoded)
+        };
+        assert_eq!(decode(&ids).unwrap(), "hello-a-##b-world");
+        let decoded = decode_with_params_impl(handle.0, ids.as_ptr(), ids.len() as u32, params(0)).unwrap();
+        assert_eq!(decoded.to_str().unwrap(), "hello-a-##b-world");
+        assert_eq!(decode(&[]).unwrap_err(), "decoding failed: host decoder failed");
+
+        // A null callback restores the decoder of the tokenizer.
+        assert_eq!(take_error(unsafe { set_host_decoder(handle.0, None, null_mut()) }), None);
+        assert_eq!(decode(&ids).unwrap(), "hello ab world");
+        assert_eq!(
+            take_error(unsafe { set_host_decoder(null_mut(), None, null_mut()) }).as_deref(),
+            Some("tokenizer passed is null"));
+    }
+
+    // decode_spans returns the text and spans of `decode_with_spans`, or its error.
+    fn decode_spans(handle: &TestHandle, ids: &[u32], skip_special_tokens: bool) -> (String, Vec<(u32, u32)>) {
+        let result = unsafe { decode_with_spans(handle.0, ids.as_ptr(), ids.len() as u32, skip_special_tokens) };
+        assert!(result.error.is_null());
+        let text = unsafe { CStr::from_ptr(result.text) }.to_str().unwrap().to_string();
+        let spans = unsafe { std::slice::from_raw_parts(result.spans, result.len as usize) };
+        let spans = spans.iter().map(|span| (span.start, span.end)).collect();
+        unsafe { free_decoded_with_spans(result) };
+        (text, spans)
+    }
+
+    #[test]
+    fn decode_with_spans_of_ids() {
+        let handle = TestHandle::new(WORDPIECE);
+        let (text, spans) = decode_spans(&handle, &[4, 1, 3, 5, 6], true);
+        assert_eq!(text, "hello ab world");
+        assert_eq!(spans, [(0, 5), (5, 7), (7, 8), (8, 8), (8, 14)]);
+        let (text, spans) = decode_spans(&handle, &[4, 1, 3, 5, 6], false);
+        assert_eq!(text, "hello ab <|im_end|> world");
+        assert_eq!(spans[3..], [(8, 19), (19, 25)]);
+
+        // The incomplete character is emitted with the next id, and the span shared.
+        let handle = TestHandle::new(BYTE_LEVEL_BPE);
+        assert_eq!(decode_spans(&handle, &[18, 11], true), ("\u{FFFD}hello".to_string(), vec![(0, 8), (0, 8)]));
+        let (text, spans) = decode_spans(&handle, &[11, 17, 12, 18], true);
+        assert_eq!(text, "hello world\u{FFFD}");
+        assert_eq!(spans, [(0, 5), (5, 11), (11, 11), (11, 14)]);
+        assert_eq!(decode_spans(&handle, &[], true), (String::new(), vec![]));
+
+        let result = unsafe { decode_with_spans(null_mut(), [4].as_ptr(), 1, false) };
+        assert!(result.text.is_null() && result.spans.is_null());
+        assert_eq!(take_error(result.error).as_deref(), Some("tokenizer passed is null"));
+    }
+
+    #[test]
+    fn decode_batch_sizes() {
+        assert!(!decode_in_parallel(MIN_PARALLEL_DECODE_BATCH - 1, false));
+        assert!(decode_in_parallel(MIN_PARALLEL_DECODE_BATCH, false));
+        assert!(!decode_in_parallel(MIN_PARALLEL_DECODE_BATCH, true));
+
+        let handle = TestHandle::new(WORDPIECE);
+        for num_sequences in [1, MIN_PARALLEL_DECODE_BATCH + 1] {
+            let sequences: Vec<Vec<u32>> = (0..num_sequences).map(|i| vec![4; i % 3]).collect();
+            let ids: Vec<*const u32> = sequences.iter().map(|s| s.as_ptr()).collect();
+            let lengths: Vec<u32> = sequences.iter().map(|s| s.len() as u32).collect();
+            let results = unsafe {
+                decode_batch(handle.0, num_sequences as u64, ids.as_ptr(), lengths.as_ptr(), false)
+            };
+            assert!(results.error.is_null());
+            let decoded = unsafe { std::slice::from_raw_parts(results.decoded, results.len as usize) };
+            let texts: Vec<&str> = decoded.iter().map(|&t| unsafe { CStr::from_ptr(t) }.to_str().unwrap()).collect();
+            let expected: Vec<&str> = (0..num_sequences).map(|i| ["", "hello", "hello hello"][i % 3]).collect();
+            assert_eq!(texts, expected);
+            unsafe { free_decode_results(results) };
+        }
+    }
 }
@@ -0,0 +1,202 @@
+//! Human-readable description of the pipeline of a tokenizer (see `describe`), for logs and support tickets.
+
+use std::error::Error;
+use std::ffi::{c_char, CString};
+use std::fmt::Write;
+use std::ptr::null_mut;
+use serde_json::{Map, Value};
+use tokenizers::tokenizer::Tokenizer;
+use crate::handle::{convert_to_handle_ref, TokenizerHandle};
+use crate::panics::catch_panic;
+
+// Longer strings are truncated, and longer arrays summarized by their length.
+const MAX_STRING_LEN: usize = 40;
+const MAX_ARRAY_LEN: usize = 8;
+
+// describe_string returns the quoted `s`, truncated to MAX_STRING_LEN characters.
+fn describe_string(s: &str) -> String {
+    match s.char_indices().nth(MAX_STRING_LEN) {
+        Some((end, _)) => format!("{:?}...", &s[..end]),
+        None => format!("{:?}", s),
+    }
+}
+
+// describe_value returns a one-line description of a parameter of a component.
+fn describe_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => describe_string(s),
+        Value::Array(items) if items.len() > MAX_ARRAY_LEN => format!("[{} items]", items.len()),
+        Value::Array(items) => format!("[{}]", items.iter().map(describe_value).collect::<Vec<_>>().join(", ")),
+        Value::Object(object) if object.contains_key("type") => describe_component(value),
+        // Enums with a value, e.g. the `{"Regex": "\s+"}` patterns.
+        Value::Object(object) if object.len() == 1 => {
+            let (key, value) = object.iter().next().unwrap();
+            format!("{}({})", key, describe_value(value))
+        }
+        Value::Object(object) if object.len() > MAX_ARRAY_LEN => format!("{{{} entries}}", object.len()),
+        Value::Object(object) => format!("{{{}}}", describe_params(object, &[])),
+        value => value.to_string(),
+    }
+}
+
+// describe_params returns the `key=value` descriptions of the parameters of a component, except `type` and
+// the `skip` ones.
+fn describe_params(params: &Map<String, Value>, skip: &[&str]) -> String {
+    params
+        .iter()
+        .filter(|(key, _)| *key != "type" && !skip.contains(&key.as_str()))
+        .map(|(key, value)| format!("{}={}", key, describe_value(value)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// describe_template returns the template of a TemplateProcessing post-processor, e.g. "[CLS] $A [SEP]",
+// with the type ids as ":1" when not 0.
+fn describe_template(pieces: &Value) -> String {
+    let pieces = pieces.as_array().map_or(&[][..], Vec::as_slice).iter().map(|piece| {
+        let (text, type_id) = match (piece.get("SpecialToken"), piece.get("Sequence")) {
+            (Some(special), _) => (special["id"].as_str().unwrap_or("?").to_string(), &special["type_id"]),
+            (_, Some(sequence)) => (format!("${}", sequence["id"].as_str().unwrap_or("?")), &sequence["type_id"]),
+            _ => (piece.to_string(), &Value::Null),
+        };
+        match type_id.as_u64() {
+            Some(type_id) if type_id != 0 => format!("{}:{}", text, type_id),
+            _ => text,
+        }
+    });
+    format!("{:?}", pieces.collect::<Vec<_>>().join(" "))
+}
+
+// describe_component returns a one-line description of a serialized component of the pipeline: its type
+// with its parameters, or for `Sequence` the list of its sub-components.
+fn describe_component(component: &Value) -> String {
+    let Some(object) = component.as_object() else {
+        return describe_value(component);
+    };
+    let component_type = object.get("type").and_then(Value::as_str).unwrap_or("?");
+    match component_type {
+        "Sequence" => {
+            let sub_components = object
+                .iter()
+                .find_map(|(key, value)| value.as_array().filter(|_| key != "type"))
+                .map_or(&[][..], Vec::as_slice);
+            format!("Sequence[{}]", sub_components.iter().map(describe_component).collect::<Vec<_>>().join(", "))
+        }
+        "TemplateProcessing" => {
+            let num_special_tokens = object.get("special_tokens").and_then(Value::as_object).map_or(0, Map::len);
+            format!(
+                "TemplateProcessing(single={}, pair={}, special_tokens={})",
+                describe_template(&object["single"]),
+                describe_template(&object["pair"]),
+                num_special_tokens,
+            )
+        }
+        "Precompiled" => "Precompiled".to_string(),
+        _ => match describe_params(object, &[]) {
+            params if params.is_empty() => component_type.to_string(),
+            params => format!("{}({})", component_type, params),
+        },
+    }
+}
+
+// describe_model returns the description of the model: its type, vocabulary size and parameters.
+fn describe_model(tokenizer: &Tokenizer, model: &Value) -> String {
+    let Some(object) = model.as_object() else {
+        return describe_value(model);
+    };
+    let mut description = format!(
+        "{}(vocab_size={}",
+        object.get("type").and_then(Value::as_str).unwrap_or("?"),
+        tokenizer.get_vocab_size(false),
+    );
+    if let Some(merges) = object.get("merges").and_then(Value::as_array) {
+        write!(description, ", merges={}", merges.len()).unwrap();
+    }
+    match describe_params(object, &["vocab", "merges"]) {
+        params if params.is_empty() => {}
+        params => write!(description, ", {}", params).unwrap(),
+    }
+    description.push(')');
+    description
+}
+
+fn describe_impl(tokenizer_ptr: *mut TokenizerHandle) -> Result<String, Box<dyn Error>> {
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    let tokenizer: &Tokenizer = &state.tokenizer;
+    let json = serde_json::to_value(tokenizer)?;
+    let optional = |component: &Value| if component.is_null() { "none".to_string() } else { describe_component(component) };
+
+    let mut description = String::new();
+    writeln!(description, "normalizer: {}", optional(&json["normalizer"]))?;
+    writeln!(description, "pre_tokenizer: {}", optional(&json["pre_tokenizer"]))?;
+    writeln!(description, "model: {}", describe_model(tokenizer, &json["model"]))?;
+    let added_tokens = tokenizer.get_added_tokens_decoder();
+    writeln!(
+        description,
+        "added_tokens: {} ({} special)",
+        added_tokens.len(),
+        added_tokens.values().filter(|token| token.special).count(),
+    )?;
+    writeln!(description, "post_processor: {}", optional(&json["post_processor"]))?;
+    writeln!(description, "decoder: {}", optional(&json["decoder"]))?;
+    for key in ["padding", "truncation"] {
+        match json[key].as_object() {
+            Some(params) => writeln!(description, "{}: {}", key, describe_params(params, &[]))?,
+            None => writeln!(description, "{}: none", key)?,
+        }
+    }
+    Ok(description)
+}
+
+/// describe returns a concise multi-line description of the pipeline of the tokenizer, for logs and support
+/// tickets: one line for each of the normalizer, pre-tokenizer, model (type, vocabulary size and its main
+/// parameters), added tokens, post-processor (with its template), decoder, padding and truncation. Sequences of
+/// components are listed in order, and large parameters (e.g. the vocabulary) are summarized.
+///
+/// The format is meant for humans, and it may change between versions.
+///
+/// It returns null if the tokenizer is invalid. The returned string needs to be freed with `free_string`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("describe"))]
+pub unsafe extern "C" fn describe(tokenizer_ptr: *mut TokenizerHandle) -> *mut c_char {
+    match catch_panic(|| describe_impl(tokenizer_ptr)) {
+        Ok(description) => CString::new(description).map_or(null_mut(), CString::into_raw),
+        Err(_) => null_mut(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::testing::{take_error, TestHandle, WORDPIECE_BERT};
+
+    #[test]
+    fn describe_pipeline() {
+        let handle = TestHandle::new(WORDPIECE_BERT);
+        let description = take_error(unsafe { describe(handle.0) }).unwrap();
+        assert_eq!(description, concat!(
+            "normalizer: none\n",
+            "pre_tokenizer: Whitespace\n",
+            "model: WordPiece(vocab_size=12, continuing_subword_prefix=\"##\", max_input_chars_per_word=100, ",
+            "unk_token=\"[UNK]\")\n",
+            "added_tokens: 3 (3 special)\n",
+            "post_processor: TemplateProcessing(single=\"[CLS] $A [SEP]\", pair=\"[CLS] $A [SEP] $B:1 [SEP]:1\", ",
+            "special_tokens=2)\n",
+            "decoder: WordPiece(cleanup=true, prefix=\"##\")\n",
+            "padding: none\n",
+            "truncation: none\n",
+        ));
+        assert!(unsafe { describe(null_mut()) }.is_null());
+
+        let sequence = json!({"type": "Sequence", "normalizers": [
+            {"type": "Precompiled", "precompiled_charsmap": "AAAA"},
+            {"type": "Replace", "pattern": {"Regex": " {2,}"}, "content": "x".repeat(50)},
+            {"type": "Prepend", "prepend": [1, 2, 3, 4, 5, 6, 7, 8, 9]},
+        ]});
+        let replace = format!(r#"Replace(content="{}"..., pattern=Regex(" {{2,}}"))"#, "x".repeat(40));
+        let expected = format!("Sequence[Precompiled, {}, Prepend(prepend=[9 items])]", replace);
+        assert_eq!(describe_component(&sequence), expected);
+    }
+}
@@ -0,0 +1,249 @@
+//! Encoding of structured documents: a list of text fields (e.g. the title, body and url of a web page for a
+//! ranker) encoded in one sequence, with separators between the fields and per-field type ids.
+
+use std::error::Error;
+use tokenizers::tokenizer::{OffsetType, Tokenizer};
+use tokenizers::utils::padding::pad_encodings;
+use tokenizers::Encoding;
+use crate::compat::{template_special_tokens, TemplateToken};
+use crate::encode::{
+    encode_process, err, result_to_encode_results_v2, tokenizer_for, Buffer, EncodeParams, EncodeResultsV2, Offset,
+    ENCODE_ADD_SPECIAL_TOKENS, ENCODE_RETURN_DEBUG_INFO, ENCODE_RETURN_WORD_STARTS, ENCODE_SKIP_NORMALIZATION,
+    ENCODE_WITH_OFFSETS_CHAR_MODE,
+};
+use crate::handle::{convert_to_handle_ref, TokenizerHandle};
+use crate::panics::catch_panic;
+use crate::words::WordStarts;
+
+/// DocumentField is one field of a document encoded with `encode_document`.
+#[repr(C)]
+pub struct DocumentField {
+    text: *const u8,
+    len: u32,
+    type_id: u32,  // Type (segment) id of the tokens of the field.
+    max_tokens: u32,  // Maximum number of tokens of the field, the rest are dropped; 0 for no limit.
+}
+
+// special_tokens returns the encoding of the special `tokens`, with the given `type_id`.
+pub(crate) fn special_tokens(tokens: &[(u32, String)], type_id: u32) -> Encoding {
+    let len = tokens.len();
+    Encoding::new(
+        tokens.iter().map(|(id, _)| *id).collect(),
+        vec![type_id; len],
+        tokens.iter().map(|(_, token)| token.clone()).collect(),
+        vec![None; len],
+        vec![(0, 0); len],
+        vec![1; len],
+        vec![1; len],
+        Vec::new(),
+        Default::default(),
+    )
+}
+
+// encode_field returns the encoding of the field `index` with the `text`, with its type id and sequence id (the
+// index of the field), and its word ids starting at `first_word`.
+fn encode_field(
+    tokenizer: &Tokenizer,
+    field: &DocumentField,
+    text: &str,
+    index: usize,
+    first_word: u32,
+    options: &EncodeParams,
+) -> Result<Encoding, Box<dyn Error>> {
+    let offsets_type = if options.has(ENCODE_WITH_OFFSETS_CHAR_MODE) { OffsetType::Char } else { OffsetType::Byte };
+    let encoding = crate::profile::encode_sequence(
+        tokenizer,
+        text,
+        field.type_id,
+        offsets_type,
+        !options.has(ENCODE_SKIP_NORMALIZATION),
+    )
+    .map_err(|e| err(format!("encoding field #{} failed: {}", index, e)))?;
+    let len = match field.max_tokens {
+        0 => encoding.len(),
+        max_tokens => encoding.len().min(max_tokens as usize),
+    };
+    let mut encoding = Encoding::new(
+        encoding.get_ids()[..len].to_vec(),
+        encoding.get_type_ids()[..len].to_vec(),
+        encoding.get_tokens()[..len].to_vec(),
+        encoding.get_word_ids()[..len].iter().map(|word| word.map(|word| word + first_word)).collect(),
+        encoding.get_offsets()[..len].to_vec(),
+        encoding.get_special_tokens_mask()[..len].to_vec(),
+        encoding.get_attention_mask()[..len].to_vec(),
+        Vec::new(),
+        Default::default(),
+    );
+    encoding.set_sequence_id(index);
+    Ok(encoding)
+}
+
+fn encode_document_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    fields: &[DocumentField],
+    separator_ids: &[u32],
+    options: EncodeParams,
+    field_spans: *mut Offset,
+) -> Result<Vec<Buffer>, Box<dyn Error>> {
+    options.validate()?;
+    let texts = fields
+        .iter()
+        .enumerate()
+        .map(|(index, field)| {
+            let bytes = if field.len == 0 {
+                &[][..]
+            } else {
+                unsafe { std::slice::from_raw_parts(field.text, field.len as usize) }
+            };
+            std::str::from_utf8(bytes).map_err(|e| err(format!("field #{} is not valid UTF-8: {}", index, e)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    state.encode_limits().check(std::iter::once(texts.iter().map(|text| text.len()).sum()))?;
+    let tokenizer = tokenizer_for(&state, &options);
+    let separator = separator_ids
+        .iter()
+        .map(|&id| match tokenizer.id_to_token(id) {
+            Some(token) => Ok((id, token)),
+            None => Err(err(format!("separator id {} is not in the vocabulary", id))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let (bos, eos) = if options.has(ENCODE_ADD_SPECIAL_TOKENS) {
+        let template = template_special_tokens(tokenizer)?;
+        let tokens = |tokens: Vec<TemplateToken>| tokens.into_iter().map(|t| (t.id, t.token)).collect::<Vec<_>>();
+        (tokens(template.bos), tokens(template.eos))
+    } else {
+        (Vec::new(), Vec::new())
+    };
+
+    let first_type_id = fields.first().map_or(0, |field| field.type_id);
+    let mut encoding = special_tokens(&bos, first_type_id);
+    let mut spans = Vec::with_capacity(fields.len());
+    let mut num_words = 0;
+    for (index, (field, text)) in fields.iter().zip(&texts).enumerate() {
+        if index > 0 {
+            encoding.merge_with(special_tokens(&separator, fields[index - 1].type_id), false);
+        }
+        let field_encoding = encode_field(tokenizer, field, text, index, num_words, &options)?;
+        num_words = field_encoding.get_word_ids().iter().flatten().max().map_or(num_words, |&word| word + 1);
+        let start = encoding.len();
+        encoding.merge_with(field_encoding, false);
+        spans.push(Offset::new((start, encoding.len()))?);
+    }
+    let last_type_id = fields.last().map_or(0, |field| field.type_id);
+    encoding.merge_with(special_tokens(&eos, last_type_id), false);
+    if let Some(padding) = tokenizer.get_padding() {
+        pad_encodings(std::slice::from_mut(&mut encoding), padding)
+            .map_err(|e| err(format!("padding failed: {}", e)))?;
+        // Padding to the left shifts the tokens of the fields.
+        let shift = encoding.get_attention_mask().iter().take_while(|&&mask| mask == 0).count() as u32;
+        spans.iter_mut().for_each(|span| *span = Offset { start: span.start + shift, end: span.end + shift });
+    }
+    if let Some(stats) = state.sequence_stats() {
+        stats.record(std::slice::from_ref(&encoding));
+    }
+
+    let scores = options.has(ENCODE_RETURN_DEBUG_INFO).then(|| state.token_scores());
+    let word_starts = options.has(ENCODE_RETURN_WORD_STARTS).then(|| WordStarts::new(tokenizer));
+    let buffer = encode_process(encoding, &texts, &options, scores.as_deref(), word_starts.as_ref())?;
+    if !field_spans.is_null() {
+        for (index, span) in spans.into_iter().enumerate() {
+            unsafe { field_spans.add(index).write(span) };
+        }
+    }
+    Ok(vec![buffer])
+}
+
+/// encode_document encodes the `num_fields` fields of a structured document (e.g. the title, body and url of a
+/// web page for a ranker) in one sequence, using given tokenizer and EncodeParams, and returns an EncodeResultsV2
+/// with one Buffer:
+///
+/// - The tokens of each field get the `type_id` of the field, and are limited to its `max_tokens` (if not 0).
+/// - The `num_separator_ids` tokens `separator_ids` (e.g. the id of "[SEP]") are inserted between the fields,
+///   as special tokens with the type id of the previous field.
+/// - If ENCODE_ADD_SPECIAL_TOKENS is set, the special tokens the post-processor adds before and after a single
+///   sequence (e.g. "[CLS]" and "[SEP]") are added before the first field and after the last one.
+/// - The padding of the tokenizer is applied, but not its truncation: use the `max_tokens` of the fields instead.
+///
+/// The offsets of the tokens are into the text of their field, and the word ids are numbered across all the
+/// fields. The span of the tokens of each field, as token indices, is written to the `num_fields` entries of
+/// `field_spans`, if not null.
+///
+/// The results must be freed with `free_encode_results_v2`.
+///
+/// # Safety
+///
+/// `fields` must point to `num_fields` DocumentField, each with `len` bytes of `text`, `separator_ids` to
+/// `num_separator_ids` ids, and `field_spans` must be null or point to space for `num_fields` Offset.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encode_document"))]
+pub unsafe extern "C" fn encode_document(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_fields: u32,
+    fields: *const DocumentField,
+    num_separator_ids: u32,
+    separator_ids: *const u32,
+    options: EncodeParams,
+    field_spans: *mut Offset,
+) -> EncodeResultsV2 {
+    let fields = if num_fields == 0 {
+        &[][..]
+    } else {
+        unsafe { std::slice::from_raw_parts(fields, num_fields as usize) }
+    };
+    let separator_ids = if num_separator_ids == 0 {
+        &[][..]
+    } else {
+        unsafe { std::slice::from_raw_parts(separator_ids, num_separator_ids as usize) }
+    };
+    result_to_encode_results_v2(
+        catch_panic(|| encode_document_impl(tokenizer_ptr, fields, separator_ids, options, field_spans)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{free_encode_results_v2, ENCODE_PARAMS_VERSION, ENCODE_RETURN_TYPE_IDS, ENCODE_RETURN_WORDS};
+    use crate::testing::{take_error, TestHandle, WORDPIECE_BERT};
+
+    fn field(text: &str, type_id: u32, max_tokens: u32) -> DocumentField {
+        DocumentField { text: text.as_ptr(), len: text.len() as u32, type_id, max_tokens }
+    }
+
+    #[test]
+    fn encode_fields() {
+        let handle = TestHandle::new(WORDPIECE_BERT);
+        let fields = [field("hello world", 0, 0), field("ab a b", 1, 2), field("", 2, 0)];
+        let separator = [11];
+        let flags = ENCODE_ADD_SPECIAL_TOKENS | ENCODE_RETURN_TYPE_IDS | ENCODE_RETURN_WORDS;
+        let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags };
+        let mut spans = [Offset { start: 99, end: 99 }, Offset { start: 99, end: 99 }, Offset { start: 99, end: 99 }];
+        let results = unsafe {
+            encode_document(handle.0, 3, fields.as_ptr(), 1, separator.as_ptr(), options, spans.as_mut_ptr())
+        };
+        assert!(results.error.is_null());
+        let buffer = unsafe { &*results.encoded };
+        let values = |ptr| unsafe { std::slice::from_raw_parts(ptr, buffer.len as usize) }.to_vec();
+        // [CLS] hello world [SEP] a ##b [SEP] [SEP]: the last field is empty.
+        assert_eq!(values(buffer.ids), [10, 4, 6, 11, 1, 3, 11, 11]);
+        assert_eq!(values(buffer.type_ids), [0, 0, 0, 0, 1, 1, 1, 2]);
+        let word_ids = unsafe { std::slice::from_raw_parts(buffer.word_ids, buffer.len as usize) };
+        assert_eq!(word_ids, [-1, 0, 1, -1, 2, 2, -1, -1]);
+        let spans: Vec<(u32, u32)> = spans.iter().map(|span| (span.start, span.end)).collect();
+        assert_eq!(spans, [(1, 3), (4, 6), (7, 7)]);
+        unsafe { free_encode_results_v2(results) };
+
+        let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags: 0 };
+        let results = unsafe {
+            encode_document(handle.0, 2, fields.as_ptr(), 1, [99].as_ptr(), options, std::ptr::null_mut())
+        };
+        assert_eq!(take_error(results.error).as_deref(), Some("separator id 99 is not in the vocabulary"));
+        let invalid_utf8 = DocumentField { text: b"\xff".as_ptr(), len: 1, type_id: 0, max_tokens: 0 };
+        let invalid = [field("hello", 0, 0), invalid_utf8];
+        let results = unsafe {
+            encode_document(handle.0, 2, invalid.as_ptr(), 0, std::ptr::null(), options, std::ptr::null_mut())
+        };
+        assert!(take_error(results.error).unwrap().starts_with("field #1 is not valid UTF-8"));
+    }
+}
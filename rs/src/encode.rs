@@ -1,22 +1,141 @@
+use crate::debug::TokenScores;
 use crate::free_string;
-use std::ffi::CStr;
+use crate::handle::{convert_to_handle_ref, TokenizerHandle, TokenizerState};
+use crate::limits::TokenBudgetExceeded;
+use crate::panics::{catch_panic, catch_panic_or, error_c_string};
+use crate::words::WordStarts;
+use std::borrow::Cow;
+use std::ffi::{c_char, CStr};
 use std::ptr::null_mut;
+use std::sync::Arc;
+use std::time::Instant;
 use tokenizers::Encoding;
-use tokenizers::tokenizer::Tokenizer;
+use tokenizers::tokenizer::{EncodeInput, OffsetType, Tokenizer};
 use std::error::Error;
 
+/// Version of the EncodeParams struct implemented by this library.
+pub const ENCODE_PARAMS_VERSION: u32 = 1;
+
+/// Flags for `EncodeParams.flags`.
+pub const ENCODE_ADD_SPECIAL_TOKENS: u64 = 1 << 0;
+pub const ENCODE_RETURN_TOKENS: u64 = 1 << 1;
+pub const ENCODE_RETURN_TYPE_IDS: u64 = 1 << 2;
+pub const ENCODE_RETURN_SPECIAL_TOKENS_MASK: u64 = 1 << 3;
+pub const ENCODE_RETURN_ATTENTION_MASK: u64 = 1 << 4;
+pub const ENCODE_RETURN_OFFSETS: u64 = 1 << 5;
+pub const ENCODE_WITH_OFFSETS_CHAR_MODE: u64 = 1 << 6;
+pub const ENCODE_RETURN_TOKEN_CHAR_LENGTHS: u64 = 1 << 7;
+/// Offsets are returned in UTF-16 code units (as used by JavaScript and JVM strings), instead of bytes.
+/// It can't be combined with ENCODE_WITH_OFFSETS_CHAR_MODE.
+pub const ENCODE_WITH_OFFSETS_UTF16_MODE: u64 = 1 << 8;
+/// Return the overflowing windows of truncated sentences (see `Buffer.overflowing`), and the span of
+/// the original text covered by each window (see `Buffer.window`).
+pub const ENCODE_RETURN_OVERFLOWING: u64 = 1 << 9;
+/// Special tokens (those with `special_tokens_mask` set) report an empty offset at the boundary of the
+/// text next to them: the end of the previous non-special token, or for leading special tokens, the start
+/// of the first non-special token. By default special tokens always report the offset (0, 0).
+pub const ENCODE_SPECIAL_TOKENS_BOUNDARY_OFFSETS: u64 = 1 << 10;
+/// Return per-token debug information about the segmentation: the pre-token each token came from
+/// (`Buffer.word_ids`) and its model score (`Buffer.token_scores`).
+pub const ENCODE_RETURN_DEBUG_INFO: u64 = 1 << 11;
+/// Special token strings in the text (e.g. "<|im_end|>") are encoded as plain text, instead of as the
+/// special tokens, overriding the tokenizer setting (see `set_encode_special_tokens`). It can't be combined
+/// with ENCODE_MATCH_SPECIAL_TOKENS.
+pub const ENCODE_SPLIT_SPECIAL_TOKENS: u64 = 1 << 12;
+/// Special token strings in the text are encoded as the special tokens, overriding the tokenizer setting
+/// (see `set_encode_special_tokens`).
+pub const ENCODE_MATCH_SPECIAL_TOKENS: u64 = 1 << 13;
+
+/// Return the length of the sequence before padding (see `Buffer.length`), as `return_length` in transformers.
+pub const ENCODE_RETURN_LENGTH: u64 = 1 << 14;
+/// The matrices of `encode_batch_padded` hold i32 values (see `PaddedBatch.dtype`), instead of u32. It fails
+/// if an id doesn't fit. It can't be combined with ENCODE_OUTPUT_INT64, and it is ignored by the other functions.
+pub const ENCODE_OUTPUT_INT32: u64 = 1 << 15;
+/// The matrices of `encode_batch_padded` hold i64 values (see `PaddedBatch.dtype`), instead of u32. It is
+/// ignored by the other functions.
+pub const ENCODE_OUTPUT_INT64: u64 = 1 << 16;
+/// Return the words (pre-tokens) of the text (see `Buffer.words`), and the word each token came from (see
+/// `Buffer.word_ids`), e.g. for whole-word masking.
+pub const ENCODE_RETURN_WORDS: u64 = 1 << 17;
+/// Skip the normalizer of the tokenizer, for texts already normalized upstream: the offsets are then into the
+/// exact text given. The rest of the pipeline (added tokens, pre-tokenizer, model and post-processor) is unchanged.
+pub const ENCODE_SKIP_NORMALIZATION: u64 = 1 << 18;
+/// Return whether each token starts a new word (see `Buffer.word_starts`), e.g. for whole-word masking.
+pub const ENCODE_RETURN_WORD_STARTS: u64 = 1 << 19;
+/// The attention and special tokens masks (if requested) are returned bit-packed, 1 bit per token, in
+/// `Buffer.attention_mask_bits` and `Buffer.special_tokens_mask_bits`, instead of `Buffer.attention_mask` and
+/// `Buffer.special_tokens_mask`, for very long sequences. It is ignored by `encode_batch_padded`.
+pub const ENCODE_PACKED_MASKS: u64 = 1 << 20;
+/// Special tokens (those with `special_tokens_mask` set) report the offset (NO_OFFSET, NO_OFFSET), instead of
+/// (0, 0), so only the content tokens have offsets into the text. It can't be combined with
+/// ENCODE_SPECIAL_TOKENS_BOUNDARY_OFFSETS.
+pub const ENCODE_SPECIAL_TOKENS_NO_OFFSETS: u64 = 1 << 21;
+/// Return the word (pre-token) each token came from (see `Buffer.word_ids`), without the debug information of
+/// ENCODE_RETURN_DEBUG_INFO or the word spans of ENCODE_RETURN_WORDS, as `word_ids()` in transformers.
+pub const ENCODE_RETURN_WORD_IDS: u64 = 1 << 22;
+/// Return the sequence (0 for the first text, 1 for the second text of a pair) each token came from (see
+/// `Buffer.sequence_ids`), as `sequence_ids()` in transformers.
+pub const ENCODE_RETURN_SEQUENCE_IDS: u64 = 1 << 23;
+
+// All flags known by this library version.
+const ENCODE_KNOWN_FLAGS: u64 = (1 << 24) - 1;
+
+/// NO_OFFSET is the start and end of the offsets of the special tokens with ENCODE_SPECIAL_TOKENS_NO_OFFSETS.
+pub const NO_OFFSET: u32 = u32::MAX;
+
 /// EncodeParams specifies what information to return from the
 /// encoded sentences.
 /// It controls which fields in Buffer are set.
+///
+/// `flags` is a bitmask of the `ENCODE_*` flags, and `version` should be set to
+/// the `ENCODE_PARAMS_VERSION` the caller was compiled with: new options are added as
+/// new flags, so older callers keep working with newer versions of the library.
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct EncodeParams {
-    add_special_tokens: bool,
-    return_tokens: bool,
-    return_type_ids: bool,
-    return_special_tokens_mask: bool,
-    return_attention_mask: bool,
-    return_offsets: bool,
-    with_offsets_char_mode: bool,
+    pub(crate) version: u32,
+    pub(crate) flags: u64,
+}
+
+impl EncodeParams {
+    #[cfg(feature = "wasm")]
+    pub(crate) fn new(flags: u64) -> Self {
+        EncodeParams {
+            version: ENCODE_PARAMS_VERSION,
+            flags,
+        }
+    }
+
+    // has returns whether the given flag is set.
+    pub(crate) fn has(&self, flag: u64) -> bool {
+        self.flags & flag != 0
+    }
+
+    // validate checks that the version and the flags are supported by this library.
+    pub(crate) fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.version == 0 || self.version > ENCODE_PARAMS_VERSION {
+            return Err(err(format!(
+                "EncodeParams version {} not supported, this library supports up to version {}",
+                self.version, ENCODE_PARAMS_VERSION)));
+        }
+        let unknown = self.flags & !ENCODE_KNOWN_FLAGS;
+        if unknown != 0 {
+            return Err(err(format!("EncodeParams flags {:#x} not supported by this library", unknown)));
+        }
+        if self.has(ENCODE_WITH_OFFSETS_CHAR_MODE) && self.has(ENCODE_WITH_OFFSETS_UTF16_MODE) {
+            return Err(err("EncodeParams flags ENCODE_WITH_OFFSETS_CHAR_MODE and ENCODE_WITH_OFFSETS_UTF16_MODE are exclusive"));
+        }
+        if self.has(ENCODE_SPLIT_SPECIAL_TOKENS) && self.has(ENCODE_MATCH_SPECIAL_TOKENS) {
+            return Err(err("EncodeParams flags ENCODE_SPLIT_SPECIAL_TOKENS and ENCODE_MATCH_SPECIAL_TOKENS are exclusive"));
+        }
+        if self.has(ENCODE_SPECIAL_TOKENS_BOUNDARY_OFFSETS) && self.has(ENCODE_SPECIAL_TOKENS_NO_OFFSETS) {
+            return Err(err("EncodeParams flags ENCODE_SPECIAL_TOKENS_BOUNDARY_OFFSETS and ENCODE_SPECIAL_TOKENS_NO_OFFSETS are exclusive"));
+        }
+        if self.has(ENCODE_OUTPUT_INT32) && self.has(ENCODE_OUTPUT_INT64) {
+            return Err(err("EncodeParams flags ENCODE_OUTPUT_INT32 and ENCODE_OUTPUT_INT64 are exclusive"));
+        }
+        Ok(())
+    }
 }
 
 /// EncodeResult represents the result of encoding one (`encode` function)
@@ -30,7 +149,20 @@ pub struct EncodeParams {
 pub struct EncodeResults {
     len: u32,
     encoded: *mut Buffer,
-    error: *mut libc::c_char,
+    error: *mut c_char,
+}
+
+/// EncodeResultsV2 is the 64-bit version of EncodeResults, returned by `encode_v2` and
+/// `encode_batch_v2`: the number of results and the `total_tokens` over all of them are u64, so
+/// batches with more than 4G tokens can be represented.
+///
+/// Once it is no longer used, free the data with `free_encode_results_v2`.
+#[repr(C)]
+pub struct EncodeResultsV2 {
+    pub(crate) len: u64,
+    pub(crate) total_tokens: u64,
+    pub(crate) encoded: *mut Buffer,
+    pub(crate) error: *mut c_char,
 }
 
 /// Buffer represents the result of an encoded sentence.
@@ -38,90 +170,229 @@ pub struct EncodeResults {
 /// EncodeParams setting.
 #[repr(C)]
 pub struct Buffer {
-    ids: *mut u32,
-    type_ids: *mut u32,
-    special_tokens_mask: *mut u32,
-    attention_mask: *mut u32,
-    tokens: *mut *mut libc::c_char,
-    offsets: *mut Offset,
-    len: u32,
+    pub(crate) ids: *mut u32,
+    pub(crate) type_ids: *mut u32,
+    pub(crate) special_tokens_mask: *mut u32,
+    pub(crate) attention_mask: *mut u32,
+    pub(crate) tokens: *mut *mut c_char,
+    pub(crate) offsets: *mut Offset,
+    pub(crate) len: u32,
+
+    // Only set if ENCODE_RETURN_DEBUG_INFO is set (`word_ids` also if ENCODE_RETURN_WORDS or
+    // ENCODE_RETURN_WORD_IDS is set).
+    //
+    // `word_ids` holds the index of the pre-token (word) each token came from, or -1 for special tokens.
+    // `token_scores` holds the model score of each token: the merge rank for BPE models (lower merges first),
+    // the log-probability for Unigram models, and NaN if not available (e.g.: other models, or BPE tokens
+    // of the initial alphabet).
+    pub(crate) word_ids: *mut i64,
+    pub(crate) token_scores: *mut f64,
+
+    // Only set if ENCODE_RETURN_OVERFLOWING is set.
+    //
+    // `window` is the span of the original text covered by the (non-special) tokens of this buffer, in
+    // the same units as the offsets, and `overflowing` holds `num_overflowing` buffers with the windows
+    // that didn't fit the truncation `max_length`, in order.
+    //
+    // The offsets of all windows (including the overflowing ones) always refer to the full original text,
+    // so they can be projected back to it directly.
+    pub(crate) window: Offset,
+    pub(crate) overflowing: *mut Buffer,
+    pub(crate) num_overflowing: u64,
+
+    // `tokens_arena` holds the `tokens_arena_len` bytes of all the (null-terminated) `tokens` strings, which
+    // point into it: they are allocated at once, and freed with the Buffer.
+    pub(crate) tokens_arena: *mut c_char,
+    pub(crate) tokens_arena_len: u64,
+
+    // Only set if ENCODE_RETURN_LENGTH is set.
+    //
+    // `length` is the number of tokens before padding (those with the attention mask set): with padding,
+    // the ids after them (or before them, if padding to the left) are padding.
+    pub(crate) length: u64,
+
+    // Only set if ENCODE_RETURN_WORDS is set.
+    //
+    // `words` holds the `num_words` spans of the words (pre-tokens) of the text, indexed by `word_ids`, in the
+    // same units as the offsets. Words without tokens in this buffer (e.g. those of other overflowing windows)
+    // have an empty span (0, 0).
+    pub(crate) words: *mut Offset,
+    pub(crate) num_words: u64,
+
+    // Only set if ENCODE_RETURN_WORD_STARTS is set.
+    //
+    // `word_starts` is 1 for the tokens that start a new word, and 0 for the tokens continuing the word of the
+    // previous one (e.g. "##ing" in WordPiece, or the pieces without the "Ġ"/"▁" space marker in byte-level and
+    // SentencePiece tokenizers) and for special tokens. It is decided by the model and pipeline of the tokenizer.
+    pub(crate) word_starts: *mut u32,
+
+    // Only set if ENCODE_PACKED_MASKS is set, instead of `attention_mask` and `special_tokens_mask` (each only if
+    // requested).
+    //
+    // The masks packed with 1 bit per token, in `(len + 7) / 8` bytes: the token `i` is the bit `i % 8` (least
+    // significant first) of the byte `i / 8`.
+    pub(crate) attention_mask_bits: *mut u8,
+    pub(crate) special_tokens_mask_bits: *mut u8,
+
+    // Only set if ENCODE_RETURN_SEQUENCE_IDS is set.
+    //
+    // `sequence_ids` holds the index of the text each token came from: 0 for the first text, 1 for the second text
+    // of a pair, or -1 for special tokens (and padding), as `word_ids`.
+    pub(crate) sequence_ids: *mut i64,
+
+    // Only set if ENCODE_RETURN_TOKEN_CHAR_LENGTHS is set.
+    //
+    // `token_char_lengths` holds the number of characters of the text spanned by each token (0 for special tokens,
+    // which don't span any).
+    pub(crate) token_char_lengths: *mut u32,
 }
 
 /// Offset of the toke in the sentence.
 /// The Go library limits this to u32 -- we don't expect sentences larger than ~4GB.
 #[repr(C)]
 pub struct Offset {
-    start: u32,
-    end: u32,
-}
-
-fn encode_process(encoding: Encoding, options: &EncodeParams) -> Result<Buffer, Box<dyn Error>> {
-    // ids, tokens
-    let mut vec_ids = encoding.get_ids().to_vec();
-    vec_ids.shrink_to_fit();
-    let ids = vec_ids.as_mut_ptr();
-    let len = vec_ids.len();
-    std::mem::forget(vec_ids);
-
-    let tokens: *mut *mut libc::c_char;
-    if options.return_tokens {
-        let tokens_string = encoding.get_tokens();
-        let mut vec_tokens: Vec<*mut libc::c_char> = Vec::with_capacity(tokens_string.len());
-        for token in tokens_string {
-            vec_tokens.push(std::ffi::CString::new(token.as_bytes())?.into_raw());
-        }
-        vec_tokens.shrink_to_fit();
-        tokens = vec_tokens.as_mut_ptr();
-        std::mem::forget(vec_tokens);
-    } else {
-        tokens = null_mut();
-    }
+    pub(crate) start: u32,
+    pub(crate) end: u32,
+}
 
-    // type_ids
-    let type_ids: *mut u32;
-    if options.return_type_ids {
-        let mut vec_type_ids = encoding.get_type_ids().to_vec();
-        vec_type_ids.shrink_to_fit();
-        type_ids = vec_type_ids.as_mut_ptr();
-        std::mem::forget(vec_type_ids);
-    } else {
-        type_ids = null_mut();
+impl Offset {
+    pub(crate) fn new(span: (usize, usize)) -> Result<Offset, Box<dyn Error>> {
+        match (u32::try_from(span.0), u32::try_from(span.1)) {
+            (Ok(start), Ok(end)) => Ok(Offset { start, end }),
+            _ => Err(err(format!("offset ({}, {}) overflows u32", span.0, span.1))),
+        }
     }
+}
 
-    // special_tokens_mask
-    let mut special_tokens_mask: *mut u32 = null_mut();
-    if options.return_special_tokens_mask {
-        let mut vec_special_tokens_mask = encoding.get_special_tokens_mask().to_vec();
-        vec_special_tokens_mask.shrink_to_fit();
-        special_tokens_mask = vec_special_tokens_mask.as_mut_ptr();
-        std::mem::forget(vec_special_tokens_mask);
-    }
+// buffer_len returns the `len` of the Buffer of an encoding of `num_tokens` tokens, or an error if it overflows
+// u32: only the counts over all the results (e.g. `EncodeResultsV2::total_tokens`) are 64 bits.
+pub(crate) fn buffer_len(num_tokens: usize) -> Result<u32, Box<dyn Error>> {
+    u32::try_from(num_tokens).map_err(|_| err(format!("{} tokens overflow the len of a Buffer (u32)", num_tokens)))
+}
 
-    // attention mask
-    let mut attention_mask: *mut u32 = null_mut();
-    if options.return_attention_mask {
-        let mut vec_attention_mask = encoding.get_attention_mask().to_vec();
-        vec_attention_mask.shrink_to_fit();
-        attention_mask = vec_attention_mask.as_mut_ptr();
-        std::mem::forget(vec_attention_mask);
+// encode_process converts the `encoding` of `texts` (one text, or the two texts of a pair) to a Buffer, with the
+// fields requested in `options`.
+//
+// The fields that can fail to convert are converted first, so nothing is leaked on errors.
+//
+// `scores` is only used if ENCODE_RETURN_DEBUG_INFO is set, and `word_starts` if ENCODE_RETURN_WORD_STARTS is set.
+pub(crate) fn encode_process(
+    mut encoding: Encoding,
+    texts: &[&str],
+    options: &EncodeParams,
+    scores: Option<&TokenScores>,
+    word_starts: Option<&WordStarts>,
+) -> Result<Buffer, Box<dyn Error>> {
+    let len = buffer_len(encoding.get_ids().len())?;
+
+    // tokens
+    let mut tokens_arena: Option<Vec<u8>> = None;
+    if options.has(ENCODE_RETURN_TOKENS) {
+        tokens_arena = Some(tokens_arena_from(encoding.get_tokens())?);
     }
 
     // offsets
-    let mut offsets: *mut Offset = null_mut();
-    if options.return_offsets {
-        let mut vec_offsets = encoding
-            .get_offsets()
+    let mut vec_offsets: Option<Vec<Offset>> = None;
+    if options.has(ENCODE_RETURN_OFFSETS) {
+        let offsets: Result<Vec<_>, _> = get_offsets(&encoding, texts, options)
             .iter()
-            .map(|s| Offset {
-                start: s.0 as u32,
-                end: s.1 as u32,
-            })
-            .collect::<Vec<_>>();
-        vec_offsets.shrink_to_fit();
-        offsets = vec_offsets.as_mut_ptr();
-        std::mem::forget(vec_offsets);
+            .map(|&span| Offset::new(span))
+            .collect();
+        vec_offsets = Some(offsets?);
+    }
+
+    // words
+    let mut vec_words: Option<Vec<Offset>> = None;
+    if options.has(ENCODE_RETURN_WORDS) {
+        let words: Result<Vec<_>, _> = get_words(&encoding, &get_offsets(&encoding, texts, options))
+            .into_iter()
+            .map(Offset::new)
+            .collect();
+        vec_words = Some(words?);
     }
 
+    // window and overflowing
+    let mut window = Offset { start: 0, end: 0 };
+    let mut vec_overflowing: Vec<Buffer> = Vec::new();
+    if options.has(ENCODE_RETURN_OVERFLOWING) {
+        window = Offset::new(get_window(&encoding, &get_offsets(&encoding, texts, options)))?;
+        for overflow in encoding.take_overflowing() {
+            match encode_process(overflow, texts, options, scores, word_starts) {
+                Ok(buf) => vec_overflowing.push(buf),
+                Err(e) => {
+                    vec_overflowing.into_iter().for_each(free_buffer);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    // From here on nothing fails: convert vectors to raw pointers owned by the Buffer.
+    let ids = vec_into_raw(encoding.get_ids().to_vec());
+    let (tokens, tokens_arena, tokens_arena_len) = match tokens_arena {
+        Some(arena) => {
+            let arena_len = arena.len() as u64;
+            let arena: *mut c_char = vec_into_raw(arena).cast();
+            let mut start = 0;
+            let tokens = encoding.get_tokens().iter().map(|token| {
+                let ptr = unsafe { arena.add(start) };
+                start += token.len() + 1;
+                ptr
+            });
+            (vec_into_raw(tokens.collect()), arena, arena_len)
+        }
+        None => (null_mut(), null_mut(), 0),
+    };
+    let type_ids = if options.has(ENCODE_RETURN_TYPE_IDS) {
+        vec_into_raw(encoding.get_type_ids().to_vec())
+    } else {
+        null_mut()
+    };
+    let packed = options.has(ENCODE_PACKED_MASKS);
+    let (special_tokens_mask, special_tokens_mask_bits) = match options.has(ENCODE_RETURN_SPECIAL_TOKENS_MASK) {
+        true if packed => (null_mut(), vec_into_raw(pack_bits(encoding.get_special_tokens_mask()))),
+        true => (vec_into_raw(encoding.get_special_tokens_mask().to_vec()), null_mut()),
+        false => (null_mut(), null_mut()),
+    };
+    let (attention_mask, attention_mask_bits) = match options.has(ENCODE_RETURN_ATTENTION_MASK) {
+        true if packed => (null_mut(), vec_into_raw(pack_bits(encoding.get_attention_mask()))),
+        true => (vec_into_raw(encoding.get_attention_mask().to_vec()), null_mut()),
+        false => (null_mut(), null_mut()),
+    };
+    let offsets = vec_offsets.map_or(null_mut(), vec_into_raw);
+    let token_char_lengths = if options.has(ENCODE_RETURN_TOKEN_CHAR_LENGTHS) {
+        vec_into_raw(get_token_char_lengths(&encoding, texts, options))
+    } else {
+        null_mut()
+    };
+    let (word_ids, token_scores) = match scores {
+        Some(scores) if options.has(ENCODE_RETURN_DEBUG_INFO) => (
+            vec_into_raw(get_word_ids(&encoding)),
+            vec_into_raw(get_token_scores(&encoding, scores)),
+        ),
+        _ if options.has(ENCODE_RETURN_WORDS | ENCODE_RETURN_WORD_IDS) => {
+            (vec_into_raw(get_word_ids(&encoding)), null_mut())
+        }
+        _ => (null_mut(), null_mut()),
+    };
+    let sequence_ids = if options.has(ENCODE_RETURN_SEQUENCE_IDS) {
+        vec_into_raw(get_sequence_ids(&encoding))
+    } else {
+        null_mut()
+    };
+    let word_starts = match word_starts {
+        Some(word_starts) if options.has(ENCODE_RETURN_WORD_STARTS) => vec_into_raw(word_starts.get(&encoding)),
+        _ => null_mut(),
+    };
+    let num_words = vec_words.as_ref().map_or(0, Vec::len) as u64;
+    let words = vec_words.map_or(null_mut(), vec_into_raw);
+    let num_overflowing = vec_overflowing.len() as u64;
+    let overflowing = if vec_overflowing.is_empty() {
+        null_mut()
+    } else {
+        vec_into_raw(vec_overflowing)
+    };
+
     Ok(Buffer {
         ids,
         type_ids,
@@ -129,14 +400,242 @@ fn encode_process(encoding: Encoding, options: &EncodeParams) -> Result<Buffer,
         attention_mask,
         tokens,
         offsets,
-        len: (len as u32),
+        token_char_lengths,
+        len,
+        word_ids,
+        token_scores,
+        window,
+        overflowing,
+        num_overflowing,
+        tokens_arena,
+        tokens_arena_len,
+        length: if options.has(ENCODE_RETURN_LENGTH) { get_length(&encoding) } else { 0 },
+        words,
+        num_words,
+        word_starts,
+        attention_mask_bits,
+        special_tokens_mask_bits,
+        sequence_ids,
     })
 }
 
+// tokens_arena_from returns the `tokens` as null-terminated strings, stored contiguously.
+fn tokens_arena_from(tokens: &[String]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut arena = Vec::with_capacity(tokens.iter().map(|token| token.len() + 1).sum());
+    for token in tokens {
+        if token.as_bytes().contains(&0) {
+            return Err(err(format!("token {:?} contains a null byte", token)));
+        }
+        arena.extend_from_slice(token.as_bytes());
+        arena.push(0);
+    }
+    Ok(arena)
+}
+
+// vec_into_raw converts the vector to a raw pointer, with capacity equal to its length: it must be
+// freed with `Vec::from_raw_parts(ptr, len, len)`.
+fn vec_into_raw<T>(mut vec: Vec<T>) -> *mut T {
+    vec.shrink_to_fit();
+    let ptr = vec.as_mut_ptr();
+    std::mem::forget(vec);
+    ptr
+}
+
+// pack_bits packs the `mask` with 1 bit per value (set if not zero), least significant bit first.
+pub(crate) fn pack_bits(mask: &[u32]) -> Vec<u8> {
+    let mut bits = vec![0_u8; mask.len().div_ceil(8)];
+    for (i, _) in mask.iter().enumerate().filter(|&(_, &value)| value != 0) {
+        bits[i / 8] |= 1 << (i % 8);
+    }
+    bits
+}
+
+// get_length returns the number of tokens of the `encoding` before padding.
+pub(crate) fn get_length(encoding: &Encoding) -> u64 {
+    encoding.get_attention_mask().iter().filter(|&&mask| mask != 0).count() as u64
+}
+
+// get_words returns the span of each word (pre-token) of the `encoding`, indexed by the word ids, given the
+// `offsets` of its tokens: (0, 0) for the words without tokens.
+pub(crate) fn get_words(encoding: &Encoding, offsets: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    let mut words: Vec<Option<(usize, usize)>> = vec![None; get_num_words(encoding)];
+    for (word, &(start, end)) in encoding.get_word_ids().iter().zip(offsets) {
+        if let Some(word) = word {
+            let span = &mut words[*word as usize];
+            *span = Some(span.map_or((start, end), |(s, e)| (s.min(start), e.max(end))));
+        }
+    }
+    words.into_iter().map(|span| span.unwrap_or((0, 0))).collect()
+}
+
+// get_num_words returns the number of words of the `encoding`, the length of `get_words`.
+pub(crate) fn get_num_words(encoding: &Encoding) -> usize {
+    encoding.get_word_ids().iter().flatten().max().map_or(0, |&word| word as usize + 1)
+}
+
+// get_word_ids returns the index of the word (pre-token) of each token of the `encoding`, or -1 if none.
+pub(crate) fn get_word_ids(encoding: &Encoding) -> Vec<i64> {
+    encoding.get_word_ids().iter().map(|word| word.map_or(-1, i64::from)).collect()
+}
+
+// get_sequence_ids returns the index of the text each token of the `encoding` came from, or -1 for special tokens.
+pub(crate) fn get_sequence_ids(encoding: &Encoding) -> Vec<i64> {
+    encoding.get_sequence_ids().iter().map(|sequence| sequence.map_or(-1, |sequence| sequence as i64)).collect()
+}
+
+// get_token_scores returns the model score of each token of the `encoding`, see `TokenScores`.
+pub(crate) fn get_token_scores(encoding: &Encoding, scores: &TokenScores) -> Vec<f64> {
+    encoding.get_ids().iter().map(|&id| scores.get(id)).collect()
+}
+
+// get_window returns the span of the original text covered by the non-special tokens of the `encoding`,
+// given its `offsets`. It returns (0, 0) if there are no such tokens.
+pub(crate) fn get_window(encoding: &Encoding, offsets: &[(usize, usize)]) -> (usize, usize) {
+    offsets
+        .iter()
+        .zip(encoding.get_special_tokens_mask())
+        .filter(|&(_, &special)| special == 0)
+        .map(|(&span, _)| span)
+        .reduce(|window, span| (window.0.min(span.0), window.1.max(span.1)))
+        .unwrap_or((0, 0))
+}
+
+// get_offsets returns the offsets of the `encoding` of `texts` (one text, or the two texts of a pair), converted to
+// UTF-16 code units if ENCODE_WITH_OFFSETS_UTF16_MODE is set, and with the offsets of special tokens following
+// the convention selected by ENCODE_SPECIAL_TOKENS_BOUNDARY_OFFSETS or ENCODE_SPECIAL_TOKENS_NO_OFFSETS.
+pub(crate) fn get_offsets<'a>(encoding: &'a Encoding, texts: &[&str], options: &EncodeParams) -> Cow<'a, [(usize, usize)]> {
+    let mut offsets = if options.has(ENCODE_WITH_OFFSETS_UTF16_MODE) {
+        let utf16_offsets: Vec<Utf16Offsets> = texts.iter().map(|text| Utf16Offsets::new(text)).collect();
+        Cow::Owned(encoding
+            .get_offsets()
+            .iter()
+            .zip(token_sequences(encoding, texts.len()))
+            .map(|(&(start, end), sequence)| {
+                (utf16_offsets[sequence].start(start), utf16_offsets[sequence].end(end))
+            })
+            .collect())
+    } else {
+        Cow::Borrowed(encoding.get_offsets())
+    };
+    set_special_tokens_offsets(encoding.get_special_tokens_mask(), &mut offsets, options);
+    offsets
+}
+
+// set_special_tokens_offsets sets the offsets of the special tokens (given by `special_tokens_mask`)
+// according to the convention selected by ENCODE_SPECIAL_TOKENS_BOUNDARY_OFFSETS or
+// ENCODE_SPECIAL_TOKENS_NO_OFFSETS.
+fn set_special_tokens_offsets(special_tokens_mask: &[u32], offsets: &mut Cow<[(usize, usize)]>, options: &EncodeParams) {
+    let is_special = |i: usize| special_tokens_mask.get(i).is_some_and(|&m| m != 0);
+    if !options.has(ENCODE_SPECIAL_TOKENS_BOUNDARY_OFFSETS) {
+        let offset = if options.has(ENCODE_SPECIAL_TOKENS_NO_OFFSETS) {
+            (NO_OFFSET as usize, NO_OFFSET as usize)
+        } else {
+            (0, 0)
+        };
+        // Only copy the offsets if there is anything to change.
+        if (0..offsets.len()).any(|i| is_special(i) && offsets[i] != offset) {
+            let offsets = offsets.to_mut();
+            (0..offsets.len()).filter(|&i| is_special(i)).for_each(|i| offsets[i] = offset);
+        }
+        return;
+    }
+    if !(0..offsets.len()).any(is_special) {
+        return;
+    }
+    let offsets = offsets.to_mut();
+    let mut boundary = match (0..offsets.len()).find(|&i| !is_special(i)) {
+        Some(first) => offsets[first].0,
+        None => 0,
+    };
+    for (i, offset) in offsets.iter_mut().enumerate() {
+        if is_special(i) {
+            *offset = (boundary, boundary);
+        } else {
+            boundary = offset.1;
+        }
+    }
+}
+
+// Utf16Offsets converts byte offsets of a text to UTF-16 code units offsets.
+struct Utf16Offsets<'a> {
+    text: &'a str,
+
+    // units[i] is the number of UTF-16 code units of the characters of `text` that end at or before byte i.
+    units: Vec<usize>,
+}
+
+impl<'a> Utf16Offsets<'a> {
+    fn new(text: &'a str) -> Self {
+        let mut units = Vec::with_capacity(text.len() + 1);
+        let mut count = 0;
+        for c in text.chars() {
+            units.extend(std::iter::repeat_n(count, c.len_utf8()));
+            count += c.len_utf16();
+        }
+        units.push(count);
+        Utf16Offsets { text, units }
+    }
+
+    // start converts the start of a span: if it is not aligned to a character, it is rounded down.
+    fn start(&self, byte_offset: usize) -> usize {
+        self.units[byte_offset.min(self.text.len())]
+    }
+
+    // end converts the end of a span: if it is not aligned to a character, it is rounded up.
+    fn end(&self, byte_offset: usize) -> usize {
+        let mut byte_offset = byte_offset.min(self.text.len());
+        while !self.text.is_char_boundary(byte_offset) {
+            byte_offset += 1;
+        }
+        self.units[byte_offset]
+    }
+}
+
+// token_sequences returns the index in the encoded texts (0 or 1 for the second text of a pair) of the text of
+// each token of the `encoding`, given the `num_texts`: the special tokens are assigned to the first.
+fn token_sequences(encoding: &Encoding, num_texts: usize) -> Vec<usize> {
+    if num_texts < 2 {
+        return vec![0; encoding.len()];
+    }
+    encoding
+        .get_sequence_ids()
+        .into_iter()
+        .map(|sequence| sequence.unwrap_or(0).min(num_texts - 1))
+        .collect()
+}
+
+// get_token_char_lengths returns the number of characters of the `texts` (one text, or the two texts of a pair)
+// spanned by each token of the `encoding`.
+pub(crate) fn get_token_char_lengths(encoding: &Encoding, texts: &[&str], options: &EncodeParams) -> Vec<u32> {
+    let char_mode = options.has(ENCODE_WITH_OFFSETS_CHAR_MODE);
+    encoding
+        .get_offsets()
+        .iter()
+        .zip(token_sequences(encoding, texts.len()))
+        .map(|(&(start, end), sequence)| {
+            if char_mode {
+                end.saturating_sub(start) as u32
+            } else {
+                count_chars_in_span(texts[sequence], start, end)
+            }
+        })
+        .collect()
+}
+
+// count_chars_in_span returns the number of UTF-8 characters that start within the byte span
+// `[start, end)` of `text`. It works even if the span is not aligned to character boundaries.
+fn count_chars_in_span(text: &str, start: usize, end: usize) -> u32 {
+    let bytes = text.as_bytes();
+    let end = end.min(bytes.len());
+    let start = start.min(end);
+    // Continuation bytes are 0b10xxxxxx, everything else starts a character.
+    bytes[start..end].iter().filter(|&&b| (b & 0xC0) != 0x80).count() as u32
+}
+
 // result_to_encode_results converts errors in a Result<EncodedResult, Error> to
 // a new `EncodeResults` struct, with the error converted to C-string.
-fn result_to_encode_results(r: Result<EncodeResults, Box<dyn Error>>) -> EncodeResults {
-    match r {
+fn result_to_encode_results(r: Result<Vec<Buffer>, Box<dyn Error>>) -> EncodeResults {
+    match r.and_then(buffers_to_encode_results) {
         Ok(encode_results) => {
             encode_results
         }
@@ -144,138 +643,591 @@ fn result_to_encode_results(r: Result<EncodeResults, Box<dyn Error>>) -> EncodeR
             EncodeResults{
                 len: 0,
                 encoded: std::ptr::null_mut(),
-                error: std::ffi::CString::new(err.to_string())
-                    .unwrap().into_raw(),
+                error: error_c_string(err),
             }
         }
     }
 }
 
-// Create an error from the given message.
-fn err<S: AsRef<str>>(message: S) -> Box<dyn Error> {
-    Box::new(std::io::Error::new(std::io::ErrorKind::Other, message.as_ref()))
+// result_to_encode_results_v2 is the `EncodeResultsV2` version of `result_to_encode_results`.
+pub(crate) fn result_to_encode_results_v2(r: Result<Vec<Buffer>, Box<dyn Error>>) -> EncodeResultsV2 {
+    match r.and_then(buffers_to_encode_results_v2) {
+        Ok(encode_results) => {
+            encode_results
+        }
+        Err(err) => {
+            EncodeResultsV2{
+                len: 0,
+                total_tokens: 0,
+                encoded: std::ptr::null_mut(),
+                error: error_c_string(err),
+            }
+        }
+    }
 }
 
-// convert_to_tokenizer_ref given a C `void *`.
-pub fn convert_to_tokenizer_ref<'a>(tokenizer_ptr: *mut libc::c_void) -> Result<&'a Tokenizer, Box<dyn Error>> {
-    unsafe {
-        if tokenizer_ptr.cast::<Tokenizer>().as_mut().is_none() {
-            return Err(err("tokenizer passed is null"));
+// buffers_to_encode_results packages the buffers into an `EncodeResults`, or returns an error
+// (freeing the buffers) if there are more than fits its u32 `len`.
+fn buffers_to_encode_results(mut buffers: Vec<Buffer>) -> Result<EncodeResults, Box<dyn Error>> {
+    let len = match u32::try_from(buffers.len()) {
+        Ok(len) => len,
+        Err(_) => {
+            let num_buffers = buffers.len();
+            buffers.into_iter().for_each(free_buffer);
+            return Err(err(format!(
+                "{} results overflow EncodeResults, use the `_v2` functions instead", num_buffers)));
         }
-        match tokenizer_ptr
-            .cast::<Tokenizer>()
-            .as_mut() {
-            Some(t) => Ok(t),
-            None => Err(err("invalid reference to Tokenizer"))
+    };
+    buffers.shrink_to_fit();
+    let encode_results = EncodeResults{
+        len,
+        encoded: buffers.as_mut_ptr(),
+        error: null_mut(),
+    };
+    std::mem::forget(buffers);
+    Ok(encode_results)
+}
+
+// buffers_to_encode_results_v2 packages the buffers into an `EncodeResultsV2`, or returns an error
+// (freeing the buffers) if the total number of tokens overflows u64.
+fn buffers_to_encode_results_v2(mut buffers: Vec<Buffer>) -> Result<EncodeResultsV2, Box<dyn Error>> {
+    let total_tokens = buffers
+        .iter()
+        .try_fold(0_u64, |total, buf| total.checked_add(u64::from(buf.len)));
+    let total_tokens = match total_tokens {
+        Some(total_tokens) => total_tokens,
+        None => {
+            buffers.into_iter().for_each(free_buffer);
+            return Err(err("total number of tokens overflows u64"));
         }
-    }
+    };
+    buffers.shrink_to_fit();
+    let encode_results = EncodeResultsV2{
+        len: buffers.len() as u64,
+        total_tokens,
+        encoded: buffers.as_mut_ptr(),
+        error: null_mut(),
+    };
+    std::mem::forget(buffers);
+    Ok(encode_results)
+}
+
+// Create an error from the given message.
+pub(crate) fn err<S: AsRef<str>>(message: S) -> Box<dyn Error> {
+    Box::new(std::io::Error::other(message.as_ref()))
 }
 
-fn encode_impl(tokenizer_ptr: *mut libc::c_void,
-                   message: *const libc::c_char,
+fn encode_impl(tokenizer_ptr: *mut TokenizerHandle,
+                   message: *const c_char,
                    options: EncodeParams,
-               ) -> Result<EncodeResults, Box<dyn Error>> {
-    let tokenizer: &Tokenizer = convert_to_tokenizer_ref(tokenizer_ptr)?;
+               ) -> Result<Vec<Buffer>, Box<dyn Error>> {
     let message_cstr = unsafe { CStr::from_ptr(message) };
-    let message = message_cstr.to_str().unwrap();
+    let message = message_cstr.to_str()?;
+    encode_str_impl(tokenizer_ptr, message, options)
+}
 
-    let encoding_res = if options.with_offsets_char_mode {
-        tokenizer.encode_char_offsets(message, options.add_special_tokens)
+fn encode_bytes_impl(tokenizer_ptr: *mut TokenizerHandle,
+                     bytes: *const u8,
+                     len: usize,
+                     options: EncodeParams,
+               ) -> Result<Vec<Buffer>, Box<dyn Error>> {
+    let bytes_slice: &[u8] = if len == 0 {
+        &[]
     } else {
-        tokenizer.encode(message, options.add_special_tokens)
+        unsafe { std::slice::from_raw_parts(bytes, len) }
     };
-    let encoding: Encoding;
-    match encoding_res {
-        Ok(e) => encoding = e,
-        Err(error) => return Err(err(format!("encoding failed: {}", error.to_string()))),
-    }
-
-    // Encode it.
-    let buffer = encode_process(encoding, &options)?;
-
-    // Package one Buffer into EncodeResults.
-    let mut vec_buf: Vec<Buffer> = Vec::with_capacity(1);
-    vec_buf.push(buffer);
-    let vec_ptr = vec_buf.as_mut_ptr();
-    std::mem::forget(vec_buf);
-    Ok(EncodeResults{
-        len: 1,
-        encoded: vec_ptr,
-        error: null_mut(),
-    })
+    let message = std::str::from_utf8(bytes_slice)
+        .map_err(|e| err(format!("input is not valid UTF-8: {}", e)))?;
+    encode_str_impl(tokenizer_ptr, message, options)
+}
+
+pub(crate) fn encode_str_impl(tokenizer_ptr: *mut TokenizerHandle,
+                   message: &str,
+                   options: EncodeParams,
+               ) -> Result<Vec<Buffer>, Box<dyn Error>> {
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    options.validate()?;
+    crate::record::record_encode(handle, &state, &[message], &options, false);
+    let tokenizer = tokenizer_for(&state, &options);
+    state.encode_limits().check(std::iter::once(message.len()))?;
+
+    let max_tokens = state.encode_limits().max_tokens();
+    let start = Instant::now();
+    let encoding_res = if crate::profile::enabled() || crate::trace::enabled() || options.has(ENCODE_SKIP_NORMALIZATION)
+        || max_tokens < usize::MAX
+    {
+        let offsets_type = if options.has(ENCODE_WITH_OFFSETS_CHAR_MODE) { OffsetType::Char } else { OffsetType::Byte };
+        let normalize = !options.has(ENCODE_SKIP_NORMALIZATION);
+        let add_special_tokens = options.has(ENCODE_ADD_SPECIAL_TOKENS);
+        crate::profile::encode(tokenizer, message, None, add_special_tokens, offsets_type, normalize, max_tokens)
+    } else if options.has(ENCODE_WITH_OFFSETS_CHAR_MODE) {
+        tokenizer.encode_char_offsets(message, options.has(ENCODE_ADD_SPECIAL_TOKENS))
+    } else {
+        tokenizer.encode(message, options.has(ENCODE_ADD_SPECIAL_TOKENS))
+    };
+    let encoding: Encoding = match encoding_res {
+        Ok(e) => e,
+        Err(error) if error.is::<TokenBudgetExceeded>() => return Err(err(error.to_string())),
+        Err(error) => return Err(err(format!("encoding failed: {}", error))),
+    };
+    crate::trace::check(message, start, &encoding);
+    if let Some(stats) = state.sequence_stats() {
+        stats.record(std::slice::from_ref(&encoding));
+    }
+
+    // Encode it: only one Buffer is returned.
+    let scores = options.has(ENCODE_RETURN_DEBUG_INFO).then(|| state.token_scores());
+    let word_starts = options.has(ENCODE_RETURN_WORD_STARTS).then(|| WordStarts::new(tokenizer));
+    let buffer = encode_process(encoding, &[message], &options, scores.as_deref(), word_starts.as_ref())?;
+    Ok(vec![buffer])
 }
 
 
 /// Encodes string using given tokenizer and EncodeParams.
 #[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encode"))]
 pub unsafe extern "C" fn encode(
-    tokenizer_ptr: *mut libc::c_void,
-    message: *const libc::c_char,
+    tokenizer_ptr: *mut TokenizerHandle,
+    message: *const c_char,
+    options: EncodeParams,
+) -> EncodeResults {
+    result_to_encode_results(
+        catch_panic(|| encode_impl(tokenizer_ptr, message, options)))
+}
+
+/// Encodes the UTF-8 string given by `bytes` and `len` using given tokenizer and EncodeParams.
+///
+/// Different from `encode`, the string doesn't need to be NUL-terminated, and it may contain NUL
+/// characters: so the caller can pass the string contents without copying them to a C string.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encode_bytes"))]
+pub unsafe extern "C" fn encode_bytes(
+    tokenizer_ptr: *mut TokenizerHandle,
+    bytes: *const u8,
+    len: u32,
     options: EncodeParams,
 ) -> EncodeResults {
     result_to_encode_results(
-        encode_impl(tokenizer_ptr, message, options))
+        catch_panic(|| encode_bytes_impl(tokenizer_ptr, bytes, len as usize, options)))
+}
+
+/// Same as `encode`, but returns an `EncodeResultsV2`, which must be freed with `free_encode_results_v2`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encode_v2"))]
+pub unsafe extern "C" fn encode_v2(
+    tokenizer_ptr: *mut TokenizerHandle,
+    message: *const c_char,
+    options: EncodeParams,
+) -> EncodeResultsV2 {
+    result_to_encode_results_v2(
+        catch_panic(|| encode_impl(tokenizer_ptr, message, options)))
 }
 
 /// Encode a batch of strings using given tokenizer and EncodeParams.
-/// The
+/// The results are returned in the same order as the `messages`.
 #[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encode_batch"))]
 pub unsafe extern "C" fn encode_batch(
-    tokenizer_ptr: *mut libc::c_void,
+    tokenizer_ptr: *mut TokenizerHandle,
     num_messages: u32,
-    messages: *const *const libc::c_char,
+    messages: *const *const c_char,
     options: EncodeParams,
 ) -> EncodeResults {
     result_to_encode_results(
-        encode_batch_impl(tokenizer_ptr, num_messages, messages, options))
+        catch_panic(|| encode_batch_impl(tokenizer_ptr, num_messages as usize, messages, options)))
 }
 
-fn encode_batch_impl(
-    tokenizer_ptr: *mut libc::c_void,
+/// Encode a batch of UTF-8 strings, each given by a pointer in `messages` and its length in `lengths`,
+/// using given tokenizer and EncodeParams.
+///
+/// Different from `encode_batch`, the strings don't need to be NUL-terminated, and they may contain NUL
+/// characters: so the caller can pass the strings contents without copying them to C strings.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encode_batch_bytes"))]
+pub unsafe extern "C" fn encode_batch_bytes(
+    tokenizer_ptr: *mut TokenizerHandle,
     num_messages: u32,
-    messages: *const *const libc::c_char,
+    messages: *const *const u8,
+    lengths: *const u32,
+    options: EncodeParams,
+) -> EncodeResults {
+    result_to_encode_results(
+        catch_panic(|| encode_batch_bytes_impl(tokenizer_ptr, num_messages as usize, messages, lengths, options)))
+}
+
+/// Encodes a batch mixing single texts and pairs of texts (e.g. heterogeneous requests, where only some are
+/// sentence pairs) in one parallel call, using given tokenizer and EncodeParams. Input `i` is the UTF-8 text
+/// given by `messages[i]` and `lengths[i]` and, if `pairs[i]` is not null, the second text of a pair, given
+/// by `pairs[i]` and `pair_lengths[i]`. `pairs` (and `pair_lengths`) can be null if there are no pairs.
+///
+/// The type ids and special tokens of the pairs are those of the pair template of the post-processor, and the
+/// offsets of the tokens of the second text of a pair are relative to it.
+///
+/// The results are returned in the same order as the inputs, and must be freed with `free_encode_results_v2`.
+///
+/// # Safety
+///
+/// `messages` and `lengths` must point to `num_messages` elements, as `pairs` and `pair_lengths` if not null.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encode_batch_mixed"))]
+pub unsafe extern "C" fn encode_batch_mixed(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_messages: u64,
+    messages: *const *const u8,
+    lengths: *const u32,
+    pairs: *const *const u8,
+    pair_lengths: *const u32,
+    options: EncodeParams,
+) -> EncodeResultsV2 {
+    let num_messages = match usize::try_from(num_messages) {
+        Ok(n) => n,
+        Err(_) => return result_to_encode_results_v2(
+            Err(err(format!("num_messages={} overflows the platform's usize", num_messages)))),
+    };
+    result_to_encode_results_v2(catch_panic(|| {
+        encode_batch_mixed_impl(tokenizer_ptr, num_messages, messages, lengths, pairs, pair_lengths, options)
+    }))
+}
+
+fn encode_batch_mixed_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_messages: usize,
+    messages: *const *const u8,
+    lengths: *const u32,
+    pairs: *const *const u8,
+    pair_lengths: *const u32,
+    options: EncodeParams,
+) -> Result<Vec<Buffer>, Box<dyn Error>> {
+    let texts = messages_from_bytes(num_messages, messages, lengths)?;
+    let mut inputs: Vec<(&str, Option<&str>)> = Vec::with_capacity(num_messages);
+    for (index, text) in texts.into_iter().enumerate() {
+        let pair = if pairs.is_null() { std::ptr::null() } else { unsafe { *pairs.add(index) } };
+        if pair.is_null() {
+            inputs.push((text, None));
+            continue;
+        }
+        let len = unsafe { *pair_lengths.add(index) } as usize;
+        let bytes_slice: &[u8] = if len == 0 { &[] } else { unsafe { std::slice::from_raw_parts(pair, len) } };
+        let pair = std::str::from_utf8(bytes_slice)
+            .map_err(|e| err(format!("pair of input #{} is not valid UTF-8: {}", index, e)))?;
+        inputs.push((text, Some(pair)));
+    }
+    encode_inputs_impl(tokenizer_ptr, &inputs, options)
+}
+
+// encode_inputs_impl encodes the batch of `inputs`, each a text with the optional second text of a pair, and
+// returns their Buffers.
+fn encode_inputs_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    inputs: &[(&str, Option<&str>)],
     options: EncodeParams,
-) -> Result<EncodeResults, Box<dyn Error>> {
-    let tokenizer: &Tokenizer = convert_to_tokenizer_ref(tokenizer_ptr)?;
-    let mut encode_messages: Vec<String> = Vec::with_capacity(num_messages as usize);
+) -> Result<Vec<Buffer>, Box<dyn Error>> {
+    // Pairs are not recorded (see `start_recording`): the recorded calls only hold single texts.
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    let encodings = encode_inputs(&state, inputs, &options)?;
+    let scores = options.has(ENCODE_RETURN_DEBUG_INFO).then(|| state.token_scores());
+    let word_starts = options.has(ENCODE_RETURN_WORD_STARTS).then(|| WordStarts::new(&state.tokenizer));
+    let mut vec_buffers: Vec<Buffer> = Vec::with_capacity(inputs.len());
+    for (encoding, &(text, pair)) in encodings.into_iter().zip(inputs) {
+        let texts = match pair {
+            Some(pair) => vec![text, pair],
+            None => vec![text],
+        };
+        match encode_process(encoding, &texts, &options, scores.as_deref(), word_starts.as_ref()) {
+            Ok(buf) => vec_buffers.push(buf),
+            Err(e) => {
+                vec_buffers.into_iter().for_each(free_buffer);
+                return Err(e);
+            }
+        }
+    }
+    Ok(vec_buffers)
+}
+
+/// Encodes the pair of UTF-8 texts given by `text_a` (with `len_a` bytes) and `text_b` (with `len_b` bytes),
+/// e.g. a (question, context) pair for cross-encoders and QA models, using given tokenizer and EncodeParams.
+///
+/// The type ids and special tokens are those of the pair template of the post-processor, the truncation
+/// strategy decides which of the texts is truncated, and the offsets of the tokens of `text_b` are relative to
+/// it. The result must be freed with `free_encode_results_v2`.
+///
+/// # Safety
+///
+/// `text_a` and `text_b` must point to `len_a` and `len_b` bytes.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encode_pair"))]
+pub unsafe extern "C" fn encode_pair(
+    tokenizer_ptr: *mut TokenizerHandle,
+    text_a: *const u8,
+    len_a: u32,
+    text_b: *const u8,
+    len_b: u32,
+    options: EncodeParams,
+) -> EncodeResultsV2 {
+    result_to_encode_results_v2(
+        catch_panic(|| encode_batch_pairs_impl(tokenizer_ptr, 1, &text_a, &len_a, &text_b, &len_b, options)))
+}
+
+/// Encodes a batch of pairs of texts in one parallel call, using given tokenizer and EncodeParams: pair `i` is
+/// made of the UTF-8 texts given by `texts_a[i]` and `lengths_a[i]`, and by `texts_b[i]` and `lengths_b[i]`.
+/// Each pair is encoded as in `encode_pair` -- see `encode_batch_mixed` to mix pairs and single texts.
+///
+/// The results are returned in the same order as the pairs, and must be freed with `free_encode_results_v2`.
+///
+/// # Safety
+///
+/// `texts_a`, `lengths_a`, `texts_b` and `lengths_b` must point to `num_pairs` elements.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encode_batch_pairs"))]
+pub unsafe extern "C" fn encode_batch_pairs(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_pairs: u64,
+    texts_a: *const *const u8,
+    lengths_a: *const u32,
+    texts_b: *const *const u8,
+    lengths_b: *const u32,
+    options: EncodeParams,
+) -> EncodeResultsV2 {
+    let num_pairs = match usize::try_from(num_pairs) {
+        Ok(n) => n,
+        Err(_) => return result_to_encode_results_v2(
+            Err(err(format!("num_pairs={} overflows the platform's usize", num_pairs)))),
+    };
+    result_to_encode_results_v2(catch_panic(|| {
+        encode_batch_pairs_impl(tokenizer_ptr, num_pairs, texts_a, lengths_a, texts_b, lengths_b, options)
+    }))
+}
+
+fn encode_batch_pairs_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_pairs: usize,
+    texts_a: *const *const u8,
+    lengths_a: *const u32,
+    texts_b: *const *const u8,
+    lengths_b: *const u32,
+    options: EncodeParams,
+) -> Result<Vec<Buffer>, Box<dyn Error>> {
+    let texts_a = messages_from_bytes(num_pairs, texts_a, lengths_a)?;
+    let texts_b = messages_from_bytes(num_pairs, texts_b, lengths_b)
+        .map_err(|e| err(format!("second text of a pair: {}", e)))?;
+    let inputs: Vec<(&str, Option<&str>)> = texts_a.into_iter().zip(texts_b.into_iter().map(Some)).collect();
+    encode_inputs_impl(tokenizer_ptr, &inputs, options)
+}
+
+/// Same as `encode_batch`, but it takes a u64 `num_messages` and returns an `EncodeResultsV2`, with
+/// u64 counts. It must be freed with `free_encode_results_v2`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encode_batch_v2"))]
+pub unsafe extern "C" fn encode_batch_v2(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_messages: u64,
+    messages: *const *const c_char,
+    options: EncodeParams,
+) -> EncodeResultsV2 {
+    let num_messages = match usize::try_from(num_messages) {
+        Ok(n) => n,
+        Err(_) => return result_to_encode_results_v2(
+            Err(err(format!("num_messages={} overflows the platform's usize", num_messages)))),
+    };
+    result_to_encode_results_v2(
+        catch_panic(|| encode_batch_impl(tokenizer_ptr, num_messages, messages, options)))
+}
+
+fn encode_batch_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_messages: usize,
+    messages: *const *const c_char,
+    options: EncodeParams,
+) -> Result<Vec<Buffer>, Box<dyn Error>> {
+    let mut encode_messages: Vec<Cow<str>> = Vec::with_capacity(num_messages);
     unsafe {
         for index in 0..num_messages {
-            let cstr_ptr = *messages.offset(index as isize);
-            let rust_string = CStr::from_ptr(cstr_ptr).to_string_lossy().into_owned();
-            encode_messages.push(rust_string);
+            let cstr_ptr = *messages.add(index);
+            encode_messages.push(CStr::from_ptr(cstr_ptr).to_string_lossy());
         }
     }
-    let encoding_res = if options.with_offsets_char_mode {
-        tokenizer
-            .encode_batch_char_offsets(encode_messages, options.add_special_tokens)
-    } else {
-        tokenizer
-            .encode_batch(encode_messages, options.add_special_tokens)
-    };
-    let encoding: Vec<Encoding>;
-    match encoding_res {
-        Ok(e) => encoding = e,
-        Err(error) => return Err(err(format!("encoding failed: {}", error.to_string()))),
+    let inputs: Vec<&str> = encode_messages.iter().map(|m| m.as_ref()).collect();
+    encode_batch_str_impl(tokenizer_ptr, &inputs, options)
+}
+
+fn encode_batch_bytes_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_messages: usize,
+    messages: *const *const u8,
+    lengths: *const u32,
+    options: EncodeParams,
+) -> Result<Vec<Buffer>, Box<dyn Error>> {
+    let inputs = messages_from_bytes(num_messages, messages, lengths)?;
+    encode_batch_str_impl(tokenizer_ptr, &inputs, options)
+}
+
+// messages_from_bytes converts the `num_messages` UTF-8 strings given by pointers in `messages` and their
+// lengths in `lengths` to a vector of `&str`.
+pub(crate) fn messages_from_bytes<'a>(
+    num_messages: usize,
+    messages: *const *const u8,
+    lengths: *const u32,
+) -> Result<Vec<&'a str>, Box<dyn Error>> {
+    let mut inputs: Vec<&str> = Vec::with_capacity(num_messages);
+    unsafe {
+        for index in 0..num_messages {
+            let len = *lengths.add(index) as usize;
+            let bytes_slice: &[u8] = if len == 0 {
+                &[]
+            } else {
+                std::slice::from_raw_parts(*messages.add(index), len)
+            };
+            let message = std::str::from_utf8(bytes_slice)
+                .map_err(|e| err(format!("input #{} is not valid UTF-8: {}", index, e)))?;
+            inputs.push(message);
+        }
     }
+    Ok(inputs)
+}
+
+pub(crate) fn encode_batch_str_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    messages: &[&str],
+    options: EncodeParams,
+) -> Result<Vec<Buffer>, Box<dyn Error>> {
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    // The encodings, scores and word starts are read under the same guard: all from the same Tokenizer, even if
+    // it is modified concurrently.
+    let (encoding, scores, word_starts) = {
+        let state = handle.read();
+        let encoding = encode_batch_with_state(handle, &state, messages, &options)?;
+        (encoding, token_scores_for(&state, &options), word_starts_for(&state, &options))
+    };
 
     // batch process
-    let mut vec_buffers: Vec<Buffer> = Vec::with_capacity(num_messages as usize);
-    for enc in encoding {
-        vec_buffers.push(encode_process(enc, &options)?);
+    let mut vec_buffers: Vec<Buffer> = Vec::with_capacity(messages.len());
+    for (enc, message) in encoding.into_iter().zip(messages.iter()) {
+        match encode_process(enc, &[message], &options, scores.as_deref(), word_starts.as_ref()) {
+            Ok(buf) => vec_buffers.push(buf),
+            Err(e) => {
+                vec_buffers.into_iter().for_each(free_buffer);
+                return Err(e);
+            }
+        }
     }
-    vec_buffers.shrink_to_fit();
-    let encode_results = EncodeResults{
-        len: vec_buffers.len() as u32,
-        encoded: vec_buffers.as_mut_ptr(),
-        error: null_mut(),
+    Ok(vec_buffers)
+}
+
+// token_scores_for returns the model scores of the tokens if ENCODE_RETURN_DEBUG_INFO is set.
+pub(crate) fn token_scores_for(state: &TokenizerState, options: &EncodeParams) -> Option<Arc<TokenScores>> {
+    options.has(ENCODE_RETURN_DEBUG_INFO).then(|| state.token_scores())
+}
+
+// word_starts_for returns the rule to find the tokens starting a word if ENCODE_RETURN_WORD_STARTS is set.
+pub(crate) fn word_starts_for(state: &TokenizerState, options: &EncodeParams) -> Option<WordStarts> {
+    options.has(ENCODE_RETURN_WORD_STARTS).then(|| WordStarts::new(&state.tokenizer))
+}
+
+// encode_batch_encodings encodes the batch of `messages`, and returns the Encoding of each.
+pub(crate) fn encode_batch_encodings(
+    tokenizer_ptr: *mut TokenizerHandle,
+    messages: &[&str],
+    options: &EncodeParams,
+) -> Result<Vec<Encoding>, Box<dyn Error>> {
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    encode_batch_with_handle(handle, messages, options)
+}
+
+// encode_batch_with_handle encodes the batch of `messages` with the tokenizer in `handle`, and returns
+// the Encoding of each.
+pub(crate) fn encode_batch_with_handle(
+    handle: &TokenizerHandle,
+    messages: &[&str],
+    options: &EncodeParams,
+) -> Result<Vec<Encoding>, Box<dyn Error>> {
+    encode_batch_with_state(handle, &handle.read(), messages, options)
+}
+
+// encode_batch_with_state encodes the batch of `messages` with the `state` read from `handle`, and returns the
+// Encoding of each.
+pub(crate) fn encode_batch_with_state(
+    handle: &TokenizerHandle,
+    state: &TokenizerState,
+    messages: &[&str],
+    options: &EncodeParams,
+) -> Result<Vec<Encoding>, Box<dyn Error>> {
+    options.validate()?;
+    crate::record::record_encode(handle, state, messages, options, true);
+    let inputs: Vec<(&str, Option<&str>)> = messages.iter().map(|&message| (message, None)).collect();
+    encode_inputs(state, &inputs, options)
+}
+
+// tokenizer_for returns the tokenizer of the `state` to encode with the `options`: the setting of
+// `encode_special_tokens` can be overridden by the flags.
+pub(crate) fn tokenizer_for<'a>(state: &'a TokenizerState, options: &EncodeParams) -> &'a Tokenizer {
+    if options.has(ENCODE_SPLIT_SPECIAL_TOKENS) {
+        state.tokenizer_with_encode_special_tokens(true)
+    } else if options.has(ENCODE_MATCH_SPECIAL_TOKENS) {
+        state.tokenizer_with_encode_special_tokens(false)
+    } else {
+        &state.tokenizer
+    }
+}
+
+// encode_inputs encodes the batch of `inputs`, each a text with the optional second text of a pair, with the
+// tokenizer of `state`, and returns the Encoding of each.
+pub(crate) fn encode_inputs(
+    state: &TokenizerState,
+    inputs: &[(&str, Option<&str>)],
+    options: &EncodeParams,
+) -> Result<Vec<Encoding>, Box<dyn Error>> {
+    options.validate()?;
+    state.encode_limits().check(inputs.iter().map(|(text, pair)| text.len() + pair.map_or(0, str::len)))?;
+    let tokenizer = tokenizer_for(state, options);
+    let max_tokens = state.encode_limits().max_tokens();
+    let encoding_res = crate::threads::install(|| {
+        if crate::profile::enabled() || crate::trace::enabled() || options.has(ENCODE_SKIP_NORMALIZATION)
+            || max_tokens < usize::MAX
+        {
+            let offsets_type = if options.has(ENCODE_WITH_OFFSETS_CHAR_MODE) {
+                OffsetType::Char
+            } else {
+                OffsetType::Byte
+            };
+            crate::profile::encode_batch(
+                tokenizer,
+                inputs,
+                options.has(ENCODE_ADD_SPECIAL_TOKENS),
+                offsets_type,
+                !options.has(ENCODE_SKIP_NORMALIZATION),
+                max_tokens,
+            )
+        } else {
+            let inputs: Vec<EncodeInput> = inputs
+                .iter()
+                .map(|&(text, pair)| match pair {
+                    Some(pair) => (text, pair).into(),
+                    None => text.into(),
+                })
+                .collect();
+            if options.has(ENCODE_WITH_OFFSETS_CHAR_MODE) {
+                tokenizer.encode_batch_char_offsets(inputs, options.has(ENCODE_ADD_SPECIAL_TOKENS))
+            } else {
+                tokenizer.encode_batch(inputs, options.has(ENCODE_ADD_SPECIAL_TOKENS))
+            }
+        }
+    });
+    let encodings = match encoding_res {
+        Ok(e) => e,
+        Err(error) if error.is::<TokenBudgetExceeded>() => return Err(err(error.to_string())),
+        Err(error) => return Err(err(format!("encoding failed: {}", error))),
     };
-    std::mem::forget(vec_buffers);
-    Ok(encode_results)
+    if let Some(stats) = state.sequence_stats() {
+        stats.record(&encodings);
+    }
+    Ok(encodings)
 }
 
 /// This function is release a Buffer struct from Rust returned to Golang by `encode`.
 // It is not exported to C/Go because one should use EncodeResults instead.
-fn free_buffer(buf: Buffer) {
+pub(crate) fn free_buffer(buf: Buffer) {
     if !buf.ids.is_null() {
         unsafe {
             Vec::from_raw_parts(buf.ids, buf.len as usize, buf.len as usize);
@@ -298,10 +1250,12 @@ fn free_buffer(buf: Buffer) {
     }
     if !buf.tokens.is_null() {
         unsafe {
-            let strings = Vec::from_raw_parts(buf.tokens, buf.len as usize, buf.len as usize);
-            for s in strings {
-                drop(std::ffi::CString::from_raw(s));
-            }
+            Vec::from_raw_parts(buf.tokens, buf.len as usize, buf.len as usize);
+        }
+    }
+    if !buf.tokens_arena.is_null() {
+        unsafe {
+            Vec::from_raw_parts(buf.tokens_arena, buf.tokens_arena_len as usize, buf.tokens_arena_len as usize);
         }
     }
     if !buf.offsets.is_null() {
@@ -309,20 +1263,475 @@ fn free_buffer(buf: Buffer) {
             Vec::from_raw_parts(buf.offsets, buf.len as usize, buf.len as usize).clear();
         }
     }
+    if !buf.token_char_lengths.is_null() {
+        unsafe {
+            Vec::from_raw_parts(buf.token_char_lengths, buf.len as usize, buf.len as usize);
+        }
+    }
+    if !buf.word_ids.is_null() {
+        unsafe {
+            Vec::from_raw_parts(buf.word_ids, buf.len as usize, buf.len as usize);
+        }
+    }
+    if !buf.token_scores.is_null() {
+        unsafe {
+            Vec::from_raw_parts(buf.token_scores, buf.len as usize, buf.len as usize);
+        }
+    }
+    if !buf.words.is_null() {
+        unsafe {
+            Vec::from_raw_parts(buf.words, buf.num_words as usize, buf.num_words as usize).clear();
+        }
+    }
+    if !buf.word_starts.is_null() {
+        unsafe {
+            Vec::from_raw_parts(buf.word_starts, buf.len as usize, buf.len as usize);
+        }
+    }
+    if !buf.sequence_ids.is_null() {
+        unsafe {
+            Vec::from_raw_parts(buf.sequence_ids, buf.len as usize, buf.len as usize);
+        }
+    }
+    let num_bytes = (buf.len as usize).div_ceil(8);
+    for bits in [buf.attention_mask_bits, buf.special_tokens_mask_bits] {
+        if !bits.is_null() {
+            unsafe {
+                Vec::from_raw_parts(bits, num_bytes, num_bytes);
+            }
+        }
+    }
+    free_buffers(buf.overflowing, buf.num_overflowing as usize);
 }
 
 /// This function is release Vec<Buffer> from Rust returned to Golang by `encode_batch`.
 #[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("free_encode_results"))]
 pub unsafe extern "C" fn free_encode_results(results: EncodeResults) {
-    if !results.error.is_null() {
-        free_string(results.error);
-    }
-    if results.len > 0 {
+    catch_panic_or((), || {
+        if !results.error.is_null() {
+            free_string(results.error);
+        }
+        free_buffers(results.encoded, results.len as usize);
+    })
+}
+
+/// This function is release Vec<Buffer> from Rust returned to Golang by `encode_v2` and `encode_batch_v2`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("free_encode_results_v2"))]
+pub unsafe extern "C" fn free_encode_results_v2(results: EncodeResultsV2) {
+    catch_panic_or((), || {
+        if !results.error.is_null() {
+            free_string(results.error);
+        }
+        free_buffers(results.encoded, results.len as usize);
+    })
+}
+
+// free_buffers releases the array of `len` buffers pointed by `encoded`.
+fn free_buffers(encoded: *mut Buffer, len: usize) {
+    if len > 0 {
         unsafe {
-            let vec_buffers = Vec::from_raw_parts(results.encoded, results.len as usize, results.len as usize);
+            let vec_buffers = Vec::from_raw_parts(encoded, len, len);
             for buf in vec_buffers {
                 free_buffer(buf);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{encode_ids, take_error, TestHandle, BYTE_LEVEL_BPE, WORDPIECE, WORDPIECE_BERT};
+
+    // values returns the `len` values pointed by `ptr`.
+    fn values<'a, T>(ptr: *const T, len: u32) -> &'a [T] {
+        unsafe { std::slice::from_raw_parts(ptr, len as usize) }
+    }
+
+    // encode_one encodes `text` with `encode_bytes` and the given flags, returning its only Buffer, and the results
+    // (to be freed with `free_encode_results`).
+    fn encode_one(handle: &TestHandle, text: &str, flags: u64) -> (&'static Buffer, EncodeResults) {
+        let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags };
+        let results = unsafe { encode_bytes(handle.0, text.as_ptr(), text.len() as u32, options) };
+        assert!(results.error.is_null());
+        assert_eq!(results.len, 1);
+        (unsafe { &*results.encoded }, results)
+    }
+
+    #[test]
+    fn tokens_arena() {
+        let handle = TestHandle::new(WORDPIECE);
+        let (buf, results) = encode_one(&handle, "hello ab world", ENCODE_RETURN_TOKENS);
+        assert_eq!(buf.tokens_arena_len, 18);
+        let arena = values(buf.tokens_arena.cast::<u8>(), buf.tokens_arena_len as u32);
+        assert_eq!(arena, b"hello\0a\0##b\0world\0");
+        // The tokens point into the arena, in order.
+        let tokens = values(buf.tokens, buf.len);
+        let starts: Vec<usize> = tokens.iter().map(|&t| unsafe { t.offset_from(buf.tokens_arena) } as usize).collect();
+        assert_eq!(starts, [0, 6, 8, 12]);
+        unsafe { free_encode_results(results) };
+
+        let (buf, results) = encode_one(&handle, "hello", 0);
+        assert!(buf.tokens.is_null() && buf.tokens_arena.is_null());
+        unsafe { free_encode_results(results) };
+
+        assert!(tokens_arena_from(&["a\0b".to_string()]).is_err());
+    }
+
+    #[test]
+    fn encode_results_v2_total_tokens() {
+        let handle = TestHandle::new(WORDPIECE);
+        let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags: 0 };
+        let results = unsafe { encode_v2(handle.0, c"hello world".as_ptr(), options) };
+        assert!(results.error.is_null());
+        assert_eq!((results.len, results.total_tokens), (1, 2));
+        assert_eq!(unsafe { (*results.encoded).len }, 2_u32);
+        unsafe { free_encode_results_v2(results) };
+
+        // The len of a Buffer stays u32 (the v1 ABI): larger encodings are an error.
+        assert_eq!(buffer_len(7).unwrap(), 7);
+        assert!(buffer_len(u32::MAX as usize + 1).is_err());
+    }
+
+    #[test]
+    fn encode_params_validation() {
+        let params = |version, flags| EncodeParams { version, flags };
+        assert!(params(ENCODE_PARAMS_VERSION, ENCODE_RETURN_TOKENS).has(ENCODE_RETURN_TOKENS));
+        assert!(params(ENCODE_PARAMS_VERSION, ENCODE_RETURN_TOKENS | ENCODE_RETURN_OFFSETS).validate().is_ok());
+        for version in [0, ENCODE_PARAMS_VERSION + 1] {
+            assert!(params(version, 0).validate().unwrap_err().to_string().contains("not supported"));
+        }
+        let error = params(ENCODE_PARAMS_VERSION, 1 << 40).validate().unwrap_err();
+        assert_eq!(error.to_string(), "EncodeParams flags 0x10000000000 not supported by this library");
+        let exclusive = params(ENCODE_PARAMS_VERSION, ENCODE_OUTPUT_INT32 | ENCODE_OUTPUT_INT64).validate();
+        assert!(exclusive.unwrap_err().to_string().ends_with("are exclusive"));
+
+        // Invalid params are returned as the error of the results.
+        let handle = TestHandle::new(WORDPIECE);
+        let results = unsafe { encode(handle.0, c"hello".as_ptr(), params(0, 0)) };
+        assert!(take_error(results.error).is_some());
+        assert_eq!(results.len, 0);
+    }
+
+    #[test]
+    fn token_char_lengths() {
+        let handle = TestHandle::new(WORDPIECE);
+        let (buffer, results) = encode_one(&handle, "a é", ENCODE_RETURN_TOKEN_CHAR_LENGTHS | ENCODE_RETURN_OFFSETS);
+        assert_eq!(values(buffer.ids, buffer.len), [1, 0]);
+        assert_eq!(offsets(buffer), [(0, 1), (2, 4)]);
+        assert_eq!(values(buffer.token_char_lengths, buffer.len), [1, 1]);
+        unsafe { free_encode_results(results) };
+
+        let (buffer, results) = encode_one(&handle, "a", 0);
+        assert!(buffer.token_char_lengths.is_null());
+        unsafe { free_encode_results(results) };
+        assert_eq!(count_chars_in_span("aé€", 1, 4), 2);
+        assert_eq!(count_chars_in_span("aé€", 2, 100), 1);
+    }
+
+    #[test]
+    fn encode_bytes_without_nul() {
+        let handle = TestHandle::new(WORDPIECE);
+        let (buffer, results) = encode_one(&handle, "hello\0world", 0);
+        assert_eq!(values(buffer.ids, buffer.len), [4, 0, 6]);
+        unsafe { free_encode_results(results) };
+
+        // Only `len` bytes are read.
+        let (buffer, results) = encode_one(&handle, &"hello world"[..5], 0);
+        assert_eq!(values(buffer.ids, buffer.len), [4]);
+        unsafe { free_encode_results(results) };
+
+        let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags: 0 };
+        let results = unsafe { encode_bytes(handle.0, b"\xff".as_ptr(), 1, options) };
+        assert!(take_error(results.error).is_some());
+    }
+
+    // offsets returns the offsets of the `buffer`, as pairs.
+    fn offsets(buffer: &Buffer) -> Vec<(u32, u32)> {
+        values(buffer.offsets, buffer.len).iter().map(|o| (o.start, o.end)).collect()
+    }
+
+    #[test]
+    fn encode_batch_with_lengths() {
+        let handle = TestHandle::new(WORDPIECE);
+        let texts = ["hello world", "a b", ""];
+        let messages: Vec<*const u8> = texts.iter().map(|t| t.as_ptr()).collect();
+        let lengths: Vec<u32> = texts.iter().map(|t| t.len() as u32).collect();
+        let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags: 0 };
+        let results = unsafe { encode_batch_bytes(handle.0, 3, messages.as_ptr(), lengths.as_ptr(), options) };
+        assert!(results.error.is_null());
+        let buffers = values(results.encoded, results.len);
+        let ids: Vec<&[u32]> = buffers.iter().map(|b| values(b.ids, b.len)).collect();
+        assert_eq!(ids, [&[4, 6][..], &[1, 2], &[]]);
+        unsafe { free_encode_results(results) };
+
+        // An invalid message fails the whole batch.
+        let invalid = [messages[0], b"\xff".as_ptr()];
+        let results = unsafe { encode_batch_bytes(handle.0, 2, invalid.as_ptr(), [11, 1].as_ptr(), options) };
+        assert!(take_error(results.error).is_some());
+        assert_eq!(results.len, 0);
+    }
+
+    #[test]
+    fn encode_mixed_batch() {
+        let handle = TestHandle::new(WORDPIECE_BERT);
+        let messages = ["hello".as_ptr(), "a b".as_ptr()];
+        let lengths = [5, 3];
+        let pairs = [std::ptr::null(), "world".as_ptr()];
+        let flags = ENCODE_ADD_SPECIAL_TOKENS | ENCODE_RETURN_TYPE_IDS | ENCODE_RETURN_OFFSETS;
+        let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags };
+        let encode = |pairs: *const *const u8, pair_lengths: *const u32| unsafe {
+            encode_batch_mixed(handle.0, 2, messages.as_ptr(), lengths.as_ptr(), pairs, pair_lengths, options)
+        };
+        let results = encode(pairs.as_ptr(), [0, 5].as_ptr());
+        assert!(results.error.is_null());
+        assert_eq!((results.len, results.total_tokens), (2, 9));
+        let buffers = values(results.encoded, results.len as u32);
+        assert_eq!(values(buffers[0].ids, buffers[0].len), [10, 4, 11]);
+        assert_eq!(values(buffers[1].ids, buffers[1].len), [10, 1, 2, 11, 6, 11]);
+        assert_eq!(values(buffers[1].type_ids, buffers[1].len), [0, 0, 0, 0, 1, 1]);
+        // The offsets of the second text are relative to it.
+        assert_eq!(offsets(&buffers[1])[4], (0, 5));
+        unsafe { free_encode_results_v2(results) };
+
+        // Without pairs, as `encode_batch_bytes`.
+        let results = encode(std::ptr::null(), std::ptr::null());
+        assert!(results.error.is_null());
+        assert_eq!(results.total_tokens, 7);
+        unsafe { free_encode_results_v2(results) };
+
+        let invalid = [std::ptr::null(), b"\xff".as_ptr()];
+        let results = encode(invalid.as_ptr(), [0, 1].as_ptr());
+        assert!(take_error(results.error).unwrap().starts_with("pair of input #1 is not valid UTF-8"));
+        assert_eq!(results.len, 0);
+    }
+
+    #[test]
+    fn skip_normalization() {
+        let lowercase = WORDPIECE.replace(r#""normalizer": null"#, r#""normalizer": {"type": "Lowercase"}"#);
+        let handle = TestHandle::new(&lowercase);
+        assert_eq!(encode_ids(&handle, "HELLO World", 0), [4, 6]);
+        assert_eq!(encode_ids(&handle, "HELLO World", ENCODE_SKIP_NORMALIZATION), [0, 0]);
+        assert_eq!(encode_ids(&handle, "hello world", ENCODE_SKIP_NORMALIZATION), [4, 6]);
+
+        let (buffer, results) = encode_one(&handle, "hello World", ENCODE_SKIP_NORMALIZATION | ENCODE_RETURN_OFFSETS);
+        assert_eq!(values(buffer.ids, buffer.len), [4, 0]);
+        assert_eq!(offsets(buffer), [(0, 5), (6, 11)]);
+        unsafe { free_encode_results(results) };
+
+        // Batches take the same path.
+        let texts = ["HELLO world", "hello"];
+        let messages: Vec<*const u8> = texts.iter().map(|t| t.as_ptr()).collect();
+        let lengths: Vec<u32> = texts.iter().map(|t| t.len() as u32).collect();
+        let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags: ENCODE_SKIP_NORMALIZATION };
+        let results = unsafe { encode_batch_bytes(handle.0, 2, messages.as_ptr(), lengths.as_ptr(), options) };
+        assert!(results.error.is_null());
+        let ids: Vec<&[u32]> = values(results.encoded, results.len).iter().map(|b| values(b.ids, b.len)).collect();
+        assert_eq!(ids, [&[0, 6][..], &[4]]);
+        unsafe { free_encode_results(results) };
+    }
+
+    #[test]
+    fn utf16_offsets() {
+        let handle = TestHandle::new(WORDPIECE);
+        // "😀" is 4 bytes, 1 character and 2 UTF-16 code units.
+        let text = "😀 é hello";
+        for (flags, expected) in [
+            (0, [(0, 4), (5, 7), (8, 13)]),
+            (ENCODE_WITH_OFFSETS_CHAR_MODE, [(0, 1), (2, 3), (4, 9)]),
+            (ENCODE_WITH_OFFSETS_UTF16_MODE, [(0, 2), (3, 4), (5, 10)]),
+        ] {
+            let (buffer, results) = encode_one(&handle, text, ENCODE_RETURN_OFFSETS | flags);
+            assert_eq!(offsets(buffer), expected);
+            unsafe { free_encode_results(results) };
+        }
+    }
+
+    #[test]
+    fn overflowing_windows() {
+        let handle = TestHandle::new(WORDPIECE);
+        let params = crate::configure::truncation_params(1, 0, 2, 0).unwrap();  // Right, LongestFirst.
+        let h = convert_to_handle_ref(handle.0).unwrap();
+        h.write().unwrap().tokenizer_mut().with_truncation(Some(params)).unwrap();
+
+        let text = "a b hello world";
+        let (buffer, results) = encode_one(&handle, text, ENCODE_RETURN_OFFSETS | ENCODE_RETURN_OVERFLOWING);
+        assert_eq!(values(buffer.ids, buffer.len), [1, 2]);
+        assert_eq!((buffer.window.start, buffer.window.end), (0, 3));
+        assert_eq!(buffer.num_overflowing, 1);
+        // The offsets of the overflowing windows are into the full text.
+        let overflowing = unsafe { &*buffer.overflowing };
+        assert_eq!(values(overflowing.ids, overflowing.len), [4, 6]);
+        assert_eq!(offsets(overflowing), [(4, 9), (10, 15)]);
+        assert_eq!((overflowing.window.start, overflowing.window.end), (4, 15));
+        unsafe { free_encode_results(results) };
+
+        let (buffer, results) = encode_one(&handle, text, ENCODE_RETURN_OFFSETS);
+        assert!(buffer.overflowing.is_null());
+        unsafe { free_encode_results(results) };
+    }
+
+    #[test]
+    fn return_length() {
+        let handle = TestHandle::new(WORDPIECE);
+        let (buffer, results) = encode_one(&handle, "hello world", ENCODE_RETURN_LENGTH);
+        assert_eq!((buffer.len, buffer.length), (2, 2));
+        unsafe { free_encode_results(results) };
+
+        // The length doesn't count the padding, on either side.
+        for direction in [0, 1] {
+            let padding = crate::configure::padding_params(5, direction, 0, 0, 0, "[UNK]".to_string());
+            let h = convert_to_handle_ref(handle.0).unwrap();
+            h.write().unwrap().tokenizer_mut().with_padding(Some(padding));
+            let (buffer, results) = encode_one(&handle, "hello world", ENCODE_RETURN_LENGTH);
+            assert_eq!((buffer.len, buffer.length), (5, 2));
+            unsafe { free_encode_results(results) };
+        }
+
+        let (buffer, results) = encode_one(&handle, "hello world", 0);
+        assert_eq!((buffer.len, buffer.length), (5, 0));
+        unsafe { free_encode_results(results) };
+    }
+
+    #[test]
+    fn packed_masks() {
+        let handle = TestHandle::new(WORDPIECE_BERT);
+        let padding = crate::configure::padding_params(10, 1, 0, 0, 0, "[UNK]".to_string());
+        convert_to_handle_ref(handle.0).unwrap().write().unwrap().tokenizer_mut().with_padding(Some(padding));
+        // [CLS] hello a ##b world [SEP], and 4 padding tokens.
+        let masks = ENCODE_ADD_SPECIAL_TOKENS | ENCODE_RETURN_ATTENTION_MASK | ENCODE_RETURN_SPECIAL_TOKENS_MASK;
+        let (buffer, results) = encode_one(&handle, "hello ab world", masks | ENCODE_PACKED_MASKS);
+        assert_eq!(buffer.len, 10);
+        assert!(buffer.attention_mask.is_null() && buffer.special_tokens_mask.is_null());
+        assert_eq!(values(buffer.attention_mask_bits, 2), [0b0011_1111, 0]);
+        assert_eq!(values(buffer.special_tokens_mask_bits, 2), [0b1110_0001, 0b11]);
+        unsafe { free_encode_results(results) };
+
+        let (buffer, results) = encode_one(&handle, "hello ab world", masks);
+        assert!(buffer.attention_mask_bits.is_null() && buffer.special_tokens_mask_bits.is_null());
+        assert_eq!(values(buffer.attention_mask, buffer.len), [1, 1, 1, 1, 1, 1, 0, 0, 0, 0]);
+        unsafe { free_encode_results(results) };
+        assert_eq!(pack_bits(&[1; 9]), [0xFF, 1]);
+        assert!(pack_bits(&[]).is_empty());
+    }
+
+    #[test]
+    fn return_words() {
+        let handle = TestHandle::new(WORDPIECE_BERT);
+        let text = "hello ab é world";
+        let (buffer, results) = encode_one(&handle, text, ENCODE_ADD_SPECIAL_TOKENS | ENCODE_RETURN_WORDS);
+        assert_eq!(values(buffer.ids, buffer.len), [10, 4, 1, 3, 0, 6, 11]);
+        assert_eq!(values(buffer.word_ids, buffer.len), [-1, 0, 1, 1, 2, 3, -1]);
+        let words = values(buffer.words, buffer.num_words as u32);
+        let words: Vec<(u32, u32)> = words.iter().map(|o| (o.start, o.end)).collect();
+        assert_eq!(words, [(0, 5), (6, 8), (9, 11), (12, 17)]);
+        assert!(buffer.offsets.is_null() && buffer.token_scores.is_null());
+        unsafe { free_encode_results(results) };
+
+        // The word spans are in the units of the offsets.
+        let flags = ENCODE_RETURN_WORDS | ENCODE_WITH_OFFSETS_CHAR_MODE;
+        let (buffer, results) = encode_one(&handle, text, flags);
+        assert_eq!(unsafe { (*buffer.words.add(3)).start }, 11);
+        unsafe { free_encode_results(results) };
+
+        let words = get_words(&tokenizers::Encoding::default(), &[]);
+        assert!(words.is_empty());
+    }
+
+    #[test]
+    fn special_tokens_offsets() {
+        let handle = TestHandle::new(WORDPIECE_BERT);
+        let flags = ENCODE_ADD_SPECIAL_TOKENS | ENCODE_RETURN_OFFSETS;
+        for (convention, expected) in [
+            (0, [(0, 0), (1, 6), (7, 12), (0, 0)]),
+            (ENCODE_SPECIAL_TOKENS_BOUNDARY_OFFSETS, [(1, 1), (1, 6), (7, 12), (12, 12)]),
+            (ENCODE_SPECIAL_TOKENS_NO_OFFSETS, [(NO_OFFSET, NO_OFFSET), (1, 6), (7, 12), (NO_OFFSET, NO_OFFSET)]),
+        ] {
+            let (buffer, results) = encode_one(&handle, " hello world", flags | convention);
+            assert_eq!(values(buffer.ids, buffer.len), [10, 4, 6, 11]);
+            assert_eq!(offsets(buffer), expected);
+            unsafe { free_encode_results(results) };
+        }
+
+        let flags = ENCODE_SPECIAL_TOKENS_BOUNDARY_OFFSETS | ENCODE_SPECIAL_TOKENS_NO_OFFSETS;
+        let error = EncodeParams { version: ENCODE_PARAMS_VERSION, flags }.validate().unwrap_err().to_string();
+        assert!(error.ends_with("are exclusive"), "{}", error);
+    }
+
+    #[test]
+    fn debug_info() {
+        let handle = TestHandle::new(BYTE_LEVEL_BPE);
+        let (buffer, results) = encode_one(&handle, "hello world", ENCODE_RETURN_DEBUG_INFO);
+        assert_eq!(values(buffer.word_ids, buffer.len), [0, 1]);
+        assert_eq!(values(buffer.token_scores, buffer.len), [3.0, 8.0]);
+        unsafe { free_encode_results(results) };
+    }
+
+    #[test]
+    fn encode_special_tokens_flags() {
+        let handle = TestHandle::new(WORDPIECE);
+        assert_eq!(encode_ids(&handle, "hello<|im_end|>", 0), vec![4, 5]);
+        assert_eq!(encode_ids(&handle, "hello<|im_end|>", ENCODE_SPLIT_SPECIAL_TOKENS), vec![4, 7, 9, 8]);
+        assert_eq!(encode_ids(&handle, "hello<|im_end|>", ENCODE_MATCH_SPECIAL_TOKENS), vec![4, 5]);
+    }
+
+    #[test]
+    fn encode_pairs() {
+        let handle = TestHandle::new(WORDPIECE_BERT);
+        let flags = ENCODE_ADD_SPECIAL_TOKENS | ENCODE_RETURN_TYPE_IDS | ENCODE_RETURN_OFFSETS;
+        let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags };
+        let (a, b) = ("hello a", "b world");
+        let results = unsafe { encode_pair(handle.0, a.as_ptr(), a.len() as u32, b.as_ptr(), b.len() as u32, options) };
+        assert!(results.error.is_null());
+        let buffer = unsafe { &*results.encoded };
+        assert_eq!(values(buffer.ids, buffer.len), [10, 4, 1, 11, 2, 6, 11]);
+        assert_eq!(values(buffer.type_ids, buffer.len), [0, 0, 0, 0, 1, 1, 1]);
+        // The offsets of the tokens of the second text are relative to it.
+        assert_eq!(offsets(buffer), [(0, 0), (0, 5), (6, 7), (0, 0), (0, 1), (2, 7), (0, 0)]);
+        unsafe { free_encode_results_v2(results) };
+
+        let (texts_a, texts_b) = (["hello", "ab"], ["world", ""]);
+        let (lengths_a, lengths_b) = (texts_a.map(|t| t.len() as u32), texts_b.map(|t| t.len() as u32));
+        let (pointers_a, pointers_b) = (texts_a.map(str::as_ptr), texts_b.map(str::as_ptr));
+        let results = unsafe {
+            encode_batch_pairs(handle.0, 2, pointers_a.as_ptr(), lengths_a.as_ptr(), pointers_b.as_ptr(),
+                lengths_b.as_ptr(), options)
+        };
+        assert!(results.error.is_null());
+        let buffers = unsafe { std::slice::from_raw_parts(results.encoded, results.len as usize) };
+        let ids: Vec<&[u32]> = buffers.iter().map(|buffer| values(buffer.ids, buffer.len)).collect();
+        assert_eq!(ids, [&[10, 4, 11, 6, 11][..], &[10, 1, 3, 11, 11]]);
+        unsafe { free_encode_results_v2(results) };
+
+        let invalid = b"\xff";
+        let results = unsafe { encode_pair(handle.0, a.as_ptr(), a.len() as u32, invalid.as_ptr(), 1, options) };
+        let error = take_error(results.error).unwrap();
+        assert!(error.starts_with("second text of a pair: "), "{}", error);
+    }
+
+    #[test]
+    fn word_and_sequence_ids() {
+        let handle = TestHandle::new(WORDPIECE_BERT);
+        let (a, b) = ("hello ab", "b world");
+        let encode = |flags| {
+            let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags: ENCODE_ADD_SPECIAL_TOKENS | flags };
+            unsafe { encode_pair(handle.0, a.as_ptr(), a.len() as u32, b.as_ptr(), b.len() as u32, options) }
+        };
+
+        // The words are numbered in each text, and the special tokens belong to no word or sequence.
+        let results = encode(ENCODE_RETURN_WORD_IDS | ENCODE_RETURN_SEQUENCE_IDS);
+        let buffer = unsafe { &*results.encoded };
+        assert_eq!(values(buffer.ids, buffer.len), [10, 4, 1, 3, 11, 2, 6, 11]);
+        assert_eq!(values(buffer.word_ids, buffer.len), [-1, 0, 1, 1, -1, 0, 1, -1]);
+        assert_eq!(values(buffer.sequence_ids, buffer.len), [-1, 0, 0, 0, -1, 1, 1, -1]);
+        assert!(buffer.token_scores.is_null());
+        unsafe { free_encode_results_v2(results) };
+
+        let results = encode(0);
+        let buffer = unsafe { &*results.encoded };
+        assert!(buffer.word_ids.is_null() && buffer.sequence_ids.is_null());
+        unsafe { free_encode_results_v2(results) };
+    }
+}
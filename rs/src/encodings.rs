@@ -0,0 +1,372 @@
+//! Encoding handles: the full result of encoding a text, kept in Rust so it can be serialized (e.g. to cache
+//! pre-tokenized datasets on disk, or to ship them between processes) and converted to a Buffer later,
+//! without encoding the text again, or extended with more text (see `encoding_append`).
+
+use std::error::Error;
+use std::ffi::c_char;
+use std::ptr::null_mut;
+use serde::{Deserialize, Serialize};
+use tokenizers::tokenizer::OffsetType;
+use tokenizers::Encoding;
+use crate::encode::{
+    encode_batch_encodings, encode_process, err, messages_from_bytes, result_to_encode_results_v2, tokenizer_for,
+    Buffer, EncodeParams, EncodeResultsV2, ENCODE_ADD_SPECIAL_TOKENS, ENCODE_PARAMS_VERSION, ENCODE_SKIP_NORMALIZATION,
+    ENCODE_WITH_OFFSETS_CHAR_MODE,
+};
+use crate::handle::{convert_to_handle_ref, TokenizerHandle};
+use crate::panics::{catch_panic, catch_panic_or, error_c_string};
+use crate::{BytesOrError, PointerOrError};
+
+/// EncodingHandle is an opaque handle to an encoded text, including its overflowing windows. It is
+/// returned by `encode_to_handle` and `encoding_deserialize`, and must be freed with `free_encoding`.
+#[derive(Serialize, Deserialize)]
+pub struct EncodingHandle {
+    encoding: Encoding,
+    // The text encoded and the EncodeParams flags used, to append to it (see `encoding_append`).
+    text: String,
+    flags: u64,
+    // The encoding of the text before the post-processing (special tokens, truncation and padding), built by
+    // the first `encoding_append`, and updated by each.
+    #[serde(skip)]
+    sequence: Option<Encoding>,
+}
+
+// new_encoding_handle converts the result with an EncodingHandle to a PointerOrError.
+fn new_encoding_handle(r: Result<EncodingHandle, Box<dyn Error>>) -> PointerOrError {
+    match r {
+        Ok(handle) => PointerOrError {
+            value: Box::into_raw(Box::new(handle)).cast(),
+            error: std::ptr::null_mut(),
+        },
+        Err(e) => PointerOrError {
+            value: std::ptr::null_mut(),
+            error: error_c_string(e),
+        },
+    }
+}
+
+// convert_to_encoding_ref converts the `encoding_ptr` to a reference, or returns an error if it is null.
+fn convert_to_encoding_ref<'a>(encoding_ptr: *const EncodingHandle) -> Result<&'a EncodingHandle, Box<dyn Error>> {
+    unsafe { encoding_ptr.as_ref() }.ok_or_else(|| err("encoding handle is null"))
+}
+
+fn encode_to_handle_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    bytes: *const u8,
+    len: u32,
+    options: EncodeParams,
+) -> Result<EncodingHandle, Box<dyn Error>> {
+    let lengths = [len];
+    let inputs = messages_from_bytes(1, &bytes, lengths.as_ptr())?;
+    let mut encodings = encode_batch_encodings(tokenizer_ptr, &inputs, &options)?;
+    let encoding = encodings.pop().ok_or_else(|| err("encoding failed: no results"))?;
+    Ok(EncodingHandle { encoding, text: inputs[0].to_string(), flags: options.flags, sequence: None })
+}
+
+/// Encodes the UTF-8 string given by `bytes` and `len` using given tokenizer, and returns an EncodingHandle
+/// to the result in the `value` field, instead of converting it to a Buffer. Only the `options` flags that
+/// change how the text is encoded are used (e.g. ENCODE_ADD_SPECIAL_TOKENS or ENCODE_WITH_OFFSETS_CHAR_MODE):
+/// the fields of the Buffer are selected by `encoding_to_buffer`.
+///
+/// The caller owns the returned handle, and should free it with `free_encoding`.
+///
+/// # Safety
+///
+/// `bytes` must point to `len` bytes.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encode_to_handle"))]
+pub unsafe extern "C" fn encode_to_handle(
+    tokenizer_ptr: *mut TokenizerHandle,
+    bytes: *const u8,
+    len: u32,
+    options: EncodeParams,
+) -> PointerOrError {
+    new_encoding_handle(catch_panic(|| encode_to_handle_impl(tokenizer_ptr, bytes, len, options)))
+}
+
+fn encoding_to_buffer_impl(
+    encoding_ptr: *const EncodingHandle,
+    text: *const u8,
+    len: u32,
+    options: EncodeParams,
+) -> Result<Vec<Buffer>, Box<dyn Error>> {
+    options.validate()?;
+    let handle = convert_to_encoding_ref(encoding_ptr)?;
+    let text = if text.is_null() {
+        handle.text.as_str()
+    } else {
+        let lengths = [len];
+        messages_from_bytes(1, &text, lengths.as_ptr())?[0]
+    };
+    Ok(vec![encode_process(handle.encoding.clone(), &[text], &options, None, None)?])
+}
+
+/// Converts the encoding to a Buffer, returned as an EncodeResultsV2 with one result, with the fields requested
+/// in `options`, as `encode_v2` does. The offsets mode flags must be the same used to encode it, and the
+/// debug scores of ENCODE_RETURN_DEBUG_INFO and the word starts of ENCODE_RETURN_WORD_STARTS are not available
+/// (there is no tokenizer).
+///
+/// `text` (with `len` bytes) is the text encoded: it can be null, to use the text kept in the handle.
+///
+/// The results must be freed with `free_encode_results_v2`.
+///
+/// # Safety
+///
+/// `encoding_ptr` must have been returned by `encode_to_handle` or `encoding_deserialize`, and `text` must be
+/// null or point to `len` bytes.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encoding_to_buffer"))]
+pub unsafe extern "C" fn encoding_to_buffer(
+    encoding_ptr: *const EncodingHandle,
+    text: *const u8,
+    len: u32,
+    options: EncodeParams,
+) -> EncodeResultsV2 {
+    result_to_encode_results_v2(catch_panic(|| encoding_to_buffer_impl(encoding_ptr, text, len, options)))
+}
+
+/// Serializes the encoding (ids, tokens, offsets, masks, word ids and overflowing windows), with the text
+/// encoded, to a MessagePack blob, which can be stored or sent to another process, and loaded with
+/// `encoding_deserialize`.
+///
+/// The returned bytes must be freed with `free_bytes`.
+///
+/// # Safety
+///
+/// `encoding_ptr` must have been returned by `encode_to_handle` or `encoding_deserialize`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encoding_serialize"))]
+pub unsafe extern "C" fn encoding_serialize(encoding_ptr: *const EncodingHandle) -> BytesOrError {
+    BytesOrError::from_result(catch_panic(|| Ok(rmp_serde::to_vec_named(convert_to_encoding_ref(encoding_ptr)?)?)))
+}
+
+/// Loads an encoding serialized with `encoding_serialize` from the `len` bytes, and returns an
+/// EncodingHandle to it in the `value` field.
+///
+/// The caller owns the returned handle, and should free it with `free_encoding`.
+///
+/// # Safety
+///
+/// `bytes` must point to `len` bytes.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encoding_deserialize"))]
+pub unsafe extern "C" fn encoding_deserialize(bytes: *const u8, len: u32) -> PointerOrError {
+    let bytes = if len == 0 { &[][..] } else { unsafe { std::slice::from_raw_parts(bytes, len as usize) } };
+    new_encoding_handle(catch_panic(|| {
+        rmp_serde::from_slice(bytes).map_err(|e| err(format!("invalid serialized encoding: {}", e)))
+    }))
+}
+
+// last_word_start returns the index of the first token of the last word of the `sequence` (not post-processed),
+// and the offset where the word starts: the tokens from there are encoded again when appending text, since the
+// word may continue in it. It returns (0, 0) if there are no words.
+fn last_word_start(sequence: &Encoding) -> (usize, usize) {
+    let words = sequence.get_word_ids();
+    let Some(last_word) = words.iter().rev().find_map(|word| *word) else {
+        return (0, 0);
+    };
+    let index = words.iter().position(|word| *word == Some(last_word)).unwrap();
+    (index, sequence.get_offsets()[index].0)
+}
+
+// concat_sequences returns the first `len` tokens of the `head` followed by those of the `tail`, with the offsets
+// of the tail shifted by `offset` and its word ids by `word`.
+fn concat_sequences(head: &Encoding, len: usize, tail: Encoding, offset: usize, word: u32) -> Encoding {
+    let mut ids = head.get_ids()[..len].to_vec();
+    let mut type_ids = head.get_type_ids()[..len].to_vec();
+    let mut tokens = head.get_tokens()[..len].to_vec();
+    let mut words = head.get_word_ids()[..len].to_vec();
+    let mut offsets = head.get_offsets()[..len].to_vec();
+    let mut special_tokens_mask = head.get_special_tokens_mask()[..len].to_vec();
+    let mut attention_mask = head.get_attention_mask()[..len].to_vec();
+    ids.extend_from_slice(tail.get_ids());
+    type_ids.extend_from_slice(tail.get_type_ids());
+    tokens.extend_from_slice(tail.get_tokens());
+    words.extend(tail.get_word_ids().iter().map(|w| w.map(|w| w + word)));
+    offsets.extend(tail.get_offsets().iter().map(|(start, end)| (start + offset, end + offset)));
+    special_tokens_mask.extend_from_slice(tail.get_special_tokens_mask());
+    attention_mask.extend_from_slice(tail.get_attention_mask());
+    Encoding::new(
+        ids,
+        type_ids,
+        tokens,
+        words,
+        offsets,
+        special_tokens_mask,
+        attention_mask,
+        Vec::new(),
+        Default::default(),
+    )
+}
+
+fn encoding_append_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    encoding_ptr: *mut EncodingHandle,
+    text: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    let appended = std::str::from_utf8(text).map_err(|e| err(format!("input is not valid UTF-8: {}", e)))?;
+    let handle = unsafe { encoding_ptr.as_mut() }.ok_or_else(|| err("encoding handle is null"))?;
+    let tokenizer_handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = tokenizer_handle.read();
+    state.encode_limits().check(std::iter::once(handle.text.len() + appended.len()))?;
+    let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags: handle.flags };
+    let tokenizer = tokenizer_for(&state, &options);
+    let char_mode = options.has(ENCODE_WITH_OFFSETS_CHAR_MODE);
+    let offsets_type = if char_mode { OffsetType::Char } else { OffsetType::Byte };
+    let normalize = !options.has(ENCODE_SKIP_NORMALIZATION);
+    let encode_sequence = |text: &str| {
+        crate::profile::encode_sequence(tokenizer, text, 0, offsets_type, normalize)
+            .map_err(|e| err(format!("encoding failed: {}", e)))
+    };
+
+    let sequence = match handle.sequence.take() {
+        Some(sequence) => sequence,
+        None => encode_sequence(&handle.text)?,
+    };
+    let (index, offset) = last_word_start(&sequence);
+    let word = sequence.get_word_ids().get(index).copied().flatten().unwrap_or(0);
+    let start = if char_mode {
+        handle.text.char_indices().nth(offset).map_or(handle.text.len(), |(start, _)| start)
+    } else {
+        offset
+    };
+    if !handle.text.is_char_boundary(start) {
+        return Err(err("invalid offsets in the encoding"));
+    }
+    let mut text = handle.text.clone();
+    text.push_str(appended);
+    let tail = encode_sequence(&text[start..])?;
+    let sequence = concat_sequences(&sequence, index, tail, offset, word);
+    handle.encoding = tokenizer
+        .post_process(sequence.clone(), None, options.has(ENCODE_ADD_SPECIAL_TOKENS))
+        .map_err(|e| err(format!("encoding failed: {}", e)))?;
+    handle.text = text;
+    handle.sequence = Some(sequence);
+    Ok(())
+}
+
+/// Appends the `len` bytes of UTF-8 `text` to the text of the encoding, and updates the encoding as if the whole
+/// text had been encoded with the `tokenizer_ptr` and the same EncodeParams: only the last word of the
+/// encoding and the appended text are encoded again, and the post-processing (special tokens, truncation and
+/// padding) is redone. It is meant for interactive editors that tokenize a document as the user types.
+///
+/// The tokenizer must be the one used to encode it. The result is the same as encoding the whole text for the
+/// usual pipelines, where pre-tokens (words) don't depend on the text after them.
+///
+/// It returns null if ok, or a string with an error message (owned by caller), to be freed with `free_string`.
+///
+/// # Safety
+///
+/// `encoding_ptr` must have been returned by `encode_to_handle` or `encoding_deserialize`, and `text` must
+/// point to `len` bytes.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encoding_append"))]
+pub unsafe extern "C" fn encoding_append(
+    tokenizer_ptr: *mut TokenizerHandle,
+    encoding_ptr: *mut EncodingHandle,
+    text: *const u8,
+    len: u32,
+) -> *mut c_char {
+    let text = if len == 0 { &[][..] } else { unsafe { std::slice::from_raw_parts(text, len as usize) } };
+    match catch_panic(|| encoding_append_impl(tokenizer_ptr, encoding_ptr, text)) {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+/// Frees an EncodingHandle returned by `encode_to_handle` or `encoding_deserialize`.
+///
+/// # Safety
+///
+/// `encoding_ptr` must have been returned by Rust, and it must not be used after this call.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("free_encoding"))]
+pub unsafe extern "C" fn free_encoding(encoding_ptr: *mut EncodingHandle) {
+    catch_panic_or((), || {
+        if !encoding_ptr.is_null() {
+            unsafe {
+                drop(Box::from_raw(encoding_ptr));
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{free_encode_results_v2, ENCODE_RETURN_OFFSETS};
+    use crate::testing::{take_bytes, take_error, TestHandle, WORDPIECE_BERT};
+
+    // encode returns the EncodingHandle of the `text`, encoded with the `flags`.
+    fn encode(handle: &TestHandle, text: &str, flags: u64) -> *mut EncodingHandle {
+        let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags };
+        let result = unsafe { encode_to_handle(handle.0, text.as_ptr(), text.len() as u32, options) };
+        assert_eq!(take_error(result.error), None);
+        result.value.cast()
+    }
+
+    // ids_and_offsets converts the encoding to a Buffer and returns its ids and offsets.
+    fn ids_and_offsets(encoding: *const EncodingHandle) -> (Vec<u32>, Vec<(u32, u32)>) {
+        let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags: ENCODE_RETURN_OFFSETS };
+        let results = unsafe { encoding_to_buffer(encoding, std::ptr::null(), 0, options) };
+        assert!(results.error.is_null());
+        assert_eq!(results.len, 1);
+        let buffer = unsafe { &*results.encoded };
+        let ids = unsafe { std::slice::from_raw_parts(buffer.ids, buffer.len as usize) }.to_vec();
+        let offsets = unsafe { std::slice::from_raw_parts(buffer.offsets, buffer.len as usize) };
+        let offsets = offsets.iter().map(|o| (o.start, o.end)).collect();
+        unsafe { free_encode_results_v2(results) };
+        (ids, offsets)
+    }
+
+    #[test]
+    fn serialize_encodings() {
+        let handle = TestHandle::new(WORDPIECE_BERT);
+        let encoding = encode(&handle, "hello world", ENCODE_ADD_SPECIAL_TOKENS);
+        let expected = (vec![10, 4, 6, 11], vec![(0, 0), (0, 5), (6, 11), (0, 0)]);
+        assert_eq!(ids_and_offsets(encoding), expected);
+
+        let bytes = take_bytes(unsafe { encoding_serialize(encoding) }).unwrap();
+        unsafe { free_encoding(encoding) };
+        let result = unsafe { encoding_deserialize(bytes.as_ptr(), bytes.len() as u32) };
+        assert_eq!(take_error(result.error), None);
+        let encoding: *mut EncodingHandle = result.value.cast();
+        assert_eq!(unsafe { &(*encoding).text }, "hello world");
+        assert_eq!(ids_and_offsets(encoding), expected);
+        unsafe { free_encoding(encoding) };
+
+        let result = unsafe { encoding_deserialize(bytes.as_ptr(), bytes.len() as u32 / 2) };
+        assert!(result.value.is_null());
+        assert!(take_error(result.error).unwrap().starts_with("invalid serialized encoding: "));
+        let result = unsafe { encoding_serialize(std::ptr::null()) };
+        assert_eq!(take_bytes(result), Err("encoding handle is null".to_string()));
+    }
+
+    #[test]
+    fn append_text() {
+        let handle = TestHandle::new(WORDPIECE_BERT);
+        let append = |encoding, text: &[u8]| {
+            take_error(unsafe { encoding_append(handle.0, encoding, text.as_ptr(), text.len() as u32) })
+        };
+        // The last word continues in the appended text: "a" + "b" is encoded again as "ab".
+        let encoding = encode(&handle, "hello a", ENCODE_ADD_SPECIAL_TOKENS);
+        assert_eq!(append(encoding, b"b"), None);
+        assert_eq!(ids_and_offsets(encoding), (vec![10, 4, 1, 3, 11], vec![(0, 0), (0, 5), (6, 7), (7, 8), (0, 0)]));
+        assert_eq!(append(encoding, b" world"), None);
+        assert_eq!(unsafe { &(*encoding).text }, "hello ab world");
+        let whole = encode(&handle, "hello ab world", ENCODE_ADD_SPECIAL_TOKENS);
+        assert_eq!(ids_and_offsets(encoding), ids_and_offsets(whole));
+        unsafe { free_encoding(whole) };
+
+        assert!(append(encoding, b"\xff").unwrap().starts_with("input is not valid UTF-8"));
+        assert_eq!(unsafe { &(*encoding).text }, "hello ab world");
+        unsafe { free_encoding(encoding) };
+
+        // Appending to an empty text.
+        let encoding = encode(&handle, "", 0);
+        assert_eq!(append(encoding, b"hello"), None);
+        assert_eq!(ids_and_offsets(encoding), (vec![4], vec![(0, 5)]));
+        unsafe { free_encoding(encoding) };
+        assert_eq!(append(null_mut(), b"hello").as_deref(), Some("encoding handle is null"));
+    }
+}
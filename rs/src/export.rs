@@ -0,0 +1,441 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::ffi::{c_char, CStr};
+use std::io::Write;
+use std::path::Path;
+use std::ptr::null_mut;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use serde::Serialize;
+use tokenizers::models::ModelWrapper;
+use tokenizers::Model;
+use crate::compat::template_special_tokens;
+use crate::debug::TokenScores;
+use crate::encode::err;
+use crate::generation::byte_level_char_bytes;
+use crate::handle::{convert_to_handle_ref, TokenizerHandle};
+use crate::panics::{catch_panic, error_c_string};
+use crate::BytesOrError;
+
+fn tokenizer_to_bytes_impl(tokenizer_ptr: *mut TokenizerHandle, pretty: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let json = handle.read().pipeline_json_string(pretty)?;
+    Ok(json.into_bytes())
+}
+
+/// tokenizer_to_bytes returns the `tokenizer.json` contents of the tokenizer, with all its modifications (e.g.
+/// truncation, padding and added tokens), to be loaded back with `from_bytes`. If `pretty` is set, the JSON is
+/// indented.
+///
+/// The per-tokenizer options of the binding (e.g. deterministic mode) are not included: they are not part of
+/// the `tokenizer.json` format.
+///
+/// The result is returned in a BytesOrError, that must be freed with `free_bytes`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("tokenizer_to_bytes"))]
+pub extern "C" fn tokenizer_to_bytes(tokenizer_ptr: *mut TokenizerHandle, pretty: bool) -> BytesOrError {
+    BytesOrError::from_result(catch_panic(|| tokenizer_to_bytes_impl(tokenizer_ptr, pretty)))
+}
+
+fn tokenizer_save_impl(tokenizer_ptr: *mut TokenizerHandle, path: *const c_char, pretty: bool) -> Result<(), Box<dyn Error>> {
+    if path.is_null() {
+        return Err(err("path is null"));
+    }
+    let path = unsafe { CStr::from_ptr(path) }.to_str()?;
+    let bytes = tokenizer_to_bytes_impl(tokenizer_ptr, pretty)?;
+    std::fs::write(path, bytes).map_err(|e| err(format!("failed to save tokenizer to {:?}: {}", path, e)))?;
+    Ok(())
+}
+
+/// tokenizer_save writes the `tokenizer.json` contents of the tokenizer (see `tokenizer_to_bytes`) to the file
+/// at `path`, replacing it if it exists.
+///
+/// It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+/// The returned string needs to be freed with `free_string`.
+///
+/// # Safety
+///
+/// `path` must be a valid C string.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("tokenizer_save"))]
+pub unsafe extern "C" fn tokenizer_save(tokenizer_ptr: *mut TokenizerHandle, path: *const c_char, pretty: bool) -> *mut c_char {
+    match catch_panic(|| tokenizer_save_impl(tokenizer_ptr, path, pretty)) {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+fn export_legacy_files_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    dir: *const c_char,
+    prefix: *const c_char,
+) -> Result<(), Box<dyn Error>> {
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let dir = unsafe { CStr::from_ptr(dir) }.to_str()?;
+    let prefix = if prefix.is_null() {
+        None
+    } else {
+        Some(unsafe { CStr::from_ptr(prefix) }.to_str()?)
+    };
+    let state = handle.read();
+    state.tokenizer.get_model().save(Path::new(dir), prefix).map_err(|e| err(e.to_string()))?;
+    Ok(())
+}
+
+/// export_legacy_files writes the vocabulary (and merges) of the model in the classic formats to the
+/// existing directory `dir`, for tools that can't read `tokenizer.json`:
+///
+/// - BPE: `vocab.json` and `merges.txt`.
+/// - WordPiece: `vocab.txt`.
+/// - WordLevel: `vocab.json`.
+/// - Unigram: `unigram.json`.
+///
+/// If `prefix` is not null, the file names are prefixed with `<prefix>-`. Only the model is exported: added
+/// tokens and the other components of the pipeline (normalizer, pre-tokenizer, etc.) are not.
+///
+/// It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+/// The returned string needs to be freed with `free_string`.
+///
+/// # Safety
+///
+/// `dir` must be a valid C string, and `prefix` either null or a valid C string.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("export_legacy_files"))]
+pub unsafe extern "C" fn export_legacy_files(
+    tokenizer_ptr: *mut TokenizerHandle,
+    dir: *const c_char,
+    prefix: *const c_char,
+) -> *mut c_char {
+    match catch_panic(|| export_legacy_files_impl(tokenizer_ptr, dir, prefix)) {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(format!("failed to export legacy files: {}", e)),
+    }
+}
+
+fn export_tiktoken_ranks_impl(tokenizer_ptr: *mut TokenizerHandle) -> Result<Vec<u8>, Box<dyn Error>> {
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    let model = state.tokenizer.get_model();
+    if !matches!(model, ModelWrapper::BPE(_)) {
+        return Err(err("tiktoken export requires a BPE model"));
+    }
+    let char_bytes = byte_level_char_bytes();
+    let mut vocab: Vec<(String, u32)> = model.get_vocab().into_iter().collect();
+    vocab.sort_by_key(|&(_, id)| id);
+    let mut ranks = Vec::new();
+    for (token, id) in vocab {
+        let bytes: Option<Vec<u8>> = token.chars().map(|c| char_bytes.get(&c).copied()).collect();
+        let bytes = bytes.ok_or_else(|| err(format!(
+            "token {:?} is not a byte-level token, only byte-level BPE models can be exported to tiktoken", token)))?;
+        writeln!(ranks, "{} {}", BASE64_STANDARD.encode(bytes), id)?;
+    }
+    Ok(ranks)
+}
+
+/// export_tiktoken_ranks returns the mergeable ranks of a byte-level BPE model (GPT-2 style) in the tiktoken
+/// format: one line per token, with the base64 encoding of its bytes and its rank (its id), separated by a
+/// space. Together with `export_tiktoken_special_tokens` it allows using the tokenizer with runtimes that only
+/// understand tiktoken files.
+///
+/// Ranks are the token ids, which matches the merge priorities of tokenizers converted from tiktoken. It fails
+/// if the model is not a byte-level BPE.
+///
+/// The result is returned in a BytesOrError, that must be freed with `free_bytes`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("export_tiktoken_ranks"))]
+pub unsafe extern "C" fn export_tiktoken_ranks(tokenizer_ptr: *mut TokenizerHandle) -> BytesOrError {
+    BytesOrError::from_result(catch_panic(|| export_tiktoken_ranks_impl(tokenizer_ptr)))
+}
+
+fn export_tiktoken_special_tokens_impl(tokenizer_ptr: *mut TokenizerHandle) -> Result<Vec<u8>, Box<dyn Error>> {
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    let model = state.tokenizer.get_model();
+    let special_tokens: BTreeMap<String, u32> = state
+        .tokenizer
+        .get_added_tokens_decoder()
+        .into_iter()
+        .filter(|(id, _)| model.id_to_token(*id).is_none())
+        .map(|(id, token)| (token.content, id))
+        .collect();
+    Ok(serde_json::to_vec(&special_tokens)?)
+}
+
+/// export_tiktoken_special_tokens returns the special tokens map to use along with `export_tiktoken_ranks`:
+/// a JSON object mapping each added token that is not part of the model's vocabulary to its id.
+///
+/// The result is returned in a BytesOrError, that must be freed with `free_bytes`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("export_tiktoken_special_tokens"))]
+pub unsafe extern "C" fn export_tiktoken_special_tokens(tokenizer_ptr: *mut TokenizerHandle) -> BytesOrError {
+    BytesOrError::from_result(catch_panic(|| export_tiktoken_special_tokens_impl(tokenizer_ptr)))
+}
+
+// GGUF token types, as defined by llama.cpp.
+const GGUF_TOKEN_NORMAL: i32 = 1;
+const GGUF_TOKEN_UNKNOWN: i32 = 2;
+const GGUF_TOKEN_CONTROL: i32 = 3;
+const GGUF_TOKEN_USER_DEFINED: i32 = 4;
+const GGUF_TOKEN_UNUSED: i32 = 5;
+const GGUF_TOKEN_BYTE: i32 = 6;
+
+// Score given to added tokens, the same used by the llama.cpp converters.
+const GGUF_ADDED_TOKEN_SCORE: f32 = -1000.0;
+
+// GgufVocab holds the `tokenizer.ggml.*` metadata of a GGUF file, serialized by `export_gguf_vocab`.
+#[derive(Serialize)]
+struct GgufVocab {
+    #[serde(rename = "tokenizer.ggml.model")]
+    model: &'static str,
+    #[serde(rename = "tokenizer.ggml.tokens")]
+    tokens: Vec<String>,
+    #[serde(rename = "tokenizer.ggml.scores")]
+    scores: Vec<f32>,
+    #[serde(rename = "tokenizer.ggml.token_type")]
+    token_type: Vec<i32>,
+    #[serde(rename = "tokenizer.ggml.merges", skip_serializing_if = "Vec::is_empty")]
+    merges: Vec<String>,
+    #[serde(rename = "tokenizer.ggml.bos_token_id", skip_serializing_if = "Option::is_none")]
+    bos_token_id: Option<u32>,
+    #[serde(rename = "tokenizer.ggml.eos_token_id", skip_serializing_if = "Option::is_none")]
+    eos_token_id: Option<u32>,
+    #[serde(rename = "tokenizer.ggml.add_bos_token")]
+    add_bos_token: bool,
+    #[serde(rename = "tokenizer.ggml.add_eos_token")]
+    add_eos_token: bool,
+    #[serde(rename = "tokenizer.ggml.unknown_token_id", skip_serializing_if = "Option::is_none")]
+    unknown_token_id: Option<u32>,
+    #[serde(rename = "tokenizer.ggml.padding_token_id", skip_serializing_if = "Option::is_none")]
+    padding_token_id: Option<u32>,
+}
+
+fn export_gguf_vocab_impl(tokenizer_ptr: *mut TokenizerHandle) -> Result<Vec<u8>, Box<dyn Error>> {
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    let tokenizer = &state.tokenizer;
+    let model = tokenizer.get_model();
+    let model_json = serde_json::to_value(model)?;
+
+    // Merges, in rank order, as "left right" strings.
+    let merges: Vec<String> = match model_json.get("merges").and_then(|m| m.as_array()) {
+        Some(merges) => merges
+            .iter()
+            .filter_map(|merge| match merge {
+                serde_json::Value::String(joined) => Some(joined.clone()),
+                serde_json::Value::Array(pair) => match pair.as_slice() {
+                    [serde_json::Value::String(left), serde_json::Value::String(right)] =>
+                        Some(format!("{} {}", left, right)),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    let byte_fallback = model_json.get("byte_fallback").and_then(|b| b.as_bool()).unwrap_or(false);
+    let char_bytes = byte_level_char_bytes();
+    let vocab = model.get_vocab();
+    let is_byte_level = vocab.keys().all(|token| token.chars().all(|c| char_bytes.contains_key(&c)));
+    let ggml_model = match model {
+        ModelWrapper::BPE(_) if byte_fallback => "llama",
+        ModelWrapper::BPE(_) if is_byte_level => "gpt2",
+        ModelWrapper::Unigram(_) => "llama",
+        ModelWrapper::WordPiece(_) => "bert",
+        _ => return Err(err("GGUF export supports byte-level BPE, SentencePiece (BPE with byte fallback or \
+            Unigram) and WordPiece models only")),
+    };
+
+    let num_tokens = match tokenizer.get_vocab(true).into_values().max() {
+        Some(max_id) => max_id as usize + 1,
+        None => 0,
+    };
+    let unk_id = match &model_json.get("unk_token") {
+        Some(serde_json::Value::String(unk_token)) => model.token_to_id(unk_token),
+        _ => model_json.get("unk_id").and_then(|id| id.as_u64()).map(|id| id as u32),
+    };
+    let template_tokens = template_special_tokens(tokenizer)?;
+    let scores = TokenScores::new(tokenizer);
+    let added_tokens = tokenizer.get_added_tokens_decoder();
+    let mut gguf = GgufVocab {
+        model: ggml_model,
+        tokens: Vec::with_capacity(num_tokens),
+        scores: Vec::with_capacity(num_tokens),
+        token_type: Vec::with_capacity(num_tokens),
+        merges: if ggml_model == "bert" { Vec::new() } else { merges },
+        bos_token_id: template_tokens.bos.first().map(|token| token.id),
+        eos_token_id: template_tokens.eos.last().map(|token| token.id),
+        add_bos_token: !template_tokens.bos.is_empty(),
+        add_eos_token: !template_tokens.eos.is_empty(),
+        unknown_token_id: unk_id,
+        padding_token_id: tokenizer.get_padding().map(|padding| padding.pad_id),
+    };
+    for id in 0..num_tokens as u32 {
+        let (token, score, token_type) = match (added_tokens.get(&id), model.id_to_token(id)) {
+            (Some(added), _) => (
+                added.content.clone(),
+                GGUF_ADDED_TOKEN_SCORE,
+                if added.special { GGUF_TOKEN_CONTROL } else { GGUF_TOKEN_USER_DEFINED },
+            ),
+            (None, Some(token)) => {
+                let token_type = if Some(id) == unk_id {
+                    GGUF_TOKEN_UNKNOWN
+                } else if byte_fallback && token.starts_with("<0x") && token.ends_with('>') && token.len() == 6 {
+                    GGUF_TOKEN_BYTE
+                } else {
+                    GGUF_TOKEN_NORMAL
+                };
+                // BPE merge ranks are converted to scores that decrease with the rank.
+                let score = match (model, scores.get(id)) {
+                    (_, score) if score.is_nan() => 0.0,
+                    (ModelWrapper::BPE(_), rank) => -rank as f32,
+                    (_, score) => score as f32,
+                };
+                (token, score, token_type)
+            }
+            (None, None) => (format!("[PAD{}]", id), 0.0, GGUF_TOKEN_UNUSED),
+        };
+        gguf.tokens.push(token);
+        gguf.scores.push(score);
+        gguf.token_type.push(token_type);
+    }
+    Ok(serde_json::to_vec(&gguf)?)
+}
+
+/// export_gguf_vocab returns the vocabulary of the tokenizer in the layout llama.cpp expects in GGUF files, so
+/// models converted outside of Python ship a matching tokenizer. It is returned as a JSON object with the GGUF
+/// metadata keys and their values:
+///
+/// - `tokenizer.ggml.model`: "gpt2" for byte-level BPE, "llama" for SentencePiece models (BPE with byte fallback
+///   or Unigram), or "bert" for WordPiece.
+/// - `tokenizer.ggml.tokens`: array with the token of each id, including added tokens. Gaps in the ids are
+///   filled with unused `[PAD<id>]` tokens.
+/// - `tokenizer.ggml.scores`: array with the score of each token: the log-probability for Unigram, minus the
+///   merge rank for BPE, and -1000 for added tokens.
+/// - `tokenizer.ggml.token_type`: array with the llama.cpp type of each token (1: normal, 2: unknown,
+///   3: control, 4: user defined, 5: unused, 6: byte).
+/// - `tokenizer.ggml.merges`: array of "left right" merges, for BPE models.
+/// - `tokenizer.ggml.bos_token_id` and `tokenizer.ggml.eos_token_id`, if the post-processor inserts them, and
+///   `tokenizer.ggml.add_bos_token` and `tokenizer.ggml.add_eos_token` (see `get_template_special_tokens`).
+/// - `tokenizer.ggml.unknown_token_id` and `tokenizer.ggml.padding_token_id`, if defined.
+///
+/// The result is returned in a BytesOrError, that must be freed with `free_bytes`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("export_gguf_vocab"))]
+pub unsafe extern "C" fn export_gguf_vocab(tokenizer_ptr: *mut TokenizerHandle) -> BytesOrError {
+    BytesOrError::from_result(catch_panic(|| export_gguf_vocab_impl(tokenizer_ptr)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::ptr::null;
+    use crate::testing::{
+        encode_ids, take_bytes, take_error, temp_dir, TestHandle, BYTE_LEVEL_BPE, UNIGRAM, WORDPIECE, WORDPIECE_BERT,
+    };
+
+    #[test]
+    fn to_bytes_and_save() {
+        let handle = TestHandle::new(WORDPIECE_BERT);
+        let strategy = tokenizers::PaddingStrategy::Fixed(4);
+        let padding = tokenizers::PaddingParams { strategy, ..Default::default() };
+        convert_to_handle_ref(handle.0).unwrap().write().unwrap().tokenizer_mut().with_padding(Some(padding));
+        assert_eq!(take_error(unsafe { crate::vocab::add_tokens(handle.0, 1, [c"<new>".as_ptr()].as_ptr()) }), None);
+
+        // The modifications are kept when loading the contents back.
+        let compact = String::from_utf8(take_bytes(tokenizer_to_bytes(handle.0, false)).unwrap()).unwrap();
+        assert!(!compact.contains('\n'));
+        let reloaded = TestHandle::new(&compact);
+        assert_eq!(encode_ids(&reloaded, "a <new>", 0), [1, 12, 0, 0]);
+        let pretty = String::from_utf8(take_bytes(tokenizer_to_bytes(handle.0, true)).unwrap()).unwrap();
+        assert!(pretty.contains('\n'));
+        let json = |s: &str| serde_json::from_str::<serde_json::Value>(s).unwrap();
+        assert_eq!(json(&pretty), json(&compact));
+
+        let path = temp_dir().join("tokenizer.json");
+        let path_c = CString::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(take_error(unsafe { tokenizer_save(handle.0, path_c.as_ptr(), false) }), None);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), compact);
+
+        assert_eq!(take_error(unsafe { tokenizer_save(handle.0, null(), false) }).as_deref(), Some("path is null"));
+        let missing = CString::new(path.join("tokenizer.json").to_str().unwrap()).unwrap();
+        let error = take_error(unsafe { tokenizer_save(handle.0, missing.as_ptr(), false) }).unwrap();
+        assert!(error.starts_with("failed to save tokenizer to "), "{}", error);
+        assert!(take_bytes(tokenizer_to_bytes(null_mut(), false)).is_err());
+    }
+
+    #[test]
+    fn legacy_files() {
+        let dir = temp_dir();
+        let dir_c = CString::new(dir.to_str().unwrap()).unwrap();
+        let handle = TestHandle::new(WORDPIECE);
+        assert_eq!(take_error(unsafe { export_legacy_files(handle.0, dir_c.as_ptr(), null_mut()) }), None);
+        let vocab = std::fs::read_to_string(dir.join("vocab.txt")).unwrap();
+        assert_eq!(vocab.lines().collect::<Vec<_>>(), ["[UNK]", "a", "b", "##b", "hello", "<|im_end|>", "world", "<|",
+            "|>", "im_end"]);
+
+        let handle = TestHandle::new(BYTE_LEVEL_BPE);
+        assert_eq!(take_error(unsafe { export_legacy_files(handle.0, dir_c.as_ptr(), c"gpt".as_ptr()) }), None);
+        let merges = std::fs::read_to_string(dir.join("gpt-merges.txt")).unwrap();
+        assert!(merges.lines().any(|line| line == "hell o"));
+        let vocab = std::fs::read(dir.join("gpt-vocab.json")).unwrap();
+        let vocab: serde_json::Value = serde_json::from_slice(&vocab).unwrap();
+        assert_eq!(vocab["hello"], 11);
+
+        let missing = CString::new(dir.join("missing").to_str().unwrap()).unwrap();
+        let error = take_error(unsafe { export_legacy_files(handle.0, missing.as_ptr(), null_mut()) }).unwrap();
+        assert!(error.starts_with("failed to export legacy files: "), "{}", error);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn tiktoken_ranks() {
+        let handle = TestHandle::new(BYTE_LEVEL_BPE);
+        let ranks = String::from_utf8(take_bytes(unsafe { export_tiktoken_ranks(handle.0) }).unwrap()).unwrap();
+        let ranks: Vec<&str> = ranks.lines().collect();
+        assert_eq!(ranks.len(), 19);
+        // "Ġ" is the space, and "â" the byte 0xE2.
+        assert_eq!([ranks[0], ranks[4], ranks[11], ranks[18]], ["aA== 0", "IA== 4", "aGVsbG8= 11", "4g== 18"]);
+        // `<|endoftext|>` is in the vocabulary of the model.
+        assert_eq!(take_bytes(unsafe { export_tiktoken_special_tokens(handle.0) }).unwrap(), b"{}");
+
+        let handle = TestHandle::new(WORDPIECE);
+        let error = take_bytes(unsafe { export_tiktoken_ranks(handle.0) }).unwrap_err();
+        assert_eq!(error, "tiktoken export requires a BPE model");
+    }
+
+    // gguf_vocab returns the JSON returned by `export_gguf_vocab` for the tokenizer `json`.
+    fn gguf_vocab(json: &str) -> serde_json::Value {
+        let handle = TestHandle::new(json);
+        serde_json::from_slice(&take_bytes(unsafe { export_gguf_vocab(handle.0) }).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn gguf() {
+        assert_eq!(gguf_vocab(UNIGRAM), serde_json::json!({
+            "tokenizer.ggml.model": "llama",
+            "tokenizer.ggml.tokens": ["<unk>", "a", "b", "ab", "c"],
+            "tokenizer.ggml.scores": [0.0, -1.0, -2.0, -1.5, -3.0],
+            "tokenizer.ggml.token_type": [2, 1, 1, 1, 1],
+            "tokenizer.ggml.add_bos_token": false,
+            "tokenizer.ggml.add_eos_token": false,
+            "tokenizer.ggml.unknown_token_id": 0,
+        }));
+
+        // The special tokens are those of the post-processor template.
+        let vocab = gguf_vocab(WORDPIECE_BERT);
+        assert_eq!(vocab["tokenizer.ggml.model"], "bert");
+        assert_eq!(vocab["tokenizer.ggml.tokens"][10], "[CLS]");
+        assert_eq!(vocab["tokenizer.ggml.token_type"], serde_json::json!([2, 1, 1, 1, 1, 3, 1, 1, 1, 1, 3, 3]));
+        assert_eq!(vocab["tokenizer.ggml.scores"][11], -1000.0);
+        assert_eq!(vocab["tokenizer.ggml.bos_token_id"], 10);
+        assert_eq!(vocab["tokenizer.ggml.eos_token_id"], 11);
+        assert_eq!(vocab["tokenizer.ggml.add_bos_token"], true);
+        assert!(vocab.get("tokenizer.ggml.merges").is_none());
+
+        // BPE merge ranks are negated.
+        let vocab = gguf_vocab(BYTE_LEVEL_BPE);
+        assert_eq!(vocab["tokenizer.ggml.model"], "gpt2");
+        assert_eq!(vocab["tokenizer.ggml.scores"][11], -3.0);
+        assert_eq!(vocab["tokenizer.ggml.merges"][0], "h e");
+    }
+}
@@ -0,0 +1,193 @@
+//! Fertility statistics: how many tokens a tokenizer produces per word and per byte of a sample corpus (see
+//! `fertility`), to compare candidate tokenizers for a language or domain.
+
+use std::error::Error;
+use std::ffi::c_char;
+use std::ptr::null_mut;
+use tokenizers::parallelism::MaybeParallelIterator;
+use tokenizers::tokenizer::{OffsetType, Tokenizer};
+use crate::encode::{err, messages_from_bytes};
+use crate::handle::{convert_to_handle_ref, TokenizerHandle};
+use crate::panics::{catch_panic, error_c_string};
+
+/// FertilityDistribution summarizes the distribution of a ratio of tokens, see `FertilityStats`.
+#[repr(C)]
+#[derive(Default)]
+pub struct FertilityDistribution {
+    mean: f64,
+    p50: f64,
+    p90: f64,
+    p99: f64,
+    max: f64,
+}
+
+impl FertilityDistribution {
+    // new returns the distribution of the `values`, sorting them; the percentiles are computed with the
+    // nearest-rank method.
+    fn new(values: &mut [f64]) -> Self {
+        if values.is_empty() {
+            return Self::default();
+        }
+        values.sort_by(f64::total_cmp);
+        let percentile = |p: usize| values[(values.len() * p).div_ceil(100).max(1) - 1];
+        FertilityDistribution {
+            mean: values.iter().sum::<f64>() / values.len() as f64,
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: values[values.len() - 1],
+        }
+    }
+}
+
+/// FertilityStats holds the fertility statistics of a tokenizer over a sample corpus, see `fertility`.
+#[repr(C)]
+pub struct FertilityStats {
+    /// Number of texts of the corpus.
+    num_texts: u64,
+    /// Number of words of the corpus: the sequences of characters separated by (Unicode) whitespace.
+    num_words: u64,
+    /// Number of bytes of the corpus.
+    num_bytes: u64,
+    /// Number of tokens of the corpus, not counting the special tokens added by the post-processor.
+    num_tokens: u64,
+    /// Number of tokens per word, over the words of the corpus.
+    tokens_per_word: FertilityDistribution,
+    /// Number of tokens per byte, over the (non-empty) texts of the corpus. The ratio of the whole corpus is
+    /// `num_tokens / num_bytes`.
+    tokens_per_byte: FertilityDistribution,
+}
+
+// TextFertility is the number of tokens of each word of a text, and of the whole text.
+struct TextFertility {
+    word_tokens: Vec<u32>,
+    num_tokens: usize,
+}
+
+// text_fertility encodes the `text` (without post-processing, so without truncation or special tokens), and
+// counts the tokens of each of its words: a token is counted for the first word its offsets overlap, and the
+// tokens overlapping no word (e.g. whitespace) are only counted for the text.
+fn text_fertility(tokenizer: &Tokenizer, text: &str) -> tokenizers::Result<TextFertility> {
+    let words: Vec<(usize, usize)> = text
+        .split_whitespace()
+        .map(|word| {
+            let start = word.as_ptr() as usize - text.as_ptr() as usize;
+            (start, start + word.len())
+        })
+        .collect();
+    let encoding = crate::profile::encode_sequence(tokenizer, text, 0, OffsetType::Byte, true)?;
+    let mut word_tokens = vec![0; words.len()];
+    for &(start, end) in encoding.get_offsets() {
+        let index = words.partition_point(|&(_, word_end)| word_end <= start);
+        if index < words.len() && words[index].0 < end.max(start + 1) {
+            word_tokens[index] += 1;
+        }
+    }
+    Ok(TextFertility { word_tokens, num_tokens: encoding.len() })
+}
+
+fn fertility_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_messages: usize,
+    messages: *const *const u8,
+    lengths: *const u32,
+    stats: *mut FertilityStats,
+) -> Result<(), Box<dyn Error>> {
+    if stats.is_null() {
+        return Err(err("stats is null"));
+    }
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    let tokenizer: &Tokenizer = &state.tokenizer;
+    let texts = messages_from_bytes(num_messages, messages, lengths)?;
+    let fertilities = crate::threads::install(|| {
+        (&texts)
+            .into_maybe_par_iter()
+            .map(|text| text_fertility(tokenizer, text))
+            .collect::<tokenizers::Result<Vec<_>>>()
+    })
+    .map_err(|e| err(format!("encoding failed: {}", e)))?;
+
+    let mut tokens_per_word: Vec<f64> = fertilities
+        .iter()
+        .flat_map(|fertility| fertility.word_tokens.iter().map(|&count| count as f64))
+        .collect();
+    let mut tokens_per_byte: Vec<f64> = texts
+        .iter()
+        .zip(&fertilities)
+        .filter(|(text, _)| !text.is_empty())
+        .map(|(text, fertility)| fertility.num_tokens as f64 / text.len() as f64)
+        .collect();
+    let result = FertilityStats {
+        num_texts: texts.len() as u64,
+        num_words: tokens_per_word.len() as u64,
+        num_bytes: texts.iter().map(|text| text.len() as u64).sum(),
+        num_tokens: fertilities.iter().map(|fertility| fertility.num_tokens as u64).sum(),
+        tokens_per_word: FertilityDistribution::new(&mut tokens_per_word),
+        tokens_per_byte: FertilityDistribution::new(&mut tokens_per_byte),
+    };
+    unsafe { stats.write(result) };
+    Ok(())
+}
+
+/// fertility writes to `stats` the fertility statistics of the tokenizer over a sample corpus: the
+/// `num_messages` UTF-8 strings (given as in `encode_batch_bytes`). These are the number of tokens per word
+/// (the words being separated by whitespace, so that tokenizers with different pre-tokenizers can be compared)
+/// and per byte, with their mean and percentiles -- a lower fertility means shorter sequences for the same text.
+///
+/// The texts are encoded without the special tokens and without truncation, and the encoding is not recorded
+/// in the sequence statistics (see `set_sequence_stats`).
+///
+/// It returns null if ok, or a string with an error message (owned by caller), to be freed with `free_string`.
+///
+/// # Safety
+///
+/// `messages` and `lengths` must point to `num_messages` strings and lengths, and `stats` to a writable
+/// FertilityStats.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("fertility"))]
+pub unsafe extern "C" fn fertility(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_messages: u32,
+    messages: *const *const u8,
+    lengths: *const u32,
+    stats: *mut FertilityStats,
+) -> *mut c_char {
+    match catch_panic(|| fertility_impl(tokenizer_ptr, num_messages as usize, messages, lengths, stats)) {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::MaybeUninit;
+    use crate::testing::{take_error, TestHandle, WORDPIECE_BERT};
+
+    fn distribution(d: &FertilityDistribution) -> [f64; 5] {
+        [d.mean, d.p50, d.p90, d.p99, d.max]
+    }
+
+    #[test]
+    fn fertility_stats() {
+        let handle = TestHandle::new(WORDPIECE_BERT);
+        let texts = ["hello world", "ab a", ""];
+        let pointers = texts.map(str::as_ptr);
+        let lengths = texts.map(|text| text.len() as u32);
+        let mut stats = MaybeUninit::<FertilityStats>::uninit();
+        let error = unsafe { fertility(handle.0, 3, pointers.as_ptr(), lengths.as_ptr(), stats.as_mut_ptr()) };
+        assert_eq!(take_error(error), None);
+        let stats = unsafe { stats.assume_init() };
+
+        // The special tokens are not counted: "ab" has 2 tokens, the other words 1.
+        assert_eq!([stats.num_texts, stats.num_words, stats.num_bytes, stats.num_tokens], [3, 4, 15, 5]);
+        assert_eq!(distribution(&stats.tokens_per_word), [1.25, 1.0, 2.0, 2.0, 2.0]);
+        let (first, second) = (2.0 / 11.0, 3.0 / 4.0);
+        assert_eq!(distribution(&stats.tokens_per_byte), [(first + second) / 2.0, first, second, second, second]);
+
+        let error = unsafe { fertility(handle.0, 3, pointers.as_ptr(), lengths.as_ptr(), null_mut()) };
+        assert_eq!(take_error(error).as_deref(), Some("stats is null"));
+        assert_eq!(distribution(&FertilityDistribution::new(&mut [])), [0.0; 5]);
+    }
+}
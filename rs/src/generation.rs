@@ -0,0 +1,636 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::{c_char, CStr, CString};
+use std::ptr::null_mut;
+use tokenizers::decoders::DecoderWrapper;
+use tokenizers::tokenizer::{Decoder, OffsetType, PostProcessor, Tokenizer, TruncationParams};
+use tokenizers::utils::truncation::truncate_encodings;
+use crate::compat::template_special_tokens;
+use crate::decode::ByteTokens;
+use crate::encode::{
+    encode_process, err, messages_from_bytes, result_to_encode_results_v2, tokenizer_for, Buffer, EncodeParams,
+    EncodeResultsV2, ENCODE_ADD_SPECIAL_TOKENS, ENCODE_RETURN_DEBUG_INFO, ENCODE_RETURN_WORD_STARTS,
+    ENCODE_SKIP_NORMALIZATION, ENCODE_WITH_OFFSETS_CHAR_MODE,
+};
+use crate::handle::{convert_to_handle_ref, TokenizerHandle};
+use crate::panics::{catch_panic, catch_panic_or, error_c_string};
+use crate::words::WordStarts;
+use crate::{free_string, BytesOrError};
+
+/// TokenHealing is the result of `token_healing`: the `len` token `ids` of the prompt without its trailing
+/// partial token, and the text `prefix` the first generated token must start with -- the text of the
+/// removed token, or an empty string if no token was removed.
+///
+/// Either the results or `error` will be defined. Once it is no longer used, free it with `free_token_healing`.
+#[repr(C)]
+pub struct TokenHealing {
+    ids: *mut u32,
+    len: u64,
+    prefix: *mut c_char,
+    error: *mut c_char,
+}
+
+/// token_healing encodes the `prompt` and removes its last token if it may be a partial token, that is, if
+/// there are other tokens in the vocabulary that extend it (e.g.: a prompt ending in "http" is likely to
+/// continue as "https"): generating constrained to tokens that start with the returned `prefix` lets the
+/// model pick the best tokenization across the prompt/generation boundary ("token healing").
+///
+/// If `add_special_tokens` is true, the special tokens are added to the prompt, and no token is removed if
+/// the prompt ends with a special token.
+///
+/// The result must be freed with `free_token_healing`.
+///
+/// # Safety
+///
+/// `prompt` must be a valid C string.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("token_healing"))]
+pub unsafe extern "C" fn token_healing(
+    tokenizer_ptr: *mut TokenizerHandle,
+    prompt: *const c_char,
+    add_special_tokens: bool,
+) -> TokenHealing {
+    match catch_panic(|| token_healing_impl(tokenizer_ptr, prompt, add_special_tokens)) {
+        Ok((ids, prefix)) => {
+            let mut ids = ids.into_boxed_slice();
+            let healing = TokenHealing {
+                ids: ids.as_mut_ptr(),
+                len: ids.len() as u64,
+                prefix: prefix.into_raw(),
+                error: null_mut(),
+            };
+            std::mem::forget(ids);
+            healing
+        }
+        Err(e) => TokenHealing {
+            ids: null_mut(),
+            len: 0,
+            prefix: null_mut(),
+            error: error_c_string(e),
+        },
+    }
+}
+
+fn token_healing_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    prompt: *const c_char,
+    add_special_tokens: bool,
+) -> Result<(Vec<u32>, CString), Box<dyn Error>> {
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let prompt = unsafe { CStr::from_ptr(prompt) }.to_str()?;
+    let state = handle.read();
+    let tokenizer = &state.tokenizer;
+    let encoding = tokenizer
+        .encode(prompt, add_special_tokens)
+        .map_err(|e| err(format!("encoding failed: {}", e)))?;
+    let mut ids = encoding.get_ids().to_vec();
+    let last = match ids.len().checked_sub(1) {
+        Some(last) => last,
+        None => return Ok((ids, CString::default())),
+    };
+    if encoding.get_special_tokens_mask()[last] != 0 {
+        return Ok((ids, CString::default()));
+    }
+
+    // The last token is partial if other tokens of the vocabulary extend it.
+    let last_token = &encoding.get_tokens()[last];
+    let is_partial = tokenizer
+        .get_vocab(true)
+        .keys()
+        .any(|token| token.len() > last_token.len() && token.starts_with(last_token.as_str()));
+    if !is_partial {
+        return Ok((ids, CString::default()));
+    }
+    // The prefix starts where the previous non-special token ends, so the whitespace that may be merged
+    // into the removed token is not lost.
+    let start = (0..last)
+        .rev()
+        .find(|&i| encoding.get_special_tokens_mask()[i] == 0)
+        .map_or(0, |i| encoding.get_offsets()[i].1);
+    let prefix = prompt.get(start..).unwrap_or_default();
+    ids.truncate(last);
+    Ok((ids, CString::new(prefix)?))
+}
+
+/// Frees a `TokenHealing` returned by Rust to Golang.
+///
+/// # Safety
+///
+/// `healing` must have been returned by `token_healing`, and it must not be used after this call.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("free_token_healing"))]
+pub unsafe extern "C" fn free_token_healing(healing: TokenHealing) {
+    catch_panic_or((), || {
+        free_string(healing.error);
+        free_string(healing.prefix);
+        if !healing.ids.is_null() {
+            unsafe {
+                drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(healing.ids, healing.len as usize)));
+            }
+        }
+    })
+}
+
+/// VocabTrie is a trie of the surface form (the bytes they decode to) of the tokens of the vocabulary,
+/// used to find the tokens consistent with a text prefix. Special tokens are not included.
+///
+/// It is built on first use, and cached in the TokenizerState, see `TokenizerState::vocab_trie`.
+pub struct VocabTrie {
+    nodes: Vec<TrieNode>,
+
+    // ids of the tokens, in depth-first order of the node where they end: so the ids of all tokens
+    // of a subtree are contiguous.
+    ids: Vec<u32>,
+
+    // mask_len is the number of bytes of the masks returned by `allowed_tokens_mask`.
+    mask_len: usize,
+}
+
+#[derive(Default)]
+struct TrieNode {
+    // children sorted by byte.
+    children: Vec<(u8, usize)>,
+
+    // ids_end is the end of the ids of the tokens ending at this node, and subtree_end is the end of
+    // the ids of all tokens of the subtree: both start at ids_start.
+    ids_start: usize,
+    ids_end: usize,
+    subtree_end: usize,
+}
+
+impl VocabTrie {
+    pub fn new(tokenizer: &Tokenizer) -> Self {
+        let mut nodes = vec![TrieNode::default()];
+        let mut node_ids: Vec<Vec<u32>> = vec![Vec::new()];
+        let surfaces = TokenSurfaces::new(tokenizer);
+        let added_tokens = tokenizer.get_added_tokens_decoder();
+        let vocab = tokenizer.get_vocab(true);
+        let max_id = vocab.values().copied().max();
+        for (token, id) in vocab {
+            let surface = match added_tokens.get(&id) {
+                Some(added) if added.special => continue,
+                Some(added) => added.content.as_bytes().to_vec(),
+                None => surfaces.surface(&token),
+            };
+            if surface.is_empty() {
+                continue;
+            }
+            let mut node = 0;
+            for &b in &surface {
+                node = match nodes[node].children.binary_search_by_key(&b, |&(c, _)| c) {
+                    Ok(pos) => nodes[node].children[pos].1,
+                    Err(pos) => {
+                        nodes.push(TrieNode::default());
+                        node_ids.push(Vec::new());
+                        let child = nodes.len() - 1;
+                        nodes[node].children.insert(pos, (b, child));
+                        child
+                    }
+                };
+            }
+            node_ids[node].push(id);
+        }
+
+        // Lay out the ids in depth-first order.
+        let mut ids = Vec::new();
+        let mut stack = vec![(0, false)];
+        while let Some((node, visited)) = stack.pop() {
+            if visited {
+                nodes[node].subtree_end = ids.len();
+                continue;
+            }
+            nodes[node].ids_start = ids.len();
+            ids.append(&mut node_ids[node]);
+            nodes[node].ids_end = ids.len();
+            stack.push((node, true));
+            stack.extend(nodes[node].children.iter().rev().map(|&(_, child)| (child, false)));
+        }
+        VocabTrie {
+            nodes,
+            ids,
+            mask_len: max_id.map_or(0, |max_id| max_id as usize / 8 + 1),
+        }
+    }
+
+    /// allowed_tokens_mask returns a bit-mask of the tokens consistent with continuing the text `prefix`: the
+    /// tokens whose surface form is a prefix of `prefix`, or that start with `prefix`.
+    pub fn allowed_tokens_mask(&self, prefix: &[u8]) -> Vec<u8> {
+        let mut mask = vec![0_u8; self.mask_len];
+        let mut set = |ids: &[u32]| ids.iter().for_each(|&id| mask[id as usize / 8] |= 1 << (id % 8));
+        let mut node = 0;
+        for &b in prefix {
+            let current = &self.nodes[node];
+            set(&self.ids[current.ids_start..current.ids_end]);
+            node = match current.children.binary_search_by_key(&b, |&(c, _)| c) {
+                Ok(pos) => current.children[pos].1,
+                Err(_) => return mask,
+            };
+        }
+        let current = &self.nodes[node];
+        set(&self.ids[current.ids_start..current.subtree_end]);
+        mask
+    }
+
+    /// to_bytes serializes the trie in the binary format described in `export_vocab_trie`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let num_edges: usize = self.nodes.iter().map(|node| node.children.len()).sum();
+        let mut bytes = Vec::with_capacity(
+            VOCAB_TRIE_MAGIC.len() + 16 + self.nodes.len() * 20 + num_edges * 5 + self.ids.len() * 4,
+        );
+        let put = |bytes: &mut Vec<u8>, value: usize| bytes.extend_from_slice(&(value as u32).to_le_bytes());
+        bytes.extend_from_slice(VOCAB_TRIE_MAGIC);
+        for value in [VOCAB_TRIE_VERSION as usize, self.nodes.len(), num_edges, self.ids.len()] {
+            put(&mut bytes, value);
+        }
+        let mut first_child = 0;
+        for node in &self.nodes {
+            for value in [first_child, node.children.len(), node.ids_start, node.ids_end, node.subtree_end] {
+                put(&mut bytes, value);
+            }
+            first_child += node.children.len();
+        }
+        for node in &self.nodes {
+            for &(_, child) in &node.children {
+                put(&mut bytes, child);
+            }
+        }
+        for &id in &self.ids {
+            put(&mut bytes, id as usize);
+        }
+        for node in &self.nodes {
+            bytes.extend(node.children.iter().map(|&(b, _)| b));
+        }
+        bytes
+    }
+}
+
+// TokenSurfaces converts tokens of the model vocabulary to their surface form, that is, the bytes they
+// decode to when they are not the first token.
+pub(crate) struct TokenSurfaces<'a> {
+    decoder: Option<&'a DecoderWrapper>,
+
+    // byte_tokens is set if the decoder maps (some) tokens back to bytes: byte fallback or byte-level tokens.
+    byte_tokens: Option<ByteTokens>,
+
+    // anchor is the decoded form of the token prepended to each token decoded, see `surface`.
+    anchor: String,
+}
+
+// SURFACE_ANCHOR is the token prepended to each token decoded, so they are not decoded as the first token.
+const SURFACE_ANCHOR: &str = "a";
+
+impl<'a> TokenSurfaces<'a> {
+    pub(crate) fn new(tokenizer: &'a Tokenizer) -> Self {
+        let decoder = tokenizer.get_decoder();
+        let byte_tokens = decoder.and_then(ByteTokens::of_decoder);
+        let anchor = decoder.and_then(|d| d.decode(vec![SURFACE_ANCHOR.to_string()]).ok()).unwrap_or_default();
+        TokenSurfaces { decoder, byte_tokens, anchor }
+    }
+
+    pub(crate) fn surface(&self, token: &str) -> Vec<u8> {
+        if let Some(bytes) = self.byte_tokens.as_ref().and_then(|byte_tokens| byte_tokens.bytes(token)) {
+            return bytes;
+        }
+        let decoded = self
+            .decoder
+            .and_then(|d| d.decode(vec![SURFACE_ANCHOR.to_string(), token.to_string()]).ok());
+        match decoded.as_deref().and_then(|d| d.strip_prefix(self.anchor.as_str())) {
+            Some(surface) => surface.as_bytes().to_vec(),
+            None => token.as_bytes().to_vec(),
+        }
+    }
+}
+
+// byte_level_char_bytes returns the byte represented by each character in byte-level (GPT-2 style)
+// vocabularies: printable bytes represent themselves, and the others are mapped to characters from 256 on.
+pub(crate) fn byte_level_char_bytes() -> HashMap<char, u8> {
+    let is_printable = |b: u8| (b'!'..=b'~').contains(&b) || (0xA1..=0xAC).contains(&b) || b >= 0xAE;
+    let mut char_bytes = HashMap::with_capacity(256);
+    let mut next = 256_u32;
+    for b in 0..=255_u8 {
+        if is_printable(b) {
+            char_bytes.insert(char::from(b), b);
+        } else {
+            char_bytes.insert(char::from_u32(next).unwrap(), b);
+            next += 1;
+        }
+    }
+    char_bytes
+}
+
+/// allowed_tokens_mask returns the bit-mask of the tokens consistent with continuing the text given by the
+/// `len` bytes of `prefix`, for prefix-constrained decoding: the tokens whose text is a prefix of `prefix` (so
+/// they consume part of it), or that start with `prefix` (so they complete it). Special tokens are never
+/// allowed.
+///
+/// The mask has one bit per token id up to `max_token_id` (inclusive), where token id `i` is the bit `i % 8`
+/// (least significant first) of byte `i / 8`. It is returned in a BytesOrError, that must be freed with
+/// `free_bytes`.
+///
+/// The vocabulary trie used is built on the first call, and cached until the tokenizer is modified.
+///
+/// # Safety
+///
+/// `prefix` must point to `len` bytes.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("allowed_tokens_mask"))]
+pub unsafe extern "C" fn allowed_tokens_mask(
+    tokenizer_ptr: *mut TokenizerHandle,
+    prefix: *const u8,
+    len: u32,
+) -> BytesOrError {
+    BytesOrError::from_result(catch_panic(|| {
+        let handle = convert_to_handle_ref(tokenizer_ptr)?;
+        let prefix: &[u8] = if len == 0 {
+            &[]
+        } else {
+            unsafe { std::slice::from_raw_parts(prefix, len as usize) }
+        };
+        Ok(handle.read().vocab_trie().allowed_tokens_mask(prefix))
+    }))
+}
+
+// VOCAB_TRIE_MAGIC and VOCAB_TRIE_VERSION identify the binary format of the trie returned by `export_vocab_trie`.
+const VOCAB_TRIE_MAGIC: &[u8; 4] = b"GTVT";
+const VOCAB_TRIE_VERSION: u32 = 1;
+
+/// export_vocab_trie returns the prefix trie of the vocabulary used by `allowed_tokens_mask`, serialized as a
+/// compact binary blob, so client-side autocomplete and constrained decoding engines can do prefix matching
+/// consistent with this tokenizer. The trie is keyed by the surface form of the tokens (the bytes they decode
+/// to), and special tokens are not included.
+///
+/// The blob is made of, with all integers as little-endian uint32:
+///
+/// - Header: the magic "GTVT", the format version (1), `num_nodes`, `num_edges` and `num_ids`.
+/// - Nodes: `num_nodes` entries of (first_edge, num_edges, ids_start, ids_end, subtree_end). The node 0 is the
+///   root. The children of a node are the edges `first_edge..first_edge+num_edges`, sorted by byte. The tokens
+///   ending at the node are `ids[ids_start..ids_end]`, and the tokens of its whole subtree (the ones starting with
+///   the prefix of the node) are `ids[ids_start..subtree_end]`.
+/// - Edge targets: `num_edges` child node indices.
+/// - Ids: `num_ids` token ids.
+/// - Edge bytes: `num_edges` bytes, the byte of each edge.
+///
+/// It is returned in a BytesOrError, that must be freed with `free_bytes`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("export_vocab_trie"))]
+pub unsafe extern "C" fn export_vocab_trie(tokenizer_ptr: *mut TokenizerHandle) -> BytesOrError {
+    BytesOrError::from_result(catch_panic(|| Ok(convert_to_handle_ref(tokenizer_ptr)?.read().vocab_trie().to_bytes())))
+}
+
+fn get_template_special_tokens_impl(tokenizer_ptr: *mut TokenizerHandle) -> Result<Vec<u8>, Box<dyn Error>> {
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    Ok(serde_json::to_vec(&template_special_tokens(&state.tokenizer)?)?)
+}
+
+/// get_template_special_tokens returns the special tokens inserted by the post-processor (TemplateProcessing,
+/// BertProcessing or RobertaProcessing), so generation code can find the BOS/EOS token ids of the model. It is
+/// returned as a JSON object with the keys:
+///
+/// - `single` and `pair`: arrays with the special tokens inserted for single and pair inputs, in order.
+/// - `bos`: array with the special tokens inserted before the (first) sequence.
+/// - `eos`: array with the special tokens inserted after the (last) sequence.
+///
+/// Each special token is an object with the `token` string and its `id`. The arrays are empty if the
+/// tokenizer has no such post-processor.
+///
+/// The result is returned in a BytesOrError, that must be freed with `free_bytes`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("get_template_special_tokens"))]
+pub unsafe extern "C" fn get_template_special_tokens(tokenizer_ptr: *mut TokenizerHandle) -> BytesOrError {
+    BytesOrError::from_result(catch_panic(|| get_template_special_tokens_impl(tokenizer_ptr)))
+}
+
+fn encode_with_generation_budget_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    text: &str,
+    reserve_for_generation: u32,
+    options: EncodeParams,
+    dropped: *mut u64,
+) -> Result<Vec<Buffer>, Box<dyn Error>> {
+    options.validate()?;
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    state.encode_limits().check(std::iter::once(text.len()))?;
+    let tokenizer = tokenizer_for(&state, &options);
+    let truncation = tokenizer
+        .get_truncation()
+        .ok_or_else(|| err("encoding with a generation budget requires the truncation to be configured"))?;
+    let add_special_tokens = options.has(ENCODE_ADD_SPECIAL_TOKENS);
+    let num_special_tokens = match tokenizer.get_post_processor() {
+        Some(processor) if add_special_tokens => processor.added_tokens(false),
+        _ => 0,
+    };
+    let max_prompt_length = truncation
+        .max_length
+        .checked_sub(reserve_for_generation as usize + num_special_tokens)
+        .ok_or_else(|| err(format!(
+            "reserve_for_generation {} (plus {} special tokens) exceeds the truncation max_length {}",
+            reserve_for_generation, num_special_tokens, truncation.max_length)))?;
+
+    // The prompt is truncated before the post-processing: the truncation of the tokenizer is then a no-op.
+    let offsets_type = if options.has(ENCODE_WITH_OFFSETS_CHAR_MODE) { OffsetType::Char } else { OffsetType::Byte };
+    let normalize = !options.has(ENCODE_SKIP_NORMALIZATION);
+    let encoding = crate::profile::encode_sequence(tokenizer, text, 0, offsets_type, normalize)
+        .map_err(|e| err(format!("encoding failed: {}", e)))?;
+    let num_dropped = encoding.len().saturating_sub(max_prompt_length);
+    let params = TruncationParams { max_length: max_prompt_length, ..*truncation };
+    let (encoding, _) = truncate_encodings(encoding, None, &params).map_err(|e| err(format!("truncation failed: {}", e)))?;
+    let encoding = tokenizer
+        .post_process(encoding, None, add_special_tokens)
+        .map_err(|e| err(format!("encoding failed: {}", e)))?;
+    if let Some(stats) = state.sequence_stats() {
+        stats.record(std::slice::from_ref(&encoding));
+    }
+    let scores = options.has(ENCODE_RETURN_DEBUG_INFO).then(|| state.token_scores());
+    let word_starts = options.has(ENCODE_RETURN_WORD_STARTS).then(|| WordStarts::new(tokenizer));
+    let buffer = encode_process(encoding, &[text], &options, scores.as_deref(), word_starts.as_ref())?;
+    if let Some(dropped) = unsafe { dropped.as_mut() } {
+        *dropped = num_dropped as u64;
+    }
+    Ok(vec![buffer])
+}
+
+/// encode_with_generation_budget encodes the `len` bytes of the UTF-8 `text` (a prompt) using given tokenizer and
+/// EncodeParams, as `encode_v2` does, but truncated to leave room for `reserve_for_generation` generated tokens:
+/// the prompt (with its special tokens) is truncated to the configured truncation `max_length` minus
+/// `reserve_for_generation`, with the configured truncation direction and stride. It is an error if the
+/// truncation is not configured (see `set_truncation`), or if the reserve doesn't fit in `max_length`.
+///
+/// The number of tokens of the prompt dropped by the truncation is written to `dropped`, if not null.
+///
+/// The results must be freed with `free_encode_results_v2`.
+///
+/// # Safety
+///
+/// `text` must point to `len` bytes, and `dropped` must be null or point to a u64.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encode_with_generation_budget"))]
+pub unsafe extern "C" fn encode_with_generation_budget(
+    tokenizer_ptr: *mut TokenizerHandle,
+    text: *const u8,
+    len: u32,
+    reserve_for_generation: u32,
+    options: EncodeParams,
+    dropped: *mut u64,
+) -> EncodeResultsV2 {
+    let lengths = [len];
+    result_to_encode_results_v2(catch_panic(|| {
+        let texts = messages_from_bytes(1, &text, lengths.as_ptr())?;
+        encode_with_generation_budget_impl(tokenizer_ptr, texts[0], reserve_for_generation, options, dropped)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use crate::encode::{free_encode_results_v2, ENCODE_PARAMS_VERSION};
+    use crate::testing::{take_bytes, take_error, TestHandle, BYTE_LEVEL_BPE, WORDPIECE, WORDPIECE_BERT};
+
+    // heal returns the ids and the prefix returned by `token_healing` for the `prompt`.
+    fn heal(handle: &TestHandle, prompt: &CStr) -> (Vec<u32>, String) {
+        let healing = unsafe { token_healing(handle.0, prompt.as_ptr(), false) };
+        assert_eq!(take_error(healing.error), None);
+        let ids = unsafe { std::slice::from_raw_parts(healing.ids, healing.len as usize) }.to_vec();
+        let prefix = unsafe { CStr::from_ptr(healing.prefix) }.to_str().unwrap().to_string();
+        unsafe { free_token_healing(TokenHealing { error: null_mut(), ..healing }) };
+        (ids, prefix)
+    }
+
+    #[test]
+    fn token_surfaces() {
+        let handle = TestHandle::new(BYTE_LEVEL_BPE);
+        let state = convert_to_handle_ref(handle.0).unwrap().read();
+        let surfaces = TokenSurfaces::new(&state.tokenizer);
+        assert_eq!(surfaces.surface("Ġworld"), b" world");
+        assert_eq!(surfaces.surface("â"), [0xE2]);
+
+        let handle = TestHandle::new(WORDPIECE);
+        let state = convert_to_handle_ref(handle.0).unwrap().read();
+        let surfaces = TokenSurfaces::new(&state.tokenizer);
+        assert_eq!(surfaces.surface("##b"), b"b");
+        assert_eq!(surfaces.surface("world"), b" world");
+    }
+
+    #[test]
+    fn token_healing_partial_tokens() {
+        let handle = TestHandle::new(BYTE_LEVEL_BPE);
+        // "Ġwor" may continue as "Ġworl" or "Ġworld": it is removed, with the space before it.
+        assert_eq!(heal(&handle, c"hello wor"), (vec![11], " wor".to_string()));
+        assert_eq!(heal(&handle, c"hello world"), (vec![11, 17], String::new()));
+        assert_eq!(heal(&handle, c""), (vec![], String::new()));
+        assert!(take_error(unsafe { token_healing(null_mut(), c"hello".as_ptr(), false) }.error).is_some());
+    }
+
+    // allowed_ids returns the ids set in the mask returned by `allowed_tokens_mask` for the `prefix`.
+    fn allowed_ids(handle: &TestHandle, prefix: &str) -> Vec<u32> {
+        let mask = take_bytes(unsafe { allowed_tokens_mask(handle.0, prefix.as_ptr(), prefix.len() as u32) }).unwrap();
+        assert_eq!(mask.len(), 3);  // Ids up to 18.
+        (0..mask.len() as u32 * 8).filter(|&id| mask[id as usize / 8] & (1 << (id % 8)) != 0).collect()
+    }
+
+    #[test]
+    fn allowed_tokens() {
+        let handle = TestHandle::new(BYTE_LEVEL_BPE);
+        // "Ġ" and "Ġw" consume part of the prefix, the others complete it.
+        assert_eq!(allowed_ids(&handle, " wo"), [4, 13, 15, 16, 17]);
+        assert_eq!(allowed_ids(&handle, "hel"), [0, 8, 10, 11]);
+        // All tokens but the special ones.
+        assert_eq!(allowed_ids(&handle, "").len(), 18);
+        assert!(allowed_ids(&handle, "x").is_empty());
+    }
+
+    // trie_ids looks up the `prefix` in the trie `blob` exported by `export_vocab_trie`, and returns the ids of
+    // the tokens ending at its node, and of its whole subtree, sorted.
+    fn trie_ids(blob: &[u8], prefix: &[u8]) -> (Vec<u32>, Vec<u32>) {
+        let u32_at = |index: usize| u32::from_le_bytes(blob[4 + index * 4..8 + index * 4].try_into().unwrap()) as usize;
+        let (num_nodes, num_edges, num_ids) = (u32_at(1), u32_at(2), u32_at(3));
+        let node_field = |node: usize, field: usize| u32_at(4 + node * 5 + field);
+        let edges_start = 4 + num_nodes * 5;
+        let ids_start = edges_start + num_edges;
+        let edge_bytes = &blob[4 + (ids_start + num_ids) * 4..];
+        assert_eq!(edge_bytes.len(), num_edges);
+        let mut node = 0;
+        for &b in prefix {
+            let first_edge = node_field(node, 0);
+            let edge = (first_edge..first_edge + node_field(node, 1)).find(|&edge| edge_bytes[edge] == b).unwrap();
+            node = u32_at(edges_start + edge);
+        }
+        let ids = |end_field| {
+            let mut ids: Vec<u32> = (node_field(node, 2)..node_field(node, end_field))
+                .map(|i| u32_at(ids_start + i) as u32)
+                .collect();
+            ids.sort();
+            ids
+        };
+        (ids(3), ids(4))
+    }
+
+    #[test]
+    fn vocab_trie() {
+        let handle = TestHandle::new(BYTE_LEVEL_BPE);
+        let blob = take_bytes(unsafe { export_vocab_trie(handle.0) }).unwrap();
+        assert_eq!(&blob[..8], b"GTVT\x01\0\0\0");
+        assert_eq!(trie_ids(&blob, b"hell"), (vec![10], vec![10, 11]));
+        assert_eq!(trie_ids(&blob, b" wor"), (vec![15], vec![15, 16, 17]));
+        assert_eq!(trie_ids(&blob, b"\xE2"), (vec![18], vec![18]));
+        // All tokens but the special ones.
+        assert_eq!(trie_ids(&blob, b"").1.len(), 18);
+        assert!(take_bytes(unsafe { export_vocab_trie(null_mut()) }).is_err());
+    }
+
+    #[test]
+    fn generation_budget() {
+        let handle = TestHandle::new(WORDPIECE_BERT);
+        let text = "hello ab world";
+        let encode = |reserve, flags| -> Result<(Vec<u32>, u64), String> {
+            let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags };
+            let mut dropped = 99;
+            let (bytes, len) = (text.as_ptr(), text.len() as u32);
+            let results = unsafe { encode_with_generation_budget(handle.0, bytes, len, reserve, options, &mut dropped) };
+            if let Some(e) = take_error(results.error) {
+                return Err(e);
+            }
+            let buffer = unsafe { &*results.encoded };
+            let ids = unsafe { std::slice::from_raw_parts(buffer.ids, buffer.len as usize) }.to_vec();
+            unsafe { free_encode_results_v2(results) };
+            Ok((ids, dropped))
+        };
+        assert_eq!(
+            encode(2, ENCODE_ADD_SPECIAL_TOKENS).unwrap_err(),
+            "encoding with a generation budget requires the truncation to be configured");
+
+        // Left truncation to 6 tokens: the prompt has room for 2 tokens, with [CLS] and [SEP].
+        let truncation = crate::configure::truncation_params(0, 0, 6, 0).unwrap();
+        let mut state = convert_to_handle_ref(handle.0).unwrap().write().unwrap();
+        state.tokenizer_mut().with_truncation(Some(truncation)).unwrap();
+        drop(state);
+        assert_eq!(encode(2, ENCODE_ADD_SPECIAL_TOKENS), Ok((vec![10, 3, 6, 11], 2)));
+        assert_eq!(encode(2, 0), Ok((vec![4, 1, 3, 6], 0)));
+        assert_eq!(encode(6, 0), Ok((vec![], 4)));
+        assert_eq!(
+            encode(5, ENCODE_ADD_SPECIAL_TOKENS).unwrap_err(),
+            "reserve_for_generation 5 (plus 2 special tokens) exceeds the truncation max_length 6");
+    }
+
+    // template_tokens returns the JSON returned by `get_template_special_tokens` for the tokenizer `json`.
+    fn template_tokens(json: &str) -> Value {
+        let handle = TestHandle::new(json);
+        serde_json::from_slice(&take_bytes(unsafe { get_template_special_tokens(handle.0) }).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn template_special_tokens() {
+        let cls = json!({"token": "[CLS]", "id": 10});
+        let sep = json!({"token": "[SEP]", "id": 11});
+        assert_eq!(template_tokens(WORDPIECE_BERT), json!({
+            "single": [cls, sep], "pair": [cls, sep, sep], "bos": [cls], "eos": [sep],
+        }));
+        // Bert post-processors are read as the equivalent template.
+        let bert = WORDPIECE_BERT.replacen(
+            r#""type": "TemplateProcessing","#, r#""type": "BertProcessing", "sep": ["[SEP]", 11], "cls": ["[CLS]", 10],"#, 1);
+        assert_eq!(template_tokens(&bert)["pair"], json!([cls, sep, sep]));
+        assert_eq!(template_tokens(WORDPIECE), json!({"single": [], "pair": [], "bos": [], "eos": []}));
+    }
+}
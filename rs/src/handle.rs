@@ -0,0 +1,754 @@
+use std::error::Error;
+use std::ops::Deref;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::ffi::{c_char, c_void};
+use std::str::FromStr;
+use serde_json::Value;
+use tokenizers::models::ModelWrapper;
+use tokenizers::tokenizer::{PaddingParams, Tokenizer, TruncationParams};
+use crate::compat::{apply_compat_options, restore_components, CompatOptions, StashedComponents};
+use crate::config::TokenizerConfig;
+use crate::debug::TokenScores;
+use crate::decode::HostDecoder;
+use crate::encode::err;
+use crate::generation::VocabTrie;
+use crate::limits::EncodeLimits;
+use crate::panics::{catch_panic, catch_panic_or, error_c_string};
+use crate::stats::SequenceStatsCollector;
+use crate::vocab::TokenStrings;
+use crate::PointerOrError;
+
+/// TokenizerHandle is the object returned to Golang by `from_bytes`: an opaque `TokenizerHandle *` in C.
+///
+/// It is reference counted (an `Arc`): see `tokenizer_retain` and `tokenizer_release`. Since it can
+/// be shared, the Tokenizer and the per-tokenizer options are kept behind a `RwLock` -- except for
+/// frozen handles (see `freeze`), which are immutable and don't need locking.
+pub struct TokenizerHandle {
+    state: HandleState,
+
+    // generation identifies the current version of the state: it changes whenever the state is locked for
+    // writing, and it is unique across handles (see `next_generation`).
+    generation: AtomicU64,
+}
+
+// next_generation returns a new, unique, generation for the state of a handle.
+fn next_generation() -> u64 {
+    static GENERATIONS: AtomicU64 = AtomicU64::new(1);
+    GENERATIONS.fetch_add(1, Ordering::Relaxed)
+}
+
+enum HandleState {
+    Mutable(RwLock<TokenizerState>),
+    Frozen(TokenizerState),
+}
+
+/// TokenizerState holds the Tokenizer along with the per-tokenizer options of the binding, that are not
+/// part of the `tokenizer.json` definitions.
+#[derive(Clone)]
+pub struct TokenizerState {
+    // tokenizer may be shared with the handles loaded from the same contents, see `tokenizer_mut`.
+    pub tokenizer: Arc<Tokenizer>,
+
+    // deterministic mode disables any stochastic behavior of the model (e.g.: BPE dropout).
+    deterministic: bool,
+
+    // stashed_dropout holds the BPE dropout while deterministic mode is on, so it can be restored.
+    stashed_dropout: Option<f32>,
+
+    // compat holds the compatibility options (see `compat.rs`), and stashed_components the original
+    // pipeline components while they are not the defaults.
+    compat: CompatOptions,
+    stashed_components: Option<StashedComponents>,
+
+    // host_decoder replaces the decoder of the tokenizer when decoding, see `set_host_decoder`.
+    host_decoder: Option<HostDecoder>,
+
+    // sequence_stats collects the statistics of the encoded sequences, if enabled (see `set_sequence_stats`).
+    // It is shared with the copies of the state.
+    sequence_stats: Option<Arc<SequenceStatsCollector>>,
+
+    // config holds the settings of the `tokenizer_config.json` file, if given (see `set_tokenizer_config`).
+    config: Option<Arc<TokenizerConfig>>,
+
+    // encode_limits are the limits on the inputs of the encode calls (see `set_encode_limits`).
+    encode_limits: EncodeLimits,
+
+    // Caches derived from the tokenizer: built on first use, and cleared whenever the state is locked
+    // for writing.
+    vocab_trie: OnceLock<Arc<VocabTrie>>,
+    token_strings: OnceLock<Arc<TokenStrings>>,
+    token_scores: OnceLock<Arc<TokenScores>>,
+    toggled_encode_special_tokens: OnceLock<Arc<Tokenizer>>,
+}
+
+impl TokenizerHandle {
+    /// Returns a new handle for the `tokenizer`: either owned, or an `Arc` shared with other handles (see
+    /// `shared_from_bytes`), which is copied the first time the handle modifies it.
+    pub fn new(tokenizer: impl Into<Arc<Tokenizer>>) -> Self {
+        TokenizerHandle {
+            state: HandleState::Mutable(RwLock::new(TokenizerState {
+                tokenizer: tokenizer.into(),
+                deterministic: false,
+                stashed_dropout: None,
+                compat: CompatOptions::default(),
+                stashed_components: None,
+                host_decoder: None,
+                sequence_stats: None,
+                config: None,
+                encode_limits: EncodeLimits::default(),
+                vocab_trie: OnceLock::new(),
+                token_strings: OnceLock::new(),
+                token_scores: OnceLock::new(),
+                toggled_encode_special_tokens: OnceLock::new(),
+            })),
+            generation: AtomicU64::new(next_generation()),
+        }
+    }
+
+    /// Converts the handle to a C `void *` owning one reference to it.
+    pub fn into_raw(self: Arc<Self>) -> *mut c_void {
+        Arc::into_raw(self).cast_mut().cast()
+    }
+
+    /// Returns the generation of the state, which changes whenever it may have been modified. It is unique
+    /// across handles, so it identifies the state.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    /// Locks the state for reading -- frozen handles are not locked. A lock poisoned by a panic is
+    /// still used, since the state is never left half-updated.
+    pub fn read(&self) -> StateGuard<'_> {
+        match &self.state {
+            HandleState::Mutable(lock) => StateGuard::Locked(lock.read().unwrap_or_else(|e| e.into_inner())),
+            HandleState::Frozen(state) => StateGuard::Frozen(state),
+        }
+    }
+
+    /// Locks the state for writing. It fails if the handle is frozen.
+    ///
+    /// Since the Tokenizer may be changed, the caches derived from it are cleared.
+    pub fn write(&self) -> Result<RwLockWriteGuard<'_, TokenizerState>, Box<dyn Error>> {
+        match &self.state {
+            HandleState::Mutable(lock) => {
+                let mut state = lock.write().unwrap_or_else(|e| e.into_inner());
+                state.clear_caches();
+                self.generation.store(next_generation(), Ordering::Relaxed);
+                Ok(state)
+            }
+            HandleState::Frozen(_) => Err(err("tokenizer is frozen (read-only), it can't be modified")),
+        }
+    }
+
+    /// Modifies the state with `modify` without blocking the other calls while it runs (e.g. for the modifications
+    /// that parse a Tokenizer): it is applied to a copy of the state, which then replaces it. If the state was
+    /// modified in the meantime, `modify` is applied again to the current state, while locked for writing.
+    ///
+    /// If `modify` fails, the state is kept. It fails if the handle is frozen.
+    pub fn update(
+        &self,
+        modify: impl Fn(&mut TokenizerState) -> Result<(), Box<dyn Error>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let lock = match &self.state {
+            HandleState::Mutable(lock) => lock,
+            HandleState::Frozen(_) => return Err(err("tokenizer is frozen (read-only), it can't be modified")),
+        };
+        // The generation only changes while the state is locked for writing.
+        let (generation, mut updated) = {
+            let state = lock.read().unwrap_or_else(|e| e.into_inner());
+            (self.generation(), state.clone())
+        };
+        modify(&mut updated)?;
+        let mut state = lock.write().unwrap_or_else(|e| e.into_inner());
+        if self.generation() == generation {
+            updated.clear_caches();
+            *state = updated;
+        } else {
+            let mut updated = state.clone();
+            modify(&mut updated)?;
+            updated.clear_caches();
+            *state = updated;
+        }
+        self.generation.store(next_generation(), Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// StateGuard gives read access to the TokenizerState of a handle, see `TokenizerHandle::read`.
+pub enum StateGuard<'a> {
+    Locked(RwLockReadGuard<'a, TokenizerState>),
+    Frozen(&'a TokenizerState),
+}
+
+impl Deref for StateGuard<'_> {
+    type Target = TokenizerState;
+
+    fn deref(&self) -> &TokenizerState {
+        match self {
+            StateGuard::Locked(guard) => guard,
+            StateGuard::Frozen(state) => state,
+        }
+    }
+}
+
+impl TokenizerState {
+    /// Enables or disables deterministic mode.
+    ///
+    /// While enabled, the BPE dropout (if any) is removed from the model, and restored once it is disabled.
+    /// Unigram models always use the best (Viterbi) segmentation when encoding, so there is nothing to disable.
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        if deterministic == self.deterministic {
+            return;
+        }
+        self.deterministic = deterministic;
+        if let ModelWrapper::BPE(bpe) = self.tokenizer.get_model() {
+            let mut bpe = bpe.clone();
+            if deterministic {
+                self.stashed_dropout = bpe.dropout.take();
+            } else {
+                bpe.dropout = self.stashed_dropout.take();
+            }
+            self.tokenizer_mut().with_model(bpe);
+        }
+    }
+
+    /// Returns the Tokenizer for modification. It may be shared with other handles loaded from the same
+    /// contents (see `from_bytes`), in which case it is copied first, so the others are not affected.
+    pub fn tokenizer_mut(&mut self) -> &mut Tokenizer {
+        Arc::make_mut(&mut self.tokenizer)
+    }
+
+    pub fn is_deterministic(&self) -> bool {
+        self.deterministic
+    }
+
+    /// Returns the trie of the vocabulary of the Tokenizer, building it on first use.
+    pub fn vocab_trie(&self) -> &VocabTrie {
+        self.vocab_trie.get_or_init(|| Arc::new(VocabTrie::new(&self.tokenizer)))
+    }
+
+    /// Returns the table of the strings of the tokens of the Tokenizer, building it on first use: encode-only
+    /// workloads never build it. See `preload_token_strings`.
+    pub fn token_strings(&self) -> &TokenStrings {
+        self.token_strings.get_or_init(|| Arc::new(TokenStrings::new(&self.tokenizer)))
+    }
+
+    /// Returns the model scores of the tokens of the Tokenizer, building them on first use.
+    pub fn token_scores(&self) -> Arc<TokenScores> {
+        self.token_scores.get_or_init(|| Arc::new(TokenScores::new(&self.tokenizer))).clone()
+    }
+
+    // clear_caches clears the caches derived from the Tokenizer.
+    fn clear_caches(&mut self) {
+        self.vocab_trie = OnceLock::new();
+        self.token_strings = OnceLock::new();
+        self.token_scores = OnceLock::new();
+        self.toggled_encode_special_tokens = OnceLock::new();
+    }
+
+    /// Returns the Tokenizer with the given `encode_special_tokens` setting (see `set_encode_special_tokens`):
+    /// if it differs from the current one, a copy with the setting toggled is built on first use.
+    pub fn tokenizer_with_encode_special_tokens(&self, value: bool) -> &Tokenizer {
+        if self.tokenizer.get_encode_special_tokens() == value {
+            return &self.tokenizer;
+        }
+        self.toggled_encode_special_tokens.get_or_init(|| {
+            let mut tokenizer = Tokenizer::clone(&self.tokenizer);
+            tokenizer.set_encode_special_tokens(value);
+            Arc::new(tokenizer)
+        })
+    }
+
+    pub fn host_decoder(&self) -> Option<&HostDecoder> {
+        self.host_decoder.as_ref()
+    }
+
+    pub fn set_host_decoder(&mut self, host_decoder: Option<HostDecoder>) {
+        self.host_decoder = host_decoder;
+    }
+
+    pub fn sequence_stats(&self) -> Option<&SequenceStatsCollector> {
+        self.sequence_stats.as_deref()
+    }
+
+    pub fn set_sequence_stats(&mut self, sequence_stats: Option<Arc<SequenceStatsCollector>>) {
+        self.sequence_stats = sequence_stats;
+    }
+
+    pub fn config(&self) -> Option<&TokenizerConfig> {
+        self.config.as_deref()
+    }
+
+    pub fn set_config(&mut self, config: Option<Arc<TokenizerConfig>>) {
+        self.config = config;
+    }
+
+    pub fn encode_limits(&self) -> EncodeLimits {
+        self.encode_limits
+    }
+
+    pub fn set_encode_limits(&mut self, limits: EncodeLimits) {
+        self.encode_limits = limits;
+    }
+
+    pub fn compat_options(&self) -> CompatOptions {
+        self.compat
+    }
+
+    /// Sets the compatibility options, editing the pipeline of the Tokenizer accordingly.
+    pub fn set_compat_options(&mut self, options: CompatOptions) -> Result<(), Box<dyn Error>> {
+        if options == self.compat {
+            return Ok(());
+        }
+        let (tokenizer, stashed) = apply_compat_options(&self.tokenizer, self.stashed_components.as_ref(), &options)?;
+        self.tokenizer = Arc::new(tokenizer);
+        self.stashed_components = stashed;
+        self.compat = options;
+        Ok(())
+    }
+
+    /// Returns the serialized Tokenizer without the per-tokenizer options (the compatibility options and the
+    /// deterministic mode), so its pipeline can be edited, and set back with `replace_tokenizer`.
+    pub fn pipeline_json(&self) -> Result<Value, Box<dyn Error>> {
+        let mut json = serde_json::to_value(&*self.tokenizer)?;
+        if let Some(stashed) = &self.stashed_components {
+            restore_components(&mut json, stashed);
+        }
+        if let Some(dropout) = self.stashed_dropout {
+            json["model"]["dropout"] = Value::from(dropout);
+        }
+        Ok(json)
+    }
+
+    /// Returns the `tokenizer.json` contents of the pipeline (see `pipeline_json`), indented if `pretty` is set.
+    /// Without per-tokenizer options, the Tokenizer is serialized directly, which keeps the order of the fields
+    /// (and of the vocabulary, by id) of the files written by the tokenizers library.
+    pub fn pipeline_json_string(&self, pretty: bool) -> Result<String, Box<dyn Error>> {
+        if self.stashed_components.is_none() && self.stashed_dropout.is_none() {
+            return self.tokenizer.to_string(pretty).map_err(|e| err(e.to_string()));
+        }
+        let json = self.pipeline_json()?;
+        Ok(if pretty { serde_json::to_string_pretty(&json)? } else { serde_json::to_string(&json)? })
+    }
+
+    /// Replaces the Tokenizer, keeping the per-tokenizer options (re-applied to the new Tokenizer).
+    ///
+    /// If the options can't be applied to the new Tokenizer, it returns an error, and the current Tokenizer
+    /// is kept.
+    ///
+    /// A shared Tokenizer (see `shared_from_bytes`) is only copied if the options modify it.
+    pub fn replace_tokenizer(&mut self, tokenizer: impl Into<Arc<Tokenizer>>) -> Result<(), Box<dyn Error>> {
+        let (mut tokenizer, stashed) = if self.compat == CompatOptions::default() {
+            (tokenizer.into(), None)
+        } else {
+            let (tokenizer, stashed) = apply_compat_options(&tokenizer.into(), None, &self.compat)?;
+            (Arc::new(tokenizer), stashed)
+        };
+        let encode_special_tokens = self.tokenizer.get_encode_special_tokens();
+        if tokenizer.get_encode_special_tokens() != encode_special_tokens {
+            Arc::make_mut(&mut tokenizer).set_encode_special_tokens(encode_special_tokens);
+        }
+        self.tokenizer = tokenizer;
+        self.stashed_components = stashed;
+        self.stashed_dropout = None;
+        if self.deterministic {
+            self.deterministic = false;
+            self.set_deterministic(true);
+        }
+        Ok(())
+    }
+}
+
+/// ConfigSnapshot is a copy of the configuration of a TokenizerState (see `TokenizerState::config_snapshot`):
+/// padding, truncation, added tokens and the per-tokenizer options.
+pub struct ConfigSnapshot {
+    padding: Option<PaddingParams>,
+    truncation: Option<TruncationParams>,
+    added_tokens: Value,
+    encode_special_tokens: bool,
+    deterministic: bool,
+    compat: CompatOptions,
+    host_decoder: Option<HostDecoder>,
+    sequence_stats: Option<Arc<SequenceStatsCollector>>,
+    config: Option<Arc<TokenizerConfig>>,
+    encode_limits: EncodeLimits,
+}
+
+impl TokenizerState {
+    /// Returns a snapshot of the configuration, to be restored with `restore_config`.
+    pub fn config_snapshot(&self) -> Result<ConfigSnapshot, Box<dyn Error>> {
+        Ok(ConfigSnapshot {
+            padding: self.tokenizer.get_padding().cloned(),
+            truncation: self.tokenizer.get_truncation().cloned(),
+            added_tokens: serde_json::to_value(&*self.tokenizer)?["added_tokens"].take(),
+            encode_special_tokens: self.tokenizer.get_encode_special_tokens(),
+            deterministic: self.deterministic,
+            compat: self.compat,
+            host_decoder: self.host_decoder,
+            sequence_stats: self.sequence_stats.clone(),
+            config: self.config.clone(),
+            encode_limits: self.encode_limits,
+        })
+    }
+
+    /// Restores the configuration of the `snapshot`. The added vocabulary is only rebuilt if the added tokens
+    /// changed.
+    ///
+    /// It is all or nothing: if it fails, the current configuration is kept.
+    pub fn restore_config(&mut self, snapshot: &ConfigSnapshot) -> Result<(), Box<dyn Error>> {
+        let mut restored = self.clone();
+        let mut json = restored.pipeline_json()?;
+        if json["added_tokens"] != snapshot.added_tokens {
+            json["added_tokens"] = snapshot.added_tokens.clone();
+            let tokenizer = Tokenizer::from_str(&json.to_string())
+                .map_err(|e| err(format!("failed to restore added tokens: {}", e)))?;
+            restored.replace_tokenizer(tokenizer)?;
+        }
+        restored.set_compat_options(snapshot.compat)?;
+        restored.set_deterministic(snapshot.deterministic);
+        restored.tokenizer_mut().with_padding(snapshot.padding.clone());
+        restored
+            .tokenizer_mut()
+            .with_truncation(snapshot.truncation.clone())
+            .map_err(|e| err(format!("failed to restore truncation: {}", e)))?;
+        restored.tokenizer_mut().set_encode_special_tokens(snapshot.encode_special_tokens);
+        restored.host_decoder = snapshot.host_decoder;
+        restored.sequence_stats = snapshot.sequence_stats.clone();
+        restored.config = snapshot.config.clone();
+        restored.encode_limits = snapshot.encode_limits;
+        *self = restored;
+        Ok(())
+    }
+}
+
+// convert_to_handle_ref given a C `TokenizerHandle *`.
+pub fn convert_to_handle_ref<'a>(tokenizer_ptr: *mut TokenizerHandle) -> Result<&'a TokenizerHandle, Box<dyn Error>> {
+    unsafe {
+        match tokenizer_ptr.as_ref() {
+            Some(h) => Ok(h),
+            None => Err(err("tokenizer passed is null")),
+        }
+    }
+}
+
+/// tokenizer_retain increments the reference count of the tokenizer, so it can be independently held
+/// (and released) by different owners.
+///
+/// Each call must be matched by a call to `tokenizer_release`.
+///
+/// # Safety
+///
+/// `tokenizer_ptr` must be a valid tokenizer, that is, one that was not yet fully released.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("tokenizer_retain"))]
+pub unsafe extern "C" fn tokenizer_retain(tokenizer_ptr: *mut TokenizerHandle) {
+    catch_panic_or((), || {
+        if tokenizer_ptr.is_null() {
+            return;
+        }
+        unsafe {
+            Arc::increment_strong_count(tokenizer_ptr.cast_const());
+        }
+    })
+}
+
+/// tokenizer_release decrements the reference count of the tokenizer, and frees it when the last
+/// reference is released.
+///
+/// # Safety
+///
+/// `tokenizer_ptr` must be a valid tokenizer, and the caller must own the reference being released.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("tokenizer_release"))]
+pub unsafe extern "C" fn tokenizer_release(tokenizer_ptr: *mut TokenizerHandle) {
+    catch_panic_or((), || {
+        if tokenizer_ptr.is_null() {
+            return;
+        }
+        crate::record::record_release(tokenizer_ptr);
+        unsafe {
+            Arc::decrement_strong_count(tokenizer_ptr.cast_const());
+        }
+    })
+}
+
+fn tokenizer_reload_impl(tokenizer_ptr: *mut TokenizerHandle, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let tokenizer =
+        crate::load::shared_from_bytes(bytes).map_err(|e| err(format!("failed to reload tokenizer: {}", e)))?;
+    handle.update(|state| state.replace_tokenizer(Arc::clone(&tokenizer)))
+}
+
+/// tokenizer_reload parses a new Tokenizer from the json contents of a `tokenizer.json` file (see
+/// `from_bytes`) and atomically swaps it in place of the current one, behind the same handle: all existing
+/// references remain valid, and pick up the new Tokenizer. Per-tokenizer options (e.g.: deterministic mode)
+/// are preserved. As with `from_bytes`, the new Tokenizer is shared with the other handles loaded from the same
+/// contents.
+///
+/// The parsing, and the application of the per-tokenizer options, happen without holding the tokenizer lock,
+/// so concurrent encode/decode calls are only blocked for the swap itself. If parsing fails, the current
+/// Tokenizer is kept.
+///
+/// It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+/// The returned string needs to be freed with `free_string`.
+///
+/// # Safety
+///
+/// `bytes` must point to `len` bytes.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("tokenizer_reload"))]
+pub unsafe extern "C" fn tokenizer_reload(tokenizer_ptr: *mut TokenizerHandle, bytes: *const u8, len: u32) -> *mut c_char {
+    let bytes_slice = if len == 0 { &[][..] } else { unsafe { std::slice::from_raw_parts(bytes, len as usize) } };
+    match catch_panic(|| tokenizer_reload_impl(tokenizer_ptr, bytes_slice)) {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+fn set_model_from_json_impl(tokenizer_ptr: *mut TokenizerHandle, model_json: *const c_char) -> Result<(), Box<dyn Error>> {
+    if model_json.is_null() {
+        return Err(err("model_json is null"));
+    }
+    let model_json = unsafe { std::ffi::CStr::from_ptr(model_json) }.to_str()?;
+    // The model is parsed before updating the tokenizer, so a malformed one doesn't block encoding.
+    let model: ModelWrapper =
+        serde_json::from_str(model_json).map_err(|e| err(format!("failed to parse model: {}", e)))?;
+    let model = serde_json::to_value(&model)?;
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    handle.update(|state| {
+        let mut json = state.pipeline_json()?;
+        json["model"] = model.clone();
+        let tokenizer =
+            Tokenizer::from_str(&json.to_string()).map_err(|e| err(format!("failed to set model: {}", e)))?;
+        state.replace_tokenizer(tokenizer)
+    })
+}
+
+/// set_model_from_json replaces the model of the tokenizer (e.g. with a pruned BPE) with the one defined by
+/// `model_json`, the JSON of the `model` field of a `tokenizer.json` file, keeping the rest of the pipeline
+/// (normalizer, pre-tokenizer, post-processor, decoder, padding, truncation and added tokens), e.g. to A/B test
+/// vocabularies behind identical preprocessing. Per-tokenizer options (e.g.: deterministic mode) are preserved.
+///
+/// The added tokens keep their ids: the new vocabulary is expected to be compatible with them. If the model
+/// can't be parsed, the current one is kept.
+///
+/// It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+/// The returned string needs to be freed with `free_string`.
+///
+/// # Safety
+///
+/// `model_json` must be a valid C string.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("set_model_from_json"))]
+pub unsafe extern "C" fn set_model_from_json(tokenizer_ptr: *mut TokenizerHandle, model_json: *const c_char) -> *mut c_char {
+    match catch_panic(|| set_model_from_json_impl(tokenizer_ptr, model_json)) {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+/// freeze returns a new frozen (read-only) handle with a snapshot of the tokenizer and its options, in
+/// the `value` field.
+///
+/// Configuration setters (`set_truncation`, `set_padding`, etc.) on a frozen handle are rejected, and
+/// in exchange encode/decode calls don't need any locking. The original tokenizer is not affected, and can
+/// still be configured.
+///
+/// The caller owns the returned handle, and should free it with `free_tokenizer`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("freeze"))]
+pub unsafe extern "C" fn freeze(tokenizer_ptr: *mut TokenizerHandle) -> PointerOrError {
+    let frozen = catch_panic(|| {
+        let handle = convert_to_handle_ref(tokenizer_ptr)?;
+        Ok(TokenizerHandle {
+            state: HandleState::Frozen(handle.read().clone()),
+            generation: AtomicU64::new(next_generation()),
+        })
+    });
+    match frozen {
+        Ok(frozen) => PointerOrError {
+            value: Arc::new(frozen).into_raw(),
+            error: null_mut(),
+        },
+        Err(e) => PointerOrError {
+            value: null_mut(),
+            error: error_c_string(e),
+        },
+    }
+}
+
+/// is_frozen returns whether the tokenizer is a frozen (read-only) handle, see `freeze`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("is_frozen"))]
+pub unsafe extern "C" fn is_frozen(tokenizer_ptr: *mut TokenizerHandle) -> bool {
+    catch_panic_or(false, || match convert_to_handle_ref(tokenizer_ptr) {
+        Ok(handle) => matches!(handle.state, HandleState::Frozen(_)),
+        Err(_) => false,
+    })
+}
+
+/// set_deterministic enables or disables the deterministic mode of the tokenizer: while enabled, all
+/// stochastic behavior configured in the model (e.g.: BPE dropout) is disabled, so encodings are
+/// reproducible. Disabling it restores the model configuration.
+///
+/// It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+/// The returned string needs to be freed with `free_string`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("set_deterministic"))]
+pub unsafe extern "C" fn set_deterministic(tokenizer_ptr: *mut TokenizerHandle, deterministic: bool) -> *mut c_char {
+    let result = catch_panic(|| {
+        convert_to_handle_ref(tokenizer_ptr)?.write()?.set_deterministic(deterministic);
+        Ok(())
+    });
+    match result {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+/// get_deterministic returns whether the deterministic mode of the tokenizer is enabled.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("get_deterministic"))]
+pub unsafe extern "C" fn get_deterministic(tokenizer_ptr: *mut TokenizerHandle) -> bool {
+    catch_panic_or(false, || match convert_to_handle_ref(tokenizer_ptr) {
+        Ok(handle) => handle.read().is_deterministic(),
+        Err(_) => false,
+    })
+}
+
+/// set_encode_special_tokens sets whether special token strings in the text (e.g. "<|im_end|>") are encoded
+/// as plain text (if true), instead of as the special tokens (the default). Encoding user-supplied text as
+/// plain text prevents it from injecting control tokens. It can be overridden per call with the EncodeParams
+/// flags ENCODE_SPLIT_SPECIAL_TOKENS and ENCODE_MATCH_SPECIAL_TOKENS.
+///
+/// It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+/// The returned string needs to be freed with `free_string`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("set_encode_special_tokens"))]
+pub unsafe extern "C" fn set_encode_special_tokens(tokenizer_ptr: *mut TokenizerHandle, value: bool) -> *mut c_char {
+    let result = catch_panic(|| {
+        convert_to_handle_ref(tokenizer_ptr)?.write()?.tokenizer_mut().set_encode_special_tokens(value);
+        Ok(())
+    });
+    match result {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+/// get_encode_special_tokens returns whether special token strings in the text are encoded as plain text,
+/// see `set_encode_special_tokens`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("get_encode_special_tokens"))]
+pub unsafe extern "C" fn get_encode_special_tokens(tokenizer_ptr: *mut TokenizerHandle) -> bool {
+    catch_panic_or(false, || match convert_to_handle_ref(tokenizer_ptr) {
+        Ok(handle) => handle.read().tokenizer.get_encode_special_tokens(),
+        Err(_) => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{encode_ids, take_error, TestHandle, BYTE_LEVEL_BPE, WORDPIECE};
+
+    fn reload(handle: &TestHandle, json: &str) -> Option<String> {
+        take_error(unsafe { tokenizer_reload(handle.0, json.as_ptr(), json.len() as u32) })
+    }
+
+    #[test]
+    fn reload_shares_tokenizer() {
+        let handle = TestHandle::new(WORDPIECE);
+        let other = TestHandle::new(BYTE_LEVEL_BPE);
+        assert_eq!(encode_ids(&handle, "hello", 0), vec![4]);
+        assert_eq!(reload(&handle, BYTE_LEVEL_BPE), None);
+        assert_eq!(encode_ids(&handle, "hello", 0), vec![11]);
+        let state = convert_to_handle_ref(handle.0).unwrap().read();
+        let other_state = convert_to_handle_ref(other.0).unwrap().read();
+        assert!(Arc::ptr_eq(&state.tokenizer, &other_state.tokenizer));
+    }
+
+    #[test]
+    fn reload_keeps_options() {
+        let handle = TestHandle::new(WORDPIECE);
+        let h = convert_to_handle_ref(handle.0).unwrap();
+        h.write().unwrap().tokenizer_mut().set_encode_special_tokens(true);
+        assert!(reload(&handle, "{").unwrap().starts_with("failed to reload tokenizer: "));
+        assert_eq!(reload(&handle, WORDPIECE), None);
+        assert!(h.read().tokenizer.get_encode_special_tokens());
+        assert_eq!(encode_ids(&handle, "<|im_end|>", 0), vec![7, 9, 8]);
+    }
+
+    // strong_count returns the number of references to the handle `ptr`.
+    fn strong_count(ptr: *mut TokenizerHandle) -> usize {
+        Arc::strong_count(&std::mem::ManuallyDrop::new(unsafe { Arc::from_raw(ptr.cast_const()) }))
+    }
+
+    #[test]
+    fn retain_release() {
+        let handle = TestHandle::new(WORDPIECE);
+        assert_eq!(strong_count(handle.0), 1);
+        unsafe { tokenizer_retain(handle.0) };
+        assert_eq!(strong_count(handle.0), 2);
+
+        // Released by the other owner: the tokenizer is still usable.
+        unsafe { tokenizer_release(handle.0) };
+        assert_eq!(strong_count(handle.0), 1);
+        assert_eq!(encode_ids(&handle, "hello", 0), vec![4]);
+        unsafe { tokenizer_retain(null_mut()) };
+        unsafe { tokenizer_release(null_mut()) };
+    }
+
+    #[test]
+    fn frozen_is_read_only() {
+        let handle = TestHandle::new(WORDPIECE);
+        let result = unsafe { freeze(handle.0) };
+        assert_eq!(take_error(result.error), None);
+        let frozen = TestHandle(result.value.cast());
+        assert!(unsafe { is_frozen(frozen.0) });
+        assert!(!unsafe { is_frozen(handle.0) });
+
+        let error = take_error(unsafe { set_encode_special_tokens(frozen.0, true) });
+        assert_eq!(error.as_deref(), Some("tokenizer is frozen (read-only), it can't be modified"));
+
+        // The original can still be configured, without affecting the frozen snapshot.
+        assert_eq!(take_error(unsafe { set_encode_special_tokens(handle.0, true) }), None);
+        assert_eq!(encode_ids(&handle, "<|im_end|>", 0), vec![7, 9, 8]);
+        assert_eq!(encode_ids(&frozen, "<|im_end|>", 0), vec![5]);
+    }
+
+    #[test]
+    fn set_model() {
+        let handle = TestHandle::new(WORDPIECE);
+        assert_eq!(take_error(unsafe { set_encode_special_tokens(handle.0, true) }), None);
+        let model = concat!(
+            r###"{"type": "WordPiece", "unk_token": "[UNK]", "continuing_subword_prefix": "##", "###,
+            r#""max_input_chars_per_word": 100, "#,
+            r#""vocab": {"[UNK]": 0, "world": 1, "hello": 2, "<|": 3, "|>": 4, "<|im_end|>": 5, "im_end": 6}}"#, "\0");
+        assert_eq!(take_error(unsafe { set_model_from_json(handle.0, model.as_ptr().cast()) }), None);
+        assert_eq!(encode_ids(&handle, "hello world b", 0), vec![2, 1, 0]);
+        // The per-tokenizer options are kept.
+        assert_eq!(encode_ids(&handle, "<|im_end|>", 0), vec![3, 6, 4]);
+
+        let error = take_error(unsafe { set_model_from_json(handle.0, c"{\"type\": \"WordPiece\"}".as_ptr()) });
+        assert!(error.unwrap().starts_with("failed to parse model: "));
+        assert_eq!(encode_ids(&handle, "hello world", 0), vec![2, 1]);
+        let error = take_error(unsafe { set_model_from_json(handle.0, std::ptr::null()) });
+        assert_eq!(error.as_deref(), Some("model_json is null"));
+    }
+
+    #[test]
+    fn deterministic_disables_dropout() {
+        // A dropout of 1.0 drops every merge.
+        let handle = TestHandle::new(&BYTE_LEVEL_BPE.replace(r#""dropout": null"#, r#""dropout": 1.0"#));
+        assert_eq!(encode_ids(&handle, "hello", 0), vec![0, 1, 2, 2, 3]);
+        assert_eq!(take_error(unsafe { set_deterministic(handle.0, true) }), None);
+        assert!(unsafe { get_deterministic(handle.0) });
+        assert_eq!(encode_ids(&handle, "hello", 0), vec![11]);
+        assert_eq!(take_error(unsafe { set_deterministic(handle.0, false) }), None);
+        assert!(!unsafe { get_deterministic(handle.0) });
+        assert_eq!(encode_ids(&handle, "hello", 0), vec![0, 1, 2, 2, 3]);
+    }
+}
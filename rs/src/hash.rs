@@ -0,0 +1,41 @@
+//! Stable hashing of token id sequences, e.g. for dataset deduplication or prompt-cache keys, without copying
+//! the ids back to the host to hash them there.
+
+use xxhash_rust::xxh3::Xxh3;
+use crate::panics::catch_panic_or;
+
+/// hash_ids returns a stable hash of the `len` token `ids`: the XXH3 64-bit hash (with seed 0) of the ids as
+/// little-endian u32 values, so it is the same across platforms and releases, and it can be reproduced
+/// elsewhere (e.g. `xxhash.xxh3_64(numpy.asarray(ids, dtype="<u4").tobytes())` in Python).
+///
+/// # Safety
+///
+/// `ids` must point to `len` ids.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("hash_ids"))]
+pub unsafe extern "C" fn hash_ids(ids: *const u32, len: u64) -> u64 {
+    catch_panic_or(0, || {
+        let ids = if len == 0 { &[][..] } else { unsafe { std::slice::from_raw_parts(ids, len as usize) } };
+        let mut hasher = Xxh3::new();
+        for id in ids {
+            hasher.update(&id.to_le_bytes());
+        }
+        hasher.digest()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xxhash_rust::xxh3::xxh3_64;
+
+    #[test]
+    fn stable_hash() {
+        let ids = [1, 2, 0x01020304];
+        let bytes: Vec<u8> = ids.iter().flat_map(|id: &u32| id.to_le_bytes()).collect();
+        assert_eq!(unsafe { hash_ids(ids.as_ptr(), 3) }, xxh3_64(&bytes));
+        assert_ne!(unsafe { hash_ids(ids.as_ptr(), 2) }, unsafe { hash_ids(ids.as_ptr(), 3) });
+        // The XXH3 64-bit hash of no bytes, which also accepts a null `ids`.
+        assert_eq!(unsafe { hash_ids(std::ptr::null(), 0) }, 0x2D06800538D394C2);
+    }
+}
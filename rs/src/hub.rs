@@ -0,0 +1,320 @@
+//! HuggingFace Hub (`http` feature): `from_pretrained` downloads the `tokenizer.json` of a model repository, and
+//! caches it in the same layout as the HuggingFace libraries (`models--org--name/snapshots/<commit>/...` in
+//! `~/.cache/huggingface/hub`), so the files downloaded by either are shared.
+//!
+//! The files are downloaded by hf-hub, the client of `Tokenizer::from_pretrained` in the tokenizers crate.
+
+use std::error::Error;
+use std::ffi::{c_char, CStr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use hf_hub::api::sync::ApiBuilder;
+use hf_hub::{Cache, Repo, RepoType};
+use tokenizers::Tokenizer;
+use crate::encode::err;
+use crate::load::{new_handle, new_verified_handle, shared_from_bytes, verified_from_bytes, LOAD_ERROR_IO};
+use crate::panics::catch_panic;
+use crate::PointerOrError;
+
+const DEFAULT_ENDPOINT: &str = "https://huggingface.co";
+const DEFAULT_REVISION: &str = "main";
+const TOKENIZER_FILE: &str = "tokenizer.json";
+
+/// HubConfig is where and how files are fetched from the Hub: by default, from the environment variables of the
+/// HuggingFace libraries (see `HubConfig::from_env`).
+pub(crate) struct HubConfig {
+    pub(crate) endpoint: String,
+    pub(crate) cache_dir: PathBuf,
+    pub(crate) token: Option<String>,
+    pub(crate) offline: bool,
+}
+
+impl HubConfig {
+    /// Returns the configuration from the environment: `HF_ENDPOINT`, `HF_HUB_CACHE` (or `HF_HOME/hub`, or
+    /// `XDG_CACHE_HOME/huggingface/hub`, or `~/.cache/huggingface/hub`), `HF_TOKEN` and `HF_HUB_OFFLINE`.
+    pub(crate) fn from_env() -> HubConfig {
+        let var = |key: &str| std::env::var(key).ok().filter(|value| !value.is_empty());
+        let cache_dir = match (var("HF_HUB_CACHE"), var("HF_HOME"), var("XDG_CACHE_HOME")) {
+            (Some(hub_cache), _, _) => PathBuf::from(hub_cache),
+            (None, Some(home), _) => Path::new(&home).join("hub"),
+            (None, None, Some(cache)) => Path::new(&cache).join("huggingface").join("hub"),
+            (None, None, None) => Path::new(&var("HOME").unwrap_or_default()).join(".cache/huggingface/hub"),
+        };
+        HubConfig {
+            endpoint: var("HF_ENDPOINT").unwrap_or_else(|| DEFAULT_ENDPOINT.to_string()),
+            cache_dir,
+            token: var("HF_TOKEN"),
+            offline: var("HF_HUB_OFFLINE").is_some_and(|value| value != "0" && value.to_lowercase() != "false"),
+        }
+    }
+}
+
+// is_commit returns whether the `revision` is a commit hash, rather than a branch or tag.
+fn is_commit(revision: &str) -> bool {
+    revision.len() == 40 && revision.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+// valid_name returns whether `name` (a model id or revision) only has the characters accepted by
+// `Tokenizer::from_pretrained` -- alphanumeric, '-', '_', '.' and '/' --, and no empty, "." or ".." parts.
+fn valid_name(name: &str) -> bool {
+    let valid_char = |c: char| c.is_alphanumeric() || matches!(c, '-' | '_' | '.' | '/');
+    name.chars().all(valid_char) && name.split('/').all(|part| !part.is_empty() && part != "." && part != "..")
+}
+
+/// Returns the contents of the `file` of the model repository `model_id` (e.g. "bert-base-uncased" or
+/// "google/gemma-2b") at `revision` (a branch, tag or commit): from the cache if it was downloaded before,
+/// otherwise downloaded from the Hub, and cached.
+pub(crate) fn fetch(config: &HubConfig, model_id: &str, revision: &str, file: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    if model_id.split('/').count() > 2 || !valid_name(model_id) {
+        return Err(err(format!("invalid model id {:?}", model_id)));
+    }
+    if !valid_name(revision) {
+        return Err(err(format!("invalid revision {:?}", revision)));
+    }
+    let repo = Repo::with_revision(model_id.to_string(), RepoType::Model, revision.to_string());
+    let cache = Cache::new(config.cache_dir.clone());
+    // The cache only maps branches and tags to their snapshot: a commit is its own snapshot.
+    let snapshot = cache.path().join(repo.folder_name()).join("snapshots").join(revision).join(file);
+    let cached = match is_commit(revision) && snapshot.is_file() {
+        true => Some(snapshot),
+        false => cache.repo(repo.clone()).get(file),
+    };
+    if let Some(path) = cached {
+        return std::fs::read(&path).map_err(|e| err(format!("failed to read {}: {}", path.display(), e)));
+    }
+    if config.offline {
+        return Err(err(format!(
+            "{} of {} (revision {}) is not in the cache {}, and HF_HUB_OFFLINE is set",
+            file, model_id, revision, config.cache_dir.display())));
+    }
+
+    let mut builder = ApiBuilder::from_cache(cache)
+        .with_endpoint(config.endpoint.trim_end_matches('/').to_string())
+        .with_progress(false);
+    if let Some(token) = &config.token {
+        builder = builder.with_token(Some(token.clone()));
+    }
+    let path = builder
+        .build()
+        .and_then(|api| api.repo(repo).download(file))
+        .map_err(|e| err(format!("failed to download {} of {}: {}", file, model_id, e)))?;
+    std::fs::read(&path).map_err(|e| err(format!("failed to read {}: {}", path.display(), e)))
+}
+
+// c_str_or converts the C string `ptr` to a &str, or returns `default` if it is null.
+unsafe fn c_str_or<'a>(ptr: *const c_char, default: &'a str, name: &str) -> Result<&'a str, Box<dyn Error>> {
+    if ptr.is_null() {
+        return Ok(default);
+    }
+    unsafe { CStr::from_ptr(ptr) }.to_str().map_err(|e| err(format!("{} is not valid UTF-8: {}", name, e)))
+}
+
+// fetch_pretrained returns the contents of the `tokenizer.json` of `model_id`, fetched with `config` (its token
+// replaced by `auth_token` if not null), see `from_pretrained`.
+fn fetch_pretrained(
+    mut config: HubConfig,
+    model_id: *const c_char,
+    revision: *const c_char,
+    auth_token: *const c_char,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    if model_id.is_null() {
+        return Err(err("model_id is null"));
+    }
+    let model_id = unsafe { c_str_or(model_id, "", "model_id") }?;
+    let revision = unsafe { c_str_or(revision, DEFAULT_REVISION, "revision") }?;
+    if !auth_token.is_null() {
+        config.token = Some(unsafe { c_str_or(auth_token, "", "auth_token") }?.to_string());
+    }
+    fetch(&config, model_id, revision, TOKENIZER_FILE)
+}
+
+// verified_pretrained returns the tokenizer of `model_id` fetched with `config`, after checking its digest, see
+// `from_pretrained_verified`.
+fn verified_pretrained(
+    config: HubConfig,
+    model_id: *const c_char,
+    revision: *const c_char,
+    auth_token: *const c_char,
+    expected_sha256: *const u8,
+) -> Result<Arc<Tokenizer>, (u32, Box<dyn Error>)> {
+    let contents = fetch_pretrained(config, model_id, revision, auth_token).map_err(|e| (LOAD_ERROR_IO, e))?;
+    verified_from_bytes(&contents, expected_sha256)
+}
+
+/// from_pretrained loads the tokenizer of the model repository `model_id` (e.g. "bert-base-uncased" or
+/// "google/gemma-2b") of the HuggingFace Hub: its `tokenizer.json` file at `revision` (a branch, tag or commit,
+/// "main" if null), downloaded with the `auth_token` (if not null) for private or gated models.
+///
+/// The files are cached as the HuggingFace libraries do (and shared with them): in `HF_HUB_CACHE`, by default
+/// `~/.cache/huggingface/hub`, so a tokenizer is only downloaded once. The environment variables `HF_ENDPOINT`,
+/// `HF_HOME`, `HF_TOKEN` (used if `auth_token` is null) and `HF_HUB_OFFLINE` (only the cache is used) are
+/// honored as well.
+///
+/// It returns the Tokenizer in the `value` field, or an error, as `from_bytes`. Only available with the `http`
+/// feature (enabled by default).
+///
+/// # Safety
+///
+/// `model_id` must be a null-terminated string, and `revision` and `auth_token` null or null-terminated strings.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("from_pretrained"))]
+pub unsafe extern "C" fn from_pretrained(
+    model_id: *const c_char,
+    revision: *const c_char,
+    auth_token: *const c_char,
+) -> PointerOrError {
+    new_handle(catch_panic(|| {
+        shared_from_bytes(&fetch_pretrained(HubConfig::from_env(), model_id, revision, auth_token)?)
+    }))
+}
+
+/// from_pretrained_verified loads the tokenizer of the model repository `model_id` of the HuggingFace Hub, as
+/// `from_pretrained`, after checking that the SHA-256 digest of its `tokenizer.json` (downloaded or cached) is
+/// the 32 bytes at `expected_sha256` (if not null), as `from_bytes_verified`.
+///
+/// It returns the Tokenizer in the `value` field, or an error. If `error_code` is not null, it is set to
+/// `LOAD_OK`, `LOAD_ERROR_IO` (the file can't be downloaded), `LOAD_ERROR_CHECKSUM_MISMATCH` or
+/// `LOAD_ERROR_INVALID`.
+///
+/// # Safety
+///
+/// As `from_pretrained`; `expected_sha256` must be null or point to 32 bytes, and `error_code` must be null or
+/// point to a writable u32.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("from_pretrained_verified"))]
+pub unsafe extern "C" fn from_pretrained_verified(
+    model_id: *const c_char,
+    revision: *const c_char,
+    auth_token: *const c_char,
+    expected_sha256: *const u8,
+    error_code: *mut u32,
+) -> PointerOrError {
+    new_verified_handle(error_code, || {
+        verified_pretrained(HubConfig::from_env(), model_id, revision, auth_token, expected_sha256)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use crate::load::{LOAD_ERROR_CHECKSUM_MISMATCH, LOAD_OK};
+    use crate::testing::{take_error, temp_dir, WORDPIECE};
+
+    const COMMIT: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    // serve answers the requests of the next connections to `listener` with the `responses`, in order, and
+    // returns the requests received.
+    fn serve(listener: TcpListener, responses: Vec<String>) -> std::thread::JoinHandle<Vec<String>> {
+        std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request = String::new();
+                while !request.ends_with("\r\n\r\n") {
+                    reader.read_line(&mut request).unwrap();
+                }
+                requests.push(request);
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        })
+    }
+
+    fn config(endpoint: String) -> HubConfig {
+        HubConfig { endpoint, cache_dir: temp_dir(), token: Some("secret".to_string()), offline: false }
+    }
+
+    #[test]
+    fn fetch_and_cache() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        // The metadata of the file (its first byte), then its contents.
+        let server = serve(listener, vec![
+            format!(
+                "HTTP/1.1 206 Partial Content\r\nX-Repo-Commit: {}\r\nETag: \"blob\"\r\nContent-Range: bytes 0-0/{}\r\n\
+                 Content-Length: 1\r\nConnection: close\r\n\r\n{}",
+                COMMIT, WORDPIECE.len(), &WORDPIECE[..1]),
+            format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", WORDPIECE.len(), WORDPIECE),
+        ]);
+        let config = config(endpoint);
+        let contents = fetch(&config, "org/model", "main", TOKENIZER_FILE).unwrap();
+        assert_eq!(contents, WORDPIECE.as_bytes());
+        let requests = server.join().unwrap();
+        for request in &requests {
+            assert!(request.starts_with("GET /org/model/resolve/main/tokenizer.json HTTP/1.1\r\n"));
+            assert!(request.to_lowercase().contains("authorization: bearer secret\r\n"));
+        }
+
+        // The server is gone: it is read from the cache.
+        let repo_dir = config.cache_dir.join("models--org--model");
+        assert_eq!(std::fs::read_to_string(repo_dir.join("refs/main")).unwrap(), COMMIT);
+        assert_eq!(fetch(&config, "org/model", "main", TOKENIZER_FILE).unwrap(), WORDPIECE.as_bytes());
+        assert_eq!(fetch(&config, "org/model", COMMIT, TOKENIZER_FILE).unwrap(), WORDPIECE.as_bytes());
+        std::fs::remove_dir_all(&config.cache_dir).unwrap();
+    }
+
+    #[test]
+    fn fetch_errors() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let response = "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string();
+        let server = serve(listener, vec![response]);
+        let mut config = config(endpoint);
+        let e = fetch(&config, "org/missing", "main", TOKENIZER_FILE).unwrap_err().to_string();
+        assert!(e.starts_with("failed to download tokenizer.json of org/missing: "), "{}", e);
+        assert!(e.contains("404"), "{}", e);
+        server.join().unwrap();
+
+        let e = fetch(&config, "../model", "main", TOKENIZER_FILE).unwrap_err();
+        assert_eq!(e.to_string(), "invalid model id \"../model\"");
+        let e = fetch(&config, "org/model", "v1?x=1", TOKENIZER_FILE).unwrap_err();
+        assert_eq!(e.to_string(), "invalid revision \"v1?x=1\"");
+        config.offline = true;
+        let e = fetch(&config, "org/model", "main", TOKENIZER_FILE).unwrap_err();
+        assert!(e.to_string().ends_with("and HF_HUB_OFFLINE is set"));
+        std::fs::remove_dir_all(&config.cache_dir).unwrap();
+
+        let result = unsafe { from_pretrained(std::ptr::null(), std::ptr::null(), std::ptr::null()) };
+        assert_eq!(take_error(result.error).as_deref(), Some("model_id is null"));
+    }
+
+    #[test]
+    fn from_pretrained_verified_digest() {
+        use sha2::{Digest, Sha256};
+
+        // The tokenizer is found in the (offline) cache.
+        let cache_dir = temp_dir();
+        let snapshot = cache_dir.join("models--org--model/snapshots").join(COMMIT);
+        std::fs::create_dir_all(&snapshot).unwrap();
+        std::fs::write(snapshot.join(TOKENIZER_FILE), WORDPIECE).unwrap();
+        let config = || HubConfig { endpoint: String::new(), cache_dir: cache_dir.clone(), token: None, offline: true };
+
+        let model_id = c"org/model".as_ptr();
+        let revision = std::ffi::CString::new(COMMIT).unwrap();
+        let mut digest: [u8; 32] = Sha256::digest(WORDPIECE).into();
+        let mut code = u32::MAX;
+        let result = new_verified_handle(&mut code, || {
+            verified_pretrained(config(), model_id, revision.as_ptr(), std::ptr::null(), digest.as_ptr())
+        });
+        assert_eq!(take_error(result.error), None);
+        assert_eq!(code, LOAD_OK);
+        unsafe { crate::free_tokenizer(result.value.cast()) };
+
+        digest[0] ^= 1;
+        let result = new_verified_handle(&mut code, || {
+            verified_pretrained(config(), model_id, revision.as_ptr(), std::ptr::null(), digest.as_ptr())
+        });
+        assert!(take_error(result.error).unwrap().contains("checksum mismatch"));
+        assert_eq!(code, LOAD_ERROR_CHECKSUM_MISMATCH);
+        let other = c"org/other".as_ptr();
+        let result = new_verified_handle(&mut code, || {
+            verified_pretrained(config(), other, std::ptr::null(), std::ptr::null(), digest.as_ptr())
+        });
+        assert!(take_error(result.error).is_some());
+        assert_eq!(code, LOAD_ERROR_IO);
+        std::fs::remove_dir_all(&cache_dir).unwrap();
+    }
+}
@@ -0,0 +1,238 @@
+//! Background tokenization jobs: `submit_batch` returns immediately, and the batch is encoded on threads
+//! owned by this library, so the host can overlap tokenization with other work without blocking its threads.
+
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+use crate::encode::{
+    encode_batch_str_impl, err, messages_from_bytes, result_to_encode_results_v2, EncodeParams, EncodeResultsV2,
+};
+use crate::handle::TokenizerHandle;
+use crate::panics::{catch_panic, catch_panic_or};
+
+/// JobCallback is an optional host callback called (from a library thread) once the job `job_id` is
+/// completed, so its results can be taken with `poll_result`. `user_data` is the one given to `submit_batch`.
+pub type JobCallback = Option<unsafe extern "C" fn(user_data: *mut c_void, job_id: u64)>;
+
+/// poll_result status: the job is still running.
+pub const JOB_PENDING: u8 = 0;
+/// poll_result status: the job is completed, and its results were returned.
+pub const JOB_COMPLETED: u8 = 1;
+/// poll_result status: there is no such job (or its results were already taken).
+pub const JOB_UNKNOWN: u8 = 2;
+
+// Job is a batch submitted for encoding, with its own copy of the texts.
+struct Job {
+    id: u64,
+    tokenizer: Arc<TokenizerHandle>,
+    texts: Vec<String>,
+    options: EncodeParams,
+    callback: JobCallback,
+    user_data: *mut c_void,
+}
+
+// The host is responsible for the callback (and its user data) being usable from any thread.
+unsafe impl Send for Job {}
+
+// JobResults are the results of a completed job, owned by the registry until taken by `poll_result`.
+struct JobResults(EncodeResultsV2);
+
+unsafe impl Send for JobResults {}
+
+// Jobs is the registry of the submitted jobs: None while they are pending.
+struct Jobs {
+    next_id: AtomicU64,
+    results: Mutex<HashMap<u64, Option<JobResults>>>,
+    queue: Mutex<Sender<Job>>,
+}
+
+// jobs returns the registry of jobs, starting the worker threads on first use.
+fn jobs() -> &'static Jobs {
+    static JOBS: OnceLock<Jobs> = OnceLock::new();
+    JOBS.get_or_init(|| {
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let num_workers = std::thread::available_parallelism().map_or(1, |n| n.get());
+        for i in 0..num_workers {
+            let receiver = receiver.clone();
+            // If no worker can be started, jobs stay pending: the error is reported by `submit_batch`.
+            let _ = std::thread::Builder::new()
+                .name(format!("tokenizers-jobs-{}", i))
+                .spawn(move || run_jobs(&receiver));
+        }
+        Jobs {
+            next_id: AtomicU64::new(1),
+            results: Mutex::new(HashMap::new()),
+            queue: Mutex::new(sender),
+        }
+    })
+}
+
+// run_jobs is the loop of each worker thread: it encodes the queued jobs, one at a time.
+fn run_jobs(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = match receiver.lock().unwrap_or_else(|e| e.into_inner()).recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        let inputs: Vec<&str> = job.texts.iter().map(String::as_str).collect();
+        let tokenizer_ptr = Arc::as_ptr(&job.tokenizer).cast_mut();
+        // A panic is stored as the error of the job: the worker keeps running, and the job is still completed.
+        let results = catch_panic(|| encode_batch_str_impl(tokenizer_ptr, &inputs, job.options));
+        let results = result_to_encode_results_v2(results);
+        jobs().results.lock().unwrap_or_else(|e| e.into_inner()).insert(job.id, Some(JobResults(results)));
+        if let Some(callback) = job.callback {
+            unsafe { callback(job.user_data, job.id) };
+        }
+    }
+}
+
+/// submit_batch submits a batch of UTF-8 strings (given as in `encode_batch_bytes`) to be encoded in the
+/// background, with the given EncodeParams, and returns immediately the id of the job -- always positive.
+///
+/// The texts are copied, so they can be freed once it returns. The tokenizer is retained until the job is
+/// completed. Once completed, the optional `callback` is called, and the results can be taken with
+/// `poll_result`. Errors (including invalid inputs) are reported in the results.
+///
+/// # Safety
+///
+/// `messages` and `lengths` must point to `num_messages` strings and lengths.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("submit_batch"))]
+pub unsafe extern "C" fn submit_batch(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_messages: u32,
+    messages: *const *const u8,
+    lengths: *const u32,
+    options: EncodeParams,
+    callback: JobCallback,
+    user_data: *mut c_void,
+) -> u64 {
+    let jobs = jobs();
+    let id = jobs.next_id.fetch_add(1, Ordering::Relaxed);
+    let job = catch_panic(|| {
+        if tokenizer_ptr.is_null() {
+            return Err(err("tokenizer passed is null"));
+        }
+        let inputs = messages_from_bytes(num_messages as usize, messages, lengths)?;
+        Ok(Job {
+            id,
+            tokenizer: unsafe {
+                Arc::increment_strong_count(tokenizer_ptr.cast_const());
+                Arc::from_raw(tokenizer_ptr.cast_const())
+            },
+            texts: inputs.into_iter().map(str::to_string).collect(),
+            options,
+            callback,
+            user_data,
+        })
+    });
+    let mut results = jobs.results.lock().unwrap_or_else(|e| e.into_inner());
+    let queued = job.and_then(|job| {
+        jobs.queue.lock().unwrap_or_else(|e| e.into_inner())
+            .send(job)
+            .map_err(|_| err("no tokenization worker is running"))
+    });
+    match queued {
+        Ok(()) => {
+            results.insert(id, None);
+        }
+        Err(e) => {
+            // Completed right away, with the error.
+            results.insert(id, Some(JobResults(result_to_encode_results_v2(Err(e)))));
+            drop(results);
+            if let Some(callback) = callback {
+                unsafe { callback(user_data, id) };
+            }
+        }
+    }
+    id
+}
+
+/// poll_result returns the status of the job `job_id` (JOB_PENDING, JOB_COMPLETED or JOB_UNKNOWN). If it is
+/// completed, and `results` is not null, its results are written to `results` and ownership is transferred to
+/// the caller, who must free them with `free_encode_results_v2`: the job is then forgotten. With a null
+/// `results`, only the status is returned.
+///
+/// # Safety
+///
+/// `results` must be null or point to a writable EncodeResultsV2.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("poll_result"))]
+pub unsafe extern "C" fn poll_result(job_id: u64, results: *mut EncodeResultsV2) -> u8 {
+    catch_panic_or(JOB_UNKNOWN, || {
+        let mut jobs = jobs().results.lock().unwrap_or_else(|e| e.into_inner());
+        match jobs.get(&job_id) {
+            None => return JOB_UNKNOWN,
+            Some(None) => return JOB_PENDING,
+            Some(Some(_)) if results.is_null() => return JOB_COMPLETED,
+            Some(Some(_)) => {}
+        }
+        if let Some(Some(JobResults(job_results))) = jobs.remove(&job_id) {
+            unsafe { results.write(job_results) };
+        }
+        JOB_COMPLETED
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr::null_mut;
+    use crate::encode::{free_encode_results_v2, ENCODE_PARAMS_VERSION};
+    use crate::testing::{take_error, TestHandle, WORDPIECE};
+
+    // count_calls is a JobCallback counting its calls in the AtomicU64 given as `user_data`.
+    unsafe extern "C" fn count_calls(user_data: *mut c_void, _job_id: u64) {
+        unsafe { &*(user_data as *const AtomicU64) }.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // wait_result waits for the job to be completed, and returns its results.
+    fn wait_result(job_id: u64) -> EncodeResultsV2 {
+        let mut results = EncodeResultsV2 { len: 0, total_tokens: 0, encoded: null_mut(), error: null_mut() };
+        while unsafe { poll_result(job_id, &mut results) } == JOB_PENDING {
+            std::thread::yield_now();
+        }
+        results
+    }
+
+    #[test]
+    fn submit_and_poll() {
+        let handle = TestHandle::new(WORDPIECE);
+        let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags: 0 };
+        let calls = AtomicU64::new(0);
+        let user_data = &calls as *const AtomicU64 as *mut c_void;
+        let texts = ["hello world".to_string(), "ab".to_string()];
+        let messages: Vec<*const u8> = texts.iter().map(|t| t.as_ptr()).collect();
+        let lengths: Vec<u32> = texts.iter().map(|t| t.len() as u32).collect();
+        let job_id = unsafe {
+            submit_batch(handle.0, 2, messages.as_ptr(), lengths.as_ptr(), options, Some(count_calls), user_data)
+        };
+        assert!(job_id > 0);
+        // The texts are copied.
+        drop(texts);
+
+        let results = wait_result(job_id);
+        assert!(results.error.is_null());
+        assert_eq!((results.len, results.total_tokens), (2, 4));
+        let ids = unsafe { std::slice::from_raw_parts((*results.encoded.add(1)).ids, 2) };
+        assert_eq!(ids, [1, 3]);
+        unsafe { free_encode_results_v2(results) };
+        // The job is forgotten once its results are taken.
+        assert_eq!(unsafe { poll_result(job_id, null_mut()) }, JOB_UNKNOWN);
+        while calls.load(Ordering::SeqCst) == 0 {
+            std::thread::yield_now();
+        }
+
+        // Errors are reported in the results, and the callback is called right away.
+        let job_id = unsafe {
+            submit_batch(null_mut(), 0, std::ptr::null(), std::ptr::null(), options, Some(count_calls), user_data)
+        };
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(unsafe { poll_result(job_id, null_mut()) }, JOB_COMPLETED);
+        let results = wait_result(job_id);
+        assert_eq!(take_error(results.error).as_deref(), Some("tokenizer passed is null"));
+    }
+}
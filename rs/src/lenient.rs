@@ -0,0 +1,226 @@
+//! Lenient loading of `tokenizer.json` files written by other versions of the tokenizers library, see
+//! `from_bytes_lenient`.
+
+use std::error::Error;
+use std::ffi::{c_char, CString};
+use std::ptr::null_mut;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tokenizers::tokenizer::{PaddingParams, Tokenizer, TruncationParams};
+use tokenizers::{AddedToken, DecoderWrapper, ModelWrapper, NormalizerWrapper, PostProcessorWrapper, PreTokenizerWrapper};
+use crate::encode::err;
+use crate::load::new_handle;
+use crate::panics::catch_panic;
+use crate::PointerOrError;
+
+// SERIALIZATION_VERSION is the only `version` of the `tokenizer.json` format the tokenizers library accepts.
+const SERIALIZATION_VERSION: &str = "1.0";
+
+// Parse checks whether a component of the tokenizer can be deserialized, returning the error otherwise.
+type Parse = fn(&Value) -> Result<(), String>;
+
+fn parse<T: DeserializeOwned>(value: &Value) -> Result<(), String> {
+    serde_json::from_value::<T>(value.clone()).map(|_| ()).map_err(|e| e.to_string())
+}
+
+// COMPONENTS are the fields of the `tokenizer.json` holding the components of the tokenizer, and how they are
+// parsed. The `added_tokens` are parsed one at a time.
+const COMPONENTS: [(&str, Parse); 7] = [
+    ("truncation", parse::<TruncationParams>),
+    ("padding", parse::<PaddingParams>),
+    ("normalizer", parse::<NormalizerWrapper>),
+    ("pre_tokenizer", parse::<PreTokenizerWrapper>),
+    ("model", parse::<ModelWrapper>),
+    ("post_processor", parse::<PostProcessorWrapper>),
+    ("decoder", parse::<DecoderWrapper>),
+];
+
+// SEQUENCE_FIELDS are the fields holding the components of a "Sequence" component (e.g. of normalizers).
+const SEQUENCE_FIELDS: [&str; 4] = ["normalizers", "pretokenizers", "processors", "decoders"];
+
+// Failure is a component (or field of a component) that can't be deserialized, at `path`.
+struct Failure {
+    path: String,
+    error: String,
+}
+
+// migrate_component applies the migrations to the component at `path`, and returns the Failure of the
+// component or field that still can't be deserialized, if any:
+//
+// - The components of a "Sequence" are migrated individually.
+// - Null fields that can't be deserialized are dropped: they are unset optional fields, written as null by
+//   other versions, and the defaults are used instead.
+fn migrate_component(value: &mut Value, path: &str, parse: Parse, migrations: &mut Vec<String>) -> Option<Failure> {
+    let error = parse(value).err()?;
+    let Some(fields) = value.as_object_mut() else {
+        return Some(Failure { path: path.to_string(), error });
+    };
+    if fields.get("type").and_then(Value::as_str) == Some("Sequence") {
+        for field in SEQUENCE_FIELDS {
+            if let Some(Value::Array(items)) = fields.get_mut(field) {
+                for (index, item) in items.iter_mut().enumerate() {
+                    let item_path = format!("{}.{}[{}]", path, field, index);
+                    if let Some(failure) = migrate_component(item, &item_path, parse, migrations) {
+                        return Some(failure);
+                    }
+                }
+            }
+        }
+    }
+    for key in fields.keys().filter(|&key| key != "type").cloned().collect::<Vec<_>>() {
+        // The fields are deserialized in order until one fails, so the failing field is the one whose removal
+        // changes the error (if required, it is then reported as missing).
+        let field = fields.remove(&key).unwrap_or(Value::Null);
+        match (parse(&Value::Object(fields.clone())), field.is_null()) {
+            (Ok(()), true) => {
+                migrations.push(format!("dropped null field {}.{}", path, key));
+                return None;
+            }
+            (Err(other), _) if other == error => {
+                fields.insert(key, field);
+            }
+            _ => {
+                fields.insert(key.clone(), field);
+                return Some(Failure { path: format!("{}.{}", path, key), error });
+            }
+        }
+    }
+    let error = match parse(value) {
+        Ok(()) => return None,
+        Err(error) => error,
+    };
+    match value.get("type").and_then(Value::as_str) {
+        Some(component_type) => Some(Failure { path: format!("{} (type {:?})", path, component_type), error }),
+        None => Some(Failure { path: path.to_string(), error }),
+    }
+}
+
+// migrate applies the migrations to the definition of the tokenizer, and returns the descriptions of the
+// migrations applied, or the error naming the field that can't be migrated.
+fn migrate(json: &mut Value) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut migrations = Vec::new();
+    let fields = json.as_object_mut().ok_or_else(|| err("tokenizer definition is not a JSON object"))?;
+    match fields.get("version") {
+        Some(Value::String(version)) if version == SERIALIZATION_VERSION => {}
+        Some(version) => {
+            migrations.push(format!("read version {} as \"{}\"", version, SERIALIZATION_VERSION));
+            fields.insert("version".to_string(), Value::from(SERIALIZATION_VERSION));
+        }
+        None => {}
+    }
+    let known = |key: &str| key == "version" || key == "added_tokens" || COMPONENTS.iter().any(|(name, _)| *name == key);
+    for key in fields.keys().filter(|key| !known(key)) {
+        migrations.push(format!("ignored unknown field {}", key));
+    }
+
+    let mut failure = None;
+    if let Some(Value::Array(tokens)) = fields.get_mut("added_tokens") {
+        for (index, token) in tokens.iter_mut().enumerate() {
+            let path = format!("added_tokens[{}]", index);
+            failure = failure.or_else(|| migrate_component(token, &path, parse::<AddedToken>, &mut migrations));
+        }
+    }
+    for (name, parse) in COMPONENTS {
+        match fields.get_mut(name) {
+            Some(Value::Null) | None => {}
+            Some(component) => failure = failure.or_else(|| migrate_component(component, name, parse, &mut migrations)),
+        }
+    }
+    match failure {
+        Some(failure) => Err(err(format!("failed to load tokenizer: field {}: {}", failure.path, failure.error))),
+        None => Ok(migrations),
+    }
+}
+
+fn from_bytes_lenient_impl(bytes: &[u8]) -> Result<(Tokenizer, Vec<String>), Box<dyn Error>> {
+    let mut json: Value = serde_json::from_slice(bytes).map_err(|e| err(format!("failed to load tokenizer: {}", e)))?;
+    let migrations = migrate(&mut json)?;
+    let tokenizer = serde_json::from_value(json).map_err(|e| err(format!("failed to load tokenizer: {}", e)))?;
+    Ok((tokenizer, migrations))
+}
+
+/// from_bytes_lenient loads a tokenizer, like `from_bytes`, but tolerating `tokenizer.json` files written by
+/// other (e.g. newer) versions of the tokenizers library, by applying the known migrations:
+///
+/// - A `version` other than "1.0" is read as "1.0".
+/// - Unknown fields are ignored (as `from_bytes` does).
+/// - Null fields of the components that this version can't read are dropped, so their defaults are used.
+///
+/// Changes that can't be migrated safely (e.g. a component of an unknown type, or a field with a value this
+/// version doesn't support) still fail, but the error names the field, e.g.
+/// `pre_tokenizer.pretokenizers[1].behavior`, instead of only the position in the JSON.
+///
+/// It returns the Tokenizer in the `value` field, or an error. If `migrations` is not null, it is set to the
+/// descriptions of the migrations applied, one per line (a C string that must be freed with `free_string`),
+/// or to null if none was needed.
+///
+/// # Safety
+///
+/// `bytes` must point to `len` bytes, and `migrations` must be null or point to a writable pointer.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("from_bytes_lenient"))]
+pub unsafe extern "C" fn from_bytes_lenient(bytes: *const u8, len: u32, migrations: *mut *mut c_char) -> PointerOrError {
+    let bytes = if len == 0 { &[][..] } else { unsafe { std::slice::from_raw_parts(bytes, len as usize) } };
+    let (tokenizer, applied) = match catch_panic(|| from_bytes_lenient_impl(bytes)) {
+        Ok((tokenizer, applied)) => (Ok(tokenizer), applied),
+        Err(e) => (Err(e), Vec::new()),
+    };
+    if !migrations.is_null() {
+        let applied = if applied.is_empty() {
+            null_mut()
+        } else {
+            CString::new(applied.join("\n")).unwrap().into_raw()
+        };
+        unsafe { migrations.write(applied) };
+    }
+    new_handle(tokenizer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::testing::{encode_ids, take_error, TestHandle, WORDPIECE};
+
+    // load returns the handle of the tokenizer loaded leniently from `json` and the migrations, or the error.
+    fn load(json: &Value) -> Result<(TestHandle, Option<String>), String> {
+        let bytes = json.to_string();
+        let mut migrations = null_mut();
+        let result = unsafe { from_bytes_lenient(bytes.as_ptr(), bytes.len() as u32, &mut migrations) };
+        let migrations = take_error(migrations);
+        match take_error(result.error) {
+            Some(e) => Err(e),
+            None => Ok((TestHandle(result.value.cast()), migrations)),
+        }
+    }
+
+    #[test]
+    fn migrations() {
+        let mut json: Value = serde_json::from_str(WORDPIECE).unwrap();
+        let (handle, migrations) = load(&json).unwrap();
+        assert_eq!(migrations, None);
+        assert_eq!(encode_ids(&handle, "hello world", 0), [4, 6]);
+
+        json["version"] = json!("2.0");
+        json["future"] = json!(true);
+        json["pre_tokenizer"] = json!({"type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true,
+            "use_regex": null});
+        let (handle, migrations) = load(&json).unwrap();
+        let expected = concat!(
+            "read version \"2.0\" as \"1.0\"\n",
+            "ignored unknown field future\n",
+            "dropped null field pre_tokenizer.use_regex",
+        );
+        assert_eq!(migrations.as_deref(), Some(expected));
+        assert_eq!(encode_ids(&handle, "ab", 0), [1, 3]);
+
+        json["pre_tokenizer"] = json!({"type": "Sequence", "pretokenizers": [
+            {"type": "Whitespace"},
+            {"type": "Split", "pattern": {"String": " "}, "behavior": "Unknown", "invert": false},
+        ]});
+        let error = load(&json).err().unwrap();
+        let prefix = "failed to load tokenizer: field pre_tokenizer.pretokenizers[1].behavior: ";
+        assert!(error.starts_with(prefix), "{}", error);
+        assert_eq!(load(&json!([])).err().as_deref(), Some("tokenizer definition is not a JSON object"));
+    }
+}
@@ -1,9 +1,74 @@
+// Exported functions have a `#[cfg_attr(symbol_prefix, export_name = symbol!("name"))]` along their
+// `#[no_mangle]` (which cbindgen requires to find them), so that when built with a symbol prefix (see
+// `build.rs`) the export name wins, and rustc reports the `#[no_mangle]` as unused.
+#![cfg_attr(symbol_prefix, allow(unused_attributes))]
+
+// symbol! returns the exported name of the function `name`: prefixed with GOMLX_TOKENIZERS_SYMBOL_PREFIX.
+#[cfg(symbol_prefix)]
+macro_rules! symbol {
+    ($name:literal) => {
+        concat!(env!("GOMLX_TOKENIZERS_SYMBOL_PREFIX"), $name)
+    };
+}
+
+mod arena;
+#[cfg(feature = "arrow")]
+mod arrow;
+#[cfg(feature = "chat-template")]
+mod chat;
+mod compat;
+mod compiled;
+mod config;
 mod configure;
+pub mod conformance;
+mod corpus;
+mod debug;
 mod encode;
+mod encodings;
+mod export;
+mod fertility;
+mod flat;
 mod decode;
+mod describe;
+mod document;
+mod generation;
+mod handle;
+mod hash;
+#[cfg(feature = "http")]
+mod hub;
+mod jobs;
+mod lenient;
+mod limits;
+mod load;
+mod padded;
+mod panics;
+mod pipeline;
+mod positions;
+mod profile;
+mod prompt;
+mod raw;
+mod record;
+mod registry;
+mod serialized;
+mod stats;
+mod stream;
+#[cfg(test)]
+mod testing;
+mod threads;
+mod trace;
+#[cfg(feature = "training")]
+mod train;
+mod vocab;
+mod warmup;
+#[cfg(feature = "wasm")]
+mod wasm;
+mod words;
 
 use std::ptr::null_mut;
-use tokenizers::tokenizer::Tokenizer;
+use std::sync::Arc;
+use std::ffi::{c_char, c_void};
+use crate::handle::TokenizerHandle;
+use crate::panics::{catch_panic, catch_panic_or, error_c_string};
 
 /// PointerOrError returns either a `void *` pointer or an error. 
 /// It can be used by functions interfacing with Rust from other languages (using the C binding).
@@ -15,8 +80,39 @@ use tokenizers::tokenizer::Tokenizer;
 /// Ownership of `error` is transferred back to the caller.
 #[repr(C)]
 pub struct PointerOrError {
-    value: *mut libc::c_void,
-    error: *mut libc::c_char,
+    pub(crate) value: *mut c_void,
+    pub(crate) error: *mut c_char,
+}
+
+/// BytesOrError returns either a byte buffer or an error.
+///
+/// Either `data` (with `len` bytes) or `error` will be defined. Ownership of both is transferred
+/// to the caller, who must free it with `free_bytes`.
+#[repr(C)]
+pub struct BytesOrError {
+    data: *mut u8,
+    len: u64,
+    error: *mut c_char,
+}
+
+impl BytesOrError {
+    // from_result converts a Result with the bytes or the error to a BytesOrError.
+    fn from_result(r: Result<Vec<u8>, Box<dyn std::error::Error>>) -> BytesOrError {
+        match r {
+            Ok(bytes) => {
+                let mut bytes = bytes.into_boxed_slice();
+                let len = bytes.len() as u64;
+                let data = bytes.as_mut_ptr();
+                std::mem::forget(bytes);
+                BytesOrError { data, len, error: null_mut() }
+            }
+            Err(err) => BytesOrError {
+                data: null_mut(),
+                len: 0,
+                error: error_c_string(err),
+            },
+        }
+    }
 }
 
 /// This function returns a Tokenizer reference to Golang (casted as a C `void*` in the `value` field) or
@@ -25,40 +121,122 @@ pub struct PointerOrError {
 /// The parameter `bytes` should be the json contents for a `tokenizer.json` file, with its definitions (symbols, 
 /// truncation parameters, etc.)
 ///
+/// Tokenizers loaded from identical contents share the parsed Tokenizer, so loading the same tokenizer many
+/// times (e.g. once per worker) only holds it in memory once. Each handle can still be configured independently:
+/// it gets its own copy of the Tokenizer the first time it is modified.
+///
 /// # Safety
 ///
 /// The caller has ownership of `bytes` and of the returned `Tokenizer`.
 #[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("from_bytes"))]
 pub unsafe extern "C" fn from_bytes(bytes: *const u8, len: u32) -> PointerOrError {
     let bytes_slice = unsafe { std::slice::from_raw_parts(bytes, len as usize) };
-    match Tokenizer::from_bytes(bytes_slice) {
-        Ok(t) => return PointerOrError{
-            value: Box::into_raw(Box::new(t)).cast(),
+    match catch_panic(|| crate::load::shared_from_bytes(bytes_slice)) {
+        Ok(t) => PointerOrError{
+            value: Arc::new(TokenizerHandle::new(t)).into_raw(),
             error: null_mut(),
         },
-        Err(err) => return PointerOrError{
+        Err(err) => PointerOrError{
             value: null_mut(),
-            error: std::ffi::CString::new(err.to_string()).unwrap().into_raw(),
+            error: error_c_string(err),
         }
     }
 }
 
 /// Frees a Tokenizer allocated by Rust and returned to Golang.
+///
+/// It is the same as `tokenizer_release`: the Tokenizer is only freed once all references
+/// acquired with `tokenizer_retain` are also released.
+///
+/// # Safety
+///
+/// `ptr` must have been returned by `from_bytes`, and it must not be used after this call.
 #[no_mangle]
-pub unsafe extern "C" fn free_tokenizer(ptr: *mut libc::c_void) {
-    if ptr.is_null() {
-        return;
-    }
-    ptr.cast::<Tokenizer>();
+#[cfg_attr(symbol_prefix, export_name = symbol!("free_tokenizer"))]
+pub unsafe extern "C" fn free_tokenizer(ptr: *mut TokenizerHandle) {
+    catch_panic_or((), || crate::handle::tokenizer_release(ptr));
 }
 
 /// Frees a `*C.char` allocated by Rust and return to Golang.
+///
+/// # Safety
+///
+/// `ptr` must have been allocated by Rust, and it must not be used after this call.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("free_string"))]
+pub unsafe extern "C" fn free_string(ptr: *mut c_char) {
+    catch_panic_or((), || {
+        if ptr.is_null() {
+            return;
+        }
+        unsafe {
+            drop(std::ffi::CString::from_raw(ptr));
+        }
+    })
+}
+
+/// Frees a `BytesOrError` returned by Rust to Golang.
+///
+/// # Safety
+///
+/// `bytes` must have been returned by Rust, and it must not be used after this call.
 #[no_mangle]
-pub unsafe extern "C" fn free_string(ptr: *mut libc::c_char) {
-    if ptr.is_null() {
-        return;
+#[cfg_attr(symbol_prefix, export_name = symbol!("free_bytes"))]
+pub unsafe extern "C" fn free_bytes(bytes: BytesOrError) {
+    catch_panic_or((), || {
+        free_string(bytes.error);
+        if !bytes.data.is_null() {
+            unsafe {
+                let slice = std::ptr::slice_from_raw_parts_mut(bytes.data, bytes.len as usize);
+                drop(Box::from_raw(slice));
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    // The C header generated by `build.rs`, with the handles as opaque types.
+    const HEADER: &str = include_str!("../../internal/rs/gomlx_tokenizers.h");
+
+    #[test]
+    fn generated_header() {
+        for declaration in [
+            "typedef struct TokenizerHandle TokenizerHandle;",
+            "struct PointerOrError from_bytes(const uint8_t *bytes,",
+            "void free_tokenizer(struct TokenizerHandle *ptr);",
+            "typedef struct EncodeParams {",
+        ] {
+            assert!(HEADER.contains(declaration), "{:?} not in the header", declaration);
+        }
+        // The Rust layout of the handles is not exposed.
+        assert!(!HEADER.contains("typedef struct TokenizerHandle {"));
     }
-    unsafe {
-        drop(std::ffi::CString::from_raw(ptr));
+
+    #[test]
+    fn regex_backend() {
+        use tokenizers::pre_tokenizers::split::{Split, SplitPattern};
+        use tokenizers::{OffsetReferential, OffsetType, PreTokenizedString, PreTokenizer, SplitDelimiterBehavior};
+
+        // The look-ahead of the GPT-2 pattern is supported by both `onig` and `fancy-regex`.
+        let pattern = SplitPattern::Regex(r"\s+(?!\S)|\s+".to_string());
+        let split = Split::new(pattern, SplitDelimiterBehavior::Isolated, false).unwrap();
+        let mut pretokenized = PreTokenizedString::from("hello   world ");
+        split.pre_tokenize(&mut pretokenized).unwrap();
+        let splits = pretokenized.get_splits(OffsetReferential::Original, OffsetType::Byte);
+        let splits: Vec<&str> = splits.iter().map(|(split, _, _)| *split).collect();
+        assert_eq!(splits, ["hello", "  ", " ", "world", " "]);
+    }
+
+    #[test]
+    fn symbol_prefix() {
+        let prefix = env!("GOMLX_TOKENIZERS_SYMBOL_PREFIX");
+        assert_eq!(cfg!(symbol_prefix), !prefix.is_empty());
+        #[cfg(symbol_prefix)]
+        assert_eq!(symbol!("free_string"), format!("{}free_string", prefix));
+        // The header maps the unprefixed names to the exported symbols.
+        let define = format!("#define free_string {}free_string", prefix);
+        assert_eq!(HEADER.contains(&define), !prefix.is_empty());
     }
 }
@@ -0,0 +1,231 @@
+//! Limits on the inputs of the encode calls of a tokenizer (see `set_encode_limits`), so that oversized inputs
+//! (e.g. adversarial requests to public endpoints) fail fast, instead of using unbounded CPU and memory.
+
+use std::error::Error;
+use std::ffi::{c_char, CStr};
+use std::fmt;
+use std::ptr::null_mut;
+use crate::encode::err;
+use crate::handle::{convert_to_handle_ref, TokenizerHandle};
+use crate::panics::{catch_panic, catch_panic_or, error_c_string};
+
+/// Error code returned by `encode_error_code` when there is no error.
+pub const ENCODE_OK: u32 = 0;
+/// Error code returned by `encode_error_code` for the errors without a specific code.
+pub const ENCODE_ERROR_OTHER: u32 = 1;
+/// Error code returned by `encode_error_code`: an input has more bytes than `EncodeLimits.max_input_bytes`.
+pub const ENCODE_ERROR_INPUT_TOO_LARGE: u32 = 2;
+/// Error code returned by `encode_error_code`: the batch has more inputs than `EncodeLimits.max_batch_size`.
+pub const ENCODE_ERROR_BATCH_TOO_LARGE: u32 = 3;
+/// Error code returned by `encode_error_code`: an input produces more tokens than `EncodeLimits.max_tokens`.
+/// The number of tokens produced until the encoding was aborted is returned by `encode_error_token_count`.
+pub const ENCODE_ERROR_TOKEN_BUDGET_EXCEEDED: u32 = 4;
+
+// The prefixes of the error messages with a specific code, see `encode_error_code`.
+const INPUT_TOO_LARGE: &str = "input too large: ";
+const BATCH_TOO_LARGE: &str = "batch too large: ";
+const TOKEN_BUDGET_EXCEEDED: &str = "token budget exceeded: ";
+
+/// EncodeLimits are the limits on the inputs of the encode calls of a tokenizer, see `set_encode_limits`.
+#[repr(C)]
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct EncodeLimits {
+    max_input_bytes: u64,  // Maximum number of bytes of an input (of both texts, for pairs); 0 for no limit.
+    max_batch_size: u64,  // Maximum number of inputs of a batch; 0 for no limit.
+    max_tokens: u64,  // Maximum number of tokens of an input (of both texts, for pairs), see below; 0 for no limit.
+}
+
+impl EncodeLimits {
+    /// Checks the `sizes` of the inputs of an encode call, in bytes, against the limits: it fails with the
+    /// error of the first limit exceeded.
+    pub fn check(&self, sizes: impl ExactSizeIterator<Item = usize>) -> Result<(), Box<dyn Error>> {
+        if self.max_batch_size > 0 && sizes.len() as u64 > self.max_batch_size {
+            return Err(err(format!(
+                "{}{} inputs, more than the limit of {}", BATCH_TOO_LARGE, sizes.len(), self.max_batch_size)));
+        }
+        if self.max_input_bytes == 0 {
+            return Ok(());
+        }
+        for (index, size) in sizes.enumerate() {
+            if size as u64 > self.max_input_bytes {
+                return Err(err(format!(
+                    "{}input #{} has {} bytes, more than the limit of {}",
+                    INPUT_TOO_LARGE, index, size, self.max_input_bytes)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the maximum number of tokens of an input, `usize::MAX` if there is no limit.
+    pub fn max_tokens(&self) -> usize {
+        match self.max_tokens {
+            0 => usize::MAX,
+            max_tokens => usize::try_from(max_tokens).unwrap_or(usize::MAX),
+        }
+    }
+}
+
+/// TokenBudgetExceeded is the error of the encoding of an input aborted once it produced more than `max_tokens`
+/// tokens, see `EncodeLimits.max_tokens`.
+#[derive(Debug)]
+pub struct TokenBudgetExceeded {
+    pub max_tokens: usize,
+    pub num_tokens: usize,  // Number of tokens produced until the encoding was aborted.
+}
+
+impl fmt::Display for TokenBudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{} tokens produced, more than the limit of {}", TOKEN_BUDGET_EXCEEDED, self.num_tokens, self.max_tokens)
+    }
+}
+
+impl Error for TokenBudgetExceeded {}
+
+/// set_encode_limits sets the limits on the inputs of the encode calls of the tokenizer: the calls exceeding
+/// them fail before encoding anything, with an error that `encode_error_code` identifies as
+/// `ENCODE_ERROR_INPUT_TOO_LARGE` or `ENCODE_ERROR_BATCH_TOO_LARGE`. A limit of 0 disables it, so the default
+/// `EncodeLimits` (all zeros) removes all limits.
+///
+/// The `max_tokens` limit (e.g. a quota) is checked while encoding the texts with `encode*` and `encode_batch*`:
+/// the encoding of an input is aborted as soon as its texts produce more tokens -- not counting the special
+/// tokens added, and before any truncation -- and the call fails with `ENCODE_ERROR_TOKEN_BUDGET_EXCEEDED`.
+///
+/// It returns null if ok, or a string with an error message (owned by caller), to be freed with `free_string`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("set_encode_limits"))]
+pub unsafe extern "C" fn set_encode_limits(tokenizer_ptr: *mut TokenizerHandle, limits: EncodeLimits) -> *mut c_char {
+    let result = catch_panic(|| {
+        convert_to_handle_ref(tokenizer_ptr)?.write()?.set_encode_limits(limits);
+        Ok(())
+    });
+    match result {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+/// get_encode_limits returns the limits on the inputs of the encode calls of the tokenizer (see
+/// `set_encode_limits`), or no limits if the tokenizer is null.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("get_encode_limits"))]
+pub unsafe extern "C" fn get_encode_limits(tokenizer_ptr: *mut TokenizerHandle) -> EncodeLimits {
+    catch_panic_or(EncodeLimits::default(), || match convert_to_handle_ref(tokenizer_ptr) {
+        Ok(handle) => handle.read().encode_limits(),
+        Err(_) => EncodeLimits::default(),
+    })
+}
+
+/// encode_error_code returns the code of the `error` of the results of an encode call (e.g.
+/// `EncodeResults.error`): `ENCODE_OK` if it is null, one of the specific `ENCODE_ERROR_*` codes, or
+/// `ENCODE_ERROR_OTHER`.
+///
+/// # Safety
+///
+/// `error` must be null or a valid C string.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encode_error_code"))]
+pub unsafe extern "C" fn encode_error_code(error: *const c_char) -> u32 {
+    catch_panic_or(ENCODE_ERROR_OTHER, || {
+        if error.is_null() {
+            return ENCODE_OK;
+        }
+        let error = unsafe { CStr::from_ptr(error) }.to_bytes();
+        if error.starts_with(INPUT_TOO_LARGE.as_bytes()) {
+            ENCODE_ERROR_INPUT_TOO_LARGE
+        } else if error.starts_with(BATCH_TOO_LARGE.as_bytes()) {
+            ENCODE_ERROR_BATCH_TOO_LARGE
+        } else if error.starts_with(TOKEN_BUDGET_EXCEEDED.as_bytes()) {
+            ENCODE_ERROR_TOKEN_BUDGET_EXCEEDED
+        } else {
+            ENCODE_ERROR_OTHER
+        }
+    })
+}
+
+/// encode_error_token_count returns the number of tokens produced by the input that exceeded the token budget
+/// until its encoding was aborted, if `error` is an `ENCODE_ERROR_TOKEN_BUDGET_EXCEEDED` error (see
+/// `encode_error_code`), or 0 otherwise.
+///
+/// # Safety
+///
+/// `error` must be null or a valid C string.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encode_error_token_count"))]
+pub unsafe extern "C" fn encode_error_token_count(error: *const c_char) -> u64 {
+    catch_panic_or(0, || {
+        if error.is_null() {
+            return 0;
+        }
+        let error = unsafe { CStr::from_ptr(error) }.to_string_lossy();
+        match error.strip_prefix(TOKEN_BUDGET_EXCEEDED) {
+            Some(count) => count.split(' ').next().and_then(|count| count.parse().ok()).unwrap_or(0),
+            None => 0,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use crate::encode::{encode_str_impl, free_buffer, EncodeParams, ENCODE_PARAMS_VERSION};
+    use crate::encode::ENCODE_ADD_SPECIAL_TOKENS;
+    use crate::testing::{take_error, TestHandle, WORDPIECE, WORDPIECE_BERT};
+
+    // encode_error returns the error of encoding `text`, with its code and token count, or None if it succeeds.
+    fn encode_error(handle: &TestHandle, text: &str, flags: u64) -> Option<(String, u32, u64)> {
+        let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags };
+        match encode_str_impl(handle.0, text, options) {
+            Ok(buffers) => {
+                buffers.into_iter().for_each(free_buffer);
+                None
+            }
+            Err(e) => {
+                let error = CString::new(e.to_string()).unwrap();
+                let code = unsafe { encode_error_code(error.as_ptr()) };
+                Some((e.to_string(), code, unsafe { encode_error_token_count(error.as_ptr()) }))
+            }
+        }
+    }
+
+    #[test]
+    fn input_limits() {
+        let handle = TestHandle::new(WORDPIECE);
+        assert!(unsafe { get_encode_limits(handle.0) } == EncodeLimits::default());
+        let limits = EncodeLimits { max_input_bytes: 5, max_batch_size: 2, max_tokens: 0 };
+        assert_eq!(take_error(unsafe { set_encode_limits(handle.0, limits) }), None);
+        assert!(unsafe { get_encode_limits(handle.0) } == limits);
+
+        assert_eq!(encode_error(&handle, "hello", 0), None);
+        let error = "input too large: input #0 has 11 bytes, more than the limit of 5".to_string();
+        assert_eq!(encode_error(&handle, "hello world", 0), Some((error, ENCODE_ERROR_INPUT_TOO_LARGE, 0)));
+        assert!(limits.check([1, 5].into_iter()).is_ok());
+        let error = limits.check([1, 2, 3].into_iter()).unwrap_err().to_string();
+        assert_eq!(error, "batch too large: 3 inputs, more than the limit of 2");
+
+        let codes = [c"batch too large: 3 inputs", c"input too large: x", c"failed"]
+            .map(|error| unsafe { encode_error_code(error.as_ptr()) });
+        assert_eq!(codes, [ENCODE_ERROR_BATCH_TOO_LARGE, ENCODE_ERROR_INPUT_TOO_LARGE, ENCODE_ERROR_OTHER]);
+        assert_eq!(unsafe { encode_error_code(null_mut()) }, ENCODE_OK);
+
+        // The default limits remove all limits.
+        assert_eq!(take_error(unsafe { set_encode_limits(handle.0, EncodeLimits::default()) }), None);
+        assert_eq!(encode_error(&handle, "hello world", 0), None);
+        assert!(take_error(unsafe { set_encode_limits(null_mut(), limits) }).is_some());
+    }
+
+    #[test]
+    fn token_budget() {
+        let handle = TestHandle::new(WORDPIECE_BERT);
+        let limits = EncodeLimits { max_tokens: 2, ..Default::default() };
+        assert_eq!(take_error(unsafe { set_encode_limits(handle.0, limits) }), None);
+
+        // The special tokens added are not counted.
+        assert_eq!(encode_error(&handle, "hello world", ENCODE_ADD_SPECIAL_TOKENS), None);
+        let error = "token budget exceeded: 3 tokens produced, more than the limit of 2".to_string();
+        let exceeded = Some((error, ENCODE_ERROR_TOKEN_BUDGET_EXCEEDED, 3));
+        assert_eq!(encode_error(&handle, "hello world a b", 0), exceeded);
+        assert_eq!(encode_error(&handle, "hello ab", ENCODE_ADD_SPECIAL_TOKENS), exceeded);
+        assert_eq!(unsafe { encode_error_token_count(c"input too large: 3 tokens".as_ptr()) }, 0);
+    }
+}
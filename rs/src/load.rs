@@ -0,0 +1,879 @@
+//! Loading of tokenizers from sources other than a `tokenizer.json` already in memory (see `from_bytes`).
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::{c_char, c_void, CStr};
+use std::io::{BufReader, Read};
+use std::ptr::null_mut;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokenizers::tokenizer::Tokenizer;
+use crate::config::parse_config;
+use crate::encode::err;
+use crate::generation::byte_level_char_bytes;
+use crate::handle::TokenizerHandle;
+use crate::panics::{catch_panic, error_c_string};
+use crate::PointerOrError;
+
+/// ReadCallback is a host function that reads up to `len` bytes into `buf`, like Go's `io.Reader`: it returns
+/// the number of bytes read, 0 at the end of the input, or a negative value on error.
+pub type ReadCallback = Option<unsafe extern "C" fn(user_data: *mut c_void, buf: *mut u8, len: u64) -> i64>;
+
+// HostReader reads from a host ReadCallback.
+struct HostReader {
+    read: unsafe extern "C" fn(user_data: *mut c_void, buf: *mut u8, len: u64) -> i64,
+    user_data: *mut c_void,
+}
+
+impl Read for HostReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = unsafe { (self.read)(self.user_data, buf.as_mut_ptr(), buf.len() as u64) };
+        match usize::try_from(n) {
+            Ok(n) if n <= buf.len() => Ok(n),
+            Ok(n) => Err(std::io::Error::other(format!("read callback returned {} bytes, more than the {} requested", n, buf.len()))),
+            Err(_) => Err(std::io::Error::other(format!("read callback failed with {}", n))),
+        }
+    }
+}
+
+// shared_tokenizers is the content-addressed cache of the Tokenizers loaded by `from_bytes` (and
+// `from_compiled`), indexed by the SHA-256 of their contents. It only holds weak references: a Tokenizer is freed once no
+// handle uses it, and its entry is pruned on the next insertion.
+fn shared_tokenizers() -> &'static Mutex<HashMap<[u8; 32], Weak<Tokenizer>>> {
+    static SHARED: OnceLock<Mutex<HashMap<[u8; 32], Weak<Tokenizer>>>> = OnceLock::new();
+    SHARED.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Returns the Tokenizer defined by the `tokenizer.json` contents in `bytes`, shared with the other handles
+/// loaded from identical contents that are still alive -- e.g. by the workers of a multi-tenant server, each
+/// loading the same tokenizer -- so it is only parsed and held in memory once.
+///
+/// The handles still behave as independent copies: the first time one is modified (e.g. its padding is
+/// set), it gets its own copy of the Tokenizer (see `TokenizerState::tokenizer_mut`).
+pub(crate) fn shared_from_bytes(bytes: &[u8]) -> Result<Arc<Tokenizer>, Box<dyn Error>> {
+    shared_tokenizer(bytes, |bytes| Tokenizer::from_bytes(bytes).map_err(|e| err(e.to_string())))
+}
+
+/// Returns the Tokenizer parsed by `parse` from the `bytes`, shared as in `shared_from_bytes`.
+pub(crate) fn shared_tokenizer(
+    bytes: &[u8],
+    parse: impl FnOnce(&[u8]) -> Result<Tokenizer, Box<dyn Error>>,
+) -> Result<Arc<Tokenizer>, Box<dyn Error>> {
+    let digest: [u8; 32] = Sha256::digest(bytes).into();
+    let cached = shared_tokenizers().lock().unwrap_or_else(|e| e.into_inner()).get(&digest).and_then(Weak::upgrade);
+    if let Some(tokenizer) = cached {
+        return Ok(tokenizer);
+    }
+
+    // Parsing happens without holding the lock: if the same contents are loaded concurrently, the first
+    // Tokenizer inserted wins.
+    let tokenizer = Arc::new(parse(bytes)?);
+    let mut shared = shared_tokenizers().lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(cached) = shared.get(&digest).and_then(Weak::upgrade) {
+        return Ok(cached);
+    }
+    shared.retain(|_, tokenizer| tokenizer.strong_count() > 0);
+    shared.insert(digest, Arc::downgrade(&tokenizer));
+    Ok(tokenizer)
+}
+
+// new_handle returns the raw handle of a new tokenizer (owned, or shared as returned by `shared_from_bytes`), or
+// the error.
+pub(crate) fn new_handle(tokenizer: Result<impl Into<Arc<Tokenizer>>, Box<dyn Error>>) -> PointerOrError {
+    match tokenizer {
+        Ok(tokenizer) => PointerOrError {
+            value: Arc::new(TokenizerHandle::new(tokenizer)).into_raw(),
+            error: null_mut(),
+        },
+        Err(e) => PointerOrError {
+            value: null_mut(),
+            error: error_c_string(e),
+        },
+    }
+}
+
+fn from_reader_impl(read: ReadCallback, user_data: *mut c_void) -> Result<Tokenizer, Box<dyn Error>> {
+    let read = read.ok_or_else(|| err("read callback is null"))?;
+    let reader = BufReader::new(HostReader { read, user_data });
+    serde_json::from_reader(reader).map_err(|e| err(format!("failed to load tokenizer: {}", e)))
+}
+
+/// from_reader loads a tokenizer from the `tokenizer.json` contents read by the host `read` callback (see
+/// `ReadCallback`), called repeatedly (from the calling thread) until the end of the input, with the given
+/// `user_data`. The contents are parsed as they are read, so they are never buffered whole in memory.
+///
+/// It returns the Tokenizer in the `value` field, or an error, as `from_bytes`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("from_reader"))]
+pub unsafe extern "C" fn from_reader(read: ReadCallback, user_data: *mut c_void) -> PointerOrError {
+    new_handle(catch_panic(|| from_reader_impl(read, user_data)))
+}
+
+// merge_overlay merges the JSON `overlay` fragment into the tokenizer definition `base`: objects are merged
+// recursively and other values replaced, except for the `added_tokens`, merged by id -- the overlay tokens
+// replace those with the same id, and the others are appended.
+fn merge_overlay(base: &mut Value, overlay: Value) {
+    let overlay = match overlay {
+        Value::Object(overlay) => overlay,
+        overlay => {
+            *base = overlay;
+            return;
+        }
+    };
+    let base = match base {
+        Value::Object(base) => base,
+        base => {
+            *base = Value::Object(overlay);
+            return;
+        }
+    };
+    for (key, value) in overlay {
+        match (key.as_str(), base.get_mut(&key), value) {
+            ("added_tokens", Some(Value::Array(tokens)), Value::Array(overlay_tokens)) => {
+                for token in overlay_tokens {
+                    match tokens.iter_mut().find(|added| !token["id"].is_null() && added["id"] == token["id"]) {
+                        Some(added) => *added = token,
+                        None => tokens.push(token),
+                    }
+                }
+            }
+            (_, Some(base_value), value) => merge_overlay(base_value, value),
+            (_, None, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn from_bytes_with_overlays_impl(
+    bytes: &[u8],
+    num_overlays: usize,
+    overlays: *const *const u8,
+    lengths: *const u32,
+) -> Result<Tokenizer, Box<dyn Error>> {
+    let mut json: Value = serde_json::from_slice(bytes).map_err(|e| err(format!("invalid tokenizer: {}", e)))?;
+    for index in 0..num_overlays {
+        let overlay = unsafe {
+            let len = *lengths.add(index) as usize;
+            if len == 0 {
+                &[]
+            } else {
+                std::slice::from_raw_parts(*overlays.add(index), len)
+            }
+        };
+        let overlay: Value = serde_json::from_slice(overlay)
+            .map_err(|e| err(format!("invalid overlay #{}: {}", index, e)))?;
+        merge_overlay(&mut json, overlay);
+    }
+    serde_json::from_value(json).map_err(|e| err(format!("failed to load tokenizer with overlays: {}", e)))
+}
+
+/// from_bytes_with_overlays loads a tokenizer, like `from_bytes`, from the `tokenizer.json` contents in `bytes`
+/// merged with the `num_overlays` JSON fragments (e.g. sidecar files with extra added tokens, or a
+/// post-processor template) given by pointers in `overlays` and their lengths in `lengths`, in order.
+///
+/// Each overlay is a JSON object, merged into the definition: objects are merged recursively, and other
+/// values replaced -- except for `added_tokens`, where the tokens of the overlay replace those with the same
+/// `id`, and the others are appended.
+///
+/// It returns the Tokenizer in the `value` field, or an error.
+///
+/// # Safety
+///
+/// `overlays` and `lengths` must point to `num_overlays` buffers and lengths.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("from_bytes_with_overlays"))]
+pub unsafe extern "C" fn from_bytes_with_overlays(
+    bytes: *const u8,
+    len: u32,
+    num_overlays: u32,
+    overlays: *const *const u8,
+    lengths: *const u32,
+) -> PointerOrError {
+    let bytes = unsafe { std::slice::from_raw_parts(bytes, len as usize) };
+    new_handle(catch_panic(|| from_bytes_with_overlays_impl(bytes, num_overlays as usize, overlays, lengths)))
+}
+
+/// DecryptCallback is a host function that decrypts the `len` bytes of `data`: it returns the decrypted bytes
+/// (setting their length in `out_len`), or null on error. The returned bytes are owned by the host, and only
+/// need to remain valid until the function loading the tokenizer returns -- they are parsed in place, not
+/// copied.
+pub type DecryptCallback = Option<
+    unsafe extern "C" fn(user_data: *mut c_void, data: *const u8, len: u64, out_len: *mut u64) -> *const u8,
+>;
+
+fn from_encrypted_bytes_impl(bytes: &[u8], decrypt: DecryptCallback, user_data: *mut c_void) -> Result<Tokenizer, Box<dyn Error>> {
+    let decrypt = decrypt.ok_or_else(|| err("decrypt callback is null"))?;
+    let mut len: u64 = 0;
+    let decrypted = unsafe { decrypt(user_data, bytes.as_ptr(), bytes.len() as u64, &mut len) };
+    if decrypted.is_null() {
+        return Err(err("failed to decrypt tokenizer"));
+    }
+    let decrypted = unsafe { std::slice::from_raw_parts(decrypted, usize::try_from(len)?) };
+    Tokenizer::from_bytes(decrypted).map_err(|e| err(format!("failed to load decrypted tokenizer: {}", e)))
+}
+
+/// from_encrypted_bytes loads a tokenizer, like `from_bytes`, from the encrypted `tokenizer.json` contents in
+/// `bytes`: they are passed to the host `decrypt` callback (with the given `user_data`), and the decrypted
+/// contents it returns are parsed (see `DecryptCallback`). So the decrypted contents are never written to disk.
+///
+/// It returns the Tokenizer in the `value` field, or an error.
+///
+/// # Safety
+///
+/// `bytes` must point to `len` bytes.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("from_encrypted_bytes"))]
+pub unsafe extern "C" fn from_encrypted_bytes(
+    bytes: *const u8,
+    len: u64,
+    decrypt: DecryptCallback,
+    user_data: *mut c_void,
+) -> PointerOrError {
+    let bytes = match usize::try_from(len) {
+        Ok(0) => &[][..],
+        Ok(len) => unsafe { std::slice::from_raw_parts(bytes, len) },
+        Err(_) => return new_handle(Err::<Tokenizer, _>(err(format!("{} bytes overflow usize", len)))),
+    };
+    new_handle(catch_panic(|| from_encrypted_bytes_impl(bytes, decrypt, user_data)))
+}
+
+/// Error code set by the verified loaders (e.g. `from_bytes_verified`): the tokenizer was loaded.
+pub const LOAD_OK: u32 = 0;
+/// Error code set by the verified loaders: the tokenizer definition is invalid.
+pub const LOAD_ERROR_INVALID: u32 = 1;
+/// Error code set by the verified loaders: the SHA-256 digest of the contents doesn't match the expected one.
+pub const LOAD_ERROR_CHECKSUM_MISMATCH: u32 = 2;
+/// Error code set by the verified loaders: the contents couldn't be read (or downloaded).
+pub const LOAD_ERROR_IO: u32 = 3;
+
+// hex formats the bytes as lowercase hexadecimal.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Returns the Tokenizer defined by the `tokenizer.json` contents in `bytes`, shared as in `shared_from_bytes`,
+/// after checking that their SHA-256 digest is the 32 bytes at `expected_sha256` (if not null). The errors come
+/// with their `LOAD_ERROR_*` code.
+pub(crate) fn verified_from_bytes(
+    bytes: &[u8],
+    expected_sha256: *const u8,
+) -> Result<Arc<Tokenizer>, (u32, Box<dyn Error>)> {
+    if !expected_sha256.is_null() {
+        let expected = unsafe { std::slice::from_raw_parts(expected_sha256, 32) };
+        let digest = Sha256::digest(bytes);
+        if digest.as_slice() != expected {
+            return Err((LOAD_ERROR_CHECKSUM_MISMATCH, err(format!(
+                "tokenizer SHA-256 checksum mismatch: expected {}, got {}", hex(expected), hex(&digest)))));
+        }
+    }
+    shared_from_bytes(bytes).map_err(|e| (LOAD_ERROR_INVALID, err(format!("failed to load tokenizer: {}", e))))
+}
+
+/// Returns the handle of the tokenizer returned by `load`, or its error, setting `error_code` (if not null) to
+/// `LOAD_OK` or the code of the error. A panic is returned as a `LOAD_ERROR_INVALID` error.
+pub(crate) fn new_verified_handle(
+    error_code: *mut u32,
+    load: impl FnOnce() -> Result<Arc<Tokenizer>, (u32, Box<dyn Error>)>,
+) -> PointerOrError {
+    let result = catch_panic(|| Ok(load())).unwrap_or_else(|e| Err((LOAD_ERROR_INVALID, e)));
+    let (code, result) = match result {
+        Ok(tokenizer) => (LOAD_OK, Ok(tokenizer)),
+        Err((code, e)) => (code, Err(e)),
+    };
+    if !error_code.is_null() {
+        unsafe { error_code.write(code) };
+    }
+    new_handle(result)
+}
+
+/// from_bytes_verified loads a tokenizer, like `from_bytes`, after checking that the SHA-256 digest of the
+/// `tokenizer.json` contents in `bytes` is the 32 bytes at `expected_sha256` (if not null). This protects from
+/// loading corrupted or tampered files.
+///
+/// It returns the Tokenizer in the `value` field, or an error. If `error_code` is not null, it is set to
+/// `LOAD_OK`, `LOAD_ERROR_CHECKSUM_MISMATCH` or `LOAD_ERROR_INVALID`.
+///
+/// # Safety
+///
+/// `bytes` must point to `len` bytes, `expected_sha256` must be null or point to 32 bytes, and `error_code`
+/// must be null or point to a writable u32.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("from_bytes_verified"))]
+pub unsafe extern "C" fn from_bytes_verified(
+    bytes: *const u8,
+    len: u32,
+    expected_sha256: *const u8,
+    error_code: *mut u32,
+) -> PointerOrError {
+    let bytes = unsafe { std::slice::from_raw_parts(bytes, len as usize) };
+    new_verified_handle(error_code, || verified_from_bytes(bytes, expected_sha256))
+}
+
+fn from_file_verified_impl(
+    path: *const c_char,
+    expected_sha256: *const u8,
+) -> Result<Arc<Tokenizer>, (u32, Box<dyn Error>)> {
+    let path = path_arg(path, "tokenizer")
+        .and_then(|path| path.ok_or_else(|| err("tokenizer path is null")))
+        .map_err(|e| (LOAD_ERROR_INVALID, e))?;
+    let bytes = std::fs::read(path).map_err(|e| (LOAD_ERROR_IO, err(format!("failed to read {}: {}", path, e))))?;
+    verified_from_bytes(&bytes, expected_sha256)
+}
+
+/// from_file_verified loads a tokenizer from the `tokenizer.json` file at `path`, after checking that the
+/// SHA-256 digest of its contents is the 32 bytes at `expected_sha256` (if not null), as `from_bytes_verified`.
+///
+/// It returns the Tokenizer in the `value` field, or an error. If `error_code` is not null, it is set to
+/// `LOAD_OK`, `LOAD_ERROR_IO` (the file can't be read), `LOAD_ERROR_CHECKSUM_MISMATCH` or `LOAD_ERROR_INVALID`.
+///
+/// # Safety
+///
+/// `path` must be a null-terminated string, `expected_sha256` must be null or point to 32 bytes, and
+/// `error_code` must be null or point to a writable u32.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("from_file_verified"))]
+pub unsafe extern "C" fn from_file_verified(
+    path: *const c_char,
+    expected_sha256: *const u8,
+    error_code: *mut u32,
+) -> PointerOrError {
+    new_verified_handle(error_code, || from_file_verified_impl(path, expected_sha256))
+}
+
+fn from_bytes_with_config_impl(bytes: &[u8], config: *const u8, config_len: u32) -> Result<TokenizerHandle, Box<dyn Error>> {
+    let config = parse_config(config, config_len)?;
+    let tokenizer = shared_from_bytes(bytes).map_err(|e| err(format!("failed to load tokenizer: {}", e)))?;
+    let handle = TokenizerHandle::new(tokenizer);
+    handle.write()?.set_config(config.map(Arc::new));
+    Ok(handle)
+}
+
+/// from_bytes_with_config loads a tokenizer, like `from_bytes`, along with the settings of the `config_len`
+/// bytes of `config`, the contents of its `tokenizer_config.json` file (e.g. the chat templates, see
+/// `get_chat_template`). If `config` is null, it is the same as `from_bytes`.
+///
+/// It returns the Tokenizer in the `value` field, or an error.
+///
+/// # Safety
+///
+/// `bytes` must point to `len` bytes, and `config` must be null or point to `config_len` bytes.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("from_bytes_with_config"))]
+pub unsafe extern "C" fn from_bytes_with_config(
+    bytes: *const u8,
+    len: u32,
+    config: *const u8,
+    config_len: u32,
+) -> PointerOrError {
+    let bytes = unsafe { std::slice::from_raw_parts(bytes, len as usize) };
+    match catch_panic(|| from_bytes_with_config_impl(bytes, config, config_len)) {
+        Ok(handle) => PointerOrError {
+            value: Arc::new(handle).into_raw(),
+            error: null_mut(),
+        },
+        Err(e) => PointerOrError {
+            value: null_mut(),
+            error: error_c_string(e),
+        },
+    }
+}
+
+/// TiktokenDefinition is the in-memory representation of a tiktoken encoding, see `from_tiktoken_ranks`.
+#[repr(C)]
+pub struct TiktokenDefinition {
+    // The mergeable ranks: `num_ranks` tokens (byte strings, given by pointers in `tokens` and their lengths in
+    // `token_lengths`) and their ranks.
+    num_ranks: u64,
+    tokens: *const *const u8,
+    token_lengths: *const u32,
+    ranks: *const u32,
+
+    // The special tokens: `num_special_tokens` UTF-8 strings (given by pointers in `special_tokens` and their
+    // lengths in `special_token_lengths`) and their ids.
+    num_special_tokens: u64,
+    special_tokens: *const *const u8,
+    special_token_lengths: *const u32,
+    special_token_ids: *const u32,
+
+    // The regular expression splitting the text before the merges (`pat_str` in tiktoken), or null for the
+    // GPT-2 one.
+    pattern: *const c_char,
+}
+
+// byte_strings returns the `num` byte strings given by pointers in `strings` and their lengths in `lengths`.
+unsafe fn byte_strings<'a>(num: u64, strings: *const *const u8, lengths: *const u32) -> Result<Vec<&'a [u8]>, Box<dyn Error>> {
+    let num = usize::try_from(num)?;
+    Ok((0..num)
+        .map(|index| unsafe {
+            match *lengths.add(index) as usize {
+                0 => &[][..],
+                len => std::slice::from_raw_parts(*strings.add(index), len),
+            }
+        })
+        .collect())
+}
+
+fn from_tiktoken_ranks_impl(definition: *const TiktokenDefinition) -> Result<Tokenizer, Box<dyn Error>> {
+    let definition = unsafe { definition.as_ref() }.ok_or_else(|| err("tiktoken definition is null"))?;
+    let tokens = unsafe { byte_strings(definition.num_ranks, definition.tokens, definition.token_lengths)? };
+    let ranks: HashMap<&[u8], u32> = tokens
+        .iter()
+        .enumerate()
+        .map(|(index, &token)| (token, unsafe { *definition.ranks.add(index) }))
+        .collect();
+    let special_tokens = unsafe {
+        byte_strings(definition.num_special_tokens, definition.special_tokens, definition.special_token_lengths)?
+    };
+
+    // Tokens are stored in the byte-level alphabet, and the merges are all the splits of each token in two
+    // tokens, ordered by the rank of the token and then of the parts -- as the tiktoken converter of
+    // transformers does.
+    let mut byte_chars = ['\0'; 256];
+    for (c, b) in byte_level_char_bytes() {
+        byte_chars[b as usize] = c;
+    }
+    let to_chars = |token: &[u8]| token.iter().map(|&b| byte_chars[b as usize]).collect::<String>();
+    let mut vocab = serde_json::Map::with_capacity(ranks.len());
+    let mut merges = Vec::new();
+    for (&token, &rank) in &ranks {
+        vocab.insert(to_chars(token), json!(rank));
+        let mut splits: Vec<(u32, u32, usize)> = (1..token.len())
+            .filter_map(|index| Some((*ranks.get(&token[..index])?, *ranks.get(&token[index..])?, index)))
+            .collect();
+        splits.sort_unstable();
+        merges.extend(splits.into_iter().map(|(_, _, index)| (rank, &token[..index], &token[index..])));
+    }
+    merges.sort_by_key(|&(rank, _, _)| rank);
+    let merges: Vec<Value> = merges.into_iter().map(|(_, left, right)| json!([to_chars(left), to_chars(right)])).collect();
+
+    // The special tokens are also in the vocabulary of the model, so they keep their ids: added tokens that are
+    // not are given the ids after those of the model.
+    let mut added_tokens = Vec::with_capacity(special_tokens.len());
+    for (index, token) in special_tokens.into_iter().enumerate() {
+        let content = std::str::from_utf8(token).map_err(|e| err(format!("special token #{} is not valid UTF-8: {}", index, e)))?;
+        let id = unsafe { *definition.special_token_ids.add(index) };
+        if vocab.insert(content.to_string(), json!(id)).is_some() {
+            return Err(err(format!("special token {:?} is also a mergeable token", content)));
+        }
+        added_tokens.push(json!({
+            "id": id,
+            "content": content,
+            "single_word": false,
+            "lstrip": false,
+            "rstrip": false,
+            "normalized": false,
+            "special": true,
+        }));
+    }
+    let pre_tokenizer = if definition.pattern.is_null() {
+        json!({"type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": true})
+    } else {
+        let pattern = unsafe { CStr::from_ptr(definition.pattern) }.to_str()?;
+        json!({"type": "Sequence", "pretokenizers": [
+            {"type": "Split", "pattern": {"Regex": pattern}, "behavior": "Isolated", "invert": false},
+            {"type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": false},
+        ]})
+    };
+    let json = json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": added_tokens,
+        "normalizer": null,
+        "pre_tokenizer": pre_tokenizer,
+        "post_processor": {"type": "ByteLevel", "add_prefix_space": true, "trim_offsets": false, "use_regex": true},
+        "decoder": {"type": "ByteLevel", "add_prefix_space": true, "trim_offsets": true, "use_regex": true},
+        "model": {
+            "type": "BPE",
+            "dropout": null,
+            "unk_token": null,
+            "continuing_subword_prefix": null,
+            "end_of_word_suffix": null,
+            "fuse_unk": false,
+            "byte_fallback": false,
+            "ignore_merges": false,
+            "vocab": vocab,
+            "merges": merges,
+        },
+    });
+    json.to_string().parse().map_err(|e| err(format!("failed to build tiktoken tokenizer: {}", e)))
+}
+
+/// from_tiktoken_ranks builds a byte-level BPE tokenizer from the in-memory representation of a tiktoken
+/// encoding (see `TiktokenDefinition`): its mergeable ranks (token bytes to rank, which are also the token
+/// ids), its special tokens and its split pattern -- as the tiktoken converter of transformers does, but without
+/// writing any intermediary file. It is the inverse of `export_tiktoken_ranks` and
+/// `export_tiktoken_special_tokens`.
+///
+/// It returns the Tokenizer in the `value` field, or an error.
+///
+/// # Safety
+///
+/// `definition` must point to a TiktokenDefinition, with valid pointers to its arrays, and `pattern` null or a
+/// null-terminated string.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("from_tiktoken_ranks"))]
+pub unsafe extern "C" fn from_tiktoken_ranks(definition: *const TiktokenDefinition) -> PointerOrError {
+    new_handle(catch_panic(|| from_tiktoken_ranks_impl(definition)))
+}
+
+// The special tokens registered as added tokens by `from_files`, if they are in the vocabulary: those of the
+// BERT (WordPiece) and of the GPT-2 and RoBERTa (BPE) models.
+const WORDPIECE_SPECIAL_TOKENS: [&str; 5] = ["[PAD]", "[UNK]", "[CLS]", "[SEP]", "[MASK]"];
+const BPE_SPECIAL_TOKENS: [&str; 6] = ["<|endoftext|>", "<s>", "</s>", "<unk>", "<pad>", "<mask>"];
+
+// path_arg returns the path given as a null-terminated string, or None if it is null.
+fn path_arg<'a>(path: *const c_char, name: &str) -> Result<Option<&'a str>, Box<dyn Error>> {
+    if path.is_null() {
+        return Ok(None);
+    }
+    let path = unsafe { CStr::from_ptr(path) }.to_str().map_err(|e| err(format!("{} path: {}", name, e)))?;
+    Ok(Some(path))
+}
+
+fn from_files_impl(vocab: *const c_char, merges: *const c_char, lowercase: bool) -> Result<Tokenizer, Box<dyn Error>> {
+    let vocab_path = path_arg(vocab, "vocab")?.ok_or_else(|| err("vocab path is null"))?;
+    let merges_path = path_arg(merges, "merges")?;
+    let files = match merges_path {
+        Some(merges_path) => format!("{:?} and {:?}", vocab_path, merges_path),
+        None => format!("{:?}", vocab_path),
+    };
+    let file_error = |e| err(format!("failed to read {}: {}", files, e));
+
+    // The pipelines are those the converters of transformers build for these models: BERT for WordPiece, and
+    // GPT-2 (or RoBERTa, if its special tokens are in the vocabulary) for BPE.
+    let (vocab, special_tokens, mut json) = match merges_path {
+        None => {
+            let vocab = tokenizers::models::wordpiece::WordPiece::read_file(vocab_path).map_err(file_error)?;
+            let post_processor = match (vocab.get("[CLS]"), vocab.get("[SEP]")) {
+                (Some(&cls), Some(&sep)) => json!({"type": "BertProcessing", "sep": ["[SEP]", sep], "cls": ["[CLS]", cls]}),
+                _ => Value::Null,
+            };
+            let json = json!({
+                "normalizer": {
+                    "type": "BertNormalizer",
+                    "clean_text": true,
+                    "handle_chinese_chars": true,
+                    "strip_accents": null,
+                    "lowercase": lowercase,
+                },
+                "pre_tokenizer": {"type": "BertPreTokenizer"},
+                "post_processor": post_processor,
+                "decoder": {"type": "WordPiece", "prefix": "##", "cleanup": true},
+                "model": {
+                    "type": "WordPiece",
+                    "unk_token": "[UNK]",
+                    "continuing_subword_prefix": "##",
+                    "max_input_chars_per_word": 100,
+                    "vocab": vocab,
+                },
+            });
+            (vocab, &WORDPIECE_SPECIAL_TOKENS[..], json)
+        }
+        Some(merges_path) => {
+            let (vocab, merges) = tokenizers::models::bpe::BPE::read_file(vocab_path, merges_path).map_err(file_error)?;
+            let post_processor = match (vocab.get("<s>"), vocab.get("</s>")) {
+                (Some(&cls), Some(&sep)) => json!({
+                    "type": "RobertaProcessing",
+                    "sep": ["</s>", sep],
+                    "cls": ["<s>", cls],
+                    "trim_offsets": true,
+                    "add_prefix_space": false,
+                }),
+                _ => json!({"type": "ByteLevel", "add_prefix_space": true, "trim_offsets": false, "use_regex": true}),
+            };
+            let json = json!({
+                "normalizer": if lowercase { json!({"type": "Lowercase"}) } else { Value::Null },
+                "pre_tokenizer": {"type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": true},
+                "post_processor": post_processor,
+                "decoder": {"type": "ByteLevel", "add_prefix_space": true, "trim_offsets": true, "use_regex": true},
+                "model": {
+                    "type": "BPE",
+                    "dropout": null,
+                    "unk_token": null,
+                    "continuing_subword_prefix": null,
+                    "end_of_word_suffix": null,
+                    "fuse_unk": false,
+                    "byte_fallback": false,
+                    "ignore_merges": false,
+                    "vocab": vocab,
+                    "merges": merges,
+                },
+            });
+            (vocab, &BPE_SPECIAL_TOKENS[..], json)
+        }
+    };
+    let added_tokens: Vec<Value> = special_tokens
+        .iter()
+        .filter_map(|&content| {
+            Some(json!({
+                "id": vocab.get(content)?,
+                "content": content,
+                "single_word": false,
+                "lstrip": false,
+                "rstrip": false,
+                "normalized": false,
+                "special": true,
+            }))
+        })
+        .collect();
+    json["version"] = json!("1.0");
+    json["truncation"] = Value::Null;
+    json["padding"] = Value::Null;
+    json["added_tokens"] = json!(added_tokens);
+    serde_json::from_value(json).map_err(|e| err(format!("failed to build tokenizer from {:?}: {}", vocab_path, e)))
+}
+
+/// from_files builds a tokenizer from the files of the models that don't ship a `tokenizer.json`, as the
+/// converters of transformers do:
+///
+/// - With a null `merges`, a WordPiece tokenizer (as BERT) from `vocab`, a `vocab.txt` file with one token per
+///   line (its id being the line number). `lowercase` is the `do_lower_case` of the model (see its
+///   `tokenizer_config.json`): if set, the text is lowercased and its accents removed.
+/// - Otherwise, a byte-level BPE tokenizer (as GPT-2, or RoBERTa if "<s>" and "</s>" are in the vocabulary)
+///   from `vocab`, a `vocab.json` file mapping the tokens to their ids, and `merges`, a `merges.txt` file with
+///   one merge per line. If `lowercase` is set, the text is lowercased.
+///
+/// The special tokens of these models found in the vocabulary (e.g. "[CLS]", "[SEP]" or "<|endoftext|>") are
+/// registered as special tokens. The tokenizer can then be saved as a `tokenizer.json` with `tokenizer_save`.
+///
+/// It returns the Tokenizer in the `value` field, or an error.
+///
+/// # Safety
+///
+/// `vocab` must be a null-terminated string, and `merges` null or a null-terminated string.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("from_files"))]
+pub unsafe extern "C" fn from_files(vocab: *const c_char, merges: *const c_char, lowercase: bool) -> PointerOrError {
+    new_handle(catch_panic(|| from_files_impl(vocab, merges, lowercase)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use crate::testing::{encode_ids, take_error, temp_dir, TestHandle, WORDPIECE};
+
+    // load_verified returns the handle (or error) of `from_bytes_verified`, and the error code.
+    fn load_verified(bytes: &[u8], expected_sha256: Option<[u8; 32]>) -> (PointerOrError, u32) {
+        let mut code = u32::MAX;
+        let expected = expected_sha256.as_ref().map_or(std::ptr::null(), |digest| digest.as_ptr());
+        let result = unsafe { from_bytes_verified(bytes.as_ptr(), bytes.len() as u32, expected, &mut code) };
+        (result, code)
+    }
+
+    // read_chunks is a ReadCallback reading at most 7 bytes at a time from the `&[u8]` given as `user_data`.
+    unsafe extern "C" fn read_chunks(user_data: *mut c_void, buf: *mut u8, len: u64) -> i64 {
+        let input = unsafe { &mut *(user_data as *mut &[u8]) };
+        let n = input.len().min(len as usize).min(7);
+        unsafe { std::ptr::copy_nonoverlapping(input.as_ptr(), buf, n) };
+        *input = &input[n..];
+        n as i64
+    }
+
+    // read_error is a ReadCallback that always fails.
+    unsafe extern "C" fn read_error(_user_data: *mut c_void, _buf: *mut u8, _len: u64) -> i64 {
+        -1
+    }
+
+    #[test]
+    fn from_reader_callback() {
+        let mut input = WORDPIECE.as_bytes();
+        let result = unsafe { from_reader(Some(read_chunks), &mut input as *mut &[u8] as *mut c_void) };
+        assert_eq!(take_error(result.error), None);
+        let handle = TestHandle(result.value.cast());
+        assert_eq!(encode_ids(&handle, "hello world", 0), [4, 6]);
+
+        let mut input = &WORDPIECE.as_bytes()[..20];
+        let result = unsafe { from_reader(Some(read_chunks), &mut input as *mut &[u8] as *mut c_void) };
+        assert!(result.value.is_null());
+        assert!(take_error(result.error).unwrap().starts_with("failed to load tokenizer: EOF while parsing"));
+        let result = unsafe { from_reader(Some(read_error), null_mut()) };
+        let error = take_error(result.error).unwrap();
+        assert!(error.starts_with("failed to load tokenizer: read callback failed with -1"), "{}", error);
+
+        let result = unsafe { from_reader(None, null_mut()) };
+        assert_eq!(take_error(result.error).as_deref(), Some("read callback is null"));
+    }
+
+    #[test]
+    fn overlays() {
+        let mut base = json!({"a": {"b": 1, "c": [1]}, "added_tokens": [{"id": 1, "content": "x"}]});
+        merge_overlay(&mut base, json!({"a": {"c": [2], "d": null}, "added_tokens": [{"id": 1, "content": "y"}]}));
+        assert_eq!(base, json!({"a": {"b": 1, "c": [2], "d": null}, "added_tokens": [{"id": 1, "content": "y"}]}));
+
+        let overlays = [
+            r#"{"added_tokens": [{"id": 5, "content": "<|im_end|>", "single_word": false, "lstrip": false,
+                "rstrip": false, "normalized": false, "special": false}]}"#,
+            r#"{"normalizer": {"type": "Lowercase"}, "added_tokens": [{"id": 10, "content": "ab",
+                "single_word": false, "lstrip": false, "rstrip": false, "normalized": true, "special": true}]}"#,
+        ];
+        let pointers: Vec<*const u8> = overlays.iter().map(|o| o.as_ptr()).collect();
+        let lengths: Vec<u32> = overlays.iter().map(|o| o.len() as u32).collect();
+        let result = unsafe {
+            let (bytes, len) = (WORDPIECE.as_ptr(), WORDPIECE.len() as u32);
+            from_bytes_with_overlays(bytes, len, 2, pointers.as_ptr(), lengths.as_ptr())
+        };
+        assert_eq!(take_error(result.error), None);
+        let handle = TestHandle(result.value.cast());
+        assert_eq!(encode_ids(&handle, "HELLO AB <|im_end|>", 0), [4, 10, 5]);
+        let added = unsafe { (*handle.0).read().tokenizer.get_added_tokens_decoder() };
+        assert_eq!((added.len(), added[&5].special), (2, false));
+
+        let invalid = [b"{".as_ptr()];
+        let (bytes, len) = (WORDPIECE.as_ptr(), WORDPIECE.len() as u32);
+        let result = unsafe { from_bytes_with_overlays(bytes, len, 1, invalid.as_ptr(), [1].as_ptr()) };
+        assert!(take_error(result.error).unwrap().starts_with("invalid overlay #0: "));
+    }
+
+    // xor_decrypt is a DecryptCallback "decrypting" the data by XOR with 0x5A, into the Vec<u8> given as
+    // `user_data`. Empty data fails.
+    unsafe extern "C" fn xor_decrypt(
+        user_data: *mut c_void,
+        data: *const u8,
+        len: u64,
+        out_len: *mut u64,
+    ) -> *const u8 {
+        if len == 0 {
+            return std::ptr::null();
+        }
+        let decrypted = unsafe { &mut *(user_data as *mut Vec<u8>) };
+        *decrypted = unsafe { std::slice::from_raw_parts(data, len as usize) }.iter().map(|b| b ^ 0x5A).collect();
+        unsafe { *out_len = decrypted.len() as u64 };
+        decrypted.as_ptr()
+    }
+
+    #[test]
+    fn from_encrypted_bytes_callback() {
+        let encrypted: Vec<u8> = WORDPIECE.bytes().map(|b| b ^ 0x5A).collect();
+        let mut decrypted: Vec<u8> = Vec::new();
+        let user_data = &mut decrypted as *mut Vec<u8> as *mut c_void;
+        let load = |bytes: &[u8], decrypt: DecryptCallback| unsafe {
+            from_encrypted_bytes(bytes.as_ptr(), bytes.len() as u64, decrypt, user_data)
+        };
+        let result = load(&encrypted, Some(xor_decrypt));
+        assert_eq!(take_error(result.error), None);
+        let handle = TestHandle(result.value.cast());
+        assert_eq!(encode_ids(&handle, "hello world", 0), [4, 6]);
+
+        assert_eq!(take_error(load(&[], Some(xor_decrypt)).error).as_deref(), Some("failed to decrypt tokenizer"));
+        assert!(take_error(load(WORDPIECE.as_bytes(), Some(xor_decrypt)).error)
+            .unwrap()
+            .starts_with("failed to load decrypted tokenizer: "));
+        assert_eq!(take_error(load(&encrypted, None).error).as_deref(), Some("decrypt callback is null"));
+    }
+
+    #[test]
+    fn tiktoken_ranks() {
+        let tokens: [&[u8]; 8] = [b"h", b"e", b"l", b"o", b"he", b"ll", b"hell", b"hello"];
+        let token_pointers: Vec<*const u8> = tokens.iter().map(|t| t.as_ptr()).collect();
+        let token_lengths: Vec<u32> = tokens.iter().map(|t| t.len() as u32).collect();
+        let ranks: Vec<u32> = (0..tokens.len() as u32).collect();
+        let load = |special_token: &str| {
+            let (special_tokens, special_token_lengths) = ([special_token.as_ptr()], [special_token.len() as u32]);
+            let special_token_ids = [100];
+            let definition = TiktokenDefinition {
+                num_ranks: tokens.len() as u64,
+                tokens: token_pointers.as_ptr(),
+                token_lengths: token_lengths.as_ptr(),
+                ranks: ranks.as_ptr(),
+                num_special_tokens: 1,
+                special_tokens: special_tokens.as_ptr(),
+                special_token_lengths: special_token_lengths.as_ptr(),
+                special_token_ids: special_token_ids.as_ptr(),
+                pattern: std::ptr::null(),
+            };
+            unsafe { from_tiktoken_ranks(&definition) }
+        };
+        let result = load("<|end|>");
+        assert_eq!(take_error(result.error), None);
+        let handle = TestHandle(result.value.cast());
+        assert_eq!(encode_ids(&handle, "hello<|end|>", 0), [7, 100]);
+        assert_eq!(encode_ids(&handle, "hel", 0), [4, 2]);
+        // The merges are the splits of each token in two tokens, ordered by rank.
+        let model = serde_json::to_value(unsafe { (*handle.0).read().tokenizer.get_model() }).unwrap();
+        assert_eq!(model["merges"], json!([["h", "e"], ["l", "l"], ["he", "ll"], ["hell", "o"]]));
+
+        assert_eq!(
+            take_error(load("he").error).as_deref(),
+            Some("special token \"he\" is also a mergeable token"));
+        assert_eq!(
+            take_error(unsafe { from_tiktoken_ranks(std::ptr::null()) }.error).as_deref(),
+            Some("tiktoken definition is null"));
+    }
+
+    #[test]
+    fn from_bytes_verified_codes() {
+        let digest: [u8; 32] = Sha256::digest(WORDPIECE).into();
+        let (first, code) = load_verified(WORDPIECE.as_bytes(), Some(digest));
+        assert_eq!((take_error(first.error), code), (None, LOAD_OK));
+        let (second, code) = load_verified(WORDPIECE.as_bytes(), None);
+        assert_eq!((take_error(second.error), code), (None, LOAD_OK));
+        // Both share the Tokenizer parsed once, as `from_bytes`.
+        unsafe {
+            let (first, second) = (first.value.cast::<TokenizerHandle>(), second.value.cast::<TokenizerHandle>());
+            assert!(Arc::ptr_eq(&(*first).read().tokenizer, &(*second).read().tokenizer));
+            crate::free_tokenizer(first);
+            crate::free_tokenizer(second);
+        }
+
+        let mut wrong = digest;
+        wrong[31] ^= 1;
+        let (result, code) = load_verified(WORDPIECE.as_bytes(), Some(wrong));
+        assert!(result.value.is_null());
+        assert_eq!(take_error(result.error), Some(format!(
+            "tokenizer SHA-256 checksum mismatch: expected {}, got {}", hex(&wrong), hex(&digest))));
+        assert_eq!(code, LOAD_ERROR_CHECKSUM_MISMATCH);
+
+        let (result, code) = load_verified(b"{}", None);
+        assert!(take_error(result.error).unwrap().starts_with("failed to load tokenizer: "));
+        assert_eq!(code, LOAD_ERROR_INVALID);
+    }
+
+    #[test]
+    fn from_file_verified_codes() {
+        let dir = temp_dir();
+        let path = dir.join("tokenizer.json");
+        std::fs::write(&path, WORDPIECE).unwrap();
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+        let digest: [u8; 32] = Sha256::digest(WORDPIECE).into();
+        let mut code = u32::MAX;
+        let result = unsafe { from_file_verified(path.as_ptr(), digest.as_ptr(), &mut code) };
+        assert_eq!((take_error(result.error), code), (None, LOAD_OK));
+        unsafe { crate::free_tokenizer(result.value.cast()) };
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        let result = unsafe { from_file_verified(path.as_ptr(), digest.as_ptr(), &mut code) };
+        assert!(take_error(result.error).unwrap().starts_with("failed to read "));
+        assert_eq!(code, LOAD_ERROR_IO);
+    }
+
+    #[test]
+    fn shared_tokenizers_of_identical_contents() {
+        // The leading spaces make the contents unique among the tests, which share the cache.
+        let json = format!("  {}", WORDPIECE);
+        let (first, second) = (TestHandle::new(&json), TestHandle::new(&json));
+        let tokenizer = |handle: &TestHandle| unsafe { (*handle.0).read() }.tokenizer.clone();
+        assert!(Arc::ptr_eq(&tokenizer(&first), &tokenizer(&second)));
+
+        // Modifying a handle copies its Tokenizer, without changing the other handle.
+        let padding = tokenizers::PaddingParams::default();
+        unsafe { (*second.0).write() }.unwrap().tokenizer_mut().with_padding(Some(padding));
+        assert!(!Arc::ptr_eq(&tokenizer(&first), &tokenizer(&second)));
+        assert!(tokenizer(&first).get_padding().is_none());
+        assert!(tokenizer(&second).get_padding().is_some());
+
+        // The contents are parsed again once no handle uses the Tokenizer.
+        let mut parsed = 0;
+        let mut parse = |bytes: &[u8]| {
+            parsed += 1;
+            Tokenizer::from_bytes(bytes).map_err(|e| err(e.to_string()))
+        };
+        shared_tokenizer(json.as_bytes(), &mut parse).unwrap();
+        drop(first);
+        shared_tokenizer(json.as_bytes(), &mut parse).unwrap();
+        assert_eq!(parsed, 1);
+    }
+}
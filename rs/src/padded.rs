@@ -0,0 +1,427 @@
+//! Padded batches: the encodings of a batch returned as rectangular row-major matrices, that can be used
+//! directly as the memory of tensors -- or written directly to the memory of the host tensors.
+
+use std::error::Error;
+use std::ffi::{c_char, c_void};
+use std::ptr::null_mut;
+use tokenizers::tokenizer::{PaddingDirection, TruncationDirection};
+use tokenizers::Encoding;
+use crate::encode::{
+    encode_batch_with_state, err, get_length, messages_from_bytes, EncodeParams, ENCODE_OUTPUT_INT32,
+    ENCODE_OUTPUT_INT64, ENCODE_RETURN_TYPE_IDS,
+};
+use crate::free_string;
+use crate::handle::{convert_to_handle_ref, TokenizerHandle, TokenizerState};
+use crate::panics::{catch_panic, catch_panic_or, error_c_string};
+
+/// Type of the values of the PaddedBatch matrices (u32, unless selected by the EncodeParams flags), or of the
+/// tensors of `encode_batch_into_tensors`.
+pub const PADDED_DTYPE_UINT32: u32 = 0;
+/// Type of the values of the PaddedBatch matrices with ENCODE_OUTPUT_INT32, or of the tensors of
+/// `encode_batch_into_tensors`.
+pub const PADDED_DTYPE_INT32: u32 = 1;
+/// Type of the values of the PaddedBatch matrices with ENCODE_OUTPUT_INT64, or of the tensors of
+/// `encode_batch_into_tensors`.
+pub const PADDED_DTYPE_INT64: u32 = 2;
+
+/// PaddedBatch holds the encodings of a batch as `[batch_size, max_len]` row-major matrices, see
+/// `encode_batch_padded`.
+///
+/// Once it is no longer used, free it with `free_padded_batch`.
+#[repr(C)]
+pub struct PaddedBatch {
+    batch_size: u64,
+    max_len: u64,
+
+    // `ids` and `attention_mask` have `batch_size * max_len` values of type `dtype` (one of the PADDED_DTYPE_*
+    // constants), and `type_ids` too if ENCODE_RETURN_TYPE_IDS is set (null otherwise).
+    dtype: u32,
+    ids: *mut c_void,
+    attention_mask: *mut c_void,
+    type_ids: *mut c_void,
+
+    // `lengths` has the number of tokens before padding of each sequence, `batch_size` values.
+    lengths: *mut u64,
+
+    error: *mut c_char,
+}
+
+// into_raw converts the values to a raw pointer, owned by the caller: null if empty.
+pub(crate) fn into_raw<T>(values: Vec<T>) -> *mut T {
+    if values.is_empty() {
+        return null_mut();
+    }
+    Box::into_raw(values.into_boxed_slice()).cast()
+}
+
+// free_raw frees the `len` values returned by `into_raw`.
+pub(crate) unsafe fn free_raw<T>(ptr: *mut T, len: usize) {
+    if !ptr.is_null() {
+        drop(unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, len)) });
+    }
+}
+
+// matrix returns the row-major matrix of the `values` of the (padded) `encodings`, of the given `dtype`.
+fn matrix(encodings: &[Encoding], size: usize, dtype: u32, values: fn(&Encoding) -> &[u32]) -> *mut c_void {
+    fn collect<T>(encodings: &[Encoding], size: usize, values: fn(&Encoding) -> &[u32], convert: fn(u32) -> T) -> *mut c_void {
+        let mut matrix = Vec::with_capacity(size);
+        for encoding in encodings {
+            matrix.extend(values(encoding).iter().map(|&value| convert(value)));
+        }
+        into_raw(matrix).cast()
+    }
+    match dtype {
+        PADDED_DTYPE_INT32 => collect(encodings, size, values, |value| value as i32),
+        PADDED_DTYPE_INT64 => collect(encodings, size, values, i64::from),
+        _ => collect(encodings, size, values, |value| value),
+    }
+}
+
+// padding_params returns the pad id, type id and token, and the padding direction configured in the tokenizer,
+// or id 0 in the padding side of the tokenizer config (see `apply_tokenizer_config`), or to the right, if none.
+fn padding_params(state: &TokenizerState) -> (u32, u32, String, PaddingDirection) {
+    match state.tokenizer.get_padding() {
+        Some(padding) => (padding.pad_id, padding.pad_type_id, padding.pad_token.clone(), padding.direction),
+        None => {
+            let direction = state.config().and_then(|config| config.padding_side());
+            (0, 0, String::new(), direction.unwrap_or(PaddingDirection::Right))
+        }
+    }
+}
+
+// check_int32 checks that the ids of the `encodings` fit i32.
+fn check_int32(encodings: &[Encoding]) -> Result<(), Box<dyn Error>> {
+    match encodings.iter().flat_map(Encoding::get_ids).find(|&&id| id > i32::MAX as u32) {
+        Some(id) => Err(err(format!("token id {} overflows i32", id))),
+        None => Ok(()),
+    }
+}
+
+fn encode_batch_padded_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_messages: usize,
+    messages: *const *const u8,
+    lengths: *const u32,
+    options: EncodeParams,
+) -> Result<PaddedBatch, Box<dyn Error>> {
+    let inputs = messages_from_bytes(num_messages, messages, lengths)?;
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    // The padding is read under the same guard as the encoding: both from the same Tokenizer.
+    let (mut encodings, (pad_id, pad_type_id, pad_token, direction)) = {
+        let state = handle.read();
+        (encode_batch_with_state(handle, &state, &inputs, &options)?, padding_params(&state))
+    };
+
+    let max_len = encodings.iter().map(|encoding| encoding.len()).max().unwrap_or(0);
+    let size = encodings.len().checked_mul(max_len)
+        .ok_or_else(|| err(format!("padded batch of {} x {} tokens overflows", encodings.len(), max_len)))?;
+    let sequence_lengths: Vec<u64> = encodings.iter().map(get_length).collect();
+    for encoding in encodings.iter_mut() {
+        encoding.pad(max_len, pad_id, pad_type_id, &pad_token, direction);
+    }
+    let dtype = if options.has(ENCODE_OUTPUT_INT32) {
+        check_int32(&encodings)?;
+        PADDED_DTYPE_INT32
+    } else if options.has(ENCODE_OUTPUT_INT64) {
+        PADDED_DTYPE_INT64
+    } else {
+        PADDED_DTYPE_UINT32
+    };
+    Ok(PaddedBatch {
+        batch_size: encodings.len() as u64,
+        max_len: max_len as u64,
+        dtype,
+        ids: matrix(&encodings, size, dtype, Encoding::get_ids),
+        attention_mask: matrix(&encodings, size, dtype, Encoding::get_attention_mask),
+        type_ids: if options.has(ENCODE_RETURN_TYPE_IDS) {
+            matrix(&encodings, size, dtype, Encoding::get_type_ids)
+        } else {
+            null_mut()
+        },
+        lengths: into_raw(sequence_lengths),
+        error: null_mut(),
+    })
+}
+
+/// encode_batch_padded encodes a batch of UTF-8 strings (given as in `encode_batch_bytes`), and returns the
+/// ids and attention masks (and type ids, if ENCODE_RETURN_TYPE_IDS is set) as `[batch_size, max_len]`
+/// row-major matrices, where `max_len` is the length of the longest encoding, along with the length of
+/// each sequence before padding. So they can be wrapped as tensors without copying each sequence.
+///
+/// The matrices hold u32 values, or i32 or i64 values with the ENCODE_OUTPUT_INT32 or ENCODE_OUTPUT_INT64
+/// flags, as used by most inference runtimes (see `PaddedBatch.dtype`).
+///
+/// The sequences are padded with the padding parameters of the tokenizer (see `set_padding`), or with id 0
+/// in the padding side of the tokenizer config (see `apply_tokenizer_config`), or to the right, if none is set.
+/// Only the `ENCODE_ADD_SPECIAL_TOKENS`, `ENCODE_RETURN_TYPE_IDS`, the special tokens flags and the output
+/// type flags of the EncodeParams are used.
+///
+/// Once it is no longer used, free it with `free_padded_batch`.
+///
+/// # Safety
+///
+/// `messages` and `lengths` must point to `num_messages` strings and lengths.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encode_batch_padded"))]
+pub unsafe extern "C" fn encode_batch_padded(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_messages: u32,
+    messages: *const *const u8,
+    lengths: *const u32,
+    options: EncodeParams,
+) -> PaddedBatch {
+    match catch_panic(|| encode_batch_padded_impl(tokenizer_ptr, num_messages as usize, messages, lengths, options)) {
+        Ok(batch) => batch,
+        Err(e) => PaddedBatch {
+            batch_size: 0,
+            max_len: 0,
+            dtype: PADDED_DTYPE_UINT32,
+            ids: null_mut(),
+            attention_mask: null_mut(),
+            type_ids: null_mut(),
+            lengths: null_mut(),
+            error: error_c_string(e),
+        },
+    }
+}
+
+/// Frees the PaddedBatch returned by `encode_batch_padded`.
+///
+/// # Safety
+///
+/// `batch` must have been returned by `encode_batch_padded`, and it must not be used after this call.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("free_padded_batch"))]
+pub unsafe extern "C" fn free_padded_batch(batch: PaddedBatch) {
+    catch_panic_or((), || {
+        free_string(batch.error);
+        let size = (batch.batch_size * batch.max_len) as usize;
+        unsafe {
+            for matrix in [batch.ids, batch.attention_mask, batch.type_ids] {
+                match batch.dtype {
+                    PADDED_DTYPE_INT64 => free_raw(matrix.cast::<i64>(), size),
+                    PADDED_DTYPE_INT32 => free_raw(matrix.cast::<i32>(), size),
+                    _ => free_raw(matrix.cast::<u32>(), size),
+                }
+            }
+            free_raw(batch.lengths, batch.batch_size as usize);
+        }
+    })
+}
+
+// write_tensor writes the `values` of the (padded) `encodings` to the host tensor at `dst`, of the given `dtype`.
+unsafe fn write_tensor(dst: *mut c_void, size: usize, dtype: u32, encodings: &[Encoding], values: fn(&Encoding) -> &[u32]) {
+    unsafe fn write<T>(dst: *mut c_void, size: usize, encodings: &[Encoding], values: fn(&Encoding) -> &[u32], convert: fn(u32) -> T) {
+        let dst = unsafe { std::slice::from_raw_parts_mut(dst.cast::<T>(), size) };
+        let values = encodings.iter().flat_map(|encoding| values(encoding).iter().copied());
+        for (dst, value) in dst.iter_mut().zip(values) {
+            *dst = convert(value);
+        }
+    }
+    unsafe {
+        match dtype {
+            PADDED_DTYPE_INT32 => write(dst, size, encodings, values, |value| value as i32),
+            PADDED_DTYPE_INT64 => write(dst, size, encodings, values, i64::from),
+            _ => write(dst, size, encodings, values, |value| value),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn encode_batch_into_tensors_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_messages: usize,
+    messages: *const *const u8,
+    lengths: *const u32,
+    options: EncodeParams,
+    ids: *mut c_void,
+    attention_mask: *mut c_void,
+    rows: u64,
+    cols: u64,
+    dtype: u32,
+    pad_id: u32,
+) -> Result<(), Box<dyn Error>> {
+    if ids.is_null() {
+        return Err(err("ids tensor is null"));
+    }
+    if !matches!(dtype, PADDED_DTYPE_UINT32 | PADDED_DTYPE_INT32 | PADDED_DTYPE_INT64) {
+        return Err(err(format!("tensor dtype {} not supported", dtype)));
+    }
+    let (rows, cols) = (usize::try_from(rows)?, usize::try_from(cols)?);
+    if num_messages > rows {
+        return Err(err(format!("{} sentences don't fit the {} rows of the tensors", num_messages, rows)));
+    }
+    let size = rows.checked_mul(cols)
+        .ok_or_else(|| err(format!("tensors of {} x {} values overflow", rows, cols)))?;
+    let inputs = messages_from_bytes(num_messages, messages, lengths)?;
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let (mut encodings, (_, pad_type_id, pad_token, direction)) = {
+        let state = handle.read();
+        (encode_batch_with_state(handle, &state, &inputs, &options)?, padding_params(&state))
+    };
+
+    // Truncate and pad every row to `cols`: the rows without a sentence are all padding.
+    encodings.resize_with(rows, Encoding::default);
+    for encoding in encodings.iter_mut() {
+        encoding.truncate(cols, 0, TruncationDirection::Right);
+        encoding.pad(cols, pad_id, pad_type_id, &pad_token, direction);
+    }
+    if dtype == PADDED_DTYPE_INT32 {
+        check_int32(&encodings)?;
+    }
+    unsafe {
+        write_tensor(ids, size, dtype, &encodings, Encoding::get_ids);
+        if !attention_mask.is_null() {
+            write_tensor(attention_mask, size, dtype, &encodings, Encoding::get_attention_mask);
+        }
+    }
+    Ok(())
+}
+
+/// encode_batch_into_tensors encodes a batch of UTF-8 strings (given as in `encode_batch_bytes`), and writes
+/// the ids and the attention masks directly to the `[rows, cols]` row-major tensors at `ids` and
+/// `attention_mask` (if not null), owned by the host -- e.g. the memory of GoMLX/XLA tensors -- so the
+/// inputs of a model are built without any intermediary copy.
+///
+/// The values of the tensors are of type `dtype`, one of the PADDED_DTYPE_* constants (the ENCODE_OUTPUT_*
+/// flags are ignored). The sequences are truncated to `cols` tokens (the overflowing tokens are dropped),
+/// and padded with `pad_id` (in the padding direction of the tokenizer, see `set_padding`, or the padding
+/// side of the tokenizer config, or to the right). There must be at most `rows` strings: the remaining rows are filled with padding.
+///
+/// It returns null if ok, or a string with an error message (owned by caller), to be freed with `free_string`.
+/// On error, the tensors are not written.
+///
+/// # Safety
+///
+/// `messages` and `lengths` must point to `num_messages` strings and lengths, and `ids` and `attention_mask`
+/// (if not null) must point to writable, aligned `rows * cols` values of type `dtype`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encode_batch_into_tensors"))]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn encode_batch_into_tensors(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_messages: u32,
+    messages: *const *const u8,
+    lengths: *const u32,
+    options: EncodeParams,
+    ids: *mut c_void,
+    attention_mask: *mut c_void,
+    rows: u64,
+    cols: u64,
+    dtype: u32,
+    pad_id: u32,
+) -> *mut c_char {
+    let result = catch_panic(|| {
+        encode_batch_into_tensors_impl(
+            tokenizer_ptr, num_messages as usize, messages, lengths, options, ids, attention_mask, rows, cols, dtype,
+            pad_id,
+        )
+    });
+    match result {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::ENCODE_PARAMS_VERSION;
+    use crate::testing::{take_error, TestHandle, WORDPIECE};
+
+    const TEXTS: [&str; 3] = ["hello world a", "ab", ""];
+
+    // encode_padded encodes the TEXTS with `encode_batch_padded` and the given flags.
+    fn encode_padded(handle: &TestHandle, flags: u64) -> PaddedBatch {
+        let messages: Vec<*const u8> = TEXTS.iter().map(|t| t.as_ptr()).collect();
+        let lengths: Vec<u32> = TEXTS.iter().map(|t| t.len() as u32).collect();
+        let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags };
+        unsafe { encode_batch_padded(handle.0, TEXTS.len() as u32, messages.as_ptr(), lengths.as_ptr(), options) }
+    }
+
+    // values returns the `len` values of type T pointed by `ptr`.
+    fn values<T: Copy>(ptr: *const c_void, len: u64) -> Vec<T> {
+        unsafe { std::slice::from_raw_parts(ptr.cast::<T>(), len as usize) }.to_vec()
+    }
+
+    #[test]
+    fn padded_batch() {
+        let handle = TestHandle::new(WORDPIECE);
+        let batch = encode_padded(&handle, ENCODE_RETURN_TYPE_IDS);
+        assert!(batch.error.is_null());
+        assert_eq!((batch.batch_size, batch.max_len, batch.dtype), (3, 3, PADDED_DTYPE_UINT32));
+        let size = batch.batch_size * batch.max_len;
+        assert_eq!(values::<u32>(batch.ids, size), [4, 6, 1, 1, 3, 0, 0, 0, 0]);
+        assert_eq!(values::<u32>(batch.attention_mask, size), [1, 1, 1, 1, 1, 0, 0, 0, 0]);
+        assert_eq!(values::<u32>(batch.type_ids, size), [0; 9]);
+        assert_eq!(values::<u64>(batch.lengths.cast(), 3), [3, 2, 0]);
+        unsafe { free_padded_batch(batch) };
+
+        // The padding parameters of the tokenizer are used: here to the left, with id 9.
+        let padding = crate::configure::padding_params(0, 0, 0, 9, 0, "im_end".to_string());
+        let h = convert_to_handle_ref(handle.0).unwrap();
+        h.write().unwrap().tokenizer_mut().with_padding(Some(padding));
+        let batch = encode_padded(&handle, 0);
+        assert_eq!(values::<u32>(batch.ids, 9), [4, 6, 1, 9, 1, 3, 9, 9, 9]);
+        assert!(batch.type_ids.is_null());
+        unsafe { free_padded_batch(batch) };
+
+        let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags: 0 };
+        let batch = unsafe { encode_batch_padded(null_mut(), 0, std::ptr::null(), std::ptr::null(), options) };
+        assert_eq!(take_error(batch.error).as_deref(), Some("tokenizer passed is null"));
+    }
+
+    #[test]
+    fn padded_output_types() {
+        let handle = TestHandle::new(WORDPIECE);
+        let batch = encode_padded(&handle, ENCODE_OUTPUT_INT32);
+        assert_eq!(batch.dtype, PADDED_DTYPE_INT32);
+        assert_eq!(values::<i32>(batch.ids, 9), [4, 6, 1, 1, 3, 0, 0, 0, 0]);
+        assert_eq!(values::<i32>(batch.attention_mask, 9), [1, 1, 1, 1, 1, 0, 0, 0, 0]);
+        unsafe { free_padded_batch(batch) };
+
+        let batch = encode_padded(&handle, ENCODE_OUTPUT_INT64 | ENCODE_RETURN_TYPE_IDS);
+        assert_eq!(batch.dtype, PADDED_DTYPE_INT64);
+        assert_eq!(values::<i64>(batch.ids, 9), [4, 6, 1, 1, 3, 0, 0, 0, 0]);
+        assert_eq!(values::<i64>(batch.type_ids, 9), [0; 9]);
+        unsafe { free_padded_batch(batch) };
+
+        let large = Encoding::from_tokens(vec![tokenizers::Token::new(u32::MAX, "x".to_string(), (0, 1))], 0);
+        assert!(check_int32(&[Encoding::default()]).is_ok());
+        assert_eq!(check_int32(&[large]).unwrap_err().to_string(), format!("token id {} overflows i32", u32::MAX));
+    }
+
+    #[test]
+    fn into_tensors() {
+        let handle = TestHandle::new(WORDPIECE);
+        let messages: Vec<*const u8> = TEXTS.iter().map(|t| t.as_ptr()).collect();
+        let lengths: Vec<u32> = TEXTS.iter().map(|t| t.len() as u32).collect();
+        let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags: 0 };
+        let encode_into = |ids: *mut c_void, mask: *mut c_void, rows: u64, cols: u64, dtype: u32| unsafe {
+            let (messages, lengths) = (messages.as_ptr(), lengths.as_ptr());
+            let error =
+                encode_batch_into_tensors(handle.0, 3, messages, lengths, options, ids, mask, rows, cols, dtype, 7);
+            take_error(error)
+        };
+
+        // The sequences are truncated to the columns, and the rows without a sentence are padding.
+        let (mut ids, mut mask) = ([-1_i64; 8], [-1_i64; 8]);
+        let error = encode_into(ids.as_mut_ptr().cast(), mask.as_mut_ptr().cast(), 4, 2, PADDED_DTYPE_INT64);
+        assert_eq!(error, None);
+        assert_eq!(ids, [4, 6, 1, 3, 7, 7, 7, 7]);
+        assert_eq!(mask, [1, 1, 1, 1, 0, 0, 0, 0]);
+
+        let mut ids = [0_u32; 9];
+        assert_eq!(encode_into(ids.as_mut_ptr().cast(), null_mut(), 3, 3, PADDED_DTYPE_UINT32), None);
+        assert_eq!(ids, [4, 6, 1, 1, 3, 7, 7, 7, 7]);
+
+        // On error, the tensors are not written.
+        let mut ids = [0_i32; 4];
+        let error = encode_into(ids.as_mut_ptr().cast(), null_mut(), 2, 2, PADDED_DTYPE_INT32);
+        assert_eq!(error.as_deref(), Some("3 sentences don't fit the 2 rows of the tensors"));
+        assert_eq!(ids, [0; 4]);
+        let error = encode_into(ids.as_mut_ptr().cast(), null_mut(), 3, 1, 3);
+        assert_eq!(error.as_deref(), Some("tensor dtype 3 not supported"));
+        assert_eq!(encode_into(null_mut(), null_mut(), 3, 1, 0).as_deref(), Some("ids tensor is null"));
+    }
+}
@@ -0,0 +1,58 @@
+//! Panics at the FFI boundary: a panic unwinding out of an `extern "C"` function is undefined behavior, that
+//! crashes the host program. The exported functions run their implementation with `catch_panic`, so a panic
+//! (e.g. a bug, or an unexpected input to the tokenizers crate) is returned as an error instead.
+
+use std::any::Any;
+use std::error::Error;
+use std::ffi::{c_char, CString};
+use std::fmt::Display;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use crate::encode::err;
+
+// panic_message returns the message of the panic with the given `payload`, as printed by the panic hook.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => payload.downcast_ref::<String>().map_or("Box<dyn Any>", String::as_str),
+    }
+}
+
+/// Runs `f`, returning its panic, if it panics, as an error.
+///
+/// The state shared with `f` is not rolled back: the handles are safe to use after a panic, since their state
+/// is never left half-updated (see `TokenizerHandle::read`).
+pub(crate) fn catch_panic<T>(f: impl FnOnce() -> Result<T, Box<dyn Error>>) -> Result<T, Box<dyn Error>> {
+    catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|payload| Err(err(format!("internal error (panic): {}", panic_message(&*payload)))))
+}
+
+/// Runs `f`, returning `default` if it panics: for the exported functions without a way to return errors.
+pub(crate) fn catch_panic_or<T>(default: T, f: impl FnOnce() -> T) -> T {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or(default)
+}
+
+/// Returns the C string with the message of the error `e`, owned by the caller (to be freed with
+/// `free_string`). NUL characters, which can't be part of a C string, are replaced by U+FFFD.
+pub(crate) fn error_c_string(e: impl Display) -> *mut c_char {
+    let message = e.to_string().replace('\0', "\u{FFFD}");
+    CString::new(message).unwrap_or_default().into_raw()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::take_error;
+
+    #[test]
+    fn panics_to_errors() {
+        let result = catch_panic::<()>(|| panic!("failed at {}", 42));
+        assert_eq!(result.unwrap_err().to_string(), "internal error (panic): failed at 42");
+        assert_eq!(catch_panic(|| Ok(1)).unwrap(), 1);
+        assert_eq!(catch_panic_or(-1, || panic!("failed")), -1);
+        assert_eq!(take_error(error_c_string("a\0b")).as_deref(), Some("a\u{FFFD}b"));
+
+        // The exported functions return their errors, e.g. for a name that is not registered.
+        let error = unsafe { crate::registry::registry_unload(c"missing".as_ptr()) };
+        assert_eq!(take_error(error).as_deref(), Some("no tokenizer registered as \"missing\""));
+    }
+}
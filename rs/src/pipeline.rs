@@ -0,0 +1,314 @@
+//! Pipeline mode: texts and their encodings flow through a ring of slots in memory shared with the host,
+//! drained continuously by a pool of Rust worker threads, so streaming tokenization takes no FFI call per text.
+
+use std::cell::UnsafeCell;
+use std::error::Error;
+use std::ffi::{c_void, CString};
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use crate::arena::write_batch;
+use crate::encode::{err, EncodeParams, EncodeResultsV2};
+use crate::handle::TokenizerHandle;
+use crate::panics::{catch_panic, catch_panic_or, error_c_string};
+use crate::PointerOrError;
+
+// Number of times an idle worker yields before sleeping between checks for new inputs.
+const IDLE_SPINS: u32 = 64;
+const IDLE_SLEEP: Duration = Duration::from_micros(50);
+
+/// PipelineSlot is one slot of the PipelineRing: the input text set by the host, and its encoding set by
+/// the workers.
+#[repr(C)]
+pub struct PipelineSlot {
+    // Set by the host: a sequence number passed through to the results, and the `len` bytes of UTF-8 `text`,
+    // which must remain valid until the slot is completed.
+    seq: u64,
+    text: *const u8,
+    len: u64,
+
+    // Set by the workers: `completed` is set (atomically, with release ordering) to the absolute index of the
+    // slot plus one once `results` are written -- the host must load it with acquire ordering. The `results`
+    // (with one Buffer, or an error) are owned by the pipeline, and valid until the slot is released.
+    completed: u64,
+    results: EncodeResultsV2,
+}
+
+/// PipelineRing is the ring of `capacity` slots shared by the host and the pipeline workers (see
+/// `pipeline_new`). `head` and `tail` are absolute indices (slot `i` is `slots[i % capacity]`), only written by
+/// the host, and read by the workers: they must be accessed atomically (with release stores), e.g. with Go's
+/// `sync/atomic`.
+///
+/// The host (a single producer and consumer) streams texts with:
+///
+/// 1. Produce: while `tail - head < capacity`, fill the `seq`, `text` and `len` of slot `tail`, and then
+///    increment `tail`.
+/// 2. Consume: once the `completed` field of slot `head` equals `head + 1`, read its `results`, and then
+///    increment `head` to release the slot (its results are no longer valid).
+///
+/// Results are consumed in the order the texts were produced, although they are encoded in parallel.
+#[repr(C)]
+pub struct PipelineRing {
+    capacity: u64,
+    slots: *mut PipelineSlot,
+    head: u64,
+    tail: u64,
+
+    // Internal state of the pipeline.
+    internal: *mut c_void,
+}
+
+// SlotState is the memory owned by the pipeline for the results of one slot, reused across rounds.
+#[derive(Default)]
+struct SlotState {
+    arena: Vec<u64>,
+    error: Option<CString>,
+}
+
+// PipelineShared is the state of the pipeline shared with the workers.
+struct PipelineShared {
+    ring: UnsafeCell<PipelineRing>,
+    slots: Box<[UnsafeCell<PipelineSlot>]>,
+    states: Box<[UnsafeCell<SlotState>]>,
+    tokenizer: Arc<TokenizerHandle>,
+    options: EncodeParams,
+
+    // `claimed` is the absolute index of the next slot to be encoded by a worker.
+    claimed: AtomicU64,
+    stop: AtomicBool,
+}
+
+// The ring fields are only written by the host, the other fields of the slots are written by a single worker
+// after it claims the slot (see `PipelineShared::work`).
+unsafe impl Send for PipelineShared {}
+unsafe impl Sync for PipelineShared {}
+
+impl PipelineShared {
+    // tail returns the index of the next slot to be filled by the host.
+    fn tail(&self) -> u64 {
+        unsafe { AtomicU64::from_ptr(&raw mut (*self.ring.get()).tail) }.load(Ordering::Acquire)
+    }
+
+    // work is the loop of each worker: it claims the filled slots and encodes them, until stopped.
+    fn work(&self) {
+        let mut idle = 0;
+        while !self.stop.load(Ordering::Relaxed) {
+            let index = self.claimed.load(Ordering::Relaxed);
+            if index >= self.tail() {
+                idle += 1;
+                if idle < IDLE_SPINS {
+                    std::thread::yield_now();
+                } else {
+                    std::thread::sleep(IDLE_SLEEP);
+                }
+                continue;
+            }
+            idle = 0;
+            if self.claimed.compare_exchange(index, index + 1, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+                self.encode_slot(index);
+            }
+        }
+    }
+
+    // encode_slot encodes the text of the slot with absolute `index`, which was claimed by the caller.
+    fn encode_slot(&self, index: u64) {
+        let position = (index % self.slots.len() as u64) as usize;
+        let slot = self.slots[position].get();
+        let state = unsafe { &mut *self.states[position].get() };
+        let (text, len) = unsafe { ((*slot).text, (*slot).len) };
+        let result = catch_panic(|| {
+            let len = u32::try_from(len).map_err(|_| err(format!("text of {} bytes is too long", len)))?;
+            let tokenizer_ptr = Arc::as_ptr(&self.tokenizer).cast_mut();
+            write_batch(tokenizer_ptr, 1, &text, &len, &self.options, &mut state.arena, 0)
+        });
+        let results = match result {
+            Ok(results) => {
+                state.error = None;
+                results
+            }
+            Err(e) => {
+                let error = state.error.insert(unsafe { CString::from_raw(error_c_string(e)) });
+                EncodeResultsV2 { len: 0, total_tokens: 0, encoded: null_mut(), error: error.as_ptr().cast_mut() }
+            }
+        };
+        unsafe {
+            (*slot).results = results;
+            AtomicU64::from_ptr(&raw mut (*slot).completed).store(index + 1, Ordering::Release);
+        }
+    }
+}
+
+// Pipeline owns the shared state and the worker threads.
+struct Pipeline {
+    shared: Arc<PipelineShared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+fn pipeline_new_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    capacity: u64,
+    num_workers: u32,
+    options: EncodeParams,
+) -> Result<*mut PipelineRing, Box<dyn Error>> {
+    if tokenizer_ptr.is_null() {
+        return Err(err("tokenizer passed is null"));
+    }
+    if capacity == 0 || num_workers == 0 {
+        return Err(err("pipeline capacity and number of workers must be positive"));
+    }
+    let capacity_usize = usize::try_from(capacity)?;
+    let tokenizer = unsafe {
+        Arc::increment_strong_count(tokenizer_ptr.cast_const());
+        Arc::from_raw(tokenizer_ptr.cast_const())
+    };
+    let slots: Box<[UnsafeCell<PipelineSlot>]> = (0..capacity_usize)
+        .map(|_| UnsafeCell::new(PipelineSlot {
+            seq: 0,
+            text: std::ptr::null(),
+            len: 0,
+            completed: 0,
+            results: EncodeResultsV2 { len: 0, total_tokens: 0, encoded: null_mut(), error: null_mut() },
+        }))
+        .collect();
+    let shared = Arc::new(PipelineShared {
+        ring: UnsafeCell::new(PipelineRing {
+            capacity,
+            slots: slots.as_ptr().cast_mut().cast(),
+            head: 0,
+            tail: 0,
+            internal: null_mut(),
+        }),
+        slots,
+        states: (0..capacity_usize).map(|_| UnsafeCell::new(SlotState::default())).collect(),
+        tokenizer,
+        options,
+        claimed: AtomicU64::new(0),
+        stop: AtomicBool::new(false),
+    });
+    let workers = (0..num_workers)
+        .map(|i| {
+            let shared = shared.clone();
+            std::thread::Builder::new()
+                .name(format!("tokenizers-pipeline-{}", i))
+                .spawn(move || shared.work())
+        })
+        .collect::<Result<Vec<_>, _>>();
+    let workers = match workers {
+        Ok(workers) => workers,
+        Err(e) => {
+            shared.stop.store(true, Ordering::Relaxed);
+            return Err(err(format!("failed to start pipeline workers: {}", e)));
+        }
+    };
+    let ring = shared.ring.get();
+    let pipeline = Box::new(Pipeline { shared, workers });
+    unsafe { (*ring).internal = Box::into_raw(pipeline).cast() };
+    Ok(ring)
+}
+
+/// pipeline_new starts a pipeline encoding the texts streamed through a ring of `capacity` slots (see
+/// `PipelineRing`) with `num_workers` threads, using the tokenizer and the given EncodeParams. It returns the
+/// `PipelineRing *` in the `value` field, or an error.
+///
+/// The pipeline holds a reference to the tokenizer (see `tokenizer_retain`), so it can be freed independently.
+/// Stop the pipeline and free it with `pipeline_free`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("pipeline_new"))]
+pub unsafe extern "C" fn pipeline_new(
+    tokenizer_ptr: *mut TokenizerHandle,
+    capacity: u64,
+    num_workers: u32,
+    options: EncodeParams,
+) -> PointerOrError {
+    match catch_panic(|| pipeline_new_impl(tokenizer_ptr, capacity, num_workers, options)) {
+        Ok(ring) => PointerOrError { value: ring.cast(), error: null_mut() },
+        Err(e) => PointerOrError { value: null_mut(), error: error_c_string(e) },
+    }
+}
+
+/// pipeline_free stops the workers of the pipeline (waiting for the texts being encoded), and frees it with
+/// all the results of its slots.
+///
+/// # Safety
+///
+/// `ring` must have been returned by `pipeline_new`, and it must not be used after this call.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("pipeline_free"))]
+pub unsafe extern "C" fn pipeline_free(ring: *mut PipelineRing) {
+    catch_panic_or((), || {
+        if ring.is_null() {
+            return;
+        }
+        let pipeline = unsafe { Box::from_raw((*ring).internal.cast::<Pipeline>()) };
+        pipeline.shared.stop.store(true, Ordering::Relaxed);
+        for worker in pipeline.workers {
+            let _ = worker.join();
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::ENCODE_PARAMS_VERSION;
+    use crate::testing::{take_error, TestHandle, WORDPIECE};
+
+    #[test]
+    fn pipeline_streaming() {
+        let handle = TestHandle::new(WORDPIECE);
+        let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags: 0 };
+        let result = unsafe { pipeline_new(handle.0, 2, 3, options) };
+        assert!(result.error.is_null());
+        let ring = result.value.cast::<PipelineRing>();
+        let head = unsafe { AtomicU64::from_ptr(&raw mut (*ring).head) };
+        let tail = unsafe { AtomicU64::from_ptr(&raw mut (*ring).tail) };
+        let capacity = unsafe { (*ring).capacity };
+        let slot = |index: u64| unsafe { (*ring).slots.add((index % capacity) as usize) };
+        let completed = |index: u64| unsafe { AtomicU64::from_ptr(&raw mut (*slot(index)).completed) };
+
+        let texts = ["hello", "world", "a b", "hello hello world", "\u{FFFD}", "ab"];
+        let mut produced = 0;
+        let mut consumed: Vec<(u64, Vec<u32>)> = Vec::new();
+        while consumed.len() < texts.len() {
+            let (h, t) = (head.load(Ordering::Acquire), tail.load(Ordering::Acquire));
+            if produced < texts.len() && t - h < capacity {
+                let text = texts[produced];
+                unsafe {
+                    (*slot(t)).seq = 100 + produced as u64;
+                    (*slot(t)).text = text.as_ptr();
+                    (*slot(t)).len = text.len() as u64;
+                }
+                produced += 1;
+                tail.store(t + 1, Ordering::Release);
+            } else if completed(h).load(Ordering::Acquire) == h + 1 {
+                let (seq, results) = unsafe { ((*slot(h)).seq, &(*slot(h)).results) };
+                assert!(results.error.is_null());
+                let buf = unsafe { &*results.encoded };
+                consumed.push((seq, unsafe { std::slice::from_raw_parts(buf.ids, buf.len as usize) }.to_vec()));
+                head.store(h + 1, Ordering::Release);
+            } else {
+                std::thread::yield_now();
+            }
+        }
+        assert_eq!(
+            consumed,
+            [
+                (100, vec![4]),
+                (101, vec![6]),
+                (102, vec![1, 2]),
+                (103, vec![4, 4, 6]),
+                (104, vec![0]),
+                (105, vec![1, 3]),
+            ]);
+        unsafe { pipeline_free(ring) };
+
+        let result = unsafe { pipeline_new(handle.0, 0, 1, options) };
+        assert_eq!(
+            take_error(result.error).as_deref(),
+            Some("pipeline capacity and number of workers must be positive"));
+        let result = unsafe { pipeline_new(null_mut(), 1, 1, options) };
+        assert_eq!(take_error(result.error).as_deref(), Some("tokenizer passed is null"));
+    }
+}
@@ -0,0 +1,201 @@
+//! Mapping of the token offsets to (line, column) positions in the original text, for tooling of code models
+//! that annotates editors.
+
+use std::error::Error;
+use std::ffi::c_char;
+use std::ptr::null_mut;
+use crate::encode::{err, Buffer, EncodeParams, ENCODE_WITH_OFFSETS_CHAR_MODE, ENCODE_WITH_OFFSETS_UTF16_MODE};
+use crate::panics::{catch_panic, catch_panic_or, error_c_string};
+
+/// LineColumnSpan is the span of a token in the text as (line, column) positions, all 0-based: the end is
+/// exclusive, as the offsets. See `token_line_columns`.
+#[repr(C)]
+pub struct LineColumnSpan {
+    start_line: u32,
+    start_column: u32,
+    end_line: u32,
+    end_column: u32,
+}
+
+/// TokenLineColumns is the result of `token_line_columns`: one LineColumnSpan per token of the Buffer.
+///
+/// Either the `len` `spans` or `error` will be defined. Once it is no longer used, free it with
+/// `free_token_line_columns`.
+#[repr(C)]
+pub struct TokenLineColumns {
+    spans: *mut LineColumnSpan,
+    len: u64,
+    error: *mut c_char,
+}
+
+// Boundary is the position of a character boundary of the text.
+struct Boundary {
+    offset: usize,  // In the units of the offsets.
+    line: u32,
+    column: u32,
+}
+
+// boundaries returns the positions of all the character boundaries of the `text`, sorted by offset. Offsets
+// are counted with `unit_len` for each character, and columns in the same units, except that tabs advance to
+// the next multiple of `tab_width` (if not 0). "\r\n", "\n" and "\r" are line breaks.
+fn boundaries(text: &str, unit_len: impl Fn(char) -> usize, tab_width: u32) -> Result<Vec<Boundary>, Box<dyn Error>> {
+    let overflow = || err("text too long for u32 line/column positions");
+    let mut boundaries = Vec::with_capacity(text.len() + 1);
+    let (mut offset, mut line, mut column) = (0, 0u32, 0u32);
+    let mut chars = text.chars().peekable();
+    boundaries.push(Boundary { offset, line, column });
+    while let Some(c) = chars.next() {
+        offset += unit_len(c);
+        match c {
+            '\r' if chars.peek() == Some(&'\n') => column = column.checked_add(1).ok_or_else(overflow)?,
+            '\n' | '\r' => {
+                line = line.checked_add(1).ok_or_else(overflow)?;
+                column = 0;
+            }
+            '\t' if tab_width > 0 => column = (column / tab_width + 1).checked_mul(tab_width).ok_or_else(overflow)?,
+            _ => column = u32::try_from(unit_len(c)).ok().and_then(|len| column.checked_add(len)).ok_or_else(overflow)?,
+        }
+        boundaries.push(Boundary { offset, line, column });
+    }
+    Ok(boundaries)
+}
+
+// position returns the (line, column) of the `offset`: offsets inside a character (or past the end of the
+// text) are rounded down to the previous boundary.
+fn position(boundaries: &[Boundary], offset: usize) -> (u32, u32) {
+    let index = boundaries.partition_point(|boundary| boundary.offset <= offset).max(1) - 1;
+    (boundaries[index].line, boundaries[index].column)
+}
+
+fn token_line_columns_impl(
+    text: &[u8],
+    buffer: *const Buffer,
+    options: &EncodeParams,
+    tab_width: u32,
+) -> Result<Vec<LineColumnSpan>, Box<dyn Error>> {
+    options.validate()?;
+    let text = std::str::from_utf8(text)?;
+    let buffer = unsafe { buffer.as_ref() }.ok_or_else(|| err("buffer is null"))?;
+    if buffer.offsets.is_null() {
+        return Err(err("buffer has no offsets, encode with ENCODE_RETURN_OFFSETS"));
+    }
+    let offsets = unsafe { std::slice::from_raw_parts(buffer.offsets, buffer.len as usize) };
+    let boundaries = if options.has(ENCODE_WITH_OFFSETS_UTF16_MODE) {
+        boundaries(text, char::len_utf16, tab_width)?
+    } else if options.has(ENCODE_WITH_OFFSETS_CHAR_MODE) {
+        boundaries(text, |_| 1, tab_width)?
+    } else {
+        boundaries(text, char::len_utf8, tab_width)?
+    };
+    Ok(offsets
+        .iter()
+        .map(|offset| {
+            let (start_line, start_column) = position(&boundaries, offset.start as usize);
+            let (end_line, end_column) = position(&boundaries, offset.end as usize);
+            LineColumnSpan { start_line, start_column, end_line, end_column }
+        })
+        .collect())
+}
+
+/// token_line_columns returns the (line, column) start and end of each token of the `buffer` (an encoding
+/// returned by any of the encode functions, with ENCODE_RETURN_OFFSETS) in the `len` bytes of the UTF-8 `text`
+/// it was encoded from. The `options` must have the same offsets mode flags used to encode it, and the
+/// columns are counted in the same units as the offsets (bytes, characters or UTF-16 code units, as used by
+/// LSP), except that tabs advance to the next multiple of `tab_width` columns, if it is not 0.
+///
+/// Lines and columns are 0-based. "\r\n", "\n" and "\r" are line breaks: a token ending right after a line
+/// break ends at column 0 of the next line. The overflowing windows of a Buffer have offsets into the same
+/// text, so they can be mapped with the same `text`.
+///
+/// The result must be freed with `free_token_line_columns`.
+///
+/// # Safety
+///
+/// `text` must point to `len` bytes, and `buffer` to a Buffer returned by Rust that was not freed yet.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("token_line_columns"))]
+pub unsafe extern "C" fn token_line_columns(
+    text: *const u8,
+    len: u32,
+    buffer: *const Buffer,
+    options: EncodeParams,
+    tab_width: u32,
+) -> TokenLineColumns {
+    let text = if len == 0 { &[][..] } else { unsafe { std::slice::from_raw_parts(text, len as usize) } };
+    match catch_panic(|| token_line_columns_impl(text, buffer, &options, tab_width)) {
+        Ok(spans) => {
+            let mut spans = spans.into_boxed_slice();
+            let result = TokenLineColumns { spans: spans.as_mut_ptr(), len: spans.len() as u64, error: null_mut() };
+            std::mem::forget(spans);
+            result
+        }
+        Err(e) => TokenLineColumns {
+            spans: null_mut(),
+            len: 0,
+            error: error_c_string(e),
+        },
+    }
+}
+
+/// Frees the TokenLineColumns returned by `token_line_columns`.
+///
+/// # Safety
+///
+/// `result` must have been returned by `token_line_columns`, and it must not be used after this call.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("free_token_line_columns"))]
+pub unsafe extern "C" fn free_token_line_columns(result: TokenLineColumns) {
+    catch_panic_or((), || {
+        crate::free_string(result.error);
+        if !result.spans.is_null() {
+            unsafe {
+                drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(result.spans, result.len as usize)));
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{encode_str_impl, free_buffer, ENCODE_PARAMS_VERSION, ENCODE_RETURN_OFFSETS};
+    use crate::testing::{take_error, TestHandle, WORDPIECE};
+
+    // line_columns encodes the `text` with the `flags` and returns the (line, column) spans of its tokens.
+    fn line_columns(handle: &TestHandle, text: &str, flags: u64, tab_width: u32) -> Vec<(u32, u32, u32, u32)> {
+        let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags };
+        let buffers = encode_str_impl(handle.0, text, options).unwrap();
+        let result = unsafe { token_line_columns(text.as_ptr(), text.len() as u32, &buffers[0], options, tab_width) };
+        assert!(result.error.is_null());
+        let spans = unsafe { std::slice::from_raw_parts(result.spans, result.len as usize) };
+        let spans = spans.iter().map(|s| (s.start_line, s.start_column, s.end_line, s.end_column)).collect();
+        unsafe { free_token_line_columns(result) };
+        buffers.into_iter().for_each(free_buffer);
+        spans
+    }
+
+    #[test]
+    fn line_column_spans() {
+        let handle = TestHandle::new(WORDPIECE);
+        let text = "hello\n\ta\r\nworld é";
+        assert_eq!(
+            line_columns(&handle, text, ENCODE_RETURN_OFFSETS, 4),
+            [(0, 0, 0, 5), (1, 4, 1, 5), (2, 0, 2, 5), (2, 6, 2, 8)]);
+        assert_eq!(line_columns(&handle, text, ENCODE_RETURN_OFFSETS, 0)[1], (1, 1, 1, 2));
+        let flags = ENCODE_RETURN_OFFSETS | ENCODE_WITH_OFFSETS_CHAR_MODE;
+        assert_eq!(line_columns(&handle, text, flags, 4)[3], (2, 6, 2, 7));
+
+        // Offsets inside a character are rounded down, and a break right before the end moves it to the next line.
+        let bounds = boundaries("é\n", char::len_utf8, 0).unwrap();
+        assert_eq!([position(&bounds, 1), position(&bounds, 3), position(&bounds, 9)], [(0, 0), (1, 0), (1, 0)]);
+
+        let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags: 0 };
+        let buffers = encode_str_impl(handle.0, text, options).unwrap();
+        let result = unsafe { token_line_columns(text.as_ptr(), text.len() as u32, &buffers[0], options, 0) };
+        assert!(result.spans.is_null());
+        assert_eq!(
+            take_error(result.error).as_deref(),
+            Some("buffer has no offsets, encode with ENCODE_RETURN_OFFSETS"));
+        buffers.into_iter().for_each(free_buffer);
+    }
+}
@@ -0,0 +1,295 @@
+//! Profiling of the encoding pipeline: when enabled with `set_profiling`, the batch calls encode each text
+//! phase by phase (as the tokenizers crate does), timing normalization, pre-tokenization, the model and
+//! post-processing. The timings are aggregated in histograms, retrieved with `get_profile`.
+
+use std::cell::Cell;
+use std::ffi::c_char;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+use tokenizers::parallelism::MaybeParallelIterator;
+use tokenizers::tokenizer::{pad_encodings, EncodeInput, Encoding, Model, OffsetType, PreTokenizer, Result, Tokenizer};
+use crate::encode::err;
+use crate::limits::TokenBudgetExceeded;
+use crate::panics::{catch_panic, catch_panic_or, error_c_string};
+
+/// Number of buckets of the PhaseProfile histograms: bucket `i` counts the calls that took from `2^i`
+/// (inclusive) to `2^(i+1)` (exclusive) nanoseconds -- bucket 0 also counts the calls under 1 nanosecond, and
+/// the last bucket all the longer calls.
+pub const PROFILE_NUM_BUCKETS: usize = 40;
+
+/// PhaseProfile aggregates the durations of one phase of the encoding, one per text encoded.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PhaseProfile {
+    count: u64,
+    total_ns: u64,
+    max_ns: u64,
+    buckets: [u64; PROFILE_NUM_BUCKETS],
+}
+
+/// Profile holds the histograms of the durations of each phase of the encoding, see `get_profile`.
+#[repr(C)]
+pub struct Profile {
+    /// Normalization, including the extraction of the added tokens.
+    normalize: PhaseProfile,
+    pre_tokenize: PhaseProfile,
+    /// Tokenization of the pre-tokenized words by the model.
+    model: PhaseProfile,
+    /// Truncation and post-processing (e.g. adding special tokens): the padding to the longest text of the
+    /// batch is not included.
+    post_process: PhaseProfile,
+}
+
+// PhaseCounters aggregates the durations of one phase, shared by the threads encoding.
+struct PhaseCounters {
+    count: AtomicU64,
+    total_ns: AtomicU64,
+    max_ns: AtomicU64,
+    buckets: [AtomicU64; PROFILE_NUM_BUCKETS],
+}
+
+impl PhaseCounters {
+    const fn new() -> Self {
+        PhaseCounters {
+            count: AtomicU64::new(0),
+            total_ns: AtomicU64::new(0),
+            max_ns: AtomicU64::new(0),
+            buckets: [const { AtomicU64::new(0) }; PROFILE_NUM_BUCKETS],
+        }
+    }
+
+    // record adds the duration since `start` to the counters, and returns the current instant, the start of
+    // the next phase.
+    fn record(&self, start: Instant) -> Instant {
+        let now = Instant::now();
+        let ns = u64::try_from((now - start).as_nanos()).unwrap_or(u64::MAX);
+        let bucket = (ns.max(1).ilog2() as usize).min(PROFILE_NUM_BUCKETS - 1);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_ns.fetch_add(ns, Ordering::Relaxed);
+        self.max_ns.fetch_max(ns, Ordering::Relaxed);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        now
+    }
+
+    fn snapshot(&self) -> PhaseProfile {
+        PhaseProfile {
+            count: self.count.load(Ordering::Relaxed),
+            total_ns: self.total_ns.load(Ordering::Relaxed),
+            max_ns: self.max_ns.load(Ordering::Relaxed),
+            buckets: std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed)),
+        }
+    }
+
+    fn reset(&self) {
+        self.count.store(0, Ordering::Relaxed);
+        self.total_ns.store(0, Ordering::Relaxed);
+        self.max_ns.store(0, Ordering::Relaxed);
+        self.buckets.iter().for_each(|bucket| bucket.store(0, Ordering::Relaxed));
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NORMALIZE: PhaseCounters = PhaseCounters::new();
+static PRE_TOKENIZE: PhaseCounters = PhaseCounters::new();
+static MODEL: PhaseCounters = PhaseCounters::new();
+static POST_PROCESS: PhaseCounters = PhaseCounters::new();
+
+// enabled returns whether the encoding should be profiled.
+pub(crate) fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// encode_sequence runs the phases of the encoding of one text before the post-processing (normalization,
+// pre-tokenization and the model), recording the duration of each if profiling is enabled. If `normalize` is
+// false, the normalizer of the tokenizer is skipped (see ENCODE_SKIP_NORMALIZATION). The tokens get the
+// `type_id`: 0 for a single text or the first text of a pair, 1 for the second.
+pub(crate) fn encode_sequence(
+    tokenizer: &Tokenizer,
+    text: &str,
+    type_id: u32,
+    offsets_type: OffsetType,
+    normalize: bool,
+) -> Result<Encoding> {
+    encode_sequence_within(tokenizer, text, type_id, offsets_type, normalize, usize::MAX)
+}
+
+// encode_sequence_within is `encode_sequence`, but it fails with TokenBudgetExceeded as soon as the text produces
+// more than `max_tokens` tokens: the model is stopped at the first word that exceeds it.
+pub(crate) fn encode_sequence_within(
+    tokenizer: &Tokenizer,
+    text: &str,
+    type_id: u32,
+    offsets_type: OffsetType,
+    normalize: bool,
+    max_tokens: usize,
+) -> Result<Encoding> {
+    let profiling = enabled();
+    let record = |counters: &PhaseCounters, start| if profiling { counters.record(start) } else { start };
+    let start = Instant::now();
+    let mut pre_tokenized = tokenizer
+        .get_added_vocabulary()
+        .extract_and_normalize(tokenizer.get_normalizer().filter(|_| normalize), text);
+    let start = record(&NORMALIZE, start);
+    if let Some(pre_tokenizer) = tokenizer.get_pre_tokenizer() {
+        pre_tokenizer.pre_tokenize(&mut pre_tokenized)?;
+    }
+    let start = record(&PRE_TOKENIZE, start);
+    let num_tokens = Cell::new(0);
+    pre_tokenized.tokenize(|normalized| {
+        let tokens = tokenizer.get_model().tokenize(normalized.get())?;
+        num_tokens.set(num_tokens.get() + tokens.len());
+        if num_tokens.get() > max_tokens {
+            return Err(Box::new(TokenBudgetExceeded { max_tokens, num_tokens: num_tokens.get() }));
+        }
+        Ok(tokens)
+    })?;
+    let encoding = pre_tokenized.into_encoding(None, type_id, offsets_type)?;
+    record(&MODEL, start);
+    // The added tokens found in the text are not produced by the model.
+    if encoding.len() > max_tokens {
+        return Err(Box::new(TokenBudgetExceeded { max_tokens, num_tokens: encoding.len() }));
+    }
+    Ok(encoding)
+}
+
+// encode encodes one text, or the pair of texts if `pair` is given, phase by phase (see `encode_sequence`),
+// recording the duration of each if profiling is enabled: it returns the same Encoding as `Tokenizer::encode`
+// (or `encode_char_offsets`, depending on `offsets_type`), except that the normalizer is skipped if `normalize`
+// is false. It fails with TokenBudgetExceeded if the texts produce more than `max_tokens` tokens (not counting
+// the special tokens added).
+pub(crate) fn encode(
+    tokenizer: &Tokenizer,
+    text: &str,
+    pair: Option<&str>,
+    add_special_tokens: bool,
+    offsets_type: OffsetType,
+    normalize: bool,
+    max_tokens: usize,
+) -> Result<Encoding> {
+    let encoding = encode_sequence_within(tokenizer, text, 0, offsets_type, normalize, max_tokens)?;
+    let pair_encoding = pair
+        .map(|pair| {
+            encode_sequence_within(tokenizer, pair, 1, offsets_type, normalize, max_tokens - encoding.len())
+                .map_err(|e| match e.downcast::<TokenBudgetExceeded>() {
+                    Ok(exceeded) => Box::new(TokenBudgetExceeded {
+                        max_tokens,
+                        num_tokens: encoding.len() + exceeded.num_tokens,
+                    }),
+                    Err(e) => e,
+                })
+        })
+        .transpose()?;
+    let start = Instant::now();
+    let encoding = tokenizer.post_process(encoding, pair_encoding, add_special_tokens)?;
+    if enabled() {
+        POST_PROCESS.record(start);
+    }
+    Ok(encoding)
+}
+
+// encode_batch is the instrumented version of `Tokenizer::encode_batch` (or `encode_batch_char_offsets`): each
+// input, a text with the optional second text of a pair, is profiled (if enabled) and checked by the slow-input
+// hook (see `trace::check`). If `normalize` is false, the normalizer is skipped, and if an input produces more
+// than `max_tokens` tokens, it fails with TokenBudgetExceeded.
+pub(crate) fn encode_batch(
+    tokenizer: &Tokenizer,
+    inputs: &[(&str, Option<&str>)],
+    add_special_tokens: bool,
+    offsets_type: OffsetType,
+    normalize: bool,
+    max_tokens: usize,
+) -> Result<Vec<Encoding>> {
+    let profiling = enabled();
+    let mut encodings = inputs
+        .to_vec()
+        .into_maybe_par_iter()
+        .map(|(text, pair)| {
+            let start = Instant::now();
+            let input: EncodeInput = match pair {
+                Some(pair) => (text, pair).into(),
+                None => text.into(),
+            };
+            let encoding = if profiling || !normalize || max_tokens < usize::MAX {
+                encode(tokenizer, text, pair, add_special_tokens, offsets_type, normalize, max_tokens)?
+            } else if matches!(offsets_type, OffsetType::Char) {
+                tokenizer.encode_char_offsets(input, add_special_tokens)?
+            } else {
+                tokenizer.encode(input, add_special_tokens)?
+            };
+            crate::trace::check(text, start, &encoding);
+            Ok(encoding)
+        })
+        .collect::<Result<Vec<Encoding>>>()?;
+    if let Some(params) = tokenizer.get_padding() {
+        pad_encodings(&mut encodings, params)?;
+    }
+    Ok(encodings)
+}
+
+/// set_profiling enables (or disables) the profiling of the encoding by the encode calls, see `get_profile`.
+///
+/// Profiling adds the overhead of timing each phase of each text: it is meant for diagnostics.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("set_profiling"))]
+pub extern "C" fn set_profiling(enabled: bool) {
+    catch_panic_or((), || ENABLED.store(enabled, Ordering::Relaxed))
+}
+
+/// get_profile writes to `profile` the histograms of the durations of each phase of the encoding, aggregated
+/// over all the texts encoded (by all tokenizers) while profiling was enabled, since the last `reset_profile`.
+///
+/// It returns null if ok, or a string with an error message (owned by caller), to be freed with `free_string`.
+///
+/// # Safety
+///
+/// `profile` must point to a writable Profile.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("get_profile"))]
+pub unsafe extern "C" fn get_profile(profile: *mut Profile) -> *mut c_char {
+    let result = catch_panic(|| {
+        if profile.is_null() {
+            return Err(err("profile is null"));
+        }
+        unsafe {
+            profile.write(Profile {
+                normalize: NORMALIZE.snapshot(),
+                pre_tokenize: PRE_TOKENIZE.snapshot(),
+                model: MODEL.snapshot(),
+                post_process: POST_PROCESS.snapshot(),
+            });
+        }
+        Ok(())
+    });
+    match result {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+/// reset_profile clears the histograms returned by `get_profile`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("reset_profile"))]
+pub extern "C" fn reset_profile() {
+    catch_panic_or((), || {
+        for counters in [&NORMALIZE, &PRE_TOKENIZE, &MODEL, &POST_PROCESS] {
+            counters.reset();
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{encode_ids, TestHandle, WORDPIECE};
+
+    #[test]
+    fn profile_single_encode() {
+        let handle = TestHandle::new(WORDPIECE);
+        set_profiling(true);
+        let before = MODEL.snapshot().count;
+        encode_ids(&handle, "hello world", 0);
+        set_profiling(false);
+        assert!(MODEL.snapshot().count > before);
+    }
+}
@@ -0,0 +1,411 @@
+//! Prompts with placeholder tokens: the sentinels of T5 (`<extra_id_0>`, ...) and the fill-in-the-middle markers
+//! of code models (`<fim_prefix>`, ...), written as text in the prompt and mapped to their token ids.
+
+use std::error::Error;
+use std::ffi::{c_char, CStr};
+use regex::Regex;
+use tokenizers::tokenizer::{OffsetType, Tokenizer};
+use tokenizers::Encoding;
+use crate::compat::template_special_tokens;
+use crate::document::special_tokens;
+use crate::encode::{
+    encode_process, err, result_to_encode_results_v2, tokenizer_for, Buffer, EncodeParams, EncodeResultsV2,
+    ENCODE_ADD_SPECIAL_TOKENS, ENCODE_RETURN_DEBUG_INFO, ENCODE_RETURN_WORD_STARTS, ENCODE_SKIP_NORMALIZATION,
+    ENCODE_WITH_OFFSETS_CHAR_MODE,
+};
+use crate::handle::{convert_to_handle_ref, TokenizerHandle};
+use crate::panics::catch_panic;
+use crate::words::WordStarts;
+
+// DEFAULT_PLACEHOLDER_PATTERN matches the T5 sentinels, and the FIM markers in both the StarCoder (`<fim_prefix>`)
+// and the Qwen / CodeGemma (`<|fim_prefix|>`) forms.
+const DEFAULT_PLACEHOLDER_PATTERN: &str = r"<extra_id_\d+>|<\|?fim_(?:prefix|middle|suffix|pad)\|?>";
+
+// Prompt accumulates the tokens of the pieces of text of a prompt, and of the placeholders in between them,
+// before the post-processing.
+#[derive(Default)]
+struct Prompt {
+    ids: Vec<u32>,
+    tokens: Vec<String>,
+    words: Vec<Option<u32>>,
+    offsets: Vec<(usize, usize)>,
+    special_tokens_mask: Vec<u32>,
+    num_words: u32,
+}
+
+impl Prompt {
+    // push_encoding appends the tokens of the `encoding` of the piece of text starting at `offset`.
+    fn push_encoding(&mut self, encoding: &Encoding, offset: usize) {
+        let num_words = self.num_words;
+        self.ids.extend_from_slice(encoding.get_ids());
+        self.tokens.extend_from_slice(encoding.get_tokens());
+        self.words.extend(encoding.get_word_ids().iter().map(|word| word.map(|word| word + num_words)));
+        self.offsets.extend(encoding.get_offsets().iter().map(|(start, end)| (start + offset, end + offset)));
+        self.special_tokens_mask.extend_from_slice(encoding.get_special_tokens_mask());
+        self.num_words += encoding.get_word_ids().iter().flatten().max().map_or(0, |&max| max + 1);
+    }
+
+    // push_placeholder appends the placeholder `token`, at the `span` of the text: it is a special token.
+    fn push_placeholder(&mut self, id: u32, token: &str, span: (usize, usize)) {
+        self.ids.push(id);
+        self.tokens.push(token.to_string());
+        self.words.push(None);
+        self.offsets.push(span);
+        self.special_tokens_mask.push(1);
+    }
+
+    fn into_encoding(self) -> Encoding {
+        let len = self.ids.len();
+        Encoding::new(
+            self.ids,
+            vec![0; len],
+            self.tokens,
+            self.words,
+            self.offsets,
+            self.special_tokens_mask,
+            vec![1; len],
+            Vec::new(),
+            Default::default(),
+        )
+    }
+}
+
+// placeholder_regex returns the regex of the placeholders: the `pattern`, or DEFAULT_PLACEHOLDER_PATTERN if null.
+fn placeholder_regex(pattern: *const c_char) -> Result<Regex, Box<dyn Error>> {
+    let pattern = if pattern.is_null() {
+        DEFAULT_PLACEHOLDER_PATTERN
+    } else {
+        unsafe { CStr::from_ptr(pattern) }.to_str()?
+    };
+    Regex::new(pattern).map_err(|e| err(format!("invalid placeholder pattern: {}", e)))
+}
+
+// encode_placeholders returns the encoding (before the post-processing) of the `text`, with the placeholders
+// matched by `regex` mapped to their token ids. It is an error if a placeholder is not a token of the tokenizer.
+pub(crate) fn encode_placeholders(
+    tokenizer: &Tokenizer,
+    text: &str,
+    regex: &Regex,
+    offsets_type: OffsetType,
+    normalize: bool,
+) -> Result<Encoding, Box<dyn Error>> {
+    // The offsets of the pieces of text are shifted by their position in the `text`, in the units of the offsets.
+    let position = |byte: usize| match offsets_type {
+        OffsetType::Char => text[..byte].chars().count(),
+        _ => byte,
+    };
+    let mut prompt = Prompt::default();
+    let push_text = |prompt: &mut Prompt, start: usize, end: usize| -> Result<(), Box<dyn Error>> {
+        if start < end {
+            let encoding = crate::profile::encode_sequence(tokenizer, &text[start..end], 0, offsets_type, normalize)
+                .map_err(|e| err(format!("encoding failed: {}", e)))?;
+            prompt.push_encoding(&encoding, position(start));
+        }
+        Ok(())
+    };
+    let mut last = 0;
+    for placeholder in regex.find_iter(text).filter(|placeholder| !placeholder.is_empty()) {
+        let id = tokenizer.token_to_id(placeholder.as_str()).ok_or_else(|| {
+            err(format!(
+                "placeholder {:?} at byte {} is not a token of the tokenizer",
+                placeholder.as_str(),
+                placeholder.start()
+            ))
+        })?;
+        push_text(&mut prompt, last, placeholder.start())?;
+        prompt.push_placeholder(id, placeholder.as_str(), (position(placeholder.start()), position(placeholder.end())));
+        last = placeholder.end();
+    }
+    push_text(&mut prompt, last, text.len())?;
+    Ok(prompt.into_encoding())
+}
+
+fn encode_with_placeholders_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    text: &[u8],
+    pattern: *const c_char,
+    options: EncodeParams,
+) -> Result<Vec<Buffer>, Box<dyn Error>> {
+    options.validate()?;
+    let text = std::str::from_utf8(text)?;
+    let regex = placeholder_regex(pattern)?;
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    state.encode_limits().check(std::iter::once(text.len()))?;
+    let tokenizer = tokenizer_for(&state, &options);
+    let offsets_type = if options.has(ENCODE_WITH_OFFSETS_CHAR_MODE) { OffsetType::Char } else { OffsetType::Byte };
+    let encoding = encode_placeholders(tokenizer, text, &regex, offsets_type, !options.has(ENCODE_SKIP_NORMALIZATION))?;
+    let encoding = tokenizer
+        .post_process(encoding, None, options.has(ENCODE_ADD_SPECIAL_TOKENS))
+        .map_err(|e| err(format!("encoding failed: {}", e)))?;
+    if let Some(stats) = state.sequence_stats() {
+        stats.record(std::slice::from_ref(&encoding));
+    }
+    let scores = options.has(ENCODE_RETURN_DEBUG_INFO).then(|| state.token_scores());
+    let word_starts = options.has(ENCODE_RETURN_WORD_STARTS).then(|| WordStarts::new(tokenizer));
+    Ok(vec![encode_process(encoding, &[text], &options, scores.as_deref(), word_starts.as_ref())?])
+}
+
+/// Encodes the `len` bytes of UTF-8 `text` with placeholder markers, using given tokenizer and EncodeParams, and
+/// returns an EncodeResultsV2 with one Buffer.
+///
+/// The placeholders are the substrings matched by the `pattern` regex, or if null by the default pattern, which
+/// matches the T5 sentinels (`<extra_id_0>`, `<extra_id_1>`, ...) and the fill-in-the-middle markers
+/// (`<fim_prefix>`, `<fim_middle>`, `<fim_suffix>`, `<fim_pad>`, and the same in the `<|fim_prefix|>` form).
+/// Each placeholder is mapped to the id of the token with the same content, as a special token, and the text in
+/// between them is encoded as usual. Unlike `encode`, the placeholders are mapped even if they are not added
+/// tokens of the tokenizer, or if the special tokens are encoded as text (see `set_encode_special_tokens`).
+///
+/// It is an error if a placeholder is not a token of the tokenizer: e.g. `<fim_prefix>` with a tokenizer
+/// without FIM tokens.
+///
+/// The results must be freed with `free_encode_results_v2`.
+///
+/// # Safety
+///
+/// `text` must point to `len` bytes, and `pattern` must be null or a null-terminated string.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encode_with_placeholders"))]
+pub unsafe extern "C" fn encode_with_placeholders(
+    tokenizer_ptr: *mut TokenizerHandle,
+    text: *const u8,
+    len: u32,
+    pattern: *const c_char,
+    options: EncodeParams,
+) -> EncodeResultsV2 {
+    let text = if len == 0 { &[][..] } else { unsafe { std::slice::from_raw_parts(text, len as usize) } };
+    result_to_encode_results_v2(catch_panic(|| encode_with_placeholders_impl(tokenizer_ptr, text, pattern, options)))
+}
+
+/// build_fim_prompt mode: prefix-suffix-middle, the prompt is `<prefix> prefix <suffix> suffix <middle>`.
+pub const FIM_PSM: u32 = 0;
+/// build_fim_prompt mode: suffix-prefix-middle, the prompt is `<prefix> <suffix> suffix <middle> prefix`, so
+/// the generated middle continues the prefix.
+pub const FIM_SPM: u32 = 1;
+
+// FIM_TOKENS are the (prefix, suffix, middle) fill-in-the-middle tokens of the code model families: StarCoder,
+// Qwen / CodeGemma, SantaCoder, CodeLlama and DeepSeek-Coder.
+const FIM_TOKENS: [[&str; 3]; 5] = [
+    ["<fim_prefix>", "<fim_suffix>", "<fim_middle>"],
+    ["<|fim_prefix|>", "<|fim_suffix|>", "<|fim_middle|>"],
+    ["<fim-prefix>", "<fim-suffix>", "<fim-middle>"],
+    ["▁<PRE>", "▁<SUF>", "▁<MID>"],
+    ["<｜fim▁begin｜>", "<｜fim▁hole｜>", "<｜fim▁end｜>"],
+];
+
+// fim_tokens returns the (id, token) of the prefix, suffix and middle fill-in-the-middle tokens of the tokenizer,
+// the first of the FIM_TOKENS it has all of.
+fn fim_tokens(tokenizer: &Tokenizer) -> Result<[(u32, String); 3], Box<dyn Error>> {
+    FIM_TOKENS
+        .iter()
+        .find_map(|tokens| {
+            let ids = tokens.map(|token| tokenizer.token_to_id(token));
+            match ids {
+                [Some(prefix), Some(suffix), Some(middle)] => Some([
+                    (prefix, tokens[0].to_string()),
+                    (suffix, tokens[1].to_string()),
+                    (middle, tokens[2].to_string()),
+                ]),
+                _ => None,
+            }
+        })
+        .ok_or_else(|| err("tokenizer has no fill-in-the-middle tokens"))
+}
+
+fn build_fim_prompt_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    prefix: &[u8],
+    suffix: &[u8],
+    mode: u32,
+    options: EncodeParams,
+) -> Result<Vec<Buffer>, Box<dyn Error>> {
+    options.validate()?;
+    if mode != FIM_PSM && mode != FIM_SPM {
+        return Err(err(format!("invalid fill-in-the-middle mode {}", mode)));
+    }
+    let texts = [
+        std::str::from_utf8(prefix).map_err(|e| err(format!("prefix is not valid UTF-8: {}", e)))?,
+        std::str::from_utf8(suffix).map_err(|e| err(format!("suffix is not valid UTF-8: {}", e)))?,
+    ];
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    state.encode_limits().check(std::iter::once(prefix.len() + suffix.len()))?;
+    let tokenizer = tokenizer_for(&state, &options);
+    let [prefix_token, suffix_token, middle_token] = fim_tokens(tokenizer)?;
+    let offsets_type = if options.has(ENCODE_WITH_OFFSETS_CHAR_MODE) { OffsetType::Char } else { OffsetType::Byte };
+    let normalize = !options.has(ENCODE_SKIP_NORMALIZATION);
+    let mut num_words = 0;
+    let [prefix, suffix] = [0, 1].map(|sequence_id| {
+        let encoding = crate::profile::encode_sequence(tokenizer, texts[sequence_id], 0, offsets_type, normalize)
+            .map_err(|e| err(format!("encoding failed: {}", e)))?;
+        // The words of the suffix are numbered after the ones of the prefix.
+        let mut prompt = Prompt { num_words, ..Default::default() };
+        prompt.push_encoding(&encoding, 0);
+        num_words = prompt.num_words;
+        let mut encoding = prompt.into_encoding();
+        encoding.set_sequence_id(sequence_id);
+        Ok::<_, Box<dyn Error>>(encoding)
+    });
+    let (prefix, suffix) = (prefix?, suffix?);
+
+    // The prompt starts with the BOS tokens the post-processor adds (e.g. "<s>" for CodeLlama), but the end of
+    // sequence tokens are not added: the model generates the middle after the prompt.
+    let mut encoding = if options.has(ENCODE_ADD_SPECIAL_TOKENS) {
+        let bos = template_special_tokens(tokenizer)?.bos.into_iter().map(|t| (t.id, t.token)).collect::<Vec<_>>();
+        special_tokens(&bos, 0)
+    } else {
+        Encoding::default()
+    };
+    let special = |token: &(u32, String)| special_tokens(std::slice::from_ref(token), 0);
+    encoding.merge_with(special(&prefix_token), false);
+    if mode == FIM_PSM {
+        encoding.merge_with(prefix, false);
+        encoding.merge_with(special(&suffix_token), false);
+        encoding.merge_with(suffix, false);
+        encoding.merge_with(special(&middle_token), false);
+    } else {
+        encoding.merge_with(special(&suffix_token), false);
+        encoding.merge_with(suffix, false);
+        encoding.merge_with(special(&middle_token), false);
+        encoding.merge_with(prefix, false);
+    }
+    if let Some(stats) = state.sequence_stats() {
+        stats.record(std::slice::from_ref(&encoding));
+    }
+    let scores = options.has(ENCODE_RETURN_DEBUG_INFO).then(|| state.token_scores());
+    let word_starts = options.has(ENCODE_RETURN_WORD_STARTS).then(|| WordStarts::new(tokenizer));
+    Ok(vec![encode_process(encoding, &texts, &options, scores.as_deref(), word_starts.as_ref())?])
+}
+
+/// build_fim_prompt encodes the fill-in-the-middle prompt of a code model, to generate the code in between the
+/// `prefix_len` bytes of UTF-8 `prefix` and the `suffix_len` bytes of `suffix`, using given tokenizer and
+/// EncodeParams. It returns an EncodeResultsV2 with one Buffer, with the tokens ordered according to `mode`,
+/// FIM_PSM or FIM_SPM.
+///
+/// The FIM tokens are the ones the tokenizer defines, following the conventions of the code model families:
+/// `<fim_prefix>`, `<fim_suffix>` and `<fim_middle>` (StarCoder), `<|fim_prefix|>`, ... (Qwen, CodeGemma),
+/// `<fim-prefix>`, ... (SantaCoder), `▁<PRE>`, `▁<SUF>` and `▁<MID>` (CodeLlama), or `<｜fim▁begin｜>`,
+/// `<｜fim▁hole｜>` and `<｜fim▁end｜>` (DeepSeek-Coder). It is an error if the tokenizer has none of them.
+///
+/// If ENCODE_ADD_SPECIAL_TOKENS is set, the prompt starts with the BOS tokens the post-processor adds (e.g.
+/// "<s>"), but no tokens are added at the end. The offsets of the prefix tokens are into the `prefix` (they are
+/// the sequence 0), and the ones of the suffix into the `suffix` (the sequence 1). The truncation and padding
+/// of the tokenizer are not applied.
+///
+/// The results must be freed with `free_encode_results_v2`.
+///
+/// # Safety
+///
+/// `prefix` and `suffix` must point to `prefix_len` and `suffix_len` bytes.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("build_fim_prompt"))]
+pub unsafe extern "C" fn build_fim_prompt(
+    tokenizer_ptr: *mut TokenizerHandle,
+    prefix: *const u8,
+    prefix_len: u32,
+    suffix: *const u8,
+    suffix_len: u32,
+    mode: u32,
+    options: EncodeParams,
+) -> EncodeResultsV2 {
+    let bytes = |ptr: *const u8, len: u32| {
+        if len == 0 { &[][..] } else { unsafe { std::slice::from_raw_parts(ptr, len as usize) } }
+    };
+    let (prefix, suffix) = (bytes(prefix, prefix_len), bytes(suffix, suffix_len));
+    result_to_encode_results_v2(catch_panic(|| build_fim_prompt_impl(tokenizer_ptr, prefix, suffix, mode, options)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{
+        free_encode_results_v2, ENCODE_PARAMS_VERSION, ENCODE_RETURN_OFFSETS, ENCODE_RETURN_SPECIAL_TOKENS_MASK,
+    };
+    use crate::testing::{take_error, TestHandle, WORDPIECE_BERT};
+
+    // with_placeholder_tokens returns WORDPIECE_BERT with the T5 sentinel `<extra_id_0>` (id 12) and the StarCoder
+    // FIM tokens (ids 13 to 15) in the vocabulary of the model: they are not added tokens.
+    fn with_placeholder_tokens() -> String {
+        WORDPIECE_BERT.replacen(
+            r#""[SEP]": 11}"#,
+            r#""[SEP]": 11, "<extra_id_0>": 12, "<fim_prefix>": 13, "<fim_suffix>": 14, "<fim_middle>": 15}"#,
+            1,
+        )
+    }
+
+    type Encoded = Result<(Vec<u32>, Vec<u32>, Vec<(u32, u32)>), String>;
+
+    // ids_masks_and_offsets returns the ids, special tokens mask and offsets of the only Buffer of the `results`,
+    // or their error, and frees them.
+    fn ids_masks_and_offsets(results: EncodeResultsV2) -> Encoded {
+        if let Some(error) = take_error(results.error) {
+            return Err(error);
+        }
+        let buffer = unsafe { &*results.encoded };
+        let len = buffer.len as usize;
+        let ids = unsafe { std::slice::from_raw_parts(buffer.ids, len) }.to_vec();
+        let mask = unsafe { std::slice::from_raw_parts(buffer.special_tokens_mask, len) }.to_vec();
+        let offsets = unsafe { std::slice::from_raw_parts(buffer.offsets, len) };
+        let offsets = offsets.iter().map(|offset| (offset.start, offset.end)).collect();
+        unsafe { free_encode_results_v2(results) };
+        Ok((ids, mask, offsets))
+    }
+
+    #[test]
+    fn placeholders() {
+        let handle = TestHandle::new(&with_placeholder_tokens());
+        let encode = |text: &str, pattern: *const c_char, flags: u64| {
+            let options = EncodeParams {
+                version: ENCODE_PARAMS_VERSION,
+                flags: flags | ENCODE_RETURN_SPECIAL_TOKENS_MASK | ENCODE_RETURN_OFFSETS,
+            };
+            let results =
+                unsafe { encode_with_placeholders(handle.0, text.as_ptr(), text.len() as u32, pattern, options) };
+            ids_masks_and_offsets(results)
+        };
+
+        let encoded = encode("hello<extra_id_0> a", std::ptr::null(), ENCODE_ADD_SPECIAL_TOKENS);
+        assert_eq!(encoded, Ok((
+            vec![10, 4, 12, 1, 11],
+            vec![1, 0, 1, 0, 1],
+            vec![(0, 0), (0, 5), (0, 0), (18, 19), (0, 0)],
+        )));
+        let encoded = encode("a [CLS] b", c"\\[CLS\\]".as_ptr(), 0);
+        assert_eq!(encoded, Ok((vec![1, 10, 2], vec![0, 1, 0], vec![(0, 1), (0, 0), (8, 9)])));
+
+        let error = r#"placeholder "<extra_id_1>" at byte 6 is not a token of the tokenizer"#;
+        assert_eq!(encode("hello <extra_id_1>", std::ptr::null(), 0), Err(error.to_string()));
+        assert!(encode("hello", c"(".as_ptr(), 0).unwrap_err().starts_with("invalid placeholder pattern"));
+    }
+
+    #[test]
+    fn fim_prompt() {
+        let build = |handle: &TestHandle, prefix: &str, suffix: &str, mode: u32| {
+            let flags = ENCODE_ADD_SPECIAL_TOKENS | ENCODE_RETURN_SPECIAL_TOKENS_MASK | ENCODE_RETURN_OFFSETS;
+            let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags };
+            let (prefix_len, suffix_len) = (prefix.len() as u32, suffix.len() as u32);
+            let results = unsafe {
+                build_fim_prompt(handle.0, prefix.as_ptr(), prefix_len, suffix.as_ptr(), suffix_len, mode, options)
+            };
+            ids_masks_and_offsets(results)
+        };
+
+        // The offsets of the prefix tokens are into the prefix, and the ones of the suffix into the suffix.
+        let handle = TestHandle::new(&with_placeholder_tokens());
+        assert_eq!(build(&handle, "hello a", "b world", FIM_PSM), Ok((
+            vec![10, 13, 4, 1, 14, 2, 6, 15],
+            vec![1, 1, 0, 0, 1, 0, 0, 1],
+            vec![(0, 0), (0, 0), (0, 5), (6, 7), (0, 0), (0, 1), (2, 7), (0, 0)],
+        )));
+        assert_eq!(build(&handle, "hello a", "b world", FIM_SPM), Ok((
+            vec![10, 13, 14, 2, 6, 15, 4, 1],
+            vec![1, 1, 1, 0, 0, 1, 0, 0],
+            vec![(0, 0), (0, 0), (0, 0), (0, 1), (2, 7), (0, 0), (0, 5), (6, 7)],
+        )));
+        assert_eq!(build(&handle, "", "", FIM_PSM).map(|(ids, _, _)| ids), Ok(vec![10, 13, 14, 15]));
+        assert_eq!(build(&handle, "a", "b", 2), Err("invalid fill-in-the-middle mode 2".to_string()));
+
+        let handle = TestHandle::new(WORDPIECE_BERT);
+        assert_eq!(build(&handle, "a", "b", FIM_PSM), Err("tokenizer has no fill-in-the-middle tokens".to_string()));
+    }
+}
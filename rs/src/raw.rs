@@ -0,0 +1,244 @@
+//! Encoding of raw bytes, not required to be valid UTF-8 (e.g. logs with invalid sequences or mixed
+//! encodings), for byte-level and byte-fallback tokenizers.
+
+use std::collections::HashMap;
+use std::error::Error;
+use tokenizers::tokenizer::{Model, OffsetType, Tokenizer};
+use tokenizers::Encoding;
+use crate::encode::{
+    encode_process, err, result_to_encode_results_v2, tokenizer_for, Buffer, EncodeParams, EncodeResultsV2,
+    ENCODE_ADD_SPECIAL_TOKENS, ENCODE_RETURN_DEBUG_INFO, ENCODE_RETURN_TOKEN_CHAR_LENGTHS, ENCODE_SKIP_NORMALIZATION,
+    ENCODE_RETURN_WORD_STARTS, ENCODE_WITH_OFFSETS_CHAR_MODE, ENCODE_WITH_OFFSETS_UTF16_MODE,
+};
+use crate::generation::byte_level_char_bytes;
+use crate::handle::{convert_to_handle_ref, TokenizerHandle};
+use crate::panics::catch_panic;
+use crate::words::WordStarts;
+
+// byte_tokens_of returns the token id and string of each byte value, for the bytes that are not valid UTF-8:
+// the byte fallback tokens (e.g. "<0xFF>") if the model has them, or the byte-level (GPT-2 style) characters
+// if the pre-tokenizer is byte-level.
+fn byte_tokens_of(tokenizer: &Tokenizer) -> Result<Vec<(u32, String)>, Box<dyn Error>> {
+    let model = tokenizer.get_model();
+    let fallback: Option<Vec<_>> = (0..=255_u8)
+        .map(|b| {
+            let token = format!("<0x{:02X}>", b);
+            model.token_to_id(&token).map(|id| (id, token))
+        })
+        .collect();
+    if let Some(fallback) = fallback {
+        return Ok(fallback);
+    }
+    let is_byte_level = match tokenizer.get_pre_tokenizer() {
+        Some(pre_tokenizer) => serde_json::to_string(pre_tokenizer)?.contains(r#""type":"ByteLevel""#),
+        None => false,
+    };
+    if !is_byte_level {
+        return Err(err("raw bytes can only be encoded by byte-level tokenizers, or by models with byte fallback"));
+    }
+    let byte_chars: HashMap<u8, char> = byte_level_char_bytes().into_iter().map(|(c, b)| (b, c)).collect();
+    (0..=255_u8)
+        .map(|b| {
+            let token = byte_chars[&b].to_string();
+            match model.token_to_id(&token) {
+                Some(id) => Ok((id, token)),
+                None => Err(err(format!("the vocabulary has no byte-level token for byte 0x{:02X}", b))),
+            }
+        })
+        .collect()
+}
+
+// Sequence accumulates the tokens of the parts of the raw bytes, before the post-processing.
+#[derive(Default)]
+struct Sequence {
+    ids: Vec<u32>,
+    tokens: Vec<String>,
+    words: Vec<Option<u32>>,
+    offsets: Vec<(usize, usize)>,
+    num_words: u32,
+    // Whether the last bytes appended were invalid: the next invalid bytes continue the same word.
+    in_invalid_run: bool,
+}
+
+impl Sequence {
+    // push_encoding appends the tokens of the `encoding` of the part of the bytes starting at `offset`.
+    fn push_encoding(&mut self, encoding: &Encoding, offset: usize) {
+        let num_words = self.num_words;
+        self.ids.extend_from_slice(encoding.get_ids());
+        self.tokens.extend_from_slice(encoding.get_tokens());
+        self.words.extend(encoding.get_word_ids().iter().map(|word| word.map(|word| word + num_words)));
+        self.offsets.extend(encoding.get_offsets().iter().map(|(start, end)| (start + offset, end + offset)));
+        self.num_words += encoding.get_word_ids().iter().flatten().max().map_or(0, |&max| max + 1);
+        self.in_invalid_run = false;
+    }
+
+    // push_invalid appends the tokens of the `invalid` bytes starting at `offset`: each run of invalid bytes is
+    // one word.
+    fn push_invalid(&mut self, invalid: &[u8], offset: usize, byte_tokens: &[(u32, String)]) {
+        if !self.in_invalid_run {
+            self.num_words += 1;
+            self.in_invalid_run = true;
+        }
+        for (i, &b) in invalid.iter().enumerate() {
+            let (id, token) = &byte_tokens[b as usize];
+            self.ids.push(*id);
+            self.tokens.push(token.clone());
+            self.words.push(Some(self.num_words - 1));
+            self.offsets.push((offset + i, offset + i + 1));
+        }
+    }
+
+    fn into_encoding(self) -> Encoding {
+        let len = self.ids.len();
+        Encoding::new(
+            self.ids,
+            vec![0; len],
+            self.tokens,
+            self.words,
+            self.offsets,
+            vec![0; len],
+            vec![1; len],
+            Vec::new(),
+            Default::default(),
+        )
+    }
+}
+
+fn encode_raw_bytes_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    bytes: &[u8],
+    options: EncodeParams,
+) -> Result<Vec<Buffer>, Box<dyn Error>> {
+    options.validate()?;
+    if options.has(ENCODE_WITH_OFFSETS_CHAR_MODE)
+        || options.has(ENCODE_WITH_OFFSETS_UTF16_MODE)
+        || options.has(ENCODE_RETURN_TOKEN_CHAR_LENGTHS)
+    {
+        return Err(err("raw bytes only support byte offsets: characters are not defined for invalid UTF-8"));
+    }
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    state.encode_limits().check(std::iter::once(bytes.len()))?;
+    let tokenizer = tokenizer_for(&state, &options);
+    let normalize = !options.has(ENCODE_SKIP_NORMALIZATION);
+    let mut byte_tokens: Option<Vec<(u32, String)>> = None;
+    let mut sequence = Sequence::default();
+    let mut offset = 0;
+    for chunk in bytes.utf8_chunks() {
+        let valid = chunk.valid();
+        if !valid.is_empty() {
+            let encoding = crate::profile::encode_sequence(tokenizer, valid, 0, OffsetType::Byte, normalize)
+                .map_err(|e| err(format!("encoding failed: {}", e)))?;
+            sequence.push_encoding(&encoding, offset);
+            offset += valid.len();
+        }
+        let invalid = chunk.invalid();
+        if !invalid.is_empty() {
+            if byte_tokens.is_none() {
+                byte_tokens = Some(byte_tokens_of(tokenizer)?);
+            }
+            sequence.push_invalid(invalid, offset, byte_tokens.as_deref().unwrap());
+            offset += invalid.len();
+        }
+    }
+    let encoding = tokenizer
+        .post_process(sequence.into_encoding(), None, options.has(ENCODE_ADD_SPECIAL_TOKENS))
+        .map_err(|e| err(format!("encoding failed: {}", e)))?;
+    if let Some(stats) = state.sequence_stats() {
+        stats.record(std::slice::from_ref(&encoding));
+    }
+    let scores = options.has(ENCODE_RETURN_DEBUG_INFO).then(|| state.token_scores());
+    let word_starts = options.has(ENCODE_RETURN_WORD_STARTS).then(|| WordStarts::new(tokenizer));
+    // The text is only used for the character offsets modes, not supported.
+    Ok(vec![encode_process(encoding, &[""], &options, scores.as_deref(), word_starts.as_ref())?])
+}
+
+/// Encodes the `len` raw bytes, not required to be valid UTF-8 (e.g. logs with invalid sequences, or mixed
+/// encodings), using given tokenizer and EncodeParams, and returns an EncodeResultsV2 with one Buffer.
+///
+/// The valid UTF-8 parts are encoded as text, and each byte of the invalid sequences is encoded as its byte
+/// token: the byte fallback token (e.g. "<0xFF>") if the model has them, or the byte-level (GPT-2 style) token
+/// of the byte for byte-level tokenizers -- other tokenizers fail. Each run of invalid bytes is one word.
+///
+/// Offsets are in bytes: ENCODE_WITH_OFFSETS_CHAR_MODE, ENCODE_WITH_OFFSETS_UTF16_MODE and
+/// ENCODE_RETURN_TOKEN_CHAR_LENGTHS are not supported.
+///
+/// The results must be freed with `free_encode_results_v2`.
+///
+/// # Safety
+///
+/// `bytes` must point to `len` bytes.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encode_raw_bytes"))]
+pub unsafe extern "C" fn encode_raw_bytes(
+    tokenizer_ptr: *mut TokenizerHandle,
+    bytes: *const u8,
+    len: u64,
+    options: EncodeParams,
+) -> EncodeResultsV2 {
+    let bytes = if len == 0 { &[][..] } else { unsafe { std::slice::from_raw_parts(bytes, len as usize) } };
+    result_to_encode_results_v2(catch_panic(|| encode_raw_bytes_impl(tokenizer_ptr, bytes, options)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{free_encode_results_v2, ENCODE_PARAMS_VERSION, ENCODE_RETURN_OFFSETS, ENCODE_RETURN_WORDS};
+    use crate::testing::{take_error, TestHandle, BYTE_FALLBACK_BPE, BYTE_LEVEL_BPE, WORDPIECE};
+
+    // with_all_byte_tokens returns BYTE_FALLBACK_BPE with only the byte fallback tokens of all the bytes, "<0x00>"
+    // to "<0xFF>" with ids 1 to 256: all the text is encoded as bytes.
+    fn with_all_byte_tokens() -> String {
+        let bytes: Vec<String> = (0..=255).map(|b| format!(r#""<0x{:02X}>": {}"#, b, b + 1)).collect();
+        let vocab = format!(r#""vocab": {{"<unk>": 0, {}}}"#, bytes.join(", "));
+        let start = BYTE_FALLBACK_BPE.find(r#""vocab""#).unwrap();
+        let end = start + BYTE_FALLBACK_BPE[start..].find('}').unwrap() + 1;
+        format!("{}{}{}", &BYTE_FALLBACK_BPE[..start], vocab, &BYTE_FALLBACK_BPE[end..])
+    }
+
+    // Encoded are the ids, offsets and word ids of an encoding.
+    type Encoded = (Vec<u32>, Vec<(u32, u32)>, Vec<i64>);
+
+    // encode returns the encoding of the raw `bytes`, or the error.
+    fn encode(handle: &TestHandle, bytes: &[u8], flags: u64) -> Result<Encoded, String> {
+        let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags };
+        let results = unsafe { encode_raw_bytes(handle.0, bytes.as_ptr(), bytes.len() as u64, options) };
+        if let Some(e) = take_error(results.error) {
+            return Err(e);
+        }
+        let buffer = unsafe { &*results.encoded };
+        let len = buffer.len as usize;
+        let ids = unsafe { std::slice::from_raw_parts(buffer.ids, len) }.to_vec();
+        let offsets = unsafe { std::slice::from_raw_parts(buffer.offsets, len) };
+        let offsets = offsets.iter().map(|o| (o.start, o.end)).collect();
+        let words = unsafe { std::slice::from_raw_parts(buffer.word_ids, len) }.to_vec();
+        unsafe { free_encode_results_v2(results) };
+        Ok((ids, offsets, words))
+    }
+
+    #[test]
+    fn raw_bytes() {
+        let handle = TestHandle::new(&with_all_byte_tokens());
+        let flags = ENCODE_RETURN_OFFSETS | ENCODE_RETURN_WORDS;
+        // Each valid part is normalized on its own: "▁hello" and "▁world" are 8 byte tokens each.
+        let (ids, offsets, words) = encode(&handle, b"hello\xff\xfeworld", flags).unwrap();
+        assert_eq!(ids.len(), 18);
+        assert_eq!((&ids[..4], &ids[8..10], &ids[17..]), (&[227, 151, 130, 105][..], &[256, 255][..], &[101][..]));
+        assert_eq!((offsets[7], offsets[8], offsets[9], offsets[10]), ((4, 5), (5, 6), (6, 7), (7, 8)));
+        // The run of invalid bytes is one word.
+        assert_eq!(words, [vec![0; 8], vec![1; 2], vec![2; 8]].concat());
+        assert_eq!(encode(&handle, b"", flags), Ok((vec![], vec![], vec![])));
+        assert_eq!(
+            encode(&handle, b"\xff", flags | ENCODE_WITH_OFFSETS_CHAR_MODE).unwrap_err(),
+            "raw bytes only support byte offsets: characters are not defined for invalid UTF-8");
+
+        // Byte-level tokenizers need a token for each byte, but valid UTF-8 is encoded as text.
+        let handle = TestHandle::new(BYTE_LEVEL_BPE);
+        assert_eq!(encode(&handle, b"hello", flags), Ok((vec![11], vec![(0, 5)], vec![0])));
+        let error = encode(&handle, b"\xff", flags).unwrap_err();
+        assert_eq!(error, "the vocabulary has no byte-level token for byte 0x00");
+        assert_eq!(
+            encode(&TestHandle::new(WORDPIECE), b"\xff", flags).unwrap_err(),
+            "raw bytes can only be encoded by byte-level tokenizers, or by models with byte fallback");
+    }
+}
@@ -0,0 +1,339 @@
+//! Record/replay of the FFI calls, for debugging: while recording (see `start_recording`), the encode and
+//! decode calls are logged (before they run) to a file, one JSON object per line, along with the definition
+//! of the tokenizers they use. `replay_recording` then repeats the calls, so a crash in production can be
+//! reproduced in Rust alone.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::{c_char, CStr, CString};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::ptr::{null, null_mut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use serde::{Deserialize, Serialize};
+use tokenizers::tokenizer::Tokenizer;
+use crate::decode::{decode_with_params, free_decode_results, DecodeParams};
+use crate::encode::{
+    encode_batch_str_impl, encode_str_impl, err, free_encode_results_v2, result_to_encode_results_v2,
+    EncodeParams,
+};
+use crate::handle::{TokenizerHandle, TokenizerState};
+use crate::panics::{catch_panic, catch_panic_or, error_c_string};
+
+/// Call is one recorded FFI call (one line of the recording). Tokenizers are identified by the address of
+/// their handle at recording time.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "call", rename_all = "snake_case")]
+enum Call {
+    // Definition of the tokenizer used by the following calls, logged before its first use and whenever it
+    // changes.
+    Tokenizer { handle: u64, json: String, encode_special_tokens: bool },
+    Encode {
+        handle: u64,
+        // Whether it was a batch call: single texts are encoded without the batch padding.
+        batch: bool,
+        version: u32,
+        flags: u64,
+        // The byte length of each text, and the texts themselves if recording the inputs.
+        lengths: Vec<u64>,
+        texts: Option<Vec<String>>,
+    },
+    Decode {
+        handle: u64,
+        len: u64,
+        ids: Option<Vec<u32>>,
+        skip_special_tokens: bool,
+        spaces_between_special_tokens: u8,
+        token_filter: Option<String>,
+        token_filter_replacement: Option<String>,
+        #[serde(default)]
+        invalid_utf8: u8,
+    },
+    // Release of a reference to the tokenizer, for context: it is not replayed.
+    Release { handle: u64 },
+}
+
+// Recorder writes the calls to the recording.
+struct Recorder {
+    out: BufWriter<File>,
+    include_inputs: bool,
+
+    // Generation of the tokenizer definition last logged for each handle.
+    logged: HashMap<u64, u64>,
+}
+
+impl Recorder {
+    // write logs the `call`, flushing it right away so it is kept if the process crashes.
+    fn write(&mut self, call: &Call) {
+        // Errors are ignored: recording must not make the calls fail.
+        if serde_json::to_writer(&mut self.out, call).is_ok() {
+            let _ = self.out.write_all(b"\n");
+            let _ = self.out.flush();
+        }
+    }
+
+    // log_tokenizer logs the definition of the tokenizer of `handle`, if it changed since it was last logged.
+    fn log_tokenizer(&mut self, handle: &TokenizerHandle, state: &TokenizerState) -> u64 {
+        let address = handle as *const TokenizerHandle as u64;
+        let generation = handle.generation();
+        if self.logged.get(&address) != Some(&generation) {
+            if let Ok(json) = state.tokenizer.to_string(false) {
+                self.write(&Call::Tokenizer {
+                    handle: address,
+                    json,
+                    encode_special_tokens: state.tokenizer.get_encode_special_tokens(),
+                });
+            }
+            self.logged.insert(address, generation);
+        }
+        address
+    }
+}
+
+static RECORDING: AtomicBool = AtomicBool::new(false);
+static RECORDER: Mutex<Option<Recorder>> = Mutex::new(None);
+
+// with_recorder calls `f` with the recorder, if recording.
+fn with_recorder(f: impl FnOnce(&mut Recorder)) {
+    if !RECORDING.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Some(recorder) = RECORDER.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        f(recorder);
+    }
+}
+
+// record_encode records an encode call of the `texts`, in a batch or not.
+pub(crate) fn record_encode(
+    handle: &TokenizerHandle,
+    state: &TokenizerState,
+    texts: &[&str],
+    options: &EncodeParams,
+    batch: bool,
+) {
+    with_recorder(|recorder| {
+        let address = recorder.log_tokenizer(handle, state);
+        recorder.write(&Call::Encode {
+            handle: address,
+            batch,
+            version: options.version,
+            flags: options.flags,
+            lengths: texts.iter().map(|text| text.len() as u64).collect(),
+            texts: recorder.include_inputs.then(|| texts.iter().map(|text| text.to_string()).collect()),
+        });
+    });
+}
+
+// record_decode records a decode call of the `ids` (the token transform, if any, is not recorded).
+pub(crate) fn record_decode(handle: &TokenizerHandle, state: &TokenizerState, ids: &[u32], params: &DecodeParams) {
+    with_recorder(|recorder| {
+        let address = recorder.log_tokenizer(handle, state);
+        let optional_string = |s: *const c_char| {
+            (!s.is_null()).then(|| unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned())
+        };
+        recorder.write(&Call::Decode {
+            handle: address,
+            len: ids.len() as u64,
+            ids: recorder.include_inputs.then(|| ids.to_vec()),
+            skip_special_tokens: params.skip_special_tokens,
+            spaces_between_special_tokens: params.spaces_between_special_tokens,
+            token_filter: optional_string(params.token_filter),
+            token_filter_replacement: optional_string(params.token_filter_replacement),
+            invalid_utf8: params.invalid_utf8,
+        });
+    });
+}
+
+// record_release records the release of a reference to the tokenizer.
+pub(crate) fn record_release(tokenizer_ptr: *mut TokenizerHandle) {
+    with_recorder(|recorder| recorder.write(&Call::Release { handle: tokenizer_ptr as u64 }));
+}
+
+fn start_recording_impl(path: *const c_char, include_inputs: bool) -> Result<(), Box<dyn Error>> {
+    if path.is_null() {
+        return Err(err("recording path is null"));
+    }
+    let path = unsafe { CStr::from_ptr(path) }.to_str()?;
+    let file = File::create(path).map_err(|e| err(format!("failed to create recording {:?}: {}", path, e)))?;
+    *RECORDER.lock().unwrap_or_else(|e| e.into_inner()) = Some(Recorder {
+        out: BufWriter::new(file),
+        include_inputs,
+        logged: HashMap::new(),
+    });
+    RECORDING.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// start_recording starts logging the encode and decode calls (of all tokenizers) to a new file at the
+/// null-terminated `path`, replacing any recording in progress. The definitions of the tokenizers are always
+/// logged, but the texts and token ids only if `include_inputs` is set: otherwise only their lengths are.
+///
+/// Recording is meant for debugging: each call is written (and flushed) to the file before it runs.
+///
+/// It returns null if ok, or a string with an error message (owned by caller), to be freed with `free_string`.
+///
+/// # Safety
+///
+/// `path` must be a null-terminated string.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("start_recording"))]
+pub unsafe extern "C" fn start_recording(path: *const c_char, include_inputs: bool) -> *mut c_char {
+    match catch_panic(|| start_recording_impl(path, include_inputs)) {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+/// stop_recording stops the recording in progress, if any, and closes its file.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("stop_recording"))]
+pub extern "C" fn stop_recording() {
+    catch_panic_or((), || {
+        RECORDING.store(false, Ordering::Relaxed);
+        *RECORDER.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    })
+}
+
+// replay_call repeats the recorded `call`, with the tokenizers created so far in `handles`. The results
+// of the calls (including their errors) are discarded.
+fn replay_call(call: Call, handles: &mut HashMap<u64, Arc<TokenizerHandle>>) -> Result<(), Box<dyn Error>> {
+    let handle_ptr = |address: u64| {
+        handles
+            .get(&address)
+            .map(|handle| Arc::as_ptr(handle).cast_mut())
+            .ok_or_else(|| err(format!("tokenizer {:#x} used before being defined", address)))
+    };
+    match call {
+        Call::Tokenizer { handle, json, encode_special_tokens } => {
+            let mut tokenizer: Tokenizer = json.parse().map_err(|e| err(format!("invalid tokenizer: {}", e)))?;
+            tokenizer.set_encode_special_tokens(encode_special_tokens);
+            handles.insert(handle, Arc::new(TokenizerHandle::new(tokenizer)));
+        }
+        Call::Encode { handle, batch, version, flags, lengths, texts } => {
+            let tokenizer_ptr = handle_ptr(handle)?;
+            // Without the inputs, texts of the same lengths are used.
+            let texts = texts.unwrap_or_else(|| lengths.iter().map(|&len| "x".repeat(len as usize)).collect());
+            let texts: Vec<&str> = texts.iter().map(String::as_str).collect();
+            let options = EncodeParams { version, flags };
+            let result = match texts.as_slice() {
+                [text] if !batch => encode_str_impl(tokenizer_ptr, text, options),
+                _ => encode_batch_str_impl(tokenizer_ptr, &texts, options),
+            };
+            unsafe { free_encode_results_v2(result_to_encode_results_v2(result)) };
+        }
+        Call::Decode {
+            handle,
+            len,
+            ids,
+            skip_special_tokens,
+            spaces_between_special_tokens,
+            token_filter,
+            token_filter_replacement,
+            invalid_utf8,
+        } => {
+            let tokenizer_ptr = handle_ptr(handle)?;
+            // Without the inputs, ids of the same length are used.
+            let ids = ids.unwrap_or_else(|| vec![0; len as usize]);
+            let token_filter = token_filter.map(CString::new).transpose()?;
+            let token_filter_replacement = token_filter_replacement.map(CString::new).transpose()?;
+            let params = DecodeParams {
+                skip_special_tokens,
+                spaces_between_special_tokens,
+                token_filter: token_filter.as_ref().map_or(null(), |s| s.as_ptr()),
+                token_filter_replacement: token_filter_replacement.as_ref().map_or(null(), |s| s.as_ptr()),
+                token_transform: None,
+                token_transform_data: null_mut(),
+                invalid_utf8,
+            };
+            let len = u32::try_from(ids.len())?;
+            unsafe { free_decode_results(decode_with_params(tokenizer_ptr, ids.as_ptr(), len, params)) };
+        }
+        // Other references may still be held, so the tokenizer is kept: it is replaced if the address is reused.
+        Call::Release { .. } => {}
+    }
+    Ok(())
+}
+
+fn replay_recording_impl(path: *const c_char) -> Result<(), Box<dyn Error>> {
+    if path.is_null() {
+        return Err(err("recording path is null"));
+    }
+    let path = unsafe { CStr::from_ptr(path) }.to_str()?;
+    let file = File::open(path).map_err(|e| err(format!("failed to open recording {:?}: {}", path, e)))?;
+    let mut handles = HashMap::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let call: Call = serde_json::from_str(&line)
+            .map_err(|e| err(format!("line {} of the recording: {}", index + 1, e)))?;
+        replay_call(call, &mut handles).map_err(|e| err(format!("line {} of the recording: {}", index + 1, e)))?;
+    }
+    Ok(())
+}
+
+/// replay_recording repeats the calls logged by `start_recording` to the file at the null-terminated `path`,
+/// in order, using the recorded tokenizer definitions. If the inputs were not recorded, placeholder inputs
+/// of the same lengths are used. Token transform callbacks (see `decode_with_params`) are not replayed.
+///
+/// The results of the replayed calls are discarded: the point is to reproduce their crashes (panics, which
+/// are reported to stderr by the panic hook, and returned by the calls as errors). It
+/// returns null if ok, or a string with an error message (owned by caller) if the recording is invalid, to
+/// be freed with `free_string`.
+///
+/// # Safety
+///
+/// `path` must be a null-terminated string.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("replay_recording"))]
+pub unsafe extern "C" fn replay_recording(path: *const c_char) -> *mut c_char {
+    match catch_panic(|| replay_recording_impl(path)) {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use crate::decode::decode;
+    use crate::testing::{encode_ids, take_error, temp_dir, TestHandle, WORDPIECE};
+
+    #[test]
+    fn record_and_replay() {
+        let handle = TestHandle::new(WORDPIECE);
+        let dir = temp_dir();
+        let path = CString::new(dir.join("recording.jsonl").to_str().unwrap()).unwrap();
+        assert_eq!(take_error(unsafe { start_recording(path.as_ptr(), true) }), None);
+        encode_ids(&handle, "hello world", 0);
+        let ids = [4_u32, 6];
+        unsafe { free_decode_results(decode(handle.0, ids.as_ptr(), 2, true)) };
+        stop_recording();
+
+        // Other tests may be running concurrently: only the calls with this tokenizer are checked.
+        let recording = std::fs::read_to_string(dir.join("recording.jsonl")).unwrap();
+        let calls: Vec<Value> = recording
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .filter(|call| call["handle"] == handle.0 as u64)
+            .collect();
+        let names: Vec<&str> = calls.iter().map(|call| call["call"].as_str().unwrap()).collect();
+        assert_eq!(names, ["tokenizer", "encode", "decode"]);
+        assert_eq!(calls[1]["texts"], serde_json::json!(["hello world"]));
+        assert_eq!(calls[2]["ids"], serde_json::json!([4, 6]));
+        assert_eq!(take_error(unsafe { replay_recording(path.as_ptr()) }), None);
+
+        let invalid = dir.join("invalid.jsonl");
+        let decode_call = serde_json::json!({
+            "call": "decode", "handle": 1, "len": 0, "skip_special_tokens": false, "spaces_between_special_tokens": 0,
+        });
+        std::fs::write(&invalid, format!("{{\"call\":\"release\",\"handle\":1}}\n\n{}\n", decode_call)).unwrap();
+        let invalid = CString::new(invalid.to_str().unwrap()).unwrap();
+        assert_eq!(
+            take_error(unsafe { replay_recording(invalid.as_ptr()) }).as_deref(),
+            Some("line 3 of the recording: tokenizer 0x1 used before being defined"));
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::ffi::{c_char, c_void, CStr};
+use std::ptr::null_mut;
+use std::sync::{Arc, Mutex, OnceLock};
+use crate::encode::err;
+use crate::handle::TokenizerHandle;
+use crate::load::shared_from_bytes;
+use crate::panics::{catch_panic, catch_panic_or, error_c_string};
+use crate::PointerOrError;
+
+// RegistryEntry holds a registered tokenizer and the number of references to it.
+struct RegistryEntry {
+    handle: Arc<TokenizerHandle>,
+    ref_count: usize,
+}
+
+impl RegistryEntry {
+    fn handle_ptr(&self) -> *mut c_void {
+        Arc::as_ptr(&self.handle).cast_mut().cast()
+    }
+}
+
+// registry returns the global registry of tokenizers, indexed by name.
+fn registry() -> &'static Mutex<HashMap<String, RegistryEntry>> {
+    static REGISTRY: OnceLock<Mutex<HashMap<String, RegistryEntry>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// name_from_cstr converts the C string `name` to a String.
+unsafe fn name_from_cstr(name: *const c_char) -> Result<String, String> {
+    if name.is_null() {
+        return Err("registry name is null".to_string());
+    }
+    match unsafe { CStr::from_ptr(name) }.to_str() {
+        Ok(s) => Ok(s.to_string()),
+        Err(e) => Err(format!("registry name is not valid UTF-8: {}", e)),
+    }
+}
+
+/// registry_load registers a tokenizer under `name`, parsing it from the json contents of a
+/// `tokenizer.json` file (see `from_bytes`), and returns a reference to it in the `value` field.
+///
+/// If a tokenizer is already registered under `name`, `bytes` is ignored, and the registered
+/// tokenizer is returned instead. In either case the reference count of `name` is incremented,
+/// and the caller should call `registry_unload` once it no longer uses it.
+///
+/// The tokenizer returned is owned by the registry: it must not be freed with `free_tokenizer`, unless
+/// an extra reference was taken with `tokenizer_retain`.
+///
+/// # Safety
+///
+/// `name` must be a valid C string, and `bytes` must point to `len` bytes.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("registry_load"))]
+pub unsafe extern "C" fn registry_load(name: *const c_char, bytes: *const u8, len: u32) -> PointerOrError {
+    let result = catch_panic(|| {
+        let name = unsafe { name_from_cstr(name) }.map_err(err)?;
+        let mut registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(entry) = registry.get_mut(&name) {
+            entry.ref_count += 1;
+            return Ok(entry.handle_ptr());
+        }
+        let bytes_slice = unsafe { std::slice::from_raw_parts(bytes, len as usize) };
+        let tokenizer = shared_from_bytes(bytes_slice)?;
+        let entry = registry.entry(name).or_insert(RegistryEntry {
+            handle: Arc::new(TokenizerHandle::new(tokenizer)),
+            ref_count: 1,
+        });
+        Ok(entry.handle_ptr())
+    });
+    match result {
+        Ok(value) => PointerOrError { value, error: null_mut() },
+        Err(e) => PointerOrError { value: null_mut(), error: error_c_string(e) },
+    }
+}
+
+/// registry_get returns the tokenizer registered under `name`, or null if there is none.
+///
+/// If found, the reference count of `name` is incremented, and the caller should call `registry_unload`
+/// once it no longer uses it.
+///
+/// The tokenizer returned is owned by the registry: it must not be freed with `free_tokenizer`.
+///
+/// # Safety
+///
+/// `name` must be a valid C string.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("registry_get"))]
+pub unsafe extern "C" fn registry_get(name: *const c_char) -> *mut TokenizerHandle {
+    catch_panic_or(null_mut(), || {
+        let name = match unsafe { name_from_cstr(name) } {
+            Ok(name) => name,
+            Err(_) => return null_mut(),
+        };
+        match registry().lock().unwrap_or_else(|e| e.into_inner()).get_mut(&name) {
+            Some(entry) => {
+                entry.ref_count += 1;
+                entry.handle_ptr().cast()
+            }
+            None => null_mut(),
+        }
+    })
+}
+
+/// registry_unload decrements the reference count of the tokenizer registered under `name`, and
+/// once it reaches 0, the tokenizer is unregistered and the registry's reference to it is released.
+///
+/// It returns null if ok, or a string with an error message (owned by caller) if `name` is not registered.
+/// The returned string needs to be freed with `free_string`.
+///
+/// # Safety
+///
+/// `name` must be a valid C string.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("registry_unload"))]
+pub unsafe extern "C" fn registry_unload(name: *const c_char) -> *mut c_char {
+    let result = catch_panic(|| {
+        let name = unsafe { name_from_cstr(name) }.map_err(err)?;
+        let mut registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+        let entry = registry.get_mut(&name).ok_or_else(|| err(format!("no tokenizer registered as {:?}", name)))?;
+        entry.ref_count -= 1;
+        if entry.ref_count == 0 {
+            registry.remove(&name);
+        }
+        Ok(())
+    });
+    match result {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{take_error, WORDPIECE};
+
+    #[test]
+    fn registry_ref_counts() {
+        let name = c"registry_ref_counts";
+        assert!(unsafe { registry_get(name.as_ptr()) }.is_null());
+        let loaded = unsafe { registry_load(name.as_ptr(), WORDPIECE.as_ptr(), WORDPIECE.len() as u32) };
+        assert_eq!(take_error(loaded.error), None);
+
+        // Loading it again ignores the bytes, and returns the registered tokenizer.
+        let again = unsafe { registry_load(name.as_ptr(), b"{".as_ptr(), 1) };
+        assert_eq!(take_error(again.error), None);
+        assert_eq!(again.value, loaded.value);
+        assert_eq!(unsafe { registry_get(name.as_ptr()) }, loaded.value.cast());
+
+        // It is unregistered once the 3 references are released.
+        for _ in 0..3 {
+            assert_eq!(take_error(unsafe { registry_unload(name.as_ptr()) }), None);
+        }
+        assert!(unsafe { registry_get(name.as_ptr()) }.is_null());
+        assert!(take_error(unsafe { registry_unload(name.as_ptr()) }).is_some());
+
+        let invalid = unsafe { registry_load(c"registry_invalid".as_ptr(), b"{".as_ptr(), 1) };
+        assert!(invalid.value.is_null());
+        assert!(take_error(invalid.error).is_some());
+    }
+}
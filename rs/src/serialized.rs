@@ -0,0 +1,198 @@
+use serde::{Serialize, Serializer};
+use std::borrow::Cow;
+use std::error::Error;
+use tokenizers::Encoding;
+use crate::debug::TokenScores;
+use crate::encode::{
+    encode_batch_with_state, get_length, get_offsets, get_token_char_lengths, get_token_scores, get_window,
+    get_sequence_ids, get_word_ids, get_words, messages_from_bytes, pack_bits, token_scores_for, word_starts_for, EncodeParams,
+    ENCODE_PACKED_MASKS, ENCODE_RETURN_ATTENTION_MASK,
+    ENCODE_RETURN_LENGTH, ENCODE_RETURN_OFFSETS, ENCODE_RETURN_OVERFLOWING, ENCODE_RETURN_SPECIAL_TOKENS_MASK, ENCODE_RETURN_TOKENS,
+    ENCODE_RETURN_TOKEN_CHAR_LENGTHS, ENCODE_RETURN_TYPE_IDS, ENCODE_RETURN_WORDS, ENCODE_RETURN_SEQUENCE_IDS, ENCODE_RETURN_WORD_IDS,
+};
+use crate::panics::catch_panic;
+use crate::words::WordStarts;
+use crate::handle::{convert_to_handle_ref, TokenizerHandle};
+use crate::BytesOrError;
+
+// SerializedResults is the top-level object serialized by `encode_batch_serialized`.
+#[derive(Serialize)]
+struct SerializedResults<'a> {
+    encodings: Vec<SerializedEncoding<'a>>,
+}
+
+// SerializedEncoding is the serialized version of one Encoding: fields not requested are omitted.
+#[derive(Serialize)]
+struct SerializedEncoding<'a> {
+    ids: &'a [u32],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    type_ids: Option<&'a [u32]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    special_tokens_mask: Option<&'a [u32]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attention_mask: Option<&'a [u32]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tokens: Option<&'a [String]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offsets: Option<Cow<'a, [(usize, usize)]>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_char_lengths: Option<Vec<u32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    word_ids: Option<Vec<i64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_scores: Option<Vec<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    window: Option<(usize, usize)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    overflowing: Option<Vec<SerializedEncoding<'a>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    length: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    words: Option<Vec<(usize, usize)>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    word_starts: Option<Vec<u32>>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_bits")]
+    attention_mask_bits: Option<Vec<u8>>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_bits")]
+    special_tokens_mask_bits: Option<Vec<u8>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sequence_ids: Option<Vec<i64>>,
+}
+
+// serialize_bits serializes the packed bits of a mask as binary data, instead of an array of uint.
+fn serialize_bits<S: Serializer>(bits: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+    match bits {
+        Some(bits) => serializer.serialize_bytes(bits),
+        None => serializer.serialize_none(),
+    }
+}
+
+// serialize_encoding converts the `encoding` of `text`: `scores` are only given if ENCODE_RETURN_DEBUG_INFO is set,
+// and `word_starts` if ENCODE_RETURN_WORD_STARTS is set.
+fn serialize_encoding<'a>(
+    encoding: &'a Encoding,
+    text: &str,
+    options: &EncodeParams,
+    scores: Option<&TokenScores>,
+    word_starts: Option<&WordStarts>,
+) -> SerializedEncoding<'a> {
+    let packed = options.has(ENCODE_PACKED_MASKS);
+    SerializedEncoding {
+        ids: encoding.get_ids(),
+        type_ids: options.has(ENCODE_RETURN_TYPE_IDS).then(|| encoding.get_type_ids()),
+        special_tokens_mask: (options.has(ENCODE_RETURN_SPECIAL_TOKENS_MASK) && !packed)
+            .then(|| encoding.get_special_tokens_mask()),
+        attention_mask: (options.has(ENCODE_RETURN_ATTENTION_MASK) && !packed).then(|| encoding.get_attention_mask()),
+        tokens: options.has(ENCODE_RETURN_TOKENS).then(|| encoding.get_tokens()),
+        offsets: options.has(ENCODE_RETURN_OFFSETS).then(|| get_offsets(encoding, &[text], options)),
+        token_char_lengths: options.has(ENCODE_RETURN_TOKEN_CHAR_LENGTHS)
+            .then(|| get_token_char_lengths(encoding, &[text], options)),
+        word_ids: (scores.is_some() || options.has(ENCODE_RETURN_WORDS | ENCODE_RETURN_WORD_IDS)).then(|| get_word_ids(encoding)),
+        token_scores: scores.map(|scores| get_token_scores(encoding, scores)),
+        window: options.has(ENCODE_RETURN_OVERFLOWING)
+            .then(|| get_window(encoding, &get_offsets(encoding, &[text], options))),
+        overflowing: options.has(ENCODE_RETURN_OVERFLOWING).then(|| {
+            encoding.get_overflowing().iter().map(|overflow| serialize_encoding(overflow, text, options, scores, word_starts)).collect()
+        }),
+        length: options.has(ENCODE_RETURN_LENGTH).then(|| get_length(encoding)),
+        words: options.has(ENCODE_RETURN_WORDS).then(|| get_words(encoding, &get_offsets(encoding, &[text], options))),
+        word_starts: word_starts.map(|word_starts| word_starts.get(encoding)),
+        attention_mask_bits: (options.has(ENCODE_RETURN_ATTENTION_MASK) && packed)
+            .then(|| pack_bits(encoding.get_attention_mask())),
+        special_tokens_mask_bits: (options.has(ENCODE_RETURN_SPECIAL_TOKENS_MASK) && packed)
+            .then(|| pack_bits(encoding.get_special_tokens_mask())),
+        sequence_ids: options.has(ENCODE_RETURN_SEQUENCE_IDS).then(|| get_sequence_ids(encoding)),
+    }
+}
+
+fn encode_batch_serialized_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_messages: usize,
+    messages: *const *const u8,
+    lengths: *const u32,
+    options: EncodeParams,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let inputs = messages_from_bytes(num_messages, messages, lengths)?;
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let (encodings, scores, word_starts) = {
+        let state = handle.read();
+        let encodings = encode_batch_with_state(handle, &state, &inputs, &options)?;
+        (encodings, token_scores_for(&state, &options), word_starts_for(&state, &options))
+    };
+    let results = SerializedResults {
+        encodings: encodings
+            .iter()
+            .zip(inputs.iter())
+            .map(|(encoding, text)| serialize_encoding(encoding, text, &options, scores.as_deref(), word_starts.as_ref()))
+            .collect(),
+    };
+    Ok(rmp_serde::to_vec_named(&results)?)
+}
+
+/// Encode a batch of UTF-8 strings (given as in `encode_batch_bytes`) using given tokenizer and
+/// EncodeParams, and return all results serialized in one MessagePack blob, so the caller does only
+/// one copy and one free (with `free_bytes`) per batch.
+///
+/// The schema of the blob is a map with the following keys (string keys, in this order):
+///
+/// - `encodings`: array with one map per message encoded, with the keys:
+///   - `ids`: array of uint, always present.
+///   - `type_ids`: array of uint, if ENCODE_RETURN_TYPE_IDS is set.
+///   - `special_tokens_mask`: array of uint, if ENCODE_RETURN_SPECIAL_TOKENS_MASK is set.
+///   - `attention_mask`: array of uint, if ENCODE_RETURN_ATTENTION_MASK is set.
+///   - `tokens`: array of str, if ENCODE_RETURN_TOKENS is set.
+///   - `offsets`: array of `[start, end]` uint pairs, if ENCODE_RETURN_OFFSETS is set (in UTF-16 code units if
+///     ENCODE_WITH_OFFSETS_UTF16_MODE is set).
+///   - `token_char_lengths`: array of uint, if ENCODE_RETURN_TOKEN_CHAR_LENGTHS is set.
+///   - `word_ids`: array of int, if ENCODE_RETURN_DEBUG_INFO, ENCODE_RETURN_WORDS or ENCODE_RETURN_WORD_IDS is set
+///     (see `Buffer.word_ids`).
+///   - `token_scores`: array of float, if ENCODE_RETURN_DEBUG_INFO is set (see `Buffer.token_scores`).
+///   - `window`: `[start, end]` uint pair, if ENCODE_RETURN_OVERFLOWING is set (see `Buffer.window`).
+///   - `overflowing`: array of maps with these same keys, one per overflowing window, if
+///     ENCODE_RETURN_OVERFLOWING is set.
+///   - `length`: uint, if ENCODE_RETURN_LENGTH is set (see `Buffer.length`).
+///   - `words`: array of `[start, end]` uint pairs, if ENCODE_RETURN_WORDS is set (see `Buffer.words`).
+///   - `word_starts`: array of uint, if ENCODE_RETURN_WORD_STARTS is set (see `Buffer.word_starts`).
+///   - `attention_mask_bits` and `special_tokens_mask_bits`: binary, instead of `attention_mask` and
+///     `special_tokens_mask` if ENCODE_PACKED_MASKS is set (see `Buffer.attention_mask_bits`).
+///   - `sequence_ids`: array of int, if ENCODE_RETURN_SEQUENCE_IDS is set (see `Buffer.sequence_ids`).
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("encode_batch_serialized"))]
+pub unsafe extern "C" fn encode_batch_serialized(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_messages: u32,
+    messages: *const *const u8,
+    lengths: *const u32,
+    options: EncodeParams,
+) -> BytesOrError {
+    BytesOrError::from_result(
+        catch_panic(|| encode_batch_serialized_impl(tokenizer_ptr, num_messages as usize, messages, lengths, options)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use crate::encode::ENCODE_PARAMS_VERSION;
+    use crate::testing::{take_bytes, TestHandle, WORDPIECE};
+
+    #[test]
+    fn serialized_batch() {
+        let handle = TestHandle::new(WORDPIECE);
+        let texts = ["hello world", "b"];
+        let messages: Vec<*const u8> = texts.iter().map(|t| t.as_ptr()).collect();
+        let lengths: Vec<u32> = texts.iter().map(|t| t.len() as u32).collect();
+        let serialize = |version, flags| {
+            let options = EncodeParams { version, flags };
+            take_bytes(unsafe { encode_batch_serialized(handle.0, 2, messages.as_ptr(), lengths.as_ptr(), options) })
+        };
+        let blob = serialize(ENCODE_PARAMS_VERSION, ENCODE_RETURN_TOKENS | ENCODE_RETURN_OFFSETS).unwrap();
+        let results: Value = rmp_serde::from_slice(&blob).unwrap();
+        assert_eq!(results, json!({"encodings": [
+            {"ids": [4, 6], "tokens": ["hello", "world"], "offsets": [[0, 5], [6, 11]]},
+            {"ids": [2], "tokens": ["b"], "offsets": [[0, 1]]},
+        ]}));
+
+        assert!(serialize(0, 0).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+//! Sequence-length statistics: when enabled on a tokenizer (see `set_sequence_stats`), the lengths of the
+//! encoded sequences, how often they were truncated and how much padding was added are aggregated, to choose
+//! `max_length` and padding based on real inputs.
+
+use std::error::Error;
+use std::ffi::c_char;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokenizers::Encoding;
+use crate::encode::{err, get_length};
+use crate::handle::{convert_to_handle_ref, TokenizerHandle};
+use crate::panics::{catch_panic, catch_panic_or, error_c_string};
+
+/// Number of buckets of the SequenceStats histogram: bucket 0 counts the empty sequences, and bucket `i > 0`
+/// the sequences with `2^(i-1)` (inclusive) to `2^i` (exclusive) tokens -- the last bucket also counts all
+/// the longer sequences.
+pub const SEQUENCE_STATS_NUM_BUCKETS: usize = 33;
+
+/// SequenceStats holds the statistics of the sequences encoded by a tokenizer, see `get_sequence_stats`.
+#[repr(C)]
+pub struct SequenceStats {
+    /// Number of sequences encoded.
+    count: u64,
+    /// Number of sequences truncated (to the `max_length` of the truncation parameters).
+    truncated: u64,
+    /// Total number of tokens, not counting the padding.
+    total_tokens: u64,
+    /// Total number of padding tokens added.
+    total_padding: u64,
+    /// Longest sequence, not counting the padding.
+    max_len: u64,
+    /// Histogram of the lengths of the sequences, not counting the padding.
+    buckets: [u64; SEQUENCE_STATS_NUM_BUCKETS],
+}
+
+/// SequenceStatsCollector aggregates the statistics of the sequences encoded by a tokenizer. It is shared
+/// by the readers of the tokenizer, so it is updated atomically.
+pub struct SequenceStatsCollector {
+    count: AtomicU64,
+    truncated: AtomicU64,
+    total_tokens: AtomicU64,
+    total_padding: AtomicU64,
+    max_len: AtomicU64,
+    buckets: [AtomicU64; SEQUENCE_STATS_NUM_BUCKETS],
+}
+
+impl SequenceStatsCollector {
+    pub fn new() -> Self {
+        SequenceStatsCollector {
+            count: AtomicU64::new(0),
+            truncated: AtomicU64::new(0),
+            total_tokens: AtomicU64::new(0),
+            total_padding: AtomicU64::new(0),
+            max_len: AtomicU64::new(0),
+            buckets: [const { AtomicU64::new(0) }; SEQUENCE_STATS_NUM_BUCKETS],
+        }
+    }
+
+    /// Records the statistics of the encoded sequences.
+    pub fn record(&self, encodings: &[Encoding]) {
+        for encoding in encodings {
+            let len = get_length(encoding);
+            let padding = encoding.len() as u64 - len;
+            let bucket = match len {
+                0 => 0,
+                len => (len.ilog2() as usize + 1).min(SEQUENCE_STATS_NUM_BUCKETS - 1),
+            };
+            self.count.fetch_add(1, Ordering::Relaxed);
+            if !encoding.get_overflowing().is_empty() {
+                self.truncated.fetch_add(1, Ordering::Relaxed);
+            }
+            self.total_tokens.fetch_add(len, Ordering::Relaxed);
+            self.total_padding.fetch_add(padding, Ordering::Relaxed);
+            self.max_len.fetch_max(len, Ordering::Relaxed);
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn snapshot(&self) -> SequenceStats {
+        SequenceStats {
+            count: self.count.load(Ordering::Relaxed),
+            truncated: self.truncated.load(Ordering::Relaxed),
+            total_tokens: self.total_tokens.load(Ordering::Relaxed),
+            total_padding: self.total_padding.load(Ordering::Relaxed),
+            max_len: self.max_len.load(Ordering::Relaxed),
+            buckets: std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed)),
+        }
+    }
+
+    fn reset(&self) {
+        for counter in [&self.count, &self.truncated, &self.total_tokens, &self.total_padding, &self.max_len] {
+            counter.store(0, Ordering::Relaxed);
+        }
+        self.buckets.iter().for_each(|bucket| bucket.store(0, Ordering::Relaxed));
+    }
+}
+
+fn set_sequence_stats_impl(tokenizer_ptr: *mut TokenizerHandle, enabled: bool) -> Result<(), Box<dyn Error>> {
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let mut state = handle.write()?;
+    match (enabled, state.sequence_stats().is_some()) {
+        (true, false) => state.set_sequence_stats(Some(Arc::new(SequenceStatsCollector::new()))),
+        (false, true) => state.set_sequence_stats(None),
+        _ => {}
+    }
+    Ok(())
+}
+
+/// set_sequence_stats enables (or disables) the collection of the statistics of the sequences encoded by
+/// the tokenizer, retrieved with `get_sequence_stats`. Enabling it when already enabled keeps the statistics,
+/// and disabling it discards them.
+///
+/// It must be enabled before freezing the tokenizer, and the statistics are then shared with the frozen copy.
+///
+/// It returns null if ok, or a string with an error message (owned by caller), to be freed with `free_string`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("set_sequence_stats"))]
+pub unsafe extern "C" fn set_sequence_stats(tokenizer_ptr: *mut TokenizerHandle, enabled: bool) -> *mut c_char {
+    match catch_panic(|| set_sequence_stats_impl(tokenizer_ptr, enabled)) {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+fn get_sequence_stats_impl(tokenizer_ptr: *mut TokenizerHandle, stats: *mut SequenceStats) -> Result<(), Box<dyn Error>> {
+    if stats.is_null() {
+        return Err(err("stats is null"));
+    }
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    let collector = state.sequence_stats().ok_or_else(|| err("sequence statistics are not enabled"))?;
+    unsafe { stats.write(collector.snapshot()) };
+    Ok(())
+}
+
+/// get_sequence_stats writes to `stats` the statistics of the sequences encoded by the tokenizer since they
+/// were enabled (see `set_sequence_stats`), or since the last `reset_sequence_stats`.
+///
+/// It returns null if ok, or a string with an error message (owned by caller), to be freed with `free_string`.
+///
+/// # Safety
+///
+/// `stats` must point to a writable SequenceStats.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("get_sequence_stats"))]
+pub unsafe extern "C" fn get_sequence_stats(tokenizer_ptr: *mut TokenizerHandle, stats: *mut SequenceStats) -> *mut c_char {
+    match catch_panic(|| get_sequence_stats_impl(tokenizer_ptr, stats)) {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+/// reset_sequence_stats clears the statistics of the sequences encoded by the tokenizer, if enabled.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("reset_sequence_stats"))]
+pub unsafe extern "C" fn reset_sequence_stats(tokenizer_ptr: *mut TokenizerHandle) {
+    catch_panic_or((), || {
+        if let Ok(handle) = convert_to_handle_ref(tokenizer_ptr) {
+            if let Some(collector) = handle.read().sequence_stats() {
+                collector.reset();
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{encode_ids, take_error, TestHandle, WORDPIECE};
+
+    // stats returns the statistics of the tokenizer.
+    fn stats(handle: &TestHandle) -> SequenceStats {
+        let mut stats = SequenceStatsCollector::new().snapshot();
+        assert_eq!(take_error(unsafe { get_sequence_stats(handle.0, &mut stats) }), None);
+        stats
+    }
+
+    #[test]
+    fn sequence_stats() {
+        let handle = TestHandle::new(WORDPIECE);
+        let mut snapshot = SequenceStatsCollector::new().snapshot();
+        assert_eq!(
+            take_error(unsafe { get_sequence_stats(handle.0, &mut snapshot) }).as_deref(),
+            Some("sequence statistics are not enabled"));
+
+        assert_eq!(take_error(unsafe { set_sequence_stats(handle.0, true) }), None);
+        for text in ["hello world a b", "", "hello", "a b a"] {
+            encode_ids(&handle, text, 0);
+        }
+        let collected = stats(&handle);
+        let totals = (collected.count, collected.truncated, collected.total_tokens, collected.total_padding);
+        assert_eq!(totals, (4, 0, 8, 0));
+        assert_eq!(collected.max_len, 4);
+        assert_eq!(collected.buckets[..4], [1, 1, 1, 1]);
+
+        // Enabling it again keeps the statistics, resetting them clears them.
+        assert_eq!(take_error(unsafe { set_sequence_stats(handle.0, true) }), None);
+        assert_eq!(stats(&handle).count, 4);
+        unsafe { reset_sequence_stats(handle.0) };
+        assert_eq!(stats(&handle).count, 0);
+
+        assert_eq!(take_error(unsafe { set_sequence_stats(handle.0, false) }), None);
+        let error = take_error(unsafe { get_sequence_stats(handle.0, &mut snapshot) });
+        assert_eq!(error.as_deref(), Some("sequence statistics are not enabled"));
+    }
+}
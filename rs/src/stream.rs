@@ -0,0 +1,242 @@
+//! Streaming decoding: the ids generated one at a time are decoded incrementally, emitting the text as soon as
+//! it is complete, as the `DecodeStream` of the tokenizers crate.
+
+use std::error::Error;
+use std::ffi::CString;
+use std::ptr::null_mut;
+use std::sync::Arc;
+use tokenizers::tokenizer::step_decode_stream;
+use crate::encode::err;
+use crate::generation::TokenSurfaces;
+use crate::handle::{convert_to_handle_ref, TokenizerHandle};
+use crate::panics::{catch_panic, catch_panic_or, error_c_string};
+use crate::PointerOrError;
+
+/// DecodeStream decodes the ids generated one at a time, see `decode_stream_new`. It is opaque to the host.
+pub struct DecodeStream {
+    tokenizer: Arc<TokenizerHandle>,
+    skip_special_tokens: bool,
+
+    // The state of `step_decode_stream`: the ids decoded again with each new id (for the decoders that depend
+    // on the context), the text of `ids[..prefix_index]` already emitted, and `prefix_index`. The ids from
+    // `prefix_index` on are withheld: they don't decode to complete characters yet.
+    ids: Vec<u32>,
+    prefix: String,
+    prefix_index: usize,
+}
+
+/// DecodeStreamStatus tells whether a DecodeStream is withholding text, see `decode_stream_status`.
+#[repr(C)]
+pub struct DecodeStreamStatus {
+    pending_ids: u32,  // Number of ids decoded but not emitted yet, 0 if no text is withheld.
+    pending_bytes: u32,  // Number of bytes of the withheld ids, e.g. the first bytes of a multi-byte character.
+}
+
+impl DecodeStream {
+    fn step(&mut self, id: u32) -> Result<Option<String>, Box<dyn Error>> {
+        let state = self.tokenizer.read();
+        let text = step_decode_stream(
+            &state.tokenizer,
+            id,
+            self.skip_special_tokens,
+            &mut self.ids,
+            &mut self.prefix,
+            &mut self.prefix_index,
+        )
+        .map_err(|e| err(format!("decoding failed: {}", e)))?;
+        Ok(text)
+    }
+
+    fn status(&self) -> DecodeStreamStatus {
+        let pending = &self.ids[self.prefix_index.min(self.ids.len())..];
+        if pending.is_empty() {
+            return DecodeStreamStatus { pending_ids: 0, pending_bytes: 0 };
+        }
+        let state = self.tokenizer.read();
+        let tokenizer = &state.tokenizer;
+        let surfaces = TokenSurfaces::new(tokenizer);
+        let added_tokens = tokenizer.get_added_tokens_decoder();
+        let pending_bytes: usize = pending
+            .iter()
+            .map(|id| match (added_tokens.get(id), tokenizer.id_to_token(*id)) {
+                (Some(token), _) if token.special && self.skip_special_tokens => 0,
+                (Some(token), _) => token.content.len(),
+                (None, Some(token)) => surfaces.surface(&token).len(),
+                (None, None) => 0,
+            })
+            .sum();
+        DecodeStreamStatus {
+            pending_ids: pending.len() as u32,
+            pending_bytes: u32::try_from(pending_bytes).unwrap_or(u32::MAX),
+        }
+    }
+
+    // flush returns the text withheld, with the incomplete characters decoded as U+FFFD, or None if there is none.
+    // The ids emitted before it are kept as the context of the ids after it (e.g. for the leading space of a
+    // word), as if the withheld ids had not been given.
+    fn flush(&mut self) -> Result<Option<String>, Box<dyn Error>> {
+        if self.prefix_index >= self.ids.len() {
+            return Ok(None);
+        }
+        let state = self.tokenizer.read();
+        let string = state
+            .tokenizer
+            .decode(&self.ids, self.skip_special_tokens)
+            .map_err(|e| err(format!("decoding failed: {}", e)))?;
+        let text = string
+            .strip_prefix(self.prefix.as_str())
+            .ok_or_else(|| err("decoded text is not an extension of the previously decoded text"))?
+            .to_string();
+        // `prefix` is the text of `ids[..prefix_index]`.
+        self.ids.truncate(self.prefix_index);
+        Ok(Some(text))
+    }
+}
+
+// text_or_error converts the optional text of a DecodeStream to a PointerOrError.
+fn text_or_error(result: Result<Option<String>, Box<dyn Error>>) -> PointerOrError {
+    match result.and_then(|text| Ok(text.map(CString::new).transpose()?)) {
+        Ok(Some(text)) => PointerOrError { value: text.into_raw().cast(), error: null_mut() },
+        Ok(None) => PointerOrError { value: null_mut(), error: null_mut() },
+        Err(e) => PointerOrError { value: null_mut(), error: error_c_string(e) },
+    }
+}
+
+/// decode_stream_new returns a `DecodeStream *` in the `value` field, to decode the ids generated one at a time
+/// (e.g. by a language model) with `decode_stream_step`, as `decode` would decode all of them, but in time
+/// linear in the number of ids.
+///
+/// The stream holds a reference to the tokenizer (see `tokenizer_retain`), so it can be freed independently.
+/// Free the stream with `decode_stream_free`.
+///
+/// # Safety
+///
+/// `tokenizer_ptr` must be null or a live tokenizer returned by this library (e.g. by `from_bytes`).
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("decode_stream_new"))]
+pub unsafe extern "C" fn decode_stream_new(
+    tokenizer_ptr: *mut TokenizerHandle,
+    skip_special_tokens: bool,
+) -> PointerOrError {
+    let result = catch_panic(|| {
+        convert_to_handle_ref(tokenizer_ptr)?;
+        let tokenizer = unsafe {
+            Arc::increment_strong_count(tokenizer_ptr.cast_const());
+            Arc::from_raw(tokenizer_ptr.cast_const())
+        };
+        Ok(DecodeStream { tokenizer, skip_special_tokens, ids: Vec::new(), prefix: String::new(), prefix_index: 0 })
+    });
+    match result {
+        Ok(stream) => PointerOrError { value: Box::into_raw(Box::new(stream)).cast(), error: null_mut() },
+        Err(e) => PointerOrError { value: null_mut(), error: error_c_string(e) },
+    }
+}
+
+/// decode_stream_step decodes the next `id` of the stream, and returns the text it completes in the `value`
+/// field (a C string that must be freed with `free_string`), or null if it doesn't complete any text yet: e.g.
+/// the first bytes of a multi-byte character, with byte-level or byte fallback tokens. Broken UTF-8 is never
+/// emitted: see `decode_stream_status` for the text withheld.
+///
+/// # Safety
+///
+/// `stream` must have been returned by `decode_stream_new`, and not freed.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("decode_stream_step"))]
+pub unsafe extern "C" fn decode_stream_step(stream: *mut DecodeStream, id: u32) -> PointerOrError {
+    text_or_error(catch_panic(|| unsafe { stream.as_mut() }.ok_or_else(|| err("decode stream is null"))?.step(id)))
+}
+
+/// decode_stream_status returns whether the stream is withholding text pending the completion of a multi-byte
+/// character, and how much: the number of ids, and of their bytes (e.g. 2 for the first 2 bytes of a 4-byte
+/// emoji). Hosts can use it to flush the withheld text (see `decode_stream_flush`) after a timeout, e.g. for
+/// interactive UIs.
+///
+/// # Safety
+///
+/// `stream` must have been returned by `decode_stream_new`, and not freed.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("decode_stream_status"))]
+pub unsafe extern "C" fn decode_stream_status(stream: *const DecodeStream) -> DecodeStreamStatus {
+    catch_panic_or(DecodeStreamStatus { pending_ids: 0, pending_bytes: 0 }, || match unsafe { stream.as_ref() } {
+        Some(stream) => stream.status(),
+        None => DecodeStreamStatus { pending_ids: 0, pending_bytes: 0 },
+    })
+}
+
+/// decode_stream_flush returns the text withheld by the stream in the `value` field (a C string that must be
+/// freed with `free_string`), with the incomplete characters replaced by U+FFFD -- so it is still valid UTF-8 --
+/// or null if no text is withheld. The ids given after a flush are decoded following the text emitted before the
+/// withheld ids (e.g. with the space before a word), as if these had not been given.
+///
+/// # Safety
+///
+/// `stream` must have been returned by `decode_stream_new`, and not freed.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("decode_stream_flush"))]
+pub unsafe extern "C" fn decode_stream_flush(stream: *mut DecodeStream) -> PointerOrError {
+    text_or_error(catch_panic(|| unsafe { stream.as_mut() }.ok_or_else(|| err("decode stream is null"))?.flush()))
+}
+
+/// decode_stream_free frees the stream, and its reference to the tokenizer.
+///
+/// # Safety
+///
+/// `stream` must have been returned by `decode_stream_new`, and it must not be used after this call.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("decode_stream_free"))]
+pub unsafe extern "C" fn decode_stream_free(stream: *mut DecodeStream) {
+    catch_panic_or((), || {
+        if !stream.is_null() {
+            drop(unsafe { Box::from_raw(stream) });
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+    use crate::testing::{take_error, TestHandle, BYTE_FALLBACK_BPE, BYTE_LEVEL_BPE};
+
+    // take_text returns the text of the `result` of a DecodeStream call (freeing it), or None if there is none.
+    fn take_text(result: PointerOrError) -> Option<String> {
+        if let Some(e) = take_error(result.error) {
+            panic!("decode stream failed: {}", e);
+        }
+        if result.value.is_null() {
+            return None;
+        }
+        let text = unsafe { CStr::from_ptr(result.value.cast()) }.to_str().unwrap().to_string();
+        unsafe { crate::free_string(result.value.cast()) };
+        Some(text)
+    }
+
+    #[test]
+    fn decode_stream() {
+        let result = unsafe { decode_stream_new(null_mut(), false) };
+        assert!(result.value.is_null());
+        assert_eq!(take_error(result.error).as_deref(), Some("tokenizer passed is null"));
+
+        let handle = TestHandle::new(BYTE_LEVEL_BPE);
+        let stream: *mut DecodeStream = unsafe { decode_stream_new(handle.0, false) }.value.cast();
+        drop(handle);  // The stream holds its own reference.
+        let step = |id| take_text(unsafe { decode_stream_step(stream, id) });
+        assert_eq!(step(11).as_deref(), Some("hello"));
+        assert_eq!(step(18), None);
+        assert_eq!(step(17).as_deref(), Some("\u{FFFD} world"));
+        unsafe { decode_stream_free(stream) };
+    }
+
+    #[test]
+    fn decode_stream_flush_keeps_context() {
+        let handle = TestHandle::new(BYTE_FALLBACK_BPE);
+        let stream: *mut DecodeStream = unsafe { decode_stream_new(handle.0, false) }.value.cast();
+        let step = |id| take_text(unsafe { decode_stream_step(stream, id) });
+        assert_eq!(step(4).as_deref(), Some("hello"));
+        assert_eq!(step(1), None);
+        assert_eq!(take_text(unsafe { decode_stream_flush(stream) }).as_deref(), Some("\u{FFFD}"));
+        assert_eq!(take_text(unsafe { decode_stream_flush(stream) }), None);
+        assert_eq!(step(5).as_deref(), Some(" world"));
+        unsafe { decode_stream_free(stream) };
+    }
+}
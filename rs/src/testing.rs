@@ -0,0 +1,222 @@
+//! Helpers for the unit tests: small tokenizers defined inline, loaded as handles through the exported functions.
+
+use std::ffi::{c_char, CStr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::encode::{encode_str_impl, free_buffer, EncodeParams, ENCODE_PARAMS_VERSION};
+use crate::handle::TokenizerHandle;
+use crate::{free_bytes, free_string, free_tokenizer, from_bytes, BytesOrError};
+
+/// A WordPiece tokenizer splitting on whitespace and punctuation, with the special token `<|im_end|>`.
+pub(crate) const WORDPIECE: &str = r###"{
+    "version": "1.0",
+    "truncation": null,
+    "padding": null,
+    "added_tokens": [
+        {"id": 5, "content": "<|im_end|>", "single_word": false, "lstrip": false, "rstrip": false,
+         "normalized": false, "special": true}
+    ],
+    "normalizer": null,
+    "pre_tokenizer": {"type": "Whitespace"},
+    "post_processor": null,
+    "decoder": {"type": "WordPiece", "prefix": "##", "cleanup": true},
+    "model": {
+        "type": "WordPiece", "unk_token": "[UNK]", "continuing_subword_prefix": "##",
+        "max_input_chars_per_word": 100,
+        "vocab": {"[UNK]": 0, "a": 1, "b": 2, "##b": 3, "hello": 4, "<|im_end|>": 5, "world": 6, "<|": 7, "|>": 8,
+                  "im_end": 9}
+    }
+}"###;
+
+/// WORDPIECE with the special tokens `[CLS]` and `[SEP]` added by its post-processor, as BERT's: `[CLS] $A [SEP]`
+/// for single texts, and `[CLS] $A [SEP] $B [SEP]` (with type id 1 for `$B [SEP]`) for pairs.
+pub(crate) const WORDPIECE_BERT: &str = r###"{
+    "version": "1.0",
+    "truncation": null,
+    "padding": null,
+    "added_tokens": [
+        {"id": 5, "content": "<|im_end|>", "single_word": false, "lstrip": false, "rstrip": false,
+         "normalized": false, "special": true},
+        {"id": 10, "content": "[CLS]", "single_word": false, "lstrip": false, "rstrip": false,
+         "normalized": false, "special": true},
+        {"id": 11, "content": "[SEP]", "single_word": false, "lstrip": false, "rstrip": false,
+         "normalized": false, "special": true}
+    ],
+    "normalizer": null,
+    "pre_tokenizer": {"type": "Whitespace"},
+    "post_processor": {
+        "type": "TemplateProcessing",
+        "single": [{"SpecialToken": {"id": "[CLS]", "type_id": 0}}, {"Sequence": {"id": "A", "type_id": 0}},
+                   {"SpecialToken": {"id": "[SEP]", "type_id": 0}}],
+        "pair": [{"SpecialToken": {"id": "[CLS]", "type_id": 0}}, {"Sequence": {"id": "A", "type_id": 0}},
+                 {"SpecialToken": {"id": "[SEP]", "type_id": 0}}, {"Sequence": {"id": "B", "type_id": 1}},
+                 {"SpecialToken": {"id": "[SEP]", "type_id": 1}}],
+        "special_tokens": {
+            "[CLS]": {"id": "[CLS]", "ids": [10], "tokens": ["[CLS]"]},
+            "[SEP]": {"id": "[SEP]", "ids": [11], "tokens": ["[SEP]"]}
+        }
+    },
+    "decoder": {"type": "WordPiece", "prefix": "##", "cleanup": true},
+    "model": {
+        "type": "WordPiece", "unk_token": "[UNK]", "continuing_subword_prefix": "##",
+        "max_input_chars_per_word": 100,
+        "vocab": {"[UNK]": 0, "a": 1, "b": 2, "##b": 3, "hello": 4, "<|im_end|>": 5, "world": 6, "<|": 7, "|>": 8,
+                  "im_end": 9, "[CLS]": 10, "[SEP]": 11}
+    }
+}"###;
+
+/// A byte-level BPE tokenizer (as GPT-2's), with the bytes of `"hello world"` (and the byte 0xE2) and some
+/// merges.
+pub(crate) const BYTE_LEVEL_BPE: &str = r###"{
+    "version": "1.0",
+    "truncation": null,
+    "padding": null,
+    "added_tokens": [
+        {"id": 12, "content": "<|endoftext|>", "single_word": false, "lstrip": false, "rstrip": false,
+         "normalized": false, "special": true}
+    ],
+    "normalizer": null,
+    "pre_tokenizer": {"type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": true},
+    "post_processor": null,
+    "decoder": {"type": "ByteLevel", "add_prefix_space": true, "trim_offsets": true, "use_regex": true},
+    "model": {
+        "type": "BPE", "dropout": null, "unk_token": null, "continuing_subword_prefix": null,
+        "end_of_word_suffix": null, "fuse_unk": false, "byte_fallback": false, "ignore_merges": false,
+        "vocab": {"h": 0, "e": 1, "l": 2, "o": 3, "Ġ": 4, "w": 5, "r": 6, "d": 7, "he": 8, "ll": 9, "hell": 10,
+                  "hello": 11, "<|endoftext|>": 12, "Ġw": 13, "or": 14, "Ġwor": 15, "Ġworl": 16, "Ġworld": 17, "â": 18},
+        "merges": ["h e", "l l", "he ll", "hell o", "Ġ w", "o r", "Ġw or", "Ġwor l", "Ġworl d"]
+    }
+}"###;
+
+/// A Llama-style BPE tokenizer, with byte fallback tokens and a decoder that strips the leading space: only the
+/// vocabulary is defined, for the decoding tests.
+pub(crate) const BYTE_FALLBACK_BPE: &str = r###"{
+    "version": "1.0",
+    "truncation": null,
+    "padding": null,
+    "added_tokens": [],
+    "normalizer": {"type": "Sequence", "normalizers": [
+        {"type": "Prepend", "prepend": "▁"},
+        {"type": "Replace", "pattern": {"String": " "}, "content": "▁"}
+    ]},
+    "pre_tokenizer": null,
+    "post_processor": null,
+    "decoder": {"type": "Sequence", "decoders": [
+        {"type": "Replace", "pattern": {"String": "▁"}, "content": " "},
+        {"type": "ByteFallback"},
+        {"type": "Fuse"},
+        {"type": "Strip", "content": " ", "start": 1, "stop": 0}
+    ]},
+    "model": {
+        "type": "BPE", "dropout": null, "unk_token": "<unk>", "continuing_subword_prefix": null,
+        "end_of_word_suffix": null, "fuse_unk": true, "byte_fallback": true, "ignore_merges": false,
+        "vocab": {"<unk>": 0, "<0xE2>": 1, "<0x82>": 2, "<0xAC>": 3, "▁hello": 4, "▁world": 5},
+        "merges": []
+    }
+}"###;
+
+/// A Unigram tokenizer splitting on whitespace, where "ab" is more likely than "a" followed by "b".
+pub(crate) const UNIGRAM: &str = r###"{
+    "version": "1.0",
+    "truncation": null,
+    "padding": null,
+    "added_tokens": [],
+    "normalizer": null,
+    "pre_tokenizer": {"type": "WhitespaceSplit"},
+    "post_processor": null,
+    "decoder": null,
+    "model": {
+        "type": "Unigram", "unk_id": 0, "byte_fallback": false,
+        "vocab": [["<unk>", 0.0], ["a", -1.0], ["b", -2.0], ["ab", -1.5], ["c", -3.0]]
+    }
+}"###;
+
+/// A SentencePiece-style Unigram tokenizer, as T5's: the Metaspace pre-tokenizer replaces the spaces by "▁" and
+/// prepends one to the text (the dummy prefix). The post-processor inserts the BOS token `<s>`.
+pub(crate) const SENTENCEPIECE: &str = r###"{
+    "version": "1.0",
+    "truncation": null,
+    "padding": null,
+    "added_tokens": [
+        {"id": 1, "content": "<s>", "single_word": false, "lstrip": false, "rstrip": false,
+         "normalized": false, "special": true},
+        {"id": 2, "content": "</s>", "single_word": false, "lstrip": false, "rstrip": false,
+         "normalized": false, "special": true}
+    ],
+    "normalizer": null,
+    "pre_tokenizer": {"type": "Metaspace", "replacement": "▁", "prepend_scheme": "always", "split": true},
+    "post_processor": {
+        "type": "TemplateProcessing",
+        "single": [{"SpecialToken": {"id": "<s>", "type_id": 0}}, {"Sequence": {"id": "A", "type_id": 0}}],
+        "pair": [{"SpecialToken": {"id": "<s>", "type_id": 0}}, {"Sequence": {"id": "A", "type_id": 0}},
+                 {"SpecialToken": {"id": "<s>", "type_id": 1}}, {"Sequence": {"id": "B", "type_id": 1}}],
+        "special_tokens": {"<s>": {"id": "<s>", "ids": [1], "tokens": ["<s>"]}}
+    },
+    "decoder": {"type": "Metaspace", "replacement": "▁", "prepend_scheme": "always", "split": true},
+    "model": {
+        "type": "Unigram", "unk_id": 0, "byte_fallback": false,
+        "vocab": [["<unk>", 0.0], ["<s>", 0.0], ["</s>", 0.0], ["▁hello", -1.0], ["▁world", -1.0], ["hello", -2.0],
+                  ["▁", -3.0]]
+    }
+}"###;
+
+/// TestHandle owns a handle to a tokenizer, released when dropped.
+pub(crate) struct TestHandle(pub(crate) *mut TokenizerHandle);
+
+impl TestHandle {
+    /// Loads the tokenizer defined by the `tokenizer.json` contents `json`, panicking on error.
+    pub(crate) fn new(json: &str) -> TestHandle {
+        let result = unsafe { from_bytes(json.as_ptr(), json.len() as u32) };
+        if let Some(e) = take_error(result.error) {
+            panic!("failed to load tokenizer: {}", e);
+        }
+        TestHandle(result.value.cast())
+    }
+}
+
+impl Drop for TestHandle {
+    fn drop(&mut self) {
+        unsafe { free_tokenizer(self.0) };
+    }
+}
+
+/// Returns the message of the `error` returned by an exported function (freeing it), or None if it is null.
+pub(crate) fn take_error(error: *mut c_char) -> Option<String> {
+    if error.is_null() {
+        return None;
+    }
+    let message = unsafe { CStr::from_ptr(error) }.to_string_lossy().into_owned();
+    unsafe { free_string(error) };
+    Some(message)
+}
+
+/// Returns the bytes of a `BytesOrError` returned by an exported function (freeing it), or its error message.
+pub(crate) fn take_bytes(bytes: BytesOrError) -> Result<Vec<u8>, String> {
+    let result = match take_error(bytes.error) {
+        Some(e) => Err(e),
+        None if bytes.data.is_null() => Ok(Vec::new()),
+        None => Ok(unsafe { std::slice::from_raw_parts(bytes.data, bytes.len as usize) }.to_vec()),
+    };
+    unsafe { free_bytes(BytesOrError { error: std::ptr::null_mut(), ..bytes }) };
+    result
+}
+
+/// Returns the ids of the encoding of `text` by `encode_str_impl` (as `encode`), with the given flags.
+pub(crate) fn encode_ids(handle: &TestHandle, text: &str, flags: u64) -> Vec<u32> {
+    let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags };
+    let buffers = encode_str_impl(handle.0, text, options).unwrap();
+    let ids = buffers.iter().flat_map(|b| unsafe { std::slice::from_raw_parts(b.ids, b.len as usize) }.to_vec());
+    let ids = ids.collect();
+    buffers.into_iter().for_each(free_buffer);
+    ids
+}
+
+/// Returns a new empty directory, under the temporary directory of the system, for the files of a test.
+pub(crate) fn temp_dir() -> PathBuf {
+    static DIRS: AtomicU64 = AtomicU64::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "gomlx_tokenizers_test_{}_{}", std::process::id(), DIRS.fetch_add(1, Ordering::Relaxed)));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
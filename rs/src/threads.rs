@@ -0,0 +1,161 @@
+//! Thread policy: the batch calls encode in parallel with the tokenizers crate (using rayon), by default on a
+//! global pool with one thread per CPU. `set_thread_policy` replaces it with a pool of limited size, optionally
+//! pinned to a subset of the CPUs, so tokenization can share the machine with other work (e.g. inference).
+
+use std::error::Error;
+use std::ffi::c_char;
+use std::ptr::null_mut;
+use std::sync::{Arc, RwLock};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use crate::encode::err;
+use crate::panics::{catch_panic, error_c_string};
+
+/// ThreadPolicy configures the threads used by the batch calls, see `set_thread_policy`.
+#[repr(C)]
+pub struct ThreadPolicy {
+    /// Maximum number of threads used to encode a batch. Zero means one per CPU (or one per CPU in `cpus`,
+    /// if given).
+    pub max_threads: u32,
+
+    /// If not null, the `num_cpus` CPUs (as numbered by the OS) the threads are pinned to. Only supported on Linux.
+    pub cpus: *const u32,
+    pub num_cpus: u32,
+}
+
+// The pool set by `set_thread_policy`, if any.
+static POOL: RwLock<Option<Arc<ThreadPool>>> = RwLock::new(None);
+
+// install runs `f` in the pool set by `set_thread_policy` (so the parallel work of the tokenizers crate is done
+// by its threads), or in the current thread (using the global pool) if none was set.
+pub(crate) fn install<R: Send>(f: impl FnOnce() -> R + Send) -> R {
+    let pool = POOL.read().unwrap_or_else(|e| e.into_inner()).clone();
+    match pool {
+        Some(pool) => pool.install(f),
+        None => f(),
+    }
+}
+
+// pin_current_thread pins the calling thread to the `cpus`.
+#[cfg(target_os = "linux")]
+fn pin_current_thread(cpus: &[usize]) -> Result<(), Box<dyn Error>> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &cpu in cpus {
+            libc::CPU_SET(cpu, &mut set);
+        }
+        if libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(err(format!("failed to pin thread to CPUs {:?}: {}", cpus, std::io::Error::last_os_error())));
+        }
+    }
+    Ok(())
+}
+
+// validate_cpus checks the CPUs can be used by this process.
+#[cfg(target_os = "linux")]
+fn validate_cpus(cpus: &[usize]) -> Result<(), Box<dyn Error>> {
+    let allowed = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return Err(err(format!("failed to get the CPUs of the process: {}", std::io::Error::last_os_error())));
+        }
+        set
+    };
+    let max_cpus = 8 * size_of::<libc::cpu_set_t>();
+    for &cpu in cpus {
+        if cpu >= max_cpus || !unsafe { libc::CPU_ISSET(cpu, &allowed) } {
+            return Err(err(format!("CPU {} is not available to this process", cpu)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_current_thread(_cpus: &[usize]) -> Result<(), Box<dyn Error>> {
+    Err(err("pinning threads to CPUs is only supported on Linux"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn validate_cpus(_cpus: &[usize]) -> Result<(), Box<dyn Error>> {
+    Err(err("pinning threads to CPUs is only supported on Linux"))
+}
+
+fn set_thread_policy_impl(policy: Option<&ThreadPolicy>) -> Result<(), Box<dyn Error>> {
+    let policy = match policy {
+        Some(policy) if policy.max_threads != 0 || !policy.cpus.is_null() => policy,
+        _ => {
+            *POOL.write().unwrap_or_else(|e| e.into_inner()) = None;
+            return Ok(());
+        }
+    };
+    let cpus: Vec<usize> = if policy.cpus.is_null() || policy.num_cpus == 0 {
+        Vec::new()
+    } else {
+        unsafe { std::slice::from_raw_parts(policy.cpus, policy.num_cpus as usize) }
+            .iter()
+            .map(|&cpu| cpu as usize)
+            .collect()
+    };
+    let mut builder = ThreadPoolBuilder::new().thread_name(|i| format!("tokenizers-worker-{}", i));
+    let num_threads = match policy.max_threads {
+        0 => cpus.len(),
+        max_threads => max_threads as usize,
+    };
+    builder = builder.num_threads(num_threads);
+    if !cpus.is_empty() {
+        validate_cpus(&cpus)?;
+        // Validated above, so pinning is not expected to fail: if it does, the thread runs unpinned.
+        builder = builder.start_handler(move |_| {
+            let _ = pin_current_thread(&cpus);
+        });
+    }
+    let pool = builder.build().map_err(|e| err(format!("failed to create the thread pool: {}", e)))?;
+    *POOL.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(pool));
+    Ok(())
+}
+
+/// set_thread_policy configures the threads used to encode batches from now on: at most `max_threads` threads,
+/// optionally pinned to the given CPUs. A null `policy` (or one with no limit and no CPUs) restores the
+/// default, one thread per CPU in a global pool.
+///
+/// The threads are shared by all the batch calls, including concurrent ones, so they also cap the total number
+/// of threads used for tokenization. Calls already running keep the previous threads.
+///
+/// It returns null if ok, or a string with an error message (owned by caller), to be freed with `free_string`.
+///
+/// # Safety
+///
+/// `policy` must be null or point to a ThreadPolicy, whose `cpus` (if not null) has `num_cpus` values.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("set_thread_policy"))]
+pub unsafe extern "C" fn set_thread_policy(policy: *const ThreadPolicy) -> *mut c_char {
+    match catch_panic(|| set_thread_policy_impl(unsafe { policy.as_ref() })) {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::take_error;
+
+    #[test]
+    fn thread_policy() {
+        let policy = ThreadPolicy { max_threads: 2, cpus: std::ptr::null(), num_cpus: 0 };
+        assert_eq!(take_error(unsafe { set_thread_policy(&policy) }), None);
+        let (num_threads, name) =
+            install(|| (rayon::current_num_threads(), std::thread::current().name().map(str::to_string)));
+        assert_eq!(num_threads, 2);
+        assert!(name.unwrap().starts_with("tokenizers-worker-"));
+
+        // An invalid policy keeps the current one.
+        let cpus = [u32::MAX];
+        let policy = ThreadPolicy { max_threads: 0, cpus: cpus.as_ptr(), num_cpus: 1 };
+        assert!(take_error(unsafe { set_thread_policy(&policy) }).is_some());
+        assert_eq!(install(rayon::current_num_threads), 2);
+
+        assert_eq!(take_error(unsafe { set_thread_policy(std::ptr::null()) }), None);
+        assert!(POOL.read().unwrap().is_none());
+        assert_eq!(install(|| std::thread::current().id()), std::thread::current().id());
+    }
+}
@@ -0,0 +1,131 @@
+//! Slow-input tracing: the texts that take too long to encode, or that encode to too many tokens, are reported
+//! to a host callback (see `set_slow_input_hook`), to find the inputs that stall the batches.
+
+use crate::panics::catch_panic_or;
+use std::ffi::c_void;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use tokenizers::Encoding;
+
+/// SlowInput describes a text reported to the slow-input hook.
+#[repr(C)]
+pub struct SlowInput {
+    /// Length of the text in bytes.
+    len: u64,
+    /// Number of tokens the text was encoded to (before padding).
+    num_tokens: u64,
+    /// Time it took to encode the text.
+    duration_ns: u64,
+    /// The first `sample_len` bytes of the text (UTF-8, truncated at a character boundary): only valid during
+    /// the callback.
+    sample: *const u8,
+    sample_len: u64,
+}
+
+/// SlowInputCallback is called (from the thread encoding the text) with each slow input, and the `user_data`
+/// given to `set_slow_input_hook`.
+pub type SlowInputCallback = Option<unsafe extern "C" fn(user_data: *mut c_void, input: *const SlowInput)>;
+
+// SlowInputHook is the configuration set by `set_slow_input_hook`.
+struct SlowInputHook {
+    callback: unsafe extern "C" fn(user_data: *mut c_void, input: *const SlowInput),
+    user_data: *mut c_void,
+    min_duration: Option<Duration>,
+    min_tokens: Option<usize>,
+    max_sample_len: usize,
+}
+
+// The host is responsible for the callback (and its user data) being usable from any thread.
+unsafe impl Send for SlowInputHook {}
+unsafe impl Sync for SlowInputHook {}
+
+static HOOK: RwLock<Option<SlowInputHook>> = RwLock::new(None);
+
+// enabled returns whether a slow-input hook is set, in which case the encode calls must `check` each text.
+pub(crate) fn enabled() -> bool {
+    HOOK.read().unwrap_or_else(|e| e.into_inner()).is_some()
+}
+
+// check reports the `text`, whose encoding (started at `start`) is `encoding`, to the slow-input hook if it
+// crosses one of its thresholds.
+pub(crate) fn check(text: &str, start: Instant, encoding: &Encoding) {
+    let duration = start.elapsed();
+    let hook = HOOK.read().unwrap_or_else(|e| e.into_inner());
+    let hook = match hook.as_ref() {
+        Some(hook) => hook,
+        None => return,
+    };
+    let num_tokens = encoding.len();
+    let slow = hook.min_duration.is_some_and(|min_duration| duration >= min_duration)
+        || hook.min_tokens.is_some_and(|min_tokens| num_tokens >= min_tokens);
+    if !slow {
+        return;
+    }
+    let mut sample_len = hook.max_sample_len.min(text.len());
+    while !text.is_char_boundary(sample_len) {
+        sample_len -= 1;
+    }
+    let input = SlowInput {
+        len: text.len() as u64,
+        num_tokens: num_tokens as u64,
+        duration_ns: u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX),
+        sample: text.as_ptr(),
+        sample_len: sample_len as u64,
+    };
+    unsafe { (hook.callback)(hook.user_data, &input) };
+}
+
+/// set_slow_input_hook sets the `callback` called with each text, encoded by the encode calls, that took at
+/// least `min_duration_us` microseconds to encode, or that encoded to at least `min_tokens` tokens -- a zero
+/// threshold is disabled. At most `max_sample_len` bytes of the text are passed to the callback.
+///
+/// A null `callback` removes the hook. The hook applies to all tokenizers, and while it is set the texts of
+/// a batch are timed one by one.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("set_slow_input_hook"))]
+pub extern "C" fn set_slow_input_hook(
+    callback: SlowInputCallback,
+    user_data: *mut c_void,
+    min_duration_us: u64,
+    min_tokens: u64,
+    max_sample_len: u32,
+) {
+    catch_panic_or((), || {
+        let hook = callback.map(|callback| SlowInputHook {
+            callback,
+            user_data,
+            min_duration: (min_duration_us > 0).then(|| Duration::from_micros(min_duration_us)),
+            min_tokens: (min_tokens > 0).then(|| usize::try_from(min_tokens).unwrap_or(usize::MAX)),
+            max_sample_len: max_sample_len as usize,
+        });
+        *HOOK.write().unwrap_or_else(|e| e.into_inner()) = hook;
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use crate::testing::{encode_ids, TestHandle, WORDPIECE};
+
+    const TEXT: &str = "hello world world";
+
+    // count_text counts, in the AtomicU64 `user_data`, the slow inputs that are TEXT.
+    unsafe extern "C" fn count_text(user_data: *mut c_void, input: *const SlowInput) {
+        let input = unsafe { &*input };
+        let sample = unsafe { std::slice::from_raw_parts(input.sample, input.sample_len as usize) };
+        if sample == TEXT.as_bytes() && input.num_tokens == 3 {
+            unsafe { &*user_data.cast::<AtomicU64>() }.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn trace_single_encode() {
+        let handle = TestHandle::new(WORDPIECE);
+        static COUNT: AtomicU64 = AtomicU64::new(0);
+        set_slow_input_hook(Some(count_text), (&COUNT as *const AtomicU64).cast_mut().cast(), 0, 3, 1024);
+        encode_ids(&handle, TEXT, 0);
+        set_slow_input_hook(None, std::ptr::null_mut(), 0, 0, 0);
+        assert_eq!(COUNT.load(Ordering::Relaxed), 1);
+    }
+}
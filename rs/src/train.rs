@@ -0,0 +1,484 @@
+//! Training: new tokenizers trained from the files of a corpus (see `train_from_files`), and continued
+//! training, extending the vocabulary of an existing BPE tokenizer with merges learned from a domain corpus,
+//! keeping the ids of the original tokens.
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::ffi::{c_char, CStr};
+use serde_json::{json, Value};
+use tokenizers::decoders::wordpiece::WordPiece as WordPieceDecoder;
+use tokenizers::models::bpe::{BpeTrainer, BPE};
+use tokenizers::models::unigram::{Unigram, UnigramTrainer};
+use tokenizers::models::wordlevel::{WordLevel, WordLevelTrainer};
+use tokenizers::models::wordpiece::{WordPiece, WordPieceTrainer};
+use tokenizers::models::{ModelWrapper, TrainerWrapper};
+use tokenizers::pre_tokenizers::whitespace::Whitespace;
+use tokenizers::tokenizer::{Model, OffsetReferential, OffsetType, PreTokenizer, Tokenizer};
+use tokenizers::AddedToken;
+use crate::encode::{err, messages_from_bytes};
+use crate::handle::{convert_to_handle_ref, TokenizerHandle};
+use crate::load::new_handle;
+use crate::panics::catch_panic;
+use crate::PointerOrError;
+
+/// VocabExtensionParams are the options of `extend_vocabulary`.
+#[repr(C)]
+pub struct VocabExtensionParams {
+    num_merges: u32,  // Number of merges (new tokens) to learn, at most.
+    min_frequency: u32,  // Minimum number of occurrences of a pair to be merged, default (0) is 2.
+}
+
+// Word is a distinct pre-token of the corpus, as a sequence of symbols (indexes in `Symbols`), with its count.
+struct Word {
+    symbols: Vec<u32>,
+    count: u64,
+}
+
+// Symbols interns the token strings of the words.
+#[derive(Default)]
+struct Symbols {
+    strings: Vec<String>,
+    indexes: HashMap<String, u32>,
+}
+
+impl Symbols {
+    fn intern(&mut self, symbol: &str) -> u32 {
+        if let Some(&index) = self.indexes.get(symbol) {
+            return index;
+        }
+        let index = self.strings.len() as u32;
+        self.strings.push(symbol.to_string());
+        self.indexes.insert(symbol.to_string(), index);
+        index
+    }
+}
+
+// corpus_words returns the distinct pre-tokens of the `texts`, tokenized by the current model. The added tokens
+// are skipped: they are never merged.
+fn corpus_words(tokenizer: &Tokenizer, texts: &[&str], symbols: &mut Symbols) -> Result<Vec<Word>, Box<dyn Error>> {
+    let mut counts: HashMap<String, u64> = HashMap::new();
+    for text in texts {
+        let mut pre_tokenized = tokenizer
+            .get_added_vocabulary()
+            .extract_and_normalize(tokenizer.get_normalizer(), text);
+        if let Some(pre_tokenizer) = tokenizer.get_pre_tokenizer() {
+            pre_tokenizer
+                .pre_tokenize(&mut pre_tokenized)
+                .map_err(|e| err(format!("pre-tokenization failed: {}", e)))?;
+        }
+        for (word, _, tokens) in pre_tokenized.get_splits(OffsetReferential::Original, OffsetType::None) {
+            if tokens.is_none() && !word.is_empty() {
+                *counts.entry(word.to_string()).or_default() += 1;
+            }
+        }
+    }
+    let mut words = Vec::with_capacity(counts.len());
+    for (word, count) in counts {
+        let tokens = tokenizer
+            .get_model()
+            .tokenize(&word)
+            .map_err(|e| err(format!("tokenization failed: {}", e)))?;
+        words.push(Word {
+            symbols: tokens.iter().map(|token| symbols.intern(&token.value)).collect(),
+            count,
+        });
+    }
+    Ok(words)
+}
+
+// learn_merges returns up to `num_merges` merges learned from the `words`, most frequent pair first (ties are
+// broken by the pair strings, so it is deterministic), as the BPE trainer does -- but starting from the
+// segmentation of the current model. The `words` are merged as the merges are learned.
+fn learn_merges(
+    words: &mut [Word],
+    symbols: &mut Symbols,
+    num_merges: usize,
+    min_frequency: u64,
+    continuing_subword_prefix: Option<&str>,
+) -> Vec<(u32, u32, u32)> {
+    let mut merges = Vec::new();
+    while merges.len() < num_merges {
+        let mut pair_counts: HashMap<(u32, u32), u64> = HashMap::new();
+        for word in words.iter() {
+            for pair in word.symbols.windows(2) {
+                *pair_counts.entry((pair[0], pair[1])).or_default() += word.count;
+            }
+        }
+        let best = pair_counts
+            .into_iter()
+            .filter(|&(_, count)| count >= min_frequency)
+            .max_by(|(a, a_count), (b, b_count)| {
+                a_count.cmp(b_count).then_with(|| {
+                    let a = (&symbols.strings[a.0 as usize], &symbols.strings[a.1 as usize]);
+                    let b = (&symbols.strings[b.0 as usize], &symbols.strings[b.1 as usize]);
+                    b.cmp(&a)
+                })
+            });
+        let Some(((left, right), _)) = best else {
+            break;
+        };
+        let right_string = &symbols.strings[right as usize];
+        let right_string = continuing_subword_prefix
+            .and_then(|prefix| right_string.strip_prefix(prefix))
+            .unwrap_or(right_string);
+        let merged = format!("{}{}", symbols.strings[left as usize], right_string);
+        let merged = symbols.intern(&merged);
+        for word in words.iter_mut() {
+            let mut i = 0;
+            while i + 1 < word.symbols.len() {
+                if word.symbols[i] == left && word.symbols[i + 1] == right {
+                    word.symbols[i] = merged;
+                    word.symbols.remove(i + 1);
+                }
+                i += 1;
+            }
+        }
+        merges.push((left, right, merged));
+    }
+    merges
+}
+
+fn extend_vocabulary_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_messages: usize,
+    messages: *const *const u8,
+    lengths: *const u32,
+    params: *const VocabExtensionParams,
+) -> Result<Tokenizer, Box<dyn Error>> {
+    let params = unsafe { params.as_ref() }.ok_or_else(|| err("vocabulary extension params is null"))?;
+    let min_frequency = match params.min_frequency {
+        0 => 2,
+        min_frequency => u64::from(min_frequency),
+    };
+    let texts = messages_from_bytes(num_messages, messages, lengths)?;
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let mut tokenizer = Tokenizer::clone(&handle.read().tokenizer);
+    let bpe = match tokenizer.get_model() {
+        ModelWrapper::BPE(bpe) => bpe.clone(),
+        _ => return Err(err("only BPE tokenizers can be extended")),
+    };
+
+    let mut symbols = Symbols::default();
+    let mut words = corpus_words(&tokenizer, &texts, &mut symbols)?;
+    let merges = learn_merges(
+        &mut words,
+        &mut symbols,
+        params.num_merges as usize,
+        min_frequency,
+        bpe.continuing_subword_prefix.as_deref(),
+    );
+
+    // The new tokens get ids after all the current ones (including the added tokens), so these are kept.
+    let mut model = serde_json::to_value(&bpe)?;
+    let mut next_id = tokenizer.get_vocab(true).values().max().map_or(0, |&id| id + 1);
+    for (left, right, merged) in merges {
+        let [left, right, merged] = [left, right, merged].map(|symbol| symbols.strings[symbol as usize].as_str());
+        if model["vocab"].get(merged).is_none() {
+            model["vocab"][merged] = json!(next_id);
+            next_id += 1;
+        }
+        if let Some(Value::Array(model_merges)) = model.get_mut("merges") {
+            model_merges.push(json!([left, right]));
+        }
+    }
+    let bpe: BPE = serde_json::from_str(&model.to_string()).map_err(|e| err(format!("failed to build extended model: {}", e)))?;
+    tokenizer.with_model(bpe);
+    Ok(tokenizer)
+}
+
+/// extend_vocabulary returns a new tokenizer, in the `value` field, with the vocabulary of the BPE tokenizer
+/// extended with up to `params.num_merges` merges (and their tokens) learned from a domain corpus: the
+/// `num_messages` UTF-8 strings (given as in `encode_batch_bytes`). The merges are learned as the BPE trainer
+/// does, but starting from the segmentation of the texts by the current model, and they are applied after
+/// the original merges. The original tokens keep their ids, and the new ones get the ids after them.
+///
+/// Only the pairs occurring at least `params.min_frequency` times are merged, so fewer merges may be learned.
+/// The original tokenizer is not modified. It is an error if the model is not BPE.
+///
+/// The caller owns the returned tokenizer, and should free it with `free_tokenizer`.
+///
+/// # Safety
+///
+/// `messages` and `lengths` must point to `num_messages` strings and lengths, and `params` to a
+/// VocabExtensionParams.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("extend_vocabulary"))]
+pub unsafe extern "C" fn extend_vocabulary(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_messages: u32,
+    messages: *const *const u8,
+    lengths: *const u32,
+    params: *const VocabExtensionParams,
+) -> PointerOrError {
+    new_handle(catch_panic(|| extend_vocabulary_impl(tokenizer_ptr, num_messages as usize, messages, lengths, params)))
+}
+
+/// Model type of TrainParams: Byte-Pair Encoding.
+pub const TRAIN_MODEL_BPE: u32 = 0;
+/// Model type of TrainParams: WordPiece.
+pub const TRAIN_MODEL_WORDPIECE: u32 = 1;
+/// Model type of TrainParams: Unigram.
+pub const TRAIN_MODEL_UNIGRAM: u32 = 2;
+/// Model type of TrainParams: WordLevel.
+pub const TRAIN_MODEL_WORDLEVEL: u32 = 3;
+
+/// TrainParams are the options of `train_from_files`.
+#[repr(C)]
+pub struct TrainParams {
+    model: u32,  // One of the TRAIN_MODEL_* types.
+    vocab_size: u32,  // Target size of the vocabulary, including the special tokens; 0 for the trainer's default.
+    min_frequency: u32,  // Minimum number of occurrences of a merged pair (or word, for WordLevel); ignored by Unigram.
+    special_tokens: *const *const c_char,  // The `num_special_tokens` special tokens, added first to the vocabulary.
+    num_special_tokens: u32,
+    initial_alphabet: *const c_char,  // If not null, characters always included in the vocabulary (not WordLevel).
+    limit_alphabet: u32,  // Maximum number of different characters kept (BPE and WordPiece); 0 for no limit.
+    unk_token: *const c_char,  // If not null, the token of the unknown words, usually one of the special tokens.
+}
+
+// c_str returns the UTF-8 string at `ptr`, or None if it is null.
+fn c_str<'a>(ptr: *const c_char, name: &str) -> Result<Option<&'a str>, Box<dyn Error>> {
+    if ptr.is_null() {
+        return Ok(None);
+    }
+    let string = unsafe { CStr::from_ptr(ptr) }.to_str().map_err(|e| err(format!("{} is not valid UTF-8: {}", name, e)))?;
+    Ok(Some(string))
+}
+
+// trainer returns the untrained model and the trainer configured by the `params`.
+fn trainer(params: &TrainParams) -> Result<(ModelWrapper, TrainerWrapper), Box<dyn Error>> {
+    let mut special_tokens = Vec::with_capacity(params.num_special_tokens as usize);
+    for index in 0..params.num_special_tokens as usize {
+        let token = c_str(unsafe { *params.special_tokens.add(index) }, "special token")?
+            .ok_or_else(|| err(format!("special token #{} is null", index)))?;
+        special_tokens.push(AddedToken::from(token.to_string(), true));
+    }
+    let initial_alphabet: HashSet<char> = c_str(params.initial_alphabet, "initial_alphabet")?
+        .map(|alphabet| alphabet.chars().collect())
+        .unwrap_or_default();
+    let unk_token = c_str(params.unk_token, "unk_token")?.map(str::to_string);
+    let vocab_size = (params.vocab_size > 0).then_some(params.vocab_size);
+    let min_frequency = (params.min_frequency > 0).then_some(u64::from(params.min_frequency));
+
+    // configure_merges_trainer sets the options of the builder of a BPE or WordPiece trainer, which have the same
+    // methods (the WordPiece trainer is a BPE trainer).
+    macro_rules! configure_merges_trainer {
+        ($builder:expr) => {{
+            let mut builder = $builder
+                .show_progress(false)
+                .special_tokens(special_tokens)
+                .initial_alphabet(initial_alphabet);
+            if let Some(vocab_size) = vocab_size {
+                builder = builder.vocab_size(vocab_size as usize);
+            }
+            if let Some(min_frequency) = min_frequency {
+                builder = builder.min_frequency(min_frequency);
+            }
+            if params.limit_alphabet > 0 {
+                builder = builder.limit_alphabet(params.limit_alphabet as usize);
+            }
+            builder
+        }};
+    }
+
+    match params.model {
+        TRAIN_MODEL_BPE => {
+            let trainer = configure_merges_trainer!(BpeTrainer::builder()).build();
+            let mut model = BPE::builder();
+            if let Some(unk_token) = unk_token {
+                model = model.unk_token(unk_token);
+            }
+            let model = model.build().map_err(|e| err(format!("failed to build BPE model: {}", e)))?;
+            Ok((model.into(), trainer.into()))
+        }
+        TRAIN_MODEL_WORDPIECE => {
+            let trainer = configure_merges_trainer!(WordPieceTrainer::builder()).build();
+            let mut model = WordPiece::builder();
+            if let Some(unk_token) = unk_token {
+                model = model.unk_token(unk_token);
+            }
+            let model = model.build().map_err(|e| err(format!("failed to build WordPiece model: {}", e)))?;
+            Ok((model.into(), trainer.into()))
+        }
+        TRAIN_MODEL_UNIGRAM => {
+            let mut trainer = UnigramTrainer::default();
+            trainer.show_progress = false;
+            trainer.special_tokens = special_tokens;
+            trainer.unk_token = unk_token;
+            trainer.initial_alphabet = initial_alphabet.into_iter().collect();
+            if let Some(vocab_size) = vocab_size {
+                trainer.vocab_size = vocab_size;
+            }
+            Ok((Unigram::default().into(), trainer.into()))
+        }
+        TRAIN_MODEL_WORDLEVEL => {
+            let mut trainer = WordLevelTrainer::builder();
+            trainer.show_progress(false).special_tokens(special_tokens);
+            if let Some(vocab_size) = vocab_size {
+                trainer.vocab_size(vocab_size as usize);
+            }
+            if let Some(min_frequency) = min_frequency {
+                trainer.min_frequency(min_frequency);
+            }
+            let trainer = trainer.build().map_err(|e| err(format!("failed to build WordLevel trainer: {}", e)))?;
+            let mut model = WordLevel::builder();
+            if let Some(unk_token) = unk_token {
+                model = model.unk_token(unk_token);
+            }
+            let model = model.build().map_err(|e| err(format!("failed to build WordLevel model: {}", e)))?;
+            Ok((model.into(), trainer.into()))
+        }
+        model => Err(err(format!("unknown model type {} to train", model))),
+    }
+}
+
+fn train_from_files_impl(
+    template_ptr: *mut TokenizerHandle,
+    params: *const TrainParams,
+    num_files: usize,
+    files: *const *const c_char,
+) -> Result<Tokenizer, Box<dyn Error>> {
+    let params = unsafe { params.as_ref() }.ok_or_else(|| err("train params is null"))?;
+    let mut paths = Vec::with_capacity(num_files);
+    for index in 0..num_files {
+        let path = c_str(unsafe { *files.add(index) }, "file path")?
+            .ok_or_else(|| err(format!("file path #{} is null", index)))?;
+        paths.push(path.to_string());
+    }
+    let (model, mut trainer) = trainer(params)?;
+    let is_wordpiece = matches!(model, ModelWrapper::WordPiece(_));
+    let mut tokenizer = Tokenizer::new(model);
+    if template_ptr.is_null() {
+        tokenizer.with_pre_tokenizer(Some(Whitespace {}));
+        if is_wordpiece {
+            tokenizer.with_decoder(Some(WordPieceDecoder::default()));
+        }
+    } else {
+        let handle = convert_to_handle_ref(template_ptr)?;
+        let state = handle.read();
+        tokenizer.with_normalizer(state.tokenizer.get_normalizer().cloned());
+        tokenizer.with_pre_tokenizer(state.tokenizer.get_pre_tokenizer().cloned());
+        tokenizer.with_decoder(state.tokenizer.get_decoder().cloned());
+    }
+    crate::threads::install(|| tokenizer.train_from_files(&mut trainer, paths).map(|_| ()))
+        .map_err(|e| err(format!("training failed: {}", e)))?;
+    Ok(tokenizer)
+}
+
+/// train_from_files trains a new tokenizer on the corpus in the `num_files` text files at the paths in
+/// `files` (one text per line), and returns it in the `value` field, or an error. The model type and the
+/// options of its trainer (see the trainers of the tokenizers library) are given in `params`.
+///
+/// The normalizer, pre-tokenizer and decoder of the new tokenizer are copied from the tokenizer at
+/// `template_ptr` (e.g. one loaded from a `tokenizer.json` with the desired pipeline), which is not modified.
+/// Its model, added tokens, post-processor, padding and truncation are not copied, since they refer to the ids of
+/// its vocabulary: the special tokens of `params` are added instead, and the other settings can be set on the new
+/// tokenizer (e.g. with `set_tokenizer_config`). If `template_ptr` is null, the pipeline only splits the words
+/// on whitespace and punctuation (and joins the WordPiece subwords back when decoding).
+///
+/// The call blocks until the training is completed, using the threads set with `set_thread_policy`.
+///
+/// The caller owns the returned tokenizer, and should free it with `free_tokenizer`.
+///
+/// # Safety
+///
+/// `params` must point to a TrainParams, and `files` to `num_files` C strings.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("train_from_files"))]
+pub unsafe extern "C" fn train_from_files(
+    template_ptr: *mut TokenizerHandle,
+    params: *const TrainParams,
+    num_files: u32,
+    files: *const *const c_char,
+) -> PointerOrError {
+    new_handle(catch_panic(|| train_from_files_impl(template_ptr, params, num_files as usize, files)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::ptr::{null, null_mut};
+    use crate::testing::{encode_ids, take_error, temp_dir, TestHandle, BYTE_LEVEL_BPE, WORDPIECE, WORDPIECE_BERT};
+
+    // extend extends the vocabulary of the tokenizer with up to `num_merges` merges learned from the `texts`.
+    fn extend(handle: &TestHandle, texts: &[&str], num_merges: u32) -> PointerOrError {
+        let messages: Vec<*const u8> = texts.iter().map(|t| t.as_ptr()).collect();
+        let lengths: Vec<u32> = texts.iter().map(|t| t.len() as u32).collect();
+        let params = VocabExtensionParams { num_merges, min_frequency: 0 };
+        unsafe { extend_vocabulary(handle.0, texts.len() as u32, messages.as_ptr(), lengths.as_ptr(), &params) }
+    }
+
+    #[test]
+    fn vocabulary_extension() {
+        let handle = TestHandle::new(BYTE_LEVEL_BPE);
+        assert_eq!(encode_ids(&handle, "do do", 0), [7, 3, 4, 7, 3]);
+        // "d o" occurs 4 times, then "Ġ do" twice: "do" and "Ġdo" get the ids after the 19 tokens. The other
+        // pairs occur less than twice.
+        let result = extend(&handle, &["do do", "do do", "ow"], 5);
+        assert_eq!(take_error(result.error), None);
+        let extended = TestHandle(result.value.cast());
+        assert_eq!(encode_ids(&extended, "do do", 0), [19, 20]);
+        assert_eq!(encode_ids(&extended, "hello world", 0), [11, 17]);
+        let vocab = unsafe { (*extended.0).read().tokenizer.get_vocab_size(true) };
+        assert_eq!(vocab, 21);
+        // The original tokenizer is not modified.
+        assert_eq!(encode_ids(&handle, "do do", 0), [7, 3, 4, 7, 3]);
+
+        let result = extend(&TestHandle::new(WORDPIECE), &["a"], 1);
+        assert_eq!(take_error(result.error).as_deref(), Some("only BPE tokenizers can be extended"));
+    }
+
+    // train trains a tokenizer of the `model` type on the `files`, with the special tokens "[UNK]" and "[CLS]".
+    fn train(template: Option<&TestHandle>, model: u32, files: &[CString]) -> Result<TestHandle, String> {
+        let special_tokens = [c"[UNK]".as_ptr(), c"[CLS]".as_ptr()];
+        let params = TrainParams {
+            model,
+            vocab_size: 0,
+            min_frequency: 0,
+            special_tokens: special_tokens.as_ptr(),
+            num_special_tokens: 2,
+            initial_alphabet: null(),
+            limit_alphabet: 0,
+            unk_token: c"[UNK]".as_ptr(),
+        };
+        let template = template.map_or(null_mut(), |handle| handle.0);
+        let paths: Vec<*const c_char> = files.iter().map(|file| file.as_ptr()).collect();
+        let result = unsafe { train_from_files(template, &params, paths.len() as u32, paths.as_ptr()) };
+        match take_error(result.error) {
+            Some(e) => Err(e),
+            None => Ok(TestHandle(result.value.cast())),
+        }
+    }
+
+    #[test]
+    fn training() {
+        let dir = temp_dir();
+        let corpus = dir.join("corpus.txt");
+        std::fs::write(&corpus, "hello world\nhello there\nhello world\n").unwrap();
+        let files = [CString::new(corpus.to_str().unwrap()).unwrap()];
+
+        // The special tokens come first, then the words by decreasing frequency.
+        let handle = train(None, TRAIN_MODEL_WORDLEVEL, &files).unwrap();
+        let tokenizer = |handle: &TestHandle| unsafe { (*handle.0).read() }.tokenizer.clone();
+        let ids = ["[UNK]", "[CLS]", "hello", "world", "there"].map(|token| tokenizer(&handle).token_to_id(token));
+        assert_eq!(ids, [Some(0), Some(1), Some(2), Some(3), Some(4)]);
+        assert_eq!(encode_ids(&handle, "hello moon", 0), [2, 0]);
+
+        // The pipeline of the template is copied, but not its vocabulary.
+        let template = TestHandle::new(WORDPIECE_BERT);
+        for model in [TRAIN_MODEL_BPE, TRAIN_MODEL_WORDPIECE, TRAIN_MODEL_UNIGRAM] {
+            let handle = train(Some(&template), model, &files).unwrap();
+            let tokenizer = tokenizer(&handle);
+            assert_eq!(tokenizer.token_to_id("[CLS]"), Some(1), "model {}", model);
+            let ids = encode_ids(&handle, "hello world", 0);
+            assert!(!ids.is_empty() && !ids.contains(&0), "model {}: {:?}", model, ids);
+            assert!(tokenizer.get_post_processor().is_none(), "model {}", model);
+        }
+
+        assert_eq!(train(None, 9, &files).err().as_deref(), Some("unknown model type 9 to train"));
+        let missing = [CString::new(dir.join("missing.txt").to_str().unwrap()).unwrap()];
+        assert!(train(None, TRAIN_MODEL_BPE, &missing).err().unwrap().starts_with("training failed: "));
+        let result = unsafe { train_from_files(null_mut(), null(), 0, null()) };
+        assert_eq!(take_error(result.error).as_deref(), Some("train params is null"));
+    }
+}
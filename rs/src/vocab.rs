@@ -0,0 +1,942 @@
+use std::error::Error;
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::ptr::null_mut;
+use std::str::FromStr;
+use serde_json::json;
+use tokenizers::tokenizer::Tokenizer;
+use tokenizers::{AddedToken, Model};
+use crate::encode::{err, messages_from_bytes};
+use crate::free_string;
+use crate::handle::{convert_to_handle_ref, TokenizerHandle};
+use crate::panics::{catch_panic, catch_panic_or, error_c_string};
+
+/// Vocab is a list of `len` (token, id) pairs returned by the vocabulary queries (e.g. `get_added_vocab`),
+/// as parallel arrays `tokens` and `ids`, sorted by id.
+///
+/// Either the arrays or `error` will be defined. Once it is no longer used, free it with `free_vocab`.
+#[repr(C)]
+pub struct Vocab {
+    len: u64,
+    tokens: *mut *mut c_char,
+    ids: *mut u32,
+    error: *mut c_char,
+}
+
+impl Vocab {
+    // from_result converts the (token, id) pairs, or the error, to a Vocab. The pairs are sorted by id.
+    fn from_result(r: Result<Vec<(String, u32)>, Box<dyn Error>>) -> Vocab {
+        let entries = r.and_then(|mut entries| {
+            entries.sort_by_key(|&(_, id)| id);
+            entries
+                .into_iter()
+                .map(|(token, id)| Ok((CString::new(token)?, id)))
+                .collect::<Result<Vec<_>, Box<dyn Error>>>()
+        });
+        match entries {
+            Ok(entries) => {
+                let (tokens, ids): (Vec<*mut c_char>, Vec<u32>) =
+                    entries.into_iter().map(|(token, id)| (token.into_raw(), id)).unzip();
+                let mut tokens = tokens.into_boxed_slice();
+                let mut ids = ids.into_boxed_slice();
+                let vocab = Vocab {
+                    len: ids.len() as u64,
+                    tokens: tokens.as_mut_ptr(),
+                    ids: ids.as_mut_ptr(),
+                    error: null_mut(),
+                };
+                std::mem::forget(tokens);
+                std::mem::forget(ids);
+                vocab
+            }
+            Err(err) => Vocab {
+                len: 0,
+                tokens: null_mut(),
+                ids: null_mut(),
+                error: error_c_string(err),
+            },
+        }
+    }
+}
+
+/// get_added_vocab returns the tokens added on top of the base model (with `add_tokens`, `add_special_tokens`
+/// or in the `added_tokens` section of `tokenizer.json`), that is, the ids not covered by the model's own
+/// vocabulary. Added tokens that are also part of the model's vocabulary (e.g.: `[CLS]` in most BERT
+/// tokenizers) are not included, since they don't require new embedding rows.
+///
+/// The result must be freed with `free_vocab`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("get_added_vocab"))]
+pub unsafe extern "C" fn get_added_vocab(tokenizer_ptr: *mut TokenizerHandle) -> Vocab {
+    Vocab::from_result(catch_panic(|| get_added_vocab_impl(tokenizer_ptr)))
+}
+
+fn get_added_vocab_impl(tokenizer_ptr: *mut TokenizerHandle) -> Result<Vec<(String, u32)>, Box<dyn Error>> {
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    let model = state.tokenizer.get_model();
+    Ok(state
+        .tokenizer
+        .get_added_tokens_decoder()
+        .into_iter()
+        .filter(|(id, _)| model.id_to_token(*id).is_none())
+        .map(|(id, token)| (token.content, id))
+        .collect())
+}
+
+/// get_vocab returns the vocabulary of the tokenizer: the tokens of the model and, if `with_added_tokens` is
+/// set, the added tokens (see `get_added_vocab`). See `for_each_vocab_entry` to iterate over huge vocabularies
+/// without copying them.
+///
+/// The result must be freed with `free_vocab`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("get_vocab"))]
+pub unsafe extern "C" fn get_vocab(tokenizer_ptr: *mut TokenizerHandle, with_added_tokens: bool) -> Vocab {
+    Vocab::from_result(catch_panic(|| {
+        let handle = convert_to_handle_ref(tokenizer_ptr)?;
+        Ok(handle.read().tokenizer.get_vocab(with_added_tokens).into_iter().collect())
+    }))
+}
+
+/// token_to_id returns the id of the `token` (from the model or the added tokens), or -1 if it is not in the
+/// vocabulary, or if the tokenizer is invalid.
+///
+/// # Safety
+///
+/// `token` must be a valid C string.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("token_to_id"))]
+pub unsafe extern "C" fn token_to_id(tokenizer_ptr: *mut TokenizerHandle, token: *const c_char) -> i64 {
+    catch_panic_or(-1, || {
+        let handle = match convert_to_handle_ref(tokenizer_ptr) {
+            Ok(handle) => handle,
+            Err(_) => return -1,
+        };
+        if token.is_null() {
+            return -1;
+        }
+        match unsafe { CStr::from_ptr(token) }.to_str() {
+            Ok(token) => handle.read().tokenizer.token_to_id(token).map_or(-1, i64::from),
+            Err(_) => -1,
+        }
+    })
+}
+
+/// id_to_token returns the token of the `id` (from the model or the added tokens), or null if it is not in the
+/// vocabulary, or if the tokenizer is invalid. See `id_to_token_batch` to convert many ids at once.
+///
+/// The returned string needs to be freed with `free_string`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("id_to_token"))]
+pub unsafe extern "C" fn id_to_token(tokenizer_ptr: *mut TokenizerHandle, id: u32) -> *mut c_char {
+    catch_panic_or(null_mut(), || {
+        let handle = match convert_to_handle_ref(tokenizer_ptr) {
+            Ok(handle) => handle,
+            Err(_) => return null_mut(),
+        };
+        match handle.read().tokenizer.id_to_token(id).map(CString::new) {
+            Some(Ok(token)) => token.into_raw(),
+            _ => null_mut(),
+        }
+    })
+}
+
+/// Frees a `Vocab` returned by Rust to Golang.
+///
+/// # Safety
+///
+/// `vocab` must have been returned by Rust, and it must not be used after this call.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("free_vocab"))]
+pub unsafe extern "C" fn free_vocab(vocab: Vocab) {
+    catch_panic_or((), || {
+        free_string(vocab.error);
+        let len = vocab.len as usize;
+        if !vocab.tokens.is_null() {
+            unsafe {
+                let tokens = Box::from_raw(std::ptr::slice_from_raw_parts_mut(vocab.tokens, len));
+                for token in tokens.iter() {
+                    drop(CString::from_raw(*token));
+                }
+            }
+        }
+        if !vocab.ids.is_null() {
+            unsafe {
+                drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(vocab.ids, len)));
+            }
+        }
+    })
+}
+
+/// max_token_id returns the largest token id known by the tokenizer, including added tokens. Since ids
+/// may have gaps (e.g.: added tokens with ids far from the model's vocabulary), it can be larger than
+/// `vocab_size - 1`: logits and embeddings tables should have at least `max_token_id + 1` rows.
+///
+/// It returns -1 if the tokenizer is invalid or its vocabulary is empty.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("max_token_id"))]
+pub unsafe extern "C" fn max_token_id(tokenizer_ptr: *mut TokenizerHandle) -> i64 {
+    catch_panic_or(-1, || {
+        let handle = match convert_to_handle_ref(tokenizer_ptr) {
+            Ok(handle) => handle,
+            Err(_) => return -1,
+        };
+        let state = handle.read();
+        let tokenizer = &state.tokenizer;
+        let max_model_id = tokenizer.get_model().get_vocab().into_values().max();
+        let max_added_id = tokenizer.get_added_tokens_decoder().into_keys().max();
+        max_model_id.max(max_added_id).map_or(-1, i64::from)
+    })
+}
+
+/// ids_are_valid returns whether all the `len` token ids in `ids` are known by the tokenizer (either by the
+/// model or as added tokens), and hence can be decoded.
+///
+/// It returns false if the tokenizer is invalid.
+///
+/// # Safety
+///
+/// `ids` must point to `len` token ids.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("ids_are_valid"))]
+pub unsafe extern "C" fn ids_are_valid(tokenizer_ptr: *mut TokenizerHandle, ids: *const u32, len: u32) -> bool {
+    catch_panic_or(false, || {
+        let handle = match convert_to_handle_ref(tokenizer_ptr) {
+            Ok(handle) => handle,
+            Err(_) => return false,
+        };
+        if len == 0 {
+            return true;
+        }
+        let ids = unsafe { std::slice::from_raw_parts(ids, len as usize) };
+        let state = handle.read();
+        ids.iter().all(|&id| state.tokenizer.id_to_token(id).is_some())
+    })
+}
+
+/// PackedTokens holds `len` token strings packed in one buffer: the token `i` is made of the bytes
+/// `bytes[offsets[i]..offsets[i+1]]`, so `offsets` has `len + 1` entries, and `bytes` has `offsets[len]` bytes.
+///
+/// Either the arrays or `error` will be defined. Once it is no longer used, free it with `free_packed_tokens`.
+#[repr(C)]
+pub struct PackedTokens {
+    len: u64,
+    bytes: *mut u8,
+    offsets: *mut u64,
+    error: *mut c_char,
+}
+
+impl PackedTokens {
+    // from_result packs the tokens, or converts the error, to a PackedTokens.
+    fn from_result<S: AsRef<str>>(r: Result<Vec<S>, Box<dyn Error>>) -> PackedTokens {
+        match r {
+            Ok(tokens) => {
+                let mut offsets = Vec::with_capacity(tokens.len() + 1);
+                offsets.push(0);
+                let mut bytes = Vec::new();
+                for token in &tokens {
+                    bytes.extend_from_slice(token.as_ref().as_bytes());
+                    offsets.push(bytes.len() as u64);
+                }
+                let mut bytes = bytes.into_boxed_slice();
+                let mut offsets = offsets.into_boxed_slice();
+                let packed = PackedTokens {
+                    len: tokens.len() as u64,
+                    bytes: bytes.as_mut_ptr(),
+                    offsets: offsets.as_mut_ptr(),
+                    error: null_mut(),
+                };
+                std::mem::forget(bytes);
+                std::mem::forget(offsets);
+                packed
+            }
+            Err(err) => PackedTokens {
+                len: 0,
+                bytes: null_mut(),
+                offsets: null_mut(),
+                error: error_c_string(err),
+            },
+        }
+    }
+}
+
+/// Frees a `PackedTokens` returned by Rust to Golang.
+///
+/// # Safety
+///
+/// `tokens` must have been returned by Rust, and it must not be used after this call.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("free_packed_tokens"))]
+pub unsafe extern "C" fn free_packed_tokens(tokens: PackedTokens) {
+    catch_panic_or((), || {
+        free_string(tokens.error);
+        if !tokens.offsets.is_null() {
+            unsafe {
+                let len = tokens.len as usize;
+                let offsets = Box::from_raw(std::ptr::slice_from_raw_parts_mut(tokens.offsets, len + 1));
+                drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(tokens.bytes, offsets[len] as usize)));
+            }
+        }
+    })
+}
+
+/// TokenStrings is the table of the strings of the tokens, indexed by id, used to decode: the tokens of the
+/// model, replaced by the added tokens with the same id.
+///
+/// It is built on first use, and cached in the TokenizerState, see `TokenizerState::token_strings`.
+pub struct TokenStrings {
+    tokens: Vec<Option<TokenString>>,
+}
+
+struct TokenString {
+    content: String,
+    special: bool,
+}
+
+impl TokenStrings {
+    pub fn new(tokenizer: &Tokenizer) -> Self {
+        let vocab = tokenizer.get_model().get_vocab();
+        let added_tokens = tokenizer.get_added_tokens_decoder();
+        let len = vocab.values().chain(added_tokens.keys()).max().map_or(0, |&id| id as usize + 1);
+        let mut tokens: Vec<Option<TokenString>> = Vec::with_capacity(len);
+        tokens.resize_with(len, || None);
+        for (content, id) in vocab {
+            tokens[id as usize] = Some(TokenString { content, special: false });
+        }
+        for (id, token) in added_tokens {
+            tokens[id as usize] = Some(TokenString { content: token.content, special: token.special });
+        }
+        TokenStrings { tokens }
+    }
+
+    /// Returns the string of the token `id`, or None if it is not in the vocabulary.
+    pub fn get(&self, id: u32) -> Option<&str> {
+        self.tokens.get(id as usize)?.as_ref().map(|token| token.content.as_str())
+    }
+
+    /// Returns the string of the token `id` if it is a special token, or None otherwise.
+    pub fn special(&self, id: u32) -> Option<&str> {
+        match self.tokens.get(id as usize)? {
+            Some(token) if token.special => Some(token.content.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Returns the string of the token `id` to decode: None if it is not in the vocabulary, or if it is a
+    /// special token and `skip_special_tokens` is set.
+    pub fn decoded(&self, id: u32, skip_special_tokens: bool) -> Option<&str> {
+        match self.tokens.get(id as usize)? {
+            Some(token) if token.special && skip_special_tokens => None,
+            token => token.as_ref().map(|token| token.content.as_str()),
+        }
+    }
+}
+
+/// preload_token_strings builds the table of the strings of the tokens used to decode (and by
+/// `id_to_token_batch`) now, instead of on the first call that needs it. The table is built lazily so
+/// encode-only workloads don't pay for it -- in load time and memory, with huge vocabularies -- but
+/// latency-critical decode paths can call this after loading (or modifying) the tokenizer.
+///
+/// It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+/// The returned string needs to be freed with `free_string`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("preload_token_strings"))]
+pub extern "C" fn preload_token_strings(tokenizer_ptr: *mut TokenizerHandle) -> *mut c_char {
+    let result = catch_panic(|| {
+        convert_to_handle_ref(tokenizer_ptr)?.read().token_strings();
+        Ok(())
+    });
+    match result {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+fn id_to_token_batch_impl(tokenizer_ptr: *mut TokenizerHandle, ids: *const u32, len: u32) -> Result<Vec<String>, Box<dyn Error>> {
+    let ids = if len == 0 { &[][..] } else { unsafe { std::slice::from_raw_parts(ids, len as usize) } };
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    let strings = state.token_strings();
+    Ok(ids.iter().map(|&id| strings.get(id).unwrap_or_default().to_string()).collect())
+}
+
+/// id_to_token_batch returns the token strings of the `len` token ids in `ids` (from the model or the added
+/// tokens), packed in one buffer, e.g. to render the top-k tables of logits without one call per id.
+/// Unknown ids get an empty token, since tokens are never empty.
+///
+/// The result must be freed with `free_packed_tokens`.
+///
+/// # Safety
+///
+/// `ids` must point to `len` token ids.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("id_to_token_batch"))]
+pub unsafe extern "C" fn id_to_token_batch(tokenizer_ptr: *mut TokenizerHandle, ids: *const u32, len: u32) -> PackedTokens {
+    PackedTokens::from_result(catch_panic(|| id_to_token_batch_impl(tokenizer_ptr, ids, len)))
+}
+
+/// TOKEN_NOT_FOUND is the id returned by `token_to_id_batch` for the tokens not in the vocabulary.
+pub const TOKEN_NOT_FOUND: u32 = u32::MAX;
+
+fn token_to_id_batch_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_tokens: usize,
+    tokens: *const *const u8,
+    lengths: *const u32,
+    ids: *mut u32,
+) -> Result<(), Box<dyn Error>> {
+    if num_tokens > 0 && ids.is_null() {
+        return Err(err("ids is null"));
+    }
+    let tokens = messages_from_bytes(num_tokens, tokens, lengths)?;
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    for (i, token) in tokens.iter().enumerate() {
+        let id = state.tokenizer.token_to_id(token).unwrap_or(TOKEN_NOT_FOUND);
+        unsafe { *ids.add(i) = id };
+    }
+    Ok(())
+}
+
+/// token_to_id_batch writes to `ids` the id of each of the `num_tokens` tokens (from the model or the added
+/// tokens), given as UTF-8 strings as in `encode_batch_bytes`, or TOKEN_NOT_FOUND if a token is not in the
+/// vocabulary, e.g. to map word lists (stop words, bad words, constraints) to ids in one call.
+///
+/// It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+/// The returned string needs to be freed with `free_string`.
+///
+/// # Safety
+///
+/// `tokens` and `lengths` must point to `num_tokens` strings and lengths, and `ids` to space for `num_tokens`
+/// ids.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("token_to_id_batch"))]
+pub unsafe extern "C" fn token_to_id_batch(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_tokens: u32,
+    tokens: *const *const u8,
+    lengths: *const u32,
+    ids: *mut u32,
+) -> *mut c_char {
+    match catch_panic(|| token_to_id_batch_impl(tokenizer_ptr, num_tokens as usize, tokens, lengths, ids)) {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+/// AddedTokenAttributes are the attributes of an added token to update with `set_added_token_attributes`.
+/// Each is either 0 -> unchanged, 1 -> true or 2 -> false.
+#[repr(C)]
+pub struct AddedTokenAttributes {
+    single_word: u8,
+    lstrip: u8,
+    rstrip: u8,
+    normalized: u8,
+    special: u8,
+}
+
+fn set_added_token_attributes_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    token: *const c_char,
+    attributes: *const AddedTokenAttributes,
+) -> Result<(), Box<dyn Error>> {
+    if token.is_null() {
+        return Err(err("token is null"));
+    }
+    let token = unsafe { CStr::from_ptr(token) }.to_str()?;
+    let attributes = unsafe { attributes.as_ref() }.ok_or_else(|| err("AddedTokenAttributes is null"))?;
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+
+    // The added vocabulary is rebuilt from the serialized tokenizer, with the token updated.
+    handle.update(|state| {
+        let mut json = state.pipeline_json()?;
+        let added_token = json["added_tokens"]
+            .as_array_mut()
+            .and_then(|added_tokens| added_tokens.iter_mut().find(|added| added["content"] == token))
+            .ok_or_else(|| err(format!("{:?} is not an added token", token)))?;
+        let changes = [
+            ("single_word", attributes.single_word),
+            ("lstrip", attributes.lstrip),
+            ("rstrip", attributes.rstrip),
+            ("normalized", attributes.normalized),
+            ("special", attributes.special),
+        ];
+        for (key, value) in changes {
+            match value {
+                0 => {}
+                1 | 2 => added_token[key] = json!(value == 1),
+                value => return Err(err(format!("invalid value {} for added token attribute {}", value, key))),
+            }
+        }
+        let tokenizer = Tokenizer::from_str(&json.to_string())
+            .map_err(|e| err(format!("failed to update added token {:?}: {}", token, e)))?;
+        state.replace_tokenizer(tokenizer)
+    })
+}
+
+/// set_added_token_attributes updates the attributes of the added `token` (its content), e.g. to fix a
+/// misconfigured checkpoint at load time:
+///
+/// - `single_word`: whether the token only matches whole words.
+/// - `lstrip` and `rstrip`: whether the spaces on its left (right) are included in the token.
+/// - `normalized`: whether the token is matched against the normalized text, instead of the original text.
+/// - `special`: whether it is a special token (e.g. skipped when decoding with `skip_special_tokens`).
+///
+/// It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+/// The returned string needs to be freed with `free_string`.
+///
+/// # Safety
+///
+/// `token` must be a valid C string.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("set_added_token_attributes"))]
+pub unsafe extern "C" fn set_added_token_attributes(
+    tokenizer_ptr: *mut TokenizerHandle,
+    token: *const c_char,
+    attributes: *const AddedTokenAttributes,
+) -> *mut c_char {
+    match catch_panic(|| set_added_token_attributes_impl(tokenizer_ptr, token, attributes)) {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+// tokens_from_c_strings converts the `num_tokens` C strings pointed by `tokens` to a vector of `&str`.
+fn tokens_from_c_strings<'a>(num_tokens: usize, tokens: *const *const c_char) -> Result<Vec<&'a str>, Box<dyn Error>> {
+    if num_tokens > 0 && tokens.is_null() {
+        return Err(err("tokens is null"));
+    }
+    (0..num_tokens)
+        .map(|i| {
+            let token = unsafe { *tokens.add(i) };
+            if token.is_null() {
+                return Err(err(format!("token #{} is null", i)));
+            }
+            Ok(unsafe { CStr::from_ptr(token) }.to_str()?)
+        })
+        .collect()
+}
+
+fn add_tokens_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_tokens: usize,
+    tokens: *const *const c_char,
+    special: bool,
+) -> Result<(), Box<dyn Error>> {
+    let tokens: Vec<AddedToken> = tokens_from_c_strings(num_tokens, tokens)?
+        .into_iter()
+        .map(|token| AddedToken::from(token.to_string(), special))
+        .collect();
+    if let Some(index) = tokens.iter().position(|token| token.content.is_empty()) {
+        return Err(err(format!("token #{} is empty", index)));
+    }
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let mut state = handle.write()?;
+    if special {
+        state.tokenizer_mut().add_special_tokens(&tokens);
+    } else {
+        state.tokenizer_mut().add_tokens(&tokens);
+    }
+    Ok(())
+}
+
+/// add_tokens adds the `num_tokens` tokens (their contents) to the added vocabulary, e.g. domain markers, so they
+/// are matched in the input text (after normalization) and never split by the model. The tokens not already
+/// in the vocabulary get new ids, after all the current ones (see `get_added_vocab` to resize the embeddings),
+/// and the others keep their id.
+///
+/// It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+/// The returned string needs to be freed with `free_string`.
+///
+/// # Safety
+///
+/// `tokens` must point to `num_tokens` valid C strings.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("add_tokens"))]
+pub unsafe extern "C" fn add_tokens(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_tokens: u32,
+    tokens: *const *const c_char,
+) -> *mut c_char {
+    match catch_panic(|| add_tokens_impl(tokenizer_ptr, num_tokens as usize, tokens, false)) {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+/// add_special_tokens adds the `num_tokens` tokens as special tokens, as `add_tokens`, except that they are
+/// matched in the original text (not normalized), and they are skipped when decoding with
+/// `skip_special_tokens`. Tokens already added are made special.
+///
+/// It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+/// The returned string needs to be freed with `free_string`.
+///
+/// # Safety
+///
+/// `tokens` must point to `num_tokens` valid C strings.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("add_special_tokens"))]
+pub unsafe extern "C" fn add_special_tokens(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_tokens: u32,
+    tokens: *const *const c_char,
+) -> *mut c_char {
+    match catch_panic(|| add_tokens_impl(tokenizer_ptr, num_tokens as usize, tokens, true)) {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+fn remove_added_tokens_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_tokens: usize,
+    tokens: *const *const c_char,
+) -> Result<(), Box<dyn Error>> {
+    let tokens = tokens_from_c_strings(num_tokens, tokens)?;
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+
+    // The added vocabulary is rebuilt from the serialized tokenizer, without the tokens.
+    handle.update(|state| {
+        let mut json = state.pipeline_json()?;
+        let added_tokens = json["added_tokens"]
+            .as_array_mut()
+            .ok_or_else(|| err("tokenizer has no added tokens"))?;
+        for token in &tokens {
+            let index = added_tokens
+                .iter()
+                .position(|added| added["content"] == *token)
+                .ok_or_else(|| err(format!("{:?} is not an added token", token)))?;
+            added_tokens.remove(index);
+        }
+        let tokenizer = Tokenizer::from_str(&json.to_string())
+            .map_err(|e| err(format!("failed to remove added tokens: {}", e)))?;
+        state.replace_tokenizer(tokenizer)
+    })
+}
+
+/// remove_added_tokens removes the `num_tokens` added tokens (their contents) from the added vocabulary, e.g.
+/// to clean up the tokens added for an experiment without reloading the tokenizer. They are no longer
+/// matched in the input text, and the added vocabulary is rebuilt without them.
+///
+/// Tokens that are also in the vocabulary of the model (e.g. "[CLS]" in BERT) only lose their matching as
+/// added (special) tokens: they keep their id, and are encoded and decoded as the other tokens of the model.
+/// The ids of the other tokens are freed. It is an error if any of the tokens is not an added token, in which
+/// case the tokenizer is not modified.
+///
+/// It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+/// The returned string needs to be freed with `free_string`.
+///
+/// # Safety
+///
+/// `tokens` must point to `num_tokens` valid C strings.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("remove_added_tokens"))]
+pub unsafe extern "C" fn remove_added_tokens(
+    tokenizer_ptr: *mut TokenizerHandle,
+    num_tokens: u32,
+    tokens: *const *const c_char,
+) -> *mut c_char {
+    match catch_panic(|| remove_added_tokens_impl(tokenizer_ptr, num_tokens as usize, tokens)) {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+/// VocabEntryCallback is called by `for_each_vocab_entry` with each (token, id) of the vocabulary, and the
+/// `user_data` given to it: the `len` bytes of `token` are only valid during the call. It returns true to
+/// continue, or false to stop the iteration.
+pub type VocabEntryCallback =
+    Option<unsafe extern "C" fn(user_data: *mut c_void, token: *const u8, len: u64, id: u32) -> bool>;
+
+fn for_each_vocab_entry_impl(
+    tokenizer_ptr: *mut TokenizerHandle,
+    with_added_tokens: bool,
+    callback: VocabEntryCallback,
+    user_data: *mut c_void,
+) -> Result<(), Box<dyn Error>> {
+    let callback = callback.ok_or_else(|| err("vocab entry callback is null"))?;
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    let model = state.tokenizer.get_model();
+
+    // The added tokens not in the model are merged in by id: there are few of them, so they are collected.
+    let mut added: Vec<(u32, String)> = if with_added_tokens {
+        state
+            .tokenizer
+            .get_added_tokens_decoder()
+            .into_iter()
+            .filter(|(id, _)| model.id_to_token(*id).is_none())
+            .map(|(id, token)| (id, token.content))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    added.sort_by_key(|&(id, _)| id);
+    let mut added = added.into_iter().peekable();
+
+    // The model tokens are looked up one by one, so the vocabulary is never copied whole. Ids may have gaps,
+    // so it stops once all the tokens of the model were found.
+    let emit = |token: &str, id: u32| unsafe { callback(user_data, token.as_ptr(), token.len() as u64, id) };
+    let num_model_tokens = model.get_vocab_size();
+    let mut found = 0;
+    let mut id = 0u32;
+    while found < num_model_tokens {
+        if let Some(token) = model.id_to_token(id) {
+            while let Some((added_id, added_token)) = added.next_if(|&(added_id, _)| added_id < id) {
+                if !emit(&added_token, added_id) {
+                    return Ok(());
+                }
+            }
+            if !emit(&token, id) {
+                return Ok(());
+            }
+            found += 1;
+        }
+        id = match id.checked_add(1) {
+            Some(id) => id,
+            None => break,
+        };
+    }
+    for (added_id, added_token) in added {
+        if !emit(&added_token, added_id) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// for_each_vocab_entry calls the host `callback` (see `VocabEntryCallback`) from the calling thread with each
+/// (token, id) of the vocabulary, sorted by id, and the given `user_data`. If `with_added_tokens` is set, the
+/// added tokens that are not in the model's vocabulary are included.
+///
+/// Different from the bulk queries (e.g. `get_added_vocab`), the tokens are streamed one by one, so very large
+/// vocabularies are never copied whole. The tokenizer is locked for reading during the iteration: the
+/// callback must not modify it.
+///
+/// It returns null if ok (also if the callback stopped the iteration), or a string with an error message
+/// (owned by caller) if something went wrong. The returned string needs to be freed with `free_string`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("for_each_vocab_entry"))]
+pub unsafe extern "C" fn for_each_vocab_entry(
+    tokenizer_ptr: *mut TokenizerHandle,
+    with_added_tokens: bool,
+    callback: VocabEntryCallback,
+    user_data: *mut c_void,
+) -> *mut c_char {
+    match catch_panic(|| for_each_vocab_entry_impl(tokenizer_ptr, with_added_tokens, callback, user_data)) {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{encode_ids, take_error, TestHandle, WORDPIECE};
+
+    // entries returns the (token, id) pairs of the `vocab` (freeing it), or its error.
+    fn entries(vocab: Vocab) -> Result<Vec<(String, u32)>, String> {
+        if let Some(e) = take_error(vocab.error) {
+            return Err(e);
+        }
+        let len = vocab.len as usize;
+        let tokens = unsafe { std::slice::from_raw_parts(vocab.tokens, len) };
+        let ids = unsafe { std::slice::from_raw_parts(vocab.ids, len) };
+        let entries = tokens
+            .iter()
+            .zip(ids)
+            .map(|(&token, &id)| (unsafe { CStr::from_ptr(token) }.to_str().unwrap().to_string(), id))
+            .collect();
+        unsafe { free_vocab(Vocab { error: null_mut(), ..vocab }) };
+        Ok(entries)
+    }
+
+    // with_added_token returns WORDPIECE with the special token `<extra>` added with the id 10, out of the model's
+    // vocabulary.
+    fn with_added_token() -> String {
+        let extra = r#"{"id": 10, "content": "<extra>", "single_word": false, "lstrip": false, "rstrip": false,
+            "normalized": false, "special": true},"#;
+        WORDPIECE.replacen(r#""added_tokens": ["#, &format!(r#""added_tokens": [{}"#, extra), 1)
+    }
+
+    #[test]
+    fn vocab_introspection() {
+        let handle = TestHandle::new(WORDPIECE);
+        let ids = [c"world", c"##b", c"nope"].map(|token| unsafe { token_to_id(handle.0, token.as_ptr()) });
+        assert_eq!(ids, [6, 3, -1]);
+        assert_eq!(unsafe { token_to_id(handle.0, std::ptr::null()) }, -1);
+        assert_eq!(unsafe { token_to_id(null_mut(), c"world".as_ptr()) }, -1);
+        assert_eq!(take_error(unsafe { id_to_token(handle.0, 4) }).as_deref(), Some("hello"));
+        assert!(unsafe { id_to_token(handle.0, 99) }.is_null());
+
+        // New tokens get the ids after the current ones, and the tokens already in the vocabulary keep theirs.
+        let tokens = [c"<dom>".as_ptr(), c"hello".as_ptr()];
+        assert_eq!(take_error(unsafe { add_tokens(handle.0, 2, tokens.as_ptr()) }), None);
+        assert_eq!(take_error(unsafe { add_special_tokens(handle.0, 1, [c"<sp>".as_ptr()].as_ptr()) }), None);
+        assert_eq!(encode_ids(&handle, "hello<dom> a<sp>", 0), [4, 10, 1, 11]);
+        let mut vocab = entries(unsafe { get_vocab(handle.0, true) }).unwrap();
+        vocab.sort_by_key(|&(_, id)| id);
+        assert_eq!(vocab.len(), 12);
+        assert_eq!(vocab[10..], [("<dom>".to_string(), 10), ("<sp>".to_string(), 11)]);
+        assert_eq!(entries(unsafe { get_vocab(handle.0, false) }).unwrap().len(), 10);
+
+        let error = take_error(unsafe { add_tokens(handle.0, 1, [c"".as_ptr()].as_ptr()) });
+        assert_eq!(error.as_deref(), Some("token #0 is empty"));
+        assert_eq!(entries(unsafe { get_vocab(null_mut(), true) }), Err("tokenizer passed is null".to_string()));
+    }
+
+    #[test]
+    fn added_vocab() {
+        // `<|im_end|>` is also in the vocabulary of the model.
+        let handle = TestHandle::new(WORDPIECE);
+        assert_eq!(entries(unsafe { get_added_vocab(handle.0) }), Ok(vec![]));
+
+        let handle = TestHandle::new(&with_added_token());
+        assert_eq!(entries(unsafe { get_added_vocab(handle.0) }), Ok(vec![("<extra>".to_string(), 10)]));
+        let vocab = entries(unsafe { get_vocab(handle.0, true) }).unwrap();
+        assert_eq!(vocab.len(), 11);
+        assert_eq!(vocab[..2], [("[UNK]".to_string(), 0), ("a".to_string(), 1)]);
+        assert_eq!(entries(unsafe { get_vocab(handle.0, false) }).unwrap().len(), 10);
+        assert!(entries(unsafe { get_added_vocab(null_mut()) }).is_err());
+    }
+
+    #[test]
+    fn max_token_id_and_validity() {
+        let handle = TestHandle::new(&with_added_token());
+        assert_eq!(unsafe { max_token_id(handle.0) }, 10);
+        assert_eq!(unsafe { max_token_id(null_mut()) }, -1);
+        assert!(unsafe { ids_are_valid(handle.0, [0, 4, 10].as_ptr(), 3) });
+        assert!(!unsafe { ids_are_valid(handle.0, [4, 11].as_ptr(), 2) });
+        assert!(unsafe { ids_are_valid(handle.0, std::ptr::null(), 0) });
+        assert!(!unsafe { ids_are_valid(null_mut(), [4].as_ptr(), 1) });
+    }
+
+    #[test]
+    fn added_token_attributes() {
+        let handle = TestHandle::new(WORDPIECE);
+        let set = |token: &CStr, lstrip, special| {
+            let attributes = AddedTokenAttributes { single_word: 0, lstrip, rstrip: 0, normalized: 0, special };
+            take_error(unsafe { set_added_token_attributes(handle.0, token.as_ptr(), &attributes) })
+        };
+        assert_eq!(set(c"<|im_end|>", 1, 2), None);
+        let added = convert_to_handle_ref(handle.0).unwrap().read().tokenizer.get_added_tokens_decoder()[&5].clone();
+        assert_eq!((added.lstrip, added.special, added.rstrip), (true, false, false));
+
+        assert_eq!(set(c"hello", 1, 0).as_deref(), Some("\"hello\" is not an added token"));
+        assert_eq!(set(c"<|im_end|>", 3, 0).as_deref(), Some("invalid value 3 for added token attribute lstrip"));
+    }
+
+    // unpack returns the tokens of the `packed` tokens (freeing them), or its error.
+    fn unpack(packed: PackedTokens) -> Result<Vec<String>, String> {
+        if let Some(e) = take_error(packed.error) {
+            return Err(e);
+        }
+        let offsets = unsafe { std::slice::from_raw_parts(packed.offsets, packed.len as usize + 1) };
+        let bytes = unsafe { std::slice::from_raw_parts(packed.bytes, offsets[packed.len as usize] as usize) };
+        let tokens = offsets
+            .windows(2)
+            .map(|w| String::from_utf8(bytes[w[0] as usize..w[1] as usize].to_vec()).unwrap())
+            .collect();
+        unsafe { free_packed_tokens(PackedTokens { error: null_mut(), ..packed }) };
+        Ok(tokens)
+    }
+
+    #[test]
+    fn ids_to_tokens() {
+        let handle = TestHandle::new(&with_added_token());
+        let ids = [4, 3, 10, 5, 99];
+        let tokens = unpack(unsafe { id_to_token_batch(handle.0, ids.as_ptr(), ids.len() as u32) }).unwrap();
+        assert_eq!(tokens, ["hello", "##b", "<extra>", "<|im_end|>", ""]);
+        assert_eq!(unpack(unsafe { id_to_token_batch(handle.0, std::ptr::null(), 0) }), Ok(vec![]));
+        let error = unpack(unsafe { id_to_token_batch(null_mut(), ids.as_ptr(), 1) });
+        assert_eq!(error, Err("tokenizer passed is null".to_string()));
+    }
+
+    #[test]
+    fn token_strings() {
+        let handle = TestHandle::new(&with_added_token());
+        let strings = TokenStrings::new(&unsafe { (*handle.0).read() }.tokenizer);
+        assert_eq!((strings.get(4), strings.get(10), strings.get(99)), (Some("hello"), Some("<extra>"), None));
+        assert_eq!((strings.special(4), strings.special(10)), (None, Some("<extra>")));
+        assert_eq!((strings.decoded(10, true), strings.decoded(10, false)), (None, Some("<extra>")));
+
+        // The table built by preload_token_strings is rebuilt once the vocabulary changes.
+        assert_eq!(take_error(preload_token_strings(handle.0)), None);
+        let ids = [10, 11];
+        let tokens = || unpack(unsafe { id_to_token_batch(handle.0, ids.as_ptr(), 2) }).unwrap();
+        assert_eq!(tokens(), ["<extra>", ""]);
+        assert_eq!(take_error(unsafe { add_tokens(handle.0, 1, [c"<new>".as_ptr()].as_ptr()) }), None);
+        assert_eq!(tokens(), ["<extra>", "<new>"]);
+        let error = take_error(preload_token_strings(null_mut()));
+        assert_eq!(error.as_deref(), Some("tokenizer passed is null"));
+    }
+
+    #[test]
+    fn tokens_to_ids() {
+        let handle = TestHandle::new(&with_added_token());
+        let tokens = ["hello", "<extra>", "nope", "##b", ""];
+        let pointers: Vec<*const u8> = tokens.iter().map(|t| t.as_ptr()).collect();
+        let lengths: Vec<u32> = tokens.iter().map(|t| t.len() as u32).collect();
+        let mut ids = [0; 5];
+        let error = unsafe { token_to_id_batch(handle.0, 5, pointers.as_ptr(), lengths.as_ptr(), ids.as_mut_ptr()) };
+        assert_eq!(take_error(error), None);
+        assert_eq!(ids, [4, 10, TOKEN_NOT_FOUND, 3, TOKEN_NOT_FOUND]);
+
+        let error = unsafe { token_to_id_batch(handle.0, 5, pointers.as_ptr(), lengths.as_ptr(), null_mut()) };
+        assert_eq!(take_error(error).as_deref(), Some("ids is null"));
+        let invalid = [b"\xff".as_ptr()];
+        let error = unsafe { token_to_id_batch(handle.0, 1, invalid.as_ptr(), [1].as_ptr(), ids.as_mut_ptr()) };
+        assert!(take_error(error).is_some());
+    }
+
+    #[test]
+    fn remove_tokens() {
+        let handle = TestHandle::new(&with_added_token());
+        let remove = |tokens: &[&CStr]| {
+            let tokens: Vec<*const c_char> = tokens.iter().map(|t| t.as_ptr()).collect();
+            take_error(unsafe { remove_added_tokens(handle.0, tokens.len() as u32, tokens.as_ptr()) })
+        };
+        // On error, none of the tokens is removed.
+        assert_eq!(remove(&[c"<extra>", c"nope"]).as_deref(), Some("\"nope\" is not an added token"));
+        assert_eq!(unsafe { max_token_id(handle.0) }, 10);
+
+        assert_eq!(remove(&[c"<extra>"]), None);
+        assert_eq!(unsafe { max_token_id(handle.0) }, 9);
+        assert_eq!(encode_ids(&handle, "hello<|im_end|>", 0), [4, 5]);
+        // `<|im_end|>` is in the vocabulary of the model: it keeps its id, but it is no longer matched as a whole.
+        assert_eq!(remove(&[c"<|im_end|>"]), None);
+        assert_eq!(encode_ids(&handle, "hello<|im_end|>", 0), [4, 7, 9, 8]);
+        assert_eq!(unsafe { max_token_id(handle.0) }, 9);
+        assert_eq!(remove(&[c"<|im_end|>"]).as_deref(), Some("\"<|im_end|>\" is not an added token"));
+    }
+
+    // collect_entry appends the (token, id) to the Vec<(String, u32)> in `user_data`, and stops after 3 entries.
+    unsafe extern "C" fn collect_entry(user_data: *mut c_void, token: *const u8, len: u64, id: u32) -> bool {
+        let entries = unsafe { &mut *user_data.cast::<Vec<(String, u32)>>() };
+        let token = unsafe { std::slice::from_raw_parts(token, len as usize) };
+        entries.push((String::from_utf8(token.to_vec()).unwrap(), id));
+        entries.len() < 3 || entries[0].1 != 0
+    }
+
+    #[test]
+    fn vocab_entries() {
+        let handle = TestHandle::new(&with_added_token());
+        let for_each = |with_added_tokens, mut entries: Vec<(String, u32)>| {
+            let user_data = (&mut entries as *mut Vec<(String, u32)>).cast();
+            let error = unsafe { for_each_vocab_entry(handle.0, with_added_tokens, Some(collect_entry), user_data) };
+            assert_eq!(take_error(error), None);
+            entries
+        };
+        // A first entry that is not id 0 disables the stop after 3 entries.
+        let all = for_each(true, vec![("".to_string(), 99)]);
+        let ids: Vec<u32> = all[1..].iter().map(|&(_, id)| id).collect();
+        assert_eq!(ids, (0..=10).collect::<Vec<u32>>());
+        assert_eq!(all[11], ("<extra>".to_string(), 10));
+        assert_eq!(all[6], ("<|im_end|>".to_string(), 5));
+        assert_eq!(for_each(false, vec![("".to_string(), 99)]).len(), 11);
+        let first = for_each(true, vec![]);
+        assert_eq!(first, [("[UNK]".to_string(), 0), ("a".to_string(), 1), ("b".to_string(), 2)]);
+
+        let error = unsafe { for_each_vocab_entry(handle.0, true, None, null_mut()) };
+        assert_eq!(take_error(error).as_deref(), Some("vocab entry callback is null"));
+    }
+}
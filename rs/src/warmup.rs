@@ -0,0 +1,75 @@
+//! Warm-up of a tokenizer (see `warmup`): the lazily initialized parts of the pipeline are initialized ahead of
+//! the first request, so it doesn't pay for them.
+
+use std::error::Error;
+use std::ffi::c_char;
+use std::ptr::null_mut;
+use tokenizers::tokenizer::Tokenizer;
+use crate::encode::err;
+use crate::handle::{convert_to_handle_ref, TokenizerHandle};
+use crate::panics::{catch_panic, error_c_string};
+
+// WARMUP_TEXT is encoded (and decoded) to initialize the pipeline: it mixes letters, digits, punctuation,
+// whitespace and non-ASCII characters, so that most of the pre-tokenization patterns match.
+const WARMUP_TEXT: &str = "Hello, World!  It's 2024-01-01 12:34.\n\tThe naïve café costs €3.50 — 東京 😀 ok?";
+
+// warmup_encode encodes and decodes the WARMUP_TEXT.
+fn warmup_encode(tokenizer: &Tokenizer) -> tokenizers::Result<()> {
+    let encoding = tokenizer.encode(WARMUP_TEXT, true)?;
+    tokenizer.decode(encoding.get_ids(), false)?;
+    Ok(())
+}
+
+fn warmup_impl(tokenizer_ptr: *mut TokenizerHandle, on_workers: bool) -> Result<(), Box<dyn Error>> {
+    let handle = convert_to_handle_ref(tokenizer_ptr)?;
+    let state = handle.read();
+    state.vocab_trie();
+    state.token_strings();
+    state.token_scores();
+
+    let tokenizer: &Tokenizer = &state.tokenizer;
+    warmup_encode(tokenizer).map_err(|e| err(format!("warm-up failed: {}", e)))?;
+    if on_workers {
+        crate::threads::install(|| rayon::broadcast(|_| warmup_encode(tokenizer)))
+            .into_iter()
+            .collect::<tokenizers::Result<Vec<()>>>()
+            .map_err(|e| err(format!("warm-up failed: {}", e)))?;
+    }
+    Ok(())
+}
+
+/// warmup prepares the tokenizer to serve its first request as fast as the following ones: it builds the
+/// tables derived from the vocabulary now instead of on first use (see `preload_token_strings`), and encodes
+/// and decodes a sample text, initializing the parts of the pipeline initialized on first use (e.g. the
+/// patterns of the byte-level pre-tokenizer). It should be called after loading (or modifying) the tokenizer.
+///
+/// If `on_workers` is set, the sample text is also encoded on each of the threads used to encode batches (see
+/// `set_thread_policy`), starting them if needed, so the first batch call doesn't pay for it either.
+///
+/// It returns null if ok, or a string with an error message (owned by caller) if something went wrong.
+/// The returned string needs to be freed with `free_string`.
+#[no_mangle]
+#[cfg_attr(symbol_prefix, export_name = symbol!("warmup"))]
+pub extern "C" fn warmup(tokenizer_ptr: *mut TokenizerHandle, on_workers: bool) -> *mut c_char {
+    match catch_panic(|| warmup_impl(tokenizer_ptr, on_workers)) {
+        Ok(()) => null_mut(),
+        Err(e) => error_c_string(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{encode_ids, take_error, TestHandle, BYTE_LEVEL_BPE, WORDPIECE_BERT};
+
+    #[test]
+    fn warmup_tokenizers() {
+        for (json, expected) in [(BYTE_LEVEL_BPE, vec![11, 17]), (WORDPIECE_BERT, vec![4, 6])] {
+            let handle = TestHandle::new(json);
+            assert_eq!(take_error(warmup(handle.0, false)), None);
+            assert_eq!(take_error(warmup(handle.0, true)), None);
+            assert_eq!(encode_ids(&handle, "hello world", 0), expected);
+        }
+        assert_eq!(take_error(warmup(null_mut(), true)).as_deref(), Some("tokenizer passed is null"));
+    }
+}
@@ -0,0 +1,292 @@
+//! WebAssembly interface (`wasm` feature): exposes the same encode/decode/configure surface as the
+//! C functions through wasm-bindgen, using no raw C pointers or libc types.
+
+use std::sync::Arc;
+use tokenizers::tokenizer::Tokenizer;
+use tokenizers::Encoding;
+use wasm_bindgen::prelude::*;
+use crate::configure::{padding_params, truncation_params};
+use crate::debug::TokenScores;
+use crate::encode::{encode_batch_with_handle, get_length, get_offsets, get_token_char_lengths, get_token_scores, get_window,
+                    get_sequence_ids, get_word_ids, get_words, pack_bits, EncodeParams, ENCODE_PACKED_MASKS, ENCODE_RETURN_ATTENTION_MASK, ENCODE_RETURN_DEBUG_INFO, ENCODE_RETURN_LENGTH, ENCODE_RETURN_OFFSETS, ENCODE_RETURN_OVERFLOWING,
+                    ENCODE_RETURN_SPECIAL_TOKENS_MASK, ENCODE_RETURN_TOKENS, ENCODE_RETURN_TOKEN_CHAR_LENGTHS,
+                    ENCODE_RETURN_TYPE_IDS, ENCODE_RETURN_WORDS, ENCODE_RETURN_WORD_STARTS, ENCODE_RETURN_SEQUENCE_IDS, ENCODE_RETURN_WORD_IDS};
+use crate::handle::TokenizerHandle;
+use crate::words::WordStarts;
+
+/// WasmTokenizer is the WebAssembly version of the tokenizer handle returned by `from_bytes`.
+#[wasm_bindgen]
+pub struct WasmTokenizer {
+    handle: Arc<TokenizerHandle>,
+}
+
+/// WasmEncoding is the WebAssembly version of Buffer: the result of an encoded sentence.
+/// Each of the fields are only set if they were requested in the `flags` passed to encode.
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct WasmEncoding {
+    ids: Vec<u32>,
+    type_ids: Option<Vec<u32>>,
+    special_tokens_mask: Option<Vec<u32>>,
+    attention_mask: Option<Vec<u32>>,
+    tokens: Option<Vec<String>>,
+    offsets: Option<Vec<u32>>,
+    token_char_lengths: Option<Vec<u32>>,
+    word_ids: Option<Vec<i64>>,
+    token_scores: Option<Vec<f64>>,
+    window: Option<Vec<u32>>,
+    overflowing: Option<Vec<WasmEncoding>>,
+    length: Option<u32>,
+    words: Option<Vec<u32>>,
+    word_starts: Option<Vec<u32>>,
+    attention_mask_bits: Option<Vec<u8>>,
+    special_tokens_mask_bits: Option<Vec<u8>>,
+    sequence_ids: Option<Vec<i64>>,
+}
+
+#[wasm_bindgen]
+impl WasmEncoding {
+    #[wasm_bindgen(getter)]
+    pub fn ids(&self) -> Vec<u32> {
+        self.ids.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn type_ids(&self) -> Option<Vec<u32>> {
+        self.type_ids.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn special_tokens_mask(&self) -> Option<Vec<u32>> {
+        self.special_tokens_mask.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn attention_mask(&self) -> Option<Vec<u32>> {
+        self.attention_mask.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn tokens(&self) -> Option<Vec<String>> {
+        self.tokens.clone()
+    }
+
+    /// Offsets flattened as `[start_0, end_0, start_1, end_1, ...]`.
+    #[wasm_bindgen(getter)]
+    pub fn offsets(&self) -> Option<Vec<u32>> {
+        self.offsets.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn token_char_lengths(&self) -> Option<Vec<u32>> {
+        self.token_char_lengths.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn word_ids(&self) -> Option<Vec<i64>> {
+        self.word_ids.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn token_scores(&self) -> Option<Vec<f64>> {
+        self.token_scores.clone()
+    }
+
+    /// Span `[start, end]` of the original text covered by this window.
+    #[wasm_bindgen(getter)]
+    pub fn window(&self) -> Option<Vec<u32>> {
+        self.window.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn overflowing(&self) -> Option<Vec<WasmEncoding>> {
+        self.overflowing.clone()
+    }
+
+    /// Number of tokens before padding.
+    #[wasm_bindgen(getter)]
+    pub fn length(&self) -> Option<u32> {
+        self.length
+    }
+
+    /// Spans of the words flattened as `[start_0, end_0, start_1, end_1, ...]`, indexed by `word_ids`.
+    #[wasm_bindgen(getter)]
+    pub fn words(&self) -> Option<Vec<u32>> {
+        self.words.clone()
+    }
+
+    /// 1 for the tokens that start a new word, 0 for the continuation and special tokens.
+    #[wasm_bindgen(getter)]
+    pub fn word_starts(&self) -> Option<Vec<u32>> {
+        self.word_starts.clone()
+    }
+
+    /// Attention mask packed with 1 bit per token (least significant first), if ENCODE_PACKED_MASKS is set.
+    #[wasm_bindgen(getter)]
+    pub fn attention_mask_bits(&self) -> Option<Vec<u8>> {
+        self.attention_mask_bits.clone()
+    }
+
+    /// Special tokens mask packed with 1 bit per token (least significant first), if ENCODE_PACKED_MASKS is set.
+    #[wasm_bindgen(getter)]
+    pub fn special_tokens_mask_bits(&self) -> Option<Vec<u8>> {
+        self.special_tokens_mask_bits.clone()
+    }
+
+    /// Index of the text each token came from (1 for the second text of a pair), or -1 for special tokens.
+    #[wasm_bindgen(getter)]
+    pub fn sequence_ids(&self) -> Option<Vec<i64>> {
+        self.sequence_ids.clone()
+    }
+}
+
+// wasm_encoding converts the `encoding` of `text`: `scores` are only given if ENCODE_RETURN_DEBUG_INFO is set, and
+// `word_starts` if ENCODE_RETURN_WORD_STARTS is set.
+fn wasm_encoding(
+    encoding: &Encoding,
+    text: &str,
+    options: &EncodeParams,
+    scores: Option<&TokenScores>,
+    word_starts: Option<&WordStarts>,
+) -> WasmEncoding {
+    let packed = options.has(ENCODE_PACKED_MASKS);
+    WasmEncoding {
+        ids: encoding.get_ids().to_vec(),
+        type_ids: options.has(ENCODE_RETURN_TYPE_IDS).then(|| encoding.get_type_ids().to_vec()),
+        special_tokens_mask: (options.has(ENCODE_RETURN_SPECIAL_TOKENS_MASK) && !packed)
+            .then(|| encoding.get_special_tokens_mask().to_vec()),
+        attention_mask: (options.has(ENCODE_RETURN_ATTENTION_MASK) && !packed)
+            .then(|| encoding.get_attention_mask().to_vec()),
+        tokens: options.has(ENCODE_RETURN_TOKENS).then(|| encoding.get_tokens().to_vec()),
+        offsets: options.has(ENCODE_RETURN_OFFSETS).then(|| {
+            get_offsets(encoding, &[text], options).iter().flat_map(|&(start, end)| [start as u32, end as u32]).collect()
+        }),
+        token_char_lengths: options.has(ENCODE_RETURN_TOKEN_CHAR_LENGTHS)
+            .then(|| get_token_char_lengths(encoding, &[text], options)),
+        word_ids: (scores.is_some() || options.has(ENCODE_RETURN_WORDS | ENCODE_RETURN_WORD_IDS)).then(|| get_word_ids(encoding)),
+        token_scores: scores.map(|scores| get_token_scores(encoding, scores)),
+        window: options.has(ENCODE_RETURN_OVERFLOWING).then(|| {
+            let (start, end) = get_window(encoding, &get_offsets(encoding, &[text], options));
+            vec![start as u32, end as u32]
+        }),
+        overflowing: options.has(ENCODE_RETURN_OVERFLOWING).then(|| {
+            encoding.get_overflowing().iter().map(|overflow| wasm_encoding(overflow, text, options, scores, word_starts)).collect()
+        }),
+        length: options.has(ENCODE_RETURN_LENGTH).then(|| get_length(encoding) as u32),
+        words: options.has(ENCODE_RETURN_WORDS).then(|| {
+            get_words(encoding, &get_offsets(encoding, &[text], options))
+                .iter()
+                .flat_map(|&(start, end)| [start as u32, end as u32])
+                .collect()
+        }),
+        word_starts: word_starts.map(|word_starts| word_starts.get(encoding)),
+        attention_mask_bits: (options.has(ENCODE_RETURN_ATTENTION_MASK) && packed)
+            .then(|| pack_bits(encoding.get_attention_mask())),
+        special_tokens_mask_bits: (options.has(ENCODE_RETURN_SPECIAL_TOKENS_MASK) && packed)
+            .then(|| pack_bits(encoding.get_special_tokens_mask())),
+        sequence_ids: options.has(ENCODE_RETURN_SEQUENCE_IDS).then(|| get_sequence_ids(encoding)),
+    }
+}
+
+#[wasm_bindgen]
+impl WasmTokenizer {
+    /// Creates the tokenizer from the json contents of a `tokenizer.json` file.
+    #[wasm_bindgen(constructor)]
+    pub fn from_bytes(bytes: &[u8]) -> Result<WasmTokenizer, JsError> {
+        let tokenizer = Tokenizer::from_bytes(bytes).map_err(|e| JsError::new(&e.to_string()))?;
+        Ok(WasmTokenizer {
+            handle: Arc::new(TokenizerHandle::new(tokenizer)),
+        })
+    }
+
+    /// Encodes the text: `flags` is a bitmask of the `ENCODE_*` flags of the C interface.
+    pub fn encode(&self, text: &str, flags: u64) -> Result<WasmEncoding, JsError> {
+        let mut encodings = self.encode_batch(vec![text.to_string()], flags)?;
+        Ok(encodings.remove(0))
+    }
+
+    /// Encodes a batch of texts: `flags` is a bitmask of the `ENCODE_*` flags of the C interface.
+    pub fn encode_batch(&self, texts: Vec<String>, flags: u64) -> Result<Vec<WasmEncoding>, JsError> {
+        let options = EncodeParams::new(flags);
+        let inputs: Vec<&str> = texts.iter().map(|t| t.as_str()).collect();
+        let encodings = encode_batch_with_handle(&self.handle, &inputs, &options)
+            .map_err(|e| JsError::new(&e.to_string()))?;
+        let scores = options.has(ENCODE_RETURN_DEBUG_INFO).then(|| self.handle.read().token_scores());
+        let word_starts = options.has(ENCODE_RETURN_WORD_STARTS).then(|| WordStarts::new(&self.handle.read().tokenizer));
+        Ok(encodings
+            .iter()
+            .zip(inputs.iter())
+            .map(|(encoding, text)| wasm_encoding(encoding, text, &options, scores.as_deref(), word_starts.as_ref()))
+            .collect())
+    }
+
+    pub fn decode(&self, ids: &[u32], skip_special_tokens: bool) -> Result<String, JsError> {
+        self.handle.read().tokenizer
+            .decode(ids, skip_special_tokens)
+            .map_err(|e| JsError::new(&e.to_string()))
+    }
+
+    pub fn vocab_size(&self) -> u32 {
+        self.handle.read().tokenizer.get_vocab_size(true) as u32
+    }
+
+    /// Sets truncation, with the same values as the C `TruncationParams`.
+    pub fn set_truncation(&self, direction: u8, strategy: u8, max_length: u32, stride: u32) -> Result<(), JsError> {
+        let params = truncation_params(direction, strategy, max_length, stride)
+            .map_err(|e| JsError::new(&e.to_string()))?;
+        let mut state = self.handle.write().map_err(|e| JsError::new(&e.to_string()))?;
+        state.tokenizer_mut().with_truncation(Some(params)).map_err(|e| JsError::new(&e.to_string()))?;
+        Ok(())
+    }
+
+    pub fn set_no_truncation(&self) -> Result<(), JsError> {
+        let mut state = self.handle.write().map_err(|e| JsError::new(&e.to_string()))?;
+        state.tokenizer_mut().with_truncation(None).map_err(|e| JsError::new(&e.to_string()))?;
+        Ok(())
+    }
+
+    /// Sets padding, with the same values as the C `PaddingParams`.
+    pub fn set_padding(&self, strategy: u32, direction: u8, pad_to_multiple_of: u32, pad_id: u32,
+                       pad_type_id: u32, pad_token: String) -> Result<(), JsError> {
+        let mut state = self.handle.write().map_err(|e| JsError::new(&e.to_string()))?;
+        state.tokenizer_mut().with_padding(Some(padding_params(
+            strategy, direction, pad_to_multiple_of, pad_id, pad_type_id, pad_token)));
+        Ok(())
+    }
+
+    pub fn set_no_padding(&self) -> Result<(), JsError> {
+        let mut state = self.handle.write().map_err(|e| JsError::new(&e.to_string()))?;
+        state.tokenizer_mut().with_padding(None);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{ENCODE_ADD_SPECIAL_TOKENS, ENCODE_WITH_OFFSETS_UTF16_MODE};
+    use crate::testing::WORDPIECE;
+
+    // The errors (JsError) are only available on wasm32 targets: only the successful calls are tested natively.
+    #[test]
+    fn wasm_encode_decode() {
+        let tokenizer = WasmTokenizer::from_bytes(WORDPIECE.as_bytes()).unwrap();
+        assert_eq!(tokenizer.vocab_size(), 10);
+        let encoding = tokenizer.encode("hello world", ENCODE_RETURN_TOKENS | ENCODE_RETURN_OFFSETS).unwrap();
+        assert_eq!(encoding.ids(), [4, 6]);
+        assert_eq!(encoding.tokens().unwrap(), ["hello", "world"]);
+        assert_eq!(encoding.offsets().unwrap(), [0, 5, 6, 11]);
+        assert_eq!(encoding.type_ids(), None);
+
+        let texts = vec!["a".to_string(), "b".to_string()];
+        let encodings = tokenizer.encode_batch(texts, ENCODE_ADD_SPECIAL_TOKENS).unwrap();
+        assert_eq!(encodings.iter().map(WasmEncoding::ids).collect::<Vec<_>>(), [[1], [2]]);
+        let encoding = tokenizer.encode("é a", ENCODE_RETURN_OFFSETS | ENCODE_WITH_OFFSETS_UTF16_MODE).unwrap();
+        assert_eq!(encoding.offsets().unwrap(), [0, 1, 2, 3]);
+
+        tokenizer.set_truncation(1, 0, 1, 0).unwrap();  // Right, LongestFirst.
+        assert_eq!(tokenizer.encode("hello world", 0).unwrap().ids(), [4]);
+        tokenizer.set_no_truncation().unwrap();
+        assert_eq!(tokenizer.decode(&[4, 6], false).unwrap(), "hello world");
+    }
+}
@@ -0,0 +1,139 @@
+use serde_json::Value;
+use tokenizers::models::ModelWrapper;
+use tokenizers::tokenizer::Tokenizer;
+use tokenizers::Encoding;
+
+/// WordStarts is the rule used to tell whether each token starts a new word, or continues the word of the
+/// previous token (see `ENCODE_RETURN_WORD_STARTS`), derived from the model and the pipeline of the tokenizer.
+pub enum WordStarts {
+    // Continuation tokens start with the continuing subword prefix of the model (e.g. "##" for WordPiece).
+    ContinuingPrefix(String),
+
+    // Word-starting tokens start with the marker of the preceding space: "Ġ" for byte-level (GPT-2 style)
+    // tokenizers, or the replacement character (usually "▁") of Metaspace (SentencePiece style) tokenizers.
+    Marker(String),
+
+    // Otherwise each word (pre-token) is a word.
+    PreTokens,
+}
+
+// BYTE_LEVEL_SPACE is the character representing the space in byte-level vocabularies.
+const BYTE_LEVEL_SPACE: &str = "\u{120}";
+
+impl WordStarts {
+    pub fn new(tokenizer: &Tokenizer) -> Self {
+        let prefix = match tokenizer.get_model() {
+            ModelWrapper::WordPiece(wordpiece) => Some(wordpiece.continuing_subword_prefix.clone()),
+            ModelWrapper::BPE(bpe) => bpe.continuing_subword_prefix.clone(),
+            _ => None,
+        };
+        if let Some(prefix) = prefix.filter(|prefix| !prefix.is_empty()) {
+            return WordStarts::ContinuingPrefix(prefix);
+        }
+        // The marker is found in the pre-tokenizer, or in the decoder (e.g. Llama tokenizers only replace the
+        // spaces in the normalizer, and replace them back in the decoder).
+        let components = [
+            tokenizer.get_pre_tokenizer().and_then(|pre_tokenizer| serde_json::to_value(pre_tokenizer).ok()),
+            tokenizer.get_decoder().and_then(|decoder| serde_json::to_value(decoder).ok()),
+        ];
+        match components.iter().flatten().find_map(find_marker) {
+            Some(marker) => WordStarts::Marker(marker),
+            None => WordStarts::PreTokens,
+        }
+    }
+
+    /// Returns 1 for each token of the `encoding` that starts a new word, and 0 for the tokens continuing the
+    /// word of the previous one, and for the special tokens. The first token of each sequence, and the first
+    /// one after a special token, always start a new word.
+    pub fn get(&self, encoding: &Encoding) -> Vec<u32> {
+        let mut previous: Option<(Option<usize>, Option<u32>)> = None;
+        let mut starts = Vec::with_capacity(encoding.len());
+        for (i, token) in encoding.get_tokens().iter().enumerate() {
+            if encoding.get_special_tokens_mask()[i] != 0 {
+                starts.push(0);
+                previous = None;
+                continue;
+            }
+            let current = (encoding.get_sequence_ids()[i], encoding.get_word_ids()[i]);
+            let is_start = match previous {
+                Some((sequence, word)) if sequence == current.0 => match self {
+                    WordStarts::ContinuingPrefix(prefix) => !token.starts_with(prefix.as_str()),
+                    WordStarts::Marker(marker) => token.starts_with(marker.as_str()),
+                    WordStarts::PreTokens => word != current.1,
+                },
+                _ => true,
+            };
+            starts.push(u32::from(is_start));
+            previous = Some(current);
+        }
+        starts
+    }
+}
+
+// find_marker returns the word-start marker of the serialized pipeline `component`, if any.
+fn find_marker(component: &Value) -> Option<String> {
+    match component["type"].as_str() {
+        Some("ByteLevel") => return Some(BYTE_LEVEL_SPACE.to_string()),
+        Some("Metaspace") => return component["replacement"].as_str().map(str::to_string),
+        Some("Replace") if component["content"] == " " => {
+            return component["pattern"]["String"].as_str().map(str::to_string);
+        }
+        _ => {}
+    }
+    ["pretokenizers", "decoders"]
+        .iter()
+        .filter_map(|key| component[*key].as_array())
+        .flatten()
+        .find_map(find_marker)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{encode_str_impl, free_buffer, EncodeParams, ENCODE_PARAMS_VERSION, ENCODE_RETURN_WORD_STARTS};
+    use crate::testing::{TestHandle, BYTE_LEVEL_BPE, SENTENCEPIECE, UNIGRAM, WORDPIECE_BERT};
+
+    // word_starts returns the rule of the tokenizer `json`, and the word starts of the encoding of `text`.
+    fn word_starts(json: &str, text: &str) -> (WordStarts, Vec<u32>) {
+        let handle = TestHandle::new(json);
+        let state = unsafe { (*handle.0).read() };
+        let word_starts = WordStarts::new(&state.tokenizer);
+        let starts = word_starts.get(&state.tokenizer.encode(text, true).unwrap());
+        (word_starts, starts)
+    }
+
+    #[test]
+    fn word_starts_rules() {
+        // [CLS] hello a ##b world [SEP]
+        let (rule, starts) = word_starts(WORDPIECE_BERT, "hello ab world");
+        assert!(matches!(rule, WordStarts::ContinuingPrefix(prefix) if prefix == "##"));
+        assert_eq!(starts, [0, 1, 1, 0, 1, 0]);
+
+        // hello Ġworld: the first token always starts a word.
+        let (rule, starts) = word_starts(BYTE_LEVEL_BPE, "hello world");
+        assert!(matches!(rule, WordStarts::Marker(marker) if marker == "Ġ"));
+        assert_eq!(starts, [1, 1]);
+        let (_, starts) = word_starts(BYTE_LEVEL_BPE, "hellohe");
+        assert_eq!(starts, [1, 0]);
+
+        // <s> ▁hello ▁world
+        let (rule, starts) = word_starts(SENTENCEPIECE, "hello world");
+        assert!(matches!(rule, WordStarts::Marker(marker) if marker == "▁"));
+        assert_eq!(starts, [0, 1, 1]);
+
+        // ab c b: "ab" and "c b" are different pre-tokens.
+        let (rule, starts) = word_starts(UNIGRAM, "ab cb");
+        assert!(matches!(rule, WordStarts::PreTokens));
+        assert_eq!(starts, [1, 1, 0]);
+    }
+
+    #[test]
+    fn encode_word_starts() {
+        let handle = TestHandle::new(WORDPIECE_BERT);
+        let options = EncodeParams { version: ENCODE_PARAMS_VERSION, flags: ENCODE_RETURN_WORD_STARTS };
+        let buffers = encode_str_impl(handle.0, "ab a", options).unwrap();
+        let starts = unsafe { std::slice::from_raw_parts(buffers[0].word_starts, buffers[0].len as usize) };
+        assert_eq!(starts, [1, 0, 1]);
+        buffers.into_iter().for_each(free_buffer);
+    }
+}
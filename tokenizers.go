@@ -117,7 +117,10 @@ func FromBytes(data []byte) (*Tokenizer, error) {
 
 	// Parse truncation and padding:
 	var direction, truncStrategy uint8
-	t.isTruncationSet, direction, t.truncationMaxLength, truncStrategy, t.truncationStride = t.tokenizer.GetTruncation()
+	t.isTruncationSet, direction, t.truncationMaxLength, truncStrategy, t.truncationStride, err = t.tokenizer.GetTruncation()
+	if err != nil {
+		return nil, errors.WithMessage(err, "Tokenizer.FromBytes(<json-data>):")
+	}
 	t.truncationDirection = Direction(direction)
 	t.truncationStrategy = TruncationStrategy(truncStrategy)
 	if !t.isTruncationSet {
@@ -125,7 +128,10 @@ func FromBytes(data []byte) (*Tokenizer, error) {
 	}
 
 	var padStrategy uint32
-	t.isPaddingSet, padStrategy, direction, t.padToMultipleOf, t.padId, t.padTypeId, t.padToken = t.tokenizer.GetPadding()
+	t.isPaddingSet, padStrategy, direction, t.padToMultipleOf, t.padId, t.padTypeId, t.padToken, err = t.tokenizer.GetPadding()
+	if err != nil {
+		return nil, errors.WithMessage(err, "Tokenizer.FromBytes(<json-data>):")
+	}
 	t.paddingDirection = Direction(direction)
 	if padStrategy == 0 {
 		t.paddingStrategy = PadLongest
@@ -562,7 +568,11 @@ func (t *Tokenizer) Decode(tokenIds []uint32, skipSpecialTokens bool) string {
 	if len(tokenIds) == 0 {
 		return ""
 	}
-	return t.tokenizer.Decode(tokenIds, skipSpecialTokens)
+	text, err := t.tokenizer.Decode(tokenIds, skipSpecialTokens)
+	if err != nil {
+		panicf("Tokenizer.Decode(): %v", err)
+	}
+	return text
 }
 
 // VocabSize returns the number of known tokens.
@@ -570,5 +580,9 @@ func (t *Tokenizer) VocabSize() uint32 {
 	if t.tokenizer == nil {
 		panicf("Tokenizer already finalized, one cannot change or use it any longer")
 	}
-	return t.tokenizer.VocabSize()
+	size, err := t.tokenizer.VocabSize()
+	if err != nil {
+		panicf("Tokenizer.VocabSize(): %v", err)
+	}
+	return size
 }